@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(
+            &["../../schemas/proto/gateway/v1/gateway.proto"],
+            &["../../schemas/proto"],
+        )?;
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Account management for operators: `admin.users.*` lists users, changes
+//! roles, sets token balances and disables accounts. Roles and the disabled
+//! flag are read from `users` on every request, so changes apply to existing
+//! JWTs and API keys immediately. Any role defined in `roles` may be assigned
+//! (see `rbac`). Admins only see and manage users of their own tenant.
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Error as SqlxError, PgPool, Row};
+
+use crate::auth_cache::AuthCache;
+use crate::errors::ErrorCode;
+use crate::{billing, RequestContext, RpcMethodError};
+
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 500;
+const USER_COLUMNS: &str =
+    "id, username, role, tenant_id, token_balance, disabled_at, created_at, updated_at";
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AdminUsersListParams {
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    disabled: Option<bool>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AdminSetRoleParams {
+    user_id: i32,
+    role: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AdminSetTokenBalanceParams {
+    user_id: i32,
+    balance: i64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AdminDisableParams {
+    user_id: i32,
+    #[serde(default)]
+    disabled: Option<bool>,
+}
+
+fn not_found() -> RpcMethodError {
+    RpcMethodError::new(ErrorCode::UserNotFound, "user not found", None)
+}
+
+fn unknown_role(value: &str) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::InvalidParams,
+        "unsupported role",
+        Some(json!({ "role": value })),
+    )
+}
+
+/// Admins cannot demote or disable themselves, so there is always at least
+/// the caller left to undo a mistake.
+fn ensure_not_self(ctx: &RequestContext, user_id: i32, action: &str) -> Result<(), RpcMethodError> {
+    if ctx.user_id == user_id {
+        Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "admins cannot change their own account",
+            Some(json!({ "action": action })),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn user_value(row: &sqlx::postgres::PgRow) -> Value {
+    let disabled_at: Option<DateTime<Utc>> = row.get("disabled_at");
+    json!({
+        "id": row.get::<i32, _>("id"),
+        "username": row.get::<String, _>("username"),
+        "role": row.get::<String, _>("role"),
+        "tenant_id": row.get::<i32, _>("tenant_id"),
+        "token_balance": row.get::<i64, _>("token_balance"),
+        "disabled": disabled_at.is_some(),
+        "disabled_at": disabled_at.map(|at| at.to_rfc3339()),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        "updated_at": row.get::<DateTime<Utc>, _>("updated_at").to_rfc3339(),
+    })
+}
+
+fn db_error(err: sqlx::Error) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update user: {err}"))
+}
+
+pub(crate) async fn list(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: AdminUsersListParams,
+) -> Result<Value, RpcMethodError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let rows = sqlx::query(&format!(
+        "SELECT {USER_COLUMNS} FROM users \
+         WHERE tenant_id = $5 \
+           AND ($1::varchar IS NULL OR role = $1) \
+           AND ($2::boolean IS NULL OR (disabled_at IS NOT NULL) = $2) \
+           AND ($3::integer IS NULL OR id > $3) \
+         ORDER BY id LIMIT $4"
+    ))
+    .bind(params.role)
+    .bind(params.disabled)
+    .bind(params.cursor)
+    .bind(limit)
+    .bind(ctx.tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list users: {err}")))?;
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i32, _>("id")))
+        .flatten();
+    let users: Vec<Value> = rows.iter().map(user_value).collect();
+    Ok(json!({ "users": users, "next_cursor": next_cursor }))
+}
+
+pub(crate) async fn set_role(
+    pool: &PgPool,
+    auth_cache: &AuthCache,
+    ctx: &RequestContext,
+    params: AdminSetRoleParams,
+) -> Result<Value, RpcMethodError> {
+    ensure_not_self(ctx, params.user_id, "setRole")?;
+    let row = sqlx::query(&format!(
+        "UPDATE users SET role = $2, updated_at = NOW() WHERE id = $1 AND tenant_id = $3 \
+         RETURNING {USER_COLUMNS}"
+    ))
+    .bind(params.user_id)
+    .bind(&params.role)
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| match err {
+        SqlxError::Database(db_err) if db_err.code().as_deref() == Some("23503") => {
+            unknown_role(&params.role)
+        }
+        other => db_error(other),
+    })?
+    .ok_or_else(not_found)?;
+    auth_cache.invalidate_user(params.user_id);
+    Ok(user_value(&row))
+}
+
+pub(crate) async fn set_token_balance(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    method: &str,
+    params: AdminSetTokenBalanceParams,
+) -> Result<Value, RpcMethodError> {
+    if params.balance < 0 {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "balance must not be negative",
+            None,
+        ));
+    }
+    billing::set_balance(pool, ctx, method, params.user_id, params.balance)
+        .await?
+        .ok_or_else(not_found)
+}
+
+/// Disables an account, or re-enables it with `disabled: false`. Data and
+/// API keys are kept; authentication simply stops succeeding.
+pub(crate) async fn disable(
+    pool: &PgPool,
+    auth_cache: &AuthCache,
+    ctx: &RequestContext,
+    params: AdminDisableParams,
+) -> Result<Value, RpcMethodError> {
+    let disabled = params.disabled.unwrap_or(true);
+    ensure_not_self(ctx, params.user_id, "disable")?;
+    let row = sqlx::query(&format!(
+        "UPDATE users SET \
+            disabled_at = CASE WHEN $2 THEN COALESCE(disabled_at, NOW()) END, \
+            updated_at = NOW() \
+         WHERE id = $1 AND tenant_id = $3 RETURNING {USER_COLUMNS}"
+    ))
+    .bind(params.user_id)
+    .bind(disabled)
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(not_found)?;
+    auth_cache.invalidate_user(params.user_id);
+    Ok(user_value(&row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_self_changes() {
+        let ctx = RequestContext {
+            user_id: 7,
+            username: "root".to_string(),
+            role: crate::Role::Admin,
+            tenant_id: crate::tenant::DEFAULT_TENANT,
+            permissions: Default::default(),
+            token_balance: 0,
+            api_key_id: None,
+            client_ip: None,
+            request_id: uuid::Uuid::new_v4(),
+            trace_parent: opentelemetry::Context::new(),
+            credentials: Default::default(),
+        };
+        assert!(ensure_not_self(&ctx, 8, "disable").is_ok());
+        assert_eq!(
+            ensure_not_self(&ctx, 7, "disable").unwrap_err().code,
+            -32602
+        );
+    }
+}
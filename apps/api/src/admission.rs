@@ -0,0 +1,283 @@
+//! Admission control. Every call, whether it comes over RPC, REST or gRPC, is
+//! put in a class, and each class has its own concurrency limit. That keeps
+//! a wave of agent dispatches from crowding out the fast file operations of
+//! an editor:
+//!
+//! - `interactive`: `fs.*`, `project.file.*` and read-only calls
+//! - `heavy`: sandbox execution, agent dispatch, LLM calls, export/import
+//! - `standard`: everything else
+//!
+//! `ADMISSION_<CLASS>_CONCURRENCY` caps the calls of a class running at
+//! once (`0` lifts the cap), and `ADMISSION_METHOD_CLASSES` (`method=class`
+//! pairs) moves methods between classes. `ADMISSION_PRIORITY_SHARE` of each
+//! class is held back for `ADMISSION_PRIORITY_ROLES`. A call that finds no
+//! free slot within `ADMISSION_QUEUE_MS` is shed with -32096. Streams hold
+//! their slot until they end.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::metrics::AppMetrics;
+use crate::{is_read_only_method, openrpc, RequestContext, RpcMethodError};
+
+/// Suggested pause before a shed call is retried.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Class {
+    Interactive,
+    Standard,
+    Heavy,
+}
+
+impl Class {
+    const ALL: [Class; 3] = [Class::Interactive, Class::Standard, Class::Heavy];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Class::Interactive => "interactive",
+            Class::Standard => "standard",
+            Class::Heavy => "heavy",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|class| class.as_str() == value)
+    }
+
+    fn of(method: &str) -> Self {
+        match method {
+            "run.exec" | "run.session.start" | "wasm.invoke" | "micro.start" | "micro.execute"
+            | "project.run" | "agent.dispatch" | "agent.pipeline" | "agent.apply" | "llm.chat"
+            | "llm.completion" | "llm.completions" | "llm.embed" | "llm.download" | "llm.start"
+            | "project.export" | "project.import" => Class::Heavy,
+            _ if method.starts_with("fs.")
+                || method.starts_with("project.file.")
+                || is_read_only_method(method) =>
+            {
+                Class::Interactive
+            }
+            _ => Class::Standard,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AdmissionConfig {
+    /// Per class in `Class::ALL` order; `0` means unlimited.
+    limits: [usize; 3],
+    methods: HashMap<String, Class>,
+    priority_roles: HashSet<String>,
+    priority_share: f64,
+    queue: Duration,
+}
+
+impl AdmissionConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let limits = [
+            config.get("ADMISSION_INTERACTIVE_CONCURRENCY", 256),
+            config.get("ADMISSION_STANDARD_CONCURRENCY", 64),
+            config.get("ADMISSION_HEAVY_CONCURRENCY", 16),
+        ];
+        let mut methods = HashMap::new();
+        for (method, class) in config.pairs("ADMISSION_METHOD_CLASSES") {
+            if !openrpc::has_method(&method) {
+                config.invalid(
+                    "ADMISSION_METHOD_CLASSES",
+                    format!("unknown method `{method}`"),
+                );
+                continue;
+            }
+            match Class::parse(&class) {
+                Some(class) => {
+                    methods.insert(method, class);
+                }
+                None => config.invalid(
+                    "ADMISSION_METHOD_CLASSES",
+                    format!("`{class}` is not interactive, standard or heavy"),
+                ),
+            }
+        }
+        let priority_share = config.get("ADMISSION_PRIORITY_SHARE", 0.1);
+        if !(0.0..1.0).contains(&priority_share) {
+            config.invalid("ADMISSION_PRIORITY_SHARE", "must be at least 0 and below 1");
+        }
+        Self {
+            limits,
+            methods,
+            priority_roles: config
+                .list("ADMISSION_PRIORITY_ROLES", &["admin"])
+                .into_iter()
+                .collect(),
+            priority_share,
+            queue: config.millis("ADMISSION_QUEUE_MS", 100),
+        }
+    }
+}
+
+/// The slots of one class. Callers with a priority role may also take the
+/// reserved ones.
+#[derive(Debug)]
+struct Lane {
+    limit: usize,
+    shared: Arc<Semaphore>,
+    reserved: Arc<Semaphore>,
+}
+
+/// A slot held until the call (or stream) ends.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Admission {
+    lanes: Arc<[Option<Lane>; 3]>,
+    config: Arc<AdmissionConfig>,
+}
+
+impl Admission {
+    pub(crate) fn new(config: AdmissionConfig) -> Self {
+        let lanes = config.limits.map(|limit| {
+            (limit > 0).then(|| {
+                let reserved = (limit as f64 * config.priority_share) as usize;
+                Lane {
+                    limit,
+                    shared: Arc::new(Semaphore::new(limit - reserved)),
+                    reserved: Arc::new(Semaphore::new(reserved)),
+                }
+            })
+        });
+        Self {
+            lanes: Arc::new(lanes),
+            config: Arc::new(config),
+        }
+    }
+
+    fn class(&self, method: &str) -> Class {
+        self.config
+            .methods
+            .get(method)
+            .copied()
+            .unwrap_or_else(|| Class::of(method))
+    }
+
+    /// Waits up to `ADMISSION_QUEUE_MS` for a slot for the canonical
+    /// `method`.
+    pub(crate) async fn admit(
+        &self,
+        method: &str,
+        ctx: &RequestContext,
+        metrics: &AppMetrics,
+    ) -> Result<Permit, RpcMethodError> {
+        let class = self.class(method);
+        let Some(lane) = &self.lanes[class as usize] else {
+            return Ok(Permit { _slot: None });
+        };
+        let priority = self.config.priority_roles.contains(ctx.role.as_str());
+        let slot = match lane.shared.clone().try_acquire_owned() {
+            Ok(slot) => Some(slot),
+            Err(_) => {
+                let wait = async {
+                    if priority {
+                        tokio::select! {
+                            slot = lane.shared.clone().acquire_owned() => slot,
+                            slot = lane.reserved.clone().acquire_owned() => slot,
+                        }
+                    } else {
+                        lane.shared.clone().acquire_owned().await
+                    }
+                };
+                tokio::time::timeout(self.config.queue, wait)
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+            }
+        };
+        match slot {
+            Some(slot) => Ok(Permit { _slot: Some(slot) }),
+            None => {
+                metrics.admission_shed(class.as_str());
+                warn!(
+                    method,
+                    class = class.as_str(),
+                    limit = lane.limit,
+                    "shedding call; no free slot"
+                );
+                Err(RpcMethodError::new(
+                    ErrorCode::Overloaded,
+                    "server overloaded",
+                    Some(json!({
+                        "class": class.as_str(),
+                        "limit": lane.limit,
+                        "retry_after_ms": RETRY_AFTER.as_millis() as u64,
+                    })),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use auth_core::Role;
+
+    use super::*;
+    use crate::llm_mock::request_context;
+
+    fn admission(heavy: usize, priority_share: f64) -> Admission {
+        Admission::new(AdmissionConfig {
+            limits: [4, 4, heavy],
+            methods: HashMap::from([("project.search".to_string(), Class::Heavy)]),
+            priority_roles: HashSet::from(["admin".to_string()]),
+            priority_share,
+            queue: Duration::from_millis(20),
+        })
+    }
+
+    #[test]
+    fn methods_fall_into_classes() {
+        let admission = admission(1, 0.0);
+        assert_eq!(admission.class("fs.read"), Class::Interactive);
+        assert_eq!(admission.class("project.file.save"), Class::Interactive);
+        assert_eq!(admission.class("agent.status"), Class::Interactive);
+        assert_eq!(admission.class("agent.dispatch"), Class::Heavy);
+        assert_eq!(admission.class("project.search"), Class::Heavy);
+        assert_eq!(admission.class("project.create"), Class::Standard);
+    }
+
+    #[tokio::test]
+    async fn saturated_classes_shed_without_blocking_others() {
+        let metrics = AppMetrics::default();
+        let admission = admission(2, 0.5);
+        let ctx = request_context(1);
+        let held = admission.admit("run.exec", &ctx, &metrics).await.unwrap();
+        let err = admission
+            .admit("agent.dispatch", &ctx, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Overloaded.code());
+        assert_eq!(err.data.unwrap()["class"], "heavy");
+        assert!(admission.admit("fs.read", &ctx, &metrics).await.is_ok());
+
+        let admin = RequestContext {
+            role: Role::Admin,
+            ..request_context(2)
+        };
+        let reserved = admission.admit("run.exec", &admin, &metrics).await.unwrap();
+        assert!(admission.admit("run.exec", &admin, &metrics).await.is_err());
+        drop(held);
+        assert!(admission.admit("run.exec", &ctx, &metrics).await.is_ok());
+        drop(reserved);
+        assert!(metrics
+            .render(false)
+            .contains("api_admission_shed_total{class=\"heavy\"} 2"));
+    }
+}
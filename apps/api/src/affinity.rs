@@ -0,0 +1,464 @@
+//! Session affinity for API replicas behind a load balancer (migration 034).
+//! Micro VMs, run sessions and agent tasks live in the memory of the replica
+//! that started them, whether they run there or on a runner connected to it.
+//! With `REPLICA_URL` set, a replica holds a lease in `replicas`, renewed
+//! every third of `REPLICA_LEASE_SECS`, and records every VM, session and
+//! task it starts in `replica_handles`. A call naming one (`micro.execute`,
+//! `micro.stop`, `run.session.write`, `run.session.kill`, `agent.status`,
+//! `agent.cancel`, `agent.respond`, `agent.apply`) that lands on another replica is
+//! forwarded to the owner's `/rpc` with the caller's own credentials, so the
+//! owner authenticates, authorizes and bills it as if it had received it;
+//! forwarded calls are never forwarded again.
+//!
+//! Handles of a replica whose lease ran out answer as unknown, just as
+//! they would on the replica that lost them. Handles are released when a VM
+//! is stopped or a session killed over RPC and otherwise after
+//! `REPLICA_HANDLE_TTL_SECS`. Without `REPLICA_URL` every call is served
+//! where it lands. `run.exec` has no handle to follow, and
+//! `agent.list`/`agent.history` stay per replica.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{header, HeaderMap};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{telemetry, RequestContext, RpcMethodError};
+
+/// Marks a call another replica forwarded, naming that replica.
+const FORWARDED_HEADER: &str = "x-coder-forwarded-by";
+const MIN_LEASE: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone)]
+pub(crate) struct AffinityConfig {
+    /// Base URL other replicas reach this one at; affinity is off without it.
+    url: Option<String>,
+    lease: Duration,
+    handle_ttl: Duration,
+}
+
+impl AffinityConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let url: Option<String> = config.opt("REPLICA_URL");
+        if let Some(url) = &url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                config.invalid("REPLICA_URL", "must be an http(s) URL");
+            }
+        }
+        Self {
+            url: url.map(|url| url.trim_end_matches('/').to_string()),
+            lease: config.secs("REPLICA_LEASE_SECS", 30).max(MIN_LEASE),
+            handle_ttl: config.secs("REPLICA_HANDLE_TTL_SECS", 7 * 24 * 3600),
+        }
+    }
+}
+
+/// The credentials a call was made with, kept so the call can be forwarded
+/// to the replica owning its VM or task.
+#[derive(Clone, Default)]
+pub(crate) struct Credentials {
+    authorization: Option<String>,
+    api_key: Option<String>,
+    /// The call was forwarded by another replica.
+    forwarded: bool,
+}
+
+impl Credentials {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let text = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            authorization: text(header::AUTHORIZATION.as_str()),
+            api_key: text("x-api-key"),
+            forwarded: headers.contains_key(FORWARDED_HEADER),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("authorization", &self.authorization.as_ref().map(|_| "***"))
+            .field("api_key", &self.api_key.as_ref().map(|_| "***"))
+            .field("forwarded", &self.forwarded)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HandleKind {
+    Micro,
+    RunSession,
+    Agent,
+}
+
+impl HandleKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            HandleKind::Micro => "micro",
+            HandleKind::RunSession => "run_session",
+            HandleKind::Agent => "agent",
+        }
+    }
+}
+
+/// The parameter naming the VM, session or task a method acts on.
+fn handle_param(method: &str) -> Option<&'static str> {
+    match method {
+        "micro.execute" | "micro.stop" => Some("vm_id"),
+        "run.session.write" | "run.session.kill" => Some("session_id"),
+        "agent.status" | "agent.cancel" | "agent.respond" | "agent.apply" => Some("task_id"),
+        _ => None,
+    }
+}
+
+struct Replica {
+    pool: PgPool,
+    id: Uuid,
+    url: String,
+    config: AffinityConfig,
+    http: reqwest::Client,
+}
+
+/// This replica's lease and handles; does nothing when affinity is off.
+#[derive(Clone, Default)]
+pub(crate) struct Affinity {
+    replica: Option<Arc<Replica>>,
+}
+
+impl Affinity {
+    pub(crate) fn new(pool: PgPool, config: AffinityConfig) -> Self {
+        let Some(url) = config.url.clone() else {
+            return Self::default();
+        };
+        Self {
+            replica: Some(Arc::new(Replica {
+                pool,
+                id: Uuid::new_v4(),
+                url,
+                config,
+                http: reqwest::Client::new(),
+            })),
+        }
+    }
+
+    /// Takes the lease and keeps renewing it; also drops replicas whose
+    /// lease ran out a lease ago, and this replica's expired handles.
+    pub(crate) fn spawn(&self) {
+        let Some(replica) = self.replica.clone() else {
+            return;
+        };
+        info!(replica_id = %replica.id, url = %replica.url, "joining replica set");
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(replica.config.lease / 3);
+            loop {
+                ticker.tick().await;
+                if let Err(err) = replica.renew().await {
+                    warn!(replica_id = %replica.id, error = %err, "failed to renew replica lease");
+                }
+            }
+        });
+    }
+
+    /// Gives up the lease on shutdown; the handles go with it.
+    pub(crate) async fn leave(&self) {
+        let Some(replica) = &self.replica else {
+            return;
+        };
+        if let Err(err) = sqlx::query("DELETE FROM replicas WHERE id = $1")
+            .bind(replica.id)
+            .execute(&replica.pool)
+            .await
+        {
+            warn!(replica_id = %replica.id, error = %err, "failed to leave the replica set");
+        }
+    }
+
+    /// Records that `handle` lives on this replica. The call that created
+    /// it has succeeded, so a failure is only logged; the handle then
+    /// works on this replica alone.
+    pub(crate) async fn claim(&self, handle: Uuid, kind: HandleKind) {
+        let Some(replica) = &self.replica else {
+            return;
+        };
+        let claimed = sqlx::query(
+            "INSERT INTO replica_handles (handle, kind, replica_id) VALUES ($1, $2, $3) \
+             ON CONFLICT (handle) DO UPDATE SET kind = EXCLUDED.kind, \
+                replica_id = EXCLUDED.replica_id, created_at = NOW()",
+        )
+        .bind(handle)
+        .bind(kind.as_str())
+        .bind(replica.id)
+        .execute(&replica.pool)
+        .await;
+        if let Err(err) = claimed {
+            warn!(%handle, kind = kind.as_str(), error = %err, "failed to record handle owner");
+        }
+    }
+
+    pub(crate) async fn release(&self, handle: Uuid) {
+        let Some(replica) = &self.replica else {
+            return;
+        };
+        if let Err(err) =
+            sqlx::query("DELETE FROM replica_handles WHERE handle = $1 AND replica_id = $2")
+                .bind(handle)
+                .bind(replica.id)
+                .execute(&replica.pool)
+                .await
+        {
+            warn!(%handle, error = %err, "failed to release handle");
+        }
+    }
+
+    /// Forwards `method` to the replica owning the VM or task it names;
+    /// `None` means the call is served here.
+    pub(crate) async fn forward(
+        &self,
+        ctx: &RequestContext,
+        method: &str,
+        params: Option<&Value>,
+    ) -> Result<Option<Value>, RpcMethodError> {
+        let Some(replica) = &self.replica else {
+            return Ok(None);
+        };
+        if ctx.credentials.forwarded {
+            return Ok(None);
+        }
+        let Some(handle) = handle_param(method)
+            .and_then(|name| params?.get(name)?.as_str())
+            .and_then(|value| Uuid::parse_str(value).ok())
+        else {
+            return Ok(None);
+        };
+        let owner = match replica.owner(handle).await {
+            Ok(Some(owner)) => owner,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                warn!(%handle, error = %err, "failed to look up handle owner; serving locally");
+                return Ok(None);
+            }
+        };
+        replica.send(&owner, ctx, method, params).await.map(Some)
+    }
+}
+
+impl Replica {
+    async fn renew(&self) -> Result<(), sqlx::Error> {
+        let lease = self.config.lease.as_secs_f64();
+        sqlx::query(
+            "INSERT INTO replicas (id, url, lease_expires_at) \
+             VALUES ($1, $2, NOW() + make_interval(secs => $3)) \
+             ON CONFLICT (id) DO UPDATE SET url = EXCLUDED.url, \
+                lease_expires_at = EXCLUDED.lease_expires_at",
+        )
+        .bind(self.id)
+        .bind(&self.url)
+        .bind(lease)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "DELETE FROM replicas WHERE lease_expires_at < NOW() - make_interval(secs => $1)",
+        )
+        .bind(lease)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "DELETE FROM replica_handles \
+             WHERE replica_id = $1 AND created_at < NOW() - make_interval(secs => $2)",
+        )
+        .bind(self.id)
+        .bind(self.config.handle_ttl.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The URL of the live replica owning `handle`, unless that is us.
+    async fn owner(&self, handle: Uuid) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT r.url FROM replica_handles h JOIN replicas r ON r.id = h.replica_id \
+             WHERE h.handle = $1 AND r.id <> $2 AND r.lease_expires_at > NOW()",
+        )
+        .bind(handle)
+        .bind(self.id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn send(
+        &self,
+        owner: &str,
+        ctx: &RequestContext,
+        method: &str,
+        params: Option<&Value>,
+    ) -> Result<Value, RpcMethodError> {
+        let mut request = self
+            .http
+            .post(format!("{owner}/rpc"))
+            .header(FORWARDED_HEADER, self.id.to_string())
+            .header(telemetry::REQUEST_ID_HEADER, ctx.request_id.to_string())
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }));
+        if let Some(authorization) = &ctx.credentials.authorization {
+            request = request.header(header::AUTHORIZATION.as_str(), authorization);
+        }
+        if let Some(api_key) = &ctx.credentials.api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        if let Some(traceparent) = telemetry::traceparent(ctx) {
+            request = request.header("traceparent", traceparent);
+        }
+        let unreachable = |err: reqwest::Error| {
+            RpcMethodError::internal(&format!("replica owning the call is unreachable: {err}"))
+        };
+        let response: Value = request
+            .send()
+            .await
+            .map_err(unreachable)?
+            .json()
+            .await
+            .map_err(unreachable)?;
+        answer(response)
+    }
+}
+
+/// The result or error of a forwarded call's JSON-RPC response.
+fn answer(mut response: Value) -> Result<Value, RpcMethodError> {
+    match response.get_mut("error").map(Value::take) {
+        Some(error) => Err(RpcMethodError {
+            code: error["code"].as_i64().unwrap_or(-32603),
+            message: error["message"]
+                .as_str()
+                .unwrap_or("internal error")
+                .to_string(),
+            data: error.get("data").cloned(),
+        }),
+        None => Ok(response
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or(Value::Null)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    fn ctx(credentials: Credentials) -> RequestContext {
+        RequestContext {
+            user_id: 7,
+            username: "dev".to_string(),
+            role: crate::Role::Developer,
+            tenant_id: 1,
+            permissions: Default::default(),
+            token_balance: 0,
+            api_key_id: None,
+            client_ip: None,
+            request_id: Uuid::new_v4(),
+            trace_parent: opentelemetry::Context::new(),
+            credentials,
+        }
+    }
+
+    /// A replica whose database is unreachable, so every owner lookup fails.
+    fn cut_off() -> Affinity {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy("postgres://coder@127.0.0.1:1/coder")
+            .unwrap();
+        Affinity::new(
+            pool,
+            AffinityConfig {
+                url: Some("http://replica-a:8080".to_string()),
+                lease: Duration::from_secs(30),
+                handle_ttl: Duration::from_secs(3600),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn calls_are_served_here_unless_another_replica_owns_them() {
+        let vm = json!({ "vm_id": Uuid::new_v4().to_string(), "code": "" });
+        let local = |result: Result<Option<Value>, RpcMethodError>| {
+            assert!(matches!(result, Ok(None)));
+        };
+
+        let ctx = ctx(Credentials::default());
+        local(
+            Affinity::default()
+                .forward(&ctx, "micro.execute", Some(&vm))
+                .await,
+        );
+
+        let affinity = cut_off();
+        // Nothing to look up: no handle, or one that is not a UUID.
+        local(affinity.forward(&ctx, "micro.start", Some(&vm)).await);
+        local(affinity.forward(&ctx, "micro.execute", None).await);
+        let bad = json!({ "vm_id": "not-a-uuid" });
+        local(affinity.forward(&ctx, "micro.execute", Some(&bad)).await);
+        // A failed owner lookup is served here rather than failing the call.
+        local(affinity.forward(&ctx, "micro.execute", Some(&vm)).await);
+
+        let forwarded = RequestContext {
+            credentials: Credentials {
+                forwarded: true,
+                ..Credentials::default()
+            },
+            ..ctx
+        };
+        local(
+            affinity
+                .forward(&forwarded, "micro.execute", Some(&vm))
+                .await,
+        );
+    }
+
+    #[test]
+    fn forwarded_calls_keep_credentials_and_errors() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        headers.insert("x-api-key", "".parse().unwrap());
+        let credentials = Credentials::from_headers(&headers);
+        assert_eq!(credentials.authorization.as_deref(), Some("Bearer abc"));
+        assert_eq!(credentials.api_key, None);
+        assert!(!credentials.forwarded);
+        assert!(!format!("{credentials:?}").contains("abc"));
+        headers.insert(
+            FORWARDED_HEADER,
+            Uuid::new_v4().to_string().parse().unwrap(),
+        );
+        assert!(Credentials::from_headers(&headers).forwarded);
+
+        assert_eq!(handle_param("micro.execute"), Some("vm_id"));
+        assert_eq!(handle_param("agent.respond"), Some("task_id"));
+        assert_eq!(handle_param("agent.apply"), Some("task_id"));
+        assert_eq!(handle_param("run.session.kill"), Some("session_id"));
+        assert_eq!(handle_param("micro.start"), None);
+
+        let result = answer(json!({ "jsonrpc": "2.0", "id": 1, "result": { "exit_code": 0 } }));
+        assert_eq!(result.unwrap()["exit_code"], 0);
+        let err = answer(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32041, "message": "micro vm not found", "data": { "detail": "x" } },
+        }))
+        .unwrap_err();
+        assert_eq!(
+            (err.code, err.message.as_str()),
+            (-32041, "micro vm not found")
+        );
+        assert_eq!(err.data.unwrap()["detail"], "x");
+    }
+}
@@ -0,0 +1,425 @@
+//! `GET /events/agents/:task_id` streams an agent task as server-sent events
+//! so UIs need not poll `agent.status`. A `status` event carries the task
+//! snapshot, first the current one and then one per status change; the
+//! first change may repeat it. Responses of a running task arrive as
+//! `output` events ([`sandbox::AgentTaskOutput`]): one per agent round, or one per
+//! finished subtask of a fan-out. After the terminal status the stream ends
+//! with `data: [DONE]`.
+//!
+//! Tasks on a runner or on another replica have no local events; their
+//! status is polled every second instead and they send no output. Like
+//! `/notify/ws`, the bearer token may be passed as `?access_token=` since
+//! `EventSource` cannot set headers. Needs `AgentView` and the `streaming`
+//! flag.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures::stream::{self, BoxStream, StreamExt};
+use runner::protocol::Call;
+use sandbox::{AgentDispatcher, AgentEvent, AgentTaskSnapshot, AgentTaskStatus};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use uuid::Uuid;
+
+use crate::errors::ErrorCode;
+use crate::flags::Flag;
+use crate::rest::{error_event, error_response};
+use crate::{authenticate_request, AppState, Peer, Permission, RequestContext, RpcMethodError};
+
+const REMOTE_POLL: Duration = Duration::from_secs(1);
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/events/agents/:task_id", get(stream_task))
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+async fn stream_task(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    Query(query): Query<StreamQuery>,
+    UrlPath(task_id): UrlPath<String>,
+    mut headers: HeaderMap,
+) -> Response {
+    if let Some(token) = query.access_token {
+        match HeaderValue::from_str(&format!("Bearer {token}")) {
+            Ok(value) => {
+                headers.insert(header::AUTHORIZATION, value);
+            }
+            Err(_) => return error_response(RpcMethodError::unauthorized("invalid token")),
+        }
+    }
+    let ctx = match authenticate_request(&state, &headers, peer).await {
+        Ok(ctx) => ctx,
+        Err(err) => return error_response(err),
+    };
+    match open(&state, &ctx, &task_id).await {
+        Ok(events) => {
+            let events = events
+                .chain(stream::once(async { Event::default().data("[DONE]") }))
+                .map(Ok::<_, Infallible>);
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+        Err(err) => error_response(err),
+    }
+}
+
+async fn open(
+    state: &AppState,
+    ctx: &RequestContext,
+    task_id: &str,
+) -> Result<BoxStream<'static, Event>, RpcMethodError> {
+    ctx.require(Permission::AgentView)?;
+    state.flags.require(Flag::Streaming, ctx).await?;
+    let task_id = Uuid::parse_str(task_id).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid task identifier",
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })?;
+    // Subscribe first so a change right after the snapshot is not missed.
+    let events = state.agents.subscribe();
+    if let Some(snapshot) = state.agents.status(&task_id) {
+        let events = local(state.agents.clone(), task_id, snapshot, events);
+        return Ok(events.map(|event| sse_event(&event)).boxed());
+    }
+    drop(events);
+    let snapshot = remote_status(state, ctx, task_id).await?.ok_or_else(|| {
+        RpcMethodError::new(ErrorCode::AgentTaskNotFound, "agent task not found", None)
+    })?;
+    Ok(remote(state.clone(), ctx.clone(), task_id, snapshot))
+}
+
+/// Follows a task of this replica's dispatcher. A receiver that lagged
+/// behind resynchronizes with the current status.
+fn local(
+    agents: Arc<AgentDispatcher>,
+    task_id: Uuid,
+    first: AgentTaskSnapshot,
+    events: Receiver<AgentEvent>,
+) -> BoxStream<'static, AgentEvent> {
+    let done = first.status.is_terminal();
+    let changes = stream::unfold((events, done), move |(mut events, done)| {
+        let agents = agents.clone();
+        async move {
+            if done {
+                return None;
+            }
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) if event.task_id() != task_id => continue,
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        AgentEvent::Status(Box::new(agents.status(&task_id)?))
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                let done =
+                    matches!(&event, AgentEvent::Status(snapshot) if snapshot.status.is_terminal());
+                return Some((event, (events, done)));
+            }
+        }
+    });
+    stream::once(async move { AgentEvent::Status(Box::new(first)) })
+        .chain(changes)
+        .boxed()
+}
+
+/// Polls a task running elsewhere, sending its status whenever it changed.
+fn remote(
+    state: AppState,
+    ctx: RequestContext,
+    task_id: Uuid,
+    first: AgentTaskSnapshot,
+) -> BoxStream<'static, Event> {
+    poll(first, REMOTE_POLL, move || {
+        let (state, ctx) = (state.clone(), ctx.clone());
+        async move { remote_status(&state, &ctx, task_id).await }
+    })
+    .map(|snapshot| match snapshot {
+        Ok(snapshot) => status_event(&snapshot),
+        Err(err) => error_event(&err),
+    })
+    .boxed()
+}
+
+/// Sends `first`, then asks `status` every `interval` and sends each
+/// snapshot whose status differs from the last one sent. Ends after a
+/// terminal status, an error or when the task is gone.
+fn poll<F, Fut>(
+    first: AgentTaskSnapshot,
+    interval: Duration,
+    status: F,
+) -> BoxStream<'static, Result<AgentTaskSnapshot, RpcMethodError>>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<AgentTaskSnapshot>, RpcMethodError>> + Send,
+{
+    let status = Arc::new(status);
+    let last = Some(first.status).filter(|status| !status.is_terminal());
+    let changes = stream::unfold(last, move |last: Option<AgentTaskStatus>| {
+        let status = status.clone();
+        async move {
+            let last = last?;
+            loop {
+                tokio::time::sleep(interval).await;
+                let snapshot = match status().await {
+                    Ok(Some(snapshot)) => snapshot,
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                if snapshot.status != last {
+                    let next = Some(snapshot.status).filter(|status| !status.is_terminal());
+                    return Some((Ok(snapshot), next));
+                }
+            }
+        }
+    });
+    stream::once(async move { Ok(first) })
+        .chain(changes)
+        .boxed()
+}
+
+/// Status of a task pinned to a runner or owned by another replica;
+/// `None` when neither knows it.
+async fn remote_status(
+    state: &AppState,
+    ctx: &RequestContext,
+    task_id: Uuid,
+) -> Result<Option<AgentTaskSnapshot>, RpcMethodError> {
+    let status = match state.runners.pinned(&task_id) {
+        Some(runner) => {
+            state
+                .runners
+                .call(runner, Call::AgentStatus { task_id })
+                .await?
+        }
+        None => {
+            let params = json!({ "task_id": task_id });
+            match state
+                .affinity
+                .forward(ctx, "agent.status", Some(&params))
+                .await?
+            {
+                Some(status) => status,
+                None => return Ok(None),
+            }
+        }
+    };
+    serde_json::from_value::<AgentTaskSnapshot>(status)
+        .map(Some)
+        .map_err(|err| RpcMethodError::internal(&format!("invalid task snapshot: {err}")))
+}
+
+fn status_event(snapshot: &AgentTaskSnapshot) -> Event {
+    event("status", serde_json::to_value(snapshot))
+}
+
+fn sse_event(event: &AgentEvent) -> Event {
+    match event {
+        AgentEvent::Status(snapshot) => status_event(snapshot),
+        AgentEvent::Output(output) => self::event("output", serde_json::to_value(output)),
+    }
+}
+
+fn event(name: &str, data: serde_json::Result<Value>) -> Event {
+    Event::default()
+        .event(name)
+        .data(data.expect("serialize agent event").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mock_llm::{Endpoint, MockLlmServer, Reply};
+    use sandbox::{
+        AgentContext, AgentDispatchRequest, AgentDispatcherConfig, AgentKind, AgentTaskSubmission,
+    };
+    use tokio::sync::broadcast;
+
+    /// A dispatcher whose one task will answer with `summary`.
+    fn dispatched(
+        server: &MockLlmServer,
+        summary: &str,
+    ) -> (
+        Arc<AgentDispatcher>,
+        Receiver<AgentEvent>,
+        AgentTaskSubmission,
+    ) {
+        server.push(Endpoint::Chat, Reply::json(json!({ "summary": summary })));
+        let agents = Arc::new(
+            AgentDispatcher::new(AgentDispatcherConfig::new(server.url(), "mock-model")).unwrap(),
+        );
+        let events = agents.subscribe();
+        let submission = agents
+            .dispatch(AgentDispatchRequest {
+                agent: AgentKind::Code,
+                objective: "write a parser".to_string(),
+                context: AgentContext::default(),
+                model: None,
+                metadata: None,
+                parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
+            })
+            .unwrap();
+        (agents, events, submission)
+    }
+
+    fn with_status(snapshot: &AgentTaskSnapshot, status: AgentTaskStatus) -> AgentTaskSnapshot {
+        AgentTaskSnapshot {
+            status,
+            ..snapshot.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn local_tasks_stream_status_and_output_until_terminal() {
+        let server = MockLlmServer::start().await.unwrap();
+        let (agents, events, submission) = dispatched(&server, "wrote the parser");
+
+        let stream = local(agents.clone(), submission.id, submission.status, events);
+        let seen: Vec<AgentEvent> = tokio::time::timeout(Duration::from_secs(5), stream.collect())
+            .await
+            .expect("stream ends after the terminal status");
+        let kinds: Vec<String> = seen
+            .iter()
+            .map(|event| match event {
+                AgentEvent::Status(snapshot) => format!("{:?}", snapshot.status),
+                AgentEvent::Output(output) => format!("output: {}", output.summary),
+            })
+            .collect();
+        assert_eq!(kinds.first().map(String::as_str), Some("Pending"));
+        assert_eq!(kinds.last().map(String::as_str), Some("Completed"));
+        assert!(kinds.contains(&"output: wrote the parser".to_string()));
+        assert!(seen.iter().all(|event| event.task_id() == submission.id));
+
+        // A finished task sends its final status alone.
+        let finished = agents.status(&submission.id).unwrap();
+        let replay: Vec<AgentEvent> =
+            local(agents.clone(), submission.id, finished, agents.subscribe())
+                .collect()
+                .await;
+        assert_eq!(replay.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lagged_receivers_resync_with_the_current_status() {
+        let server = MockLlmServer::start().await.unwrap();
+        let (agents, _, submission) = dispatched(&server, "wrote the parser");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !agents.status(&submission.id).unwrap().status.is_terminal() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("task finishes");
+
+        // Two events into a channel of one: the receiver has missed one.
+        let (sender, events) = broadcast::channel(1);
+        for _ in 0..2 {
+            let pending = Box::new(submission.status.clone());
+            sender.send(AgentEvent::Status(pending)).unwrap();
+        }
+        let seen: Vec<AgentEvent> = tokio::time::timeout(
+            Duration::from_secs(5),
+            local(agents.clone(), submission.id, submission.status, events).collect(),
+        )
+        .await
+        .expect("stream ends after the resync");
+        let statuses: Vec<AgentTaskStatus> = seen
+            .iter()
+            .map(|event| match event {
+                AgentEvent::Status(snapshot) => snapshot.status,
+                AgentEvent::Output(_) => panic!("lagged output is not replayed"),
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            [AgentTaskStatus::Pending, AgentTaskStatus::Completed]
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_tasks_are_polled_until_terminal() {
+        let server = MockLlmServer::start().await.unwrap();
+        let (_agents, _, submission) = dispatched(&server, "wrote the parser");
+        let first = submission.status;
+        let answers = Arc::new(parking_lot::Mutex::new(vec![
+            with_status(&first, AgentTaskStatus::Completed),
+            with_status(&first, AgentTaskStatus::Running),
+            with_status(&first, AgentTaskStatus::Running),
+            with_status(&first, AgentTaskStatus::Pending),
+        ]));
+        let polls = answers.clone();
+        let seen: Vec<AgentTaskStatus> = poll(first.clone(), Duration::from_millis(1), move || {
+            let answer = polls.lock().pop();
+            async move { Ok(answer) }
+        })
+        .map(|snapshot| snapshot.unwrap().status)
+        .collect()
+        .await;
+        // Unchanged statuses are not repeated and polling stops at the end.
+        assert_eq!(
+            seen,
+            [
+                AgentTaskStatus::Pending,
+                AgentTaskStatus::Running,
+                AgentTaskStatus::Completed
+            ]
+        );
+        assert!(answers.lock().is_empty());
+
+        // A task that disappears ends the stream; a failed poll ends it
+        // with the error.
+        let gone: Vec<_> = poll(first.clone(), Duration::from_millis(1), || async {
+            Ok(None)
+        })
+        .collect()
+        .await;
+        assert_eq!(gone.len(), 1);
+        let failed: Vec<_> = poll(first.clone(), Duration::from_millis(1), || async {
+            Err(RpcMethodError::new(
+                ErrorCode::AgentTaskNotFound,
+                "agent task not found",
+                None,
+            ))
+        })
+        .collect()
+        .await;
+        assert_eq!(failed.len(), 2);
+        assert_eq!(
+            failed[1].as_ref().unwrap_err().code,
+            ErrorCode::AgentTaskNotFound.code()
+        );
+
+        // A finished task sends its status without polling.
+        let finished = with_status(&first, AgentTaskStatus::Failed);
+        let replay: Vec<_> = poll(finished, Duration::from_millis(1), || async {
+            panic!("finished tasks are not polled")
+        })
+        .collect()
+        .await;
+        assert_eq!(replay.len(), 1);
+    }
+}
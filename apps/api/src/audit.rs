@@ -0,0 +1,499 @@
+//! Audit trail for authenticated RPC calls. Handlers enqueue events on a
+//! bounded channel; a single writer task drains it in batches. When the
+//! queue is full callers wait up to `enqueue_timeout` before the event is
+//! dropped and counted, so a slow database throttles request handling
+//! without ever stalling it indefinitely. Each batch also updates the per-key
+//! daily counters in `api_key_usage` (migration 028).
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, NaiveDate, Utc};
+use hex::encode as hex_encode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{tenant, RequestContext, RpcMethodError};
+
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+const DEFAULT_BATCH_SIZE: usize = 128;
+const DEFAULT_ENQUEUE_TIMEOUT_MS: u64 = 50;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_QUERY_PAGE: i64 = 50;
+const MAX_QUERY_PAGE: i64 = 500;
+
+#[derive(Debug, Clone)]
+pub(crate) struct AuditEvent {
+    user_id: i32,
+    api_key_id: Option<Uuid>,
+    method: String,
+    params_digest: Option<String>,
+    error_code: Option<i64>,
+    latency_ms: i64,
+    client_ip: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub(crate) fn new(
+        ctx: &RequestContext,
+        method: &str,
+        params_digest: Option<String>,
+        result: &Result<Value, RpcMethodError>,
+        latency: Duration,
+    ) -> Self {
+        Self {
+            user_id: ctx.user_id,
+            api_key_id: ctx.api_key_id,
+            method: method.to_string(),
+            params_digest,
+            error_code: result.as_ref().err().map(|err| err.code),
+            latency_ms: latency.as_millis().min(i64::MAX as u128) as i64,
+            client_ip: ctx.client_ip.clone(),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Hex SHA-256 of the serialized params. Object keys serialize in sorted
+/// order, so equal params always produce the same digest.
+pub(crate) fn params_digest(params: Option<&Value>) -> Option<String> {
+    let params = params?;
+    let bytes = serde_json::to_vec(params).ok()?;
+    Some(hex_encode(Sha256::digest(bytes)))
+}
+
+/// Resolves the caller address. `X-Forwarded-For` is only honoured when the
+/// api runs behind a trusted proxy.
+pub(crate) fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_forwarded: bool,
+) -> Option<String> {
+    if trust_forwarded {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+        if let Some(ip) = forwarded {
+            return Some(ip.to_string());
+        }
+    }
+    peer.map(|addr| addr.ip().to_string())
+}
+
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    tx: mpsc::Sender<AuditEvent>,
+    enqueue_timeout: Duration,
+    trust_forwarded: bool,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Handle to the writer task; `shutdown` flushes whatever is still queued.
+pub(crate) struct AuditWriter {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl AuditWriter {
+    pub(crate) async fn shutdown(self) {
+        let _ = self.stop.send(());
+        if let Err(err) = self.handle.await {
+            error!(error = %err, "audit writer panicked");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AuditConfig {
+    capacity: usize,
+    batch_size: usize,
+    enqueue_timeout: Duration,
+    trust_forwarded: bool,
+}
+
+impl AuditConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config
+                .get("AUDIT_QUEUE_CAPACITY", DEFAULT_QUEUE_CAPACITY)
+                .max(1),
+            batch_size: config.get("AUDIT_BATCH_SIZE", DEFAULT_BATCH_SIZE).max(1),
+            enqueue_timeout: config.millis("AUDIT_ENQUEUE_TIMEOUT_MS", DEFAULT_ENQUEUE_TIMEOUT_MS),
+            trust_forwarded: config.get("AUDIT_TRUST_FORWARDED_FOR", false),
+        }
+    }
+}
+
+impl AuditLog {
+    pub(crate) fn spawn(pool: PgPool, config: AuditConfig) -> (Self, AuditWriter) {
+        let (log, rx) = Self::channel(
+            config.capacity,
+            config.enqueue_timeout,
+            config.trust_forwarded,
+        );
+        let (stop, stop_rx) = oneshot::channel();
+        let handle = tokio::spawn(run_writer(pool, rx, stop_rx, config.batch_size));
+        (log, AuditWriter { stop, handle })
+    }
+
+    fn channel(
+        capacity: usize,
+        enqueue_timeout: Duration,
+        trust_forwarded: bool,
+    ) -> (Self, mpsc::Receiver<AuditEvent>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let log = Self {
+            tx,
+            enqueue_timeout,
+            trust_forwarded,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (log, rx)
+    }
+
+    pub(crate) fn trust_forwarded(&self) -> bool {
+        self.trust_forwarded
+    }
+
+    pub(crate) async fn record(&self, event: AuditEvent) {
+        let event = match self.tx.try_send(event) {
+            Ok(()) => return,
+            Err(mpsc::error::TrySendError::Full(event)) => event,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        if self
+            .tx
+            .send_timeout(event, self.enqueue_timeout)
+            .await
+            .is_err()
+        {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(dropped, "audit queue full; event dropped");
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn run_writer(
+    pool: PgPool,
+    mut rx: mpsc::Receiver<AuditEvent>,
+    mut stop: oneshot::Receiver<()>,
+    batch_size: usize,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(event) => {
+                    batch.push(event);
+                    if batch.len() >= batch_size {
+                        flush(&pool, &mut batch).await;
+                    }
+                }
+                None => break,
+            },
+            _ = ticker.tick() => flush(&pool, &mut batch).await,
+            _ = &mut stop => {
+                rx.close();
+                while let Some(event) = rx.recv().await {
+                    batch.push(event);
+                    if batch.len() >= batch_size {
+                        flush(&pool, &mut batch).await;
+                    }
+                }
+                break;
+            }
+        }
+    }
+    flush(&pool, &mut batch).await;
+}
+
+async fn flush(pool: &PgPool, batch: &mut Vec<AuditEvent>) {
+    if batch.is_empty() {
+        return;
+    }
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "INSERT INTO audit_log (user_id, api_key_id, method, params_digest, outcome, error_code, latency_ms, client_ip, created_at) ",
+    );
+    builder.push_values(batch.iter(), |mut row, event| {
+        row.push_bind(event.user_id)
+            .push_bind(event.api_key_id)
+            .push_bind(&event.method)
+            .push_bind(&event.params_digest)
+            .push_bind(if event.error_code.is_some() {
+                "error"
+            } else {
+                "ok"
+            })
+            .push_bind(event.error_code)
+            .push_bind(event.latency_ms)
+            .push_bind(&event.client_ip)
+            .push_bind(event.created_at);
+    });
+    if let Err(err) = builder.build().execute(pool).await {
+        error!(error = %err, events = batch.len(), "failed to write audit events");
+    }
+    record_key_usage(pool, batch).await;
+    batch.clear();
+}
+
+/// Counters for one key, day and method within a batch.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct KeyUsage {
+    requests: i64,
+    errors: i64,
+    total_latency_ms: i64,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+fn key_usage(batch: &[AuditEvent]) -> BTreeMap<(Uuid, NaiveDate, &str), KeyUsage> {
+    let mut usage: BTreeMap<_, KeyUsage> = BTreeMap::new();
+    for event in batch {
+        let Some(api_key_id) = event.api_key_id else {
+            continue;
+        };
+        let entry = usage
+            .entry((
+                api_key_id,
+                event.created_at.date_naive(),
+                event.method.as_str(),
+            ))
+            .or_default();
+        entry.requests += 1;
+        entry.errors += i64::from(event.error_code.is_some());
+        entry.total_latency_ms += event.latency_ms;
+        entry.last_used_at = entry.last_used_at.max(Some(event.created_at));
+    }
+    usage
+}
+
+async fn record_key_usage(pool: &PgPool, batch: &[AuditEvent]) {
+    let usage = key_usage(batch);
+    if usage.is_empty() {
+        return;
+    }
+    // Keys deleted since the call are skipped instead of failing the batch.
+    let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "INSERT INTO api_key_usage (api_key_id, day, method, requests, errors, total_latency_ms, last_used_at) \
+         SELECT v.* FROM (",
+    );
+    builder.push_values(
+        usage.iter(),
+        |mut row, ((api_key_id, day, method), counts)| {
+            row.push_bind(*api_key_id)
+                .push_bind(*day)
+                .push_bind(*method)
+                .push_bind(counts.requests)
+                .push_bind(counts.errors)
+                .push_bind(counts.total_latency_ms)
+                .push_bind(counts.last_used_at);
+        },
+    );
+    builder.push(
+        ") AS v(api_key_id, day, method, requests, errors, total_latency_ms, last_used_at) \
+         WHERE EXISTS (SELECT 1 FROM api_keys WHERE api_keys.id = v.api_key_id) \
+         ON CONFLICT (api_key_id, day, method) DO UPDATE SET \
+            requests = api_key_usage.requests + EXCLUDED.requests, \
+            errors = api_key_usage.errors + EXCLUDED.errors, \
+            total_latency_ms = api_key_usage.total_latency_ms + EXCLUDED.total_latency_ms, \
+            last_used_at = GREATEST(api_key_usage.last_used_at, EXCLUDED.last_used_at)",
+    );
+    if let Err(err) = builder.build().execute(pool).await {
+        error!(error = %err, rows = usage.len(), "failed to update api key usage");
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AuditQueryParams {
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    outcome: Option<AuditOutcome>,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum AuditOutcome {
+    Ok,
+    Error,
+}
+
+impl AuditOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditOutcome::Ok => "ok",
+            AuditOutcome::Error => "error",
+        }
+    }
+}
+
+/// Entries of the caller's tenant; calls without a user belong to the
+/// default tenant.
+pub(crate) async fn query(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: AuditQueryParams,
+) -> Result<Value, RpcMethodError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_QUERY_PAGE)
+        .clamp(1, MAX_QUERY_PAGE);
+    let rows = sqlx::query(
+        "SELECT id, user_id, api_key_id, method, params_digest, outcome, error_code, latency_ms, client_ip, created_at \
+         FROM audit_log \
+         WHERE ($1::int IS NULL OR user_id = $1) \
+           AND ($2::text IS NULL OR method = $2) \
+           AND ($3::varchar IS NULL OR outcome = $3) \
+           AND ($4::timestamptz IS NULL OR created_at >= $4) \
+           AND ($5::timestamptz IS NULL OR created_at < $5) \
+           AND ($6::bigint IS NULL OR id < $6) \
+           AND (user_id IN (SELECT id FROM users WHERE tenant_id = $8) \
+                OR (user_id IS NULL AND $9)) \
+         ORDER BY id DESC LIMIT $7",
+    )
+    .bind(params.user_id)
+    .bind(params.method)
+    .bind(params.outcome.map(AuditOutcome::as_str))
+    .bind(params.since)
+    .bind(params.until)
+    .bind(params.cursor)
+    .bind(limit)
+    .bind(ctx.tenant_id)
+    .bind(ctx.tenant_id == tenant::DEFAULT_TENANT)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to query audit log: {err}")))?;
+
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    let entries: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": row.get::<i64, _>("id"),
+                "user_id": row.get::<i32, _>("user_id"),
+                "api_key_id": row.get::<Option<Uuid>, _>("api_key_id").map(|id| id.to_string()),
+                "method": row.get::<String, _>("method"),
+                "params_digest": row.get::<Option<String>, _>("params_digest"),
+                "outcome": row.get::<String, _>("outcome"),
+                "error_code": row.get::<Option<i64>, _>("error_code"),
+                "latency_ms": row.get::<i64, _>("latency_ms"),
+                "client_ip": row.get::<Option<String>, _>("client_ip"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(json!({ "entries": entries, "next_cursor": next_cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> AuditEvent {
+        AuditEvent {
+            user_id: 1,
+            api_key_id: None,
+            method: "fs.read".to_string(),
+            params_digest: None,
+            error_code: None,
+            latency_ms: 1,
+            client_ip: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn key_usage_groups_key_calls_by_day_and_method() {
+        let key = Uuid::new_v4();
+        let at = Utc::now();
+        let keyed = |method: &str, failed: bool| AuditEvent {
+            api_key_id: Some(key),
+            created_at: at,
+            method: method.to_string(),
+            error_code: failed.then_some(-32602),
+            latency_ms: 10,
+            ..event()
+        };
+        let batch = vec![
+            keyed("fs.read", false),
+            keyed("fs.read", true),
+            keyed("run.exec", false),
+            event(),
+        ];
+        let usage = key_usage(&batch);
+        assert_eq!(usage.len(), 2);
+        let day = at.date_naive();
+        let reads = &usage[&(key, day, "fs.read")];
+        assert_eq!(
+            (reads.requests, reads.errors, reads.total_latency_ms),
+            (2, 1, 20)
+        );
+        assert_eq!(usage[&(key, day, "run.exec")].errors, 0);
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_after_timeout() {
+        let (log, mut rx) = AuditLog::channel(1, Duration::from_millis(10), false);
+        log.record(event()).await;
+        log.record(event()).await;
+        assert_eq!(log.dropped(), 1);
+        assert!(rx.recv().await.is_some());
+        log.record(event()).await;
+        assert_eq!(log.dropped(), 1);
+    }
+
+    #[test]
+    fn digest_ignores_key_order_and_client_ip_respects_trust() {
+        let a = serde_json::from_str::<Value>(r#"{"path":"a","limit":1}"#).unwrap();
+        let b = serde_json::from_str::<Value>(r#"{"limit":1,"path":"a"}"#).unwrap();
+        assert_eq!(params_digest(Some(&a)), params_digest(Some(&b)));
+        assert!(params_digest(None).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 10.0.0.1".parse().unwrap());
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        assert_eq!(
+            client_ip(&headers, Some(peer), true).as_deref(),
+            Some("203.0.113.9")
+        );
+        assert_eq!(
+            client_ip(&headers, Some(peer), false).as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+}
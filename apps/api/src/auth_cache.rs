@@ -0,0 +1,222 @@
+//! Read-through cache of the database half of authentication: the user row
+//! behind a token (keyed by `jti`) or an API key (keyed by its hash), so a
+//! chatty client costs one lookup per `AUTH_CACHE_TTL_SECS` instead of one
+//! per call. Signatures, expiry and `jti` revocation are still checked on
+//! every request, and permissions come from the RBAC cache, which has its
+//! own invalidation.
+//!
+//! Changes that affect authentication are announced by database triggers
+//! on `auth_changes` (migration 033), whichever service makes them, and drop
+//! the user's entries on every instance. The TTL only bounds how long a
+//! missed announcement can go unnoticed; the cache is cleared whenever the
+//! listener reconnects. A cached API key's `last_used_at` is refreshed once
+//! per miss, not on every call.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use auth_core::KeyScope;
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use serde_json::Value;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::metrics::AppMetrics;
+use crate::Role;
+
+const CHANNEL: &str = "auth_changes";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AuthCacheConfig {
+    capacity: u64,
+    /// Zero turns the cache off.
+    ttl: Duration,
+}
+
+impl AuthCacheConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config.get("AUTH_CACHE_CAPACITY", 10_000),
+            ttl: config.secs("AUTH_CACHE_TTL_SECS", 5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Credential {
+    /// The `jti` of a verified token.
+    Token(String),
+    /// The hash of an API key.
+    ApiKey(String),
+}
+
+/// What authentication read from the database, minus permissions.
+#[derive(Debug, Clone)]
+pub(crate) struct Identity {
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    pub(crate) tenant_id: i32,
+    pub(crate) token_balance: i64,
+    pub(crate) api_key_id: Option<Uuid>,
+    pub(crate) key_expires_at: Option<DateTime<Utc>>,
+    /// The scope of the API key or service token.
+    pub(crate) scope: Option<KeyScope>,
+}
+
+impl Identity {
+    /// Whether the API key expired since it was cached.
+    pub(crate) fn key_expired(&self) -> bool {
+        self.key_expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthCache {
+    pool: PgPool,
+    cache: Option<Cache<Credential, Arc<Identity>>>,
+    metrics: Arc<AppMetrics>,
+}
+
+impl AuthCache {
+    pub(crate) fn new(pool: PgPool, config: AuthCacheConfig, metrics: Arc<AppMetrics>) -> Self {
+        let cache = (!config.ttl.is_zero()).then(|| {
+            Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .support_invalidation_closures()
+                .build()
+        });
+        Self {
+            pool,
+            cache,
+            metrics,
+        }
+    }
+
+    pub(crate) async fn get(&self, credential: &Credential) -> Option<Arc<Identity>> {
+        let cache = self.cache.as_ref()?;
+        let identity = cache.get(credential).await;
+        match identity {
+            Some(_) => self.metrics.auth_cache.hit(),
+            None => self.metrics.auth_cache.miss(),
+        }
+        identity
+    }
+
+    pub(crate) async fn insert(&self, credential: Credential, identity: Arc<Identity>) {
+        if let Some(cache) = &self.cache {
+            cache.insert(credential, identity).await;
+        }
+    }
+
+    /// Drops every cached credential of `user_id` on this instance.
+    pub(crate) fn invalidate_user(&self, user_id: i32) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        self.metrics.auth_cache_invalidation();
+        if let Err(err) =
+            cache.invalidate_entries_if(move |_, identity| identity.user_id == user_id)
+        {
+            warn!(user_id, error = %err, "failed to invalidate cached authentication");
+            cache.invalidate_all();
+        }
+    }
+
+    /// Follows `auth_changes` announcements until the process exits.
+    pub(crate) fn spawn_listener(&self) -> Option<JoinHandle<()>> {
+        self.cache.as_ref()?;
+        let cache = self.clone();
+        Some(tokio::spawn(async move {
+            loop {
+                if let Err(err) = cache.listen().await {
+                    warn!(error = %err, "auth change listener failed, reconnecting");
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }))
+    }
+
+    async fn listen(&self) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        // Announcements sent while disconnected are lost.
+        if let Some(cache) = &self.cache {
+            cache.invalidate_all();
+        }
+        loop {
+            let announcement = listener.recv().await?;
+            match changed_user(announcement.payload()) {
+                Some(user_id) => self.invalidate_user(user_id),
+                None => warn!(payload = announcement.payload(), "ignoring auth change"),
+            }
+        }
+    }
+}
+
+fn changed_user(payload: &str) -> Option<i32> {
+    let payload: Value = serde_json::from_str(payload).ok()?;
+    i32::try_from(payload.get("user_id")?.as_i64()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(user_id: i32) -> Arc<Identity> {
+        Arc::new(Identity {
+            user_id,
+            username: format!("user{user_id}"),
+            role: Role::Developer,
+            tenant_id: 1,
+            token_balance: 10,
+            api_key_id: None,
+            key_expires_at: None,
+            scope: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn invalidation_drops_only_the_users_entries() {
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let config = AuthCacheConfig {
+            capacity: 100,
+            ttl: Duration::from_secs(60),
+        };
+        let metrics = Arc::new(AppMetrics::default());
+        let cache = AuthCache::new(pool, config, metrics.clone());
+        let token = Credential::Token("t-1".into());
+        let key = Credential::ApiKey("hash".into());
+        let other = Credential::Token("t-2".into());
+        assert!(cache.get(&token).await.is_none());
+        cache.insert(token.clone(), identity(7)).await;
+        cache.insert(key.clone(), identity(7)).await;
+        cache.insert(other.clone(), identity(8)).await;
+        assert_eq!(cache.get(&key).await.unwrap().user_id, 7);
+
+        cache.invalidate_user(7);
+        assert!(cache.get(&token).await.is_none());
+        assert!(cache.get(&key).await.is_none());
+        assert_eq!(cache.get(&other).await.unwrap().user_id, 8);
+
+        let text = metrics.render(false);
+        assert!(text.contains("api_cache_requests_total{cache=\"auth\",result=\"hit\"} 2"));
+        assert!(text.contains("api_cache_requests_total{cache=\"auth\",result=\"miss\"} 3"));
+        assert!(text.contains("api_auth_cache_invalidations_total 1"));
+    }
+
+    #[test]
+    fn parses_announcements() {
+        assert_eq!(changed_user(r#"{"user_id": 42}"#), Some(42));
+        assert_eq!(changed_user(r#"{"user_id": null}"#), None);
+        assert_eq!(changed_user("nonsense"), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Metered billing. Every billable call appends a row to `billing_ledger`
+//! and adjusts `users.token_balance` in the same statement, so the balance
+//! and the ledger cannot disagree.
+//!
+//! LLM calls served by the local LLM server are settled by it (it deducts
+//! upstream usage for the `X-User-Id` it receives); the ledger still records
+//! them so `billing.usage` reflects every source of spend. Calls routed to
+//! external providers are deducted here from the reported `usage`.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::types::Json;
+use sqlx::{PgPool, Row};
+use tracing::error;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{RequestContext, RpcMethodError};
+
+const DEFAULT_AGENT_TASK_TOKENS: i64 = 500;
+const DEFAULT_SANDBOX_SECOND_TOKENS: i64 = 10;
+const DEFAULT_LEDGER_PAGE: i64 = 50;
+const MAX_LEDGER_PAGE: i64 = 500;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Pricing {
+    agent_task_tokens: i64,
+    sandbox_second_tokens: i64,
+}
+
+impl Pricing {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            agent_task_tokens: config
+                .get("BILLING_AGENT_TASK_TOKENS", DEFAULT_AGENT_TASK_TOKENS)
+                .max(0),
+            sandbox_second_tokens: config
+                .get(
+                    "BILLING_SANDBOX_SECOND_TOKENS",
+                    DEFAULT_SANDBOX_SECOND_TOKENS,
+                )
+                .max(0),
+        }
+    }
+}
+
+/// A single billable event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Charge {
+    /// Tokens reported in the upstream `usage` block. `settled` calls were
+    /// already deducted by the provider.
+    Llm { tokens: i64, settled: bool },
+    /// Agent tasks started by one dispatch, including fan-out subtasks.
+    AgentTasks(i64),
+    /// Sandbox execution time, billed per started second.
+    SandboxTime(Duration),
+}
+
+impl Charge {
+    pub(crate) fn llm(response: &Value, settled: bool) -> Self {
+        Charge::Llm {
+            tokens: usage_tokens(response),
+            settled,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Charge::Llm { .. } => "llm",
+            Charge::AgentTasks(_) => "agent",
+            Charge::SandboxTime(_) => "sandbox",
+        }
+    }
+
+    fn units(&self) -> i64 {
+        match self {
+            Charge::Llm { tokens, .. } => *tokens,
+            Charge::AgentTasks(count) => *count,
+            Charge::SandboxTime(duration) => {
+                let millis = duration.as_millis().min(i64::MAX as u128) as i64;
+                (millis + 999) / 1000
+            }
+        }
+    }
+
+    fn tokens(&self, pricing: &Pricing) -> i64 {
+        match self {
+            Charge::Llm { tokens, .. } => *tokens,
+            Charge::AgentTasks(_) => self.units().saturating_mul(pricing.agent_task_tokens),
+            Charge::SandboxTime(_) => self.units().saturating_mul(pricing.sandbox_second_tokens),
+        }
+    }
+
+    /// Tokens this service removes from the balance itself.
+    fn deducted(&self, pricing: &Pricing) -> i64 {
+        match self {
+            Charge::Llm { settled: true, .. } => 0,
+            _ => self.tokens(pricing),
+        }
+    }
+}
+
+/// Reads `usage.total_tokens`, falling back to the prompt and completion
+/// counts for responses that omit the total.
+fn usage_tokens(response: &Value) -> i64 {
+    let usage = match response.get("usage") {
+        Some(usage) => usage,
+        None => return 0,
+    };
+    let field = |name: &str| usage.get(name).and_then(Value::as_i64).unwrap_or(0);
+    match usage.get("total_tokens").and_then(Value::as_i64) {
+        Some(total) => total,
+        None => field("prompt_tokens") + field("completion_tokens"),
+    }
+    .max(0)
+}
+
+#[derive(Clone)]
+pub(crate) struct Billing {
+    pool: PgPool,
+    pricing: Pricing,
+}
+
+impl Billing {
+    pub(crate) fn new(pool: PgPool, pricing: Pricing) -> Self {
+        Self { pool, pricing }
+    }
+
+    /// Records `charge` against the caller. The work being billed has already
+    /// happened, so failures are logged rather than surfaced; the balance may
+    /// go negative, which `ensure_tokens` rejects on the next call.
+    pub(crate) async fn charge(&self, ctx: &RequestContext, method: &str, charge: Charge) {
+        let tokens = charge.tokens(&self.pricing);
+        if tokens == 0 && charge.units() == 0 {
+            return;
+        }
+        let result = sqlx::query(
+            "WITH updated AS ( \
+                UPDATE users SET token_balance = token_balance - $2 WHERE id = $1 \
+                RETURNING token_balance \
+            ) \
+            INSERT INTO billing_ledger (user_id, kind, method, units, tokens, balance_after, metadata) \
+            SELECT $1, $3, $4, $5, $6, token_balance, $7 FROM updated",
+        )
+        .bind(ctx.user_id)
+        .bind(charge.deducted(&self.pricing))
+        .bind(charge.kind())
+        .bind(method)
+        .bind(charge.units())
+        .bind(tokens)
+        .bind(Json(json!({ "auth": ctx.auth_source() })))
+        .execute(&self.pool)
+        .await;
+        if let Err(err) = result {
+            error!(user_id = ctx.user_id, method, error = %err, "failed to record billing charge");
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct BillingUsageParams {
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct BillingLedgerParams {
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+/// Only admins may inspect another user's billing or quota, and only for
+/// users of their own tenant.
+pub(crate) async fn target_user(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    user_id: Option<i32>,
+) -> Result<i32, RpcMethodError> {
+    match user_id {
+        Some(id) if id != ctx.user_id => {
+            if !ctx.is_admin() {
+                return Err(RpcMethodError::forbidden("insufficient permissions"));
+            }
+            let known: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND tenant_id = $2)",
+            )
+            .bind(id)
+            .bind(ctx.tenant_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|err| RpcMethodError::internal(&format!("failed to load user: {err}")))?;
+            if !known {
+                return Err(RpcMethodError::new(
+                    ErrorCode::UserNotFound,
+                    "user not found",
+                    None,
+                ));
+            }
+            Ok(id)
+        }
+        _ => Ok(ctx.user_id),
+    }
+}
+
+pub(crate) async fn usage(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: BillingUsageParams,
+) -> Result<Value, RpcMethodError> {
+    let user_id = target_user(pool, ctx, params.user_id).await?;
+    let balance: Option<i64> = sqlx::query_scalar("SELECT token_balance FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load balance: {err}")))?;
+    let balance = balance
+        .ok_or_else(|| RpcMethodError::new(ErrorCode::InvalidParams, "unknown user", None))?;
+    let rows = sqlx::query(
+        "SELECT kind, COUNT(*) AS entries, SUM(units)::BIGINT AS units, SUM(tokens)::BIGINT AS tokens \
+         FROM billing_ledger \
+         WHERE user_id = $1 \
+           AND ($2::timestamptz IS NULL OR created_at >= $2) \
+           AND ($3::timestamptz IS NULL OR created_at < $3) \
+         GROUP BY kind ORDER BY kind",
+    )
+    .bind(user_id)
+    .bind(params.since)
+    .bind(params.until)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load usage: {err}")))?;
+
+    let mut total = 0i64;
+    let breakdown: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            let tokens: i64 = row.get("tokens");
+            total += tokens;
+            json!({
+                "kind": row.get::<String, _>("kind"),
+                "entries": row.get::<i64, _>("entries"),
+                "units": row.get::<i64, _>("units"),
+                "tokens": tokens,
+            })
+        })
+        .collect();
+    Ok(json!({
+        "user_id": user_id,
+        "balance": balance,
+        "total_tokens": total,
+        "breakdown": breakdown,
+    }))
+}
+
+pub(crate) async fn ledger(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: BillingLedgerParams,
+) -> Result<Value, RpcMethodError> {
+    let user_id = target_user(pool, ctx, params.user_id).await?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LEDGER_PAGE)
+        .clamp(1, MAX_LEDGER_PAGE);
+    let rows = sqlx::query(
+        "SELECT id, kind, method, units, tokens, balance_after, created_at \
+         FROM billing_ledger \
+         WHERE user_id = $1 \
+           AND ($2::varchar IS NULL OR kind = $2) \
+           AND ($3::bigint IS NULL OR id < $3) \
+         ORDER BY id DESC LIMIT $4",
+    )
+    .bind(user_id)
+    .bind(params.kind)
+    .bind(params.cursor)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load ledger: {err}")))?;
+
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    let entries: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "id": row.get::<i64, _>("id"),
+                "kind": row.get::<String, _>("kind"),
+                "method": row.get::<String, _>("method"),
+                "units": row.get::<i64, _>("units"),
+                "tokens": row.get::<i64, _>("tokens"),
+                "balance_after": row.get::<i64, _>("balance_after"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(json!({ "entries": entries, "next_cursor": next_cursor }))
+}
+
+/// Sets a user's balance outright, e.g. after a manual top-up. The difference
+/// is recorded as an `adjustment` entry with the same sign as a charge, so a
+/// credit shows up as negative `tokens`. Returns `None` for unknown users and
+/// users of other tenants.
+pub(crate) async fn set_balance(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    method: &str,
+    user_id: i32,
+    balance: i64,
+) -> Result<Option<Value>, RpcMethodError> {
+    let row = sqlx::query(
+        "WITH previous AS ( \
+            SELECT token_balance FROM users WHERE id = $1 AND tenant_id = $5 FOR UPDATE \
+        ), updated AS ( \
+            UPDATE users SET token_balance = $2, updated_at = NOW() \
+            WHERE id = $1 AND tenant_id = $5 \
+            RETURNING token_balance \
+        ) \
+        INSERT INTO billing_ledger (user_id, kind, method, units, tokens, balance_after, metadata) \
+        SELECT $1, 'adjustment', $3, 0, previous.token_balance - updated.token_balance, \
+               updated.token_balance, $4 \
+        FROM previous, updated \
+        RETURNING tokens, balance_after",
+    )
+    .bind(user_id)
+    .bind(balance)
+    .bind(method)
+    .bind(Json(json!({ "admin_id": ctx.user_id })))
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to set balance: {err}")))?;
+    Ok(row.map(|row| {
+        let tokens: i64 = row.get("tokens");
+        let balance: i64 = row.get("balance_after");
+        json!({
+            "user_id": user_id,
+            "previous_balance": balance + tokens,
+            "balance": balance,
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charges_price_units() {
+        let pricing = Pricing {
+            agent_task_tokens: 100,
+            sandbox_second_tokens: 7,
+        };
+        let sandbox = Charge::SandboxTime(Duration::from_millis(2001));
+        assert_eq!(sandbox.units(), 3);
+        assert_eq!(sandbox.tokens(&pricing), 21);
+        assert_eq!(Charge::SandboxTime(Duration::ZERO).units(), 0);
+        assert_eq!(Charge::AgentTasks(4).deducted(&pricing), 400);
+
+        let response = json!({ "usage": { "total_tokens": 42 } });
+        let llm = Charge::llm(&response, true);
+        assert_eq!(
+            llm,
+            Charge::Llm {
+                tokens: 42,
+                settled: true
+            }
+        );
+        assert_eq!(llm.tokens(&pricing), 42);
+        assert_eq!(llm.deducted(&pricing), 0);
+        assert_eq!(Charge::llm(&response, false).deducted(&pricing), 42);
+    }
+
+    #[test]
+    fn usage_tokens_falls_back_to_components() {
+        let partial = json!({ "usage": { "prompt_tokens": 5, "completion_tokens": 8 } });
+        assert_eq!(usage_tokens(&partial), 13);
+        assert_eq!(usage_tokens(&json!({ "data": [] })), 0);
+    }
+}
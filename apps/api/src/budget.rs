@@ -0,0 +1,114 @@
+//! Response size budget. A result larger than `RPC_MAX_RESPONSE_BYTES`
+//! (default 32 MiB, `0` turns the check off) is replaced by -32098 before it
+//! is serialized for the wire, so one oversized answer cannot balloon the
+//! gateway's memory. The size is measured by serializing into a counter that
+//! stops at the limit.
+//!
+//! Methods that can return a lot fill their pages up to half the budget and
+//! hand out a cursor for the rest: `project.open` with `include_content` and
+//! `agent.history`.
+
+use std::io;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::RpcMethodError;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResponseBudget {
+    max_bytes: Option<usize>,
+}
+
+impl ResponseBudget {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let max_bytes = config.get("RPC_MAX_RESPONSE_BYTES", 32 * 1024 * 1024);
+        Self {
+            max_bytes: (max_bytes > 0).then_some(max_bytes),
+        }
+    }
+
+    /// What a paginated method may put in one page, leaving room for the
+    /// rest of the result.
+    pub(crate) fn page_bytes(&self) -> Option<usize> {
+        self.max_bytes.map(|max| max / 2)
+    }
+
+    /// Fails if `result` of the canonical `method` serializes to more than
+    /// the budget.
+    pub(crate) fn check(&self, method: &str, result: &Value) -> Result<(), RpcMethodError> {
+        let Some(limit) = self.max_bytes else {
+            return Ok(());
+        };
+        if fits(result, limit) {
+            return Ok(());
+        }
+        warn!(
+            method,
+            limit_bytes = limit,
+            "response exceeds the size budget"
+        );
+        Err(RpcMethodError::new(
+            ErrorCode::ResponseTooLarge,
+            "response too large",
+            Some(json!({
+                "method": method,
+                "limit_bytes": limit,
+                "detail": "narrow the request or page through it with limit and cursor",
+            })),
+        ))
+    }
+}
+
+/// Whether `value` serializes to at most `limit` bytes.
+pub(crate) fn fits(value: &impl Serialize, limit: usize) -> bool {
+    let mut counter = Counter { written: 0, limit };
+    serde_json::to_writer(&mut counter, value).is_ok()
+}
+
+/// Counts bytes and fails the write once they pass `limit`.
+struct Counter {
+    written: usize,
+    limit: usize,
+}
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len();
+        if self.written > self.limit {
+            return Err(io::Error::other("over budget"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_results_fail_with_the_budget() {
+        let budget = ResponseBudget {
+            max_bytes: Some(16),
+        };
+        assert!(budget.check("fs.list", &json!(["a", "b"])).is_ok());
+        let err = budget
+            .check("fs.list", &json!(["0123456789", "0123456789"]))
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::ResponseTooLarge.code());
+        assert_eq!(err.data.unwrap()["limit_bytes"], 16);
+        assert_eq!(budget.page_bytes(), Some(8));
+
+        assert!(fits(&"x".repeat(8), 10));
+        assert!(!fits(&"x".repeat(9), 10));
+        let unlimited = ResponseBudget { max_bytes: None };
+        assert!(unlimited.check("fs.list", &json!("x".repeat(64))).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+//! Read-through cache for project metadata and file listings. Every mutation
+//! invalidates the affected project explicitly; the TTL only bounds how long
+//! writes made by another API instance can go unnoticed.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use moka::future::Cache;
+use serde_json::Value;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::metrics::AppMetrics;
+use crate::{ProjectRecord, RpcMethodError};
+
+/// One page of `project_files`: the entries and the cursor for the next page.
+pub(crate) type Listing = (Vec<Value>, Option<String>);
+
+#[derive(Clone)]
+pub(crate) struct ProjectCache {
+    projects: Cache<Uuid, ProjectRecord>,
+    listings: Cache<(Uuid, String), Listing>,
+    metrics: Arc<AppMetrics>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProjectCacheConfig {
+    capacity: u64,
+    ttl: Duration,
+}
+
+impl ProjectCacheConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config.get("PROJECT_CACHE_CAPACITY", 10_000),
+            ttl: config.secs("PROJECT_CACHE_TTL_SECS", 30),
+        }
+    }
+}
+
+impl ProjectCache {
+    pub(crate) fn new(config: ProjectCacheConfig, metrics: Arc<AppMetrics>) -> Self {
+        Self {
+            projects: Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .build(),
+            listings: Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .support_invalidation_closures()
+                .build(),
+            metrics,
+        }
+    }
+
+    /// Returns the cached record or loads and caches it. Failed loads are
+    /// not cached, so a missing project is looked up again next time.
+    pub(crate) async fn project<F, Fut>(
+        &self,
+        project_id: Uuid,
+        load: F,
+    ) -> Result<ProjectRecord, RpcMethodError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ProjectRecord, RpcMethodError>>,
+    {
+        if let Some(record) = self.projects.get(&project_id).await {
+            self.metrics.project_cache.hit();
+            return Ok(record);
+        }
+        self.metrics.project_cache.miss();
+        let record = load().await?;
+        self.projects.insert(project_id, record.clone()).await;
+        Ok(record)
+    }
+
+    /// Returns a cached listing page keyed by the query fingerprint.
+    pub(crate) async fn listing<F, Fut>(
+        &self,
+        project_id: Uuid,
+        query_key: String,
+        load: F,
+    ) -> Result<Listing, RpcMethodError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Listing, RpcMethodError>>,
+    {
+        let key = (project_id, query_key);
+        if let Some(listing) = self.listings.get(&key).await {
+            self.metrics.listing_cache.hit();
+            return Ok(listing);
+        }
+        self.metrics.listing_cache.miss();
+        let listing = load().await?;
+        self.listings.insert(key, listing.clone()).await;
+        Ok(listing)
+    }
+
+    /// Drops the project record and every listing page for it.
+    pub(crate) async fn invalidate_project(&self, project_id: &Uuid) {
+        self.projects.invalidate(project_id).await;
+        self.invalidate_listings(project_id);
+    }
+
+    pub(crate) fn invalidate_listings(&self, project_id: &Uuid) {
+        let project_id = *project_id;
+        if let Err(err) = self
+            .listings
+            .invalidate_entries_if(move |(id, _), _| *id == project_id)
+        {
+            warn!(%project_id, error = %err, "failed to invalidate project listings");
+            self.listings.invalidate_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn record(id: Uuid, name: &str) -> ProjectRecord {
+        let now = Utc::now();
+        ProjectRecord {
+            id,
+            owner_id: 1,
+            tenant_id: 1,
+            name: name.to_string(),
+            description: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[tokio::test]
+    async fn invalidation_forces_a_reload() {
+        let metrics = Arc::new(AppMetrics::default());
+        let config = ProjectCacheConfig {
+            capacity: 16,
+            ttl: Duration::from_secs(60),
+        };
+        let cache = ProjectCache::new(config, metrics.clone());
+        let id = Uuid::new_v4();
+
+        let first = cache
+            .project(id, || async { Ok(record(id, "first")) })
+            .await
+            .unwrap();
+        let cached = cache
+            .project(id, || async { Ok(record(id, "second")) })
+            .await
+            .unwrap();
+        assert_eq!(first.name, "first");
+        assert_eq!(cached.name, "first");
+
+        cache
+            .listing(id, "page".to_string(), || async { Ok((Vec::new(), None)) })
+            .await
+            .unwrap();
+        cache.invalidate_project(&id).await;
+        let reloaded = cache
+            .project(id, || async { Ok(record(id, "second")) })
+            .await
+            .unwrap();
+        assert_eq!(reloaded.name, "second");
+        let listing = cache
+            .listing(id, "page".to_string(), || async {
+                Ok((Vec::new(), Some("next".to_string())))
+            })
+            .await
+            .unwrap();
+        assert_eq!(listing.1.as_deref(), Some("next"));
+
+        let text = metrics.render(false);
+        assert!(text.contains("cache=\"project\",result=\"hit\"} 1"));
+        assert!(text.contains("cache=\"project\",result=\"miss\"} 2"));
+    }
+}
@@ -0,0 +1,781 @@
+//! Layered configuration. Every setting has an upper-case name such as
+//! `WEBHOOK_TIMEOUT_SECS`; its value comes from the environment, then from
+//! the TOML file passed with `--config` (or `API_CONFIG`), then from the
+//! built-in default. In the file, table names are joined to their keys with
+//! `_`, so `[webhook] timeout_secs = 10` sets `WEBHOOK_TIMEOUT_SECS`. Arrays
+//! of plain values become comma-separated lists; other arrays (e.g.
+//! `[[sandbox.micro_images]]`) are passed on as JSON.
+//!
+//! Any value may instead name a secrets manager entry, e.g.
+//! `DATABASE_URL=vault:secret/coder/api#database_url` (see the `secrets`
+//! crate); [`Config::resolve_secrets`] fetches them before the settings are
+//! read, and they are always redacted when printed.
+//!
+//! [`ApiConfig::read`] resolves every setting before anything is started.
+//! Malformed values and file keys no setting uses are collected and reported
+//! together by [`Config::finish`]; `--check-config` prints the result.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use parking_lot::Mutex;
+use sandbox::AgentDispatcherConfig;
+use secrets::{Secrets, SecretsConfig};
+use server::tls::TlsSettings;
+
+use crate::{
+    admission, affinity, agent_config, audit, auth_cache, billing, budget, cache, deadline, events,
+    faults, flags, git, health, jobs, llm, metrics, quota, rate_limit, rbac, revocation, runners,
+    scheduler, telemetry, versioning, webhooks, workspace, JwtVerifier, SandboxSettings,
+    MAX_BASE64_PAYLOAD_BYTES,
+};
+
+const REDACTED: &str = "<redacted>";
+
+/// Command line of the `api` binary.
+#[derive(Debug, Default)]
+pub(crate) struct Args {
+    pub(crate) config: Option<PathBuf>,
+    /// Print the effective configuration and exit.
+    pub(crate) check: bool,
+    /// Check the deployment's dependencies, print a report and exit.
+    pub(crate) doctor: bool,
+}
+
+impl Args {
+    pub(crate) fn parse() -> anyhow::Result<Self> {
+        let mut args = Self {
+            config: std::env::var_os("API_CONFIG").map(PathBuf::from),
+            check: false,
+            doctor: false,
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = argv
+                        .next()
+                        .ok_or_else(|| anyhow!("--config expects a file path"))?;
+                    args.config = Some(PathBuf::from(path));
+                }
+                "--check-config" => args.check = true,
+                "--doctor" => args.doctor = true,
+                other => match other.strip_prefix("--config=") {
+                    Some(path) => args.config = Some(PathBuf::from(path)),
+                    None => anyhow::bail!(
+                        "unknown argument `{other}`; usage: api [--config <file>] [--check-config | --doctor]"
+                    ),
+                },
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Environment,
+    /// The file key the value was flattened from.
+    File(String),
+    /// Resolved from the secrets manager entry a value in `origin` named.
+    Secret {
+        reference: String,
+        origin: Box<Source>,
+    },
+    Default,
+}
+
+#[derive(Debug)]
+struct Setting {
+    value: Option<String>,
+    source: Source,
+    secret: bool,
+}
+
+/// A setting whose value named a secrets manager entry.
+struct Resolved {
+    reference: String,
+    origin: Source,
+    value: Result<String, String>,
+}
+
+pub(crate) type EnvLookup = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+pub(crate) struct Config {
+    path: Option<PathBuf>,
+    /// Flattened file values by setting name.
+    file: BTreeMap<String, (String, String)>,
+    env: EnvLookup,
+    /// Names of the environment variables, to find secret references in.
+    env_names: Vec<String>,
+    resolved: BTreeMap<String, Resolved>,
+    read: Mutex<BTreeMap<&'static str, Setting>>,
+    errors: Mutex<Vec<String>>,
+}
+
+impl Config {
+    pub(crate) fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let text = path
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))
+            })
+            .transpose()?;
+        let mut config = Self::new(
+            path.map(Path::to_path_buf),
+            text.as_deref(),
+            Box::new(|key| std::env::var(key).ok()),
+        )?;
+        config.env_names = std::env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .collect();
+        Ok(config)
+    }
+
+    pub(crate) fn new(
+        path: Option<PathBuf>,
+        text: Option<&str>,
+        env: EnvLookup,
+    ) -> anyhow::Result<Self> {
+        let mut file = BTreeMap::new();
+        if let Some(text) = text {
+            let name = path
+                .as_deref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default();
+            let table: toml::Table =
+                toml::from_str(text).with_context(|| format!("invalid config file {name}"))?;
+            flatten(&mut file, "", &table)
+                .with_context(|| format!("invalid config file {name}"))?;
+        }
+        Ok(Self {
+            path,
+            file,
+            env,
+            env_names: Vec::new(),
+            resolved: BTreeMap::new(),
+            read: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The secrets manager settings: `VAULT_*`, `AWS_*` and
+    /// `SECRETS_CACHE_TTL_SECS`. These cannot be references themselves.
+    pub(crate) fn secrets_config(&self) -> SecretsConfig {
+        SecretsConfig::from_lookup(|key, secret| match secret {
+            true => self.secret(key),
+            false => self.opt(key),
+        })
+        .unwrap_or_else(|err| {
+            self.errors.lock().push(err.to_string());
+            SecretsConfig::default()
+        })
+    }
+
+    /// Replaces every value that is a secrets manager reference with the
+    /// entry's current value. References that cannot be resolved leave their
+    /// setting unset and are reported by [`Config::finish`] if it is read.
+    pub(crate) async fn resolve_secrets(&mut self, secrets: &Secrets) {
+        let keys: BTreeSet<String> = self
+            .env_names
+            .iter()
+            .chain(self.file.keys())
+            .cloned()
+            .collect();
+        for key in keys {
+            let Some((raw, origin)) = self.lookup(&key) else {
+                continue;
+            };
+            if !secrets::is_reference(&raw) {
+                continue;
+            }
+            let value = secrets.resolve(&raw).await.map_err(|err| err.to_string());
+            let resolved = Resolved {
+                reference: raw.trim().to_string(),
+                origin,
+                value,
+            };
+            self.resolved.insert(key, resolved);
+        }
+    }
+
+    /// The secrets manager reference `key` was resolved from.
+    pub(crate) fn reference(&self, key: &str) -> Option<&str> {
+        self.resolved
+            .get(key)
+            .filter(|resolved| resolved.value.is_ok())
+            .map(|resolved| resolved.reference.as_str())
+    }
+
+    /// The value of `key` and where it came from, with secret references
+    /// resolved.
+    fn raw(&self, key: &str) -> Option<(String, Source)> {
+        match self.resolved.get(key) {
+            Some(resolved) => resolved.value.as_ref().ok().map(|value| {
+                let source = Source::Secret {
+                    reference: resolved.reference.clone(),
+                    origin: Box::new(resolved.origin.clone()),
+                };
+                (value.clone(), source)
+            }),
+            None => self.lookup(key),
+        }
+    }
+
+    /// The value of `key` as configured. Blank values count as unset.
+    fn lookup(&self, key: &str) -> Option<(String, Source)> {
+        if let Some(value) = (self.env)(key).filter(|value| !value.trim().is_empty()) {
+            return Some((value, Source::Environment));
+        }
+        self.file
+            .get(key)
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(file_key, value)| (value.clone(), Source::File(file_key.clone())))
+    }
+
+    fn describe(&self, source: &Source) -> String {
+        match source {
+            Source::Environment => "environment".to_string(),
+            Source::File(key) => match &self.path {
+                Some(path) => format!("`{key}` in {}", path.display()),
+                None => format!("`{key}` in the config file"),
+            },
+            Source::Secret { reference, origin } => {
+                format!("`{reference}` from {}", self.describe(origin))
+            }
+            Source::Default => "default".to_string(),
+        }
+    }
+
+    fn record(&self, key: &'static str, value: Option<String>, source: Source, secret: bool) {
+        // Whatever came from the secrets manager is treated as a secret.
+        let secret = secret || matches!(source, Source::Secret { .. });
+        let value = match secret {
+            true => value.map(|_| REDACTED.to_string()),
+            false => value,
+        };
+        self.read.lock().insert(
+            key,
+            Setting {
+                value,
+                source,
+                secret,
+            },
+        );
+    }
+
+    fn parse<T: FromStr>(&self, key: &'static str) -> Option<(T, Source)> {
+        let (raw, source) = self.raw(key)?;
+        match raw.trim().parse::<T>() {
+            Ok(value) => Some((value, source)),
+            Err(_) => {
+                self.errors.lock().push(format!(
+                    "{key} = {raw:?} ({}): expected {}",
+                    self.describe(&source),
+                    expected::<T>()
+                ));
+                None
+            }
+        }
+    }
+
+    pub(crate) fn get<T: FromStr + Display>(&self, key: &'static str, default: T) -> T {
+        let (value, source) = self.parse(key).unwrap_or((default, Source::Default));
+        self.record(key, Some(value.to_string()), source, false);
+        value
+    }
+
+    pub(crate) fn opt<T: FromStr + Display>(&self, key: &'static str) -> Option<T> {
+        match self.parse::<T>(key) {
+            Some((value, source)) => {
+                self.record(key, Some(value.to_string()), source, false);
+                Some(value)
+            }
+            None => {
+                self.record(key, None, Source::Default, false);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn string(&self, key: &'static str, default: &str) -> String {
+        self.get(key, default.to_string())
+    }
+
+    /// Like [`Config::opt`], but the value is redacted when printed.
+    pub(crate) fn secret(&self, key: &'static str) -> Option<String> {
+        let (value, source) = match self.raw(key) {
+            Some((value, source)) => (Some(value), source),
+            None => (None, Source::Default),
+        };
+        self.record(key, value.clone(), source, true);
+        value
+    }
+
+    pub(crate) fn secs(&self, key: &'static str, default: u64) -> Duration {
+        Duration::from_secs(self.get(key, default))
+    }
+
+    pub(crate) fn millis(&self, key: &'static str, default: u64) -> Duration {
+        Duration::from_millis(self.get(key, default))
+    }
+
+    /// A comma-separated list; `default` applies when the setting is unset.
+    pub(crate) fn list(&self, key: &'static str, default: &[&str]) -> Vec<String> {
+        let (items, source) = match self.raw(key) {
+            Some((raw, source)) => (split_list(&raw), source),
+            None => (
+                default.iter().map(|item| item.to_string()).collect(),
+                Source::Default,
+            ),
+        };
+        self.record(key, Some(items.join(",")), source, false);
+        items
+    }
+
+    /// A comma-separated list of `KEY=value` pairs.
+    pub(crate) fn pairs(&self, key: &'static str) -> Vec<(String, String)> {
+        let items = self.list(key, &[]);
+        let mut pairs = Vec::with_capacity(items.len());
+        for item in items {
+            match item.split_once('=') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    pairs.push((name.trim().to_string(), value.trim().to_string()))
+                }
+                _ => self.invalid(key, format!("entry `{item}` is not of the form KEY=value")),
+            }
+        }
+        pairs
+    }
+
+    /// Reports a value that parsed but is not usable, e.g. one of two
+    /// settings that must be given together.
+    pub(crate) fn invalid(&self, key: &str, problem: impl Display) {
+        let origin = self
+            .raw(key)
+            .map(|(_, source)| format!(" ({})", self.describe(&source)))
+            .unwrap_or_default();
+        self.errors.lock().push(format!("{key}{origin}: {problem}"));
+    }
+
+    /// Fails with every problem found while reading, references of read
+    /// settings that could not be resolved, plus file keys that no setting
+    /// uses (usually typos).
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        let mut errors = self.errors.lock().clone();
+        let read = self.read.lock();
+        for (key, resolved) in &self.resolved {
+            if let (Err(problem), true) = (&resolved.value, read.contains_key(key.as_str())) {
+                errors.push(format!(
+                    "{key} ({}): {problem}",
+                    self.describe(&resolved.origin)
+                ));
+            }
+        }
+        for (key, (file_key, _)) in &self.file {
+            if !read.contains_key(key.as_str()) {
+                errors.push(format!(
+                    "{}: unknown setting {key}",
+                    self.describe(&Source::File(file_key.clone()))
+                ));
+            }
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "invalid configuration:\n  - {}",
+            errors.join("\n  - ")
+        ))
+    }
+
+    /// The settings read so far as a TOML document, annotated with their
+    /// source. Secrets are redacted and unset settings are commented out.
+    pub(crate) fn render(&self) -> String {
+        let read = self.read.lock();
+        let mut sections: BTreeMap<String, Vec<(String, &Setting)>> = BTreeMap::new();
+        for (key, setting) in read.iter() {
+            let lower = key.to_ascii_lowercase();
+            let (section, name) = lower.split_once('_').unwrap_or(("", &lower));
+            sections
+                .entry(section.to_string())
+                .or_default()
+                .push((name.to_string(), setting));
+        }
+        let layers = match &self.path {
+            Some(path) => format!("environment > {} > defaults", path.display()),
+            None => "environment > defaults".to_string(),
+        };
+        let mut out = format!("# effective configuration ({layers})\n");
+        for (section, settings) in sections {
+            if !section.is_empty() {
+                out.push_str(&format!("\n[{section}]\n"));
+            }
+            for (name, setting) in settings {
+                let line = match &setting.value {
+                    Some(value) => format!(
+                        "{name} = {}  # {}",
+                        toml_value(value, setting.secret),
+                        self.describe(&setting.source)
+                    ),
+                    None => format!("# {name} is unset"),
+                };
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+/// Returns `None` when TLS is not configured. Setting only one of the
+/// certificate and key paths is a configuration error.
+fn tls_settings(config: &Config) -> Option<TlsSettings> {
+    let cert = config.opt::<String>("TLS_CERT_PATH").map(PathBuf::from);
+    let key = config.opt::<String>("TLS_KEY_PATH").map(PathBuf::from);
+    let reload_interval = config
+        .secs("TLS_RELOAD_INTERVAL_SECS", 3600)
+        .max(Duration::from_secs(1));
+    let redirect_addr = config.opt("TLS_REDIRECT_ADDR");
+    match (cert, key) {
+        (Some(cert), Some(key)) => Some(TlsSettings {
+            cert,
+            key,
+            reload_interval,
+            redirect_addr,
+        }),
+        (None, None) => None,
+        (Some(_), None) => {
+            config.invalid("TLS_KEY_PATH", "is required when TLS_CERT_PATH is set");
+            None
+        }
+        (None, Some(_)) => {
+            config.invalid("TLS_CERT_PATH", "is required when TLS_KEY_PATH is set");
+            None
+        }
+    }
+}
+
+fn split_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn flatten(
+    out: &mut BTreeMap<String, (String, String)>,
+    prefix: &str,
+    table: &toml::Table,
+) -> anyhow::Result<()> {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        let raw = match value {
+            toml::Value::Table(inner) => {
+                flatten(out, &path, inner)?;
+                continue;
+            }
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Array(items) if items.iter().all(is_scalar) => {
+                items.iter().map(scalar).collect::<Vec<_>>().join(",")
+            }
+            toml::Value::Array(_) => serde_json::to_string(value)?,
+            other => scalar(other),
+        };
+        let name = path.replace(['.', '-'], "_").to_ascii_uppercase();
+        if let Some((previous, _)) = out.insert(name.clone(), (path.clone(), raw)) {
+            anyhow::bail!("`{previous}` and `{path}` both set {name}");
+        }
+    }
+    Ok(())
+}
+
+fn is_scalar(value: &toml::Value) -> bool {
+    !matches!(value, toml::Value::Array(_) | toml::Value::Table(_))
+}
+
+fn scalar(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn toml_value(value: &str, secret: bool) -> String {
+    if !secret && (value.parse::<i64>().is_ok() || value.parse::<bool>().is_ok()) {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
+
+fn expected<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    match name.rsplit("::").next().unwrap_or(name) {
+        "u8" | "u16" | "u32" | "u64" | "usize" => "a non-negative integer",
+        "i8" | "i16" | "i32" | "i64" | "isize" => "an integer",
+        "f32" | "f64" => "a number",
+        "bool" => "true or false",
+        "SocketAddr" => "an address such as 0.0.0.0:6813",
+        _ => "a valid value",
+    }
+}
+
+/// Everything the API reads at startup.
+pub(crate) struct ApiConfig {
+    pub(crate) bind_addr: SocketAddr,
+    pub(crate) grpc_addr: Option<SocketAddr>,
+    pub(crate) tls: Option<TlsSettings>,
+    pub(crate) database_url: String,
+    pub(crate) database_max_connections: u32,
+    pub(crate) auth: JwtVerifier,
+    pub(crate) auth_cache: auth_cache::AuthCacheConfig,
+    pub(crate) require_verified_email: bool,
+    pub(crate) telemetry: telemetry::TelemetryConfig,
+    pub(crate) chaos: faults::ChaosConfig,
+    pub(crate) rpc_batch_limit: usize,
+    pub(crate) rpc_batch_concurrency: usize,
+    pub(crate) errors_recent_capacity: usize,
+    pub(crate) fs_batch_limit: usize,
+    pub(crate) fs_walk_limit: usize,
+    pub(crate) deadlines: deadline::DeadlineConfig,
+    pub(crate) response_budget: budget::ResponseBudget,
+    pub(crate) rpc_body_limit: usize,
+    pub(crate) upload_limit: usize,
+    pub(crate) project_version_limit: i64,
+    pub(crate) project_snapshot_limit: i64,
+    pub(crate) drain_timeout: Duration,
+    pub(crate) events: events::EventBusConfig,
+    pub(crate) flags: flags::FlagConfig,
+    pub(crate) sandbox: SandboxSettings,
+    pub(crate) agents: AgentDispatcherConfig,
+    pub(crate) llm: llm::LlmConfig,
+    pub(crate) llm_cache: crate::llm_cache::LlmCacheConfig,
+    pub(crate) audit: audit::AuditConfig,
+    pub(crate) pricing: billing::Pricing,
+    pub(crate) project_cache: cache::ProjectCacheConfig,
+    pub(crate) readiness: health::ReadinessConfig,
+    pub(crate) metrics_interval: Duration,
+    pub(crate) metrics: metrics::MetricsConfig,
+    pub(crate) jobs: jobs::JobConfig,
+    pub(crate) quotas: quota::QuotaConfig,
+    pub(crate) rbac: rbac::RbacConfig,
+    pub(crate) revocations: revocation::RevocationConfig,
+    pub(crate) runners: runners::RunnerConfig,
+    pub(crate) scheduler: scheduler::SchedulerConfig,
+    pub(crate) affinity: affinity::AffinityConfig,
+    pub(crate) admission: admission::AdmissionConfig,
+    pub(crate) rate_limits: rate_limit::RateLimitConfig,
+    pub(crate) versions: versioning::VersionConfig,
+    pub(crate) webhooks: webhooks::WebhookConfig,
+    pub(crate) workspaces: workspace::WorkspaceConfig,
+    pub(crate) git: git::GitSettings,
+}
+
+impl ApiConfig {
+    /// Reads every setting; problems are left in `config` for
+    /// [`Config::finish`].
+    pub(crate) fn read(config: &Config) -> Self {
+        let database_url = config.secret("DATABASE_URL").unwrap_or_else(|| {
+            config.invalid("DATABASE_URL", "is required");
+            String::new()
+        });
+        Self {
+            bind_addr: config.get("API_BIND_ADDR", SocketAddr::from(([0, 0, 0, 0], 6813))),
+            grpc_addr: config.opt("GRPC_BIND_ADDR"),
+            tls: tls_settings(config),
+            database_url,
+            database_max_connections: config.get("API_DATABASE_MAX_CONNECTIONS", 10),
+            auth: JwtVerifier::from_config(config),
+            auth_cache: auth_cache::AuthCacheConfig::from_config(config),
+            require_verified_email: config.get("API_REQUIRE_VERIFIED_EMAIL", false),
+            telemetry: telemetry::TelemetryConfig::from_config(config),
+            chaos: faults::ChaosConfig::from_config(config),
+            rpc_batch_limit: config.get("RPC_MAX_BATCH_SIZE", 32).max(1),
+            rpc_batch_concurrency: config.get("RPC_BATCH_CONCURRENCY", 8).max(1),
+            errors_recent_capacity: config.get("ERRORS_RECENT_CAPACITY", 1000),
+            fs_batch_limit: config.get("FS_BATCH_MAX_OPS", 64).max(1),
+            fs_walk_limit: config.get("FS_WALK_MAX_ENTRIES", 5000).max(1),
+            deadlines: deadline::DeadlineConfig::from_config(config),
+            response_budget: budget::ResponseBudget::from_config(config),
+            rpc_body_limit: config.get("RPC_MAX_BODY_BYTES", 16 * 1024 * 1024),
+            upload_limit: config.get("REST_UPLOAD_MAX_BYTES", MAX_BASE64_PAYLOAD_BYTES),
+            project_version_limit: config.get("PROJECT_FILE_VERSION_LIMIT", 20).max(0),
+            project_snapshot_limit: config.get("PROJECT_SNAPSHOT_LIMIT", 20).max(1),
+            drain_timeout: config.secs("SHUTDOWN_DRAIN_SECS", 30),
+            events: events::EventBusConfig::from_config(config),
+            flags: flags::FlagConfig::from_config(config),
+            sandbox: SandboxSettings::from_config(config),
+            agents: agent_config(config),
+            llm: llm::LlmConfig::from_config(config),
+            llm_cache: crate::llm_cache::LlmCacheConfig::from_config(config),
+            audit: audit::AuditConfig::from_config(config),
+            pricing: billing::Pricing::from_config(config),
+            project_cache: cache::ProjectCacheConfig::from_config(config),
+            readiness: health::ReadinessConfig::from_config(config),
+            metrics_interval: config
+                .secs("METRICS_SAMPLE_INTERVAL_SECS", 15)
+                .max(Duration::from_secs(1)),
+            metrics: metrics::MetricsConfig::from_config(config),
+            jobs: jobs::JobConfig::from_config(config),
+            quotas: quota::QuotaConfig::from_config(config),
+            rbac: rbac::RbacConfig::from_config(config),
+            revocations: revocation::RevocationConfig::from_config(config),
+            runners: runners::RunnerConfig::from_config(config),
+            scheduler: scheduler::SchedulerConfig::from_config(config),
+            affinity: affinity::AffinityConfig::from_config(config),
+            admission: admission::AdmissionConfig::from_config(config),
+            rate_limits: rate_limit::RateLimitConfig::from_config(config),
+            versions: versioning::VersionConfig::from_config(config),
+            webhooks: webhooks::WebhookConfig::from_config(config),
+            workspaces: workspace::WorkspaceConfig::from_config(config),
+            git: git::GitSettings::from_config(config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn config(file: &str, env: &[(&str, &str)]) -> Config {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let env_names = env.keys().cloned().collect();
+        let mut config = Config::new(
+            Some(PathBuf::from("api.toml")),
+            Some(file),
+            Box::new(move |key| env.get(key).cloned()),
+        )
+        .unwrap();
+        config.env_names = env_names;
+        config
+    }
+
+    #[test]
+    fn environment_overrides_file_and_file_overrides_defaults() {
+        let config = config(
+            "[webhook]\ntimeout_secs = 20\nmax_attempts = 3\n\n[sandbox.run]\nallowed = [\"/bin/sh\", \"/usr/bin/python3\"]\n",
+            &[("WEBHOOK_MAX_ATTEMPTS", "4")],
+        );
+        assert_eq!(config.get("WEBHOOK_TIMEOUT_SECS", 10u64), 20);
+        assert_eq!(config.get("WEBHOOK_MAX_ATTEMPTS", 8i32), 4);
+        assert_eq!(config.get("WEBHOOK_MAX_PER_USER", 20i64), 20);
+        assert_eq!(
+            config.list("SANDBOX_RUN_ALLOWED", &[]),
+            vec!["/bin/sh", "/usr/bin/python3"]
+        );
+        config.finish().unwrap();
+
+        let rendered = config.render();
+        assert!(rendered.contains("[webhook]\nmax_attempts = 4  # environment\n"));
+        assert!(rendered.contains("timeout_secs = 20  # `webhook.timeout_secs` in api.toml\n"));
+        assert!(rendered.contains("max_per_user = 20  # default\n"));
+    }
+
+    #[test]
+    fn problems_are_collected_with_their_source() {
+        let config = config(
+            "[webhook]\ntimeout = 20\n",
+            &[("JOB_WORKERS", "four"), ("OPENAI_API_KEY", "sk-secret")],
+        );
+        assert_eq!(config.get("JOB_WORKERS", 4usize), 4);
+        assert_eq!(
+            config.secret("OPENAI_API_KEY").as_deref(),
+            Some("sk-secret")
+        );
+        config.invalid("OPENAI_API_KEY", "is not used by any route");
+        assert!(!config.render().contains("sk-secret"));
+
+        let message = config.finish().unwrap_err().to_string();
+        assert!(message
+            .contains("JOB_WORKERS = \"four\" (environment): expected a non-negative integer"));
+        assert!(message.contains("OPENAI_API_KEY (environment): is not used by any route"));
+        assert!(message.contains("`webhook.timeout` in api.toml: unknown setting WEBHOOK_TIMEOUT"));
+    }
+
+    struct Vault;
+
+    #[async_trait::async_trait]
+    impl secrets::SecretProvider for Vault {
+        async fn fetch(&self, path: &str) -> Result<secrets::Fetched, secrets::SecretsError> {
+            match path {
+                "kv/coder" => Ok(secrets::Fetched {
+                    data: serde_json::json!({ "database_url": "postgres://coder:pw@db/coder" }),
+                    version: Some("3".to_string()),
+                }),
+                _ => Err(secrets::SecretsError::Request {
+                    scheme: secrets::Scheme::Vault,
+                    path: path.to_string(),
+                    message: "permission denied".to_string(),
+                }),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn secret_references_are_resolved_and_redacted() {
+        let mut config = config(
+            "database_url = \"vault:kv/coder#database_url\"\n",
+            &[
+                ("OPENAI_API_KEY", "vault:kv/openai#key"),
+                ("SIDECAR_TOKEN", "vault:kv/sidecar#token"),
+            ],
+        );
+        let secrets = Secrets::new(SecretsConfig::default())
+            .unwrap()
+            .with_provider(secrets::Scheme::Vault, Arc::new(Vault));
+        config.resolve_secrets(&secrets).await;
+
+        assert_eq!(
+            config.secret("DATABASE_URL").as_deref(),
+            Some("postgres://coder:pw@db/coder")
+        );
+        assert_eq!(
+            config.reference("DATABASE_URL"),
+            Some("vault:kv/coder#database_url")
+        );
+        assert_eq!(config.secret("OPENAI_API_KEY"), None);
+        assert_eq!(config.reference("OPENAI_API_KEY"), None);
+
+        let rendered = config.render();
+        assert!(rendered.contains(
+            "url = \"<redacted>\"  # `vault:kv/coder#database_url` from `database_url` in api.toml\n"
+        ));
+        assert!(!rendered.contains("pw@db"));
+
+        // Unresolvable references only matter for settings that are read.
+        let message = config.finish().unwrap_err().to_string();
+        assert!(message.contains(
+            "OPENAI_API_KEY (environment): vault request for `kv/openai` failed: permission denied"
+        ));
+        assert!(!message.contains("SIDECAR_TOKEN"));
+    }
+
+    #[test]
+    fn file_keys_must_not_collide() {
+        let err = Config::new(
+            None,
+            Some("sandbox_run_path = \"/bin\"\n[sandbox]\nrun_path = \"/usr/bin\"\n"),
+            Box::new(|_| None),
+        )
+        .err()
+        .expect("colliding keys are rejected");
+        assert!(format!("{err:#}").contains("both set SANDBOX_RUN_PATH"));
+    }
+}
@@ -0,0 +1,200 @@
+//! Five-field cron expressions (`minute hour day-of-month month day-of-week`)
+//! evaluated in UTC. Fields accept `*`, single values, ranges `a-b`, steps
+//! `*/n` or `a-b/n` and comma separated lists; day-of-week counts from
+//! Sunday = 0 (7 is accepted as Sunday). As in classic cron, a restricted
+//! day-of-month and day-of-week match when either one does. `@hourly`,
+//! `@daily`, `@weekly` and `@monthly` are shorthands.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+
+/// How far ahead `next_after` looks before giving up on expressions that
+/// never match, such as `0 0 30 2 *`.
+const SEARCH_YEARS: i32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(CronError(format!(
+                "expected 5 fields, found {}",
+                fields.len()
+            )));
+        };
+        let mut weekdays = parse_field(weekday, "day-of-week", 0, 7)?;
+        // Fold 7 onto Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, "minute", 0, 59)?,
+            hours: parse_field(hour, "hour", 0, 23)?,
+            days: parse_field(day, "day-of-month", 1, 31)?,
+            months: parse_field(month, "month", 1, 12)?,
+            weekdays,
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+        })
+    }
+}
+
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let invalid = |part: &str| CronError(format!("invalid {name} field `{part}`"));
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<u32>().map_err(|_| invalid(part))?;
+                if step == 0 {
+                    return Err(invalid(part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse::<u32>().map_err(|_| invalid(part))?,
+                end.parse::<u32>().map_err(|_| invalid(part))?,
+            )
+        } else {
+            let value = range.parse::<u32>().map_err(|_| invalid(part))?;
+            // `5/15` means "from 5 to the end in steps of 15".
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(CronError(format!(
+                "{name} field `{part}` is outside {min}-{max}"
+            )));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+impl Cron {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`, or `None` when the
+    /// expression never matches within the search window.
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut at = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = after.year() + SEARCH_YEARS;
+        while at.year() <= limit {
+            let date = at.date_naive();
+            if !has(self.months, at.month()) {
+                let (year, month) = match at.month() {
+                    12 => (at.year() + 1, 1),
+                    month => (at.year(), month + 1),
+                };
+                at = NaiveDate::from_ymd_opt(year, month, 1)?
+                    .and_hms_opt(0, 0, 0)?
+                    .and_utc();
+            } else if !self.matches_day(date) {
+                at = date.succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc();
+            } else if !has(self.hours, at.hour()) {
+                at = at.with_minute(0)? + Duration::hours(1);
+            } else if !has(self.minutes, at.minute()) {
+                at += Duration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn next(expr: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        expr.parse::<Cron>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn computes_next_runs() {
+        let start = at(2024, 1, 31, 10, 7);
+        assert_eq!(next("*/5 * * * *", start), Some(at(2024, 1, 31, 10, 10)));
+        assert_eq!(next("0 3 * * *", start), Some(at(2024, 2, 1, 3, 0)));
+        assert_eq!(next("@hourly", start), Some(at(2024, 1, 31, 11, 0)));
+        assert_eq!(next("30 9 29 2 *", start), Some(at(2024, 2, 29, 9, 30)));
+        // 2024-02-04 is a Sunday; 7 is folded onto 0.
+        assert_eq!(next("0 0 * * 7", start), Some(at(2024, 2, 4, 0, 0)));
+        // Restricted day-of-month and day-of-week match on either.
+        assert_eq!(next("0 0 15 * 4", start), Some(at(2024, 2, 1, 0, 0)));
+        assert_eq!(
+            next("10-20/5 8,12 * 1-3 1-5", start),
+            Some(at(2024, 1, 31, 12, 10))
+        );
+        assert_eq!(next("0 0 30 2 *", start), None);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expr in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expr.parse::<Cron>().is_err(), "{expr} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Execution deadlines for RPC methods. Every call runs under
+//! `RPC_TIMEOUT_SECS` unless `RPC_METHOD_TIMEOUTS` (`method=secs` pairs)
+//! sets its own; `0` turns the deadline off. A call that runs past its
+//! deadline is dropped and fails with -32097, so a stuck query or upstream
+//! request cannot pin a worker forever.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use serde_json::json;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::metrics::AppMetrics;
+use crate::{openrpc, RpcMethodError};
+
+#[derive(Debug, Clone)]
+pub(crate) struct DeadlineConfig {
+    default: Option<Duration>,
+    methods: HashMap<String, Option<Duration>>,
+}
+
+fn deadline(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl DeadlineConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let default = deadline(config.get("RPC_TIMEOUT_SECS", 60));
+        let mut methods = HashMap::new();
+        for (method, secs) in config.pairs("RPC_METHOD_TIMEOUTS") {
+            if !openrpc::has_method(&method) {
+                config.invalid("RPC_METHOD_TIMEOUTS", format!("unknown method `{method}`"));
+                continue;
+            }
+            match secs.parse::<u64>() {
+                Ok(secs) => {
+                    methods.insert(method, deadline(secs));
+                }
+                Err(_) => config.invalid(
+                    "RPC_METHOD_TIMEOUTS",
+                    format!("`{method}={secs}` is not a number of seconds"),
+                ),
+            }
+        }
+        Self { default, methods }
+    }
+
+    fn for_method(&self, method: &str) -> Option<Duration> {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+
+    /// Runs `call` for the canonical `method` under its deadline.
+    pub(crate) async fn run<T>(
+        &self,
+        method: &str,
+        metrics: &AppMetrics,
+        call: impl Future<Output = Result<T, RpcMethodError>>,
+    ) -> Result<T, RpcMethodError> {
+        let Some(limit) = self.for_method(method) else {
+            return call.await;
+        };
+        match tokio::time::timeout(limit, call).await {
+            Ok(result) => result,
+            Err(_) => {
+                metrics.rpc_timeout(method);
+                warn!(
+                    method,
+                    timeout_secs = limit.as_secs(),
+                    "rpc method timed out"
+                );
+                Err(RpcMethodError::new(
+                    ErrorCode::Timeout,
+                    "method timed out",
+                    Some(json!({ "method": method, "timeout_secs": limit.as_secs() })),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn deadlines(entries: &[(&str, &str)]) -> DeadlineConfig {
+        let env: HashMap<String, String> = entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let config = Config::new(None, None, Box::new(move |key| env.get(key).cloned())).unwrap();
+        let deadlines = DeadlineConfig::from_config(&config);
+        config.finish().unwrap();
+        deadlines
+    }
+
+    #[test]
+    fn method_overrides_replace_the_default() {
+        let deadlines = deadlines(&[
+            ("RPC_TIMEOUT_SECS", "5"),
+            ("RPC_METHOD_TIMEOUTS", "llm.completion=0,project.search=1"),
+        ]);
+        assert_eq!(
+            deadlines.for_method("fs.list"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            deadlines.for_method("project.search"),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(deadlines.for_method("llm.completion"), None);
+    }
+
+    #[tokio::test]
+    async fn slow_calls_fail_with_the_timeout_code() {
+        let deadlines = DeadlineConfig {
+            default: Some(Duration::from_millis(20)),
+            ..deadlines(&[("RPC_METHOD_TIMEOUTS", "run.exec=0")])
+        };
+        let metrics = AppMetrics::default();
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(7)
+        };
+        let err = deadlines
+            .run("fs.read", &metrics, slow())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, -32097);
+        assert!(metrics
+            .render(false)
+            .contains("api_rpc_timeouts_total{method=\"fs.read\"} 1"));
+        assert_eq!(
+            deadlines.run("run.exec", &metrics, slow()).await.unwrap(),
+            7
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! `api --doctor`: checks a deployment before it takes traffic. Once the
+//! configuration is valid it verifies what the first requests would
+//! otherwise trip over: a writable sandbox root, the allowed programs of
+//! `run.exec` on `SANDBOX_RUN_PATH`, the micro image binaries, that the wasm
+//! engine runs a module, Postgres, that `_sqlx_migrations` records every
+//! migration as applied and unchanged, and the LLM server. The report is
+//! printed as JSON; any failed check makes the command exit non-zero.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use sandbox::{WasmInvocation, WasmModuleSource, WasmValue};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::migrate::{Migration, Migrator};
+
+use crate::config::ApiConfig;
+use crate::{build_pool, health, initialize_sandboxes, llm};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The migrations of `database/migrations`, which `sqlx migrate run`
+/// applies and records in `_sqlx_migrations`.
+static MIGRATOR: Migrator = sqlx::migrate!("../../database/migrations");
+
+/// `(module (func (export "probe") (result i32) i32.const 42))`
+const WASM_PROBE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic, version
+    0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, // type: () -> i32
+    0x03, 0x02, 0x01, 0x00, // function 0 has type 0
+    0x07, 0x09, 0x01, 0x05, b'p', b'r', b'o', b'b', b'e', 0x00, 0x00, // export
+    0x0a, 0x06, 0x01, 0x04, 0x00, 0x41, 0x2a, 0x0b, // body: i32.const 42
+];
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Value::is_null")]
+    detail: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, started: Instant, result: Result<Value, String>) -> Self {
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok(detail) => Self {
+                name,
+                ok: true,
+                latency_ms,
+                detail,
+                error: None,
+            },
+            Err(error) => Self {
+                name,
+                ok: false,
+                latency_ms,
+                detail: Value::Null,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Runs every check and prints the report; fails if any check did.
+pub(crate) async fn run(settings: ApiConfig) -> anyhow::Result<()> {
+    let mut checks = Vec::new();
+    let run_path = settings.sandbox.run_path.clone();
+
+    let started = Instant::now();
+    match initialize_sandboxes(settings.sandbox) {
+        Ok((fs, run, wasm, micro)) => {
+            let root = fs.base_dir().to_path_buf();
+            let writable = timed(health::check_sandbox(&root)).await;
+            checks.push(Check::new(
+                "sandbox_root",
+                started,
+                writable.map(|()| json!({ "path": root })),
+            ));
+
+            let started = Instant::now();
+            let programs = run.config().allowed_programs().cloned().collect();
+            checks.push(Check::new(
+                "run_programs",
+                started,
+                resolve_all(programs, &run_path),
+            ));
+
+            let started = Instant::now();
+            let config = micro.config();
+            let path = config.base_env().get("PATH").cloned().unwrap_or_default();
+            let commands = config
+                .images()
+                .map(|image| format!("{}={}", image.name(), image.command()))
+                .collect();
+            checks.push(Check::new(
+                "micro_images",
+                started,
+                resolve_all(commands, &path),
+            ));
+
+            let started = Instant::now();
+            let invocation = WasmInvocation::new(WasmModuleSource::from_bytes(WASM_PROBE), "probe");
+            let probe = match wasm.invoke(invocation) {
+                Ok(values) if matches!(values.as_slice(), [WasmValue::I32(42)]) => Ok(Value::Null),
+                Ok(values) => Err(format!("probe module returned {} values", values.len())),
+                Err(err) => Err(err.to_string()),
+            };
+            checks.push(Check::new("wasm", started, probe));
+        }
+        Err(err) => checks.push(Check::new("sandbox_root", started, Err(err.to_string()))),
+    }
+
+    let started = Instant::now();
+    let pool = timed(async {
+        build_pool(&settings.database_url, 1)
+            .await
+            .map_err(|err| err.to_string())
+    })
+    .await;
+    match pool {
+        Ok(pool) => {
+            checks.push(Check::new("postgres", started, Ok(Value::Null)));
+            let started = Instant::now();
+            let migrations = timed(missing_migrations(&pool)).await;
+            checks.push(Check::new("migrations", started, migrations));
+            pool.close().await;
+        }
+        Err(err) => {
+            checks.push(Check::new("postgres", started, Err(err)));
+            checks.push(Check::new(
+                "migrations",
+                Instant::now(),
+                Err("skipped: postgres is unreachable".to_string()),
+            ));
+        }
+    }
+
+    let started = Instant::now();
+    let llm = match llm::LlmClient::new(&settings.llm) {
+        Ok(client) => timed(client.ping()).await.map(|()| Value::Null),
+        Err(err) => Err(err.to_string()),
+    };
+    checks.push(Check::new("llm", started, llm));
+
+    let failed = checks.iter().filter(|check| !check.ok).count();
+    let report = json!({ "ok": failed == 0, "checks": checks });
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if failed > 0 {
+        anyhow::bail!("{failed} doctor check(s) failed");
+    }
+    Ok(())
+}
+
+async fn timed<T>(
+    check: impl std::future::Future<Output = Result<T, String>>,
+) -> Result<T, String> {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {}s", CHECK_TIMEOUT.as_secs())))
+}
+
+/// Resolves every entry (a program, or `label=program`) on `path`.
+fn resolve_all(entries: Vec<String>, path: &str) -> Result<Value, String> {
+    let mut resolved = serde_json::Map::new();
+    let mut missing = Vec::new();
+    for entry in entries {
+        let (label, program) = entry.split_once('=').unwrap_or((&entry, &entry));
+        match find_executable(program, path) {
+            Some(found) => {
+                resolved.insert(label.to_string(), json!(found));
+            }
+            None => missing.push(format!("{label} (`{program}`)")),
+        }
+    }
+    if missing.is_empty() {
+        Ok(Value::Object(resolved))
+    } else {
+        Err(format!(
+            "not found or not executable: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
+/// `program` itself when it is a path, otherwise the first match on `path`.
+fn find_executable(program: &str, path: &str) -> Option<PathBuf> {
+    let executable = |candidate: &Path| {
+        std::fs::metadata(candidate)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let candidate = PathBuf::from(program);
+        return executable(&candidate).then_some(candidate);
+    }
+    path.split(':')
+        .filter(|dir| !dir.trim().is_empty())
+        .map(|dir| Path::new(dir.trim()).join(program))
+        .find(|candidate| executable(candidate))
+}
+
+/// Fails with the migrations `_sqlx_migrations` has no successful record
+/// of, or whose file changed after it was applied.
+async fn missing_migrations(pool: &sqlx::PgPool) -> Result<Value, String> {
+    let recorded: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !recorded {
+        return Err(
+            "no _sqlx_migrations table; apply database/migrations with `sqlx migrate run`"
+                .to_string(),
+        );
+    }
+    let applied: HashMap<i64, Vec<u8>> =
+        sqlx::query_as("SELECT version, checksum FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+            .map_err(|err| err.to_string())?
+            .into_iter()
+            .collect();
+    compare_migrations(MIGRATOR.iter(), &applied)
+}
+
+fn migration_name(migration: &Migration) -> String {
+    format!(
+        "{:03}_{}",
+        migration.version,
+        migration.description.replace(' ', "_")
+    )
+}
+
+/// Checks `migrations` against the applied versions and their checksums.
+fn compare_migrations<'a>(
+    migrations: impl Iterator<Item = &'a Migration>,
+    applied: &HashMap<i64, Vec<u8>>,
+) -> Result<Value, String> {
+    let mut pending = Vec::new();
+    let mut changed = Vec::new();
+    let mut latest = None;
+    for migration in migrations.filter(|migration| !migration.migration_type.is_down_migration()) {
+        match applied.get(&migration.version) {
+            None => pending.push(migration_name(migration)),
+            Some(checksum) if *checksum != *migration.checksum => {
+                changed.push(migration_name(migration))
+            }
+            Some(_) => latest = Some(migration_name(migration)),
+        }
+    }
+    let mut problems = Vec::new();
+    if !pending.is_empty() {
+        problems.push(format!("not applied: {}", pending.join(", ")));
+    }
+    if !changed.is_empty() {
+        problems.push(format!(
+            "changed after they were applied: {}",
+            changed.join(", ")
+        ));
+    }
+    if problems.is_empty() {
+        Ok(json!({ "latest": latest }))
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_migration_file_is_embedded() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../database/migrations");
+        let mut files: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                path.file_stem().unwrap().to_string_lossy().to_string()
+            })
+            .collect();
+        files.sort();
+        let embedded: Vec<String> = MIGRATOR.iter().map(migration_name).collect();
+        assert_eq!(embedded, files);
+    }
+
+    #[test]
+    fn migrations_count_as_applied_only_unchanged() {
+        let mut applied: HashMap<i64, Vec<u8>> = MIGRATOR
+            .iter()
+            .map(|migration| (migration.version, migration.checksum.to_vec()))
+            .collect();
+        let report = compare_migrations(MIGRATOR.iter(), &applied).unwrap();
+        let latest = MIGRATOR.iter().last().map(migration_name).unwrap();
+        assert_eq!(report["latest"], latest);
+
+        // Migrations that only add columns or triggers are caught as well.
+        applied.remove(&10);
+        applied.insert(20, vec![0; 48]);
+        let err = compare_migrations(MIGRATOR.iter(), &applied).unwrap_err();
+        assert_eq!(
+            err,
+            "not applied: 010_user_disabled; changed after they were applied: 020_api_key_scopes"
+        );
+    }
+
+    #[test]
+    fn programs_resolve_on_the_given_path() {
+        let found = find_executable("sh", "/nonexistent:/bin:/usr/bin").unwrap();
+        assert!(found.ends_with("sh"));
+        assert!(find_executable("sh", "/nonexistent").is_none());
+        assert!(find_executable("/definitely/not/here", "/bin").is_none());
+
+        let report = resolve_all(vec!["shell=sh".to_string()], "/bin:/usr/bin").unwrap();
+        assert!(report["shell"].as_str().unwrap().ends_with("/sh"));
+        let err = resolve_all(vec!["missing-tool".to_string()], "/bin").unwrap_err();
+        assert!(err.contains("missing-tool"));
+    }
+}
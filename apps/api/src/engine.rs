@@ -0,0 +1,802 @@
+//! Sandbox engines. A method `<engine>.<action>` that no dispatcher arm
+//! claims goes to the [`SandboxEngine`] registered under that prefix, so a
+//! new engine (a gVisor or remote runner, say) is an implementation plus a
+//! [`Engines::register`] call in `main`. `<engine>.describe` is answered
+//! from [`SandboxEngine::describe`] for every engine, and whenever a project
+//! or workspace directory goes away every engine gets to release what it
+//! still holds inside it.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::stream::{self, BoxStream, StreamExt};
+use parking_lot::RwLock;
+use runner::protocol::{process_result, Call, RemoteRun};
+use sandbox::micro::{MicroExecuteRequest, MicroStartRequest, SandboxMicro};
+use sandbox::run::{RunSessionOutput, SandboxRun};
+use sandbox::{SandboxWasm, WasmInvocation};
+use serde_json::{json, Value};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::affinity::HandleKind;
+use crate::billing::Charge;
+use crate::errors::ErrorCode;
+use crate::events::DomainEvent;
+use crate::runners::Runners;
+use crate::telemetry;
+use crate::{
+    ensure_program_allowed, parse_params, parse_project_id, project_run_policy,
+    resolve_wasm_module, sandbox_scope, scope_error, validate_params, wasm_value_to_json, AppState,
+    MicroExecuteParams, MicroStartParams, MicroStopParams, Permission, RequestContext,
+    RpcMethodError, RunExecParams, RunSessionKillParams, RunSessionWriteParams, WasmInvokeParams,
+    WasmParam,
+};
+
+/// Longest `wait_ms` of `run.session.write`.
+const MAX_SESSION_WAIT: Duration = Duration::from_secs(30);
+
+/// Output events of a streamed call, in the order they were produced.
+pub(crate) type OutputStream = BoxStream<'static, Result<Value, RpcMethodError>>;
+
+#[async_trait]
+pub(crate) trait SandboxEngine: Send + Sync {
+    /// The method prefix the engine serves, `run` for `run.exec`.
+    fn name(&self) -> &'static str;
+
+    /// Current limits and settings, returned by `<name>.describe`.
+    fn describe(&self) -> Value;
+
+    /// Runs `action`, the method name without the engine prefix. Engines
+    /// check permissions themselves; unknown actions are
+    /// [`ErrorCode::MethodNotFound`].
+    async fn execute(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        action: &str,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError>;
+
+    /// Runs `action` and yields its output as it is produced. Engines that
+    /// only have the complete result yield the response of `execute` as
+    /// the single event.
+    async fn stream(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        action: &str,
+        params: Option<Value>,
+    ) -> Result<OutputStream, RpcMethodError> {
+        let response = self.execute(state, ctx, action, params).await?;
+        Ok(stream::once(async move { Ok(response) }).boxed())
+    }
+
+    /// Releases whatever the engine keeps below `scope`, a directory
+    /// relative to the sandbox root that is about to be removed; returns
+    /// how many instances were stopped.
+    async fn cleanup(&self, _scope: &Path) -> sandbox::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// The registered engines by method prefix.
+#[derive(Clone, Default)]
+pub(crate) struct Engines {
+    engines: Arc<RwLock<HashMap<&'static str, Arc<dyn SandboxEngine>>>>,
+}
+
+impl Engines {
+    /// Adds `engine`; a prefix can only be registered once.
+    pub(crate) fn register(&self, engine: Arc<dyn SandboxEngine>) -> anyhow::Result<()> {
+        let name = engine.name();
+        if name.is_empty() || name.contains('.') {
+            anyhow::bail!("invalid sandbox engine name `{name}`");
+        }
+        let mut engines = self.engines.write();
+        if engines.contains_key(name) {
+            anyhow::bail!("sandbox engine `{name}` is already registered");
+        }
+        engines.insert(name, engine);
+        Ok(())
+    }
+
+    fn route<'m>(&self, method: &'m str) -> Option<(Arc<dyn SandboxEngine>, &'m str)> {
+        let (prefix, action) = method.split_once('.')?;
+        let engine = self.engines.read().get(prefix)?.clone();
+        Some((engine, action))
+    }
+
+    /// Runs every engine's cleanup for `scope` and adds up what they stopped.
+    pub(crate) async fn cleanup(&self, scope: &Path) -> sandbox::Result<usize> {
+        let engines: Vec<Arc<dyn SandboxEngine>> = self.engines.read().values().cloned().collect();
+        let mut stopped = 0;
+        for engine in engines {
+            stopped += engine.cleanup(scope).await?;
+        }
+        Ok(stopped)
+    }
+}
+
+fn method_not_found() -> RpcMethodError {
+    RpcMethodError::new(ErrorCode::MethodNotFound, "method not found", None)
+}
+
+/// Serves `method` from the engine registered for its prefix.
+pub(crate) async fn dispatch(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcMethodError> {
+    let (engine, action) = state.engines.route(method).ok_or_else(method_not_found)?;
+    if action == "describe" {
+        ctx.require(Permission::FsRead)?;
+        return Ok(engine.describe());
+    }
+    let started = Instant::now();
+    let result = engine.execute(state, ctx, action, params).await;
+    // Unknown actions stay out of the histogram so its label set is bounded.
+    if !matches!(&result, Err(err) if err.code == ErrorCode::MethodNotFound.code()) {
+        let trace_id = telemetry::span_trace_id(&Span::current(), ctx);
+        state
+            .metrics
+            .sandbox_duration(engine.name(), action, started.elapsed(), trace_id);
+    }
+    result
+}
+
+/// Starts `method` as a stream on the engine registered for its prefix.
+pub(crate) async fn open_stream(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    params: Option<Value>,
+) -> Result<OutputStream, RpcMethodError> {
+    validate_params(method, params.as_ref())?;
+    let (engine, action) = state.engines.route(method).ok_or_else(method_not_found)?;
+    if action == "describe" {
+        return Err(method_not_found());
+    }
+    engine.stream(state, ctx, action, params).await
+}
+
+fn parse_vm_id(value: &str) -> Result<Uuid, RpcMethodError> {
+    parse_handle(value, "invalid vm identifier")
+}
+
+fn parse_handle(value: &str, message: &str) -> Result<Uuid, RpcMethodError> {
+    Uuid::parse_str(value).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            message,
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })
+}
+
+/// Applies the project's `allowed_programs` to runs in a project directory;
+/// other scopes only have the global allowlist.
+async fn enforce_project_policy(
+    state: &AppState,
+    params: &RunExecParams,
+) -> Result<(), RpcMethodError> {
+    let Some(project_id) = params.project_id.as_deref() else {
+        return Ok(());
+    };
+    let policy = project_run_policy(&state.pool, &parse_project_id(project_id)?).await?;
+    ensure_program_allowed(policy.as_deref(), &params.command.program)
+}
+
+/// Decodes a base64 parameter that has to hold utf-8 text.
+fn decode_text(value: &str, name: &str) -> Result<String, RpcMethodError> {
+    let bytes = BASE64.decode(value.as_bytes()).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid base64 payload",
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })?;
+    String::from_utf8(bytes).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            &format!("{name} must be valid utf-8"),
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })
+}
+
+/// Processes from the `SANDBOX_RUN_ALLOWED` allowlist (`run.exec`), and
+/// interactive ones that keep stdin open across calls (`run.session.start`,
+/// `run.session.write`, `run.session.kill`). Sessions always run on this
+/// instance, never on a runner.
+pub(crate) struct RunEngine {
+    pub(crate) run: Arc<SandboxRun>,
+}
+
+#[async_trait]
+impl SandboxEngine for RunEngine {
+    fn name(&self) -> &'static str {
+        "run"
+    }
+
+    fn describe(&self) -> Value {
+        let config = self.run.config();
+        let allowed: Vec<String> = config.allowed_programs().cloned().collect();
+        json!({
+            "root": config.root().display().to_string(),
+            "allowed_programs": allowed,
+            "default_timeout_ms": config.default_timeout().as_millis(),
+            "max_timeout_ms": config.max_timeout().as_millis(),
+            "max_output_bytes": config.max_output_bytes(),
+            "max_sessions": config.max_sessions(),
+            "max_session_timeout_ms": config.max_session_timeout().as_millis(),
+            "open_sessions": self.run.open_sessions(),
+        })
+    }
+
+    async fn execute(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        action: &str,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        match action {
+            "exec" => self.exec(state, ctx, params).await,
+            "session.start" => self.start_session(state, ctx, params).await,
+            "session.write" => self.write_session(state, ctx, params).await,
+            "session.kill" => self.kill_session(state, ctx, params).await,
+            _ => Err(method_not_found()),
+        }
+    }
+
+    async fn cleanup(&self, scope: &Path) -> sandbox::Result<usize> {
+        self.run.kill_scope(scope).await
+    }
+}
+
+impl RunEngine {
+    async fn exec(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        let params: RunExecParams = parse_params(params)?;
+        ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+        ctx.ensure_tokens()?;
+        let scope = sandbox_scope(
+            state,
+            ctx,
+            params.project_id.as_deref(),
+            params.workspace_id.as_deref(),
+            Permission::Execute,
+        )
+        .await?;
+        enforce_project_policy(state, &params).await?;
+        let run = self.run.scoped(scope.clone()).map_err(scope_error)?;
+        let program = params.command.program.clone();
+        let event_scope = json!({
+            "project_id": params.project_id,
+            "workspace_id": params.workspace_id,
+        });
+        let request = params.command.into_request()?;
+        let remote = Call::RunExec {
+            scope,
+            request: RemoteRun::from(&request),
+        };
+        let result = match state.runners.offload(remote).await? {
+            Some((_, result)) => result,
+            None => {
+                let output = run.execute(request).await.map_err(|err| {
+                    RpcMethodError::from_sandbox(
+                        ErrorCode::RunExecute,
+                        "failed to execute process",
+                        err,
+                    )
+                })?;
+                process_result(
+                    output.exit_code,
+                    &output.stdout,
+                    &output.stderr,
+                    output.duration,
+                )
+            }
+        };
+        let duration = Duration::from_millis(result["duration_ms"].as_u64().unwrap_or(0));
+        state
+            .billing
+            .charge(ctx, "run.exec", Charge::SandboxTime(duration))
+            .await;
+        state.events.publish(DomainEvent::RunCompleted {
+            user_id: ctx.user_id,
+            program,
+            exit_code: result["exit_code"].as_i64(),
+            duration,
+            scope: event_scope,
+        });
+        Ok(result)
+    }
+
+    async fn start_session(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        let params: RunExecParams = parse_params(params)?;
+        ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+        ctx.ensure_tokens()?;
+        let run = self
+            .scoped(state, ctx, &params.project_id, &params.workspace_id)
+            .await?;
+        enforce_project_policy(state, &params).await?;
+        let session = run
+            .start_session(params.command.into_request()?)
+            .await
+            .map_err(|err| {
+                RpcMethodError::from_sandbox(
+                    ErrorCode::RunExecute,
+                    "failed to start run session",
+                    err,
+                )
+            })?;
+        state
+            .affinity
+            .claim(session.id, HandleKind::RunSession)
+            .await;
+        // Billed at the end however the session ends, read or not.
+        let (billing, ctx) = (state.billing.clone(), ctx.clone());
+        tokio::spawn(async move {
+            if let Ok(duration) = session.exited.await {
+                billing
+                    .charge(&ctx, "run.session.start", Charge::SandboxTime(duration))
+                    .await;
+            }
+        });
+        Ok(json!({
+            "session_id": session.id,
+            "timeout_ms": session.timeout.as_millis() as u64,
+        }))
+    }
+
+    /// Writes the stdin chunk, if any, then returns the output since the
+    /// previous call, waiting up to `wait_ms` for some.
+    async fn write_session(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        let params: RunSessionWriteParams = parse_params(params)?;
+        ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+        let id = parse_handle(&params.session_id, "invalid session identifier")?;
+        let data = match params.data.as_deref() {
+            Some(data) => BASE64.decode(data.as_bytes()).map_err(|err| {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid base64 payload",
+                    Some(json!({ "detail": err.to_string() })),
+                )
+            })?,
+            None => Vec::new(),
+        };
+        let wait = Duration::from_millis(params.wait_ms.unwrap_or(0)).min(MAX_SESSION_WAIT);
+        let run = self
+            .scoped(state, ctx, &params.project_id, &params.workspace_id)
+            .await?;
+        let failed = |err| {
+            RpcMethodError::from_sandbox(ErrorCode::RunExecute, "failed to use run session", err)
+        };
+        if !data.is_empty() || params.close_stdin {
+            run.write_session(id, &data, params.close_stdin)
+                .await
+                .map_err(failed)?;
+        }
+        let output = run.read_session(id, wait).await.map_err(failed)?;
+        Ok(self.session_output(state, id, output).await)
+    }
+
+    async fn kill_session(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        let params: RunSessionKillParams = parse_params(params)?;
+        ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+        let id = parse_handle(&params.session_id, "invalid session identifier")?;
+        let run = self
+            .scoped(state, ctx, &params.project_id, &params.workspace_id)
+            .await?;
+        let output = run.kill_session(id).await.map_err(|err| {
+            RpcMethodError::from_sandbox(ErrorCode::RunExecute, "failed to kill run session", err)
+        })?;
+        Ok(self.session_output(state, id, output).await)
+    }
+
+    async fn scoped(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        project_id: &Option<String>,
+        workspace_id: &Option<String>,
+    ) -> Result<SandboxRun, RpcMethodError> {
+        let scope = sandbox_scope(
+            state,
+            ctx,
+            project_id.as_deref(),
+            workspace_id.as_deref(),
+            Permission::Execute,
+        )
+        .await?;
+        self.run.scoped(scope).map_err(scope_error)
+    }
+
+    /// Renders `output`; once the session has exited its handle is
+    /// released.
+    async fn session_output(&self, state: &AppState, id: Uuid, output: RunSessionOutput) -> Value {
+        if output.exited {
+            state.affinity.release(id).await;
+        }
+        json!({
+            "stdout": BASE64.encode(&output.stdout),
+            "stderr": BASE64.encode(&output.stderr),
+            "exited": output.exited,
+            "exit_code": output.exit_code,
+            "timed_out": output.timed_out,
+            "duration_ms": output.duration.as_millis() as u64,
+        })
+    }
+}
+
+/// Fuel- and memory-limited wasm functions (`wasm.invoke`).
+pub(crate) struct WasmEngine {
+    pub(crate) wasm: Arc<SandboxWasm>,
+}
+
+#[async_trait]
+impl SandboxEngine for WasmEngine {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn describe(&self) -> Value {
+        let config = self.wasm.config();
+        json!({
+            "root": config.root().display().to_string(),
+            "max_memory_bytes": config.max_memory_bytes(),
+            "max_table_elements": config.max_table_elements(),
+            "default_fuel": config.default_fuel(),
+        })
+    }
+
+    async fn execute(
+        &self,
+        _state: &AppState,
+        ctx: &RequestContext,
+        action: &str,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        if action != "invoke" {
+            return Err(method_not_found());
+        }
+        ctx.require(Permission::Execute)?;
+        let params: WasmInvokeParams = parse_params(params)?;
+        let module_source = resolve_wasm_module(&params)?;
+        let wasm_params = params
+            .params
+            .into_iter()
+            .map(WasmParam::into_value)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| RpcMethodError::new(ErrorCode::InvalidParams, err.as_str(), None))?;
+
+        let mut invocation =
+            WasmInvocation::new(module_source, params.function).with_params(wasm_params);
+        if let Some(fuel) = params.fuel {
+            invocation = invocation.with_fuel(fuel);
+        }
+        if let Some(memory) = params.memory_limit {
+            invocation = invocation.with_memory_limit(memory);
+        }
+        if let Some(table) = params.table_elements_limit {
+            invocation = invocation.with_table_elements_limit(table);
+        }
+
+        let values = self.wasm.invoke(invocation).map_err(|err| {
+            RpcMethodError::from_sandbox(ErrorCode::WasmExecute, "failed to execute wasm", err)
+        })?;
+        let serialized: Vec<Value> = values.into_iter().map(wasm_value_to_json).collect();
+        Ok(json!({ "values": serialized }))
+    }
+}
+
+/// Long-lived interpreter VMs per image (`micro.start`, `micro.execute`,
+/// `micro.stop`). VMs work inside their scope, so they are the one engine
+/// with something to clean up.
+pub(crate) struct MicroEngine {
+    pub(crate) micro: Arc<SandboxMicro>,
+    /// VMs started on a runner stay there.
+    pub(crate) runners: Runners,
+}
+
+#[async_trait]
+impl SandboxEngine for MicroEngine {
+    fn name(&self) -> &'static str {
+        "micro"
+    }
+
+    fn describe(&self) -> Value {
+        let config = self.micro.config();
+        let images: Vec<Value> = config
+            .images()
+            .map(|image| {
+                json!({
+                    "name": image.name(),
+                    "command": image.command(),
+                    "args": image.args().cloned().collect::<Vec<_>>(),
+                    "extension": image.extension(),
+                    "repl": image.repl(),
+                    "env": image
+                        .env()
+                        .map(|(key, value)| json!({ "key": key, "value": value }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let base_env: Vec<Value> = config
+            .base_env()
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        json!({
+            "root": config.root().display().to_string(),
+            "default_timeout_ms": config.default_timeout().as_millis(),
+            "max_timeout_ms": config.max_timeout().as_millis(),
+            "max_output_bytes": config.max_output_bytes(),
+            "images": images,
+            "base_env": base_env,
+        })
+    }
+
+    async fn execute(
+        &self,
+        state: &AppState,
+        ctx: &RequestContext,
+        action: &str,
+        params: Option<Value>,
+    ) -> Result<Value, RpcMethodError> {
+        match action {
+            "start" => {
+                let params: MicroStartParams = parse_params(params)?;
+                ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+                let init_script = match params.init_script.as_deref() {
+                    Some(value) if !value.is_empty() => Some(decode_text(value, "init script")?),
+                    _ => None,
+                };
+                let scope = sandbox_scope(
+                    state,
+                    ctx,
+                    params.project_id.as_deref(),
+                    params.workspace_id.as_deref(),
+                    Permission::Execute,
+                )
+                .await?;
+                let event_scope = json!({
+                    "project_id": params.project_id,
+                    "workspace_id": params.workspace_id,
+                });
+                let remote = Call::MicroStart {
+                    scope: scope.clone(),
+                    image: params.image.clone(),
+                    init_script: init_script.clone(),
+                };
+                let result = match state.runners.offload(remote).await? {
+                    Some((runner, result)) => {
+                        if let Some(vm_id) = result["vm_id"].as_str().and_then(|id| id.parse().ok())
+                        {
+                            state.runners.pin(vm_id, runner);
+                        }
+                        result
+                    }
+                    None => {
+                        let request = MicroStartRequest {
+                            image: params.image,
+                            init_script,
+                            scope: Some(scope),
+                        };
+                        let instance = self.micro.start(request).await.map_err(|err| {
+                            RpcMethodError::from_sandbox(
+                                ErrorCode::MicroStart,
+                                "failed to start micro vm",
+                                err,
+                            )
+                        })?;
+                        json!({
+                            "vm_id": instance.id().to_string(),
+                            "image": instance.image().to_string(),
+                            "working_dir": instance.workdir().display().to_string(),
+                        })
+                    }
+                };
+                if let Some(vm_id) = result["vm_id"].as_str().and_then(|id| id.parse().ok()) {
+                    state.affinity.claim(vm_id, HandleKind::Micro).await;
+                }
+                state.events.publish(DomainEvent::MicroStarted {
+                    user_id: ctx.user_id,
+                    vm_id: result["vm_id"].as_str().unwrap_or_default().to_string(),
+                    image: result["image"].as_str().unwrap_or_default().to_string(),
+                    scope: event_scope,
+                });
+                Ok(result)
+            }
+            "execute" => {
+                let params: MicroExecuteParams = parse_params(params)?;
+                ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+                ctx.ensure_tokens()?;
+                let vm_id = parse_vm_id(&params.vm_id)?;
+                let code = decode_text(&params.code, "code")?;
+                let scope = sandbox_scope(
+                    state,
+                    ctx,
+                    params.project_id.as_deref(),
+                    params.workspace_id.as_deref(),
+                    Permission::Execute,
+                )
+                .await?;
+                let timeout_ms = params.timeout_ms;
+                let result = match state.runners.pinned(&vm_id) {
+                    Some(runner) => {
+                        let remote = Call::MicroExecute {
+                            scope,
+                            vm_id,
+                            code,
+                            timeout_ms,
+                        };
+                        state.runners.call(runner, remote).await?
+                    }
+                    None => {
+                        let request = MicroExecuteRequest {
+                            vm_id,
+                            code,
+                            timeout: timeout_ms.map(Duration::from_millis),
+                            scope: Some(scope),
+                        };
+                        let output = self.micro.execute(request).await.map_err(|err| {
+                            RpcMethodError::from_sandbox(
+                                ErrorCode::MicroExecute,
+                                "failed to execute micro vm code",
+                                err,
+                            )
+                        })?;
+                        process_result(
+                            output.exit_code,
+                            &output.stdout,
+                            &output.stderr,
+                            output.duration,
+                        )
+                    }
+                };
+                let duration = Duration::from_millis(result["duration_ms"].as_u64().unwrap_or(0));
+                state
+                    .billing
+                    .charge(ctx, "micro.execute", Charge::SandboxTime(duration))
+                    .await;
+                Ok(result)
+            }
+            "stop" => {
+                let params: MicroStopParams = parse_params(params)?;
+                ctx.require_for(Permission::Execute, params.project_id.as_deref())?;
+                let vm_id = parse_vm_id(&params.vm_id)?;
+                let scope = sandbox_scope(
+                    state,
+                    ctx,
+                    params.project_id.as_deref(),
+                    params.workspace_id.as_deref(),
+                    Permission::Execute,
+                )
+                .await?;
+                let event_scope = json!({
+                    "project_id": params.project_id,
+                    "workspace_id": params.workspace_id,
+                });
+                let result = match state.runners.pinned(&vm_id) {
+                    Some(runner) => {
+                        let remote = Call::MicroStop { scope, vm_id };
+                        let result = state.runners.call(runner, remote).await;
+                        state.runners.unpin(&vm_id);
+                        result?
+                    }
+                    None => {
+                        self.micro
+                            .stop_in(vm_id, Some(&scope))
+                            .await
+                            .map_err(|err| {
+                                RpcMethodError::from_sandbox(
+                                    ErrorCode::MicroStop,
+                                    "failed to stop micro vm",
+                                    err,
+                                )
+                            })?;
+                        json!({ "status": "ok" })
+                    }
+                };
+                state.affinity.release(vm_id).await;
+                state.events.publish(DomainEvent::MicroStopped {
+                    user_id: ctx.user_id,
+                    vm_id,
+                    scope: event_scope,
+                });
+                Ok(result)
+            }
+            _ => Err(method_not_found()),
+        }
+    }
+
+    async fn cleanup(&self, scope: &Path) -> sandbox::Result<usize> {
+        let stopped = self.micro.stop_scope(scope).await?;
+        Ok(stopped + self.runners.cleanup(scope).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl SandboxEngine for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn describe(&self) -> Value {
+            json!({ "engine": self.0 })
+        }
+
+        async fn execute(
+            &self,
+            _state: &AppState,
+            _ctx: &RequestContext,
+            _action: &str,
+            _params: Option<Value>,
+        ) -> Result<Value, RpcMethodError> {
+            Err(method_not_found())
+        }
+
+        async fn cleanup(&self, _scope: &Path) -> sandbox::Result<usize> {
+            Ok(2)
+        }
+    }
+
+    #[tokio::test]
+    async fn engines_are_routed_by_method_prefix() {
+        let engines = Engines::default();
+        engines.register(Arc::new(Named("gvisor"))).unwrap();
+        engines.register(Arc::new(Named("remote"))).unwrap();
+        assert!(engines.register(Arc::new(Named("gvisor"))).is_err());
+        assert!(engines.register(Arc::new(Named("a.b"))).is_err());
+
+        let (engine, action) = engines.route("gvisor.exec").unwrap();
+        assert_eq!(engine.describe()["engine"], "gvisor");
+        assert_eq!(action, "exec");
+        assert_eq!(engines.route("remote.describe").unwrap().1, "describe");
+        assert!(engines.route("fs.read").is_none());
+        assert!(engines.route("gvisor").is_none());
+
+        assert_eq!(
+            engines
+                .cleanup(Path::new("tenants/1/projects/p"))
+                .await
+                .unwrap(),
+            4
+        );
+    }
+}
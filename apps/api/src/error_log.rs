@@ -0,0 +1,217 @@
+//! The most recent failed calls, kept in memory for `errors.recent` so
+//! support can look up the request id a user reports and search the logs
+//! for it. Every call carries a request id: the caller's `X-Request-Id` for
+//! single calls, a fresh one per entry of a batch. It is a field of the
+//! `rpc` span and of the error logs, and failed calls return it as
+//! `data.request_id`.
+//!
+//! The ring holds `ERRORS_RECENT_CAPACITY` entries per API instance and is
+//! lost on restart; the audit log is the durable record.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::errors::ErrorCode;
+use crate::{tenant, RequestContext, RpcMethodError};
+
+const DEFAULT_PAGE: usize = 50;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    request_id: Uuid,
+    method: String,
+    code: i64,
+    message: String,
+    data: Option<Value>,
+    user_id: i32,
+    tenant_id: i32,
+    api_key_id: Option<Uuid>,
+    latency_ms: u64,
+    at: DateTime<Utc>,
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        json!({
+            "request_id": self.request_id,
+            "method": self.method,
+            "code": self.code,
+            "message": self.message,
+            "data": self.data,
+            "user_id": self.user_id,
+            "tenant_id": self.tenant_id,
+            "api_key_id": self.api_key_id,
+            "latency_ms": self.latency_ms,
+            "at": self.at.to_rfc3339(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorLog {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+}
+
+impl ErrorLog {
+    /// Keeps the last `capacity` failed calls; 0 keeps nothing.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity.min(1024)))),
+        }
+    }
+
+    /// Remembers a failed call, dropping the oldest entry when full. `err`
+    /// should already carry the request id.
+    pub(crate) fn record(
+        &self,
+        ctx: &RequestContext,
+        method: &str,
+        err: &RpcMethodError,
+        latency: Duration,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = Entry {
+            request_id: ctx.request_id,
+            method: method.to_string(),
+            code: err.code,
+            message: err.message.clone(),
+            data: err.data.clone(),
+            user_id: ctx.user_id,
+            tenant_id: ctx.tenant_id,
+            api_key_id: ctx.api_key_id,
+            latency_ms: latency.as_millis().min(u64::MAX as u128) as u64,
+            at: Utc::now(),
+        };
+        let mut entries = self.entries.lock();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub(crate) struct ErrorsRecentParams {
+    /// Only the call with this request id.
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    code: Option<i64>,
+    /// Defaults to 50.
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Matching entries, newest first. Admins of the default tenant see every
+/// tenant's errors, everyone else only their own tenant's.
+pub(crate) fn recent(
+    log: &ErrorLog,
+    ctx: &RequestContext,
+    params: ErrorsRecentParams,
+) -> Result<Value, RpcMethodError> {
+    let request_id = params
+        .request_id
+        .as_deref()
+        .map(|raw| {
+            Uuid::parse_str(raw.trim()).map_err(|_| {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid request id",
+                    Some(json!({ "request_id": raw })),
+                )
+            })
+        })
+        .transpose()?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE)
+        .clamp(1, log.capacity.max(1));
+    let all_tenants = ctx.tenant_id == tenant::DEFAULT_TENANT;
+    let entries = log.entries.lock();
+    let errors: Vec<Value> = entries
+        .iter()
+        .rev()
+        .filter(|entry| all_tenants || entry.tenant_id == ctx.tenant_id)
+        .filter(|entry| request_id.is_none_or(|id| entry.request_id == id))
+        .filter(|entry| params.method.as_ref().is_none_or(|m| &entry.method == m))
+        .filter(|entry| params.user_id.is_none_or(|id| entry.user_id == id))
+        .filter(|entry| params.code.is_none_or(|code| entry.code == code))
+        .take(limit)
+        .map(Entry::to_json)
+        .collect();
+    Ok(json!({ "errors": errors, "capacity": log.capacity }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(user_id: i32, tenant_id: i32) -> RequestContext {
+        RequestContext {
+            user_id,
+            username: format!("user{user_id}"),
+            role: crate::Role::Admin,
+            tenant_id,
+            permissions: Default::default(),
+            token_balance: 0,
+            api_key_id: None,
+            client_ip: None,
+            request_id: Uuid::new_v4(),
+            trace_parent: opentelemetry::Context::new(),
+            credentials: Default::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_entries_per_tenant() {
+        let log = ErrorLog::new(3);
+        let err = RpcMethodError::new(ErrorCode::FsRead, "failed to read file", None);
+        let callers = [ctx(1, 1), ctx(2, 2), ctx(3, 2), ctx(4, 2)];
+        for caller in &callers {
+            log.record(caller, "fs.read", &err, Duration::from_millis(5));
+        }
+
+        let operator = ctx(1, tenant::DEFAULT_TENANT);
+        let all = recent(&log, &operator, ErrorsRecentParams::default()).unwrap();
+        let users: Vec<i64> = all["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["user_id"].as_i64().unwrap())
+            .collect();
+        assert_eq!(users, vec![4, 3, 2]);
+
+        let params = ErrorsRecentParams {
+            request_id: Some(callers[2].request_id.to_string()),
+            ..Default::default()
+        };
+        let found = recent(&log, &ctx(9, 2), params).unwrap();
+        assert_eq!(found["errors"][0]["user_id"], 3);
+        assert_eq!(found["errors"].as_array().unwrap().len(), 1);
+
+        let other_tenant = recent(&log, &ctx(9, 3), ErrorsRecentParams::default()).unwrap();
+        assert!(other_tenant["errors"].as_array().unwrap().is_empty());
+
+        let params = ErrorsRecentParams {
+            request_id: Some("nope".into()),
+            ..Default::default()
+        };
+        assert_eq!(recent(&log, &operator, params).unwrap_err().code, -32602);
+    }
+}
@@ -0,0 +1,265 @@
+//! Catalog of the error codes the gateway returns. Handlers build errors
+//! from an [`ErrorCode`] instead of a bare number, the gRPC and REST
+//! transports map codes through it, and `rpc.errors` publishes it with a
+//! retry hint and a link into `docs/Fehlercodes.md` so SDKs can generate
+//! their constants instead of hard-coding them.
+
+use serde_json::{json, Value};
+
+/// Every row here needs an entry (anchor `err<code>`) in the docs page.
+const DOCS_PAGE: &str = "docs/Fehlercodes.md";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub(crate) enum ErrorCode {
+    InvalidRequest = -32600,
+    MethodNotFound = -32601,
+    InvalidParams = -32602,
+    Internal = -32603,
+    FsRead = -32001,
+    FsWrite = -32002,
+    FsList = -32003,
+    FsDelete = -32004,
+    FsMkdir = -32005,
+    SandboxScope = -32006,
+    RunExecute = -32010,
+    WasmExecute = -32020,
+    MicroStart = -32030,
+    MicroExecute = -32031,
+    MicroStop = -32032,
+    AgentDispatch = -32040,
+    AgentTaskNotFound = -32041,
+    AgentCancel = -32042,
+    AgentContext = -32043,
+    LlmNotFound = -32044,
+    AgentHistory = -32045,
+    AgentResume = -32046,
+    AgentApply = -32047,
+    LlmUpstream = -32048,
+    ProjectPrepare = -32050,
+    ProjectFileSave = -32051,
+    ProjectConflict = -32052,
+    ProjectFileDelete = -32053,
+    ProjectFilesRemove = -32054,
+    ProjectNotFound = -32055,
+    ProjectFileVersionNotFound = -32056,
+    ProgramNotAllowed = -32057,
+    WorkspaceNotFound = -32058,
+    WorkspaceLimit = -32059,
+    QuotaExceeded = -32060,
+    UserNotFound = -32061,
+    MethodDisabled = -32062,
+    WebhookNotFound = -32063,
+    WebhookLimit = -32064,
+    RoleNotFound = -32065,
+    GrantNotFound = -32066,
+    ScheduleNotFound = -32067,
+    JobNotFound = -32068,
+    JobState = -32069,
+    InvalidConfiguration = -32070,
+    FeatureDisabled = -32071,
+    ProjectSnapshotNotFound = -32072,
+    Git = -32073,
+    Unauthorized = -32090,
+    Forbidden = -32091,
+    InsufficientBalance = -32092,
+    LlmQuotaExhausted = -32093,
+    RateLimited = -32094,
+    EmailNotVerified = -32095,
+    Overloaded = -32096,
+    Timeout = -32097,
+    ResponseTooLarge = -32098,
+}
+
+impl ErrorCode {
+    pub(crate) const ALL: [ErrorCode; 57] = [
+        Self::InvalidRequest,
+        Self::MethodNotFound,
+        Self::InvalidParams,
+        Self::Internal,
+        Self::FsRead,
+        Self::FsWrite,
+        Self::FsList,
+        Self::FsDelete,
+        Self::FsMkdir,
+        Self::SandboxScope,
+        Self::RunExecute,
+        Self::WasmExecute,
+        Self::MicroStart,
+        Self::MicroExecute,
+        Self::MicroStop,
+        Self::AgentDispatch,
+        Self::AgentTaskNotFound,
+        Self::AgentCancel,
+        Self::AgentContext,
+        Self::LlmNotFound,
+        Self::AgentHistory,
+        Self::AgentResume,
+        Self::AgentApply,
+        Self::LlmUpstream,
+        Self::ProjectPrepare,
+        Self::ProjectFileSave,
+        Self::ProjectConflict,
+        Self::ProjectFileDelete,
+        Self::ProjectFilesRemove,
+        Self::ProjectNotFound,
+        Self::ProjectFileVersionNotFound,
+        Self::ProgramNotAllowed,
+        Self::WorkspaceNotFound,
+        Self::WorkspaceLimit,
+        Self::QuotaExceeded,
+        Self::UserNotFound,
+        Self::MethodDisabled,
+        Self::WebhookNotFound,
+        Self::WebhookLimit,
+        Self::RoleNotFound,
+        Self::GrantNotFound,
+        Self::ScheduleNotFound,
+        Self::JobNotFound,
+        Self::JobState,
+        Self::InvalidConfiguration,
+        Self::FeatureDisabled,
+        Self::ProjectSnapshotNotFound,
+        Self::Git,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::InsufficientBalance,
+        Self::LlmQuotaExhausted,
+        Self::RateLimited,
+        Self::EmailNotVerified,
+        Self::Overloaded,
+        Self::Timeout,
+        Self::ResponseTooLarge,
+    ];
+
+    pub(crate) fn code(self) -> i64 {
+        self as i64
+    }
+
+    pub(crate) fn from_code(code: i64) -> Option<Self> {
+        Self::ALL.into_iter().find(|known| known.code() == code)
+    }
+
+    /// The generic message; call sites may use a more specific one.
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "invalid request",
+            Self::MethodNotFound => "method not found",
+            Self::InvalidParams => "invalid params",
+            Self::Internal => "internal error",
+            Self::FsRead => "failed to read file",
+            Self::FsWrite => "failed to write file",
+            Self::FsList => "failed to list directory",
+            Self::FsDelete => "failed to delete path",
+            Self::FsMkdir => "failed to create directory",
+            Self::SandboxScope => "failed to prepare sandbox scope",
+            Self::RunExecute => "failed to execute process",
+            Self::WasmExecute => "failed to execute wasm",
+            Self::MicroStart => "failed to start micro vm",
+            Self::MicroExecute => "failed to execute micro vm code",
+            Self::MicroStop => "failed to stop micro vm",
+            Self::AgentDispatch => "failed to dispatch agent",
+            Self::AgentTaskNotFound => "agent task not found",
+            Self::AgentCancel => "failed to cancel agent",
+            Self::AgentContext => "failed to prepare agent context",
+            Self::LlmNotFound => "llm resource not found",
+            Self::AgentHistory => "failed to load agent history",
+            Self::AgentResume => "failed to resume agent task",
+            Self::AgentApply => "failed to apply agent actions",
+            Self::LlmUpstream => "llm provider rejected the request",
+            Self::ProjectPrepare => "failed to prepare project",
+            Self::ProjectFileSave => "failed to persist project file",
+            Self::ProjectConflict => "project conflict or project file not found",
+            Self::ProjectFileDelete => "failed to delete project file",
+            Self::ProjectFilesRemove => "failed to remove project files",
+            Self::ProjectNotFound => "project not found",
+            Self::ProjectFileVersionNotFound => "project file version not found",
+            Self::ProgramNotAllowed => "program not allowed in project",
+            Self::WorkspaceNotFound => "workspace not found",
+            Self::WorkspaceLimit => "workspace limit reached",
+            Self::QuotaExceeded => "quota exceeded",
+            Self::UserNotFound => "user not found",
+            Self::MethodDisabled => "method disabled",
+            Self::WebhookNotFound => "webhook not found",
+            Self::WebhookLimit => "webhook limit reached",
+            Self::RoleNotFound => "role not found",
+            Self::GrantNotFound => "grant not found",
+            Self::ScheduleNotFound => "schedule not found",
+            Self::JobNotFound => "job not found",
+            Self::JobState => "job is not in a state that allows this",
+            Self::InvalidConfiguration => "invalid configuration",
+            Self::FeatureDisabled => "feature disabled",
+            Self::ProjectSnapshotNotFound => "project snapshot not found",
+            Self::Git => "git operation failed",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden => "forbidden",
+            Self::InsufficientBalance => "insufficient token balance",
+            Self::LlmQuotaExhausted => "llm quota exhausted",
+            Self::RateLimited => "rate limited",
+            Self::EmailNotVerified => "email address not verified",
+            Self::Overloaded => "server overloaded",
+            Self::Timeout => "method timed out",
+            Self::ResponseTooLarge => "response too large",
+        }
+    }
+
+    /// Whether repeating the identical call later can succeed. Everything
+    /// else needs different input, permissions or an operator.
+    pub(crate) fn retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Internal
+                | Self::LlmUpstream
+                | Self::RateLimited
+                | Self::Overloaded
+                | Self::Timeout
+        )
+    }
+
+    pub(crate) fn docs_url(self) -> String {
+        format!("{DOCS_PAGE}#err{}", self.code())
+    }
+}
+
+/// `rpc.errors`: the catalog in code order.
+pub(crate) fn catalog() -> Value {
+    let mut codes = ErrorCode::ALL;
+    codes.sort_by_key(|code| std::cmp::Reverse(code.code()));
+    let errors: Vec<Value> = codes
+        .iter()
+        .map(|code| {
+            json!({
+                "code": code.code(),
+                "name": format!("{code:?}"),
+                "message": code.message(),
+                "retryable": code.retryable(),
+                "docs": code.docs_url(),
+            })
+        })
+        .collect();
+    json!({ "errors": errors })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn catalog_codes_are_unique_and_documented() {
+        let docs = include_str!("../../../docs/Fehlercodes.md");
+        let mut seen = HashSet::new();
+        for code in ErrorCode::ALL {
+            assert!(seen.insert(code.code()), "{code:?} listed twice");
+            assert_eq!(ErrorCode::from_code(code.code()), Some(code));
+            assert!(
+                docs.contains(&format!("id=\"err{}\"", code.code())),
+                "{code:?} is missing from {DOCS_PAGE}"
+            );
+        }
+        assert_eq!(ErrorCode::from_code(-1), None);
+        assert_eq!(catalog()["errors"][0]["code"], -32001);
+        assert_eq!(catalog()["errors"][56]["code"], -32603);
+    }
+}
@@ -0,0 +1,466 @@
+//! The domain event bus. Handlers publish what happened as a
+//! [`DomainEvent`] and go on, so a new consumer is a registration rather
+//! than another call in every handler. Webhooks and the notification inbox
+//! are [`EventRecorder`]s: `publish` hands them every event before it
+//! returns, and they store it in the background as the handlers used to.
+//! The `/notify/ws` sockets subscribe to the broadcast instead. With
+//! `EVENT_BUS_URL` (`nats://…` or `redis://…`) every event is also
+//! published as JSON on `<EVENT_BUS_SUBJECT>.<kind>` for services outside
+//! the api. Delivery to subscribers is at most once: a subscriber that
+//! falls more than `EVENT_BUS_CAPACITY` events behind and events published
+//! while the broker is unreachable are logged and dropped.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use redis::AsyncCommands;
+use sandbox::{AgentDispatcher, AgentEvent, AgentTaskSnapshot};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub(crate) struct EventBusConfig {
+    capacity: usize,
+    publish_url: Option<String>,
+    subject: String,
+}
+
+impl EventBusConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let publish_url = config.secret("EVENT_BUS_URL");
+        if let Some(url) = &publish_url {
+            if Broker::scheme(url).is_none() {
+                config.invalid("EVENT_BUS_URL", "must be a nats:// or redis:// url");
+            }
+        }
+        Self {
+            capacity: config.get("EVENT_BUS_CAPACITY", 4096).max(16),
+            publish_url,
+            subject: config.string("EVENT_BUS_SUBJECT", "coder.events"),
+        }
+    }
+}
+
+/// Something that happened, with whatever its consumers need to know.
+#[derive(Debug, Clone)]
+pub(crate) enum DomainEvent {
+    /// An entry in a project's activity feed; `action` is its kind, such
+    /// as `project.file.save`.
+    Project {
+        user_id: i32,
+        project_id: Uuid,
+        action: &'static str,
+        detail: Value,
+    },
+    ProjectDeleted {
+        user_id: i32,
+        project_id: Uuid,
+    },
+    ProjectShared {
+        user_id: i32,
+        project_id: String,
+        permission: Value,
+        shared_by: String,
+    },
+    RunCompleted {
+        user_id: i32,
+        program: String,
+        /// Missing when a runner reported none, e.g. for a killed process.
+        exit_code: Option<i64>,
+        duration: Duration,
+        scope: Value,
+    },
+    MicroStarted {
+        user_id: i32,
+        vm_id: String,
+        image: String,
+        scope: Value,
+    },
+    MicroStopped {
+        user_id: i32,
+        vm_id: Uuid,
+        scope: Value,
+    },
+    /// An agent task changed status.
+    Agent(Box<AgentTaskSnapshot>),
+    QuotaWarning {
+        user_id: i32,
+        quota: &'static str,
+        limit: Option<i64>,
+        used: i64,
+        percent: i64,
+    },
+    JobFinished {
+        user_id: i32,
+        job_id: i64,
+        kind: &'static str,
+        succeeded: bool,
+        error: Option<String>,
+    },
+}
+
+impl DomainEvent {
+    /// The event kind webhooks filter on, such as `run.completed`.
+    pub(crate) fn kind(&self) -> String {
+        match self {
+            DomainEvent::Project { action, .. } => action.to_string(),
+            DomainEvent::ProjectDeleted { .. } => "project.deleted".to_string(),
+            DomainEvent::ProjectShared { .. } => "project.shared".to_string(),
+            DomainEvent::RunCompleted { .. } => "run.completed".to_string(),
+            DomainEvent::MicroStarted { .. } => "micro.started".to_string(),
+            DomainEvent::MicroStopped { .. } => "micro.stopped".to_string(),
+            DomainEvent::Agent(snapshot) => match serde_json::to_value(snapshot.status) {
+                Ok(Value::String(status)) => format!("agent.task.{status}"),
+                _ => "agent.task".to_string(),
+            },
+            DomainEvent::QuotaWarning { .. } => "quota.warning".to_string(),
+            DomainEvent::JobFinished {
+                succeeded: true, ..
+            } => "job.succeeded".to_string(),
+            DomainEvent::JobFinished { .. } => "job.failed".to_string(),
+        }
+    }
+
+    /// The user the event belongs to; agent tasks dispatched outside a
+    /// request have none.
+    pub(crate) fn user_id(&self) -> Option<i32> {
+        match self {
+            DomainEvent::Project { user_id, .. }
+            | DomainEvent::ProjectDeleted { user_id, .. }
+            | DomainEvent::ProjectShared { user_id, .. }
+            | DomainEvent::RunCompleted { user_id, .. }
+            | DomainEvent::MicroStarted { user_id, .. }
+            | DomainEvent::MicroStopped { user_id, .. }
+            | DomainEvent::QuotaWarning { user_id, .. }
+            | DomainEvent::JobFinished { user_id, .. } => Some(*user_id),
+            DomainEvent::Agent(snapshot) => snapshot
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get("requested_by_id"))
+                .and_then(Value::as_i64)
+                .and_then(|id| i32::try_from(id).ok()),
+        }
+    }
+
+    /// The event's payload, as webhooks deliver it.
+    pub(crate) fn data(&self) -> Value {
+        match self {
+            DomainEvent::Project {
+                project_id, detail, ..
+            } => json!({ "project_id": project_id, "detail": detail }),
+            DomainEvent::ProjectDeleted { project_id, .. } => json!({ "project_id": project_id }),
+            DomainEvent::ProjectShared {
+                project_id,
+                permission,
+                shared_by,
+                ..
+            } => json!({
+                "project_id": project_id,
+                "permission": permission,
+                "shared_by": shared_by,
+            }),
+            DomainEvent::RunCompleted {
+                program,
+                exit_code,
+                duration,
+                scope,
+                ..
+            } => json!({
+                "program": program,
+                "exit_code": exit_code,
+                "duration_ms": duration.as_millis() as u64,
+                "scope": scope,
+            }),
+            DomainEvent::MicroStarted {
+                vm_id,
+                image,
+                scope,
+                ..
+            } => json!({ "vm_id": vm_id, "image": image, "scope": scope }),
+            DomainEvent::MicroStopped { vm_id, scope, .. } => {
+                json!({ "vm_id": vm_id, "scope": scope })
+            }
+            DomainEvent::Agent(snapshot) => serde_json::to_value(snapshot).unwrap_or(Value::Null),
+            DomainEvent::QuotaWarning {
+                quota,
+                limit,
+                used,
+                percent,
+                ..
+            } => json!({
+                "quota": quota,
+                "limit": limit,
+                "used": used,
+                "percent": percent,
+            }),
+            DomainEvent::JobFinished {
+                job_id,
+                kind,
+                error,
+                ..
+            } => json!({ "job_id": job_id, "kind": kind, "error": error }),
+        }
+    }
+
+    /// The form pushed to sockets and published to the broker.
+    pub(crate) fn envelope(&self) -> Value {
+        json!({
+            "kind": self.kind(),
+            "user_id": self.user_id(),
+            "data": self.data(),
+            "occurred_at": Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+/// A consumer that must see every event, unlike a broadcast subscriber.
+/// `record` runs on the publishing task and must not block.
+pub(crate) trait EventRecorder: Send + Sync {
+    fn record(&self, event: &DomainEvent);
+}
+
+#[derive(Clone)]
+pub(crate) struct EventBus {
+    config: Arc<EventBusConfig>,
+    live: broadcast::Sender<Arc<DomainEvent>>,
+    recorders: Arc<[Arc<dyn EventRecorder>]>,
+}
+
+impl EventBus {
+    pub(crate) fn new(config: EventBusConfig, recorders: Vec<Arc<dyn EventRecorder>>) -> Self {
+        let (live, _) = broadcast::channel(config.capacity);
+        Self {
+            config: Arc::new(config),
+            live,
+            recorders: recorders.into(),
+        }
+    }
+
+    /// Hands `event` to every recorder and subscriber; never blocks and
+    /// never fails.
+    pub(crate) fn publish(&self, event: DomainEvent) {
+        for recorder in self.recorders.iter() {
+            recorder.record(&event);
+        }
+        let _ = self.live.send(Arc::new(event));
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<DomainEvent>> {
+        self.live.subscribe()
+    }
+
+    /// Publishes agent task transitions as [`DomainEvent::Agent`].
+    pub(crate) fn spawn_agent_bridge(&self, agents: &AgentDispatcher) -> JoinHandle<()> {
+        let bus = self.clone();
+        let mut events = agents.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(AgentEvent::Status(snapshot)) => bus.publish(DomainEvent::Agent(snapshot)),
+                    Ok(AgentEvent::Output(_)) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "event bus agent bridge lagged behind");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Forwards every event to `EVENT_BUS_URL`, reconnecting after
+    /// failures; does nothing without one.
+    pub(crate) fn spawn_publisher(&self) -> Option<JoinHandle<()>> {
+        let url = self.config.publish_url.clone()?;
+        let subject = self.config.subject.clone();
+        let mut events = self.subscribe();
+        Some(tokio::spawn(async move {
+            let mut broker: Option<Broker> = None;
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "event publisher lagged behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if broker.is_none() {
+                    match Broker::connect(&url).await {
+                        Ok(connected) => {
+                            info!("connected to the event broker");
+                            broker = Some(connected);
+                        }
+                        Err(err) => {
+                            warn!(error = %format!("{err:#}"), "failed to connect to the event broker, dropping event");
+                            tokio::time::sleep(RECONNECT_DELAY).await;
+                            continue;
+                        }
+                    }
+                }
+                let Some(connected) = broker.as_mut() else {
+                    continue;
+                };
+                let topic = format!("{subject}.{}", event.kind());
+                if let Err(err) = connected.publish(topic, event.envelope().to_string()).await {
+                    warn!(error = %format!("{err:#}"), "failed to publish event, reconnecting");
+                    broker = None;
+                }
+            }
+        }))
+    }
+}
+
+/// The external broker events are mirrored to.
+enum Broker {
+    Nats(async_nats::Client),
+    Redis(redis::aio::MultiplexedConnection),
+}
+
+impl Broker {
+    fn scheme(url: &str) -> Option<&'static str> {
+        let (scheme, _) = url.split_once("://")?;
+        match scheme {
+            "nats" | "tls" => Some("nats"),
+            "redis" | "rediss" => Some("redis"),
+            _ => None,
+        }
+    }
+
+    async fn connect(url: &str) -> anyhow::Result<Self> {
+        match Self::scheme(url) {
+            Some("nats") => Ok(Broker::Nats(async_nats::connect(url).await?)),
+            Some(_) => {
+                let client = redis::Client::open(url)?;
+                Ok(Broker::Redis(
+                    client.get_multiplexed_tokio_connection().await?,
+                ))
+            }
+            None => anyhow::bail!("unsupported event broker url"),
+        }
+    }
+
+    async fn publish(&mut self, topic: String, payload: String) -> anyhow::Result<()> {
+        match self {
+            Broker::Nats(client) => {
+                client.publish(topic, payload.into()).await?;
+                client.flush().await?;
+            }
+            Broker::Redis(connection) => {
+                connection.publish::<_, _, i64>(topic, payload).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    fn config() -> EventBusConfig {
+        EventBusConfig {
+            capacity: 16,
+            publish_url: None,
+            subject: "coder.events".to_string(),
+        }
+    }
+
+    #[derive(Default)]
+    struct Kinds(Mutex<Vec<String>>);
+
+    impl EventRecorder for Kinds {
+        fn record(&self, event: &DomainEvent) {
+            self.0.lock().unwrap().push(event.kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_typed_events_with_their_kind() {
+        let bus = EventBus::new(config(), Vec::new());
+        let mut events = bus.subscribe();
+        bus.publish(DomainEvent::RunCompleted {
+            user_id: 7,
+            program: "/bin/sh".to_string(),
+            exit_code: Some(0),
+            duration: Duration::from_millis(1500),
+            scope: json!({ "project_id": null }),
+        });
+        bus.publish(DomainEvent::JobFinished {
+            user_id: 7,
+            job_id: 3,
+            kind: "project.export",
+            succeeded: false,
+            error: Some("disk full".to_string()),
+        });
+
+        let run = events.recv().await.unwrap();
+        assert_eq!(run.kind(), "run.completed");
+        assert_eq!(run.user_id(), Some(7));
+        assert_eq!(run.data()["duration_ms"], 1500);
+        let job = events.recv().await.unwrap();
+        assert_eq!(job.kind(), "job.failed");
+        assert_eq!(job.envelope()["data"]["error"], "disk full");
+        assert!(bus.spawn_publisher().is_none());
+    }
+
+    #[test]
+    fn recorders_see_every_event_while_subscribers_lag() {
+        let kinds = Arc::new(Kinds::default());
+        let bus = EventBus::new(config(), vec![kinds.clone()]);
+        let mut lagging = bus.subscribe();
+        for job_id in 0..40 {
+            bus.publish(DomainEvent::JobFinished {
+                user_id: 7,
+                job_id,
+                kind: "project.export",
+                succeeded: true,
+                error: None,
+            });
+        }
+
+        assert_eq!(kinds.0.lock().unwrap().len(), 40);
+        assert!(matches!(
+            lagging.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(24))
+        ));
+    }
+
+    #[test]
+    fn run_completed_keeps_its_webhook_payload() {
+        let event = DomainEvent::RunCompleted {
+            user_id: 7,
+            program: "/usr/bin/env".to_string(),
+            exit_code: None,
+            duration: Duration::from_millis(250),
+            scope: json!({ "project_id": null, "workspace_id": "ws" }),
+        };
+
+        assert_eq!(
+            event.data(),
+            json!({
+                "program": "/usr/bin/env",
+                "exit_code": null,
+                "duration_ms": 250,
+                "scope": { "project_id": null, "workspace_id": "ws" },
+            })
+        );
+    }
+
+    #[test]
+    fn only_nats_and_redis_brokers_are_accepted() {
+        assert_eq!(Broker::scheme("nats://localhost:4222"), Some("nats"));
+        assert_eq!(Broker::scheme("rediss://cache:6380/0"), Some("redis"));
+        assert_eq!(Broker::scheme("amqp://rabbit"), None);
+        assert_eq!(Broker::scheme("localhost:4222"), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Chaos mode for staging: with `CHAOS_ENABLED=true` the sandbox file
+//! system, the process runner and the LLM client slow down or fail a share
+//! of their operations on purpose, so retries, circuit breakers and error
+//! mapping can be watched without breaking a real dependency. Per subsystem
+//! (`FS`, `RUN`, `LLM`):
+//!
+//! - `CHAOS_<SUB>_LATENCY_MS`: extra delay of a slowed-down operation
+//! - `CHAOS_<SUB>_LATENCY_RATE`: share of operations delayed, `0`–`1`
+//! - `CHAOS_<SUB>_ERROR_RATE`: share of operations failing, `0`–`1`
+//!
+//! File system and runner failures are IO errors; LLM failures look like a
+//! provider answering 503. The settings are ignored unless chaos mode is on.
+//! Injected faults are counted in `api_chaos_faults_total` on `/metrics`.
+
+use sandbox::{FaultConfig, FaultCounts, FaultInjector};
+use tracing::warn;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ChaosConfig {
+    fs: FaultConfig,
+    run: FaultConfig,
+    llm: FaultConfig,
+}
+
+impl ChaosConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        if !config.get("CHAOS_ENABLED", false) {
+            return Self::default();
+        }
+        Self {
+            fs: FaultConfig {
+                latency: config.millis("CHAOS_FS_LATENCY_MS", 0),
+                latency_rate: rate(config, "CHAOS_FS_LATENCY_RATE"),
+                error_rate: rate(config, "CHAOS_FS_ERROR_RATE"),
+            },
+            run: FaultConfig {
+                latency: config.millis("CHAOS_RUN_LATENCY_MS", 0),
+                latency_rate: rate(config, "CHAOS_RUN_LATENCY_RATE"),
+                error_rate: rate(config, "CHAOS_RUN_ERROR_RATE"),
+            },
+            llm: FaultConfig {
+                latency: config.millis("CHAOS_LLM_LATENCY_MS", 0),
+                latency_rate: rate(config, "CHAOS_LLM_LATENCY_RATE"),
+                error_rate: rate(config, "CHAOS_LLM_ERROR_RATE"),
+            },
+        }
+    }
+}
+
+fn rate(config: &Config, key: &'static str) -> f64 {
+    let rate = config.get(key, 0.0);
+    if !(0.0..=1.0).contains(&rate) {
+        config.invalid(key, "must be between 0 and 1");
+    }
+    rate
+}
+
+/// One injector per subsystem; disabled ones do nothing.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faults {
+    pub(crate) fs: FaultInjector,
+    pub(crate) run: FaultInjector,
+    pub(crate) llm: FaultInjector,
+}
+
+impl Faults {
+    pub(crate) fn new(config: ChaosConfig) -> Self {
+        let faults = Self {
+            fs: FaultInjector::new(config.fs),
+            run: FaultInjector::new(config.run),
+            llm: FaultInjector::new(config.llm),
+        };
+        for (subsystem, injector, config) in [
+            ("fs", &faults.fs, config.fs),
+            ("run", &faults.run, config.run),
+            ("llm", &faults.llm, config.llm),
+        ] {
+            if injector.is_enabled() {
+                warn!(
+                    subsystem,
+                    latency = ?config.latency,
+                    latency_rate = config.latency_rate,
+                    error_rate = config.error_rate,
+                    "chaos mode injects faults"
+                );
+            }
+        }
+        faults
+    }
+
+    /// Faults injected so far by the enabled injectors, by subsystem.
+    pub(crate) fn counts(&self) -> Vec<(&'static str, FaultCounts)> {
+        [("fs", &self.fs), ("run", &self.run), ("llm", &self.llm)]
+            .into_iter()
+            .filter(|(_, injector)| injector.is_enabled())
+            .map(|(subsystem, injector)| (subsystem, injector.counts()))
+            .collect()
+    }
+}
@@ -0,0 +1,435 @@
+//! Feature flags for rolling capabilities out gradually and switching them
+//! off when they misbehave. Every flag has a default from `FEATURE_FLAGS`
+//! (on unless configured otherwise), which `feature_flag_overrides` can
+//! override for everyone, for one role or for one user; the most specific
+//! override wins. `process_request` checks the flag of the method it is
+//! about to run and the REST facade checks `rest` and `streaming`, so a
+//! disabled capability fails with -32071 before any of its code runs.
+//!
+//! Overrides are cached for `FEATURE_FLAGS_CACHE_TTL_SECS`. `admin.flags.*`
+//! drops the cache, so a change applies on the next request here and within
+//! the TTL on every other API instance.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Error as SqlxError, PgPool, Row};
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{RequestContext, RpcMethodError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Flag {
+    /// Server-sent event routes of the REST facade.
+    Streaming,
+    /// Agents that call tools on their own.
+    Agents,
+    /// The REST facade as a whole.
+    Rest,
+}
+
+impl Flag {
+    pub(crate) const ALL: [Flag; 3] = [Flag::Streaming, Flag::Agents, Flag::Rest];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Streaming => "streaming",
+            Self::Agents => "agents",
+            Self::Rest => "rest",
+        }
+    }
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|flag| flag.as_str() == name)
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Streaming => "POST /llm/chat/stream and POST /sandbox/{method}/stream",
+            Self::Agents => "agent.dispatch and agent.pipeline",
+            Self::Rest => "every route of the REST facade",
+        }
+    }
+
+    /// RPC methods that only run while the flag is on.
+    fn methods(self) -> &'static [&'static str] {
+        match self {
+            Self::Agents => &["agent.dispatch", "agent.pipeline"],
+            Self::Streaming | Self::Rest => &[],
+        }
+    }
+
+    fn for_method(method: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|flag| flag.methods().contains(&method))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FlagConfig {
+    defaults: [bool; Flag::ALL.len()],
+    ttl: Duration,
+}
+
+impl FlagConfig {
+    /// `FEATURE_FLAGS` is a comma-separated list of `flag=on|off`.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut defaults = [true; Flag::ALL.len()];
+        for (name, value) in config.pairs("FEATURE_FLAGS") {
+            let Some(flag) = Flag::parse(&name) else {
+                config.invalid("FEATURE_FLAGS", format!("unknown flag `{name}`"));
+                continue;
+            };
+            match value.as_str() {
+                "on" | "true" => defaults[flag as usize] = true,
+                "off" | "false" => defaults[flag as usize] = false,
+                _ => config.invalid(
+                    "FEATURE_FLAGS",
+                    format!("`{name}={value}`: expected on or off"),
+                ),
+            }
+        }
+        Self {
+            defaults,
+            ttl: config.secs("FEATURE_FLAGS_CACHE_TTL_SECS", 5),
+        }
+    }
+
+    fn default_for(&self, flag: Flag) -> bool {
+        self.defaults[flag as usize]
+    }
+}
+
+/// Who an override applies to. `Role` and `User` are mutually exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Everyone,
+    Role(String),
+    User(i32),
+}
+
+impl Target {
+    fn new(role: Option<String>, user_id: Option<i32>) -> Result<Self, RpcMethodError> {
+        match (role, user_id) {
+            (None, None) => Ok(Self::Everyone),
+            (Some(role), None) => Ok(Self::Role(role)),
+            (None, Some(user_id)) => Ok(Self::User(user_id)),
+            (Some(_), Some(_)) => Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "an override targets either a role or a user",
+                None,
+            )),
+        }
+    }
+
+    fn role(&self) -> Option<&str> {
+        match self {
+            Self::Role(role) => Some(role),
+            _ => None,
+        }
+    }
+
+    fn user_id(&self) -> Option<i32> {
+        match self {
+            Self::User(user_id) => Some(*user_id),
+            _ => None,
+        }
+    }
+
+    /// Higher is more specific.
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Everyone => 0,
+            Self::Role(_) => 1,
+            Self::User(_) => 2,
+        }
+    }
+
+    fn matches(&self, user_id: i32, role: &str) -> bool {
+        match self {
+            Self::Everyone => true,
+            Self::Role(name) => name == role,
+            Self::User(id) => *id == user_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Override {
+    flag: Flag,
+    target: Target,
+    enabled: bool,
+}
+
+/// The state of `flag` for one caller: the most specific matching override,
+/// or `default` without one.
+fn evaluate(default: bool, overrides: &[Override], flag: Flag, user_id: i32, role: &str) -> bool {
+    overrides
+        .iter()
+        .filter(|o| o.flag == flag && o.target.matches(user_id, role))
+        .max_by_key(|o| o.target.rank())
+        .map_or(default, |o| o.enabled)
+}
+
+#[derive(Clone)]
+pub(crate) struct Flags {
+    pool: PgPool,
+    config: Arc<FlagConfig>,
+    /// All overrides under the unit key; the table holds a handful of rows.
+    cache: Cache<(), Arc<Vec<Override>>>,
+}
+
+impl Flags {
+    pub(crate) fn new(pool: PgPool, config: FlagConfig) -> Self {
+        Self {
+            pool,
+            cache: Cache::builder()
+                .max_capacity(1)
+                .time_to_live(config.ttl)
+                .build(),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Fails with -32071 unless `flag` is on for the caller.
+    pub(crate) async fn require(
+        &self,
+        flag: Flag,
+        ctx: &RequestContext,
+    ) -> Result<(), RpcMethodError> {
+        let overrides = self.overrides().await?;
+        let default = self.config.default_for(flag);
+        if evaluate(default, &overrides, flag, ctx.user_id, ctx.role.as_str()) {
+            return Ok(());
+        }
+        Err(RpcMethodError::new(
+            ErrorCode::FeatureDisabled,
+            "feature disabled",
+            Some(json!({ "flag": flag.as_str() })),
+        ))
+    }
+
+    /// Checks the flag guarding `method`, if any.
+    pub(crate) async fn require_method(
+        &self,
+        method: &str,
+        ctx: &RequestContext,
+    ) -> Result<(), RpcMethodError> {
+        match Flag::for_method(method) {
+            Some(flag) => self.require(flag, ctx).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn overrides(&self) -> Result<Arc<Vec<Override>>, RpcMethodError> {
+        if let Some(overrides) = self.cache.get(&()).await {
+            return Ok(overrides);
+        }
+        let rows = sqlx::query("SELECT flag, role, user_id, enabled FROM feature_flag_overrides")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| {
+                RpcMethodError::internal(&format!("failed to load feature flags: {err}"))
+            })?;
+        // Rows of flags this build no longer knows are ignored.
+        let overrides: Vec<Override> = rows
+            .iter()
+            .filter_map(|row| {
+                Some(Override {
+                    flag: Flag::parse(row.get("flag"))?,
+                    target: Target::new(row.get("role"), row.get("user_id")).ok()?,
+                    enabled: row.get("enabled"),
+                })
+            })
+            .collect();
+        let overrides = Arc::new(overrides);
+        self.cache.insert((), overrides.clone()).await;
+        Ok(overrides)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct FlagSetParams {
+    flag: String,
+    enabled: bool,
+    /// Applies the override to users holding this role only.
+    #[serde(default)]
+    role: Option<String>,
+    /// Applies the override to this user only.
+    #[serde(default)]
+    user_id: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct FlagClearParams {
+    flag: String,
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    user_id: Option<i32>,
+}
+
+fn parse_flag(name: &str) -> Result<Flag, RpcMethodError> {
+    Flag::parse(name).ok_or_else(|| {
+        let supported: Vec<&str> = Flag::ALL.iter().map(|flag| flag.as_str()).collect();
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "unknown feature flag",
+            Some(json!({ "flag": name, "supported": supported })),
+        )
+    })
+}
+
+fn db_error(err: SqlxError) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update feature flags: {err}"))
+}
+
+/// Every flag with its configured default and its overrides.
+pub(crate) async fn list(flags: &Flags) -> Result<Value, RpcMethodError> {
+    let rows = sqlx::query(
+        "SELECT flag, role, user_id, enabled, updated_by, updated_at \
+         FROM feature_flag_overrides ORDER BY flag, user_id NULLS FIRST, role NULLS FIRST",
+    )
+    .fetch_all(&flags.pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list feature flags: {err}")))?;
+    let listed: Vec<Value> = Flag::ALL
+        .iter()
+        .map(|flag| {
+            let overrides: Vec<Value> = rows
+                .iter()
+                .filter(|row| row.get::<String, _>("flag") == flag.as_str())
+                .map(|row| {
+                    json!({
+                        "role": row.get::<Option<String>, _>("role"),
+                        "user_id": row.get::<Option<i32>, _>("user_id"),
+                        "enabled": row.get::<bool, _>("enabled"),
+                        "updated_by": row.get::<Option<i32>, _>("updated_by"),
+                        "updated_at": row.get::<DateTime<Utc>, _>("updated_at").to_rfc3339(),
+                    })
+                })
+                .collect();
+            json!({
+                "flag": flag.as_str(),
+                "description": flag.description(),
+                "default": flags.config.default_for(*flag),
+                "overrides": overrides,
+            })
+        })
+        .collect();
+    Ok(json!({ "flags": listed }))
+}
+
+/// Creates or replaces the override of `flag` for one target.
+pub(crate) async fn set(
+    flags: &Flags,
+    ctx: &RequestContext,
+    params: FlagSetParams,
+) -> Result<Value, RpcMethodError> {
+    let flag = parse_flag(&params.flag)?;
+    let target = Target::new(params.role, params.user_id)?;
+    sqlx::query(
+        "INSERT INTO feature_flag_overrides (flag, role, user_id, enabled, updated_by) \
+         VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (flag, COALESCE(role, ''), COALESCE(user_id, 0)) \
+         DO UPDATE SET enabled = EXCLUDED.enabled, updated_by = EXCLUDED.updated_by, \
+            updated_at = NOW()",
+    )
+    .bind(flag.as_str())
+    .bind(target.role())
+    .bind(target.user_id())
+    .bind(params.enabled)
+    .bind(ctx.user_id)
+    .execute(&flags.pool)
+    .await
+    .map_err(|err| match &err {
+        SqlxError::Database(db_err) if db_err.code().as_deref() == Some("23503") => {
+            RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "unknown role or user",
+                Some(json!({ "role": target.role(), "user_id": target.user_id() })),
+            )
+        }
+        _ => db_error(err),
+    })?;
+    flags.cache.invalidate_all();
+    Ok(json!({
+        "flag": flag.as_str(),
+        "role": target.role(),
+        "user_id": target.user_id(),
+        "enabled": params.enabled,
+    }))
+}
+
+/// Removes the override of `flag` for one target; the next less specific
+/// override or the default applies again.
+pub(crate) async fn clear(flags: &Flags, params: FlagClearParams) -> Result<Value, RpcMethodError> {
+    let flag = parse_flag(&params.flag)?;
+    let target = Target::new(params.role, params.user_id)?;
+    let deleted = sqlx::query(
+        "DELETE FROM feature_flag_overrides \
+         WHERE flag = $1 AND role IS NOT DISTINCT FROM $2 AND user_id IS NOT DISTINCT FROM $3",
+    )
+    .bind(flag.as_str())
+    .bind(target.role())
+    .bind(target.user_id())
+    .execute(&flags.pool)
+    .await
+    .map_err(db_error)?;
+    flags.cache.invalidate_all();
+    Ok(json!({
+        "flag": flag.as_str(),
+        "role": target.role(),
+        "user_id": target.user_id(),
+        "cleared": deleted.rows_affected() > 0,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn over(flag: Flag, target: Target, enabled: bool) -> Override {
+        Override {
+            flag,
+            target,
+            enabled,
+        }
+    }
+
+    #[test]
+    fn the_most_specific_override_wins() {
+        let overrides = vec![
+            over(Flag::Agents, Target::Everyone, false),
+            over(Flag::Agents, Target::Role("beta".into()), true),
+            over(Flag::Agents, Target::User(7), false),
+            over(Flag::Rest, Target::User(1), false),
+        ];
+        let agents = |user_id, role| evaluate(true, &overrides, Flag::Agents, user_id, role);
+        assert!(!agents(1, "developer"));
+        assert!(agents(1, "beta"));
+        assert!(!agents(7, "beta"));
+        assert!(!evaluate(true, &overrides, Flag::Rest, 1, "developer"));
+        assert!(evaluate(true, &overrides, Flag::Rest, 2, "developer"));
+        assert!(!evaluate(false, &overrides, Flag::Streaming, 1, "beta"));
+    }
+
+    #[test]
+    fn methods_map_to_their_flag() {
+        assert_eq!(Flag::for_method("agent.dispatch"), Some(Flag::Agents));
+        assert_eq!(Flag::for_method("agent.pipeline"), Some(Flag::Agents));
+        assert_eq!(Flag::for_method("agent.status"), None);
+        assert_eq!(Flag::for_method("fs.read"), None);
+        for flag in Flag::ALL {
+            assert_eq!(Flag::parse(flag.as_str()), Some(flag));
+        }
+        assert!(Target::new(Some("beta".into()), Some(1)).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! `fs.batch`: several sandbox file operations in one call. Operations run
+//! in order against one scope (user, project or workspace directory), each
+//! with its own result, so scaffolding a project or applying a multi-file
+//! change is one round trip. A failed operation does not undo earlier ones;
+//! with `stop_on_error` the remaining ones are skipped.
+
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hex::encode as hex_encode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::errors::ErrorCode;
+use crate::{
+    git, scoped_fs, AppState, Permission, RequestContext, RpcMethodError, MAX_BASE64_PAYLOAD_BYTES,
+};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct FsBatchParams {
+    /// Executed in order; at most `FS_BATCH_MAX_OPS` (default 64).
+    ops: Vec<FsBatchOp>,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+    /// Skip the remaining operations after the first failure.
+    #[serde(default)]
+    stop_on_error: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum FsBatchOp {
+    Read { path: String },
+    Write { path: String, data: String },
+    Delete { path: String },
+    Mkdir { path: String },
+}
+
+impl FsBatchOp {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "read",
+            Self::Write { .. } => "write",
+            Self::Delete { .. } => "delete",
+            Self::Mkdir { .. } => "mkdir",
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self {
+            Self::Read { path }
+            | Self::Write { path, .. }
+            | Self::Delete { path }
+            | Self::Mkdir { path } => path,
+        }
+    }
+}
+
+pub(crate) async fn execute(
+    state: &AppState,
+    ctx: &RequestContext,
+    limit: usize,
+    params: FsBatchParams,
+) -> Result<Value, RpcMethodError> {
+    if params.ops.is_empty() || params.ops.len() > limit {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid params",
+            Some(json!({ "detail": format!("a batch has between 1 and {limit} operations") })),
+        ));
+    }
+    let writes = params
+        .ops
+        .iter()
+        .any(|op| !matches!(op, FsBatchOp::Read { .. }));
+    let permission = if writes {
+        Permission::FsWrite
+    } else {
+        Permission::FsRead
+    };
+    ctx.require_for(permission, params.project_id.as_deref())?;
+    for op in &params.ops {
+        if !matches!(op, FsBatchOp::Read { .. }) {
+            git::ensure_outside_git_dir(op.path())?;
+        }
+    }
+    let sandbox = scoped_fs(
+        state,
+        ctx,
+        params.project_id.as_deref(),
+        params.workspace_id.as_deref(),
+        permission,
+    )
+    .await?;
+
+    let mut results = Vec::with_capacity(params.ops.len());
+    let (mut succeeded, mut failed) = (0, 0);
+    for op in &params.ops {
+        let mut result = json!({ "op": op.name(), "path": op.path() });
+        if params.stop_on_error && failed > 0 {
+            result["status"] = json!("skipped");
+            results.push(result);
+            continue;
+        }
+        match apply(&sandbox, op) {
+            Ok(fields) => {
+                succeeded += 1;
+                result["status"] = json!("ok");
+                if let Value::Object(fields) = fields {
+                    result.as_object_mut().expect("object").extend(fields);
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                result["status"] = json!("error");
+                result["error"] = json!({
+                    "code": err.code,
+                    "message": err.message,
+                    "data": err.data,
+                });
+            }
+        }
+        results.push(result);
+    }
+    Ok(json!({ "results": results, "succeeded": succeeded, "failed": failed }))
+}
+
+/// Runs one operation; errors carry the code the single-op method uses.
+fn apply(sandbox: &sandbox::SandboxFs, op: &FsBatchOp) -> Result<Value, RpcMethodError> {
+    match op {
+        FsBatchOp::Read { path } => {
+            let bytes = sandbox.read(Path::new(path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsRead, "failed to read file", err)
+            })?;
+            let sha256 = hex_encode(Sha256::digest(&bytes));
+            Ok(json!({ "data": BASE64.encode(bytes), "sha256": sha256 }))
+        }
+        FsBatchOp::Write { path, data } => {
+            if data.len() / 4 * 3 > MAX_BASE64_PAYLOAD_BYTES {
+                return Err(RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "payload too large",
+                    Some(json!({ "field": "data", "limit": MAX_BASE64_PAYLOAD_BYTES })),
+                ));
+            }
+            let data = BASE64.decode(data.as_bytes()).map_err(|err| {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid base64 payload",
+                    Some(json!({ "detail": err.to_string() })),
+                )
+            })?;
+            sandbox.write(Path::new(path), data).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsWrite, "failed to write file", err)
+            })?;
+            Ok(Value::Null)
+        }
+        FsBatchOp::Delete { path } => {
+            sandbox.delete(Path::new(path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsDelete, "failed to delete path", err)
+            })?;
+            Ok(Value::Null)
+        }
+        FsBatchOp::Mkdir { path } => {
+            sandbox.mkdir(Path::new(path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsMkdir, "failed to create directory", err)
+            })?;
+            Ok(Value::Null)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sandbox::{SandboxConfig, SandboxFs};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn operations_run_in_order_with_their_own_results() {
+        let root = std::env::temp_dir().join(format!("fs-batch-{}", Uuid::new_v4()));
+        let sandbox = SandboxFs::new(SandboxConfig::new(&root, 1024).unwrap());
+        let params: FsBatchParams = serde_json::from_value(json!({
+            "ops": [
+                { "op": "mkdir", "path": "src" },
+                { "op": "write", "path": "src/main.rs", "data": BASE64.encode("fn main() {}") },
+                { "op": "read", "path": "src/main.rs" },
+                { "op": "read", "path": "missing.rs" },
+            ],
+        }))
+        .unwrap();
+        let outcomes: Vec<_> = params.ops.iter().map(|op| apply(&sandbox, op)).collect();
+        assert!(outcomes[0].is_ok() && outcomes[1].is_ok());
+        assert_eq!(
+            outcomes[2].as_ref().unwrap()["data"],
+            BASE64.encode("fn main() {}")
+        );
+        assert_eq!(outcomes[3].as_ref().unwrap_err().code, -32001);
+        assert!(serde_json::from_value::<FsBatchParams>(json!({
+            "ops": [{ "op": "chmod", "path": "a" }],
+        }))
+        .is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
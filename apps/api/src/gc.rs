@@ -0,0 +1,604 @@
+//! Garbage collection of sandbox data that lost its Postgres row. The
+//! scheduler's `sandbox_gc` job walks every tenant directory and looks for
+//!
+//! - `users/<id>` directories of users that no longer exist,
+//! - `workspaces/<id>` directories without a workspace session,
+//! - micro VM workdirs (UUID-named directories in a user, project or tenant
+//!   scope holding a `script_*` file at the top, or nothing at all) that
+//!   no instance can still be running: none of this instance's
+//!   VMs works there and nothing was executed for longer than the micro VM
+//!   idle timeout,
+//! - export bundles below `.exports` whose job is gone,
+//!
+//! and project files whose sandbox mirror is missing. Only entries untouched
+//! for `SANDBOX_GC_GRACE_SECS` count, so half-created data is never taken
+//! for an orphan. Live project directories without a row are left to
+//! `trash_purge` (see `reconcile`), and files in project directories without
+//! a `project_files` row are not orphans: runs and `fs.write` put them there.
+//!
+//! Every run reports what it found as `api_sandbox_orphans` and
+//! `api_sandbox_orphan_bytes`. Orphans are deleted only with
+//! `SANDBOX_GC_REMOVE=true`; missing mirror files are only reported.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sandbox::{SandboxFs, SandboxMicro};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::metrics::AppMetrics;
+use crate::{quota, tenant, transfer};
+
+/// Rows of `project_files` checked for their mirror at a time.
+const MIRROR_PAGE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GcConfig {
+    remove: bool,
+    grace: Duration,
+}
+
+impl GcConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            remove: config.get("SANDBOX_GC_REMOVE", false),
+            grace: config.secs("SANDBOX_GC_GRACE_SECS", 3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    User,
+    Workspace,
+    Micro,
+    Export,
+    /// A `project_files` row without its mirror file.
+    Mirror,
+}
+
+impl Kind {
+    const ALL: [Kind; 5] = [
+        Kind::User,
+        Kind::Workspace,
+        Kind::Micro,
+        Kind::Export,
+        Kind::Mirror,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::User => "user",
+            Kind::Workspace => "workspace",
+            Kind::Micro => "micro",
+            Kind::Export => "export",
+            Kind::Mirror => "mirror",
+        }
+    }
+}
+
+/// A directory or file below the sandbox root, relative to it.
+#[derive(Debug, PartialEq, Eq)]
+struct Found {
+    path: PathBuf,
+    bytes: u64,
+}
+
+/// A UUID-named directory that looks like a micro VM workdir.
+#[derive(Debug, PartialEq, Eq)]
+struct MicroDir {
+    /// Set when the directory sits in a project, whose files may use the
+    /// same name.
+    project_id: Option<Uuid>,
+    name: String,
+    found: Found,
+}
+
+/// Candidates read from the sandbox root, before Postgres is asked.
+#[derive(Debug, Default)]
+struct Scan {
+    users: Vec<(i32, i32, Found)>,
+    workspaces: Vec<(i32, Uuid, Found)>,
+    micro: Vec<MicroDir>,
+    exports: Vec<(i64, Found)>,
+}
+
+/// Directories and files to consider: those last modified before `cutoff`,
+/// and micro workdirs last used before `micro_cutoff` that no VM in
+/// `running` works in.
+struct Cutoffs<'a> {
+    cutoff: SystemTime,
+    micro_cutoff: SystemTime,
+    running: &'a HashSet<PathBuf>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Entries of `dir` as (name, absolute path, is directory).
+fn entries(dir: &Path) -> Vec<(String, PathBuf, bool)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some((
+                entry.file_name().to_str()?.to_string(),
+                entry.path(),
+                is_dir,
+            ))
+        })
+        .collect()
+}
+
+/// The directories of `dir` whose name parses and that are old enough.
+fn old_dirs<T>(
+    root: &Path,
+    dir: &Path,
+    cutoff: SystemTime,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Vec<(T, Found)> {
+    entries(&root.join(dir))
+        .into_iter()
+        .filter(|(_, path, is_dir)| *is_dir && modified(path).is_some_and(|at| at < cutoff))
+        .filter_map(|(name, path, _)| {
+            let key = parse(&name)?;
+            let found = Found {
+                path: dir.join(&name),
+                bytes: quota::dir_size(&path),
+            };
+            Some((key, found))
+        })
+        .collect()
+}
+
+/// UUID-named directories of `scope` that are empty or hold a script a
+/// micro VM wrote, were last used before the cutoff and are not running.
+/// Every execution writes a new script, so the newest entry tells when the
+/// VM was last used.
+fn micro_dirs(
+    root: &Path,
+    scope: &Path,
+    project_id: Option<Uuid>,
+    cutoffs: &Cutoffs<'_>,
+) -> Vec<MicroDir> {
+    let mut found = Vec::new();
+    for (name, path, is_dir) in entries(&root.join(scope)) {
+        if !is_dir || Uuid::parse_str(&name).is_err() || cutoffs.running.contains(&path) {
+            continue;
+        }
+        let children = entries(&path);
+        let looks_like_workdir = children
+            .iter()
+            .any(|(child, _, is_dir)| !is_dir && child.starts_with("script_"))
+            || children.is_empty();
+        let last_used = children
+            .iter()
+            .filter_map(|(_, child, _)| modified(child))
+            .chain(modified(&path))
+            .max();
+        if !looks_like_workdir || last_used.is_none_or(|at| at >= cutoffs.micro_cutoff) {
+            continue;
+        }
+        found.push(MicroDir {
+            project_id,
+            name: name.clone(),
+            found: Found {
+                path: scope.join(name),
+                bytes: quota::dir_size(&path),
+            },
+        });
+    }
+    found
+}
+
+fn scan(root: &Path, cutoffs: &Cutoffs<'_>) -> Scan {
+    let mut scan = Scan::default();
+    for tenant_id in tenant::present(root) {
+        let tenant_dir = tenant::directory_relative(tenant_id);
+        let users = tenant_dir.join("users");
+        let projects = tenant_dir.join("projects");
+
+        scan.micro
+            .extend(micro_dirs(root, &tenant_dir, None, cutoffs));
+        for (name, _, is_dir) in entries(&root.join(&users)) {
+            if is_dir && name.parse::<i32>().is_ok() {
+                scan.micro
+                    .extend(micro_dirs(root, &users.join(name), None, cutoffs));
+            }
+        }
+        for (name, _, is_dir) in entries(&root.join(&projects)) {
+            if let (true, Ok(project_id)) = (is_dir, Uuid::parse_str(&name)) {
+                scan.micro.extend(micro_dirs(
+                    root,
+                    &projects.join(name),
+                    Some(project_id),
+                    cutoffs,
+                ));
+            }
+        }
+
+        scan.users.extend(
+            old_dirs(root, &users, cutoffs.cutoff, |name| name.parse().ok())
+                .into_iter()
+                .map(|(user_id, found)| (tenant_id, user_id, found)),
+        );
+        scan.workspaces.extend(
+            old_dirs(
+                root,
+                &tenant_dir.join("workspaces"),
+                cutoffs.cutoff,
+                |name| Uuid::parse_str(name).ok(),
+            )
+            .into_iter()
+            .map(|(workspace_id, found)| (tenant_id, workspace_id, found)),
+        );
+    }
+
+    let exports = Path::new(transfer::EXPORTS_DIR);
+    for (name, path, is_dir) in entries(&root.join(exports)) {
+        let job_id = name
+            .strip_suffix(".json")
+            .and_then(|id| id.parse::<i64>().ok());
+        let (false, Some(job_id)) = (is_dir, job_id) else {
+            continue;
+        };
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+        if meta.modified().is_ok_and(|at| at < cutoffs.cutoff) {
+            scan.exports.push((
+                job_id,
+                Found {
+                    path: exports.join(name),
+                    bytes: meta.len(),
+                },
+            ));
+        }
+    }
+    scan
+}
+
+/// The scanned candidates Postgres knows nothing about.
+async fn orphans(pool: &PgPool, scan: Scan) -> Result<Vec<(Kind, Found)>, sqlx::Error> {
+    let mut orphans = Vec::new();
+
+    let mut by_tenant: BTreeMap<i32, Vec<(i32, Found)>> = BTreeMap::new();
+    for (tenant_id, user_id, found) in scan.users {
+        by_tenant
+            .entry(tenant_id)
+            .or_default()
+            .push((user_id, found));
+    }
+    for (tenant_id, users) in by_tenant {
+        let ids: Vec<i32> = users.iter().map(|(id, _)| *id).collect();
+        let existing: HashSet<i32> =
+            sqlx::query_scalar("SELECT id FROM users WHERE id = ANY($1) AND tenant_id = $2")
+                .bind(&ids)
+                .bind(tenant_id)
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .collect();
+        orphans.extend(
+            users
+                .into_iter()
+                .filter(|(id, _)| !existing.contains(id))
+                .map(|(_, found)| (Kind::User, found)),
+        );
+    }
+
+    let ids: Vec<Uuid> = scan.workspaces.iter().map(|(_, id, _)| *id).collect();
+    let existing: HashSet<(Uuid, i32)> = sqlx::query_as(
+        "SELECT w.id, u.tenant_id FROM workspace_sessions w JOIN users u ON u.id = w.user_id \
+         WHERE w.id = ANY($1)",
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    orphans.extend(
+        scan.workspaces
+            .into_iter()
+            .filter(|(tenant_id, id, _)| !existing.contains(&(*id, *tenant_id)))
+            .map(|(_, _, found)| (Kind::Workspace, found)),
+    );
+
+    // A project file below a micro-looking directory makes it project data.
+    let projects: Vec<Uuid> = scan.micro.iter().filter_map(|dir| dir.project_id).collect();
+    let names: Vec<&str> = scan.micro.iter().map(|dir| dir.name.as_str()).collect();
+    let tracked: HashSet<(Uuid, String)> = if projects.is_empty() {
+        HashSet::new()
+    } else {
+        sqlx::query_as(
+            "SELECT DISTINCT project_id, split_part(path, '/', 1) FROM project_files \
+             WHERE project_id = ANY($1) AND split_part(path, '/', 1) = ANY($2)",
+        )
+        .bind(&projects)
+        .bind(&names)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect()
+    };
+    orphans.extend(
+        scan.micro
+            .into_iter()
+            .filter(|dir| {
+                dir.project_id
+                    .is_none_or(|project_id| !tracked.contains(&(project_id, dir.name.clone())))
+            })
+            .map(|dir| (Kind::Micro, dir.found)),
+    );
+
+    let ids: Vec<i64> = scan.exports.iter().map(|(id, _)| *id).collect();
+    let existing: HashSet<i64> = sqlx::query_scalar("SELECT id FROM jobs WHERE id = ANY($1)")
+        .bind(&ids)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+    orphans.extend(
+        scan.exports
+            .into_iter()
+            .filter(|(id, _)| !existing.contains(id))
+            .map(|(_, found)| (Kind::Export, found)),
+    );
+
+    // Micro workdirs of an orphaned user directory go with it.
+    let dirs: Vec<PathBuf> = orphans
+        .iter()
+        .map(|(_, found)| found.path.clone())
+        .collect();
+    orphans.retain(|(_, found)| {
+        !dirs
+            .iter()
+            .any(|dir| found.path != *dir && found.path.starts_with(dir))
+    });
+    Ok(orphans)
+}
+
+/// Project files whose mirror below the sandbox root is missing, checked a
+/// page of `MIRROR_PAGE` rows at a time.
+async fn missing_mirrors(pool: &PgPool, root: &Path) -> Result<Vec<Found>, sqlx::Error> {
+    let mut missing = Vec::new();
+    let mut after = Uuid::nil();
+    loop {
+        let files: Vec<(Uuid, Uuid, i32, String, i64)> = sqlx::query_as(
+            "SELECT f.id, f.project_id, p.tenant_id, f.path, f.size \
+             FROM project_files f JOIN projects p ON p.id = f.project_id \
+             WHERE f.id > $1 ORDER BY f.id LIMIT $2",
+        )
+        .bind(after)
+        .bind(MIRROR_PAGE)
+        .fetch_all(pool)
+        .await?;
+        let Some((last, ..)) = files.last() else {
+            break;
+        };
+        after = *last;
+        let full = files.len() as i64 == MIRROR_PAGE;
+        let root = root.to_path_buf();
+        let check = move || {
+            files
+                .into_iter()
+                .map(|(_, project_id, tenant_id, path, size)| Found {
+                    path: tenant::directory_relative(tenant_id)
+                        .join("projects")
+                        .join(project_id.to_string())
+                        .join(path),
+                    bytes: size.max(0) as u64,
+                })
+                .filter(|found| !root.join(&found.path).is_file())
+                .collect::<Vec<_>>()
+        };
+        match tokio::task::spawn_blocking(check).await {
+            Ok(found) => missing.extend(found),
+            Err(err) => {
+                warn!(error = %err, "failed to check project file mirrors");
+                break;
+            }
+        }
+        if !full {
+            break;
+        }
+    }
+    Ok(missing)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Drift {
+    found: u64,
+    bytes: u64,
+    removed: u64,
+}
+
+/// Runs one collection and returns its report, e.g.
+/// `{"remove": false, "user": {"found": 2, "bytes": 4096, "removed": 0}, ...}`.
+pub(crate) async fn collect(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    micro: &SandboxMicro,
+    metrics: &AppMetrics,
+    config: GcConfig,
+    micro_idle: Duration,
+) -> Result<Value, sqlx::Error> {
+    let root = sandbox.base_dir().to_path_buf();
+    let running: HashSet<PathBuf> = micro.workdirs().into_iter().collect();
+    let now = SystemTime::now();
+    let cutoff = now
+        .checked_sub(config.grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let micro_cutoff = cutoff
+        .checked_sub(micro_idle)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let scanned = {
+        let root = root.clone();
+        tokio::task::spawn_blocking(move || {
+            let cutoffs = Cutoffs {
+                cutoff,
+                micro_cutoff,
+                running: &running,
+            };
+            scan(&root, &cutoffs)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!(error = %err, "sandbox scan failed");
+            Scan::default()
+        })
+    };
+
+    let mut found = orphans(pool, scanned).await?;
+    found.extend(
+        missing_mirrors(pool, &root)
+            .await?
+            .into_iter()
+            .map(|found| (Kind::Mirror, found)),
+    );
+
+    let mut drift: BTreeMap<Kind, Drift> = Kind::ALL
+        .into_iter()
+        .map(|kind| (kind, Drift::default()))
+        .collect();
+    let mut doomed = Vec::new();
+    for (kind, orphan) in found {
+        let entry = drift.entry(kind).or_default();
+        entry.found += 1;
+        entry.bytes += orphan.bytes;
+        if config.remove && kind != Kind::Mirror {
+            doomed.push((kind, orphan.path));
+        }
+    }
+    let sandbox = sandbox.clone();
+    let remove = move || {
+        doomed
+            .into_iter()
+            .filter(|(kind, path)| match sandbox.delete(path) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!(kind = kind.as_str(), path = %path.display(), error = %err, "failed to remove orphaned sandbox data");
+                    false
+                }
+            })
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>()
+    };
+    match tokio::task::spawn_blocking(remove).await {
+        Ok(removed) => {
+            for kind in removed {
+                drift.entry(kind).or_default().removed += 1;
+            }
+        }
+        Err(err) => warn!(error = %err, "failed to remove orphaned sandbox data"),
+    }
+
+    let mut report = json!({ "remove": config.remove });
+    for (kind, drift) in drift {
+        metrics.sandbox_drift(kind.as_str(), drift.found, drift.bytes, drift.removed);
+        report[kind.as_str()] = json!({
+            "found": drift.found,
+            "bytes": drift.bytes,
+            "removed": drift.removed,
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("gc-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn scan_finds_candidates_old_enough() {
+        let root = temp_root();
+        let tenant = root.join("tenants/1");
+        let workspace = Uuid::new_v4();
+        let project = Uuid::new_v4();
+        let vm = Uuid::new_v4();
+        let running_vm = Uuid::new_v4();
+        let data = Uuid::new_v4();
+        fs::create_dir_all(tenant.join("users/7/notes")).unwrap();
+        fs::write(tenant.join("users/7/notes/todo.txt"), "12345").unwrap();
+        fs::create_dir_all(tenant.join(format!("workspaces/{workspace}"))).unwrap();
+        fs::create_dir_all(tenant.join(format!("projects/{project}/{vm}"))).unwrap();
+        fs::write(
+            tenant.join(format!("projects/{project}/{vm}/script_1.sh")),
+            "echo",
+        )
+        .unwrap();
+        fs::create_dir_all(tenant.join(format!("users/7/{running_vm}"))).unwrap();
+        fs::create_dir_all(tenant.join(format!("users/7/{data}"))).unwrap();
+        fs::write(tenant.join(format!("users/7/{data}/report.csv")), "a,b").unwrap();
+        fs::create_dir_all(root.join(".exports")).unwrap();
+        fs::write(root.join(".exports/42.json"), "{}").unwrap();
+        fs::write(root.join(".exports/partial.tmp"), "").unwrap();
+
+        let running: HashSet<PathBuf> = [tenant.join(format!("users/7/{running_vm}"))]
+            .into_iter()
+            .collect();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let scanned = scan(
+            &root,
+            &Cutoffs {
+                cutoff: later,
+                micro_cutoff: later,
+                running: &running,
+            },
+        );
+        assert_eq!(scanned.users.len(), 1);
+        let (tenant_id, user_id, user) = &scanned.users[0];
+        assert_eq!((*tenant_id, *user_id), (1, 7));
+        assert_eq!(user.path, PathBuf::from("tenants/1/users/7"));
+        assert_eq!(user.bytes, 5 + 3);
+        assert_eq!(scanned.workspaces[0].1, workspace);
+        assert_eq!(
+            scanned.micro,
+            vec![MicroDir {
+                project_id: Some(project),
+                name: vm.to_string(),
+                found: Found {
+                    path: PathBuf::from(format!("tenants/1/projects/{project}/{vm}")),
+                    bytes: 4,
+                },
+            }]
+        );
+        assert_eq!(
+            scanned.exports,
+            vec![(
+                42,
+                Found {
+                    path: PathBuf::from(".exports/42.json"),
+                    bytes: 2,
+                }
+            )]
+        );
+
+        let fresh = scan(
+            &root,
+            &Cutoffs {
+                cutoff: SystemTime::UNIX_EPOCH,
+                micro_cutoff: SystemTime::UNIX_EPOCH,
+                running: &running,
+            },
+        );
+        assert!(fresh.users.is_empty() && fresh.micro.is_empty() && fresh.exports.is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Git repositories in project directories (`project.git.*`). The repository
+//! lives in `.git` of the project's sandbox directory, next to the mirror of
+//! its files, so commits record what the project's files hold in Postgres.
+//! git runs as a sandboxed process in that directory and honours the
+//! repository's own configuration, so every method needs `Execute` on the
+//! project. Only the git binary runs: hooks are off, repositories defining
+//! filter drivers are refused, and no file API writes inside `.git` (see
+//! [`ensure_outside_git_dir`]).
+
+use std::path::{Component, Path};
+use std::time::Duration;
+
+use sandbox::{GitAuthor, GitConfig, SandboxError, SandboxGit};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::Error as SqlxError;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{
+    load_project, map_db_activity_error, parse_project_id, project_directory_relative,
+    record_project_activity, scope_error, AppState, Permission, ProjectIdParams, RequestContext,
+    RpcMethodError,
+};
+
+const DEFAULT_LOG_LIMIT: usize = 20;
+const MAX_LOG_LIMIT: usize = 200;
+
+#[derive(Debug, Clone)]
+pub(crate) struct GitSettings {
+    binary: String,
+    path: String,
+    timeout: Duration,
+    max_output_bytes: usize,
+}
+
+impl GitSettings {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            binary: config.string("SANDBOX_GIT_BINARY", "/usr/bin/git"),
+            path: config.string("SANDBOX_RUN_PATH", "/usr/bin:/bin"),
+            timeout: config
+                .millis("SANDBOX_GIT_TIMEOUT_MS", 30_000)
+                .max(Duration::from_millis(100)),
+            max_output_bytes: config
+                .get("SANDBOX_GIT_MAX_OUTPUT_BYTES", 1024 * 1024)
+                .max(1),
+        }
+    }
+
+    /// A missing binary is not an error here; the git methods fail instead.
+    pub(crate) fn build(self, root: &Path) -> anyhow::Result<SandboxGit> {
+        let config = GitConfig::new(
+            root,
+            self.binary,
+            self.path,
+            self.timeout,
+            self.max_output_bytes,
+        )?;
+        Ok(SandboxGit::new(config)?)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GitDiffParams {
+    project_id: String,
+    /// Limits the diff to one file or directory.
+    #[serde(default)]
+    path: Option<String>,
+    /// Diff the staged changes instead of the unstaged ones.
+    #[serde(default)]
+    staged: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GitCommitParams {
+    project_id: String,
+    message: String,
+    /// Paths to stage; every change when empty.
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GitLogParams {
+    project_id: String,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+pub(crate) async fn init(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: ProjectIdParams,
+) -> Result<Value, RpcMethodError> {
+    let (project_id, git) = repository(state, ctx, &params.project_id).await?;
+    let created = git.init().await.map_err(git_error)?;
+    if created {
+        record_project_activity(state, project_id, ctx.user_id, "project.git.init", None)
+            .await
+            .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    }
+    Ok(json!({ "created": created, "branch": "main" }))
+}
+
+pub(crate) async fn status(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: ProjectIdParams,
+) -> Result<Value, RpcMethodError> {
+    let (_, git) = repository(state, ctx, &params.project_id).await?;
+    let status = git.status().await.map_err(git_error)?;
+    Ok(json!({
+        "branch": status.branch,
+        "clean": status.entries.is_empty(),
+        "entries": status.entries,
+    }))
+}
+
+pub(crate) async fn diff(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: GitDiffParams,
+) -> Result<Value, RpcMethodError> {
+    let (_, git) = repository(state, ctx, &params.project_id).await?;
+    let diff = git
+        .diff(params.path.as_deref(), params.staged)
+        .await
+        .map_err(git_error)?;
+    Ok(json!({ "diff": diff }))
+}
+
+pub(crate) async fn commit(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: GitCommitParams,
+) -> Result<Value, RpcMethodError> {
+    let (project_id, git) = repository(state, ctx, &params.project_id).await?;
+    let author = author(state, ctx).await?;
+    let commit = git
+        .commit(&params.message, &author, &params.paths)
+        .await
+        .map_err(git_error)?;
+    record_project_activity(
+        state,
+        project_id,
+        ctx.user_id,
+        "project.git.commit",
+        Some(json!({ "commit": commit.id, "summary": commit.summary })),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(json!({ "commit": commit }))
+}
+
+pub(crate) async fn log(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: GitLogParams,
+) -> Result<Value, RpcMethodError> {
+    let (_, git) = repository(state, ctx, &params.project_id).await?;
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LOG_LIMIT)
+        .clamp(1, MAX_LOG_LIMIT);
+    let commits = git.log(limit).await.map_err(git_error)?;
+    Ok(json!({ "commits": commits }))
+}
+
+/// Checks access to the project and scopes git to its directory.
+async fn repository(
+    state: &AppState,
+    ctx: &RequestContext,
+    project_id: &str,
+) -> Result<(Uuid, SandboxGit), RpcMethodError> {
+    ctx.require_for(Permission::Execute, Some(project_id))?;
+    let project_id = parse_project_id(project_id)?;
+    let project = load_project(state, ctx, &project_id, Permission::Execute).await?;
+    let git = state
+        .git
+        .scoped(project_directory_relative(project.tenant_id, &project_id))
+        .map_err(scope_error)?;
+    Ok((project_id, git))
+}
+
+/// Commits carry the caller's username and, when set, their email.
+async fn author(state: &AppState, ctx: &RequestContext) -> Result<GitAuthor, RpcMethodError> {
+    let email: Option<String> = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(ctx.user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|err: SqlxError| {
+            RpcMethodError::internal(&format!("failed to load commit author: {err}"))
+        })?
+        .flatten();
+    Ok(GitAuthor {
+        name: ctx.username.clone(),
+        email: email.unwrap_or_else(|| format!("{}@localhost", ctx.username)),
+    })
+}
+
+/// `.git` at any depth belongs to git; its config decides what git runs,
+/// so fs.* and project file paths may not write, create or delete in it.
+pub(crate) fn ensure_outside_git_dir(path: &str) -> Result<(), RpcMethodError> {
+    let inside = Path::new(path).components().any(|component| {
+        matches!(component, Component::Normal(part) if part.eq_ignore_ascii_case(".git"))
+    });
+    if inside {
+        return Err(RpcMethodError::forbidden(
+            "paths inside .git are managed by project.git.*",
+        ));
+    }
+    Ok(())
+}
+
+fn git_error(err: SandboxError) -> RpcMethodError {
+    RpcMethodError::from_sandbox(ErrorCode::Git, "git operation failed", err)
+}
@@ -0,0 +1,669 @@
+//! gRPC surface (`schemas/proto/gateway/v1/gateway.proto`) for programmatic
+//! clients such as CI runners. Like the REST facade, every call authenticates
+//! from request metadata and forwards to `process_audited_request`, so the
+//! JSON-RPC handlers stay the single implementation; this module only maps
+//! typed messages to JSON params and results back. Served on its own listener
+//! when `GRPC_BIND_ADDR` is set.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::stream::BoxStream;
+use serde_json::{json, Map, Value};
+use server::tls::TlsSettings;
+use tokio::sync::watch;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::errors::ErrorCode;
+use crate::{
+    authenticate_request, authentication_failed, process_audited_request, wait_for_shutdown,
+    AppState, RequestContext, RpcMethodError,
+};
+use crate::{telemetry, versioning};
+
+pub(crate) mod proto {
+    tonic::include_proto!("coder.gateway.v1");
+}
+
+use proto::gateway_server::{Gateway, GatewayServer};
+use proto::{
+    wasm_invoke_request, wasm_value, AgentDispatchRequest, AgentDispatchResponse, AgentTask,
+    AgentTaskRequest, ExecRequest, FileEntry, FsPathRequest, ListDirResponse, MicroExecuteRequest,
+    MicroStartRequest, MicroStartResponse, MicroStopRequest, ProcessResult, ReadFileResponse,
+    Scope, StatusResponse, WasmInvokeRequest, WasmInvokeResponse, WasmValue, WriteFileRequest,
+};
+
+/// How often `WatchAgentTask` polls the dispatcher for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serves the gateway until shutdown, over TLS when the HTTP listener uses it.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    state: AppState,
+    tls: Option<TlsSettings>,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        let (cert, key) = tls.read_pem().await?;
+        builder =
+            builder.tls_config(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)))?;
+    }
+    info!(%addr, "grpc server starting");
+    builder
+        .add_service(GatewayServer::new(GatewayService { state }))
+        .serve_with_shutdown(addr, wait_for_shutdown(shutdown))
+        .await?;
+    Ok(())
+}
+
+struct GatewayService {
+    state: AppState,
+}
+
+impl GatewayService {
+    async fn authenticate<T>(&self, request: Request<T>) -> Result<(RequestContext, T), Status> {
+        let peer = request.remote_addr();
+        let headers = request.metadata().clone().into_headers();
+        let ctx = authenticate_request(&self.state, &headers, peer)
+            .await
+            .map_err(|err| status(authentication_failed(telemetry::request_id(&headers), err)))?;
+        Ok((ctx, request.into_inner()))
+    }
+
+    async fn call<T>(
+        &self,
+        request: Request<T>,
+        method: &str,
+        params: impl FnOnce(T) -> Value,
+    ) -> Result<Value, Status> {
+        let (ctx, message) = self.authenticate(request).await?;
+        process_audited_request(
+            &self.state,
+            &ctx,
+            versioning::versioned(method),
+            Some(params(message)),
+        )
+        .await
+        .map_err(status)
+    }
+}
+
+/// Maps JSON-RPC error codes onto gRPC status codes. The original code and
+/// data travel along as `x-rpc-error-code` / `x-rpc-error-data` metadata.
+fn status(err: RpcMethodError) -> Status {
+    let code = match ErrorCode::from_code(err.code) {
+        Some(ErrorCode::Unauthorized) => Code::Unauthenticated,
+        Some(
+            ErrorCode::Forbidden
+            | ErrorCode::SandboxScope
+            | ErrorCode::EmailNotVerified
+            | ErrorCode::FeatureDisabled,
+        ) => Code::PermissionDenied,
+        Some(
+            ErrorCode::QuotaExceeded
+            | ErrorCode::WebhookLimit
+            | ErrorCode::InsufficientBalance
+            | ErrorCode::LlmQuotaExhausted
+            | ErrorCode::RateLimited
+            | ErrorCode::ResponseTooLarge,
+        ) => Code::ResourceExhausted,
+        Some(ErrorCode::InvalidRequest | ErrorCode::InvalidParams) => Code::InvalidArgument,
+        Some(ErrorCode::MethodNotFound) => Code::Unimplemented,
+        Some(
+            ErrorCode::AgentTaskNotFound
+            | ErrorCode::UserNotFound
+            | ErrorCode::WebhookNotFound
+            | ErrorCode::RoleNotFound
+            | ErrorCode::GrantNotFound
+            | ErrorCode::ScheduleNotFound
+            | ErrorCode::JobNotFound,
+        ) => Code::NotFound,
+        Some(ErrorCode::Overloaded | ErrorCode::LlmUpstream) => Code::Unavailable,
+        Some(ErrorCode::Timeout) => Code::DeadlineExceeded,
+        Some(ErrorCode::Internal) => Code::Internal,
+        _ => Code::Unknown,
+    };
+    let mut status = Status::new(code, err.message);
+    let metadata = status.metadata_mut();
+    metadata.insert("x-rpc-error-code", MetadataValue::from(err.code));
+    if let Some(data) = err.data.and_then(|data| data.to_string().parse().ok()) {
+        metadata.insert("x-rpc-error-data", data);
+    }
+    status
+}
+
+fn params(entries: impl IntoIterator<Item = (&'static str, Option<Value>)>) -> Value {
+    let map: Map<String, Value> = entries
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key.to_string(), value)))
+        .collect();
+    Value::Object(map)
+}
+
+fn scoped(mut value: Value, scope: Option<Scope>) -> Value {
+    if let (Value::Object(map), Some(scope)) = (&mut value, scope) {
+        if let Some(project_id) = scope.project_id {
+            map.insert("project_id".to_string(), Value::String(project_id));
+        }
+        if let Some(workspace_id) = scope.workspace_id {
+            map.insert("workspace_id".to_string(), Value::String(workspace_id));
+        }
+    }
+    value
+}
+
+fn text(value: &Value, key: &str) -> String {
+    optional_text(value, key).unwrap_or_default()
+}
+
+fn optional_text(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn file_entry(entry: &Value) -> FileEntry {
+    FileEntry {
+        name: text(entry, "name"),
+        is_dir: entry
+            .get("is_dir")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        size: entry
+            .get("size")
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+        kind: text(entry, "kind"),
+        mtime: optional_text(entry, "mtime"),
+        ctime: optional_text(entry, "ctime"),
+        permissions: text(entry, "permissions"),
+    }
+}
+
+fn decode(value: &Value, key: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    BASE64.decode(value.get(key).and_then(Value::as_str).unwrap_or_default())
+}
+
+fn invalid_payload(err: base64::DecodeError) -> Status {
+    Status::internal(format!("invalid base64 in rpc result: {err}"))
+}
+
+fn status_response(value: &Value) -> StatusResponse {
+    StatusResponse {
+        status: text(value, "status"),
+    }
+}
+
+fn process_result(value: &Value) -> Result<ProcessResult, base64::DecodeError> {
+    Ok(ProcessResult {
+        exit_code: value.get("exit_code").and_then(Value::as_i64).unwrap_or(-1) as i32,
+        stdout: decode(value, "stdout")?,
+        stderr: decode(value, "stderr")?,
+        duration_ms: value
+            .get("duration_ms")
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+    })
+}
+
+fn wasm_param(value: WasmValue) -> Option<Value> {
+    Some(match value.value? {
+        wasm_value::Value::I32(v) => json!({ "type": "i32", "value": v }),
+        wasm_value::Value::I64(v) => json!({ "type": "i64", "value": v }),
+        wasm_value::Value::F32(v) => json!({ "type": "f32", "value": v }),
+        wasm_value::Value::F64(v) => json!({ "type": "f64", "value": v }),
+    })
+}
+
+fn wasm_result(value: &Value) -> WasmValue {
+    let number = value.get("value");
+    let value = match value.get("type").and_then(Value::as_str) {
+        Some("i32") => number
+            .and_then(Value::as_i64)
+            .map(|v| wasm_value::Value::I32(v as i32)),
+        Some("i64") => number.and_then(Value::as_i64).map(wasm_value::Value::I64),
+        Some("f32") => number
+            .and_then(Value::as_f64)
+            .map(|v| wasm_value::Value::F32(v as f32)),
+        Some("f64") => number.and_then(Value::as_f64).map(wasm_value::Value::F64),
+        _ => None,
+    };
+    WasmValue { value }
+}
+
+fn dispatch_params(request: AgentDispatchRequest) -> Value {
+    let files: Vec<Value> = request
+        .files
+        .into_iter()
+        .map(|file| {
+            params([
+                ("path", file.path.map(Value::from)),
+                ("title", file.title.map(Value::from)),
+                ("encoding", file.encoding.map(Value::from)),
+                ("max_bytes", file.max_bytes.map(Value::from)),
+                (
+                    "content_base64",
+                    file.content.map(|bytes| Value::from(BASE64.encode(bytes))),
+                ),
+            ])
+        })
+        .collect();
+    let context = (!request.notes.is_empty() || !files.is_empty())
+        .then(|| json!({ "notes": request.notes, "files": files }));
+    let overrides = params([
+        ("temperature", request.temperature.map(Value::from)),
+        ("max_tokens", request.max_tokens.map(Value::from)),
+        ("top_p", request.top_p.map(Value::from)),
+    ]);
+    let parameters = overrides
+        .as_object()
+        .is_some_and(|map| !map.is_empty())
+        .then_some(overrides);
+    params([
+        ("agent", Some(Value::from(request.agent))),
+        ("objective", Some(Value::from(request.objective))),
+        ("context", context),
+        ("model", request.model.map(Value::from)),
+        ("parameters", parameters),
+        (
+            "fan_out",
+            request.fan_out_files.then(|| Value::from("files")),
+        ),
+    ])
+}
+
+fn agent_task(snapshot: &Value) -> AgentTask {
+    AgentTask {
+        task_id: text(snapshot, "id"),
+        agent: text(snapshot, "agent"),
+        status: text(snapshot, "status"),
+        objective: text(snapshot, "objective"),
+        model: text(snapshot, "model"),
+        summary: optional_text(snapshot, "summary"),
+        error: snapshot
+            .get("error")
+            .and_then(|error| optional_text(error, "message")),
+        created_at: text(snapshot, "created_at"),
+        started_at: optional_text(snapshot, "started_at"),
+        finished_at: optional_text(snapshot, "finished_at"),
+        parent_id: optional_text(snapshot, "parent_id"),
+        children: snapshot
+            .get("children")
+            .and_then(Value::as_array)
+            .map(|children| {
+                children
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        snapshot_json: snapshot.to_string(),
+    }
+}
+
+fn task_params(request: AgentTaskRequest) -> Value {
+    json!({ "task_id": request.task_id })
+}
+
+struct Watch {
+    agents: std::sync::Arc<sandbox::AgentDispatcher>,
+    task_id: Uuid,
+    last: Option<String>,
+    done: bool,
+}
+
+#[tonic::async_trait]
+impl Gateway for GatewayService {
+    type WatchAgentTaskStream = BoxStream<'static, Result<AgentTask, Status>>;
+
+    async fn read_file(
+        &self,
+        request: Request<FsPathRequest>,
+    ) -> Result<Response<ReadFileResponse>, Status> {
+        let value = self
+            .call(request, "fs.read", |req| {
+                scoped(json!({ "path": req.path }), req.scope)
+            })
+            .await?;
+        Ok(Response::new(ReadFileResponse {
+            data: decode(&value, "data").map_err(invalid_payload)?,
+        }))
+    }
+
+    async fn write_file(
+        &self,
+        request: Request<WriteFileRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let value = self
+            .call(request, "fs.write", |req| {
+                scoped(
+                    json!({ "path": req.path, "data": BASE64.encode(req.data) }),
+                    req.scope,
+                )
+            })
+            .await?;
+        Ok(Response::new(status_response(&value)))
+    }
+
+    async fn list_dir(
+        &self,
+        request: Request<FsPathRequest>,
+    ) -> Result<Response<ListDirResponse>, Status> {
+        let value = self
+            .call(request, "fs.list", |req| {
+                scoped(json!({ "path": req.path }), req.scope)
+            })
+            .await?;
+        let entries = value
+            .as_array()
+            .map(|entries| entries.iter().map(file_entry).collect())
+            .unwrap_or_default();
+        Ok(Response::new(ListDirResponse { entries }))
+    }
+
+    async fn stat_path(
+        &self,
+        request: Request<FsPathRequest>,
+    ) -> Result<Response<FileEntry>, Status> {
+        let value = self
+            .call(request, "fs.stat", |req| {
+                scoped(json!({ "path": req.path }), req.scope)
+            })
+            .await?;
+        Ok(Response::new(file_entry(&value)))
+    }
+
+    async fn delete_path(
+        &self,
+        request: Request<FsPathRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let value = self
+            .call(request, "fs.delete", |req| {
+                scoped(json!({ "path": req.path }), req.scope)
+            })
+            .await?;
+        Ok(Response::new(status_response(&value)))
+    }
+
+    async fn make_dir(
+        &self,
+        request: Request<FsPathRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let value = self
+            .call(request, "fs.mkdir", |req| {
+                scoped(json!({ "path": req.path }), req.scope)
+            })
+            .await?;
+        Ok(Response::new(status_response(&value)))
+    }
+
+    async fn exec(&self, request: Request<ExecRequest>) -> Result<Response<ProcessResult>, Status> {
+        let value = self
+            .call(request, "run.exec", |req| {
+                let env: Vec<Value> = req
+                    .env
+                    .into_iter()
+                    .map(|var| json!({ "key": var.key, "value": var.value }))
+                    .collect();
+                scoped(
+                    params([
+                        ("program", Some(Value::from(req.program))),
+                        ("args", Some(Value::from(req.args))),
+                        ("env", Some(Value::from(env))),
+                        (
+                            "stdin",
+                            req.stdin.map(|stdin| Value::from(BASE64.encode(stdin))),
+                        ),
+                        ("cwd", req.cwd.map(Value::from)),
+                        ("timeout_ms", req.timeout_ms.map(Value::from)),
+                    ]),
+                    req.scope,
+                )
+            })
+            .await?;
+        Ok(Response::new(
+            process_result(&value).map_err(invalid_payload)?,
+        ))
+    }
+
+    async fn invoke_wasm(
+        &self,
+        request: Request<WasmInvokeRequest>,
+    ) -> Result<Response<WasmInvokeResponse>, Status> {
+        let value = self
+            .call(request, "wasm.invoke", |req| {
+                let (module_path, module_bytes) = match req.module {
+                    Some(wasm_invoke_request::Module::ModulePath(path)) => {
+                        (Some(Value::from(path)), None)
+                    }
+                    Some(wasm_invoke_request::Module::ModuleBytes(bytes)) => {
+                        (None, Some(Value::from(BASE64.encode(bytes))))
+                    }
+                    None => (None, None),
+                };
+                let wasm_params: Vec<Value> =
+                    req.params.into_iter().filter_map(wasm_param).collect();
+                params([
+                    ("module_path", module_path),
+                    ("module_bytes", module_bytes),
+                    ("function", Some(Value::from(req.function))),
+                    ("params", Some(Value::from(wasm_params))),
+                    ("fuel", req.fuel.map(Value::from)),
+                    ("memory_limit", req.memory_limit.map(Value::from)),
+                    (
+                        "table_elements_limit",
+                        req.table_elements_limit.map(Value::from),
+                    ),
+                ])
+            })
+            .await?;
+        let values = value
+            .get("values")
+            .and_then(Value::as_array)
+            .map(|values| values.iter().map(wasm_result).collect())
+            .unwrap_or_default();
+        Ok(Response::new(WasmInvokeResponse { values }))
+    }
+
+    async fn start_micro(
+        &self,
+        request: Request<MicroStartRequest>,
+    ) -> Result<Response<MicroStartResponse>, Status> {
+        let value = self
+            .call(request, "micro.start", |req| {
+                scoped(
+                    params([
+                        ("image", Some(Value::from(req.image))),
+                        (
+                            "init_script",
+                            req.init_script
+                                .map(|script| Value::from(BASE64.encode(script))),
+                        ),
+                    ]),
+                    req.scope,
+                )
+            })
+            .await?;
+        Ok(Response::new(MicroStartResponse {
+            vm_id: text(&value, "vm_id"),
+            image: text(&value, "image"),
+            working_dir: text(&value, "working_dir"),
+        }))
+    }
+
+    async fn execute_micro(
+        &self,
+        request: Request<MicroExecuteRequest>,
+    ) -> Result<Response<ProcessResult>, Status> {
+        let value = self
+            .call(request, "micro.execute", |req| {
+                scoped(
+                    params([
+                        ("vm_id", Some(Value::from(req.vm_id))),
+                        ("code", Some(Value::from(BASE64.encode(req.code)))),
+                        ("timeout_ms", req.timeout_ms.map(Value::from)),
+                    ]),
+                    req.scope,
+                )
+            })
+            .await?;
+        Ok(Response::new(
+            process_result(&value).map_err(invalid_payload)?,
+        ))
+    }
+
+    async fn stop_micro(
+        &self,
+        request: Request<MicroStopRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let value = self
+            .call(request, "micro.stop", |req| {
+                scoped(json!({ "vm_id": req.vm_id }), req.scope)
+            })
+            .await?;
+        Ok(Response::new(status_response(&value)))
+    }
+
+    async fn dispatch_agent(
+        &self,
+        request: Request<AgentDispatchRequest>,
+    ) -> Result<Response<AgentDispatchResponse>, Status> {
+        let value = self
+            .call(request, "agent.dispatch", dispatch_params)
+            .await?;
+        Ok(Response::new(AgentDispatchResponse {
+            task_id: text(&value, "task_id"),
+            status: text(&value, "status"),
+        }))
+    }
+
+    async fn get_agent_task(
+        &self,
+        request: Request<AgentTaskRequest>,
+    ) -> Result<Response<AgentTask>, Status> {
+        let value = self.call(request, "agent.status", task_params).await?;
+        Ok(Response::new(agent_task(&value)))
+    }
+
+    async fn cancel_agent_task(
+        &self,
+        request: Request<AgentTaskRequest>,
+    ) -> Result<Response<AgentTask>, Status> {
+        let value = self.call(request, "agent.cancel", task_params).await?;
+        Ok(Response::new(agent_task(&value)))
+    }
+
+    /// The initial `agent.status` call checks permissions, audits the watch
+    /// and validates the task id; later polls read the dispatcher directly.
+    async fn watch_agent_task(
+        &self,
+        request: Request<AgentTaskRequest>,
+    ) -> Result<Response<Self::WatchAgentTaskStream>, Status> {
+        let value = self.call(request, "agent.status", task_params).await?;
+        let task_id = Uuid::parse_str(&text(&value, "id"))
+            .map_err(|err| Status::internal(format!("invalid task id: {err}")))?;
+        let watch = Watch {
+            agents: self.state.agents.clone(),
+            task_id,
+            last: None,
+            done: false,
+        };
+        let stream = futures::stream::unfold(watch, |mut watch| async move {
+            if watch.done {
+                return None;
+            }
+            loop {
+                if watch.last.is_some() {
+                    tokio::time::sleep(WATCH_INTERVAL).await;
+                }
+                let Some(snapshot) = watch.agents.status(&watch.task_id) else {
+                    watch.done = true;
+                    return Some((Err(Status::not_found("agent task not found")), watch));
+                };
+                let value = match serde_json::to_value(&snapshot) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        watch.done = true;
+                        return Some((Err(Status::internal(err.to_string())), watch));
+                    }
+                };
+                let serialized = value.to_string();
+                if watch.last.as_deref() == Some(serialized.as_str()) {
+                    continue;
+                }
+                watch.done = snapshot.status.is_terminal();
+                watch.last = Some(serialized);
+                return Some((Ok(agent_task(&value)), watch));
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_messages_to_rpc_params_and_errors() {
+        let value = scoped(
+            json!({ "path": "src" }),
+            Some(Scope {
+                project_id: Some("p".to_string()),
+                workspace_id: None,
+            }),
+        );
+        assert_eq!(value, json!({ "path": "src", "project_id": "p" }));
+
+        let entry = file_entry(&json!({
+            "name": "link",
+            "is_dir": false,
+            "size": 3,
+            "kind": "symlink",
+            "mtime": "2024-01-01T00:00:00Z",
+            "ctime": null,
+            "permissions": "0777",
+        }));
+        assert_eq!(
+            (entry.kind.as_str(), entry.permissions.as_str()),
+            ("symlink", "0777")
+        );
+        assert_eq!(entry.mtime.as_deref(), Some("2024-01-01T00:00:00Z"));
+        assert_eq!(entry.ctime, None);
+
+        let dispatch = dispatch_params(AgentDispatchRequest {
+            agent: "code".to_string(),
+            objective: "fix".to_string(),
+            max_tokens: Some(64),
+            fan_out_files: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            dispatch,
+            json!({
+                "agent": "code",
+                "objective": "fix",
+                "parameters": { "max_tokens": 64 },
+                "fan_out": "files",
+            })
+        );
+
+        let err = status(RpcMethodError::new(
+            ErrorCode::Forbidden,
+            "insufficient permissions",
+            Some(json!({ "detail": "x" })),
+        ));
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert_eq!(err.metadata().get("x-rpc-error-code").unwrap(), "-32091");
+        assert_eq!(
+            err.metadata().get("x-rpc-error-data").unwrap(),
+            r#"{"detail":"x"}"#
+        );
+    }
+}
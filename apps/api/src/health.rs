@@ -0,0 +1,216 @@
+//! Liveness and readiness probes. `/healthz` only reports that the process is
+//! serving requests; `/readyz` verifies the dependencies a request needs and
+//! caches the outcome so frequent probes do not hammer Postgres or the LLM
+//! server.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::AppState;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(liveness))
+        .route("/healthz", get(liveness))
+        .route("/readyz", get(readiness))
+}
+
+/// Caches the last readiness report for `ttl`. The lock is held while the
+/// checks run so concurrent probes share a single round of checks.
+pub(crate) struct Readiness {
+    sandbox_root: PathBuf,
+    ttl: Duration,
+    check_timeout: Duration,
+    cached: Mutex<Option<(Instant, ReadinessReport)>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReadinessConfig {
+    ttl: Duration,
+    check_timeout: Duration,
+}
+
+impl ReadinessConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            ttl: config.millis("READINESS_CACHE_MS", 5_000),
+            check_timeout: config.millis("READINESS_CHECK_TIMEOUT_MS", 2_000),
+        }
+    }
+}
+
+impl Readiness {
+    pub(crate) fn new(sandbox_root: PathBuf, config: ReadinessConfig) -> Self {
+        Self {
+            sandbox_root,
+            ttl: config.ttl,
+            check_timeout: config.check_timeout,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn report(&self, state: &AppState) -> (ReadinessReport, bool) {
+        let mut cached = self.cached.lock().await;
+        if let Some((at, report)) = cached.as_ref() {
+            if at.elapsed() < self.ttl {
+                return (report.clone(), true);
+            }
+        }
+        let (postgres, sandbox, llm) = tokio::join!(
+            self.check(check_postgres(state)),
+            self.check(check_sandbox(&self.sandbox_root)),
+            self.check(state.llm.ping()),
+        );
+        let report = ReadinessReport::new(vec![
+            ("postgres", postgres),
+            ("sandbox", sandbox),
+            ("llm", llm),
+        ]);
+        if !report.ready {
+            warn!(checks = ?report.checks, "readiness check failed");
+        }
+        *cached = Some((Instant::now(), report.clone()));
+        (report, false)
+    }
+
+    async fn check(
+        &self,
+        probe: impl std::future::Future<Output = std::result::Result<(), String>>,
+    ) -> DependencyStatus {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(self.check_timeout, probe).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "timed out after {}ms",
+                self.check_timeout.as_millis()
+            )),
+        };
+        DependencyStatus {
+            ok: result.is_ok(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            error: result.err(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DependencyStatus {
+    ok: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    checked_at: DateTime<Utc>,
+    checks: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ReadinessReport {
+    fn new(checks: Vec<(&str, DependencyStatus)>) -> Self {
+        let ready = checks.iter().all(|(_, status)| status.ok);
+        let checks = checks
+            .into_iter()
+            .map(|(name, status)| {
+                (
+                    name.to_string(),
+                    serde_json::to_value(status).unwrap_or_default(),
+                )
+            })
+            .collect();
+        Self {
+            ready,
+            checked_at: Utc::now(),
+            checks,
+        }
+    }
+}
+
+async fn liveness() -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({ "status": "ok" })))
+}
+
+async fn readiness(State(state): State<AppState>) -> Response {
+    let (report, cached) = state.readiness.report(&state).await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "status": if report.ready { "ready" } else { "not_ready" },
+        "cached": cached,
+        "checked_at": report.checked_at,
+        "checks": report.checks,
+    });
+    (status, Json(body)).into_response()
+}
+
+async fn check_postgres(state: &AppState) -> std::result::Result<(), String> {
+    sqlx::query("SELECT 1")
+        .execute(&state.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Writes and removes a probe file to prove the sandbox root is writable.
+pub(crate) async fn check_sandbox(root: &std::path::Path) -> std::result::Result<(), String> {
+    let probe = root.join(format!(".readyz-{}", Uuid::new_v4()));
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|err| format!("sandbox root not writable: {err}"))?;
+    tokio::fs::remove_file(&probe)
+        .await
+        .map_err(|err| format!("failed to remove probe file: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(ok: bool) -> DependencyStatus {
+        DependencyStatus {
+            ok,
+            latency_ms: 1,
+            error: (!ok).then(|| "down".to_string()),
+        }
+    }
+
+    #[test]
+    fn report_is_ready_only_when_every_dependency_is() {
+        let report = ReadinessReport::new(vec![("postgres", status(true)), ("llm", status(true))]);
+        assert!(report.ready);
+        assert_eq!(report.checks["postgres"]["ok"], true);
+
+        let report = ReadinessReport::new(vec![("postgres", status(true)), ("llm", status(false))]);
+        assert!(!report.ready);
+        assert_eq!(report.checks["llm"]["error"], "down");
+        assert!(report.checks["postgres"].get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn sandbox_check_cleans_up_probe_file() {
+        let dir = std::env::temp_dir().join(format!("readyz-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        check_sandbox(&dir).await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(check_sandbox(&dir.join("missing")).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
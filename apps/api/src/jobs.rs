@@ -0,0 +1,674 @@
+//! Durable background work. `enqueue` stores a job in `jobs`; workers on
+//! every api instance claim due jobs with `FOR UPDATE SKIP LOCKED` and hold a
+//! lease they renew while the job runs, so a crashed instance's jobs are
+//! picked up again once the lease expires. Failed attempts are retried with
+//! exponential backoff; a job that runs out of attempts or fails permanently
+//! is parked as `dead` (the dead-letter state) until `job.retry` requeues it.
+//! `job.status`, `job.list` and `job.cancel` let owners follow their work.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::postgres::PgRow;
+use sqlx::types::Json;
+use sqlx::{PgPool, Row};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::events::DomainEvent;
+use crate::tenant::DEFAULT_TENANT;
+use crate::{pipeline, transfer, webhooks, AppState, RequestContext, RpcMethodError};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_CAP: Duration = Duration::from_secs(3600);
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+const COLUMNS: &str = "id, kind, user_id, status, attempts, max_attempts, run_at, progress, \
+    result, last_error, created_at, started_at, finished_at";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobKind {
+    ProjectExport,
+    ProjectImport,
+    WebhookDelivery,
+    AgentPipeline,
+}
+
+impl JobKind {
+    const ALL: [JobKind; 4] = [
+        JobKind::ProjectExport,
+        JobKind::ProjectImport,
+        JobKind::WebhookDelivery,
+        JobKind::AgentPipeline,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            JobKind::ProjectExport => "project.export",
+            JobKind::ProjectImport => "project.import",
+            JobKind::WebhookDelivery => "webhook.deliver",
+            JobKind::AgentPipeline => "agent.pipeline",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+
+    /// Whether the owner gets a notification when the job finishes. Webhook
+    /// deliveries are bookkept on the webhook itself.
+    fn notifies(self) -> bool {
+        !matches!(self, JobKind::WebhookDelivery)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct JobConfig {
+    workers: usize,
+    poll_interval: Duration,
+    lease: Duration,
+    max_attempts: i32,
+    pipeline_input_timeout: Duration,
+}
+
+impl JobConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            workers: config.get("JOB_WORKERS", 4),
+            poll_interval: config
+                .secs("JOB_POLL_INTERVAL_SECS", 5)
+                .max(Duration::from_secs(1)),
+            lease: config
+                .secs("JOB_LEASE_SECS", 60)
+                .max(Duration::from_secs(3)),
+            max_attempts: config.get("JOB_MAX_ATTEMPTS", 5).max(1),
+            pipeline_input_timeout: config
+                .secs("JOB_PIPELINE_INPUT_TIMEOUT_SECS", 900)
+                .max(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// Why an attempt failed.
+#[derive(Debug)]
+pub(crate) enum JobError {
+    /// Worth another attempt after the backoff.
+    Retry(String),
+    /// Retrying cannot help; the job goes straight to `dead`.
+    Fatal(String),
+}
+
+impl JobError {
+    pub(crate) fn retry(err: impl fmt::Display) -> Self {
+        JobError::Retry(err.to_string())
+    }
+
+    pub(crate) fn fatal(err: impl fmt::Display) -> Self {
+        JobError::Fatal(err.to_string())
+    }
+}
+
+impl From<RpcMethodError> for JobError {
+    /// Codes the catalog marks retryable (internal errors, rate limits,
+    /// timeouts) are retried; everything else will not change on retry.
+    fn from(err: RpcMethodError) -> Self {
+        let message = match err.data.as_ref().and_then(|data| data["detail"].as_str()) {
+            Some(detail) => format!("{}: {detail}", err.message),
+            None => err.message,
+        };
+        if ErrorCode::from_code(err.code).is_some_and(ErrorCode::retryable) {
+            JobError::Retry(message)
+        } else {
+            JobError::Fatal(message)
+        }
+    }
+}
+
+/// A job claimed by this worker.
+#[derive(Debug)]
+pub(crate) struct ClaimedJob {
+    pub(crate) id: i64,
+    kind: String,
+    pub(crate) user_id: Option<i32>,
+    pub(crate) payload: Value,
+    pub(crate) progress: Option<Value>,
+    pub(crate) attempts: i32,
+    pub(crate) max_attempts: i32,
+}
+
+impl ClaimedJob {
+    pub(crate) fn is_last_attempt(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Jobs {
+    pool: PgPool,
+    config: JobConfig,
+    instance: Arc<str>,
+    wake: Arc<Notify>,
+}
+
+impl Jobs {
+    pub(crate) fn new(pool: PgPool, config: JobConfig) -> Self {
+        Self {
+            pool,
+            config,
+            instance: Uuid::new_v4().to_string().into(),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queues a job with the default attempt budget and returns its id.
+    pub(crate) async fn enqueue(
+        &self,
+        kind: JobKind,
+        user_id: i32,
+        payload: Value,
+    ) -> Result<i64, RpcMethodError> {
+        let id = sqlx::query_scalar(
+            "INSERT INTO jobs (kind, user_id, payload, max_attempts) \
+             VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(kind.as_str())
+        .bind(user_id)
+        .bind(Json(payload))
+        .bind(self.config.max_attempts)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to queue job: {err}")))?;
+        self.wake();
+        Ok(id)
+    }
+
+    /// Nudges an idle worker after jobs were inserted directly.
+    pub(crate) fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// How long a pipeline step may wait for input before the job gives up.
+    pub(crate) fn pipeline_input_timeout(&self) -> Duration {
+        self.config.pipeline_input_timeout
+    }
+
+    /// Stores how far a multi-step job got, so a retry can resume there.
+    pub(crate) async fn checkpoint(
+        &self,
+        job: &ClaimedJob,
+        progress: &Value,
+    ) -> Result<(), JobError> {
+        sqlx::query("UPDATE jobs SET progress = $2, updated_at = NOW() WHERE id = $1")
+            .bind(job.id)
+            .bind(Json(progress))
+            .execute(&self.pool)
+            .await
+            .map_err(JobError::retry)?;
+        Ok(())
+    }
+
+    pub(crate) fn spawn_workers(&self, state: AppState) -> Vec<JoinHandle<()>> {
+        (0..self.config.workers)
+            .map(|slot| {
+                let jobs = self.clone();
+                let state = state.clone();
+                let worker = format!("{}/{slot}", self.instance);
+                tokio::spawn(async move { jobs.work(&state, &worker).await })
+            })
+            .collect()
+    }
+
+    async fn work(&self, state: &AppState, worker: &str) {
+        loop {
+            match self.claim(worker).await {
+                Ok(Some(job)) => {
+                    self.process(state, worker, job).await;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err) => warn!(error = %err, "failed to claim job"),
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(self.config.poll_interval) => {}
+            }
+        }
+    }
+
+    /// Takes the oldest due job, or one whose lease ran out because its
+    /// worker died.
+    async fn claim(&self, worker: &str) -> Result<Option<ClaimedJob>, sqlx::Error> {
+        let row = sqlx::query(
+            "UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_by = $1, \
+                locked_until = NOW() + make_interval(secs => $2), \
+                started_at = COALESCE(started_at, NOW()), updated_at = NOW() \
+             WHERE id = ( \
+                SELECT id FROM jobs \
+                WHERE (status = 'queued' AND run_at <= NOW()) \
+                   OR (status = 'running' AND locked_until < NOW()) \
+                ORDER BY run_at LIMIT 1 FOR UPDATE SKIP LOCKED \
+             ) \
+             RETURNING id, kind, user_id, payload, progress, attempts, max_attempts",
+        )
+        .bind(worker)
+        .bind(self.config.lease.as_secs_f64())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| ClaimedJob {
+            id: row.get("id"),
+            kind: row.get("kind"),
+            user_id: row.get("user_id"),
+            payload: row.get::<Json<Value>, _>("payload").0,
+            progress: row
+                .get::<Option<Json<Value>>, _>("progress")
+                .map(|Json(progress)| progress),
+            attempts: row.get("attempts"),
+            max_attempts: row.get("max_attempts"),
+        }))
+    }
+
+    /// Runs the job while renewing its lease. A renewal that finds the job
+    /// cancelled or taken over drops the attempt without recording anything.
+    async fn process(&self, state: &AppState, worker: &str, job: ClaimedJob) {
+        let Some(kind) = JobKind::parse(&job.kind) else {
+            let err = JobError::Fatal(format!("unknown job kind {}", job.kind));
+            self.finish(state, worker, &job, None, Err(err)).await;
+            return;
+        };
+        if job.attempts > job.max_attempts {
+            // The previous worker died during the final attempt.
+            let err = JobError::Fatal("worker lost during the final attempt".to_string());
+            self.finish(state, worker, &job, Some(kind), Err(err)).await;
+            return;
+        }
+        let run = execute(state, kind, &job);
+        tokio::pin!(run);
+        let mut renew = tokio::time::interval(self.config.lease / 3);
+        renew.tick().await;
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut run => break Some(outcome),
+                _ = renew.tick() => match self.renew(worker, job.id).await {
+                    Ok(true) => {}
+                    Ok(false) => break None,
+                    Err(err) => warn!(job = job.id, error = %err, "failed to renew job lease"),
+                },
+            }
+        };
+        match outcome {
+            Some(outcome) => self.finish(state, worker, &job, Some(kind), outcome).await,
+            None => {
+                info!(
+                    job = job.id,
+                    "job was cancelled or reassigned; dropping attempt"
+                );
+                if kind == JobKind::WebhookDelivery {
+                    // The attempt may have put the delivery back to pending
+                    // after the cancel settled it.
+                    self.abandon_delivery(&job, "job cancelled").await;
+                }
+            }
+        }
+    }
+
+    async fn renew(&self, worker: &str, id: i64) -> Result<bool, sqlx::Error> {
+        let renewed = sqlx::query(
+            "UPDATE jobs SET locked_until = NOW() + make_interval(secs => $3) \
+             WHERE id = $1 AND status = 'running' AND locked_by = $2",
+        )
+        .bind(id)
+        .bind(worker)
+        .bind(self.config.lease.as_secs_f64())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(renewed == 1)
+    }
+
+    async fn finish(
+        &self,
+        state: &AppState,
+        worker: &str,
+        job: &ClaimedJob,
+        kind: Option<JobKind>,
+        outcome: Result<Value, JobError>,
+    ) {
+        let (status, result, error, retry_in) = match outcome {
+            Ok(result) => ("succeeded", Some(Json(result)), None, None),
+            Err(JobError::Retry(err)) if !job.is_last_attempt() => {
+                ("queued", None, Some(err), Some(backoff(job.attempts)))
+            }
+            Err(JobError::Retry(err) | JobError::Fatal(err)) => ("dead", None, Some(err), None),
+        };
+        if let Some(err) = &error {
+            warn!(job = job.id, kind = %job.kind, attempts = job.attempts, status, error = %err, "job attempt failed");
+        }
+        let finished = sqlx::query(
+            "UPDATE jobs SET status = $3, result = COALESCE($4, result), last_error = $5, \
+                run_at = NOW() + make_interval(secs => $6), locked_by = NULL, \
+                locked_until = NULL, updated_at = NOW(), \
+                finished_at = CASE WHEN $3 = 'queued' THEN NULL ELSE NOW() END \
+             WHERE id = $1 AND status = 'running' AND locked_by = $2",
+        )
+        .bind(job.id)
+        .bind(worker)
+        .bind(status)
+        .bind(result)
+        .bind(&error)
+        .bind(retry_in.unwrap_or_default().as_secs_f64())
+        .execute(&self.pool)
+        .await;
+        match finished {
+            Ok(done) if done.rows_affected() == 1 => {}
+            Ok(_) => return,
+            Err(err) => {
+                warn!(job = job.id, error = %err, "failed to record job outcome");
+                return;
+            }
+        }
+        if status == "dead" && kind == Some(JobKind::WebhookDelivery) {
+            self.abandon_delivery(job, error.as_deref().unwrap_or("job failed"))
+                .await;
+        }
+        let (Some(kind), Some(user_id)) = (kind, job.user_id) else {
+            return;
+        };
+        if kind.notifies() && status != "queued" {
+            state.events.publish(DomainEvent::JobFinished {
+                user_id,
+                job_id: job.id,
+                kind: kind.as_str(),
+                succeeded: status == "succeeded",
+                error,
+            });
+        }
+    }
+
+    async fn abandon_delivery(&self, job: &ClaimedJob, error: &str) {
+        if let Err(err) = webhooks::abandon_delivery(&self.pool, job.id, error).await {
+            warn!(job = job.id, error = %err, "failed to mark webhook delivery failed");
+        }
+    }
+}
+
+async fn execute(state: &AppState, kind: JobKind, job: &ClaimedJob) -> Result<Value, JobError> {
+    match kind {
+        JobKind::ProjectExport => transfer::export(state, job).await,
+        JobKind::ProjectImport => transfer::import(state, job).await,
+        JobKind::WebhookDelivery => state.webhooks.deliver(job).await,
+        JobKind::AgentPipeline => pipeline::run(state, job).await,
+    }
+}
+
+/// Delay before retrying after `attempts` failed tries.
+fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(BACKOFF_CAP)
+}
+
+fn job_value(row: &PgRow) -> Value {
+    let json_column = |name: &str| {
+        row.get::<Option<Json<Value>>, _>(name)
+            .map(|Json(value)| value)
+    };
+    let timestamp = |name: &str| {
+        row.get::<Option<DateTime<Utc>>, _>(name)
+            .map(|at| at.to_rfc3339())
+    };
+    json!({
+        "job_id": row.get::<i64, _>("id"),
+        "kind": row.get::<String, _>("kind"),
+        "user_id": row.get::<Option<i32>, _>("user_id"),
+        "status": row.get::<String, _>("status"),
+        "attempts": row.get::<i32, _>("attempts"),
+        "max_attempts": row.get::<i32, _>("max_attempts"),
+        "run_at": row.get::<DateTime<Utc>, _>("run_at").to_rfc3339(),
+        "progress": json_column("progress"),
+        "result": json_column("result"),
+        "last_error": row.get::<Option<String>, _>("last_error"),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        "started_at": timestamp("started_at"),
+        "finished_at": timestamp("finished_at"),
+    })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct JobIdParams {
+    job_id: i64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Dead,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Dead => "dead",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct JobListParams {
+    #[serde(default)]
+    status: Option<JobStatus>,
+    #[serde(default)]
+    kind: Option<String>,
+    /// Include every user's jobs; admin only.
+    #[serde(default)]
+    all_users: bool,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+fn job_not_found(job_id: i64) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::JobNotFound,
+        "job not found",
+        Some(json!({ "job_id": job_id })),
+    )
+}
+
+fn db_error(err: sqlx::Error) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update job: {err}"))
+}
+
+/// Matches jobs of users in the tenant bound to `$param`. Jobs without an
+/// owner belong to the default tenant.
+fn in_tenant(param: u8) -> String {
+    format!(
+        "(user_id IN (SELECT id FROM users WHERE tenant_id = ${param}) \
+          OR (user_id IS NULL AND ${param} = {DEFAULT_TENANT}))"
+    )
+}
+
+/// The job row if `ctx` may see it: its owner, or an admin of its tenant.
+pub(crate) async fn load(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    job_id: i64,
+) -> Result<Value, RpcMethodError> {
+    let row = sqlx::query(&format!(
+        "SELECT {COLUMNS} FROM jobs WHERE id = $1 AND (user_id = $2 OR ($3 AND {}))",
+        in_tenant(4)
+    ))
+    .bind(job_id)
+    .bind(ctx.user_id)
+    .bind(ctx.is_admin())
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load job: {err}")))?
+    .ok_or_else(|| job_not_found(job_id))?;
+    Ok(job_value(&row))
+}
+
+pub(crate) async fn status(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: JobIdParams,
+) -> Result<Value, RpcMethodError> {
+    load(pool, ctx, params.job_id).await
+}
+
+pub(crate) async fn list(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: JobListParams,
+) -> Result<Value, RpcMethodError> {
+    if params.all_users && !ctx.is_admin() {
+        return Err(RpcMethodError::forbidden("insufficient permissions"));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let rows = sqlx::query(&format!(
+        "SELECT {COLUMNS} FROM jobs \
+         WHERE (($1 AND {}) OR user_id = $2) \
+           AND ($3::text IS NULL OR status = $3) \
+           AND ($4::text IS NULL OR kind = $4) \
+           AND ($5::bigint IS NULL OR id < $5) \
+         ORDER BY id DESC LIMIT $6",
+        in_tenant(7)
+    ))
+    .bind(params.all_users)
+    .bind(ctx.user_id)
+    .bind(params.status.map(JobStatus::as_str))
+    .bind(&params.kind)
+    .bind(params.cursor)
+    .bind(limit)
+    .bind(ctx.tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list jobs: {err}")))?;
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    let jobs: Vec<Value> = rows.iter().map(job_value).collect();
+    Ok(json!({ "jobs": jobs, "next_cursor": next_cursor }))
+}
+
+/// Cancels a queued or running job. A running attempt stops at its next
+/// lease renewal.
+pub(crate) async fn cancel(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: JobIdParams,
+) -> Result<Value, RpcMethodError> {
+    let job = transition(
+        pool,
+        ctx,
+        params.job_id,
+        "status = 'cancelled', locked_by = NULL, locked_until = NULL, finished_at = NOW()",
+        &["queued", "running"],
+    )
+    .await?;
+    if job["kind"] == JobKind::WebhookDelivery.as_str() {
+        webhooks::abandon_delivery(pool, params.job_id, "job cancelled")
+            .await
+            .map_err(db_error)?;
+    }
+    Ok(job)
+}
+
+/// Requeues a dead or cancelled job with a fresh attempt budget.
+pub(crate) async fn retry(
+    jobs: &Jobs,
+    ctx: &RequestContext,
+    params: JobIdParams,
+) -> Result<Value, RpcMethodError> {
+    let job = transition(
+        &jobs.pool,
+        ctx,
+        params.job_id,
+        "status = 'queued', attempts = 0, run_at = NOW(), finished_at = NULL",
+        &["dead", "cancelled"],
+    )
+    .await?;
+    jobs.wake();
+    Ok(job)
+}
+
+async fn transition(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    job_id: i64,
+    assignments: &str,
+    from: &[&str],
+) -> Result<Value, RpcMethodError> {
+    let row = sqlx::query(&format!(
+        "UPDATE jobs SET {assignments}, updated_at = NOW() \
+         WHERE id = $1 AND (user_id = $2 OR ($3 AND {})) AND status = ANY($4) \
+         RETURNING {COLUMNS}",
+        in_tenant(5)
+    ))
+    .bind(job_id)
+    .bind(ctx.user_id)
+    .bind(ctx.is_admin())
+    .bind(from)
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?;
+    if let Some(row) = row {
+        return Ok(job_value(&row));
+    }
+    // Tell a missing job apart from one in the wrong state.
+    let current = load(pool, ctx, job_id).await?;
+    Err(RpcMethodError::new(
+        ErrorCode::JobState,
+        "job is not in a state that allows this",
+        Some(json!({ "job_id": job_id, "status": current["status"], "expected": from })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_and_errors_classify() {
+        assert_eq!(backoff(1), Duration::from_secs(30));
+        assert_eq!(backoff(2), Duration::from_secs(60));
+        assert_eq!(backoff(4), Duration::from_secs(240));
+        assert_eq!(backoff(20), BACKOFF_CAP);
+
+        assert!(matches!(
+            JobError::from(RpcMethodError::internal("connection reset")),
+            JobError::Retry(_)
+        ));
+        assert!(matches!(
+            JobError::from(RpcMethodError::new(
+                ErrorCode::QuotaExceeded,
+                "quota exceeded",
+                None
+            )),
+            JobError::Fatal(_)
+        ));
+        for kind in JobKind::ALL {
+            assert_eq!(JobKind::parse(kind.as_str()), Some(kind));
+        }
+    }
+}
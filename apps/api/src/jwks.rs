@@ -0,0 +1,110 @@
+//! Public keys of the auth service for RS256 tokens, fetched from
+//! `API_JWT_JWKS_URL` (its `/.well-known/jwks.json`) and kept for
+//! `API_JWT_JWKS_REFRESH_SECS`. A token naming an unknown `kid` triggers an
+//! early refresh, at most once per [`MIN_REFRESH`], so a key rotation is
+//! picked up without a restart while made-up key ids cannot hammer the auth
+//! service. If a refresh fails the previous keys stay in use.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use auth_core::{DecodingKeys, KeyId};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::DecodingKey;
+use reqwest::Client;
+use tokio::sync::{Mutex, RwLock};
+use tracing::warn;
+
+use crate::RpcMethodError;
+
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Keys {
+    by_kid: DecodingKeys,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Jwks {
+    url: Arc<str>,
+    client: Client,
+    refresh: Duration,
+    keys: Arc<RwLock<Keys>>,
+    /// Serializes fetches so concurrent misses cause one request.
+    fetch: Arc<Mutex<()>>,
+}
+
+impl Jwks {
+    pub(crate) fn new(url: String, refresh: Duration) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            url: url.into(),
+            client: Client::builder().timeout(FETCH_TIMEOUT).build()?,
+            refresh,
+            keys: Arc::default(),
+            fetch: Arc::default(),
+        })
+    }
+
+    pub(crate) async fn key(&self, id: &KeyId) -> Result<DecodingKey, RpcMethodError> {
+        if let Some(key) = self.cached(id, self.refresh).await {
+            return Ok(key);
+        }
+        let _fetch = self.fetch.lock().await;
+        // Another request may have refreshed while this one waited.
+        if let Some(key) = self.cached(id, MIN_REFRESH).await {
+            return Ok(key);
+        }
+        let recently = self
+            .keys
+            .read()
+            .await
+            .fetched_at
+            .is_some_and(|at| at.elapsed() < MIN_REFRESH);
+        if !recently {
+            match self.fetch_keys().await {
+                Ok(by_kid) => {
+                    *self.keys.write().await = Keys {
+                        by_kid,
+                        fetched_at: Some(Instant::now()),
+                    };
+                }
+                Err(err) => {
+                    warn!(url = %self.url, error = %err, "failed to fetch jwks");
+                    // Keep the old keys, but don't retry on every request.
+                    self.keys.write().await.fetched_at = Some(Instant::now());
+                }
+            }
+        }
+        self.keys
+            .read()
+            .await
+            .by_kid
+            .get(id)
+            .cloned()
+            .ok_or_else(|| RpcMethodError::unauthorized("unknown signing key"))
+    }
+
+    /// The key if the set was fetched less than `max_age` ago.
+    async fn cached(&self, id: &KeyId, max_age: Duration) -> Option<DecodingKey> {
+        let keys = self.keys.read().await;
+        if keys.fetched_at.is_some_and(|at| at.elapsed() < max_age) {
+            keys.by_kid.get(id).cloned()
+        } else {
+            None
+        }
+    }
+
+    async fn fetch_keys(&self) -> anyhow::Result<DecodingKeys> {
+        let set: JwkSet = self
+            .client
+            .get(&*self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(DecodingKeys::from_jwks(&set))
+    }
+}
@@ -0,0 +1,1238 @@
+//! LLM providers. `llm.chat`, `llm.completion` and `llm.embed` are routed to
+//! a provider by model prefix (`LLM_PROVIDER_ROUTES`); every provider answers
+//! in the OpenAI response shape, so billing and the response cache read
+//! `usage` the same way regardless of where the model runs. Model management
+//! (`llm.download`, `llm.start`, ...) always targets the local llm server.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use chrono::Utc;
+use futures::stream::{self, BoxStream, StreamExt};
+use parking_lot::RwLock;
+use reqwest::{header::AUTHORIZATION, Client, Method, RequestBuilder, StatusCode as HttpStatus};
+use sandbox::FaultInjector;
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{
+    telemetry, LlmAdminLoadParams, LlmChatParams, LlmCompletionParams, LlmEmbedParams,
+    LlmModelParams, RequestContext, RpcMethodError,
+};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// OpenAI `chat.completion.chunk` objects; the last one carries `usage`.
+pub(crate) type ChatStream = BoxStream<'static, Result<Value, RpcMethodError>>;
+
+/// Where a model is served, for usage records and billing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ProviderInfo {
+    pub(crate) name: &'static str,
+    /// The provider deducts usage from the caller's balance itself, so the
+    /// gateway only records it.
+    pub(crate) settles_usage: bool,
+}
+
+#[async_trait]
+pub(crate) trait LlmProvider: Send + Sync {
+    fn info(&self) -> ProviderInfo;
+
+    async fn chat(
+        &self,
+        ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError>;
+
+    async fn completion(
+        &self,
+        ctx: &RequestContext,
+        params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError>;
+
+    async fn embed(
+        &self,
+        ctx: &RequestContext,
+        params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError>;
+
+    async fn chat_stream(
+        &self,
+        ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError>;
+}
+
+/// A model prefix and the provider serving it. Prefixes ending in `/` are
+/// namespaces (`openai/gpt-4o`) and are stripped before the call; others
+/// (`claude-`) are part of the model name.
+#[derive(Clone)]
+struct Route {
+    prefix: String,
+    provider: Arc<dyn LlmProvider>,
+}
+
+#[derive(Clone)]
+pub(crate) struct LlmClient {
+    local: Arc<LocalServer>,
+    routes: Arc<Vec<Route>>,
+    faults: FaultInjector,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProviderKind {
+    Local,
+    OpenAi,
+    Anthropic,
+}
+
+/// Provider settings. `LLM_PROVIDER_ROUTES` is a comma-separated list of
+/// `prefix=provider` pairs with provider `local`, `openai` or `anthropic`;
+/// unmatched models go to the local server.
+#[derive(Clone)]
+pub(crate) struct LlmConfig {
+    timeout: Duration,
+    server_url: String,
+    admin_token: Option<String>,
+    routes: Vec<(String, ProviderKind)>,
+    openai_base_url: String,
+    openai_api_key: Option<String>,
+    anthropic_base_url: String,
+    anthropic_api_key: Option<String>,
+    anthropic_max_tokens: u32,
+}
+
+impl LlmConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut routes = Vec::new();
+        for entry in config.list("LLM_PROVIDER_ROUTES", &[]) {
+            let route = entry
+                .split_once('=')
+                .map(|(prefix, provider)| (prefix.trim(), provider.trim()))
+                .filter(|(prefix, _)| !prefix.is_empty());
+            let Some((prefix, provider)) = route else {
+                config.invalid(
+                    "LLM_PROVIDER_ROUTES",
+                    format!("entry `{entry}` is not of the form prefix=provider"),
+                );
+                continue;
+            };
+            let kind = match provider {
+                "local" => ProviderKind::Local,
+                "openai" => ProviderKind::OpenAi,
+                "anthropic" => ProviderKind::Anthropic,
+                other => {
+                    config.invalid(
+                        "LLM_PROVIDER_ROUTES",
+                        format!("unknown provider `{other}`; expected local, openai or anthropic"),
+                    );
+                    continue;
+                }
+            };
+            routes.push((prefix.to_string(), kind));
+        }
+        let llm = Self {
+            timeout: config.secs("LLM_HTTP_TIMEOUT_SECS", 30),
+            server_url: config.string("LLM_SERVER_URL", "http://127.0.0.1:6988"),
+            admin_token: config.secret("LLM_SERVER_ADMIN_TOKEN"),
+            routes,
+            openai_base_url: config.string("OPENAI_BASE_URL", "https://api.openai.com/v1"),
+            openai_api_key: config.secret("OPENAI_API_KEY"),
+            anthropic_base_url: config.string("ANTHROPIC_BASE_URL", "https://api.anthropic.com"),
+            anthropic_api_key: config.secret("ANTHROPIC_API_KEY"),
+            anthropic_max_tokens: config
+                .get::<u32>("ANTHROPIC_DEFAULT_MAX_TOKENS", 1024)
+                .max(1),
+        };
+        if llm.routes_to(ProviderKind::OpenAi) && llm.openai_api_key.is_none() {
+            config.invalid(
+                "OPENAI_API_KEY",
+                "is required when routing models to openai",
+            );
+        }
+        if llm.routes_to(ProviderKind::Anthropic) && llm.anthropic_api_key.is_none() {
+            config.invalid(
+                "ANTHROPIC_API_KEY",
+                "is required when routing models to anthropic",
+            );
+        }
+        llm
+    }
+
+    fn routes_to(&self, kind: ProviderKind) -> bool {
+        self.routes.iter().any(|(_, route)| *route == kind)
+    }
+}
+
+impl LlmClient {
+    pub(crate) fn new(config: &LlmConfig) -> anyhow::Result<Self> {
+        let http = Client::builder().timeout(config.timeout).build()?;
+        let local = Arc::new(LocalServer {
+            http: http.clone(),
+            base_url: config.server_url.clone(),
+            admin_token: RwLock::new(config.admin_token.clone()),
+        });
+        let openai: Option<Arc<dyn LlmProvider>> =
+            config.routes_to(ProviderKind::OpenAi).then(|| {
+                Arc::new(OpenAi {
+                    http: http.clone(),
+                    base_url: config.openai_base_url.clone(),
+                    api_key: config.openai_api_key.clone().unwrap_or_default(),
+                }) as Arc<dyn LlmProvider>
+            });
+        let anthropic: Option<Arc<dyn LlmProvider>> =
+            config.routes_to(ProviderKind::Anthropic).then(|| {
+                Arc::new(Anthropic {
+                    http: http.clone(),
+                    base_url: config.anthropic_base_url.clone(),
+                    api_key: config.anthropic_api_key.clone().unwrap_or_default(),
+                    default_max_tokens: config.anthropic_max_tokens,
+                }) as Arc<dyn LlmProvider>
+            });
+        let mut routes: Vec<Route> = config
+            .routes
+            .iter()
+            .map(|(prefix, kind)| Route {
+                prefix: prefix.clone(),
+                provider: match kind {
+                    ProviderKind::Local => local.clone(),
+                    ProviderKind::OpenAi => openai.clone().expect("openai is configured"),
+                    ProviderKind::Anthropic => anthropic.clone().expect("anthropic is configured"),
+                },
+            })
+            .collect();
+        // Longest prefix wins.
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        Ok(Self {
+            local,
+            routes: Arc::new(routes),
+            faults: FaultInjector::default(),
+        })
+    }
+
+    /// Injects `faults` into chat, completion and embedding calls.
+    pub(crate) fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Fails the way an unavailable provider would when chaos mode says so.
+    async fn inject_fault(&self, operation: &str) -> Result<(), RpcMethodError> {
+        if self.faults.should_fail(operation).await {
+            let body = json!({ "error": format!("injected fault in {operation}") });
+            return Err(status_error(HttpStatus::SERVICE_UNAVAILABLE, &body));
+        }
+        Ok(())
+    }
+
+    /// Sends `token` on admin calls to the local server from now on.
+    pub(crate) fn set_admin_token(&self, token: String) {
+        *self.local.admin_token.write() = Some(token);
+    }
+
+    /// A client sending every model to `provider`.
+    #[cfg(test)]
+    pub(crate) fn with_provider(provider: Arc<dyn LlmProvider>) -> Self {
+        Self {
+            local: Arc::new(LocalServer {
+                http: Client::new(),
+                base_url: String::new(),
+                admin_token: RwLock::new(None),
+            }),
+            routes: Arc::new(vec![Route {
+                prefix: String::new(),
+                provider,
+            }]),
+            faults: FaultInjector::default(),
+        }
+    }
+
+    fn route(&self, model: &str) -> (Arc<dyn LlmProvider>, String) {
+        match self
+            .routes
+            .iter()
+            .find(|route| model.starts_with(&route.prefix))
+        {
+            Some(route) if route.prefix.ends_with('/') => (
+                route.provider.clone(),
+                model[route.prefix.len()..].to_string(),
+            ),
+            Some(route) => (route.provider.clone(), model.to_string()),
+            None => (self.local.clone(), model.to_string()),
+        }
+    }
+
+    pub(crate) fn provider(&self, model: &str) -> ProviderInfo {
+        self.route(model).0.info()
+    }
+
+    pub(crate) async fn chat(
+        &self,
+        ctx: &RequestContext,
+        mut params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError> {
+        let (provider, model) = self.route(&params.model);
+        params.model = model;
+        self.inject_fault("llm.chat").await?;
+        provider.chat(ctx, params).await
+    }
+
+    pub(crate) async fn completion(
+        &self,
+        ctx: &RequestContext,
+        mut params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError> {
+        let (provider, model) = self.route(&params.model);
+        params.model = model;
+        self.inject_fault("llm.completion").await?;
+        provider.completion(ctx, params).await
+    }
+
+    pub(crate) async fn embed(
+        &self,
+        ctx: &RequestContext,
+        mut params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError> {
+        let (provider, model) = self.route(&params.model);
+        params.model = model;
+        self.inject_fault("llm.embed").await?;
+        provider.embed(ctx, params).await
+    }
+
+    pub(crate) async fn chat_stream(
+        &self,
+        ctx: &RequestContext,
+        mut params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError> {
+        let (provider, model) = self.route(&params.model);
+        params.model = model;
+        self.inject_fault("llm.chat").await?;
+        provider.chat_stream(ctx, params).await
+    }
+
+    pub(crate) async fn list_models(&self) -> Result<Value, RpcMethodError> {
+        self.local.get_admin("/admin/models").await
+    }
+
+    pub(crate) async fn status(&self) -> Result<Value, RpcMethodError> {
+        self.local.get_admin("/admin/status").await
+    }
+
+    /// Unauthenticated reachability check used by the readiness probe.
+    pub(crate) async fn ping(&self) -> Result<(), String> {
+        let response = self
+            .local
+            .http
+            .get(format!("{}/health", self.local.base_url))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("llm server returned {}", response.status()))
+        }
+    }
+
+    pub(crate) async fn download(
+        &self,
+        ctx: &RequestContext,
+        params: &LlmModelParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.local
+            .post_admin("/admin/download", params, Some(ctx))
+            .await
+    }
+
+    pub(crate) async fn load(
+        &self,
+        ctx: &RequestContext,
+        params: LlmAdminLoadParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.local
+            .post_admin("/admin/load", &params, Some(ctx))
+            .await
+    }
+
+    pub(crate) async fn unload(
+        &self,
+        ctx: &RequestContext,
+        params: &LlmModelParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.local
+            .post_admin("/admin/unload", params, Some(ctx))
+            .await
+    }
+}
+
+/// The bundled node-llama-cpp server. It already speaks the OpenAI shape and
+/// tracks usage per `X-User-Id`.
+struct LocalServer {
+    http: Client,
+    base_url: String,
+    /// Replaced when `LLM_SERVER_ADMIN_TOKEN` rotates.
+    admin_token: RwLock<Option<String>>,
+}
+
+impl LocalServer {
+    async fn post_user<T: Serialize + Sync>(
+        &self,
+        path: &str,
+        body: &T,
+        ctx: &RequestContext,
+    ) -> Result<Value, RpcMethodError> {
+        self.send_request(
+            Method::POST,
+            path,
+            Some(body),
+            Some(ctx),
+            false,
+            Some(ctx.request_id),
+        )
+        .await
+    }
+
+    async fn post_admin<T: Serialize + Sync>(
+        &self,
+        path: &str,
+        body: &T,
+        ctx: Option<&RequestContext>,
+    ) -> Result<Value, RpcMethodError> {
+        self.send_request(
+            Method::POST,
+            path,
+            Some(body),
+            ctx,
+            true,
+            Some(ctx.map_or_else(Uuid::new_v4, |ctx| ctx.request_id)),
+        )
+        .await
+    }
+
+    async fn get_admin(&self, path: &str) -> Result<Value, RpcMethodError> {
+        self.send_request::<Value>(Method::GET, path, None, None, true, Some(Uuid::new_v4()))
+            .await
+    }
+
+    async fn send_request<T: Serialize + Sync>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&T>,
+        ctx: Option<&RequestContext>,
+        admin: bool,
+        request_id: Option<Uuid>,
+    ) -> Result<Value, RpcMethodError> {
+        let url = format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let mut trace_headers = HeaderMap::new();
+        telemetry::inject(&mut trace_headers, ctx);
+        let mut builder = self.http.request(method, url).headers(trace_headers);
+        if let Some(ctx) = ctx {
+            builder = builder.header("X-User-Id", ctx.user_id.to_string()).header(
+                "X-Request-Id",
+                request_id.unwrap_or_else(Uuid::new_v4).to_string(),
+            );
+        } else if let Some(request_id) = request_id {
+            builder = builder.header("X-Request-Id", request_id.to_string());
+        }
+        if admin {
+            let token =
+                self.admin_token.read().clone().ok_or_else(|| {
+                    RpcMethodError::internal("LLM_SERVER_ADMIN_TOKEN not configured")
+                })?;
+            builder = builder.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if let Some(body) = body {
+            builder = builder.json(body);
+        }
+        send_json(builder, status_error).await
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LocalServer {
+    fn info(&self) -> ProviderInfo {
+        ProviderInfo {
+            name: "local",
+            settles_usage: true,
+        }
+    }
+
+    async fn chat(
+        &self,
+        ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.post_user("/v1/chat/completions", &params, ctx).await
+    }
+
+    async fn completion(
+        &self,
+        ctx: &RequestContext,
+        params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.post_user("/v1/completions", &params, ctx).await
+    }
+
+    async fn embed(
+        &self,
+        ctx: &RequestContext,
+        params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.post_user("/v1/embeddings", &params, ctx).await
+    }
+
+    /// The local server generates the full answer before replying, so the
+    /// stream is a single chunk.
+    async fn chat_stream(
+        &self,
+        ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError> {
+        let response = self.chat(ctx, params).await?;
+        Ok(stream::once(async move { Ok(completion_as_chunk(&response)) }).boxed())
+    }
+}
+
+struct OpenAi {
+    http: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl OpenAi {
+    fn post(&self, path: &str, body: &Value) -> RequestBuilder {
+        self.http
+            .post(format!("{}{path}", self.base_url.trim_end_matches('/')))
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key))
+            .json(body)
+    }
+}
+
+/// OpenAI has no `top_k` or `repeat_penalty`; they are dropped.
+fn openai_chat_body(params: &LlmChatParams) -> Value {
+    json!({
+        "model": params.model,
+        "messages": params.messages,
+        "temperature": params.temperature,
+        "top_p": params.top_p,
+        "max_tokens": params.max_tokens,
+    })
+}
+
+#[async_trait]
+impl LlmProvider for OpenAi {
+    fn info(&self) -> ProviderInfo {
+        ProviderInfo {
+            name: "openai",
+            settles_usage: false,
+        }
+    }
+
+    async fn chat(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError> {
+        send_json(
+            self.post(
+                "/chat/completions",
+                &without_nulls(openai_chat_body(&params)),
+            ),
+            upstream_error,
+        )
+        .await
+    }
+
+    async fn completion(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError> {
+        let body = without_nulls(json!({
+            "model": params.model,
+            "prompt": params.prompt,
+            "temperature": params.temperature,
+            "top_p": params.top_p,
+            "max_tokens": params.max_tokens,
+        }));
+        send_json(self.post("/completions", &body), upstream_error).await
+    }
+
+    async fn embed(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError> {
+        let body = json!({ "model": params.model, "input": params.input });
+        send_json(self.post("/embeddings", &body), upstream_error).await
+    }
+
+    async fn chat_stream(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError> {
+        let mut body = without_nulls(openai_chat_body(&params));
+        body["stream"] = json!(true);
+        body["stream_options"] = json!({ "include_usage": true });
+        let response = send(self.post("/chat/completions", &body), upstream_error).await?;
+        Ok(sse_events(response)
+            .take_while(|event| {
+                futures::future::ready(!matches!(event, Ok(event) if event.data == "[DONE]"))
+            })
+            .map(|event| {
+                event.and_then(|event| {
+                    serde_json::from_str(&event.data).map_err(|err| {
+                        RpcMethodError::internal(&format!("invalid stream chunk: {err}"))
+                    })
+                })
+            })
+            .boxed())
+    }
+}
+
+struct Anthropic {
+    http: Client,
+    base_url: String,
+    api_key: String,
+    default_max_tokens: u32,
+}
+
+impl Anthropic {
+    async fn messages(&self, body: &Value) -> Result<reqwest::Response, RpcMethodError> {
+        send(
+            self.http
+                .post(format!(
+                    "{}/v1/messages",
+                    self.base_url.trim_end_matches('/')
+                ))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(body),
+            upstream_error,
+        )
+        .await
+    }
+}
+
+/// Maps chat params onto the Messages API: system messages move to the
+/// top-level `system` field and `max_tokens` becomes mandatory.
+fn anthropic_body(params: &LlmChatParams, default_max_tokens: u32) -> Value {
+    let system: Vec<&str> = params
+        .messages
+        .iter()
+        .filter(|message| message.role == "system")
+        .map(|message| message.content.as_str())
+        .collect();
+    let messages: Vec<Value> = params
+        .messages
+        .iter()
+        .filter(|message| message.role != "system")
+        .map(|message| json!({ "role": message.role, "content": message.content }))
+        .collect();
+    without_nulls(json!({
+        "model": params.model,
+        "system": (!system.is_empty()).then(|| system.join("\n\n")),
+        "messages": messages,
+        "max_tokens": params.max_tokens.unwrap_or(default_max_tokens),
+        "temperature": params.temperature,
+        "top_p": params.top_p,
+        "top_k": params.top_k,
+    }))
+}
+
+fn anthropic_text(response: &Value) -> String {
+    response
+        .get("content")
+        .and_then(Value::as_array)
+        .map(|blocks| {
+            blocks
+                .iter()
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .collect::<String>()
+        })
+        .unwrap_or_default()
+}
+
+fn anthropic_usage(usage: &Value) -> Value {
+    let prompt = usage["input_tokens"].as_i64().unwrap_or(0);
+    let completion = usage["output_tokens"].as_i64().unwrap_or(0);
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    })
+}
+
+fn anthropic_finish_reason(stop_reason: Option<&str>) -> Option<&'static str> {
+    stop_reason.map(|reason| match reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        _ => "stop",
+    })
+}
+
+fn anthropic_to_chat(response: &Value) -> Value {
+    json!({
+        "id": response["id"],
+        "object": "chat.completion",
+        "created": Utc::now().timestamp(),
+        "model": response["model"],
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": anthropic_text(response) },
+            "finish_reason": anthropic_finish_reason(response["stop_reason"].as_str()),
+        }],
+        "usage": anthropic_usage(&response["usage"]),
+    })
+}
+
+/// Folds Messages API stream events into OpenAI chunks. Input tokens arrive
+/// with `message_start`, output tokens with the closing `message_delta`.
+#[derive(Default)]
+struct AnthropicStream {
+    id: Value,
+    model: Value,
+    input_tokens: i64,
+}
+
+impl AnthropicStream {
+    fn next(&mut self, event: &SseEvent) -> Option<Result<Value, RpcMethodError>> {
+        let data: Value = match serde_json::from_str(&event.data) {
+            Ok(data) => data,
+            Err(err) => {
+                return Some(Err(RpcMethodError::internal(&format!(
+                    "invalid stream event: {err}"
+                ))))
+            }
+        };
+        match data["type"].as_str() {
+            Some("message_start") => {
+                self.id = data["message"]["id"].clone();
+                self.model = data["message"]["model"].clone();
+                self.input_tokens = data["message"]["usage"]["input_tokens"]
+                    .as_i64()
+                    .unwrap_or(0);
+                None
+            }
+            Some("content_block_delta") => data["delta"]["text"]
+                .as_str()
+                .map(|text| Ok(self.chunk(json!({ "content": text }), None, None))),
+            Some("message_delta") => {
+                let mut usage = data["usage"].clone();
+                usage["input_tokens"] = json!(self.input_tokens);
+                Some(Ok(self.chunk(
+                    json!({}),
+                    anthropic_finish_reason(data["delta"]["stop_reason"].as_str()),
+                    Some(anthropic_usage(&usage)),
+                )))
+            }
+            Some("error") => Some(Err(RpcMethodError::internal(
+                data["error"]["message"]
+                    .as_str()
+                    .unwrap_or("llm provider stream failed"),
+            ))),
+            _ => None,
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>, usage: Option<Value>) -> Value {
+        chunk(&self.id, &self.model, delta, finish_reason, usage)
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Anthropic {
+    fn info(&self) -> ProviderInfo {
+        ProviderInfo {
+            name: "anthropic",
+            settles_usage: false,
+        }
+    }
+
+    async fn chat(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError> {
+        let response = self
+            .messages(&anthropic_body(&params, self.default_max_tokens))
+            .await?;
+        Ok(anthropic_to_chat(
+            &read_json(response, upstream_error).await?,
+        ))
+    }
+
+    async fn completion(
+        &self,
+        ctx: &RequestContext,
+        params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError> {
+        let chat = LlmChatParams {
+            model: params.model,
+            messages: vec![crate::LlmChatMessage {
+                role: "user".to_string(),
+                content: params.prompt,
+            }],
+            temperature: params.temperature,
+            top_k: params.top_k,
+            top_p: params.top_p,
+            repeat_penalty: None,
+            max_tokens: params.max_tokens,
+        };
+        let response = self.chat(ctx, chat).await?;
+        Ok(json!({
+            "id": response["id"],
+            "object": "text_completion",
+            "created": response["created"],
+            "model": response["model"],
+            "choices": [{
+                "index": 0,
+                "text": response["choices"][0]["message"]["content"],
+                "finish_reason": response["choices"][0]["finish_reason"],
+            }],
+            "usage": response["usage"],
+        }))
+    }
+
+    async fn embed(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError> {
+        Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "model does not support embeddings",
+            Some(json!({ "model": params.model, "provider": "anthropic" })),
+        ))
+    }
+
+    async fn chat_stream(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError> {
+        let mut body = anthropic_body(&params, self.default_max_tokens);
+        body["stream"] = json!(true);
+        let response = self.messages(&body).await?;
+        let mut state = AnthropicStream::default();
+        Ok(sse_events(response)
+            .filter_map(move |event| {
+                futures::future::ready(match event {
+                    Ok(event) => state.next(&event),
+                    Err(err) => Some(Err(err)),
+                })
+            })
+            .boxed())
+    }
+}
+
+fn chunk(
+    id: &Value,
+    model: &Value,
+    delta: Value,
+    finish_reason: Option<&str>,
+    usage: Option<Value>,
+) -> Value {
+    let mut chunk = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": Utc::now().timestamp(),
+        "model": model,
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+    });
+    if let Some(usage) = usage {
+        chunk["usage"] = usage;
+    }
+    chunk
+}
+
+/// Replays a complete chat response as one stream chunk.
+fn completion_as_chunk(response: &Value) -> Value {
+    let choice = &response["choices"][0];
+    chunk(
+        &response["id"],
+        &response["model"],
+        json!({ "role": "assistant", "content": choice["message"]["content"] }),
+        Some(choice["finish_reason"].as_str().unwrap_or("stop")),
+        response.get("usage").cloned(),
+    )
+}
+
+/// Drops null fields so providers apply their own defaults.
+fn without_nulls(mut body: Value) -> Value {
+    if let Some(object) = body.as_object_mut() {
+        object.retain(|_, value| !value.is_null());
+    }
+    body
+}
+
+/// Turns a provider's error response into an RPC error.
+type ErrorMap = fn(HttpStatus, &Value) -> RpcMethodError;
+
+async fn send(
+    builder: RequestBuilder,
+    errors: ErrorMap,
+) -> Result<reqwest::Response, RpcMethodError> {
+    let response = builder
+        .send()
+        .await
+        .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    Err(read_json(response, errors)
+        .await
+        .err()
+        .unwrap_or_else(|| RpcMethodError::internal("llm provider request failed")))
+}
+
+async fn send_json(builder: RequestBuilder, errors: ErrorMap) -> Result<Value, RpcMethodError> {
+    let response = builder
+        .send()
+        .await
+        .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
+    read_json(response, errors).await
+}
+
+async fn read_json(response: reqwest::Response, errors: ErrorMap) -> Result<Value, RpcMethodError> {
+    let status = response.status();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
+    let body: Value = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| json!({ "error": String::from_utf8_lossy(&bytes).trim().to_string() }));
+    if status.is_success() {
+        return Ok(body);
+    }
+    Err(errors(status, &body))
+}
+
+/// The message of a provider's error response. The local server sends
+/// `{"error": "..."}`, OpenAI and Anthropic `{"error": {"message": "..."}}`.
+fn error_message(status: HttpStatus, body: &Value) -> &str {
+    body.get("error")
+        .and_then(|error| error.as_str().or_else(|| error["message"].as_str()))
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or("request failed"))
+}
+
+/// Maps an error response of the local server, which answers for the
+/// caller, onto an RPC error.
+pub(crate) fn status_error(status: HttpStatus, body: &Value) -> RpcMethodError {
+    let message = error_message(status, body);
+    match status {
+        HttpStatus::UNAUTHORIZED => RpcMethodError::unauthorized(message),
+        HttpStatus::FORBIDDEN => RpcMethodError::forbidden(message),
+        HttpStatus::TOO_MANY_REQUESTS => RpcMethodError::new(
+            ErrorCode::LlmQuotaExhausted,
+            "insufficient token balance",
+            Some(json!({ "detail": message })),
+        ),
+        HttpStatus::NOT_FOUND => {
+            RpcMethodError::new(ErrorCode::LlmNotFound, message, Some(body.clone()))
+        }
+        _ => RpcMethodError::internal(message),
+    }
+}
+
+/// Maps an error response of a hosted provider. Its 401, 403 and 429 are
+/// about the server's own API key, so they are not passed on as the
+/// caller's authentication or quota errors.
+fn upstream_error(status: HttpStatus, body: &Value) -> RpcMethodError {
+    match status {
+        HttpStatus::UNAUTHORIZED | HttpStatus::FORBIDDEN | HttpStatus::TOO_MANY_REQUESTS => {
+            RpcMethodError::new(
+                ErrorCode::LlmUpstream,
+                ErrorCode::LlmUpstream.message(),
+                Some(json!({
+                    "status": status.as_u16(),
+                    "detail": error_message(status, body),
+                })),
+            )
+        }
+        _ => status_error(status, body),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct SseEvent {
+    event: Option<String>,
+    data: String,
+}
+
+/// Incremental `text/event-stream` parser; chunks may split lines, and the
+/// characters in them, anywhere. Only complete lines are decoded.
+#[derive(Default)]
+struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    fn push(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(SseEvent {
+                        event: self.event.take(),
+                        data: std::mem::take(&mut self.data).join("\n"),
+                    });
+                }
+                self.event = None;
+            } else if let Some(value) = line.strip_prefix("data:") {
+                self.data.push(value.trim_start().to_string());
+            } else if let Some(value) = line.strip_prefix("event:") {
+                self.event = Some(value.trim().to_string());
+            }
+        }
+        events
+    }
+}
+
+fn sse_events(response: reqwest::Response) -> BoxStream<'static, Result<SseEvent, RpcMethodError>> {
+    stream::unfold(
+        (Some(response), SseDecoder::default(), VecDeque::new()),
+        |(mut response, mut decoder, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (response, decoder, pending)));
+                }
+                match response.as_mut()?.chunk().await {
+                    Ok(Some(bytes)) => pending.extend(decoder.push(&bytes)),
+                    Ok(None) => return None,
+                    Err(err) => {
+                        let err = RpcMethodError::internal(&format!("llm stream failed: {err}"));
+                        return Some((Err(err), (None, decoder, pending)));
+                    }
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LlmChatMessage;
+
+    fn chat_params(model: &str) -> LlmChatParams {
+        LlmChatParams {
+            model: model.to_string(),
+            messages: vec![
+                LlmChatMessage {
+                    role: "system".to_string(),
+                    content: "Be terse.".to_string(),
+                },
+                LlmChatMessage {
+                    role: "user".to_string(),
+                    content: "Hi".to_string(),
+                },
+            ],
+            temperature: Some(0.2),
+            top_k: Some(40),
+            top_p: None,
+            repeat_penalty: Some(1.1),
+            max_tokens: None,
+        }
+    }
+
+    #[test]
+    fn routes_pick_the_longest_prefix_and_strip_namespaces() {
+        let local = Arc::new(LocalServer {
+            http: Client::new(),
+            base_url: String::new(),
+            admin_token: RwLock::new(None),
+        });
+        let remote: Arc<dyn LlmProvider> = Arc::new(LocalServer {
+            http: Client::new(),
+            base_url: "remote".to_string(),
+            admin_token: RwLock::new(None),
+        });
+        let client = LlmClient {
+            local,
+            routes: Arc::new(vec![
+                Route {
+                    prefix: "openai/".to_string(),
+                    provider: remote.clone(),
+                },
+                Route {
+                    prefix: "claude-".to_string(),
+                    provider: remote.clone(),
+                },
+            ]),
+            faults: FaultInjector::default(),
+        };
+        let (provider, model) = client.route("openai/gpt-4o");
+        assert!(Arc::ptr_eq(&provider, &remote));
+        assert_eq!(model, "gpt-4o");
+        assert_eq!(client.route("claude-sonnet").1, "claude-sonnet");
+        let (provider, model) = client.route("deepseek-coder-1.3b");
+        assert!(!Arc::ptr_eq(&provider, &remote));
+        assert_eq!(model, "deepseek-coder-1.3b");
+    }
+
+    #[test]
+    fn params_map_onto_provider_requests() {
+        let params = chat_params("claude-sonnet");
+        let openai = without_nulls(openai_chat_body(&params));
+        assert!(openai.get("top_k").is_none());
+        assert!(openai.get("max_tokens").is_none());
+        assert_eq!(openai["messages"][0]["role"], "system");
+
+        let anthropic = anthropic_body(&params, 512);
+        assert_eq!(anthropic["system"], "Be terse.");
+        assert_eq!(anthropic["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(anthropic["max_tokens"], 512);
+        assert_eq!(anthropic["top_k"], 40);
+
+        let response = anthropic_to_chat(&json!({
+            "id": "msg_1",
+            "model": "claude-sonnet",
+            "content": [{ "type": "text", "text": "Hello" }],
+            "stop_reason": "max_tokens",
+            "usage": { "input_tokens": 7, "output_tokens": 3 },
+        }));
+        assert_eq!(response["choices"][0]["message"]["content"], "Hello");
+        assert_eq!(response["choices"][0]["finish_reason"], "length");
+        assert_eq!(response["usage"]["total_tokens"], 10);
+    }
+
+    #[test]
+    fn anthropic_stream_events_become_chunks_with_usage() {
+        let mut decoder = SseDecoder::default();
+        let mut events = decoder.push(
+            b"event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"m\",\
+              \"model\":\"c\",\"usage\":{\"input_tokens\":5}}}\n\nevent: content_block_delta\nda",
+        );
+        events.extend(decoder.push(
+            b"ta: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\r\n\r\n\
+              data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\
+              \"usage\":{\"output_tokens\":2}}\n\n",
+        ));
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].event.as_deref(), Some("content_block_delta"));
+
+        let mut state = AnthropicStream::default();
+        let chunks: Vec<Value> = events
+            .iter()
+            .filter_map(|event| state.next(event))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Hi");
+        assert_eq!(chunks[0]["model"], "c");
+        assert_eq!(chunks[1]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[1]["usage"]["total_tokens"], 7);
+    }
+
+    #[test]
+    fn sse_lines_keep_characters_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+        let line = "data: {\"text\":\"grüße\"}\n\n".as_bytes();
+        let split = line.iter().position(|byte| *byte == 0xc3).unwrap() + 1;
+        assert!(decoder.push(&line[..split]).is_empty());
+        let events = decoder.push(&line[split..]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "{\"text\":\"grüße\"}");
+    }
+
+    #[test]
+    fn hosted_provider_key_errors_are_upstream_errors() {
+        let body = json!({ "error": { "message": "invalid x-api-key" } });
+        for status in [
+            HttpStatus::UNAUTHORIZED,
+            HttpStatus::FORBIDDEN,
+            HttpStatus::TOO_MANY_REQUESTS,
+        ] {
+            let err = upstream_error(status, &body);
+            assert_eq!(err.code, ErrorCode::LlmUpstream.code());
+            assert_eq!(err.data.unwrap()["status"], status.as_u16());
+        }
+        let err = upstream_error(HttpStatus::NOT_FOUND, &body);
+        assert_eq!(err.code, ErrorCode::LlmNotFound.code());
+        let err = status_error(HttpStatus::UNAUTHORIZED, &body);
+        assert_eq!(err.code, ErrorCode::Unauthorized.code());
+    }
+
+    #[tokio::test]
+    async fn local_server_round_trips_through_the_mock_server() {
+        use mock_llm::{Endpoint, MockLlmServer, Reply};
+
+        let server = MockLlmServer::start().await.unwrap();
+        server
+            .push(Endpoint::Chat, Reply::text("from the mock"))
+            .push(Endpoint::Completions, Reply::error(429, "out of tokens"));
+        let local = LocalServer {
+            http: Client::new(),
+            base_url: server.url(),
+            admin_token: RwLock::new(None),
+        };
+        let ctx = crate::llm_mock::request_context(42);
+
+        let response = local.chat(&ctx, chat_params("tiny")).await.unwrap();
+        assert_eq!(
+            response["choices"][0]["message"]["content"],
+            "from the mock"
+        );
+        let completion = serde_json::from_value(json!({ "model": "tiny", "prompt": "Hi" }));
+        let err = local
+            .completion(&ctx, completion.unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::LlmQuotaExhausted.code());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers["x-user-id"], "42");
+        assert_eq!(
+            requests[0].headers["x-request-id"],
+            ctx.request_id.to_string()
+        );
+        assert_eq!(requests[0].body["messages"][1]["content"], "Hi");
+    }
+
+    #[tokio::test]
+    async fn injected_faults_fail_before_reaching_the_provider() {
+        use mock_llm::MockLlmServer;
+        use sandbox::FaultConfig;
+
+        let server = MockLlmServer::start().await.unwrap();
+        let client = LlmClient::with_provider(Arc::new(LocalServer {
+            http: Client::new(),
+            base_url: server.url(),
+            admin_token: RwLock::new(None),
+        }))
+        .with_faults(FaultInjector::new(FaultConfig {
+            latency: Duration::ZERO,
+            latency_rate: 0.0,
+            error_rate: 1.0,
+        }));
+        let ctx = crate::llm_mock::request_context(42);
+
+        let err = client.chat(&ctx, chat_params("tiny")).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Internal.code());
+        assert_eq!(err.data.unwrap()["detail"], "injected fault in llm.chat");
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+//! Opt-in cache for `llm.embed` and deterministic `llm.completion` calls.
+//! Responses are keyed by the SHA-256 of the caller, the method and its
+//! params, kept in a bounded in-memory cache and mirrored to `llm_cache` so
+//! other api instances and restarts can reuse them. One user never sees
+//! another's cached results. Hits are returned without calling the llm
+//! server and billed like the call they replay.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hex::encode as hex_encode;
+use moka::future::Cache;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::types::Json;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::metrics::AppMetrics;
+use crate::RpcMethodError;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LlmCacheConfig {
+    enabled: bool,
+    persist: bool,
+    ttl: Duration,
+    max_memory_bytes: u64,
+    max_entry_bytes: usize,
+    max_rows: i64,
+}
+
+impl LlmCacheConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: config.get("LLM_CACHE_ENABLED", false),
+            persist: config.get("LLM_CACHE_PERSIST", true),
+            ttl: config
+                .secs("LLM_CACHE_TTL_SECS", 24 * 3600)
+                .max(Duration::from_secs(1)),
+            max_memory_bytes: config.get("LLM_CACHE_MAX_MEMORY_BYTES", 64 * 1024 * 1024),
+            max_entry_bytes: config.get("LLM_CACHE_MAX_ENTRY_BYTES", 1024 * 1024),
+            max_rows: config.get("LLM_CACHE_MAX_ROWS", 100_000).max(1),
+        }
+    }
+}
+
+/// Identifies one cacheable request.
+#[derive(Debug, Clone)]
+pub(crate) struct CacheKey {
+    digest: String,
+    method: String,
+    model: String,
+}
+
+#[derive(Clone)]
+struct Entry {
+    response: Arc<Value>,
+    bytes: u32,
+}
+
+#[derive(Clone)]
+pub(crate) struct LlmCache {
+    config: LlmCacheConfig,
+    memory: Cache<String, Entry>,
+    pool: PgPool,
+    metrics: Arc<AppMetrics>,
+}
+
+impl LlmCache {
+    pub(crate) fn new(pool: PgPool, config: LlmCacheConfig, metrics: Arc<AppMetrics>) -> Self {
+        Self {
+            config,
+            memory: Cache::<String, Entry>::builder()
+                .max_capacity(config.max_memory_bytes)
+                .weigher(|_, entry: &Entry| entry.bytes)
+                .time_to_live(config.ttl)
+                .build(),
+            pool,
+            metrics,
+        }
+    }
+
+    /// The cache key for `params` sent by `user_id`, or `None` when caching
+    /// is disabled. Object keys serialize in sorted order, so equal params
+    /// always hash to the same key.
+    pub(crate) fn key<P: Serialize>(
+        &self,
+        user_id: i32,
+        method: &str,
+        model: &str,
+        params: &P,
+    ) -> Option<CacheKey> {
+        if !self.config.enabled {
+            return None;
+        }
+        let bytes = serde_json::to_vec(&json!({
+            "user_id": user_id,
+            "method": method,
+            "params": params,
+        }))
+        .ok()?;
+        Some(CacheKey {
+            digest: hex_encode(Sha256::digest(bytes)),
+            method: method.to_string(),
+            model: model.to_string(),
+        })
+    }
+
+    /// Returns the cached response for `key`, or calls `load` and caches a
+    /// successful result. The flag reports whether the response came from
+    /// the cache.
+    pub(crate) async fn get_or_load<F, Fut>(
+        &self,
+        key: Option<CacheKey>,
+        load: F,
+    ) -> Result<(Value, bool), RpcMethodError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Value, RpcMethodError>>,
+    {
+        let Some(key) = key else {
+            return load().await.map(|response| (response, false));
+        };
+        if let Some(response) = self.lookup(&key).await {
+            self.metrics.llm_cache.hit();
+            return Ok((response, true));
+        }
+        self.metrics.llm_cache.miss();
+        let response = load().await?;
+        self.store(key, &response).await;
+        Ok((response, false))
+    }
+
+    async fn lookup(&self, key: &CacheKey) -> Option<Value> {
+        if let Some(entry) = self.memory.get(&key.digest).await {
+            return Some(entry.response.as_ref().clone());
+        }
+        if !self.config.persist {
+            return None;
+        }
+        let row: Option<(Json<Value>, i32)> = match sqlx::query_as(
+            "SELECT response, size_bytes FROM llm_cache WHERE key = $1 AND expires_at > NOW()",
+        )
+        .bind(&key.digest)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(row) => row,
+            Err(err) => {
+                warn!(error = %err, "failed to read llm cache");
+                return None;
+            }
+        };
+        let (Json(response), bytes) = row?;
+        self.memory
+            .insert(
+                key.digest.clone(),
+                Entry {
+                    response: Arc::new(response.clone()),
+                    bytes: bytes.max(0) as u32,
+                },
+            )
+            .await;
+        Some(response)
+    }
+
+    async fn store(&self, key: CacheKey, response: &Value) {
+        let Some(bytes) = serde_json::to_vec(response)
+            .ok()
+            .map(|encoded| encoded.len())
+            .filter(|len| *len <= self.config.max_entry_bytes)
+        else {
+            return;
+        };
+        let bytes = u32::try_from(bytes).unwrap_or(u32::MAX);
+        self.memory
+            .insert(
+                key.digest.clone(),
+                Entry {
+                    response: Arc::new(response.clone()),
+                    bytes,
+                },
+            )
+            .await;
+        if !self.config.persist {
+            return;
+        }
+        if let Err(err) = sqlx::query(
+            "INSERT INTO llm_cache (key, method, model, response, size_bytes, expires_at) \
+             VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6)) \
+             ON CONFLICT (key) DO UPDATE SET response = EXCLUDED.response, \
+                size_bytes = EXCLUDED.size_bytes, created_at = NOW(), \
+                expires_at = EXCLUDED.expires_at",
+        )
+        .bind(&key.digest)
+        .bind(&key.method)
+        .bind(&key.model)
+        .bind(Json(response))
+        .bind(bytes as i32)
+        .bind(self.config.ttl.as_secs_f64())
+        .execute(&self.pool)
+        .await
+        {
+            warn!(error = %err, "failed to write llm cache");
+        }
+    }
+
+    /// Periodically drops expired rows and the oldest rows beyond
+    /// `LLM_CACHE_MAX_ROWS`. Does nothing unless the persistent tier is on.
+    pub(crate) fn spawn_pruner(&self) -> Option<JoinHandle<()>> {
+        if !(self.config.enabled && self.config.persist) {
+            return None;
+        }
+        let pool = self.pool.clone();
+        let max_rows = self.config.max_rows;
+        Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(err) = sqlx::query(
+                    "DELETE FROM llm_cache WHERE expires_at <= NOW() OR key IN ( \
+                        SELECT key FROM llm_cache ORDER BY created_at DESC OFFSET $1 \
+                     )",
+                )
+                .bind(max_rows)
+                .execute(&pool)
+                .await
+                {
+                    warn!(error = %err, "failed to prune llm cache");
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(enabled: bool) -> LlmCache {
+        let config = LlmCacheConfig {
+            enabled,
+            persist: false,
+            ttl: Duration::from_secs(60),
+            max_memory_bytes: 1024 * 1024,
+            max_entry_bytes: 64,
+            max_rows: 10,
+        };
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        LlmCache::new(pool, config, Arc::new(AppMetrics::default()))
+    }
+
+    #[tokio::test]
+    async fn repeated_requests_are_served_from_memory() {
+        let cache = cache(true);
+        let calls = AtomicUsize::new(0);
+        let load = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(json!({ "data": [[0.5, 0.25]] }))
+        };
+        let params = json!({ "model": "m", "input": "hello" });
+
+        let key = cache.key(7, "llm.embed", "m", &params);
+        let (_, cached) = cache.get_or_load(key.clone(), load).await.unwrap();
+        assert!(!cached);
+        let (response, cached) = cache.get_or_load(key, load).await.unwrap();
+        assert!(cached);
+        assert_eq!(response["data"][0][1], 0.25);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let stranger = cache.key(8, "llm.embed", "m", &params);
+        let (_, cached) = cache.get_or_load(stranger, load).await.unwrap();
+        assert!(!cached, "other users do not share cached results");
+
+        let bye = json!({ "model": "m", "input": "bye" });
+        let other = cache.key(7, "llm.embed", "m", &bye);
+        let (_, cached) = cache.get_or_load(other, load).await.unwrap();
+        assert!(!cached);
+
+        let large = cache.key(7, "llm.embed", "m", &json!({ "input": "large" }));
+        let big = || async { Ok(json!({ "data": "x".repeat(128) })) };
+        cache.get_or_load(large.clone(), big).await.unwrap();
+        let (_, cached) = cache.get_or_load(large, big).await.unwrap();
+        assert!(!cached, "entries above max_entry_bytes are not cached");
+    }
+
+    #[tokio::test]
+    async fn disabled_cache_has_no_keys() {
+        assert!(cache(false).key(7, "llm.embed", "m", &json!({})).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+//! `MockLlm`, a scripted [`LlmProvider`] for handler tests. Replies are
+//! queued per endpoint and shaped by [`mock_llm::respond`], so the bodies
+//! match what the `mock-llm` server sends over HTTP; error replies become
+//! the same RPC errors a real provider's status would. Tests that need the
+//! HTTP path itself point a [`LocalServer`](crate::llm) at a
+//! [`mock_llm::MockLlmServer`] instead.
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use mock_llm::{respond, stream_chunks, Endpoint, Reply, Script};
+use parking_lot::Mutex;
+use reqwest::StatusCode as HttpStatus;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::llm::{status_error, ChatStream, LlmProvider, ProviderInfo};
+use crate::{
+    LlmChatParams, LlmCompletionParams, LlmEmbedParams, RequestContext, Role, RpcMethodError,
+};
+
+#[derive(Default)]
+pub(crate) struct MockLlm {
+    script: Mutex<Script>,
+    calls: Mutex<Vec<(Endpoint, Value)>>,
+}
+
+impl MockLlm {
+    /// Queues `reply` as the next answer of `endpoint`; unscripted calls get
+    /// the canned default reply.
+    pub(crate) fn push(&self, endpoint: Endpoint, reply: Reply) -> &Self {
+        self.script.lock().push(endpoint, reply);
+        self
+    }
+
+    /// Request bodies received so far, oldest first.
+    pub(crate) fn calls(&self) -> Vec<(Endpoint, Value)> {
+        self.calls.lock().clone()
+    }
+
+    async fn answer(
+        &self,
+        endpoint: Endpoint,
+        params: &impl Serialize,
+    ) -> Result<Value, RpcMethodError> {
+        let request = serde_json::to_value(params)
+            .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
+        self.calls.lock().push((endpoint, request.clone()));
+        let reply = self.script.lock().next(endpoint);
+        if let Some(reply) = &reply {
+            tokio::time::sleep(reply.delay()).await;
+        }
+        let (status, body) = respond(endpoint, &request, reply.as_ref());
+        let status = HttpStatus::from_u16(status).unwrap_or(HttpStatus::INTERNAL_SERVER_ERROR);
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(status_error(status, &body))
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for MockLlm {
+    fn info(&self) -> ProviderInfo {
+        ProviderInfo {
+            name: "mock",
+            settles_usage: false,
+        }
+    }
+
+    async fn chat(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.answer(Endpoint::Chat, &params).await
+    }
+
+    async fn completion(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmCompletionParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.answer(Endpoint::Completions, &params).await
+    }
+
+    async fn embed(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmEmbedParams,
+    ) -> Result<Value, RpcMethodError> {
+        self.answer(Endpoint::Embeddings, &params).await
+    }
+
+    /// One chunk per word, the last carrying `usage`, as the mock server
+    /// streams it.
+    async fn chat_stream(
+        &self,
+        _ctx: &RequestContext,
+        params: LlmChatParams,
+    ) -> Result<ChatStream, RpcMethodError> {
+        let response = self.answer(Endpoint::Chat, &params).await?;
+        Ok(stream::iter(stream_chunks(&response).into_iter().map(Ok)).boxed())
+    }
+}
+
+/// A developer's request context for provider tests.
+pub(crate) fn request_context(user_id: i32) -> RequestContext {
+    RequestContext {
+        user_id,
+        username: "dev".to_string(),
+        role: Role::Developer,
+        tenant_id: crate::tenant::DEFAULT_TENANT,
+        permissions: Default::default(),
+        token_balance: 0,
+        api_key_id: None,
+        client_ip: None,
+        request_id: uuid::Uuid::new_v4(),
+        trace_parent: opentelemetry::Context::new(),
+        credentials: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::TryStreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::errors::ErrorCode;
+    use crate::llm::LlmClient;
+
+    fn chat(content: &str) -> LlmChatParams {
+        serde_json::from_value(json!({
+            "model": "mock-1",
+            "messages": [{ "role": "user", "content": content }],
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_answers_from_the_script() {
+        let mock = Arc::new(MockLlm::default());
+        mock.push(Endpoint::Chat, Reply::text("scripted answer"));
+        let client = LlmClient::with_provider(mock.clone());
+        let ctx = request_context(1);
+
+        let response = client.chat(&ctx, chat("hello")).await.unwrap();
+        assert_eq!(
+            response["choices"][0]["message"]["content"],
+            "scripted answer"
+        );
+        assert!(response["usage"]["total_tokens"].as_u64().unwrap() > 0);
+
+        let response = client.chat(&ctx, chat("hello there")).await.unwrap();
+        assert_eq!(
+            response["choices"][0]["message"]["content"],
+            "mock reply to: hello there"
+        );
+
+        let embed = serde_json::from_value(json!({ "model": "mock-1", "input": ["a", "b"] }));
+        let response = client.embed(&ctx, embed.unwrap()).await.unwrap();
+        assert_eq!(response["data"].as_array().unwrap().len(), 2);
+
+        let calls = mock.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].0, Endpoint::Chat);
+        assert_eq!(calls[0].1["messages"][0]["content"], "hello");
+        assert_eq!(calls[2].0, Endpoint::Embeddings);
+    }
+
+    #[tokio::test]
+    async fn error_replies_map_like_provider_statuses() {
+        let mock = Arc::new(MockLlm::default());
+        mock.push(Endpoint::Chat, Reply::error(429, "quota"))
+            .push(Endpoint::Chat, Reply::error(404, "no such model"))
+            .push(Endpoint::Chat, Reply::error(503, "overloaded"));
+        let client = LlmClient::with_provider(mock);
+        let ctx = request_context(1);
+
+        let err = client.chat(&ctx, chat("a")).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::LlmQuotaExhausted.code());
+        let err = client.chat(&ctx, chat("a")).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::LlmNotFound.code());
+        assert_eq!(err.message, "no such model");
+        let err = client.chat(&ctx, chat("a")).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Internal.code());
+    }
+
+    #[tokio::test]
+    async fn streams_and_honours_delays() {
+        let mock = Arc::new(MockLlm::default());
+        mock.push(
+            Endpoint::Chat,
+            Reply::text("two words").after(Duration::from_millis(20)),
+        );
+        let client = LlmClient::with_provider(mock);
+        let ctx = request_context(1);
+
+        let started = std::time::Instant::now();
+        let stream = client.chat_stream(&ctx, chat("go")).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        let chunks: Vec<Value> = stream.try_collect().await.unwrap();
+        let text: String = chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(text, "two words");
+        assert!(chunks.last().unwrap()["usage"].is_object());
+    }
+}
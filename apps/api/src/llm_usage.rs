@@ -0,0 +1,283 @@
+//! Per-call llm usage. Every inference call (`llm.chat`, `llm.completion`,
+//! `llm.embed` and the streamed chat) appends a row to `llm_usage` with the
+//! model, the provider that served it, token counts, latency and outcome.
+//! Cache hits and failures are kept with zero tokens. `llm.usage` aggregates
+//! the rows for one user, or across all users for admins.
+
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Row};
+use tracing::warn;
+
+use crate::billing::target_user;
+use crate::errors::ErrorCode;
+use crate::llm::ProviderInfo;
+use crate::{RequestContext, RpcMethodError};
+
+/// How a recorded call ended.
+pub(crate) enum Outcome<'a> {
+    /// Served by the provider; carries the response with its `usage` block.
+    Served(&'a Value),
+    Cached,
+    Failed(&'a RpcMethodError),
+}
+
+/// A call in flight. Created before the provider is called so the recorded
+/// latency covers the whole round trip.
+pub(crate) struct UsageEntry {
+    method: String,
+    model: String,
+    provider: ProviderInfo,
+    started: Instant,
+}
+
+impl UsageEntry {
+    pub(crate) fn start(method: &str, model: &str, provider: ProviderInfo) -> Self {
+        Self {
+            method: method.to_string(),
+            model: model.to_string(),
+            provider,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn provider(&self) -> ProviderInfo {
+        self.provider
+    }
+
+    /// Stores the entry in the background; failures are logged, never
+    /// surfaced to the caller.
+    pub(crate) fn finish(self, pool: &PgPool, ctx: &RequestContext, outcome: Outcome<'_>) {
+        let (status, error_code, counts) = match outcome {
+            Outcome::Served(response) => ("ok", None, token_counts(response)),
+            Outcome::Cached => ("cached", None, TokenCounts::default()),
+            Outcome::Failed(err) => ("error", Some(err.code as i32), TokenCounts::default()),
+        };
+        let latency_ms = self.started.elapsed().as_millis().min(i64::MAX as u128) as i64;
+        let pool = pool.clone();
+        let user_id = ctx.user_id;
+        let api_key_id = ctx.api_key_id;
+        tokio::spawn(async move {
+            let result = sqlx::query(
+                "INSERT INTO llm_usage (user_id, api_key_id, method, model, provider, \
+                    prompt_tokens, completion_tokens, total_tokens, latency_ms, status, error_code) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            )
+            .bind(user_id)
+            .bind(api_key_id)
+            .bind(&self.method)
+            .bind(&self.model)
+            .bind(self.provider.name)
+            .bind(counts.prompt)
+            .bind(counts.completion)
+            .bind(counts.total)
+            .bind(latency_ms)
+            .bind(status)
+            .bind(error_code)
+            .execute(&pool)
+            .await;
+            if let Err(err) = result {
+                warn!(user_id, method = %self.method, error = %err, "failed to record llm usage");
+            }
+        });
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct TokenCounts {
+    prompt: i64,
+    completion: i64,
+    total: i64,
+}
+
+/// Reads the OpenAI-shaped `usage` block every provider's response is
+/// mapped to. Embeddings only report prompt tokens.
+fn token_counts(response: &Value) -> TokenCounts {
+    let usage = response.get("usage");
+    let field = |name: &str| {
+        usage
+            .and_then(|usage| usage.get(name))
+            .and_then(Value::as_i64)
+            .unwrap_or(0)
+            .max(0)
+    };
+    let prompt = field("prompt_tokens");
+    let completion = field("completion_tokens");
+    let total = match field("total_tokens") {
+        0 => prompt + completion,
+        total => total,
+    };
+    TokenCounts {
+        prompt,
+        completion,
+        total,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UsageGroup {
+    #[default]
+    Model,
+    Provider,
+    Method,
+    Day,
+    ApiKey,
+    User,
+}
+
+impl UsageGroup {
+    fn name(self) -> &'static str {
+        match self {
+            UsageGroup::Model => "model",
+            UsageGroup::Provider => "provider",
+            UsageGroup::Method => "method",
+            UsageGroup::Day => "day",
+            UsageGroup::ApiKey => "api_key",
+            UsageGroup::User => "user",
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            UsageGroup::Model => "model",
+            UsageGroup::Provider => "provider",
+            UsageGroup::Method => "method",
+            UsageGroup::Day => "to_char(date_trunc('day', created_at), 'YYYY-MM-DD')",
+            UsageGroup::ApiKey => "COALESCE(api_key_id::text, 'session')",
+            UsageGroup::User => "user_id::text",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct LlmUsageParams {
+    #[serde(default)]
+    user_id: Option<i32>,
+    /// Aggregate across every user; admin only.
+    #[serde(default)]
+    all_users: bool,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    group_by: UsageGroup,
+}
+
+pub(crate) async fn usage(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: LlmUsageParams,
+) -> Result<Value, RpcMethodError> {
+    let user_id = if params.all_users {
+        if !ctx.is_admin() {
+            return Err(RpcMethodError::forbidden("insufficient permissions"));
+        }
+        if params.user_id.is_some() {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "user_id and all_users are mutually exclusive",
+                None,
+            ));
+        }
+        None
+    } else {
+        Some(target_user(pool, ctx, params.user_id).await?)
+    };
+    let rows = sqlx::query(&format!(
+        "SELECT {column} AS key, COUNT(*) AS calls, \
+            COUNT(*) FILTER (WHERE status = 'error') AS errors, \
+            COUNT(*) FILTER (WHERE status = 'cached') AS cached, \
+            SUM(prompt_tokens)::BIGINT AS prompt_tokens, \
+            SUM(completion_tokens)::BIGINT AS completion_tokens, \
+            SUM(total_tokens)::BIGINT AS total_tokens, \
+            COALESCE(AVG(latency_ms) FILTER (WHERE status <> 'cached'), 0)::BIGINT AS avg_latency_ms \
+         FROM llm_usage \
+         WHERE ($1::int IS NULL OR user_id = $1) \
+           AND user_id IN (SELECT id FROM users WHERE tenant_id = $4) \
+           AND ($2::timestamptz IS NULL OR created_at >= $2) \
+           AND ($3::timestamptz IS NULL OR created_at < $3) \
+         GROUP BY 1 ORDER BY total_tokens DESC, key",
+        column = params.group_by.column(),
+    ))
+    .bind(user_id)
+    .bind(params.since)
+    .bind(params.until)
+    .bind(ctx.tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load llm usage: {err}")))?;
+
+    let mut totals = [0i64; 6];
+    let groups: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            let counts = [
+                row.get::<i64, _>("calls"),
+                row.get::<i64, _>("errors"),
+                row.get::<i64, _>("cached"),
+                row.get::<i64, _>("prompt_tokens"),
+                row.get::<i64, _>("completion_tokens"),
+                row.get::<i64, _>("total_tokens"),
+            ];
+            for (total, count) in totals.iter_mut().zip(counts) {
+                *total += count;
+            }
+            json!({
+                "key": row.get::<String, _>("key"),
+                "calls": counts[0],
+                "errors": counts[1],
+                "cached": counts[2],
+                "prompt_tokens": counts[3],
+                "completion_tokens": counts[4],
+                "total_tokens": counts[5],
+                "avg_latency_ms": row.get::<i64, _>("avg_latency_ms"),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "user_id": user_id,
+        "group_by": params.group_by.name(),
+        "totals": {
+            "calls": totals[0],
+            "errors": totals[1],
+            "cached": totals[2],
+            "prompt_tokens": totals[3],
+            "completion_tokens": totals[4],
+            "total_tokens": totals[5],
+        },
+        "groups": groups,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_counts_fill_in_missing_totals() {
+        let chat = json!({ "usage": { "prompt_tokens": 5, "completion_tokens": 8 } });
+        assert_eq!(
+            token_counts(&chat),
+            TokenCounts {
+                prompt: 5,
+                completion: 8,
+                total: 13
+            }
+        );
+        let embed = json!({ "usage": { "prompt_tokens": 4, "total_tokens": 4 } });
+        assert_eq!(token_counts(&embed).total, 4);
+        assert_eq!(token_counts(&Value::Null), TokenCounts::default());
+
+        let params: LlmUsageParams =
+            serde_json::from_value(json!({ "group_by": "api_key" })).unwrap();
+        assert_eq!(params.group_by.name(), "api_key");
+        let params: LlmUsageParams = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(params.group_by.name(), "model");
+    }
+}
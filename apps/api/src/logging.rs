@@ -0,0 +1,396 @@
+//! JSON log lines that say which call they belong to. Every event inside an
+//! `rpc` span carries the call's `request_id`, `user_id`, `method` and, once
+//! the params name one, `project_id` as top-level fields, next to the usual
+//! `fields` and `spans`, so a call's lines can be filtered without walking
+//! the span list.
+//!
+//! `LOG_SAMPLE_RATES` (`fs.read=0.01,project.search=0.1`) keeps only that
+//! share of the calls to high-volume methods. The decision is made once per
+//! call, so a call's lines are kept or dropped together; warnings and errors
+//! are always logged.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::{Context, Filter, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::Config;
+
+/// The span [`crate::telemetry::rpc_span`] opens around every call.
+const RPC_SPAN: &str = "rpc";
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LoggingConfig {
+    /// Share of calls logged per method, in `[0, 1]`.
+    sample_rates: HashMap<String, f64>,
+}
+
+impl LoggingConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut sample_rates = HashMap::new();
+        for (method, rate) in config.pairs("LOG_SAMPLE_RATES") {
+            match rate.parse::<f64>() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => {
+                    sample_rates.insert(method, rate);
+                }
+                _ => config.invalid(
+                    "LOG_SAMPLE_RATES",
+                    format!("rate `{rate}` of {method} is not between 0 and 1"),
+                ),
+            }
+        }
+        Self { sample_rates }
+    }
+}
+
+/// The JSON fmt layer with call context and sampling.
+pub(crate) fn layer<S>(config: &LoggingConfig) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let context = RpcContext {
+        sample_rates: Arc::new(config.sample_rates.clone()),
+    };
+    let output = tracing_subscriber::fmt::layer()
+        .fmt_fields(JsonFields::new())
+        .event_format(JsonLines)
+        .with_filter(Sampled);
+    context.and_then(output)
+}
+
+/// What the `rpc` span knows about its call, kept in the span's extensions.
+#[derive(Debug, Clone)]
+struct RpcFields {
+    request_id: Option<String>,
+    user_id: Option<i64>,
+    method: Option<String>,
+    project_id: Option<String>,
+    trace_id: Option<String>,
+    sampled: bool,
+}
+
+impl RpcFields {
+    fn write_to(&self, line: &mut Map<String, Value>) {
+        let fields = [
+            ("request_id", self.request_id.as_ref().map(|id| json!(id))),
+            ("user_id", self.user_id.map(|id| json!(id))),
+            ("method", self.method.as_ref().map(|method| json!(method))),
+            ("project_id", self.project_id.as_ref().map(|id| json!(id))),
+            ("trace_id", self.trace_id.as_ref().map(|id| json!(id))),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                line.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for RpcFields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == "user_id" {
+            self.user_id = Some(value);
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_i64(field, value as i64);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let slot = match field.name() {
+            "request_id" => &mut self.request_id,
+            "rpc.method" => &mut self.method,
+            "project_id" => &mut self.project_id,
+            "trace_id" => &mut self.trace_id,
+            _ => return,
+        };
+        *slot = Some(value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // `%value` fields arrive here, formatted with `Display`.
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// Records the fields of `rpc` spans and decides whether the call is logged.
+struct RpcContext {
+    sample_rates: Arc<HashMap<String, f64>>,
+}
+
+impl RpcContext {
+    fn sampled(&self, method: Option<&str>) -> bool {
+        match method.and_then(|method| self.sample_rates.get(method)) {
+            Some(rate) => rand::random::<f64>() < *rate,
+            None => true,
+        }
+    }
+}
+
+impl<S> Layer<S> for RpcContext
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != RPC_SPAN {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = RpcFields {
+            request_id: None,
+            user_id: None,
+            method: None,
+            project_id: None,
+            trace_id: None,
+            sampled: true,
+        };
+        attrs.record(&mut fields);
+        fields.sampled = self.sampled(fields.method.as_deref());
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<RpcFields>() {
+            values.record(fields);
+        }
+    }
+}
+
+/// Drops info and below from calls that were sampled out.
+struct Sampled;
+
+impl<S> Filter<S> for Sampled
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, _: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        true
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &Context<'_, S>) -> bool {
+        if *event.metadata().level() <= Level::WARN {
+            return true;
+        }
+        cx.event_scope(event)
+            .and_then(|scope| {
+                scope
+                    .from_root()
+                    .find_map(|span| span.extensions().get::<RpcFields>().map(|f| f.sampled))
+            })
+            .unwrap_or(true)
+    }
+}
+
+/// One JSON object per line: `timestamp`, `level`, `target`, the event's
+/// `fields`, the call context and the enclosing `span`/`spans` with their
+/// fields.
+struct JsonLines;
+
+impl<S, N> FormatEvent<S, N> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".into(),
+            json!(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)),
+        );
+        line.insert("level".into(), json!(meta.level().as_str()));
+        line.insert("target".into(), json!(meta.target()));
+        let mut fields = JsonVisitor(Map::new());
+        event.record(&mut fields);
+        line.insert("fields".into(), Value::Object(fields.0));
+
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(rpc) = extensions.get::<RpcFields>() {
+                    rpc.write_to(&mut line);
+                }
+                let mut entry = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str::<Map<String, Value>>(fields).ok())
+                    .unwrap_or_default();
+                entry.insert("name".into(), json!(span.name()));
+                spans.push(Value::Object(entry));
+            }
+        }
+        if let Some(current) = spans.last() {
+            line.insert("span".into(), current.clone());
+            line.insert("spans".into(), Value::Array(spans));
+        }
+        writer.write_str(&Value::Object(line).to_string())?;
+        writer.write_char('\n')
+    }
+}
+
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), json!(value));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.0.insert(field.name().into(), json!(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), json!(format!("{value:?}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Mutex;
+
+    use tracing::{info, info_span, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(sample_rates: &[(&str, f64)], emit: impl FnOnce()) -> Vec<Value> {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let context = RpcContext {
+            sample_rates: Arc::new(
+                sample_rates
+                    .iter()
+                    .map(|(method, rate)| (method.to_string(), *rate))
+                    .collect(),
+            ),
+        };
+        let output = tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonLines)
+            .with_writer(move || writer.clone())
+            .with_filter(Sampled);
+        let subscriber = tracing_subscriber::registry().with(context.and_then(output));
+        tracing::subscriber::with_default(subscriber, emit);
+        let bytes = buffer.0.lock().unwrap().clone();
+        String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn events_carry_the_call_context() {
+        let lines = capture(&[], || {
+            info!("outside");
+            let span = info_span!(
+                "rpc",
+                rpc.method = %"fs.read",
+                request_id = %"c0ffee",
+                user_id = 7,
+                project_id = tracing::field::Empty,
+                trace_id = tracing::field::Empty,
+            );
+            span.record("project_id", "p-1");
+            let _entered = span.enter();
+            let inner = info_span!("sandbox", path = "src/lib.rs");
+            let _inner = inner.enter();
+            info!(bytes = 12, "read file");
+        });
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].get("request_id").is_none());
+        let line = &lines[1];
+        assert_eq!(line["request_id"], "c0ffee");
+        assert_eq!(line["user_id"], 7);
+        assert_eq!(line["method"], "fs.read");
+        assert_eq!(line["project_id"], "p-1");
+        assert_eq!(line["fields"]["message"], "read file");
+        assert_eq!(line["fields"]["bytes"], 12);
+        assert_eq!(line["span"]["name"], "sandbox");
+        assert_eq!(line["span"]["path"], "src/lib.rs");
+        assert_eq!(line["spans"][0]["name"], "rpc");
+    }
+
+    #[test]
+    fn sampled_out_calls_only_log_warnings() {
+        let lines = capture(&[("fs.read", 0.0)], || {
+            for method in ["fs.read", "fs.write"] {
+                let span = info_span!("rpc", rpc.method = %method, user_id = 1);
+                let _entered = span.enter();
+                info!("working");
+                warn!("slow");
+            }
+        });
+
+        let seen: Vec<(String, String)> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["method"].as_str().unwrap().to_string(),
+                    line["level"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        let expected = [
+            ("fs.read", "WARN"),
+            ("fs.write", "INFO"),
+            ("fs.write", "WARN"),
+        ]
+        .map(|(method, level)| (method.to_string(), level.to_string()));
+        assert_eq!(seen, expected);
+    }
+}
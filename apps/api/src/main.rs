@@ -1,92 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use auth_core::{
+    hash_api_key, Claims, DecodingKeys, KeyId, KeyScope, Permission, Role, TokenVerifier,
+};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
 use axum::{Json, Router};
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
 use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use globset::{GlobBuilder, GlobMatcher};
 use hex::encode as hex_encode;
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
-use reqwest::{header::AUTHORIZATION, Client, Method, StatusCode as HttpStatus};
-use sandbox::micro::{
-    MicroConfig, MicroExecuteRequest, MicroImage, MicroStartRequest, SandboxMicro,
-};
+use parking_lot::RwLock;
+use runner::protocol::Call;
+use sandbox::micro::{MicroConfig, MicroImage, MicroRepl, SandboxMicro};
 use sandbox::run::{RunConfig, RunRequest, SandboxRun};
 use sandbox::{
-    AgentContext, AgentContextFile, AgentDispatchRequest, AgentDispatcher, AgentDispatcherConfig,
-    AgentFileContent, AgentKind, AgentParameters, SandboxConfig, SandboxError, SandboxFs,
-    SandboxWasm, WasmConfig, WasmInvocation, WasmModuleSource, WasmValue,
+    AgentAction, AgentActionExecutor, AgentActionStatus, AgentApplyReport, AgentContext,
+    AgentContextFile, AgentDispatchRequest, AgentDispatcher, AgentDispatcherConfig,
+    AgentFileContent, AgentHistoryQuery, AgentKind, AgentParameters, AgentPersona, AgentSubtask,
+    AgentTaskSnapshot, AgentTaskStatus, SandboxConfig, SandboxError, SandboxFs, SandboxGit,
+    SandboxWasm, WalkOptions, WasmConfig, WasmModuleSource, WasmValue,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::types::Json;
 use sqlx::{Error as SqlxError, PgPool, Row};
+use tokio::sync::watch;
 use tower::ServiceBuilder;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{dispatcher, error, info, warn};
+use tracing::{error, info, warn, Instrument, Span};
 use uuid::Uuid;
 
+use crate::admin::{
+    AdminDisableParams, AdminSetRoleParams, AdminSetTokenBalanceParams, AdminUsersListParams,
+};
+use crate::audit::{AuditEvent, AuditQueryParams};
+use crate::billing::{BillingLedgerParams, BillingUsageParams, Charge};
+use crate::error_log::ErrorsRecentParams;
+use crate::errors::ErrorCode;
+use crate::events::DomainEvent;
+use crate::flags::{FlagClearParams, FlagSetParams};
+use crate::fs_batch::FsBatchParams;
+use crate::git::{GitCommitParams, GitDiffParams, GitLogParams};
+use crate::jobs::{JobIdParams, JobListParams};
+use crate::llm_cache::CacheKey;
+use crate::llm_usage::{LlmUsageParams, Outcome, UsageEntry};
+use crate::notify::{NotifyListParams, NotifyMarkReadParams};
+use crate::pipeline::AgentPipelineParams;
+use crate::quota::QuotaStatusParams;
+use crate::rate_limit::{RateLimitClearParams, RateLimitSetParams};
+use crate::rbac::{GrantAddParams, GrantIdParams, GrantListParams, RoleNameParams, RoleSetParams};
+use crate::revocation::TokenRevokeParams;
+use crate::scheduler::{ScheduleNameParams, ScheduleUpdateParams};
+use crate::snapshot::{SnapshotCreateParams, SnapshotListParams, SnapshotRestoreParams};
+use crate::transfer::ProjectImportParams;
+use crate::webhooks::{WebhookCreateParams, WebhookIdParams};
+use crate::workspace::{WorkspaceCreateParams, WorkspaceIdParams};
+
+mod admin;
+mod admission;
+mod affinity;
+mod agent_events;
+mod audit;
+mod auth_cache;
+mod billing;
+mod budget;
+mod cache;
+mod config;
+mod cron;
+mod deadline;
+mod doctor;
+mod engine;
+mod error_log;
+mod errors;
+mod events;
+mod faults;
+mod flags;
+mod fs_batch;
+mod gc;
+mod git;
+mod grpc;
+mod health;
+mod jobs;
+mod jwks;
+mod llm;
+mod llm_cache;
+#[cfg(test)]
+mod llm_mock;
+mod llm_usage;
+mod logging;
+mod metrics;
+mod notify;
+mod openrpc;
+mod pipeline;
+mod quota;
+mod rate_limit;
+mod rbac;
+mod reconcile;
+mod reload;
+mod rest;
+mod retention;
+mod revocation;
+mod rotation;
+mod runners;
+mod scheduler;
+mod snapshot;
+mod telemetry;
+mod tenant;
+mod transfer;
+mod versioning;
+mod webhooks;
+mod workspace;
+
 #[derive(Clone)]
 struct AppState {
     sandbox: Arc<SandboxFs>,
     run: Arc<SandboxRun>,
     wasm: Arc<SandboxWasm>,
     micro: Arc<SandboxMicro>,
+    /// Repositories in project directories, for `project.git.*`.
+    git: Arc<SandboxGit>,
+    /// Serves `run.*`, `wasm.*`, `micro.*` and any other registered engine.
+    engines: engine::Engines,
+    /// Remote runners that take run, micro and agent calls off the gateway.
+    runners: runners::Runners,
     agents: Arc<AgentDispatcher>,
     pool: PgPool,
     auth: JwtVerifier,
-    llm: LlmClient,
+    /// Users and API keys behind recently seen credentials.
+    auth_cache: auth_cache::AuthCache,
+    /// Rejects human users without `email_verified_at` (migration 022).
+    require_verified_email: bool,
+    llm: llm::LlmClient,
+    rpc_batch_limit: usize,
+    /// Batch entries executed at once within a read-only run.
+    rpc_batch_concurrency: usize,
+    fs_batch_limit: usize,
+    /// Entries one `fs.walk` returns at most.
+    fs_walk_limit: usize,
+    deadlines: deadline::DeadlineConfig,
+    response_budget: budget::ResponseBudget,
+    readiness: Arc<health::Readiness>,
+    billing: billing::Billing,
+    audit: audit::AuditLog,
+    /// Failed calls for `errors.recent`.
+    errors: error_log::ErrorLog,
+    project_version_limit: i64,
+    /// Snapshots kept per project.
+    project_snapshot_limit: i64,
+    upload_limit: usize,
+    workspaces: workspace::WorkspaceConfig,
+    quotas: quota::Quotas,
+    metrics: Arc<metrics::AppMetrics>,
+    project_cache: cache::ProjectCache,
+    llm_cache: llm_cache::LlmCache,
+    versions: versioning::VersionConfig,
+    /// Domain events for webhooks, notifications and sockets.
+    events: events::EventBus,
+    webhooks: webhooks::Webhooks,
+    rbac: rbac::Rbac,
+    /// Feature flags consulted before methods and REST routes run.
+    flags: flags::Flags,
+    revocations: revocation::Revocations,
+    notifier: notify::Notifier,
+    jobs: jobs::Jobs,
+    reloader: Arc<reload::Reloader>,
+    /// Which replica owns each micro VM and agent task.
+    affinity: affinity::Affinity,
+    /// Concurrency limits per class of method.
+    admission: admission::Admission,
+    /// Token buckets per caller, taken from before admission.
+    rate_limits: rate_limit::RateLimits,
 }
 
+/// Accepts HS256 tokens signed with the shared secret and RS256 tokens
+/// signed with a key the auth service publishes (`API_JWT_JWKS_URL`); at
+/// least one of the two has to be configured.
 #[derive(Clone)]
 struct JwtVerifier {
-    decoding: DecodingKey,
-    validation: Validation,
+    /// The HS256 secret. After it rotates the previous one is still
+    /// accepted, so tokens issued before stay valid.
+    secrets: Arc<RwLock<DecodingKeys>>,
+    /// The setting the secret came from.
+    secret_setting: Option<&'static str>,
+    jwks: Option<jwks::Jwks>,
+    tokens: TokenVerifier,
 }
 
 impl JwtVerifier {
-    fn from_env() -> anyhow::Result<Self> {
-        let secret = std::env::var("API_JWT_SECRET")
-            .or_else(|_| std::env::var("AUTH_JWT_SECRET"))
-            .map_err(|_| anyhow::anyhow!("API_JWT_SECRET environment variable is required"))?;
-        let issuer =
-            std::env::var("API_JWT_ISSUER").unwrap_or_else(|_| "cyber-dev-studio".to_string());
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation
-            .set_required_spec_claims(&["exp", "iat", "sub", "iss"])
-            .expect("required claim configuration");
-        validation.iss = Some(issuer);
-        Ok(Self {
-            decoding: DecodingKey::from_secret(secret.as_bytes()),
-            validation,
-        })
+    /// `AUTH_JWT_SECRET` is accepted in place of `API_JWT_SECRET` so both
+    /// services can share one setting.
+    fn from_config(config: &config::Config) -> Self {
+        let api_secret = config.secret("API_JWT_SECRET");
+        let auth_secret = config.secret("AUTH_JWT_SECRET");
+        let (secret_setting, secret) = match (api_secret, auth_secret) {
+            (Some(secret), _) => (Some("API_JWT_SECRET"), Some(secret)),
+            (None, Some(secret)) => (Some("AUTH_JWT_SECRET"), Some(secret)),
+            (None, None) => (None, None),
+        };
+        let jwks_url: Option<String> = config.opt("API_JWT_JWKS_URL");
+        let refresh = config.secs("API_JWT_JWKS_REFRESH_SECS", 300);
+        if secret.is_none() && jwks_url.is_none() {
+            config.invalid(
+                "API_JWT_SECRET",
+                "is required (or AUTH_JWT_SECRET or API_JWT_JWKS_URL)",
+            );
+        }
+        let jwks = jwks_url.and_then(|url| match jwks::Jwks::new(url, refresh) {
+            Ok(jwks) => Some(jwks),
+            Err(err) => {
+                config.invalid("API_JWT_JWKS_URL", err);
+                None
+            }
+        });
+        let issuer = config.string("API_JWT_ISSUER", "cyber-dev-studio");
+        let secrets = match secret {
+            Some(secret) => DecodingKeys::default().with_secret(&secret),
+            None => DecodingKeys::default(),
+        };
+        Self {
+            secrets: Arc::new(RwLock::new(secrets)),
+            secret_setting,
+            jwks,
+            tokens: TokenVerifier::new(&issuer),
+        }
     }
 
-    fn verify(&self, token: &str) -> std::result::Result<Claims, RpcMethodError> {
-        decode::<Claims>(token, &self.decoding, &self.validation)
-            .map(|data| data.claims)
-            .map_err(|_| RpcMethodError::unauthorized("invalid token"))
+    async fn verify(&self, token: &str) -> std::result::Result<Claims, RpcMethodError> {
+        let invalid = || RpcMethodError::unauthorized("invalid token");
+        let id = KeyId::of(token).ok_or_else(invalid)?;
+        match (&id, &self.jwks) {
+            (KeyId::Rsa(_), Some(jwks)) => {
+                let key = jwks.key(&id).await?;
+                self.tokens.verify(token, &id, &key).map_err(|_| invalid())
+            }
+            (KeyId::Hmac, _) => self
+                .secrets
+                .read()
+                .candidates(&id)
+                .find_map(|key| self.tokens.verify(token, &id, key).ok())
+                .ok_or_else(invalid),
+            (KeyId::Rsa(_), None) => Err(invalid()),
+        }
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct Claims {
-    sub: i32,
-    username: String,
-    role: String,
-    exp: usize,
-    iat: usize,
-    iss: String,
-    jti: String,
+    /// Verifies HS256 tokens with `secret` from now on, and with the
+    /// previous secret until the next rotation.
+    fn rotate_secret(&self, secret: &str) {
+        self.secrets.write().rotate_secret(secret);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -94,13 +271,36 @@ struct RequestContext {
     user_id: i32,
     username: String,
     role: Role,
+    /// The tenant of the account; every project, user and sandbox directory
+    /// the request reaches belongs to it.
+    tenant_id: i32,
+    /// Permissions of the role plus the user's grants, resolved at
+    /// authentication.
+    permissions: Arc<rbac::PermissionSet>,
     token_balance: i64,
     api_key_id: Option<Uuid>,
+    client_ip: Option<String>,
+    request_id: Uuid,
+    /// W3C trace context received from the caller.
+    trace_parent: opentelemetry::Context,
+    /// Credentials the call was made with, for forwarding it to another
+    /// replica.
+    credentials: affinity::Credentials,
 }
 
 impl RequestContext {
     fn require(&self, permission: Permission) -> std::result::Result<(), RpcMethodError> {
-        if self.role.allows(permission) {
+        self.require_for(permission, None)
+    }
+
+    /// Like `require`, but also accepts a grant limited to `project_id`.
+    fn require_for(
+        &self,
+        permission: Permission,
+        project_id: Option<&str>,
+    ) -> std::result::Result<(), RpcMethodError> {
+        let project_id = project_id.and_then(|id| Uuid::parse_str(id).ok());
+        if self.permissions.allows(permission, project_id.as_ref()) {
             Ok(())
         } else {
             Err(RpcMethodError::forbidden("insufficient permissions"))
@@ -120,7 +320,7 @@ impl RequestContext {
             Ok(())
         } else {
             Err(RpcMethodError::new(
-                -32092,
+                ErrorCode::InsufficientBalance,
                 "insufficient token balance",
                 Some(json!({ "detail": "recharge required" })),
             ))
@@ -128,298 +328,493 @@ impl RequestContext {
     }
 
     fn is_admin(&self) -> bool {
-        matches!(self.role, Role::Admin)
+        self.role.is_admin()
     }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Role {
-    Admin,
-    Developer,
-    Viewer,
-}
 
-impl Role {
-    fn parse(value: &str) -> Option<Self> {
-        match value {
-            "admin" => Some(Role::Admin),
-            "developer" => Some(Role::Developer),
-            "viewer" => Some(Role::Viewer),
-            _ => None,
+    /// Settings shared by every tenant (roles, schedules, sandbox policies)
+    /// are left to the default tenant's admins.
+    fn require_operator(&self) -> std::result::Result<(), RpcMethodError> {
+        if self.tenant_id == tenant::DEFAULT_TENANT {
+            Ok(())
+        } else {
+            Err(RpcMethodError::forbidden("reserved for the default tenant"))
         }
     }
+}
 
-    fn allows(self, permission: Permission) -> bool {
-        match permission {
-            Permission::FsRead | Permission::AgentView => true,
-            Permission::FsWrite
-            | Permission::Execute
-            | Permission::AgentControl
-            | Permission::LlmUse => matches!(self, Role::Admin | Role::Developer),
-            Permission::LlmAdmin => matches!(self, Role::Admin),
-        }
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = config::Args::parse()?;
+    let mut config = config::Config::load(args.config.as_deref())?;
+    let secrets = Arc::new(secrets::Secrets::new(config.secrets_config())?);
+    config.resolve_secrets(&secrets).await;
+    let settings = config::ApiConfig::read(&config);
+    if args.check {
+        print!("{}", config.render());
     }
-
-    fn as_str(self) -> &'static str {
-        match self {
-            Role::Admin => "admin",
-            Role::Developer => "developer",
-            Role::Viewer => "viewer",
-        }
+    config.finish()?;
+    if args.check {
+        println!("# configuration is valid");
+        return Ok(());
+    }
+    if args.doctor {
+        return doctor::run(settings).await;
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-enum Permission {
-    FsRead,
-    FsWrite,
-    Execute,
-    AgentView,
-    AgentControl,
-    LlmUse,
-    LlmAdmin,
-}
+    telemetry::init(&settings.telemetry);
+    let bind_addr = settings.bind_addr;
+    let pool = build_pool(&settings.database_url, settings.database_max_connections).await?;
+    let (fs_sandbox, run_sandbox, wasm_sandbox, micro_sandbox) =
+        initialize_sandboxes(settings.sandbox)?;
+    tenant::relocate_legacy_layout(fs_sandbox.base_dir()).map_err(|err| {
+        anyhow::anyhow!("failed to move the sandbox into the default tenant: {err}")
+    })?;
+    let faults = faults::Faults::new(settings.chaos);
+    let metrics = Arc::new(metrics::AppMetrics::new(settings.metrics).with_faults(faults.clone()));
+    let llm = llm::LlmClient::new(&settings.llm)?.with_faults(faults.llm);
+
+    let sandbox = Arc::new(
+        fs_sandbox
+            .with_faults(faults.fs)
+            .with_metrics(metrics.clone()),
+    );
+    let run = Arc::new(
+        run_sandbox
+            .with_faults(faults.run)
+            .with_metrics(metrics.clone()),
+    );
+    let wasm = Arc::new(wasm_sandbox.with_metrics(metrics.clone()));
+    let micro = Arc::new(micro_sandbox.with_metrics(metrics.clone()));
+    let git = Arc::new(settings.git.build(sandbox.base_dir())?);
+    let runners = runners::Runners::new(settings.runners);
+    let engines = engine::Engines::default();
+    engines.register(Arc::new(engine::RunEngine { run: run.clone() }))?;
+    engines.register(Arc::new(engine::WasmEngine { wasm: wasm.clone() }))?;
+    engines.register(Arc::new(engine::MicroEngine {
+        micro: micro.clone(),
+        runners: runners.clone(),
+    }))?;
+    let agents = Arc::new(initialize_agent_dispatcher(
+        settings.agents.with_metrics(metrics.clone()),
+        sandbox.clone(),
+    )?);
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    init_tracing();
-    let bind_addr = resolve_bind_address()?;
-    let pool = build_pool().await?;
-    let auth = JwtVerifier::from_env()?;
-    let (fs_sandbox, run_sandbox, wasm_sandbox, micro_sandbox) = initialize_sandboxes()?;
-    let agent_dispatcher = initialize_agent_dispatcher()?;
-    let llm = LlmClient::from_env()?;
-
-    let sandbox = Arc::new(fs_sandbox);
-    let run = Arc::new(run_sandbox);
-    let wasm = Arc::new(wasm_sandbox);
-    let micro = Arc::new(micro_sandbox);
-    let agents = Arc::new(agent_dispatcher);
+    let affinity = affinity::Affinity::new(pool.clone(), settings.affinity);
+    affinity.spawn();
+
+    let shutdown_handles = (
+        pool.clone(),
+        micro.clone(),
+        agents.clone(),
+        affinity.clone(),
+    );
+    let readiness = Arc::new(health::Readiness::new(
+        sandbox.base_dir().to_path_buf(),
+        settings.readiness,
+    ));
+    let billing = billing::Billing::new(pool.clone(), settings.pricing);
+    let (audit, audit_writer) = audit::AuditLog::spawn(pool.clone(), settings.audit);
+    let audit_handle = audit.clone();
+    let project_cache = cache::ProjectCache::new(settings.project_cache, metrics.clone());
+    let llm_cache = llm_cache::LlmCache::new(pool.clone(), settings.llm_cache, metrics.clone());
+    llm_cache.spawn_pruner();
+    metrics::spawn_sampler(
+        metrics.clone(),
+        metrics::SamplerSources {
+            pool: pool.clone(),
+            sandbox: sandbox.clone(),
+            run: run.clone(),
+            micro: micro.clone(),
+            agents: agents.clone(),
+        },
+        settings.metrics_interval,
+    );
+    workspace::spawn_reaper(
+        pool.clone(),
+        sandbox.clone(),
+        engines.clone(),
+        settings.workspaces,
+    );
+    let jobs = jobs::Jobs::new(pool.clone(), settings.jobs);
+    let webhooks = webhooks::Webhooks::new(pool.clone(), settings.webhooks, jobs.clone())?;
+    if let Err(err) = webhooks.seal_stored_secrets().await {
+        warn!(error = %err, "failed to seal stored webhook secrets");
+    }
+    if let Err(err) = webhooks.queue_orphaned_deliveries().await {
+        warn!(error = %err, "failed to queue pending webhook deliveries");
+    }
+    let notifier = notify::Notifier::new(pool.clone());
+    notifier.spawn_listener();
+    let events = events::EventBus::new(
+        settings.events,
+        vec![Arc::new(webhooks.clone()), Arc::new(notifier.clone())],
+    );
+    events.spawn_agent_bridge(&agents);
+    events.spawn_publisher();
+    scheduler::Scheduler::new(
+        pool.clone(),
+        sandbox.clone(),
+        micro.clone(),
+        agents.clone(),
+        metrics.clone(),
+        settings.scheduler,
+    )
+    .spawn();
+
+    let rbac = rbac::Rbac::new(pool.clone(), settings.rbac);
+    let flags = flags::Flags::new(pool.clone(), settings.flags);
+    let rate_limits = rate_limit::RateLimits::new(pool.clone(), settings.rate_limits);
+    let revocations = revocation::Revocations::new(pool.clone(), settings.revocations);
+    let auth_cache = auth_cache::AuthCache::new(pool.clone(), settings.auth_cache, metrics.clone());
+    auth_cache.spawn_listener();
 
     let state = AppState {
         sandbox,
         run,
         wasm,
         micro,
+        git,
+        engines,
+        runners,
         agents,
         pool,
-        auth,
+        auth: settings.auth,
+        auth_cache,
+        require_verified_email: settings.require_verified_email,
         llm,
+        rpc_batch_limit: settings.rpc_batch_limit,
+        rpc_batch_concurrency: settings.rpc_batch_concurrency,
+        fs_batch_limit: settings.fs_batch_limit,
+        fs_walk_limit: settings.fs_walk_limit,
+        deadlines: settings.deadlines,
+        response_budget: settings.response_budget,
+        readiness,
+        billing,
+        audit,
+        errors: error_log::ErrorLog::new(settings.errors_recent_capacity),
+        project_version_limit: settings.project_version_limit,
+        project_snapshot_limit: settings.project_snapshot_limit,
+        upload_limit: settings.upload_limit,
+        workspaces: settings.workspaces,
+        quotas: quota::Quotas::new(settings.quotas),
+        metrics,
+        project_cache,
+        llm_cache,
+        versions: settings.versions,
+        events,
+        webhooks,
+        rbac,
+        flags,
+        revocations,
+        notifier,
+        jobs,
+        reloader: Arc::new(reload::Reloader::new(args.config.clone(), secrets.clone())),
+        affinity,
+        admission: admission::Admission::new(settings.admission),
+        rate_limits,
     };
-
+    secrets.spawn_refresh();
+    rotation::spawn(&secrets, &config, state.clone());
+    state.jobs.spawn_workers(state.clone());
+    reload::spawn_sighup_listener(state.clone());
+
+    let rpc_body_limit = settings.rpc_body_limit;
+    let tls = settings.tls;
+    let grpc_state = state.clone();
     let app = Router::new()
-        .route("/health", get(health))
-        .route("/rpc", post(handle_rpc))
+        .route(
+            "/rpc",
+            post(handle_rpc).layer(DefaultBodyLimit::max(rpc_body_limit)),
+        )
+        .merge(health::routes())
+        .merge(metrics::routes())
+        .merge(rest::routes(rpc_body_limit, settings.upload_limit))
+        .merge(notify::routes())
+        .merge(agent_events::routes())
+        .merge(runners::routes())
         .with_state(state)
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::permissive()),
+                .layer(TraceLayer::new_for_http().make_span_with(request_span))
+                .layer(CorsLayer::permissive())
+                .layer(CompressionLayer::new()),
         );
 
-    info!("binding", %bind_addr, "server starting");
-    axum::Server::bind(&bind_addr)
-        .serve(app.into_make_service())
-        .await?;
-    Ok(())
-}
+    let drain_timeout = settings.drain_timeout;
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        server::shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    if let Some(grpc_addr) = settings.grpc_addr {
+        let grpc = grpc::serve(grpc_addr, grpc_state, tls.clone(), shutdown_rx.clone());
+        tokio::spawn(async move {
+            if let Err(err) = grpc.await {
+                error!(error = %err, "grpc server failed");
+            }
+        });
+    }
+
+    let mut server = match tls {
+        Some(settings) => tokio::spawn(server::tls::serve(
+            bind_addr,
+            settings,
+            app,
+            wait_for_shutdown(shutdown_rx.clone()),
+        )),
+        None => {
+            info!(%bind_addr, "server starting");
+            let server = axum::Server::bind(&bind_addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
+            tokio::spawn(async move { server.await.map_err(anyhow::Error::from) })
+        }
+    };
+    let deadline = async {
+        wait_for_shutdown(shutdown_rx).await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = &mut server => result??,
+        _ = deadline => {
+            warn!(timeout = ?drain_timeout, "drain deadline elapsed; aborting in-flight requests");
+            server.abort();
+        }
+    }
 
-fn init_tracing() {
-    if dispatcher::has_been_set() {
-        return;
+    let (pool, micro, agents, affinity) = shutdown_handles;
+    affinity.leave().await;
+    let cancelled = agents.cancel_all();
+    audit_writer.shutdown().await;
+    if audit_handle.dropped() > 0 {
+        warn!(
+            dropped = audit_handle.dropped(),
+            "audit events were dropped under backpressure"
+        );
     }
-    let subscriber = tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "info,tower_http=info".into()),
-        )
-        .json()
-        .finish();
-    if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
-        eprintln!("failed to install tracing subscriber: {err}");
+    match micro.shutdown().await {
+        Ok(stopped) => info!(
+            stopped,
+            cancelled_agents = cancelled,
+            "sandbox instances stopped"
+        ),
+        Err(err) => warn!(error = %err, "failed to stop micro instances"),
     }
+    pool.close().await;
+    info!("shutdown complete");
+    telemetry::shutdown();
+    Ok(())
 }
 
-fn resolve_bind_address() -> anyhow::Result<SocketAddr> {
-    let raw = std::env::var("API_BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:6813".to_string());
-    Ok(raw.parse()?)
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
 }
 
-async fn build_pool() -> anyhow::Result<PgPool> {
-    let database_url = std::env::var("DATABASE_URL")
-        .map_err(|_| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
-    let max_connections = std::env::var("API_DATABASE_MAX_CONNECTIONS")
-        .ok()
-        .and_then(|v| v.parse::<u32>().ok())
-        .unwrap_or(10);
+/// tower-http's default request span, with `?access_token=` (the WebSocket
+/// and SSE routes take the bearer token there) masked in the logged URI.
+fn request_span<B>(request: &Request<B>) -> tracing::Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %redact_access_token(request.uri()),
+        version = ?request.version(),
+    )
+}
+
+fn redact_access_token(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("access_token", _)) => "access_token=<redacted>",
+            _ => pair,
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+async fn build_pool(database_url: &str, max_connections: u32) -> anyhow::Result<PgPool> {
     let pool = PgPoolOptions::new()
         .max_connections(max_connections)
         .acquire_timeout(Duration::from_secs(10))
-        .connect(&database_url)
+        .connect(database_url)
         .await?;
     Ok(pool)
 }
 
-fn initialize_sandboxes() -> anyhow::Result<(SandboxFs, SandboxRun, SandboxWasm, SandboxMicro)> {
-    let max_size = std::env::var("SANDBOX_MAX_FILE_SIZE")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(512 * 1024);
-    let root = sandbox_root()?;
+/// Sandbox settings; the sandboxes themselves are built by
+/// `initialize_sandboxes` once the configuration is known to be valid.
+struct SandboxSettings {
+    root: String,
+    max_file_size: u64,
+    run_allowed: Vec<String>,
+    run_env_allow: Vec<String>,
+    run_path: String,
+    run_fixed_env: Vec<(String, String)>,
+    run_default_timeout: Duration,
+    run_max_timeout: Duration,
+    run_max_output_bytes: usize,
+    run_max_sessions: usize,
+    run_session_max_timeout: Duration,
+    wasm_max_memory_bytes: u64,
+    wasm_max_table_elements: u32,
+    wasm_default_fuel: Option<u64>,
+    micro_default_timeout: Duration,
+    micro_max_timeout: Duration,
+    micro_max_output_bytes: usize,
+    /// `SANDBOX_MICRO_IMAGES`; when unset, python and node images are built
+    /// from `SANDBOX_MICRO_PYTHON` and `SANDBOX_MICRO_NODE`.
+    micro_images: Option<Vec<RawMicroImage>>,
+    micro_python: Option<String>,
+    micro_node: Option<String>,
+    micro_path: Option<String>,
+    micro_base_env: Vec<(String, String)>,
+}
+
+impl SandboxSettings {
+    fn from_config(config: &config::Config) -> Self {
+        let micro_images =
+            config.opt::<String>("SANDBOX_MICRO_IMAGES").and_then(
+                |raw| match serde_json::from_str::<Vec<RawMicroImage>>(&raw) {
+                    Ok(images) if images.is_empty() => {
+                        config.invalid("SANDBOX_MICRO_IMAGES", "must define at least one image");
+                        None
+                    }
+                    Ok(images) => Some(images),
+                    Err(err) => {
+                        config.invalid("SANDBOX_MICRO_IMAGES", format!("invalid JSON: {err}"));
+                        None
+                    }
+                },
+            );
+        Self {
+            root: config.string("SANDBOX_ROOT", "./data/sandbox"),
+            max_file_size: config.get("SANDBOX_MAX_FILE_SIZE", 512 * 1024),
+            run_allowed: config.list("SANDBOX_RUN_ALLOWED", &["/bin/sh", "/usr/bin/env"]),
+            run_env_allow: config.list("SANDBOX_RUN_ENV_ALLOW", &["PATH"]),
+            run_path: config.string("SANDBOX_RUN_PATH", "/usr/bin:/bin"),
+            run_fixed_env: config.pairs("SANDBOX_RUN_FIXED_ENV"),
+            run_default_timeout: config.millis("SANDBOX_RUN_DEFAULT_TIMEOUT_MS", 10_000),
+            run_max_timeout: config.millis("SANDBOX_RUN_MAX_TIMEOUT_MS", 30_000),
+            run_max_output_bytes: config.get("SANDBOX_RUN_MAX_OUTPUT_BYTES", 512 * 1024),
+            run_max_sessions: config.get("SANDBOX_RUN_MAX_SESSIONS", 64),
+            run_session_max_timeout: config.secs("SANDBOX_RUN_SESSION_MAX_SECS", 600),
+            wasm_max_memory_bytes: config.get("SANDBOX_WASM_MAX_MEMORY_BYTES", 64 * 1024 * 1024),
+            wasm_max_table_elements: config.get("SANDBOX_WASM_MAX_TABLE_ELEMENTS", 2_048),
+            wasm_default_fuel: config.opt("SANDBOX_WASM_DEFAULT_FUEL"),
+            micro_default_timeout: config.millis("SANDBOX_MICRO_DEFAULT_TIMEOUT_MS", 5_000),
+            micro_max_timeout: config.millis("SANDBOX_MICRO_MAX_TIMEOUT_MS", 30_000),
+            micro_max_output_bytes: config.get("SANDBOX_MICRO_MAX_OUTPUT_BYTES", 256 * 1024),
+            micro_images,
+            micro_python: config.opt("SANDBOX_MICRO_PYTHON"),
+            micro_node: config.opt("SANDBOX_MICRO_NODE"),
+            micro_path: config.opt("SANDBOX_MICRO_PATH"),
+            micro_base_env: config.pairs("SANDBOX_MICRO_BASE_ENV"),
+        }
+    }
+}
 
-    let fs = SandboxFs::new(SandboxConfig::new(root.clone(), max_size)?);
+fn initialize_sandboxes(
+    settings: SandboxSettings,
+) -> anyhow::Result<(SandboxFs, SandboxRun, SandboxWasm, SandboxMicro)> {
+    let root = sandbox_root(&settings.root)?;
+    let fs = SandboxFs::new(SandboxConfig::new(root.clone(), settings.max_file_size)?);
+    let (run_config, wasm_config, micro_config) = sandbox_policies(settings, &root)?;
 
-    let allowed_programs = std::env::var("SANDBOX_RUN_ALLOWED")
-        .ok()
-        .map(|value| {
-            value
-                .split(',')
-                .map(|item| item.trim().to_string())
-                .filter(|item| !item.is_empty())
-                .collect::<Vec<_>>()
-        })
-        .filter(|items| !items.is_empty())
-        .unwrap_or_else(|| vec!["/bin/sh".to_string(), "/usr/bin/env".to_string()]);
-
-    let env_allowlist = std::env::var("SANDBOX_RUN_ENV_ALLOW")
-        .ok()
-        .map(|value| {
-            value
-                .split(',')
-                .map(|item| item.trim().to_string())
-                .filter(|item| !item.is_empty())
-                .collect::<Vec<_>>()
-        })
-        .unwrap_or_else(|| vec!["PATH".to_string()]);
+    Ok((
+        fs,
+        SandboxRun::new(run_config),
+        SandboxWasm::new(wasm_config),
+        SandboxMicro::new(micro_config),
+    ))
+}
 
-    let path_env =
-        std::env::var("SANDBOX_RUN_PATH").unwrap_or_else(|_| "/usr/bin:/bin".to_string());
+/// The run allowlists, wasm limits and micro images for `root`; built again
+/// by [`reload::Reloader`] when the policies are reloaded.
+fn sandbox_policies(
+    settings: SandboxSettings,
+    root: &Path,
+) -> anyhow::Result<(RunConfig, WasmConfig, MicroConfig)> {
     let mut fixed_env = vec![
-        ("PATH".to_string(), path_env),
+        ("PATH".to_string(), settings.run_path),
         ("HOME".to_string(), root.to_string_lossy().to_string()),
     ];
-
-    if let Ok(extra_fixed) = std::env::var("SANDBOX_RUN_FIXED_ENV") {
-        for pair in extra_fixed
-            .split(',')
-            .map(|p| p.trim())
-            .filter(|p| !p.is_empty())
-        {
-            if let Some((key, value)) = pair.split_once('=') {
-                fixed_env.push((key.trim().to_string(), value.trim().to_string()));
-            }
-        }
-    }
-
-    let default_timeout_ms = std::env::var("SANDBOX_RUN_DEFAULT_TIMEOUT_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(10_000);
-    let max_timeout_ms = std::env::var("SANDBOX_RUN_MAX_TIMEOUT_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(30_000);
-    let max_output_bytes_raw = std::env::var("SANDBOX_RUN_MAX_OUTPUT_BYTES")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(512 * 1024);
-    let max_output_bytes = usize::try_from(max_output_bytes_raw)
-        .map_err(|_| anyhow::anyhow!("SANDBOX_RUN_MAX_OUTPUT_BYTES exceeds platform limits"))?;
+    fixed_env.extend(settings.run_fixed_env);
 
     let run_config = RunConfig::new(
-        &root,
-        allowed_programs,
-        env_allowlist,
+        root,
+        settings.run_allowed,
+        settings.run_env_allow,
         fixed_env,
-        Duration::from_millis(default_timeout_ms),
-        Duration::from_millis(max_timeout_ms),
-        max_output_bytes,
-    )?;
-
-    let wasm_memory_limit = std::env::var("SANDBOX_WASM_MAX_MEMORY_BYTES")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(64 * 1024 * 1024);
-    let wasm_table_limit = std::env::var("SANDBOX_WASM_MAX_TABLE_ELEMENTS")
-        .ok()
-        .and_then(|v| v.parse::<u32>().ok())
-        .unwrap_or(2_048);
-    let wasm_default_fuel = std::env::var("SANDBOX_WASM_DEFAULT_FUEL")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok());
+        settings.run_default_timeout,
+        settings.run_max_timeout,
+        settings.run_max_output_bytes,
+    )?
+    .with_sessions(settings.run_max_sessions, settings.run_session_max_timeout);
 
     let wasm_config = WasmConfig::new(
-        root.clone(),
-        wasm_memory_limit,
-        wasm_table_limit,
-        wasm_default_fuel,
+        root,
+        settings.wasm_max_memory_bytes,
+        settings.wasm_max_table_elements,
+        settings.wasm_default_fuel,
     )?;
 
-    let micro_default_timeout_ms = std::env::var("SANDBOX_MICRO_DEFAULT_TIMEOUT_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(5_000);
-    let micro_max_timeout_ms = std::env::var("SANDBOX_MICRO_MAX_TIMEOUT_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(30_000);
-    let micro_max_output_bytes_raw = std::env::var("SANDBOX_MICRO_MAX_OUTPUT_BYTES")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(256 * 1024);
-    let micro_max_output_bytes = usize::try_from(micro_max_output_bytes_raw)
-        .map_err(|_| anyhow::anyhow!("SANDBOX_MICRO_MAX_OUTPUT_BYTES exceeds platform limits"))?;
-
-    let micro_images = resolve_micro_images()?;
-    let micro_base_env = resolve_micro_base_env();
+    let micro_images = match settings.micro_images {
+        Some(definitions) => resolve_micro_images(definitions)?,
+        None => default_micro_images(settings.micro_python, settings.micro_node)?,
+    };
+    let micro_base_env = resolve_micro_base_env(settings.micro_path, settings.micro_base_env);
     let micro_config = MicroConfig::new(
-        &root,
+        root,
         micro_images,
-        Duration::from_millis(micro_default_timeout_ms),
-        Duration::from_millis(micro_max_timeout_ms),
-        micro_max_output_bytes,
+        settings.micro_default_timeout,
+        settings.micro_max_timeout,
+        settings.micro_max_output_bytes,
         micro_base_env,
     )?;
 
-    Ok((
-        fs,
-        SandboxRun::new(run_config),
-        SandboxWasm::new(wasm_config),
-        SandboxMicro::new(micro_config),
-    ))
+    Ok((run_config, wasm_config, micro_config))
+}
+
+fn agent_config(config: &config::Config) -> AgentDispatcherConfig {
+    AgentDispatcherConfig::new(
+        config.string("AGENT_LLM_ENDPOINT", "http://localhost:6988"),
+        config.string("AGENT_DEFAULT_MODEL", "nous-hermes-2-3b.Q4"),
+    )
+    .with_timeout(config.millis("AGENT_LLM_TIMEOUT_MS", 30_000))
+    .with_history_capacity(config.get("AGENT_HISTORY_CAPACITY", 128))
+    .with_context_limit(config.get("AGENT_CONTEXT_LIMIT_BYTES", 512 * 1024))
+    .with_api_key(config.secret("AGENT_LLM_API_KEY"))
+    .with_native_tools(config.get("AGENT_NATIVE_TOOLS", false))
+    .with_rate_limit(
+        config.opt("AGENT_DISPATCH_PER_MINUTE"),
+        config.opt("AGENT_DISPATCH_PER_HOUR"),
+    )
+    .with_max_concurrency(config.get("AGENT_MAX_CONCURRENCY", 8))
+    .with_max_subtasks(config.get("AGENT_MAX_SUBTASKS", 16))
+    .with_system_prompt_limit(config.get("AGENT_SYSTEM_PROMPT_LIMIT_BYTES", 8 * 1024))
+    .with_max_checkpoints(config.get("AGENT_MAX_CHECKPOINTS", 3))
+    .with_input_timeout(config.secs("AGENT_INPUT_TIMEOUT_SECS", 3600))
+    .with_circuit_breaker(
+        config.get("AGENT_LLM_FAILURE_THRESHOLD", 5),
+        config.millis("AGENT_LLM_COOLDOWN_MS", 30_000),
+    )
 }
 
-fn initialize_agent_dispatcher() -> anyhow::Result<AgentDispatcher> {
-    let endpoint =
-        std::env::var("AGENT_LLM_ENDPOINT").unwrap_or_else(|_| "http://localhost:6988".to_string());
-    let default_model =
-        std::env::var("AGENT_DEFAULT_MODEL").unwrap_or_else(|_| "nous-hermes-2-3b.Q4".to_string());
-    let timeout_ms = std::env::var("AGENT_LLM_TIMEOUT_MS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(30_000);
-    let history_capacity = std::env::var("AGENT_HISTORY_CAPACITY")
-        .ok()
-        .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(128);
-    let context_limit = std::env::var("AGENT_CONTEXT_LIMIT_BYTES")
-        .ok()
-        .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(512 * 1024);
-    let api_key = std::env::var("AGENT_LLM_API_KEY").ok();
-
-    let config = AgentDispatcherConfig::new(endpoint, default_model)
-        .with_timeout(Duration::from_millis(timeout_ms))
-        .with_history_capacity(history_capacity)
-        .with_context_limit(context_limit)
-        .with_api_key(api_key);
-
-    AgentDispatcher::new(config).map_err(|err| anyhow::anyhow!(err.to_string()))
-}
-
-fn sandbox_root() -> anyhow::Result<PathBuf> {
-    let raw = std::env::var("SANDBOX_ROOT").unwrap_or_else(|_| "./data/sandbox".to_string());
-    let path = PathBuf::from(&raw);
+fn initialize_agent_dispatcher(
+    config: AgentDispatcherConfig,
+    workspace: Arc<SandboxFs>,
+) -> anyhow::Result<AgentDispatcher> {
+    AgentDispatcher::new(config)
+        .map(|dispatcher| dispatcher.with_workspace(workspace))
+        .map_err(|err| anyhow::anyhow!(err.to_string()))
+}
+
+fn sandbox_root(raw: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(raw);
     if path.is_absolute() {
         Ok(path)
     } else {
@@ -428,65 +823,54 @@ fn sandbox_root() -> anyhow::Result<PathBuf> {
     }
 }
 
-fn resolve_micro_images() -> anyhow::Result<Vec<MicroImage>> {
-    if let Ok(raw) = std::env::var("SANDBOX_MICRO_IMAGES") {
-        let definitions: Vec<RawMicroImage> = serde_json::from_str(&raw)
-            .map_err(|err| anyhow::anyhow!("failed to parse SANDBOX_MICRO_IMAGES: {err}"))?;
-        if definitions.is_empty() {
-            anyhow::bail!("SANDBOX_MICRO_IMAGES must define at least one image");
-        }
-        let mut images = Vec::with_capacity(definitions.len());
-        for definition in definitions {
-            let extension = definition
-                .extension
-                .unwrap_or_else(|| guess_extension(&definition.name).to_string());
-            let env_pairs = definition
-                .env
-                .into_iter()
-                .map(|pair| (pair.key, pair.value))
-                .collect::<Vec<_>>();
-            images.push(MicroImage::new(
+fn resolve_micro_images(definitions: Vec<RawMicroImage>) -> anyhow::Result<Vec<MicroImage>> {
+    let mut images = Vec::with_capacity(definitions.len());
+    for definition in definitions {
+        let extension = definition
+            .extension
+            .unwrap_or_else(|| guess_extension(&definition.name).to_string());
+        let env_pairs = definition
+            .env
+            .into_iter()
+            .map(|pair| (pair.key, pair.value))
+            .collect::<Vec<_>>();
+        images.push(
+            MicroImage::new(
                 definition.name,
                 definition.command,
                 definition.args,
                 extension,
                 env_pairs,
-            )?);
-        }
-        Ok(images)
-    } else {
-        default_micro_images()
-    }
-}
-
-fn default_micro_images() -> anyhow::Result<Vec<MicroImage>> {
-    let python_command = std::env::var("SANDBOX_MICRO_PYTHON")
-        .ok()
-        .filter(|value| !value.trim().is_empty())
-        .unwrap_or_else(|| detect_binary("python3").unwrap_or_else(|| "python3".to_string()));
-    let node_command = std::env::var("SANDBOX_MICRO_NODE")
-        .ok()
-        .filter(|value| !value.trim().is_empty())
-        .unwrap_or_else(|| detect_binary("node").unwrap_or_else(|| "node".to_string()));
-
-    let mut images = Vec::new();
-    images.push(MicroImage::new(
-        "python",
-        python_command,
-        vec!["-u".to_string()],
-        "py",
-        vec![("PYTHONUNBUFFERED".to_string(), "1".to_string())],
-    )?);
-    images.push(MicroImage::new(
-        "node",
-        node_command,
-        Vec::new(),
-        "js",
-        Vec::new(),
-    )?);
+            )?
+            .with_repl(definition.repl),
+        );
+    }
     Ok(images)
 }
 
+fn default_micro_images(
+    python: Option<String>,
+    node: Option<String>,
+) -> anyhow::Result<Vec<MicroImage>> {
+    let python_command =
+        python.unwrap_or_else(|| detect_binary("python3").unwrap_or_else(|| "python3".to_string()));
+    let node_command =
+        node.unwrap_or_else(|| detect_binary("node").unwrap_or_else(|| "node".to_string()));
+
+    Ok(vec![
+        MicroImage::new(
+            "python",
+            python_command,
+            vec!["-u".to_string()],
+            "py",
+            vec![("PYTHONUNBUFFERED".to_string(), "1".to_string())],
+        )?
+        .with_repl(Some(MicroRepl::Python)),
+        MicroImage::new("node", node_command, Vec::new(), "js", Vec::new())?
+            .with_repl(Some(MicroRepl::Node)),
+    ])
+}
+
 fn detect_binary(name: &str) -> Option<String> {
     let path = std::env::var("PATH").ok()?;
     for entry in path
@@ -519,10 +903,11 @@ fn guess_extension(name: &str) -> &'static str {
     }
 }
 
-fn resolve_micro_base_env() -> Vec<(String, String)> {
-    let path_env = std::env::var("SANDBOX_MICRO_PATH")
-        .ok()
-        .filter(|value| !value.trim().is_empty())
+fn resolve_micro_base_env(
+    path: Option<String>,
+    extra: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let path_env = path
         .unwrap_or_else(|| std::env::var("PATH").unwrap_or_else(|_| "/usr/bin:/bin".to_string()));
     let mut base = vec![
         ("PATH".to_string(), path_env),
@@ -530,27 +915,40 @@ fn resolve_micro_base_env() -> Vec<(String, String)> {
         ("LC_ALL".to_string(), "C".to_string()),
         ("TERM".to_string(), "dumb".to_string()),
     ];
-    if let Ok(extra) = std::env::var("SANDBOX_MICRO_BASE_ENV") {
-        for pair in extra
-            .split(',')
-            .map(|segment| segment.trim())
-            .filter(|segment| !segment.is_empty())
-        {
-            if let Some((key, value)) = pair.split_once('=') {
-                base.push((key.trim().to_string(), value.trim().to_string()));
-            }
-        }
-    }
+    base.extend(extra);
     base
 }
 
-async fn health() -> impl IntoResponse {
-    (StatusCode::OK, Json(json!({ "status": "ok" })))
+/// The client's address when the server runs with connect info, as `serve`
+/// does; a router served without it still works and records no peer.
+pub(crate) struct Peer(pub(crate) Option<SocketAddr>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Peer {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>();
+        Ok(Peer(peer.map(|ConnectInfo(peer)| *peer)))
+    }
 }
 
 async fn authenticate_request(
     state: &AppState,
     headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+) -> std::result::Result<RequestContext, RpcMethodError> {
+    let mut ctx = authenticate_credentials(state, headers).await?;
+    ctx.client_ip = audit::client_ip(headers, peer, state.audit.trust_forwarded());
+    ctx.request_id = telemetry::request_id(headers);
+    ctx.trace_parent = telemetry::extract(headers);
+    ctx.credentials = affinity::Credentials::from_headers(headers);
+    Ok(ctx)
+}
+
+async fn authenticate_credentials(
+    state: &AppState,
+    headers: &HeaderMap,
 ) -> std::result::Result<RequestContext, RpcMethodError> {
     if let Some(value) = headers.get("x-api-key") {
         if !value.as_bytes().is_empty() {
@@ -581,27 +979,48 @@ async fn authenticate_with_api_key(
         return Err(RpcMethodError::unauthorized("invalid api key"));
     }
     let hash = hash_api_key(api_key);
+    let credential = auth_cache::Credential::ApiKey(hash.clone());
+    let identity = match state.auth_cache.get(&credential).await {
+        Some(identity) if !identity.key_expired() => identity,
+        Some(_) => return Err(RpcMethodError::unauthorized("invalid api key")),
+        None => {
+            let identity = Arc::new(load_api_key_identity(state, &hash).await?);
+            state.auth_cache.insert(credential, identity.clone()).await;
+            identity
+        }
+    };
+    request_context(state, &identity).await
+}
+
+async fn load_api_key_identity(
+    state: &AppState,
+    hash: &str,
+) -> std::result::Result<auth_cache::Identity, RpcMethodError> {
     let row = sqlx::query(
-        "SELECT api_keys.id AS api_key_id, users.id AS user_id, users.username, users.role, users.token_balance \
-         FROM api_keys JOIN users ON users.id = api_keys.user_id WHERE api_keys.api_key_hash = $1",
+        "SELECT api_keys.id AS api_key_id, api_keys.scopes, api_keys.expires_at, users.id AS user_id, users.username, users.role, users.token_balance, \
+            users.tenant_id, users.email_verified_at IS NOT NULL AS email_verified, \
+            users.kind = 'service' AS service \
+         FROM api_keys JOIN users ON users.id = api_keys.user_id \
+         WHERE api_keys.api_key_hash = $1 AND users.disabled_at IS NULL \
+            AND (api_keys.expires_at IS NULL OR api_keys.expires_at > NOW())",
     )
-    .bind(&hash)
+    .bind(hash)
     .fetch_optional(&state.pool)
     .await
     .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
 
     let row = row.ok_or_else(|| RpcMethodError::unauthorized("invalid api key"))?;
-    let role_str: String = row.get("role");
-    let role = Role::parse(&role_str)
-        .ok_or_else(|| RpcMethodError::internal("user has unsupported role"))?;
-
+    ensure_email_verified(state, row.get("email_verified"), row.get("service"))?;
     let api_key_id: Uuid = row.get("api_key_id");
-    let context = RequestContext {
+    let identity = auth_cache::Identity {
         user_id: row.get("user_id"),
         username: row.get("username"),
-        role,
+        role: Role::parse(row.get("role")),
+        tenant_id: row.get("tenant_id"),
         token_balance: row.get("token_balance"),
         api_key_id: Some(api_key_id),
+        key_expires_at: row.get("expires_at"),
+        scope: KeyScope::parse(&row.get::<Vec<String>, _>("scopes")),
     };
 
     if let Err(err) = sqlx::query("UPDATE api_keys SET last_used_at = NOW() WHERE id = $1")
@@ -609,72 +1028,406 @@ async fn authenticate_with_api_key(
         .execute(&state.pool)
         .await
     {
-        warn!("failed to update api key usage", error = %err);
+        warn!(error = %err, "failed to update api key usage");
     }
 
-    Ok(context)
+    Ok(identity)
 }
 
 async fn authenticate_with_jwt(
     state: &AppState,
     token: &str,
 ) -> std::result::Result<RequestContext, RpcMethodError> {
-    let claims = state.auth.verify(token)?;
-    let row = sqlx::query("SELECT username, role, token_balance FROM users WHERE id = $1")
-        .bind(claims.sub)
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|err| match err {
-            sqlx::Error::RowNotFound => RpcMethodError::unauthorized("user not found"),
-            other => RpcMethodError::internal(&other.to_string()),
-        })?;
+    let claims = state.auth.verify(token).await?;
+    if state.revocations.is_revoked(&claims.jti).await? {
+        return Err(RpcMethodError::unauthorized("token revoked"));
+    }
+    let credential = auth_cache::Credential::Token(claims.jti.clone());
+    let identity = match state.auth_cache.get(&credential).await {
+        Some(identity) => identity,
+        None => {
+            let identity = Arc::new(load_token_identity(state, &claims).await?);
+            state.auth_cache.insert(credential, identity.clone()).await;
+            identity
+        }
+    };
+    request_context(state, &identity).await
+}
+
+async fn load_token_identity(
+    state: &AppState,
+    claims: &Claims,
+) -> std::result::Result<auth_cache::Identity, RpcMethodError> {
+    let row = sqlx::query(
+        "SELECT username, role, tenant_id, token_balance, tokens_revoked_at, \
+            email_verified_at IS NOT NULL AS email_verified, kind = 'service' AS service \
+         FROM users WHERE id = $1 AND disabled_at IS NULL",
+    )
+    .bind(claims.sub)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::RowNotFound => RpcMethodError::unauthorized("user not found"),
+        other => RpcMethodError::internal(&other.to_string()),
+    })?;
+    if revocation::issued_before_cutoff(claims.iat, row.get("tokens_revoked_at")) {
+        return Err(RpcMethodError::unauthorized("token revoked"));
+    }
+    ensure_email_verified(state, row.get("email_verified"), row.get("service"))?;
+    // Tokens issued before tenants existed carry none.
+    let tenant_id: i32 = row.get("tenant_id");
+    if claims.tenant.is_some_and(|tenant| tenant != tenant_id) {
+        return Err(RpcMethodError::unauthorized(
+            "token issued for another tenant",
+        ));
+    }
 
-    let role_str: String = row.get("role");
-    let role = Role::parse(&role_str)
-        .ok_or_else(|| RpcMethodError::internal("user has unsupported role"))?;
+    let scope = match claims.scopes() {
+        // The auth service never issues a service token without scopes.
+        Some(scopes) => Some(
+            KeyScope::parse(&scopes)
+                .ok_or_else(|| RpcMethodError::unauthorized("invalid token"))?,
+        ),
+        None => None,
+    };
 
-    Ok(RequestContext {
+    Ok(auth_cache::Identity {
         user_id: claims.sub,
         username: row.get("username"),
-        role,
+        role: Role::parse(row.get("role")),
+        tenant_id,
         token_balance: row.get("token_balance"),
         api_key_id: None,
+        key_expires_at: None,
+        scope,
+    })
+}
+
+/// The context of a call by `identity`, with its current permissions.
+async fn request_context(
+    state: &AppState,
+    identity: &auth_cache::Identity,
+) -> std::result::Result<RequestContext, RpcMethodError> {
+    let mut permissions = state
+        .rbac
+        .permissions(identity.user_id, &identity.role)
+        .await?;
+    if let Some(scope) = &identity.scope {
+        permissions = Arc::new(permissions.with_scope(scope.clone()));
+    }
+    Ok(RequestContext {
+        user_id: identity.user_id,
+        username: identity.username.clone(),
+        role: identity.role.clone(),
+        tenant_id: identity.tenant_id,
+        permissions,
+        token_balance: identity.token_balance,
+        api_key_id: identity.api_key_id,
+        client_ip: None,
+        request_id: Uuid::new_v4(),
+        trace_parent: opentelemetry::Context::new(),
+        credentials: Default::default(),
     })
 }
 
-fn hash_api_key(key: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(key.as_bytes());
-    hex_encode(hasher.finalize())
+/// Service clients (migration 023) have no e-mail address to verify.
+fn ensure_email_verified(
+    state: &AppState,
+    verified: bool,
+    service: bool,
+) -> std::result::Result<(), RpcMethodError> {
+    if state.require_verified_email && !verified && !service {
+        return Err(RpcMethodError::new(
+            ErrorCode::EmailNotVerified,
+            ErrorCode::EmailNotVerified.message(),
+            None,
+        ));
+    }
+    Ok(())
 }
 
 async fn handle_rpc(
     State(state): State<AppState>,
+    Peer(peer): Peer,
     headers: HeaderMap,
-    Json(req): Json<RpcRequest>,
-) -> impl IntoResponse {
-    if req.jsonrpc != "2.0" {
+    Json(payload): Json<Value>,
+) -> Response {
+    match payload {
+        Value::Array(entries) => handle_rpc_batch(&state, &headers, peer, entries).await,
+        single => {
+            let req = match serde_json::from_value::<RpcRequest>(single) {
+                Ok(req) => req,
+                Err(err) => return Json(invalid_rpc_request(Value::Null, &err)).into_response(),
+            };
+            if req.jsonrpc != "2.0" {
+                return Json(RpcResponse::error(
+                    req.id.unwrap_or(Value::Null),
+                    ErrorCode::InvalidRequest.code(),
+                    "invalid jsonrpc version",
+                    None,
+                ))
+                .into_response();
+            }
+            let ctx = match authenticate_request(&state, &headers, peer).await {
+                Ok(ctx) => ctx,
+                Err(err) => {
+                    let err = authentication_failed(telemetry::request_id(&headers), err);
+                    let Some(id) = req.id else {
+                        return StatusCode::NO_CONTENT.into_response();
+                    };
+                    return Json(RpcResponse::error(id, err.code, &err.message, err.data))
+                        .into_response();
+                }
+            };
+            match execute_rpc(&state, &ctx, req).await {
+                Some(response) => Json(response).into_response(),
+                None => StatusCode::NO_CONTENT.into_response(),
+            }
+        }
+    }
+}
+
+/// JSON-RPC 2.0 batch. Authentication runs once for the HTTP request while
+/// permissions are still checked per entry, and every entry gets a request
+/// id of its own, which a failed authentication is logged and tagged with
+/// too. Consecutive read-only calls run concurrently, at most
+/// `RPC_BATCH_CONCURRENCY` at a time; anything that mutates state runs alone,
+/// in request order. Responses keep the order of the requests; notifications
+/// get none, and a batch of only notifications answers with no content.
+async fn handle_rpc_batch(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    entries: Vec<Value>,
+) -> Response {
+    if entries.is_empty() {
         return Json(RpcResponse::error(
-            req.id,
-            -32600,
-            "invalid jsonrpc version",
-            None,
-        ));
+            Value::Null,
+            ErrorCode::InvalidRequest.code(),
+            "invalid request",
+            Some(json!({ "detail": "batch must not be empty" })),
+        ))
+        .into_response();
+    }
+    if entries.len() > state.rpc_batch_limit {
+        return Json(RpcResponse::error(
+            Value::Null,
+            ErrorCode::InvalidRequest.code(),
+            "batch too large",
+            Some(json!({ "limit": state.rpc_batch_limit })),
+        ))
+        .into_response();
+    }
+
+    let auth = authenticate_request(state, headers, peer).await;
+
+    let mut responses: Vec<Option<RpcResponse>> = Vec::with_capacity(entries.len());
+    let mut requests = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let request_id = Uuid::new_v4();
+        let id = entry.get("id").cloned().unwrap_or(Value::Null);
+        let response = match serde_json::from_value::<RpcRequest>(entry) {
+            Err(err) => Some(invalid_rpc_request(id, &err)),
+            Ok(req) if req.jsonrpc != "2.0" => Some(RpcResponse::error(
+                req.id.unwrap_or(Value::Null),
+                ErrorCode::InvalidRequest.code(),
+                "invalid jsonrpc version",
+                None,
+            )),
+            Ok(req) => match &auth {
+                Err(err) => {
+                    let err = authentication_failed(request_id, err.clone());
+                    req.id
+                        .map(|id| RpcResponse::error(id, err.code, &err.message, err.data))
+                }
+                Ok(_) => {
+                    requests.push((index, request_id, req));
+                    None
+                }
+            },
+        };
+        responses.push(response);
     }
-    let ctx = match authenticate_request(&state, &headers).await {
-        Ok(ctx) => ctx,
-        Err(err) => {
-            error!("authentication failed", message = %err.message);
-            return Json(RpcResponse::error(req.id, err.code, &err.message, err.data));
+
+    if let Ok(ctx) = &auth {
+        let methods: Vec<&str> = requests
+            .iter()
+            .map(|(_, _, req)| req.method.as_str())
+            .collect();
+        let segments = batch_segments(&methods);
+        let mut pending = requests.into_iter();
+        for segment in segments {
+            let calls = pending
+                .by_ref()
+                .take(segment.len())
+                .map(|(index, id, req)| async move {
+                    let ctx = RequestContext {
+                        request_id: id,
+                        ..ctx.clone()
+                    };
+                    (index, execute_rpc(state, &ctx, req).await)
+                });
+            let mut done = stream::iter(calls).buffer_unordered(state.rpc_batch_concurrency);
+            while let Some((index, response)) = done.next().await {
+                responses[index] = response;
+            }
         }
-    };
-    match process_request(&state, &ctx, req.method, req.params).await {
-        Ok(result) => Json(RpcResponse::success(req.id, result)),
-        Err(err) => {
-            error!("rpc error", message = %err.message);
-            Json(RpcResponse::error(req.id, err.code, &err.message, err.data))
+    }
+
+    let responses: Vec<RpcResponse> = responses.into_iter().flatten().collect();
+    if responses.is_empty() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    Json(responses).into_response()
+}
+
+/// Splits batch entries into runs that may execute concurrently: maximal
+/// runs of read-only methods, and every other method on its own.
+fn batch_segments(methods: &[&str]) -> Vec<std::ops::Range<usize>> {
+    let mut segments: Vec<std::ops::Range<usize>> = Vec::new();
+    for (index, method) in methods.iter().enumerate() {
+        let read_only = is_read_only_method(versioning::canonical(method));
+        match segments.last_mut() {
+            Some(last)
+                if read_only && is_read_only_method(versioning::canonical(methods[last.start])) =>
+            {
+                last.end = index + 1;
+            }
+            _ => segments.push(index..index + 1),
         }
     }
+    segments
+}
+
+/// Final name segments of methods that only read state. Methods are named
+/// `<area>.<verb>`, so a new method is classified by its verb alone; any
+/// other verb counts as mutating and runs on its own in a batch.
+const READ_ONLY_VERBS: &[&str] = &[
+    "read",
+    "list",
+    "list_models",
+    "stat",
+    "walk",
+    "open",
+    "search",
+    "history",
+    "activity",
+    "status",
+    "diff",
+    "log",
+    "describe",
+    "discover",
+    "errors",
+    "recent",
+    "usage",
+    "ledger",
+    "query",
+];
+
+fn is_read_only_method(method: &str) -> bool {
+    method
+        .rsplit('.')
+        .next()
+        .is_some_and(|verb| READ_ONLY_VERBS.contains(&verb))
+}
+
+fn invalid_rpc_request(id: Value, err: &serde_json::Error) -> RpcResponse {
+    RpcResponse::error(
+        id,
+        ErrorCode::InvalidRequest.code(),
+        "invalid request",
+        Some(json!({ "detail": err.to_string() })),
+    )
+}
+
+/// Runs one call. Notifications run like any other call but get no response.
+async fn execute_rpc(
+    state: &AppState,
+    ctx: &RequestContext,
+    req: RpcRequest,
+) -> Option<RpcResponse> {
+    let outcome = process_audited_request(state, ctx, req.method, req.params).await;
+    let id = req.id?;
+    Some(match outcome {
+        Ok(result) => RpcResponse::success(id, result),
+        Err(err) => RpcResponse::error(id, err.code, &err.message, err.data),
+    })
+}
+
+/// Logs a failed authentication under the request id the call would have
+/// had and returns the error tagged with it.
+fn authentication_failed(request_id: Uuid, err: RpcMethodError) -> RpcMethodError {
+    error!(%request_id, message = %err.message, "authentication failed");
+    err.with_request_id(request_id)
+}
+
+/// Resolves versioned and deprecated names, runs `process_request` under
+/// the method's deadline and queues an audit event for the outcome under the canonical method name.
+/// Failures are logged, kept for `errors.recent` and carry the request id in
+/// their data.
+async fn process_audited_request(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: String,
+    params: Option<Value>,
+) -> std::result::Result<Value, RpcMethodError> {
+    let started = Instant::now();
+    let digest = audit::params_digest(params.as_ref());
+    let (event_method, span, result) = match state.versions.resolve(&method, ctx, &state.metrics) {
+        Ok(method) => {
+            let span = telemetry::rpc_span(&method, ctx, params.as_ref());
+            let trace_id = telemetry::span_trace_id(&span, ctx);
+            let call = async {
+                state
+                    .rate_limits
+                    .check(&method, ctx, &state.metrics)
+                    .await?;
+                let _permit = state.admission.admit(&method, ctx, &state.metrics).await?;
+                process_request(state, ctx, method.clone(), params).await
+            };
+            let result = state
+                .deadlines
+                .run(&method, &state.metrics, call)
+                .instrument(span.clone())
+                .await
+                .and_then(|result| {
+                    state.response_budget.check(&method, &result)?;
+                    Ok(result)
+                });
+            if openrpc::has_method(&method) {
+                state
+                    .metrics
+                    .request_duration(&method, started.elapsed(), trace_id);
+            }
+            (method, Some(span), result)
+        }
+        Err(err) => (method, None, Err(err)),
+    };
+    state
+        .audit
+        .record(AuditEvent::new(
+            ctx,
+            &event_method,
+            digest,
+            &result,
+            started.elapsed(),
+        ))
+        .await;
+    result.map_err(|err| {
+        let err = err.with_request_id(ctx.request_id);
+        let _entered = span.as_ref().map(Span::enter);
+        error!(
+            request_id = %ctx.request_id,
+            method = %event_method,
+            code = err.code,
+            message = %err.message,
+            "rpc error"
+        );
+        state
+            .errors
+            .record(ctx, &event_method, &err, started.elapsed());
+        err
+    })
 }
 
 async fn process_request(
@@ -683,82 +1436,166 @@ async fn process_request(
     method: String,
     params: Option<Value>,
 ) -> std::result::Result<Value, RpcMethodError> {
+    state.flags.require_method(&method, ctx).await?;
+    validate_params(&method, params.as_ref())?;
+    if let Some(result) = state
+        .affinity
+        .forward(ctx, &method, params.as_ref())
+        .await?
+    {
+        return Ok(result);
+    }
     match method.as_str() {
         "fs.read" => {
-            ctx.require(Permission::FsRead)?;
-            let params: FsPathParams = parse_params(params)?;
-            let bytes = state
-                .sandbox
-                .read(Path::new(&params.path))
-                .map_err(|err| RpcMethodError::from_sandbox(-32001, "failed to read file", err))?;
-            Ok(json!({ "data": BASE64.encode(bytes) }))
+            let params: FsReadParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, params.project_id.as_deref())?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsRead,
+            )
+            .await?;
+            let bytes = sandbox.read(Path::new(&params.path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsRead, "failed to read file", err)
+            })?;
+            let sha256 = hex_encode(Sha256::digest(&bytes));
+            if sha256_matches(params.if_none_match.as_deref(), &sha256) {
+                return Ok(json!({ "not_modified": true, "sha256": sha256 }));
+            }
+            Ok(json!({ "data": BASE64.encode(bytes), "sha256": sha256 }))
         }
         "fs.write" => {
-            ctx.require(Permission::FsWrite)?;
             let params: FsWriteParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, params.project_id.as_deref())?;
+            git::ensure_outside_git_dir(&params.path)?;
             let data = BASE64.decode(params.data.as_bytes()).map_err(|err| {
                 RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "invalid base64 payload",
                     Some(json!({ "detail": err.to_string() })),
                 )
             })?;
-            state
-                .sandbox
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsWrite,
+            )
+            .await?;
+            sandbox
                 .write(Path::new(&params.path), data)
-                .map_err(|err| RpcMethodError::from_sandbox(-32002, "failed to write file", err))?;
+                .map_err(|err| {
+                    RpcMethodError::from_sandbox(ErrorCode::FsWrite, "failed to write file", err)
+                })?;
             Ok(json!({ "status": "ok" }))
         }
         "fs.list" => {
-            ctx.require(Permission::FsRead)?;
             let params: FsPathParams = parse_params(params)?;
-            let entries = state.sandbox.list(Path::new(&params.path)).map_err(|err| {
-                RpcMethodError::from_sandbox(-32003, "failed to list directory", err)
+            ctx.require_for(Permission::FsRead, params.project_id.as_deref())?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsRead,
+            )
+            .await?;
+            let entries = sandbox.list(Path::new(&params.path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsList, "failed to list directory", err)
             })?;
             Ok(serde_json::to_value(entries).expect("serialize entries"))
         }
-        "fs.delete" => {
-            ctx.require(Permission::FsWrite)?;
-            let params: FsPathParams = parse_params(params)?;
-            state
-                .sandbox
-                .delete(Path::new(&params.path))
+        "fs.walk" => {
+            let params: FsWalkParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, params.project_id.as_deref())?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsRead,
+            )
+            .await?;
+            let options = WalkOptions {
+                max_depth: params.max_depth,
+                include: params.include,
+                exclude: params.exclude,
+                max_entries: Some(
+                    params
+                        .max_entries
+                        .unwrap_or(state.fs_walk_limit)
+                        .clamp(1, state.fs_walk_limit),
+                ),
+            };
+            let listing = sandbox
+                .walk(Path::new(&params.path), &options)
                 .map_err(|err| {
-                    RpcMethodError::from_sandbox(-32004, "failed to delete path", err)
+                    RpcMethodError::from_sandbox(ErrorCode::FsList, "failed to walk directory", err)
                 })?;
+            Ok(serde_json::to_value(listing).expect("serialize listing"))
+        }
+        "fs.stat" => {
+            let params: FsPathParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, params.project_id.as_deref())?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsRead,
+            )
+            .await?;
+            let entry = sandbox.stat(Path::new(&params.path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsRead, "failed to stat path", err)
+            })?;
+            Ok(serde_json::to_value(entry).expect("serialize entry"))
+        }
+        "fs.delete" => {
+            let params: FsPathParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, params.project_id.as_deref())?;
+            git::ensure_outside_git_dir(&params.path)?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsWrite,
+            )
+            .await?;
+            sandbox.delete(Path::new(&params.path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsDelete, "failed to delete path", err)
+            })?;
             Ok(json!({ "status": "ok" }))
         }
         "fs.mkdir" => {
-            ctx.require(Permission::FsWrite)?;
             let params: FsPathParams = parse_params(params)?;
-            state
-                .sandbox
-                .mkdir(Path::new(&params.path))
-                .map_err(|err| {
-                    RpcMethodError::from_sandbox(-32005, "failed to create directory", err)
-                })?;
+            ctx.require_for(Permission::FsWrite, params.project_id.as_deref())?;
+            git::ensure_outside_git_dir(&params.path)?;
+            let sandbox = scoped_fs(
+                state,
+                ctx,
+                params.project_id.as_deref(),
+                params.workspace_id.as_deref(),
+                Permission::FsWrite,
+            )
+            .await?;
+            sandbox.mkdir(Path::new(&params.path)).map_err(|err| {
+                RpcMethodError::from_sandbox(ErrorCode::FsMkdir, "failed to create directory", err)
+            })?;
             Ok(json!({ "status": "ok" }))
         }
+        "fs.batch" => {
+            let params: FsBatchParams = parse_params(params)?;
+            fs_batch::execute(state, ctx, state.fs_batch_limit, params).await
+        }
         "project.create" => {
             ctx.require(Permission::FsWrite)?;
             let params: ProjectCreateParams = parse_params(params)?;
-            let name = normalize_project_name(&params.name)?;
-            let description = params.description.as_ref().map(|d| truncate_description(d));
-            let record = create_project(&state.pool, ctx, &name, description.as_deref()).await?;
-            let project_root = project_directory_relative(&record.id);
-            state.sandbox.mkdir(&project_root).map_err(|err| {
-                RpcMethodError::from_sandbox(-32050, "failed to prepare project", err)
-            })?;
-            let activity_name = record.name.clone();
-            record_project_activity(
-                &state.pool,
-                record.id,
-                ctx.user_id,
-                "project.created",
-                Some(json!({ "name": activity_name })),
-            )
-            .await
-            .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+            let record =
+                provision_project(state, ctx, &params.name, params.description.as_deref()).await?;
             Ok(record.to_value())
         }
         "project.list" => {
@@ -767,121 +1604,344 @@ async fn process_request(
             Ok(Value::Array(projects))
         }
         "project.open" => {
-            ctx.require(Permission::FsRead)?;
             let params: ProjectOpenParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
             let project_id = parse_project_id(&params.project_id)?;
-            let record = load_project(&state.pool, ctx, &project_id).await?;
-            let include_content = params.include_content.unwrap_or(false);
-            let files = project_files(&state.pool, &project_id, include_content).await?;
+            let record = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+            let mut query = params.into_file_query()?;
+            query.content_budget = state.response_budget.page_bytes();
+            let (files, next_cursor) = if query.include_content {
+                project_files(&state.pool, &project_id, &query).await?
+            } else {
+                state
+                    .project_cache
+                    .listing(project_id, query.cache_key(), || {
+                        project_files(&state.pool, &project_id, &query)
+                    })
+                    .await?
+            };
             Ok(json!({
                 "project": record.to_value(),
                 "files": files,
+                "next_cursor": next_cursor,
             }))
         }
-        "project.delete" => {
-            ctx.require(Permission::FsWrite)?;
-            let params: ProjectIdParams = parse_params(params)?;
+        "project.search" => {
+            let params: ProjectSearchParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, params.project_id.as_deref())?;
+            let project_id = match params.project_id.as_deref() {
+                Some(raw) => {
+                    let project_id = parse_project_id(raw)?;
+                    let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+                    Some(project_id)
+                }
+                None => None,
+            };
+            search_project_files(&state.pool, ctx, project_id, params).await
+        }
+        "project.activity" => {
+            let params: ProjectActivityParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
+            let project_id = parse_project_id(&params.project_id)?;
+            let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+            project_activity(&state.pool, &project_id, params).await
+        }
+        "project.update" => {
+            let params: ProjectUpdateParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
+            let project_id = parse_project_id(&params.project_id)?;
+            let record = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+            // Grants open a project for work, not for renaming it or
+            // changing its run policy.
+            if record.owner_id != ctx.user_id && !ctx.is_admin() {
+                return Err(RpcMethodError::forbidden("project access denied"));
+            }
+            let allowed_programs = params
+                .allowed_programs
+                .map(|programs| normalize_allowed_programs(&state.run, programs))
+                .transpose()?;
+            let (name, description) = if allowed_programs.is_some()
+                && params.name.is_none()
+                && params.description.is_none()
+            {
+                (record.name.clone(), record.description.clone())
+            } else {
+                resolve_project_update(&record, params.name, params.description)?
+            };
+            let updated =
+                update_project(&state.pool, &project_id, &name, description.as_deref()).await?;
+            state.project_cache.invalidate_project(&project_id).await;
+            let mut changes = serde_json::Map::new();
+            if let Some(programs) = &allowed_programs {
+                set_project_run_policy(&state.pool, &project_id, programs.as_deref()).await?;
+                changes.insert("allowed_programs".to_string(), json!(programs));
+            }
+            if updated.name != record.name {
+                changes.insert(
+                    "name".to_string(),
+                    json!({ "from": record.name, "to": updated.name }),
+                );
+            }
+            if updated.description != record.description {
+                changes.insert("description".to_string(), Value::Bool(true));
+            }
+            record_project_activity(
+                state,
+                project_id,
+                ctx.user_id,
+                "project.updated",
+                Some(Value::Object(changes)),
+            )
+            .await
+            .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+            Ok(updated.to_value())
+        }
+        "project.run" => {
+            let params: ProjectRunParams = parse_params(params)?;
+            ctx.require_for(Permission::Execute, Some(params.project_id.as_str()))?;
+            ctx.ensure_tokens()?;
             let project_id = parse_project_id(&params.project_id)?;
-            let record = load_project(&state.pool, ctx, &project_id).await?;
-            delete_project(&state.pool, &project_id).await?;
-            let project_root = project_directory_relative(&project_id);
-            state.sandbox.delete(&project_root).map_err(|err| {
-                RpcMethodError::from_sandbox(-32054, "failed to remove project files", err)
+            let project = load_project(state, ctx, &project_id, Permission::Execute).await?;
+            let policy = project_run_policy(&state.pool, &project_id).await?;
+            ensure_program_allowed(policy.as_deref(), &params.command.program)?;
+            let program = params.command.program.clone();
+            let run = state
+                .run
+                .scoped(project_directory_relative(project.tenant_id, &project_id))
+                .map_err(scope_error)?;
+            let request = params.command.into_request()?;
+            let result = run.execute(request).await.map_err(|err| {
+                RpcMethodError::from_sandbox(
+                    ErrorCode::RunExecute,
+                    "failed to execute process",
+                    err,
+                )
             })?;
-            let name = record.name.clone();
+            state
+                .billing
+                .charge(ctx, &method, Charge::SandboxTime(result.duration))
+                .await;
+            let run_detail = json!({
+                "program": program,
+                "exit_code": result.exit_code,
+                "duration_ms": result.duration.as_millis() as u64,
+            });
+            state.events.publish(DomainEvent::RunCompleted {
+                user_id: ctx.user_id,
+                program: program.clone(),
+                exit_code: Some(result.exit_code.into()),
+                duration: result.duration,
+                scope: json!({ "project_id": project_id, "workspace_id": null }),
+            });
             record_project_activity(
-                &state.pool,
+                state,
                 project_id,
                 ctx.user_id,
-                "project.deleted",
-                Some(json!({ "name": name })),
+                "project.run",
+                Some(run_detail),
             )
             .await
             .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+            Ok(json!({
+                "exit_code": result.exit_code,
+                "stdout": BASE64.encode(result.stdout),
+                "stderr": BASE64.encode(result.stderr),
+                "duration_ms": result.duration.as_millis()
+            }))
+        }
+        "project.export" => {
+            let params: ProjectIdParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
+            let project_id = parse_project_id(&params.project_id)?;
+            let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+            transfer::start_export(state, ctx, project_id).await
+        }
+        "project.import" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: ProjectImportParams = parse_params(params)?;
+            transfer::start_import(state, ctx, params).await
+        }
+        "project.snapshot.create" => {
+            let params: SnapshotCreateParams = parse_params(params)?;
+            snapshot::create(state, ctx, params).await
+        }
+        "project.snapshot.list" => {
+            let params: SnapshotListParams = parse_params(params)?;
+            snapshot::list(state, ctx, params).await
+        }
+        "project.snapshot.restore" => {
+            let params: SnapshotRestoreParams = parse_params(params)?;
+            snapshot::restore(state, ctx, params).await
+        }
+        "project.git.init" => {
+            let params: ProjectIdParams = parse_params(params)?;
+            git::init(state, ctx, params).await
+        }
+        "project.git.status" => {
+            let params: ProjectIdParams = parse_params(params)?;
+            git::status(state, ctx, params).await
+        }
+        "project.git.diff" => {
+            let params: GitDiffParams = parse_params(params)?;
+            git::diff(state, ctx, params).await
+        }
+        "project.git.commit" => {
+            let params: GitCommitParams = parse_params(params)?;
+            git::commit(state, ctx, params).await
+        }
+        "project.git.log" => {
+            let params: GitLogParams = parse_params(params)?;
+            git::log(state, ctx, params).await
+        }
+        "project.delete" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: ProjectIdParams = parse_params(params)?;
+            let project_id = parse_project_id(&params.project_id)?;
+            // Grants open a project for work, not for deletion.
+            let record = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+            if record.owner_id != ctx.user_id && !ctx.is_admin() {
+                return Err(RpcMethodError::forbidden("project access denied"));
+            }
+            // The activity feed cascades away with the project; the audit log
+            // keeps the record of the deletion.
+            reconcile::delete_project(
+                &state.pool,
+                &state.sandbox,
+                &state.engines,
+                record.tenant_id,
+                &project_id,
+            )
+            .await?;
+            state.project_cache.invalidate_project(&project_id).await;
+            state.events.publish(DomainEvent::ProjectDeleted {
+                user_id: ctx.user_id,
+                project_id,
+            });
             Ok(json!({ "status": "ok" }))
         }
         "project.file.save" => {
-            ctx.require(Permission::FsWrite)?;
             let params: ProjectFileSaveParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
             let project_id = parse_project_id(&params.project_id)?;
-            let _ = load_project(&state.pool, ctx, &project_id).await?;
+            let project = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
             let encoding = params.encoding.unwrap_or_else(|| "base64".to_string());
             if encoding.to_lowercase() != "base64" {
                 return Err(RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "unsupported file encoding",
                     Some(json!({ "detail": encoding })),
                 ));
             }
             let data = BASE64.decode(params.data.as_bytes()).map_err(|err| {
                 RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "invalid base64 payload",
                     Some(json!({ "detail": err.to_string() })),
                 )
             })?;
             let relative_path = normalize_project_path(&params.path)?;
             let sha256 = Sha256::digest(&data);
-            let saved =
-                save_project_file(&state.pool, &project_id, &relative_path, &data, &sha256).await?;
-            let project_root = project_directory_relative(&project_id).join(&relative_path);
-            state.sandbox.write(project_root, &data).map_err(|err| {
-                RpcMethodError::from_sandbox(-32051, "failed to persist project file", err)
-            })?;
-            if let Some(message) = params.message {
-                if !message.trim().is_empty() {
-                    record_project_activity(
-                        &state.pool,
-                        project_id,
-                        ctx.user_id,
-                        "project.file.save",
-                        Some(json!({
-                            "path": relative_path.to_string_lossy(),
-                            "message": message.trim(),
-                        })),
-                    )
-                    .await
-                    .map_err(|err| {
-                        map_db_activity_error(err, "failed to record project activity")
-                    })?;
-                }
-            } else {
-                record_project_activity(
-                    &state.pool,
-                    project_id,
-                    ctx.user_id,
-                    "project.file.save",
-                    Some(json!({
-                        "path": relative_path.to_string_lossy(),
-                    })),
-                )
-                .await
-                .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
-            }
-            Ok(saved)
+            store_project_file(
+                state,
+                ctx.user_id,
+                &project,
+                &relative_path,
+                &data,
+                &sha256,
+                params.message.as_deref(),
+            )
+            .await
         }
         "project.file.read" => {
-            ctx.require(Permission::FsRead)?;
-            let params: ProjectFilePathParams = parse_params(params)?;
+            let params: ProjectFileReadParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
             let project_id = parse_project_id(&params.project_id)?;
-            let _ = load_project(&state.pool, ctx, &project_id).await?;
+            let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
             let relative_path = normalize_project_path(&params.path)?;
-            let file = read_project_file(&state.pool, &project_id, &relative_path).await?;
-            Ok(file)
+            read_project_file(
+                &state.pool,
+                &project_id,
+                &relative_path,
+                params.if_none_match.as_deref(),
+            )
+            .await
+        }
+        "project.file.history" => {
+            let params: ProjectFileHistoryParams = parse_params(params)?;
+            ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
+            let project_id = parse_project_id(&params.project_id)?;
+            let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+            let relative_path = normalize_project_path(&params.path)?;
+            let limit = params
+                .limit
+                .unwrap_or(state.project_version_limit)
+                .clamp(1, state.project_version_limit.max(1));
+            project_file_history(&state.pool, &project_id, &relative_path, limit).await
+        }
+        "project.file.restore" => {
+            let params: ProjectFileRestoreParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
+            let project_id = parse_project_id(&params.project_id)?;
+            let project = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+            let relative_path = normalize_project_path(&params.path)?;
+            let data = load_project_file_version(
+                &state.pool,
+                &project_id,
+                &relative_path,
+                params.version_id,
+            )
+            .await?;
+            let sha256 = Sha256::digest(&data);
+            // An empty message skips the save entry; the restore records its own.
+            let saved = store_project_file(
+                state,
+                ctx.user_id,
+                &project,
+                &relative_path,
+                &data,
+                &sha256,
+                Some(""),
+            )
+            .await?;
+            record_project_activity(
+                state,
+                project_id,
+                ctx.user_id,
+                "project.file.restore",
+                Some(json!({
+                    "path": relative_path.to_string_lossy(),
+                    "version_id": params.version_id,
+                })),
+            )
+            .await
+            .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+            Ok(saved)
         }
         "project.file.delete" => {
-            ctx.require(Permission::FsWrite)?;
             let params: ProjectFilePathParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
             let project_id = parse_project_id(&params.project_id)?;
-            let _ = load_project(&state.pool, ctx, &project_id).await?;
+            let project = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
             let relative_path = normalize_project_path(&params.path)?;
-            delete_project_file(&state.pool, &project_id, &relative_path).await?;
-            let project_root = project_directory_relative(&project_id).join(&relative_path);
+            delete_project_file(
+                &state.pool,
+                &project_id,
+                &relative_path,
+                state.project_version_limit,
+            )
+            .await?;
+            state.project_cache.invalidate_listings(&project_id);
+            let project_root =
+                project_directory_relative(project.tenant_id, &project_id).join(&relative_path);
             state.sandbox.delete(project_root).map_err(|err| {
-                RpcMethodError::from_sandbox(-32053, "failed to delete project file", err)
+                RpcMethodError::from_sandbox(
+                    ErrorCode::ProjectFileDelete,
+                    "failed to delete project file",
+                    err,
+                )
             })?;
             record_project_activity(
-                &state.pool,
+                state,
                 project_id,
                 ctx.user_id,
                 "project.file.delete",
@@ -891,209 +1951,84 @@ async fn process_request(
             .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
             Ok(json!({ "status": "ok" }))
         }
-        "run.exec" => {
-            ctx.require(Permission::Execute)?;
-            let params: RunExecParams = parse_params(params)?;
-            let request = params.into_request()?;
-            let result = state.run.execute(request).await.map_err(|err| {
-                RpcMethodError::from_sandbox(-32010, "failed to execute process", err)
-            })?;
-            Ok(json!({
-                "exit_code": result.exit_code,
-                "stdout": BASE64.encode(result.stdout),
-                "stderr": BASE64.encode(result.stderr),
-                "duration_ms": result.duration.as_millis()
-            }))
+        "workspace.create" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: WorkspaceCreateParams = parse_params(params)?;
+            workspace::create(&state.pool, &state.sandbox, &state.workspaces, ctx, params).await
         }
-        "run.describe" => {
+        "workspace.list" => {
             ctx.require(Permission::FsRead)?;
-            let config = state.run.config();
-            let allowed: Vec<String> = config.allowed_programs().cloned().collect();
-            Ok(json!({
-                "root": config.root().display().to_string(),
-                "allowed_programs": allowed,
-                "default_timeout_ms": config.default_timeout().as_millis(),
-                "max_timeout_ms": config.max_timeout().as_millis(),
-                "max_output_bytes": config.max_output_bytes()
-            }))
+            workspace::list(&state.pool, ctx).await
         }
-        "wasm.invoke" => {
-            ctx.require(Permission::Execute)?;
-            let params: WasmInvokeParams = parse_params(params)?;
-            let module_source = resolve_wasm_module(&params)?;
-            let wasm_params = params
-                .params
-                .into_iter()
-                .map(WasmParam::into_value)
-                .collect::<std::result::Result<Vec<_>, _>>()
-                .map_err(|err| RpcMethodError::new(-32602, err.as_str(), None))?;
-
-            let mut invocation =
-                WasmInvocation::new(module_source, params.function).with_params(wasm_params);
-            if let Some(fuel) = params.fuel {
-                invocation = invocation.with_fuel(fuel);
-            }
-            if let Some(memory) = params.memory_limit {
-                invocation = invocation.with_memory_limit(memory);
-            }
-            if let Some(table) = params.table_elements_limit {
-                invocation = invocation.with_table_elements_limit(table);
-            }
-
-            let values = state.wasm.invoke(invocation).map_err(|err| {
-                RpcMethodError::from_sandbox(-32020, "failed to execute wasm", err)
-            })?;
-            let serialized: Vec<Value> = values.into_iter().map(wasm_value_to_json).collect();
-            Ok(json!({ "values": serialized }))
-        }
-        "wasm.describe" => {
-            ctx.require(Permission::FsRead)?;
-            let config = state.wasm.config();
-            Ok(json!({
-                "root": config.root().display().to_string(),
-                "max_memory_bytes": config.max_memory_bytes(),
-                "max_table_elements": config.max_table_elements(),
-                "default_fuel": config.default_fuel(),
-            }))
+        "workspace.destroy" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: WorkspaceIdParams = parse_params(params)?;
+            workspace::destroy(&state.pool, &state.sandbox, &state.engines, ctx, params).await
         }
-        "micro.start" => {
-            ctx.require(Permission::Execute)?;
-            let params: MicroStartParams = parse_params(params)?;
-            let init_script = match params.init_script {
-                Some(ref value) if !value.is_empty() => {
-                    let bytes = BASE64.decode(value.as_bytes()).map_err(|err| {
-                        RpcMethodError::new(
-                            -32602,
-                            "invalid base64 payload",
-                            Some(json!({ "detail": err.to_string() })),
-                        )
-                    })?;
-                    Some(String::from_utf8(bytes).map_err(|err| {
-                        RpcMethodError::new(
-                            -32602,
-                            "init script must be valid utf-8",
-                            Some(json!({ "detail": err.to_string() })),
-                        )
-                    })?)
-                }
-                _ => None,
-            };
-            let request = MicroStartRequest {
-                image: params.image,
-                init_script,
-            };
-            let instance = state.micro.start(request).await.map_err(|err| {
-                RpcMethodError::from_sandbox(-32030, "failed to start micro vm", err)
-            })?;
-            Ok(json!({
-                "vm_id": instance.id().to_string(),
-                "image": instance.image().to_string(),
-                "working_dir": instance.workdir().display().to_string(),
-            }))
+        "webhook.create" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: WebhookCreateParams = parse_params(params)?;
+            state.webhooks.create(ctx, params).await
         }
-        "micro.execute" => {
-            ctx.require(Permission::Execute)?;
-            let params: MicroExecuteParams = parse_params(params)?;
-            let vm_id = Uuid::parse_str(&params.vm_id).map_err(|err| {
-                RpcMethodError::new(
-                    -32602,
-                    "invalid vm identifier",
-                    Some(json!({ "detail": err.to_string() })),
-                )
-            })?;
-            let code_bytes = BASE64.decode(params.code.as_bytes()).map_err(|err| {
-                RpcMethodError::new(
-                    -32602,
-                    "invalid base64 payload",
-                    Some(json!({ "detail": err.to_string() })),
-                )
-            })?;
-            let code = String::from_utf8(code_bytes).map_err(|err| {
-                RpcMethodError::new(
-                    -32602,
-                    "code must be valid utf-8",
-                    Some(json!({ "detail": err.to_string() })),
-                )
-            })?;
-            let request = MicroExecuteRequest {
-                vm_id,
-                code,
-                timeout: params.timeout_ms.map(Duration::from_millis),
-            };
-            let result = state.micro.execute(request).await.map_err(|err| {
-                RpcMethodError::from_sandbox(-32031, "failed to execute micro vm code", err)
-            })?;
-            Ok(json!({
-                "exit_code": result.exit_code,
-                "stdout": BASE64.encode(result.stdout),
-                "stderr": BASE64.encode(result.stderr),
-                "duration_ms": result.duration.as_millis(),
-            }))
+        "webhook.list" => {
+            ctx.require(Permission::FsRead)?;
+            state.webhooks.list(ctx).await
         }
-        "micro.stop" => {
-            ctx.require(Permission::Execute)?;
-            let params: MicroStopParams = parse_params(params)?;
-            let vm_id = Uuid::parse_str(&params.vm_id).map_err(|err| {
-                RpcMethodError::new(
-                    -32602,
-                    "invalid vm identifier",
-                    Some(json!({ "detail": err.to_string() })),
-                )
-            })?;
-            state.micro.stop(vm_id).await.map_err(|err| {
-                RpcMethodError::from_sandbox(-32032, "failed to stop micro vm", err)
-            })?;
-            Ok(json!({ "status": "ok" }))
+        "webhook.delete" => {
+            ctx.require(Permission::FsWrite)?;
+            let params: WebhookIdParams = parse_params(params)?;
+            state.webhooks.delete(ctx, params).await
         }
-        "micro.describe" => {
+        "quota.status" => {
             ctx.require(Permission::FsRead)?;
-            let config = state.micro.config();
-            let images: Vec<Value> = config
-                .images()
-                .map(|image| {
-                    json!({
-                        "name": image.name(),
-                        "command": image.command(),
-                        "args": image.args().cloned().collect::<Vec<_>>(),
-                        "extension": image.extension(),
-                        "env": image
-                            .env()
-                            .map(|(key, value)| json!({ "key": key, "value": value }))
-                            .collect::<Vec<_>>(),
-                    })
-                })
-                .collect();
-            let base_env: Vec<Value> = config
-                .base_env()
-                .iter()
-                .map(|(key, value)| json!({ "key": key, "value": value }))
-                .collect();
-            Ok(json!({
-                "root": config.root().display().to_string(),
-                "default_timeout_ms": config.default_timeout().as_millis(),
-                "max_timeout_ms": config.max_timeout().as_millis(),
-                "max_output_bytes": config.max_output_bytes(),
-                "images": images,
-                "base_env": base_env,
-            }))
+            let params: QuotaStatusParams = parse_params(params)?;
+            quota::status(state, ctx, params).await
         }
         "llm.chat" => {
             ctx.require(Permission::LlmUse)?;
             ctx.ensure_tokens()?;
             let params: LlmChatParams = parse_params(params)?;
-            state.llm.chat(ctx, params).await
+            let model = params.model.clone();
+            llm_inference(state, ctx, &method, &model, None, || {
+                state.llm.chat(ctx, params)
+            })
+            .await
         }
-        "llm.completion" | "llm.completions" => {
+        "llm.completion" => {
             ctx.require(Permission::LlmUse)?;
             ctx.ensure_tokens()?;
             let params: LlmCompletionParams = parse_params(params)?;
-            state.llm.completion(ctx, params).await
+            // Sampled completions differ per call; only greedy decoding is
+            // worth caching.
+            let key = (params.temperature == Some(0.0))
+                .then(|| {
+                    state
+                        .llm_cache
+                        .key(ctx.user_id, &method, &params.model, &params)
+                })
+                .flatten();
+            let model = params.model.clone();
+            llm_inference(state, ctx, &method, &model, key, || {
+                state.llm.completion(ctx, params)
+            })
+            .await
         }
         "llm.embed" => {
             ctx.require(Permission::LlmUse)?;
             ctx.ensure_tokens()?;
             let params: LlmEmbedParams = parse_params(params)?;
-            state.llm.embed(ctx, params).await
+            let key = state
+                .llm_cache
+                .key(ctx.user_id, &method, &params.model, &params);
+            let model = params.model.clone();
+            llm_inference(state, ctx, &method, &model, key, || {
+                state.llm.embed(ctx, params)
+            })
+            .await
+        }
+        "llm.usage" => {
+            let params: LlmUsageParams = parse_params(params)?;
+            llm_usage::usage(&state.pool, ctx, params).await
         }
         "llm.list_models" => {
             ctx.require(Permission::LlmAdmin)?;
@@ -1126,30 +2061,44 @@ async fn process_request(
         "agent.history" => {
             ctx.require(Permission::AgentView)?;
             let params: AgentHistoryParams = parse_params(params)?;
-            let mut limit = params.limit.unwrap_or(20);
-            if limit == 0 {
-                limit = 1;
-            }
-            if limit > 256 {
-                limit = 256;
+            let paged = params.paged();
+            let mut query = params.into_query(ctx)?;
+            if paged {
+                query.max_bytes = state.response_budget.page_bytes();
             }
-            let history = state.agents.history(limit);
-            Ok(serde_json::to_value(history).expect("serialize history"))
+            let page = state.agents.history_page(&query).map_err(|err| {
+                RpcMethodError::from_sandbox(
+                    ErrorCode::AgentHistory,
+                    "failed to load agent history",
+                    err,
+                )
+            })?;
+            let history = if paged {
+                serde_json::to_value(page)
+            } else {
+                serde_json::to_value(page.entries)
+            };
+            Ok(history.expect("serialize history"))
         }
         "agent.status" => {
             ctx.require(Permission::AgentView)?;
             let params: AgentStatusParams = parse_params(params)?;
             let task_id = Uuid::parse_str(&params.task_id).map_err(|err| {
                 RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "invalid task identifier",
                     Some(json!({ "detail": err.to_string() })),
                 )
             })?;
-            let snapshot = state
-                .agents
-                .status(&task_id)
-                .ok_or_else(|| RpcMethodError::new(-32041, "agent task not found", None))?;
+            if let Some(runner) = state.runners.pinned(&task_id) {
+                return state
+                    .runners
+                    .call(runner, Call::AgentStatus { task_id })
+                    .await;
+            }
+            let snapshot = state.agents.status(&task_id).ok_or_else(|| {
+                RpcMethodError::new(ErrorCode::AgentTaskNotFound, "agent task not found", None)
+            })?;
             Ok(serde_json::to_value(snapshot).expect("serialize status"))
         }
         "agent.cancel" => {
@@ -1157,18 +2106,69 @@ async fn process_request(
             let params: AgentStatusParams = parse_params(params)?;
             let task_id = Uuid::parse_str(&params.task_id).map_err(|err| {
                 RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "invalid task identifier",
                     Some(json!({ "detail": err.to_string() })),
                 )
             })?;
+            if let Some(runner) = state.runners.pinned(&task_id) {
+                return state
+                    .runners
+                    .call(runner, Call::AgentCancel { task_id })
+                    .await;
+            }
             let snapshot = state.agents.cancel(&task_id).map_err(|err| {
-                RpcMethodError::from_sandbox(-32042, "failed to cancel agent", err)
+                RpcMethodError::from_sandbox(ErrorCode::AgentCancel, "failed to cancel agent", err)
+            })?;
+            Ok(serde_json::to_value(snapshot).expect("serialize status"))
+        }
+        "agent.respond" => {
+            ctx.require(Permission::AgentControl)?;
+            let params: AgentRespondParams = parse_params(params)?;
+            let task_id = Uuid::parse_str(&params.task_id).map_err(|err| {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid task identifier",
+                    Some(json!({ "detail": err.to_string() })),
+                )
             })?;
+            if let Some(runner) = state.runners.pinned(&task_id) {
+                let answer = params.answer;
+                return state
+                    .runners
+                    .call(runner, Call::AgentRespond { task_id, answer })
+                    .await;
+            }
+            let snapshot =
+                state
+                    .agents
+                    .respond(&task_id, params.answer)
+                    .map_err(|err| match err {
+                        SandboxError::AgentTaskNotFound(_) => RpcMethodError::new(
+                            ErrorCode::AgentTaskNotFound,
+                            "agent task not found",
+                            None,
+                        ),
+                        other => RpcMethodError::from_sandbox(
+                            ErrorCode::AgentResume,
+                            "failed to resume agent task",
+                            other,
+                        ),
+                    })?;
             Ok(serde_json::to_value(snapshot).expect("serialize status"))
         }
+        "agent.apply" => {
+            let params: AgentApplyParams = parse_params(params)?;
+            ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
+            if params.run_commands {
+                ctx.require_for(Permission::Execute, Some(params.project_id.as_str()))?;
+                ctx.ensure_tokens()?;
+            }
+            apply_agent_task(state, ctx, &method, params).await
+        }
         "agent.dispatch" => {
             ctx.require(Permission::AgentControl)?;
+            ctx.ensure_tokens()?;
             let params: AgentDispatchParams = parse_params(params)?;
             let AgentDispatchParams {
                 agent,
@@ -1177,12 +2177,46 @@ async fn process_request(
                 model,
                 metadata,
                 parameters,
+                fan_out,
+                system_prompt,
+                persona,
             } = params;
-            let context = build_agent_context(&state.sandbox, context).map_err(|err| {
-                RpcMethodError::from_sandbox(-32043, "failed to prepare agent context", err)
+            if system_prompt.is_some() {
+                ctx.require(Permission::AgentAdmin)?;
+            }
+            let sandbox = tenant_fs(state, ctx)?;
+            let mut context = build_agent_context(&sandbox, context).map_err(|err| {
+                RpcMethodError::from_sandbox(
+                    ErrorCode::AgentContext,
+                    "failed to prepare agent context",
+                    err,
+                )
             })?;
+            let subtasks = match fan_out {
+                Some(AgentFanOut::Files) => {
+                    if context.files.is_empty() {
+                        return Err(RpcMethodError::new(
+                            ErrorCode::InvalidParams,
+                            "fan_out requires context files",
+                            None,
+                        ));
+                    }
+                    std::mem::take(&mut context.files)
+                        .into_iter()
+                        .map(|file| AgentSubtask {
+                            objective: Some(format!("{objective}\n\nFocus on: {}", file.title)),
+                            context: AgentContext {
+                                notes: Vec::new(),
+                                files: vec![file],
+                            },
+                        })
+                        .collect()
+                }
+                None => Vec::new(),
+            };
             let parameters = parameters.map(AgentParameterOverrides::into_parameters);
             let metadata = enrich_agent_metadata(metadata, ctx);
+            let task_count = 1 + subtasks.len() as i64;
             let request = AgentDispatchRequest {
                 agent,
                 objective,
@@ -1190,217 +2224,279 @@ async fn process_request(
                 model,
                 metadata,
                 parameters,
+                subtasks,
+                system_prompt,
+                persona,
+                traceparent: telemetry::traceparent(ctx),
             };
-            let submission = state.agents.dispatch(request).map_err(|err| {
-                RpcMethodError::from_sandbox(-32040, "failed to dispatch agent", err)
-            })?;
-            Ok(json!({
-                "task_id": submission.id.to_string(),
-                "status": submission.status,
-            }))
+            let remote = Call::AgentDispatch {
+                request: Box::new(request.clone()),
+            };
+            let submission = match state.runners.offload(remote).await? {
+                Some((runner, submission)) => {
+                    if let Some(task_id) = submission["task_id"]
+                        .as_str()
+                        .and_then(|id| id.parse().ok())
+                    {
+                        state.runners.pin(task_id, runner);
+                    }
+                    submission
+                }
+                None => {
+                    let submission = state.agents.dispatch(request).map_err(|err| match err {
+                        SandboxError::RateLimited { retry_after } => {
+                            RpcMethodError::rate_limited(retry_after)
+                        }
+                        other => RpcMethodError::from_sandbox(
+                            ErrorCode::AgentDispatch,
+                            "failed to dispatch agent",
+                            other,
+                        ),
+                    })?;
+                    json!({
+                        "task_id": submission.id.to_string(),
+                        "status": submission.status,
+                    })
+                }
+            };
+            if let Some(task_id) = submission["task_id"]
+                .as_str()
+                .and_then(|id| id.parse().ok())
+            {
+                state
+                    .affinity
+                    .claim(task_id, affinity::HandleKind::Agent)
+                    .await;
+            }
+            state
+                .billing
+                .charge(ctx, &method, Charge::AgentTasks(task_count))
+                .await;
+            Ok(submission)
         }
-        _ => Err(RpcMethodError::new(-32601, "method not found", None)),
-    }
-}
-
-#[derive(Clone)]
-struct LlmClient {
-    http: Client,
-    base_url: String,
-    admin_token: Option<String>,
-}
-
-impl LlmClient {
-    fn from_env() -> anyhow::Result<Self> {
-        let base_url =
-            std::env::var("LLM_SERVER_URL").unwrap_or_else(|_| "http://127.0.0.1:6988".to_string());
-        let admin_token = std::env::var("LLM_SERVER_ADMIN_TOKEN").ok();
-        let timeout_secs = std::env::var("LLM_HTTP_TIMEOUT_SECS")
-            .ok()
-            .and_then(|value| value.parse::<u64>().ok())
-            .unwrap_or(30);
-        let http = Client::builder()
-            .timeout(Duration::from_secs(timeout_secs))
-            .build()?;
-        Ok(Self {
-            http,
-            base_url,
-            admin_token,
-        })
-    }
-
-    async fn chat(
-        &self,
-        ctx: &RequestContext,
-        params: LlmChatParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_user("/v1/chat/completions", &params, ctx).await
-    }
-
-    async fn completion(
-        &self,
-        ctx: &RequestContext,
-        params: LlmCompletionParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_user("/v1/completions", &params, ctx).await
-    }
-
-    async fn embed(
-        &self,
-        ctx: &RequestContext,
-        params: LlmEmbedParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_user("/v1/embeddings", &params, ctx).await
-    }
-
-    async fn list_models(&self) -> std::result::Result<Value, RpcMethodError> {
-        self.get_admin("/admin/models").await
-    }
-
-    async fn status(&self) -> std::result::Result<Value, RpcMethodError> {
-        self.get_admin("/admin/status").await
-    }
-
-    async fn download(
-        &self,
-        ctx: &RequestContext,
-        params: &LlmModelParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_admin("/admin/download", params, Some(ctx)).await
-    }
-
-    async fn load(
-        &self,
-        ctx: &RequestContext,
-        params: LlmAdminLoadParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_admin("/admin/load", &params, Some(ctx)).await
-    }
-
-    async fn unload(
-        &self,
-        ctx: &RequestContext,
-        params: &LlmModelParams,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.post_admin("/admin/unload", params, Some(ctx)).await
-    }
-
-    async fn post_user<T: Serialize>(
-        &self,
-        path: &str,
-        body: &T,
-        ctx: &RequestContext,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        let request_id = Uuid::new_v4();
-        self.send_request(
-            Method::POST,
-            path,
-            Some(body),
-            Some(ctx),
-            false,
-            Some(request_id),
-        )
-        .await
-    }
-
-    async fn post_admin<T: Serialize>(
-        &self,
-        path: &str,
-        body: &T,
-        ctx: Option<&RequestContext>,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        self.send_request(
-            Method::POST,
-            path,
-            Some(body),
-            ctx,
-            true,
-            Some(Uuid::new_v4()),
-        )
-        .await
-    }
-
-    async fn get_admin(&self, path: &str) -> std::result::Result<Value, RpcMethodError> {
-        self.send_request::<Value>(Method::GET, path, None, None, true, Some(Uuid::new_v4()))
-            .await
-    }
-
-    async fn send_request<T: Serialize>(
-        &self,
-        method: Method,
-        path: &str,
-        body: Option<&T>,
-        ctx: Option<&RequestContext>,
-        admin: bool,
-        request_id: Option<Uuid>,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        let url = format!(
-            "{}/{}",
-            self.base_url.trim_end_matches('/'),
-            path.trim_start_matches('/')
-        );
-        let mut builder = self.http.request(method, url);
-        if let Some(ctx) = ctx {
-            builder = builder.header("X-User-Id", ctx.user_id.to_string()).header(
-                "X-Request-Id",
-                request_id.unwrap_or_else(Uuid::new_v4).to_string(),
-            );
-        } else if let Some(request_id) = request_id {
-            builder = builder.header("X-Request-Id", request_id.to_string());
+        "agent.pipeline" => {
+            ctx.require(Permission::AgentControl)?;
+            let params: AgentPipelineParams = parse_params(params)?;
+            pipeline::start(state, ctx, &method, params).await
         }
-        if admin {
-            let token = self
-                .admin_token
-                .as_ref()
-                .ok_or_else(|| RpcMethodError::internal("LLM_SERVER_ADMIN_TOKEN not configured"))?;
-            builder = builder.header(AUTHORIZATION, format!("Bearer {token}"));
+        "billing.usage" => {
+            let params: BillingUsageParams = parse_params(params)?;
+            billing::usage(&state.pool, ctx, params).await
         }
-        if let Some(body) = body {
-            builder = builder.json(body);
+        "billing.ledger" => {
+            let params: BillingLedgerParams = parse_params(params)?;
+            billing::ledger(&state.pool, ctx, params).await
         }
-        let response = builder
-            .send()
-            .await
-            .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
-        self.handle_response(response).await
+        "audit.query" => {
+            ctx.require(Permission::AuditView)?;
+            let params: AuditQueryParams = parse_params(params)?;
+            audit::query(&state.pool, ctx, params).await
+        }
+        "admin.users.list" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: AdminUsersListParams = parse_params(params)?;
+            admin::list(&state.pool, ctx, params).await
+        }
+        "admin.users.setRole" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: AdminSetRoleParams = parse_params(params)?;
+            admin::set_role(&state.pool, &state.auth_cache, ctx, params).await
+        }
+        "admin.users.setTokenBalance" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: AdminSetTokenBalanceParams = parse_params(params)?;
+            admin::set_token_balance(&state.pool, ctx, &method, params).await
+        }
+        "admin.users.disable" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: AdminDisableParams = parse_params(params)?;
+            admin::disable(&state.pool, &state.auth_cache, ctx, params).await
+        }
+        "admin.roles.list" => {
+            ctx.require(Permission::UserAdmin)?;
+            rbac::list_roles(&state.pool, ctx).await
+        }
+        "admin.roles.set" => {
+            ctx.require(Permission::UserAdmin)?;
+            ctx.require_operator()?;
+            let params: RoleSetParams = parse_params(params)?;
+            rbac::set_role(&state.rbac, params).await
+        }
+        "admin.roles.delete" => {
+            ctx.require(Permission::UserAdmin)?;
+            ctx.require_operator()?;
+            let params: RoleNameParams = parse_params(params)?;
+            rbac::delete_role(&state.rbac, params).await
+        }
+        "admin.grants.list" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: GrantListParams = parse_params(params)?;
+            rbac::list_grants(&state.pool, ctx, params).await
+        }
+        "admin.grants.add" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: GrantAddParams = parse_params(params)?;
+            let grant = rbac::add_grant(&state.rbac, ctx, params).await?;
+            if let (Some(user_id), Some(project_id)) = (
+                grant["user_id"]
+                    .as_i64()
+                    .and_then(|id| i32::try_from(id).ok()),
+                grant["project_id"].as_str(),
+            ) {
+                state.events.publish(DomainEvent::ProjectShared {
+                    user_id,
+                    project_id: project_id.to_string(),
+                    permission: grant["permission"].clone(),
+                    shared_by: ctx.username.clone(),
+                });
+            }
+            Ok(grant)
+        }
+        "admin.grants.revoke" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: GrantIdParams = parse_params(params)?;
+            rbac::revoke_grant(&state.rbac, ctx, params).await
+        }
+        "admin.tokens.revoke" => {
+            ctx.require(Permission::UserAdmin)?;
+            let params: TokenRevokeParams = parse_params(params)?;
+            revocation::revoke(&state.revocations, &state.auth_cache, ctx, params).await
+        }
+        "admin.schedules.list" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            scheduler::list(&state.pool).await
+        }
+        "admin.schedules.update" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: ScheduleUpdateParams = parse_params(params)?;
+            scheduler::update(&state.pool, params).await
+        }
+        "admin.schedules.run" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: ScheduleNameParams = parse_params(params)?;
+            scheduler::run_now(&state.pool, params).await
+        }
+        "admin.runners.list" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            Ok(state.runners.list())
+        }
+        "admin.sandbox.reload" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            state.reloader.reload(state).await
+        }
+        "admin.flags.list" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            flags::list(&state.flags).await
+        }
+        "admin.flags.set" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: FlagSetParams = parse_params(params)?;
+            flags::set(&state.flags, ctx, params).await
+        }
+        "admin.flags.clear" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: FlagClearParams = parse_params(params)?;
+            flags::clear(&state.flags, params).await
+        }
+        "admin.rateLimits.list" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            rate_limit::list(&state.rate_limits).await
+        }
+        "admin.rateLimits.set" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: RateLimitSetParams = parse_params(params)?;
+            rate_limit::set(&state.rate_limits, ctx, params).await
+        }
+        "admin.rateLimits.clear" => {
+            ctx.require(Permission::SystemAdmin)?;
+            ctx.require_operator()?;
+            let params: RateLimitClearParams = parse_params(params)?;
+            rate_limit::clear(&state.rate_limits, params).await
+        }
+        "errors.recent" => {
+            ctx.require(Permission::SystemAdmin)?;
+            let params: ErrorsRecentParams = parse_params(params)?;
+            error_log::recent(&state.errors, ctx, params)
+        }
+        "notify.list" => {
+            let params: NotifyListParams = parse_params(params)?;
+            notify::list(&state.notifier, ctx, params).await
+        }
+        "notify.markRead" => {
+            let params: NotifyMarkReadParams = parse_params(params)?;
+            notify::mark_read(&state.notifier, ctx, params).await
+        }
+        "job.status" => {
+            let params: JobIdParams = parse_params(params)?;
+            jobs::status(&state.pool, ctx, params).await
+        }
+        "job.list" => {
+            let params: JobListParams = parse_params(params)?;
+            jobs::list(&state.pool, ctx, params).await
+        }
+        "job.cancel" => {
+            let params: JobIdParams = parse_params(params)?;
+            jobs::cancel(&state.pool, ctx, params).await
+        }
+        "job.retry" => {
+            let params: JobIdParams = parse_params(params)?;
+            jobs::retry(&state.jobs, ctx, params).await
+        }
+        "rpc.discover" => Ok(openrpc::document().clone()),
+        "rpc.errors" => Ok(errors::catalog()),
+        _ => engine::dispatch(state, ctx, &method, params).await,
     }
+}
 
-    async fn handle_response(
-        &self,
-        response: reqwest::Response,
-    ) -> std::result::Result<Value, RpcMethodError> {
-        let status = response.status();
-        let bytes = response
-            .bytes()
-            .await
-            .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
-        let body: Value = serde_json::from_slice(&bytes).unwrap_or_else(
-            |_| json!({ "error": String::from_utf8_lossy(&bytes).trim().to_string() }),
-        );
-        if status.is_success() {
-            return Ok(body);
-        }
-        let message = body
-            .get("error")
-            .and_then(|value| value.as_str())
-            .filter(|value| !value.is_empty())
-            .unwrap_or_else(|| status.canonical_reason().unwrap_or("request failed"));
-        let error = match status {
-            HttpStatus::UNAUTHORIZED => RpcMethodError::unauthorized(message),
-            HttpStatus::FORBIDDEN => RpcMethodError::forbidden(message),
-            HttpStatus::TOO_MANY_REQUESTS => RpcMethodError::new(
-                -32093,
-                "insufficient token balance",
-                Some(json!({ "detail": message })),
-            ),
-            HttpStatus::NOT_FOUND => RpcMethodError::new(-32044, message, Some(body.clone())),
-            _ => RpcMethodError::internal(message),
-        };
-        Err(error)
+/// Runs one llm inference call through the response cache, records it in
+/// `llm_usage` and bills it. Cache hits never reached the provider, so they
+/// are billed here from the cached `usage` even when the provider settles.
+async fn llm_inference<F, Fut>(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    model: &str,
+    key: Option<CacheKey>,
+    load: F,
+) -> Result<Value, RpcMethodError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Value, RpcMethodError>>,
+{
+    let entry = UsageEntry::start(method, model, state.llm.provider(model));
+    let provider = entry.provider();
+    let outcome = state.llm_cache.get_or_load(key, load).await;
+    match &outcome {
+        Ok((response, false)) => {
+            entry.finish(&state.pool, ctx, Outcome::Served(response));
+            state
+                .billing
+                .charge(ctx, method, Charge::llm(response, provider.settles_usage))
+                .await;
+        }
+        Ok((response, true)) => {
+            entry.finish(&state.pool, ctx, Outcome::Cached);
+            state
+                .billing
+                .charge(ctx, method, Charge::llm(response, false))
+                .await;
+        }
+        Err(err) => entry.finish(&state.pool, ctx, Outcome::Failed(err)),
     }
+    outcome.map(|(response, _)| response)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 struct LlmChatParams {
     model: String,
@@ -1422,13 +2518,13 @@ struct LlmChatParams {
     max_tokens: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 struct LlmChatMessage {
     role: String,
     content: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 struct LlmCompletionParams {
     model: String,
@@ -1450,26 +2546,26 @@ struct LlmCompletionParams {
     max_tokens: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 struct LlmEmbedParams {
     model: String,
     input: LlmEmbedInput,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 enum LlmEmbedInput {
     Text(String),
     Batch(Vec<String>),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 struct LlmModelParams {
     model: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 struct LlmAdminLoadParams {
     model: String,
@@ -1494,6 +2590,7 @@ struct LlmAdminLoadParams {
 struct ProjectRecord {
     id: Uuid,
     owner_id: i32,
+    tenant_id: i32,
     name: String,
     description: Option<String>,
     created_at: DateTime<Utc>,
@@ -1517,14 +2614,14 @@ fn normalize_project_name(name: &str) -> std::result::Result<String, RpcMethodEr
     let trimmed = name.trim();
     if trimmed.is_empty() {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project name is required",
             None,
         ));
     }
     if trimmed.len() > 128 {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project name must be at most 128 characters",
             Some(json!({ "max": 128 })),
         ));
@@ -1541,14 +2638,88 @@ fn truncate_description(value: &str) -> String {
     result
 }
 
-fn project_directory_relative(project_id: &Uuid) -> PathBuf {
-    PathBuf::from("projects").join(project_id.to_string())
+fn project_directory_relative(tenant_id: i32, project_id: &Uuid) -> PathBuf {
+    tenant::directory_relative(tenant_id)
+        .join("projects")
+        .join(project_id.to_string())
+}
+
+fn user_directory_relative(tenant_id: i32, user_id: i32) -> PathBuf {
+    tenant::directory_relative(tenant_id)
+        .join("users")
+        .join(user_id.to_string())
+}
+
+/// Directory below the sandbox root that an fs/run/micro call is confined to.
+/// Calls naming a project or workspace session are rooted at it once access is
+/// checked; other calls get a per-user directory. Admins get their tenant's
+/// directory; nobody sees the shared root.
+async fn sandbox_scope(
+    state: &AppState,
+    ctx: &RequestContext,
+    project_id: Option<&str>,
+    workspace_id: Option<&str>,
+    permission: Permission,
+) -> std::result::Result<PathBuf, RpcMethodError> {
+    match (project_id, workspace_id) {
+        (Some(_), Some(_)) => {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "project_id and workspace_id are mutually exclusive",
+                None,
+            ));
+        }
+        (Some(project_id), None) => {
+            let project_id = parse_project_id(project_id)?;
+            let project = load_project(state, ctx, &project_id, permission).await?;
+            return Ok(project_directory_relative(project.tenant_id, &project_id));
+        }
+        (None, Some(workspace_id)) => {
+            return workspace::resolve(&state.pool, ctx, workspace_id).await;
+        }
+        (None, None) => {}
+    }
+    if ctx.is_admin() {
+        Ok(tenant::directory_relative(ctx.tenant_id))
+    } else {
+        Ok(user_directory_relative(ctx.tenant_id, ctx.user_id))
+    }
+}
+
+async fn scoped_fs(
+    state: &AppState,
+    ctx: &RequestContext,
+    project_id: Option<&str>,
+    workspace_id: Option<&str>,
+    permission: Permission,
+) -> std::result::Result<SandboxFs, RpcMethodError> {
+    let scope = sandbox_scope(state, ctx, project_id, workspace_id, permission).await?;
+    state.sandbox.scoped(scope).map_err(scope_error)
+}
+
+/// The caller's tenant directory, which agent context paths are relative to.
+fn tenant_fs(
+    state: &AppState,
+    ctx: &RequestContext,
+) -> std::result::Result<SandboxFs, RpcMethodError> {
+    state
+        .sandbox
+        .scoped(tenant::directory_relative(ctx.tenant_id))
+        .map_err(scope_error)
+}
+
+fn scope_error(err: SandboxError) -> RpcMethodError {
+    RpcMethodError::from_sandbox(
+        ErrorCode::SandboxScope,
+        "failed to prepare sandbox scope",
+        err,
+    )
 }
 
 fn parse_project_id(value: &str) -> std::result::Result<Uuid, RpcMethodError> {
     Uuid::parse_str(value).map_err(|err| {
         RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "invalid project identifier",
             Some(json!({ "detail": err.to_string() })),
         )
@@ -1559,14 +2730,14 @@ fn normalize_project_path(path: &str) -> std::result::Result<PathBuf, RpcMethodE
     let trimmed = path.trim();
     if trimmed.is_empty() {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project path is required",
             None,
         ));
     }
     if trimmed.len() > 512 {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project path must be at most 512 characters",
             Some(json!({ "max": 512 })),
         ));
@@ -1574,7 +2745,7 @@ fn normalize_project_path(path: &str) -> std::result::Result<PathBuf, RpcMethodE
     let candidate = Path::new(trimmed);
     if candidate.is_absolute() {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project paths must be relative",
             Some(json!({ "path": trimmed })),
         ));
@@ -1586,7 +2757,7 @@ fn normalize_project_path(path: &str) -> std::result::Result<PathBuf, RpcMethodE
             Component::CurDir => continue,
             _ => {
                 return Err(RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "project path cannot traverse parents",
                     Some(json!({ "path": trimmed })),
                 ))
@@ -1595,14 +2766,43 @@ fn normalize_project_path(path: &str) -> std::result::Result<PathBuf, RpcMethodE
     }
     if normalized.as_os_str().is_empty() {
         return Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "project path cannot resolve to empty",
             Some(json!({ "path": trimmed })),
         ));
     }
+    git::ensure_outside_git_dir(trimmed)?;
     Ok(normalized)
 }
 
+/// Creates a project row and its sandbox directory after the name and
+/// quota checks. Shared by `project.create` and `project.import`.
+async fn provision_project(
+    state: &AppState,
+    ctx: &RequestContext,
+    name: &str,
+    description: Option<&str>,
+) -> std::result::Result<ProjectRecord, RpcMethodError> {
+    let name = normalize_project_name(name)?;
+    let description = description.map(truncate_description);
+    quota::ensure_project_slot(state, ctx).await?;
+    let record = create_project(&state.pool, ctx, &name, description.as_deref()).await?;
+    let project_root = project_directory_relative(record.tenant_id, &record.id);
+    state.sandbox.mkdir(&project_root).map_err(|err| {
+        RpcMethodError::from_sandbox(ErrorCode::ProjectPrepare, "failed to prepare project", err)
+    })?;
+    record_project_activity(
+        state,
+        record.id,
+        ctx.user_id,
+        "project.created",
+        Some(json!({ "name": record.name })),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(record)
+}
+
 async fn create_project(
     pool: &PgPool,
     ctx: &RequestContext,
@@ -1610,9 +2810,10 @@ async fn create_project(
     description: Option<&str>,
 ) -> std::result::Result<ProjectRecord, RpcMethodError> {
     let row = sqlx::query(
-        "INSERT INTO projects (user_id, name, description) VALUES ($1, $2, $3) RETURNING id, user_id, name, description, created_at, updated_at",
+        "INSERT INTO projects (user_id, tenant_id, name, description) VALUES ($1, $2, $3, $4) RETURNING id, user_id, tenant_id, name, description, created_at, updated_at",
     )
     .bind(ctx.user_id)
+    .bind(ctx.tenant_id)
     .bind(name)
     .bind(description)
     .fetch_one(pool)
@@ -1620,7 +2821,7 @@ async fn create_project(
     .map_err(|err| match &err {
         SqlxError::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
             RpcMethodError::new(
-                -32052,
+                ErrorCode::ProjectConflict,
                 "a project with this name already exists",
                 Some(json!({ "name": name })),
             )
@@ -1628,14 +2829,254 @@ async fn create_project(
         _ => RpcMethodError::internal(&format!("failed to create project: {err}")),
     })?;
 
-    Ok(ProjectRecord {
-        id: row.get("id"),
-        owner_id: row.get("user_id"),
-        name: row.get("name"),
-        description: row.get("description"),
-        created_at: row.get("created_at"),
-        updated_at: row.get("updated_at"),
-    })
+    Ok(ProjectRecord {
+        id: row.get("id"),
+        owner_id: row.get("user_id"),
+        tenant_id: row.get("tenant_id"),
+        name: row.get("name"),
+        description: row.get("description"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    })
+}
+
+/// Substring search over file paths and UTF-8 file contents, backed by the
+/// trigram indexes on `project_files`. Without a project id the search spans
+/// every project the caller owns (all projects of the tenant for admins).
+async fn search_project_files(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    project_id: Option<Uuid>,
+    params: ProjectSearchParams,
+) -> std::result::Result<Value, RpcMethodError> {
+    let needle = params.query.trim().to_string();
+    if needle.is_empty() || needle.chars().count() > 256 {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "search query must be between 1 and 256 characters",
+            Some(json!({ "max": 256 })),
+        ));
+    }
+    let case_sensitive = params.case_sensitive.unwrap_or(false);
+    let scope = params.scope.unwrap_or(ProjectSearchScope::All);
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let max_matches = params.max_matches_per_file.unwrap_or(5).clamp(1, 50);
+    let owner = if ctx.is_admin() {
+        None
+    } else {
+        Some(ctx.user_id)
+    };
+    let operator = if case_sensitive { "LIKE" } else { "ILIKE" };
+    let sql = format!(
+        "SELECT f.project_id, p.name AS project_name, f.path, f.search_text, f.path {operator} $3 AS path_match \
+         FROM project_files f JOIN projects p ON p.id = f.project_id \
+         WHERE ($1::uuid IS NULL OR f.project_id = $1) \
+           AND ($2::int IS NULL OR p.user_id = $2) \
+           AND p.tenant_id = $7 \
+           AND (($4 AND f.path {operator} $3) OR ($5 AND f.search_text {operator} $3)) \
+         ORDER BY p.name, f.path LIMIT $6"
+    );
+    let rows = sqlx::query(&sql)
+        .bind(project_id)
+        .bind(owner)
+        .bind(format!("%{}%", escape_like(&needle)))
+        .bind(scope.includes_path())
+        .bind(scope.includes_content())
+        .bind(limit)
+        .bind(ctx.tenant_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to search projects: {err}")))?;
+
+    let results: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            let text: Option<String> = row.get("search_text");
+            let matches = match (scope.includes_content(), text) {
+                (true, Some(text)) => line_matches(&text, &needle, case_sensitive, max_matches),
+                _ => Vec::new(),
+            };
+            json!({
+                "project_id": row.get::<Uuid, _>("project_id"),
+                "project_name": row.get::<String, _>("project_name"),
+                "path": row.get::<String, _>("path"),
+                "path_match": row.get::<bool, _>("path_match"),
+                "matches": matches,
+            })
+        })
+        .collect();
+    Ok(json!({ "query": needle, "results": results }))
+}
+
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+const SEARCH_SNIPPET_CHARS: usize = 200;
+
+/// Returns `{line, text}` for the first `max` lines containing `needle`,
+/// with line numbers starting at 1 and snippets capped in length.
+fn line_matches(text: &str, needle: &str, case_sensitive: bool, max: usize) -> Vec<Value> {
+    let folded_needle = needle.to_lowercase();
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            if case_sensitive {
+                line.contains(needle)
+            } else {
+                line.to_lowercase().contains(&folded_needle)
+            }
+        })
+        .take(max)
+        .map(|(index, line)| {
+            let snippet: String = line.trim().chars().take(SEARCH_SNIPPET_CHARS).collect();
+            json!({ "line": index + 1, "text": snippet })
+        })
+        .collect()
+}
+
+/// Merges an update request into the current record. A missing field keeps
+/// its value; an empty description clears it.
+fn resolve_project_update(
+    record: &ProjectRecord,
+    name: Option<String>,
+    description: Option<String>,
+) -> std::result::Result<(String, Option<String>), RpcMethodError> {
+    if name.is_none() && description.is_none() {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "project.update requires name, description or allowed_programs",
+            None,
+        ));
+    }
+    let name = match name {
+        Some(name) => normalize_project_name(&name)?,
+        None => record.name.clone(),
+    };
+    let description = match description {
+        Some(value) => Some(truncate_description(&value)).filter(|value| !value.is_empty()),
+        None => record.description.clone(),
+    };
+    Ok((name, description))
+}
+
+async fn update_project(
+    pool: &PgPool,
+    project_id: &Uuid,
+    name: &str,
+    description: Option<&str>,
+) -> std::result::Result<ProjectRecord, RpcMethodError> {
+    let row = sqlx::query(
+        "UPDATE projects SET name = $2, description = $3, updated_at = NOW() WHERE id = $1 RETURNING id, user_id, tenant_id, name, description, created_at, updated_at",
+    )
+    .bind(project_id)
+    .bind(name)
+    .bind(description)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| match &err {
+        SqlxError::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
+            RpcMethodError::new(
+                ErrorCode::ProjectConflict,
+                "a project with this name already exists",
+                Some(json!({ "name": name })),
+            )
+        }
+        _ => RpcMethodError::internal(&format!("failed to update project: {err}")),
+    })?;
+    let row = row.ok_or_else(|| {
+        RpcMethodError::new(ErrorCode::ProjectNotFound, "project not found", None)
+    })?;
+
+    Ok(ProjectRecord {
+        id: row.get("id"),
+        owner_id: row.get("user_id"),
+        tenant_id: row.get("tenant_id"),
+        name: row.get("name"),
+        description: row.get("description"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    })
+}
+
+/// Trims and dedupes a project's program allowlist. Every entry must already
+/// be allowed globally; an empty list clears the policy.
+fn normalize_allowed_programs(
+    run: &SandboxRun,
+    programs: Vec<String>,
+) -> std::result::Result<Option<Vec<String>>, RpcMethodError> {
+    let mut normalized: Vec<String> = Vec::new();
+    for program in programs {
+        let program = program.trim().to_string();
+        if program.is_empty() || normalized.contains(&program) {
+            continue;
+        }
+        if !run
+            .config()
+            .allowed_programs()
+            .any(|allowed| *allowed == program)
+        {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "program is not allowed by the sandbox",
+                Some(json!({ "program": program })),
+            ));
+        }
+        normalized.push(program);
+    }
+    Ok(Some(normalized).filter(|programs| !programs.is_empty()))
+}
+
+async fn set_project_run_policy(
+    pool: &PgPool,
+    project_id: &Uuid,
+    programs: Option<&[String]>,
+) -> std::result::Result<(), RpcMethodError> {
+    sqlx::query("UPDATE projects SET allowed_programs = $2 WHERE id = $1")
+        .bind(project_id)
+        .bind(programs)
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| RpcMethodError::internal(&format!("failed to update run policy: {err}")))
+}
+
+async fn project_run_policy(
+    pool: &PgPool,
+    project_id: &Uuid,
+) -> std::result::Result<Option<Vec<String>>, RpcMethodError> {
+    let policy: Option<Option<Vec<String>>> =
+        sqlx::query_scalar("SELECT allowed_programs FROM projects WHERE id = $1")
+            .bind(project_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|err| {
+                RpcMethodError::internal(&format!("failed to load run policy: {err}"))
+            })?;
+    policy.ok_or_else(|| RpcMethodError::new(ErrorCode::ProjectNotFound, "project not found", None))
+}
+
+/// Fails unless `policy`, a project's `allowed_programs`, permits `program`;
+/// without a policy the global allowlist alone decides.
+fn ensure_program_allowed(
+    policy: Option<&[String]>,
+    program: &str,
+) -> std::result::Result<(), RpcMethodError> {
+    match policy {
+        Some(allowed) if !allowed.iter().any(|entry| entry == program) => Err(RpcMethodError::new(
+            ErrorCode::ProgramNotAllowed,
+            "program not allowed in project",
+            Some(json!({ "program": program, "allowed": allowed })),
+        )),
+        _ => Ok(()),
+    }
 }
 
 async fn list_projects(
@@ -1644,8 +3085,9 @@ async fn list_projects(
 ) -> std::result::Result<Vec<Value>, RpcMethodError> {
     let rows = if ctx.is_admin() {
         sqlx::query(
-            "SELECT id, user_id, name, description, created_at, updated_at FROM projects ORDER BY created_at DESC",
+            "SELECT id, user_id, name, description, created_at, updated_at FROM projects WHERE tenant_id = $1 ORDER BY created_at DESC",
         )
+        .bind(ctx.tenant_id)
         .fetch_all(pool)
         .await
     } else {
@@ -1675,28 +3117,69 @@ async fn list_projects(
         .collect())
 }
 
+/// Loads a project through the cache and checks that the caller may use it
+/// for `permission`.
 async fn load_project(
-    pool: &PgPool,
+    state: &AppState,
+    ctx: &RequestContext,
+    project_id: &Uuid,
+    permission: Permission,
+) -> std::result::Result<ProjectRecord, RpcMethodError> {
+    let record = state
+        .project_cache
+        .project(*project_id, || fetch_project(&state.pool, project_id))
+        .await?;
+    // Other tenants' projects don't exist for the caller, not even for admins.
+    if record.tenant_id != ctx.tenant_id {
+        return Err(RpcMethodError::new(
+            ErrorCode::ProjectNotFound,
+            "project not found",
+            None,
+        ));
+    }
+    check_project_access(ctx, &record, permission)?;
+    if !ctx.permissions.in_scope(project_id) {
+        return Err(RpcMethodError::forbidden("project outside api key scope"));
+    }
+    Ok(record)
+}
+
+/// Owners and admins may use a project for anything their role allows;
+/// anyone else needs a grant of `permission` on that very project.
+fn check_project_access(
     ctx: &RequestContext,
+    record: &ProjectRecord,
+    permission: Permission,
+) -> std::result::Result<(), RpcMethodError> {
+    if record.owner_id == ctx.user_id
+        || ctx.is_admin()
+        || ctx.permissions.has_project_grant(&record.id, permission)
+    {
+        Ok(())
+    } else {
+        Err(RpcMethodError::forbidden("project access denied"))
+    }
+}
+
+async fn fetch_project(
+    pool: &PgPool,
     project_id: &Uuid,
 ) -> std::result::Result<ProjectRecord, RpcMethodError> {
     let row = sqlx::query(
-        "SELECT id, user_id, name, description, created_at, updated_at FROM projects WHERE id = $1",
+        "SELECT id, user_id, tenant_id, name, description, created_at, updated_at FROM projects WHERE id = $1",
     )
     .bind(project_id)
     .fetch_optional(pool)
     .await
     .map_err(|err| RpcMethodError::internal(&format!("failed to load project: {err}")))?;
 
-    let row = row.ok_or_else(|| RpcMethodError::new(-32055, "project not found", None))?;
-    let owner_id: i32 = row.get("user_id");
-    if owner_id != ctx.user_id && !ctx.is_admin() {
-        return Err(RpcMethodError::forbidden("project access denied"));
-    }
-
+    let row = row.ok_or_else(|| {
+        RpcMethodError::new(ErrorCode::ProjectNotFound, "project not found", None)
+    })?;
     Ok(ProjectRecord {
         id: row.get("id"),
-        owner_id,
+        owner_id: row.get("user_id"),
+        tenant_id: row.get("tenant_id"),
         name: row.get("name"),
         description: row.get("description"),
         created_at: row.get("created_at"),
@@ -1704,62 +3187,232 @@ async fn load_project(
     })
 }
 
+/// Pages through a project's files in path order. The glob is applied after
+/// the SQL prefix and cursor filters, so sparse matches keep scanning until the
+/// page is full; the scan is narrowed to the glob's literal start and reads
+/// `GLOB_SCAN_BATCH` rows at a time. Content is only loaded for the rows that
+/// end up in the page, and a page with content ends early once it passes
+/// `content_budget`.
 async fn project_files(
     pool: &PgPool,
     project_id: &Uuid,
-    include_content: bool,
-) -> std::result::Result<Vec<Value>, RpcMethodError> {
-    let rows = sqlx::query(
-        "SELECT path, size, sha256, updated_at, content FROM project_files WHERE project_id = $1 ORDER BY path",
-    )
-    .bind(project_id)
-    .fetch_all(pool)
-    .await
-    .map_err(|err| RpcMethodError::internal(&format!("failed to load project files: {err}")))?;
-
-    let mut files = Vec::with_capacity(rows.len());
-    for row in rows {
-        let path: String = row.get("path");
-        let size: i64 = row.get("size");
-        let sha: Vec<u8> = row.get("sha256");
-        let updated: DateTime<Utc> = row.get("updated_at");
-        let mut object = serde_json::Map::new();
-        object.insert("path".to_string(), Value::String(path));
-        object.insert("size".to_string(), Value::Number(size.into()));
-        object.insert("sha256".to_string(), Value::String(hex_encode(sha)));
-        object.insert(
-            "updated_at".to_string(),
-            Value::String(updated.to_rfc3339()),
-        );
-        if include_content {
-            let content: Vec<u8> = row.get("content");
-            object.insert("data".to_string(), Value::String(BASE64.encode(content)));
+    query: &ProjectFileQuery,
+) -> std::result::Result<(Vec<Value>, Option<String>), RpcMethodError> {
+    let Some(prefix) = query.scan_prefix() else {
+        return Ok((Vec::new(), None));
+    };
+    let scan_batch = match query.glob {
+        Some(_) => (query.limit + 1).max(GLOB_SCAN_BATCH),
+        None => query.limit + 1,
+    };
+    let mut files: Vec<serde_json::Map<String, Value>> = Vec::with_capacity(query.limit);
+    let mut after = query.cursor.clone();
+    let mut next_cursor = None;
+    'scan: loop {
+        let rows = sqlx::query(
+            "SELECT path, size, sha256, updated_at FROM project_files \
+             WHERE project_id = $1 \
+               AND ($2::text IS NULL OR starts_with(path, $2)) \
+               AND ($3::text IS NULL OR path > $3) \
+             ORDER BY path LIMIT $4",
+        )
+        .bind(project_id)
+        .bind(prefix)
+        .bind(after.as_deref())
+        .bind(scan_batch as i64)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load project files: {err}")))?;
+        let exhausted = rows.len() < scan_batch;
+
+        for row in rows {
+            let path: String = row.get("path");
+            after = Some(path.clone());
+            if !query.matches(&path) {
+                continue;
+            }
+            if files.len() == query.limit {
+                next_cursor = files
+                    .last()
+                    .and_then(|file| file.get("path"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                break 'scan;
+            }
+            let size: i64 = row.get("size");
+            let sha: Vec<u8> = row.get("sha256");
+            let updated: DateTime<Utc> = row.get("updated_at");
+            let mut object = serde_json::Map::new();
+            object.insert("path".to_string(), Value::String(path));
+            object.insert("size".to_string(), Value::Number(size.into()));
+            object.insert("sha256".to_string(), Value::String(hex_encode(sha)));
+            object.insert(
+                "updated_at".to_string(),
+                Value::String(updated.to_rfc3339()),
+            );
+            files.push(object);
+        }
+        if exhausted {
+            break;
         }
-        files.push(Value::Object(object));
     }
-    Ok(files)
-}
 
-async fn delete_project(
-    pool: &PgPool,
-    project_id: &Uuid,
-) -> std::result::Result<(), RpcMethodError> {
-    sqlx::query("DELETE FROM projects WHERE id = $1")
+    if query.include_content && !files.is_empty() {
+        let paths: Vec<String> = files
+            .iter()
+            .filter_map(|file| file.get("path").and_then(Value::as_str))
+            .map(str::to_string)
+            .collect();
+        let rows = sqlx::query(
+            "SELECT path, content FROM project_files WHERE project_id = $1 AND path = ANY($2)",
+        )
         .bind(project_id)
-        .execute(pool)
+        .bind(&paths)
+        .fetch_all(pool)
         .await
-        .map_err(|err| RpcMethodError::internal(&format!("failed to delete project: {err}")))?;
-    Ok(())
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load project files: {err}")))?;
+        let mut contents: HashMap<String, Vec<u8>> = rows
+            .into_iter()
+            .map(|row| (row.get("path"), row.get("content")))
+            .collect();
+        let mut content_bytes = 0usize;
+        let mut cut = None;
+        for (index, file) in files.iter_mut().enumerate() {
+            let path = file.get("path").and_then(Value::as_str).unwrap_or_default();
+            let Some(content) = contents.remove(path) else {
+                continue;
+            };
+            let encoded = base64::encoded_len(content.len(), true).unwrap_or(usize::MAX);
+            content_bytes = content_bytes.saturating_add(encoded);
+            if index > 0
+                && query
+                    .content_budget
+                    .is_some_and(|budget| content_bytes > budget)
+            {
+                cut = Some(index);
+                break;
+            }
+            file.insert("data".to_string(), Value::String(BASE64.encode(content)));
+        }
+        if let Some(cut) = cut {
+            files.truncate(cut);
+            next_cursor = files
+                .last()
+                .and_then(|file| file.get("path"))
+                .and_then(Value::as_str)
+                .map(str::to_string);
+        }
+    }
+    Ok((files.into_iter().map(Value::Object).collect(), next_cursor))
 }
 
-async fn save_project_file(
-    pool: &PgPool,
+/// Saves a project file to Postgres and the sandbox mirror and records the
+/// save in the activity feed as `user_id`. Shared by `project.file.save`, the
+/// REST upload routes and `project.import`.
+async fn store_project_file(
+    state: &AppState,
+    user_id: i32,
+    project: &ProjectRecord,
+    relative_path: &Path,
+    data: &[u8],
+    sha256: &[u8],
+    message: Option<&str>,
+) -> std::result::Result<Value, RpcMethodError> {
+    let files = [(relative_path, data, sha256)];
+    let mut saved = store_project_files(state, user_id, project, &files, message).await?;
+    Ok(saved.remove(0))
+}
+
+/// Like [`store_project_file`] for several `(path, data, sha256)` files at
+/// once. They are saved in one transaction that only commits once the
+/// mirror holds all of them, so a failure leaves neither Postgres nor the
+/// mirror with some of the files.
+async fn store_project_files(
+    state: &AppState,
+    user_id: i32,
+    project: &ProjectRecord,
+    files: &[(&Path, &[u8], &[u8])],
+    message: Option<&str>,
+) -> std::result::Result<Vec<Value>, RpcMethodError> {
+    let project_id = &project.id;
+    let save_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to save project file: {err}"));
+    let sizes: BTreeMap<PathBuf, i64> = files
+        .iter()
+        .map(|(path, data, _)| (path.to_path_buf(), data.len() as i64))
+        .collect();
+    let mut tx = state.pool.begin().await.map_err(save_error)?;
+    quota::ensure_files_fit(state, &mut tx, project_id, &sizes).await?;
+    let mut saved = Vec::with_capacity(files.len());
+    for (path, data, sha256) in files {
+        let version_limit = state.project_version_limit;
+        let file = write_project_file(&mut tx, project_id, path, data, sha256, version_limit)
+            .await
+            .map_err(save_error)?;
+        saved.push(file);
+    }
+
+    let directory = project_directory_relative(project.tenant_id, project_id);
+    let mirror = state.sandbox.scoped(&directory).map_err(scope_error)?;
+    let mut previous = Vec::with_capacity(files.len());
+    for (path, data, _) in files {
+        let current = match mirror.read(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(SandboxError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        };
+        let written = current.and_then(|content| {
+            previous.push((path.to_path_buf(), content));
+            mirror.write(path, data)
+        });
+        if let Err(err) = written {
+            restore_mirror(&mirror, &previous);
+            return Err(RpcMethodError::from_sandbox(
+                ErrorCode::ProjectFileSave,
+                "failed to persist project file",
+                err,
+            ));
+        }
+    }
+    if let Err(err) = tx.commit().await {
+        restore_mirror(&mirror, &previous);
+        return Err(save_error(err));
+    }
+    state.project_cache.invalidate_listings(project_id);
+
+    for (path, _, _) in files {
+        let detail = match message {
+            Some(message) if message.trim().is_empty() => continue,
+            Some(message) => json!({
+                "path": path.to_string_lossy(),
+                "message": message.trim(),
+            }),
+            None => json!({ "path": path.to_string_lossy() }),
+        };
+        record_project_activity(
+            state,
+            *project_id,
+            user_id,
+            "project.file.save",
+            Some(detail),
+        )
+        .await
+        .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    }
+    Ok(saved)
+}
+
+/// Archives and replaces one file inside `tx`; the caller commits.
+async fn write_project_file(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     project_id: &Uuid,
     path: &Path,
     data: &[u8],
     sha256: &[u8],
-) -> std::result::Result<Value, RpcMethodError> {
+    version_limit: i64,
+) -> std::result::Result<Value, SqlxError> {
     let path_str = path.to_string_lossy().to_string();
+    archive_project_file(tx, project_id, &path_str, Some(sha256), version_limit).await?;
     let row = sqlx::query(
         "INSERT INTO project_files (project_id, path, content, sha256, size) VALUES ($1, $2, $3, $4, $5)
         ON CONFLICT (project_id, path) DO UPDATE SET content = EXCLUDED.content, sha256 = EXCLUDED.sha256, size = EXCLUDED.size, updated_at = NOW()
@@ -1770,9 +3423,8 @@ async fn save_project_file(
     .bind(data)
     .bind(sha256)
     .bind(data.len() as i64)
-    .fetch_one(pool)
-    .await
-    .map_err(|err| RpcMethodError::internal(&format!("failed to save project file: {err}")))?;
+    .fetch_one(&mut **tx)
+    .await?;
 
     let updated: DateTime<Utc> = row.get("updated_at");
     Ok(json!({
@@ -1784,96 +3436,349 @@ async fn save_project_file(
     }))
 }
 
+/// Copies the current content of a file into `project_file_versions` unless
+/// it already matches `replacement_sha`, then trims the file's history to
+/// `version_limit` entries. Must run in the transaction that replaces or
+/// deletes the file.
+async fn archive_project_file(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    project_id: &Uuid,
+    path: &str,
+    replacement_sha: Option<&[u8]>,
+    version_limit: i64,
+) -> Result<(), SqlxError> {
+    if version_limit <= 0 {
+        return Ok(());
+    }
+    let archived = sqlx::query(
+        "INSERT INTO project_file_versions (project_id, path, content, sha256, size, created_at)
+        SELECT project_id, path, content, sha256, size, updated_at FROM project_files
+        WHERE project_id = $1 AND path = $2 AND sha256 IS DISTINCT FROM $3
+        FOR UPDATE",
+    )
+    .bind(project_id)
+    .bind(path)
+    .bind(replacement_sha)
+    .execute(&mut **tx)
+    .await?;
+    if archived.rows_affected() > 0 {
+        sqlx::query(
+            "DELETE FROM project_file_versions WHERE project_id = $1 AND path = $2 AND id NOT IN (
+                SELECT id FROM project_file_versions WHERE project_id = $1 AND path = $2 ORDER BY id DESC LIMIT $3
+            )",
+        )
+        .bind(project_id)
+        .bind(path)
+        .bind(version_limit)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn project_file_history(
+    pool: &PgPool,
+    project_id: &Uuid,
+    path: &Path,
+    limit: i64,
+) -> std::result::Result<Value, RpcMethodError> {
+    let path_str = path.to_string_lossy().to_string();
+    let rows = sqlx::query(
+        "SELECT id, size, sha256, created_at, archived_at FROM project_file_versions
+        WHERE project_id = $1 AND path = $2 ORDER BY id DESC LIMIT $3",
+    )
+    .bind(project_id)
+    .bind(&path_str)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load file history: {err}")))?;
+
+    let versions: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            let created: DateTime<Utc> = row.get("created_at");
+            let archived: DateTime<Utc> = row.get("archived_at");
+            json!({
+                "version_id": row.get::<i64, _>("id"),
+                "size": row.get::<i64, _>("size"),
+                "sha256": hex_encode(row.get::<Vec<u8>, _>("sha256")),
+                "created_at": created.to_rfc3339(),
+                "archived_at": archived.to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(json!({ "path": path_str, "versions": versions }))
+}
+
+async fn load_project_file_version(
+    pool: &PgPool,
+    project_id: &Uuid,
+    path: &Path,
+    version_id: i64,
+) -> std::result::Result<Vec<u8>, RpcMethodError> {
+    let path_str = path.to_string_lossy().to_string();
+    let content: Option<Vec<u8>> = sqlx::query_scalar(
+        "SELECT content FROM project_file_versions WHERE id = $1 AND project_id = $2 AND path = $3",
+    )
+    .bind(version_id)
+    .bind(project_id)
+    .bind(&path_str)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load file version: {err}")))?;
+    content.ok_or_else(|| {
+        RpcMethodError::new(
+            ErrorCode::ProjectFileVersionNotFound,
+            "project file version not found",
+            Some(json!({ "path": path_str, "version_id": version_id })),
+        )
+    })
+}
+
+/// Reads a project file. When `if_none_match` is the file's current
+/// `sha256` the content is not even loaded and only the metadata returns.
 async fn read_project_file(
     pool: &PgPool,
     project_id: &Uuid,
     path: &Path,
+    if_none_match: Option<&str>,
 ) -> std::result::Result<Value, RpcMethodError> {
     let path_str = path.to_string_lossy().to_string();
+    let known = if_none_match.and_then(|tag| hex::decode(etag_value(tag)).ok());
     let row = sqlx::query(
-        "SELECT content, size, sha256, updated_at FROM project_files WHERE project_id = $1 AND path = $2",
+        "SELECT CASE WHEN sha256 = $3 THEN NULL ELSE content END AS content, size, sha256, updated_at \
+         FROM project_files WHERE project_id = $1 AND path = $2",
     )
     .bind(project_id)
     .bind(&path_str)
+    .bind(known)
     .fetch_optional(pool)
     .await
     .map_err(|err| RpcMethodError::internal(&format!("failed to read project file: {err}")))?;
 
     let row = row.ok_or_else(|| {
         RpcMethodError::new(
-            -32052,
+            ErrorCode::ProjectConflict,
             "project file not found",
             Some(json!({ "path": path_str.clone() })),
         )
     })?;
-    let content: Vec<u8> = row.get("content");
+    let content: Option<Vec<u8>> = row.get("content");
     let sha: Vec<u8> = row.get("sha256");
     let updated: DateTime<Utc> = row.get("updated_at");
     let size: i64 = row.get("size");
 
-    Ok(json!({
+    let mut file = json!({
         "path": path_str,
-        "data": BASE64.encode(content),
         "size": size,
         "sha256": hex_encode(sha),
         "updated_at": updated.to_rfc3339(),
-    }))
+    });
+    match content {
+        Some(content) => file["data"] = json!(BASE64.encode(content)),
+        None => file["not_modified"] = json!(true),
+    }
+    Ok(file)
+}
+
+/// Strips the quotes and weak prefix an HTTP-minded client may have kept.
+fn etag_value(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/").trim_matches('"')
+}
+
+/// Whether a client's `if_none_match` names the current `sha256` (hex).
+fn sha256_matches(if_none_match: Option<&str>, sha256: &str) -> bool {
+    if_none_match.is_some_and(|tag| etag_value(tag).eq_ignore_ascii_case(sha256))
 }
 
 async fn delete_project_file(
     pool: &PgPool,
     project_id: &Uuid,
     path: &Path,
+    version_limit: i64,
 ) -> std::result::Result<(), RpcMethodError> {
     let path_str = path.to_string_lossy().to_string();
+    let delete_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to delete project file: {err}"));
+    let mut tx = pool.begin().await.map_err(delete_error)?;
+    archive_project_file(&mut tx, project_id, &path_str, None, version_limit)
+        .await
+        .map_err(delete_error)?;
     let result = sqlx::query("DELETE FROM project_files WHERE project_id = $1 AND path = $2")
         .bind(project_id)
         .bind(&path_str)
-        .execute(pool)
+        .execute(&mut *tx)
         .await
-        .map_err(|err| {
-            RpcMethodError::internal(&format!("failed to delete project file: {err}"))
-        })?;
+        .map_err(delete_error)?;
     if result.rows_affected() == 0 {
         return Err(RpcMethodError::new(
-            -32052,
+            ErrorCode::ProjectConflict,
             "project file not found",
             Some(json!({ "path": path_str })),
         ));
     }
+    tx.commit().await.map_err(delete_error)?;
     Ok(())
 }
 
+/// Appends to the project's activity feed and publishes the same action as
+/// a domain event.
 async fn record_project_activity(
-    pool: &PgPool,
+    state: &AppState,
     project_id: Uuid,
     user_id: i32,
-    action: &str,
+    action: &'static str,
     detail: Option<Value>,
 ) -> Result<(), SqlxError> {
+    let detail = detail.unwrap_or(Value::Null);
     sqlx::query(
         "INSERT INTO project_activity (project_id, user_id, action, detail) VALUES ($1, $2, $3, $4)",
     )
     .bind(project_id)
     .bind(user_id)
     .bind(action)
-    .bind(Json(detail.unwrap_or(Value::Null)))
-    .execute(pool)
+    .bind(Json(&detail))
+    .execute(&state.pool)
+    .await?;
+    state.events.publish(DomainEvent::Project {
+        user_id,
+        project_id,
+        action,
+        detail,
+    });
+    Ok(())
+}
+
+async fn project_activity(
+    pool: &PgPool,
+    project_id: &Uuid,
+    params: ProjectActivityParams,
+) -> std::result::Result<Value, RpcMethodError> {
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let actions = params.actions.filter(|actions| !actions.is_empty());
+    let rows = sqlx::query(
+        "SELECT a.id, a.action, a.detail, a.created_at, a.user_id, u.username \
+         FROM project_activity a LEFT JOIN users u ON u.id = a.user_id \
+         WHERE a.project_id = $1 \
+           AND ($2::text[] IS NULL OR a.action = ANY($2)) \
+           AND ($3::bigint IS NULL OR a.id < $3) \
+         ORDER BY a.id DESC LIMIT $4",
+    )
+    .bind(project_id)
+    .bind(actions)
+    .bind(params.cursor)
+    .bind(limit)
+    .fetch_all(pool)
     .await
-    .map(|_| ())
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load project activity: {err}")))?;
+
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    let entries: Vec<Value> = rows
+        .into_iter()
+        .map(|row| {
+            let created: DateTime<Utc> = row.get("created_at");
+            let detail: Option<Value> = row.get("detail");
+            json!({
+                "id": row.get::<i64, _>("id"),
+                "action": row.get::<String, _>("action"),
+                "detail": detail.unwrap_or(Value::Null),
+                "actor_id": row.get::<Option<i32>, _>("user_id"),
+                "actor": row.get::<Option<String>, _>("username"),
+                "created_at": created.to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(json!({ "entries": entries, "next_cursor": next_cursor }))
 }
 
 fn map_db_activity_error(err: SqlxError, message: &str) -> RpcMethodError {
     RpcMethodError::internal(&format!("{message}: {err}"))
 }
 
+const MAX_PARAMS_DEPTH: usize = 32;
+const MAX_BASE64_PAYLOAD_BYTES: usize = 8 * 1024 * 1024;
+
+/// Base64 fields whose decoded size is capped before the params are
+/// deserialized, keyed by method.
+const BASE64_FIELD_LIMITS: &[(&str, &str, usize)] = &[
+    ("fs.write", "data", MAX_BASE64_PAYLOAD_BYTES),
+    ("project.file.save", "data", MAX_BASE64_PAYLOAD_BYTES),
+    ("wasm.invoke", "module_bytes", MAX_BASE64_PAYLOAD_BYTES),
+    ("run.exec", "stdin", 1024 * 1024),
+    ("run.session.start", "stdin", 1024 * 1024),
+    ("run.session.write", "data", 1024 * 1024),
+];
+
+/// Cheap structural checks on raw params so oversized or hostile payloads
+/// are rejected before they are copied into typed structs.
+fn validate_params(
+    method: &str,
+    params: Option<&Value>,
+) -> std::result::Result<(), RpcMethodError> {
+    let Some(params) = params else {
+        return Ok(());
+    };
+    let object = match params {
+        Value::Null => return Ok(()),
+        Value::Object(object) => object,
+        _ => {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "invalid params",
+                Some(json!({ "detail": "params must be an object" })),
+            ))
+        }
+    };
+    if json_depth(params, MAX_PARAMS_DEPTH + 1) > MAX_PARAMS_DEPTH {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid params",
+            Some(json!({ "detail": "params nested too deeply", "max_depth": MAX_PARAMS_DEPTH })),
+        ));
+    }
+    for (limited_method, field, limit) in BASE64_FIELD_LIMITS {
+        if *limited_method != method {
+            continue;
+        }
+        if let Some(Value::String(encoded)) = object.get(*field) {
+            if encoded.len() / 4 * 3 > *limit {
+                return Err(RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "payload too large",
+                    Some(json!({ "field": field, "limit": limit })),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Nesting depth of `value`, counting stops once `cap` is reached.
+fn json_depth(value: &Value, cap: usize) -> usize {
+    if cap == 0 {
+        return 0;
+    }
+    let children: Box<dyn Iterator<Item = &Value>> = match value {
+        Value::Array(items) => Box::new(items.iter()),
+        Value::Object(map) => Box::new(map.values()),
+        _ => return 1,
+    };
+    1 + children
+        .map(|child| json_depth(child, cap - 1))
+        .max()
+        .unwrap_or(0)
+}
+
 fn parse_params<T: for<'a> Deserialize<'a>>(
     params: Option<Value>,
 ) -> std::result::Result<T, RpcMethodError> {
     let value = params.unwrap_or_else(|| Value::Object(Default::default()));
     serde_json::from_value(value).map_err(|err| {
         RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "invalid params",
             Some(json!({ "detail": err.to_string() })),
         )
@@ -1900,7 +3805,201 @@ fn enrich_agent_metadata(metadata: Option<Value>, ctx: &RequestContext) -> Optio
     if let Some(api_key_id) = ctx.api_key_id {
         map.insert("api_key_id".to_string(), json!(api_key_id));
     }
-    Some(Value::Object(map))
+    Some(Value::Object(map))
+}
+
+/// Applies the actions of a completed task to a project: a dry run first,
+/// then, unless only the dry run was asked for or it failed, the real run.
+/// Written files are saved like `project.file.save` saves them. A failing
+/// action stops the run and shows up in the report rather than as an error.
+async fn apply_agent_task(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    params: AgentApplyParams,
+) -> std::result::Result<Value, RpcMethodError> {
+    let task_id = Uuid::parse_str(&params.task_id).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid task identifier",
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })?;
+    let project_id = parse_project_id(&params.project_id)?;
+    let project = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+    if params.run_commands {
+        check_project_access(ctx, &project, Permission::Execute)?;
+    }
+
+    let snapshot: AgentTaskSnapshot = match state.runners.pinned(&task_id) {
+        Some(runner) => {
+            let status = state
+                .runners
+                .call(runner, Call::AgentStatus { task_id })
+                .await?;
+            serde_json::from_value(status).map_err(|err| {
+                RpcMethodError::internal(&format!("invalid task snapshot from runner: {err}"))
+            })?
+        }
+        None => state.agents.status(&task_id).ok_or_else(|| {
+            RpcMethodError::new(ErrorCode::AgentTaskNotFound, "agent task not found", None)
+        })?,
+    };
+    let requested_by = snapshot
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("requested_by_id"))
+        .and_then(Value::as_i64);
+    if requested_by != Some(i64::from(ctx.user_id)) && !ctx.is_admin() {
+        return Err(RpcMethodError::forbidden("agent task access denied"));
+    }
+    let outcome = match (snapshot.status, snapshot.outcome) {
+        (AgentTaskStatus::Completed, Some(outcome)) => outcome,
+        (status, _) => {
+            return Err(RpcMethodError::new(
+                ErrorCode::AgentApply,
+                "agent task has no outcome to apply",
+                Some(json!({ "status": status })),
+            ));
+        }
+    };
+
+    let mut actions = outcome.actions;
+    for action in &mut actions {
+        if let AgentAction::FilePatch { path, .. } | AgentAction::FileWrite { path, .. } = action {
+            *path = normalize_project_path(path)?.to_string_lossy().into_owned();
+        }
+    }
+    let directory = project_directory_relative(project.tenant_id, &project_id);
+    let mirror = state.sandbox.scoped(&directory).map_err(scope_error)?;
+    let mut executor = AgentActionExecutor::new(mirror.clone());
+    if params.run_commands {
+        let policy = project_run_policy(&state.pool, &project_id).await?;
+        for action in &actions {
+            if let AgentAction::Command { command, .. } = action {
+                ensure_program_allowed(policy.as_deref(), command)?;
+            }
+        }
+        executor = executor.with_run(state.run.scoped(&directory).map_err(scope_error)?);
+    }
+
+    let plan = executor.clone().with_dry_run(true).apply(&actions).await;
+    if params.dry_run || !plan.is_success() {
+        return Ok(json!({ "task_id": task_id, "project_id": project_id, "report": plan }));
+    }
+    // A path written several times only counts with its final content.
+    let mut final_sizes = BTreeMap::new();
+    for action in &plan.actions {
+        if let (Some(path), Some(content)) = (&action.path, &action.content) {
+            final_sizes.insert(PathBuf::from(path), content.len() as i64);
+        }
+    }
+    // Checked before anything runs, and again under the owner's quota lock
+    // when the files are saved.
+    let mut conn = quota::connection(state).await?;
+    quota::ensure_files_fit(state, &mut conn, &project_id, &final_sizes).await?;
+    drop(conn);
+    let mut previous = Vec::with_capacity(final_sizes.len());
+    for path in final_sizes.keys() {
+        let content = match mirror.read(path) {
+            Ok(content) => Some(content),
+            Err(SandboxError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(RpcMethodError::from_sandbox(
+                    ErrorCode::AgentApply,
+                    "failed to read project file",
+                    err,
+                ))
+            }
+        };
+        previous.push((path.clone(), content));
+    }
+
+    let report = executor.apply(&actions).await;
+    let sandbox_time: Duration = report
+        .actions
+        .iter()
+        .filter_map(|action| action.duration)
+        .sum();
+    if !sandbox_time.is_zero() {
+        state
+            .billing
+            .charge(ctx, method, Charge::SandboxTime(sandbox_time))
+            .await;
+    }
+    // The files are saved together, so a failure leaves neither Postgres nor
+    // the mirror half-applied; commands that already ran are not undone.
+    if let Err(err) = save_applied_files(state, &project_id, &report, &final_sizes).await {
+        restore_mirror(&mirror, &previous);
+        return Err(err);
+    }
+    state.project_cache.invalidate_listings(&project_id);
+    record_project_activity(
+        state,
+        project_id,
+        ctx.user_id,
+        "agent.apply",
+        Some(json!({
+            "task_id": task_id,
+            "applied": report.applied,
+            "failed": report.failed,
+        })),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(json!({ "task_id": task_id, "project_id": project_id, "report": report }))
+}
+
+/// Saves the files an apply wrote in one transaction, once their
+/// `final_sizes` fit the owner's storage quota.
+async fn save_applied_files(
+    state: &AppState,
+    project_id: &Uuid,
+    report: &AgentApplyReport,
+    final_sizes: &BTreeMap<PathBuf, i64>,
+) -> std::result::Result<(), RpcMethodError> {
+    let save_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to save project file: {err}"));
+    let mut tx = state.pool.begin().await.map_err(save_error)?;
+    quota::ensure_files_fit(state, &mut tx, project_id, final_sizes).await?;
+    for action in &report.actions {
+        if action.status != AgentActionStatus::Applied {
+            continue;
+        }
+        if let (Some(path), Some(content)) = (&action.path, &action.content) {
+            let sha256 = Sha256::digest(content);
+            write_project_file(
+                &mut tx,
+                project_id,
+                Path::new(path),
+                content,
+                &sha256,
+                state.project_version_limit,
+            )
+            .await
+            .map_err(save_error)?;
+        }
+    }
+    tx.commit().await.map_err(save_error)
+}
+
+/// Puts mirror files back the way they were before a save or an apply whose
+/// files could not be committed.
+fn restore_mirror(mirror: &SandboxFs, previous: &[(PathBuf, Option<Vec<u8>>)]) {
+    // Newest first, so a path written twice ends up as it was at the start.
+    for (path, content) in previous.iter().rev() {
+        let restored = match content {
+            Some(content) => mirror.write(path, content),
+            None => mirror.delete(path),
+        };
+        match restored {
+            Ok(()) => {}
+            Err(SandboxError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "failed to restore project mirror")
+            }
+        }
+    }
 }
 
 fn build_agent_context(
@@ -2025,7 +4124,17 @@ struct RpcRequest {
     jsonrpc: String,
     method: String,
     params: Option<Value>,
-    id: Value,
+    /// `None` for a notification; an explicit `null` id is still a call.
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+}
+
+/// Keeps a present `null` as `Some(Value::Null)`, unlike `Option`'s default.
+fn present<'de, D>(deserializer: D) -> std::result::Result<Option<Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Value::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Serialize)]
@@ -2070,7 +4179,7 @@ struct RpcError {
     data: Option<Value>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RpcMethodError {
     code: i64,
     message: String,
@@ -2078,66 +4187,292 @@ struct RpcMethodError {
 }
 
 impl RpcMethodError {
-    fn new(code: i64, message: &str, data: Option<Value>) -> Self {
+    fn new(code: ErrorCode, message: &str, data: Option<Value>) -> Self {
         Self {
-            code,
+            code: code.code(),
             message: message.to_string(),
             data,
         }
     }
 
-    fn from_sandbox(code: i64, message: &str, err: sandbox::SandboxError) -> Self {
+    fn from_sandbox(code: ErrorCode, message: &str, err: sandbox::SandboxError) -> Self {
         Self {
-            code,
+            code: code.code(),
             message: message.to_string(),
             data: Some(json!({ "detail": err.to_string() })),
         }
     }
 
     fn unauthorized(message: &str) -> Self {
-        Self::new(-32090, message, None)
+        Self::new(ErrorCode::Unauthorized, message, None)
     }
 
     fn forbidden(message: &str) -> Self {
-        Self::new(-32091, message, None)
+        Self::new(ErrorCode::Forbidden, message, None)
+    }
+
+    fn rate_limited(retry_after: Duration) -> Self {
+        Self::new(
+            ErrorCode::RateLimited,
+            "rate limited",
+            Some(json!({ "retry_after_ms": retry_after.as_millis() as u64 })),
+        )
     }
 
     fn internal(detail: &str) -> Self {
-        Self::new(-32603, "internal error", Some(json!({ "detail": detail })))
+        Self::new(
+            ErrorCode::Internal,
+            "internal error",
+            Some(json!({ "detail": detail })),
+        )
+    }
+
+    /// Adds `request_id` to the data; data that is not an object moves to
+    /// `detail`.
+    fn with_request_id(mut self, request_id: Uuid) -> Self {
+        let mut data = match self.data.take() {
+            Some(Value::Object(data)) => data,
+            None => serde_json::Map::new(),
+            Some(other) => serde_json::Map::from_iter([("detail".to_string(), other)]),
+        };
+        data.insert("request_id".to_string(), json!(request_id));
+        self.data = Some(Value::Object(data));
+        self
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct FsPathParams {
     path: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+struct FsWalkParams {
+    path: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+    /// Levels to descend; 1 lists the direct children only.
+    #[serde(default)]
+    max_depth: Option<usize>,
+    /// Globs files must match, relative to `path`.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Globs of entries to leave out, directories with their contents.
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Capped at `FS_WALK_MAX_ENTRIES`.
+    #[serde(default)]
+    max_entries: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct FsReadParams {
+    path: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+    /// `sha256` of an earlier read; if the file is unchanged only
+    /// `not_modified` and `sha256` come back.
+    #[serde(default)]
+    if_none_match: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct FsWriteParams {
     path: String,
     data: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ProjectCreateParams {
     name: String,
     #[serde(default)]
     description: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectSearchParams {
+    query: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    scope: Option<ProjectSearchScope>,
+    #[serde(default)]
+    case_sensitive: Option<bool>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    max_matches_per_file: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ProjectSearchScope {
+    All,
+    Path,
+    Content,
+}
+
+impl ProjectSearchScope {
+    fn includes_path(self) -> bool {
+        matches!(self, ProjectSearchScope::All | ProjectSearchScope::Path)
+    }
+
+    fn includes_content(self) -> bool {
+        matches!(self, ProjectSearchScope::All | ProjectSearchScope::Content)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectActivityParams {
+    project_id: String,
+    #[serde(default)]
+    actions: Option<Vec<String>>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectUpdateParams {
+    project_id: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    allowed_programs: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectRunParams {
+    project_id: String,
+    #[serde(flatten)]
+    command: RunCommandParams,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ProjectIdParams {
     project_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ProjectOpenParams {
     project_id: String,
     #[serde(default)]
     include_content: Option<bool>,
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default)]
+    prefix: Option<String>,
+    #[serde(default)]
+    glob: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+const DEFAULT_PROJECT_FILE_PAGE: usize = 200;
+const MAX_PROJECT_FILE_PAGE: usize = 1000;
+/// Rows read per query while a glob filters the listing.
+const GLOB_SCAN_BATCH: usize = 1000;
+
+struct ProjectFileQuery {
+    limit: usize,
+    cursor: Option<String>,
+    prefix: Option<String>,
+    glob: Option<GlobMatcher>,
+    include_content: bool,
+    /// Base64 content a page may carry; the page ends early past it.
+    content_budget: Option<usize>,
+}
+
+impl ProjectFileQuery {
+    /// Identifies the page for the listing cache.
+    fn cache_key(&self) -> String {
+        format!(
+            "{}|{}|{}|{}",
+            self.limit,
+            self.cursor.as_deref().unwrap_or(""),
+            self.prefix.as_deref().unwrap_or(""),
+            self.glob
+                .as_ref()
+                .map(|glob| glob.glob().glob())
+                .unwrap_or("")
+        )
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match &self.glob {
+            Some(glob) => glob.is_match(path),
+            None => true,
+        }
+    }
+
+    /// The prefix the SQL scan filters on: the requested one, narrowed to the
+    /// glob's literal start. `None` when the two rule each other out.
+    fn scan_prefix(&self) -> Option<Option<&str>> {
+        let literal = self.glob.as_ref().map(|glob| {
+            let pattern = glob.glob().glob();
+            let end = pattern
+                .find(['*', '?', '[', '{', '\\'])
+                .unwrap_or(pattern.len());
+            &pattern[..end]
+        });
+        match (self.prefix.as_deref(), literal.filter(|l| !l.is_empty())) {
+            (prefix, None) => Some(prefix),
+            (None, literal) => Some(literal),
+            (Some(prefix), Some(literal)) if literal.starts_with(prefix) => Some(Some(literal)),
+            (Some(prefix), Some(literal)) if prefix.starts_with(literal) => Some(Some(prefix)),
+            _ => None,
+        }
+    }
+}
+
+impl ProjectOpenParams {
+    fn into_file_query(self) -> std::result::Result<ProjectFileQuery, RpcMethodError> {
+        let glob = match self.glob.as_deref().filter(|value| !value.is_empty()) {
+            Some(pattern) => Some(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|err| {
+                        RpcMethodError::new(
+                            ErrorCode::InvalidParams,
+                            "invalid glob pattern",
+                            Some(json!({ "detail": err.to_string() })),
+                        )
+                    })?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+        Ok(ProjectFileQuery {
+            limit: self
+                .limit
+                .unwrap_or(DEFAULT_PROJECT_FILE_PAGE)
+                .clamp(1, MAX_PROJECT_FILE_PAGE),
+            cursor: self.cursor.filter(|value| !value.is_empty()),
+            prefix: self.prefix.filter(|value| !value.is_empty()),
+            glob,
+            include_content: self.include_content.unwrap_or(false),
+            content_budget: None,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ProjectFileSaveParams {
     project_id: String,
     path: String,
@@ -2148,14 +4483,51 @@ struct ProjectFileSaveParams {
     message: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct ProjectFilePathParams {
     project_id: String,
     path: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectFileReadParams {
+    project_id: String,
+    path: String,
+    /// `sha256` of an earlier read; if the file is unchanged the result
+    /// carries `not_modified` instead of `data`.
+    #[serde(default)]
+    if_none_match: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectFileHistoryParams {
+    project_id: String,
+    path: String,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ProjectFileRestoreParams {
+    project_id: String,
+    path: String,
+    version_id: i64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct RunExecParams {
+    #[serde(flatten)]
+    command: RunCommandParams,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+}
+
+/// The process to start, shared by `run.exec`, `run.session.start` and
+/// `project.run`.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RunCommandParams {
     program: String,
     #[serde(default)]
     args: Vec<String>,
@@ -2169,7 +4541,7 @@ struct RunExecParams {
     timeout_ms: Option<u64>,
 }
 
-impl RunExecParams {
+impl RunCommandParams {
     fn into_request(self) -> std::result::Result<RunRequest, RpcMethodError> {
         let mut request = RunRequest::new(self.program);
         if !self.args.is_empty() {
@@ -2186,7 +4558,7 @@ impl RunExecParams {
             if !stdin.is_empty() {
                 let data = BASE64.decode(stdin.as_bytes()).map_err(|err| {
                     RpcMethodError::new(
-                        -32602,
+                        ErrorCode::InvalidParams,
                         "invalid base64 payload",
                         Some(json!({ "detail": err.to_string() })),
                     )
@@ -2206,30 +4578,68 @@ impl RunExecParams {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RunSessionWriteParams {
+    session_id: String,
+    /// Base64 stdin chunk.
+    #[serde(default)]
+    data: Option<String>,
+    #[serde(default)]
+    close_stdin: bool,
+    /// How long to wait for output after writing; default 0.
+    #[serde(default)]
+    wait_ms: Option<u64>,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RunSessionKillParams {
+    session_id: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
 struct RunEnvVar {
     key: String,
     value: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct MicroStartParams {
     image: String,
     #[serde(default)]
     init_script: Option<String>,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct MicroExecuteParams {
     vm_id: String,
     code: String,
     #[serde(default)]
     timeout_ms: Option<u64>,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct MicroStopParams {
     vm_id: String,
+    #[serde(default)]
+    project_id: Option<String>,
+    #[serde(default)]
+    workspace_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -2242,9 +4652,11 @@ struct RawMicroImage {
     extension: Option<String>,
     #[serde(default)]
     env: Vec<RunEnvVar>,
+    #[serde(default)]
+    repl: Option<MicroRepl>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct AgentDispatchParams {
     agent: AgentKind,
     objective: String,
@@ -2256,9 +4668,22 @@ struct AgentDispatchParams {
     metadata: Option<Value>,
     #[serde(default)]
     parameters: Option<AgentParameterOverrides>,
+    #[serde(default)]
+    fan_out: Option<AgentFanOut>,
+    #[serde(default)]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    persona: Option<AgentPersona>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum AgentFanOut {
+    /// One subtask per context file, run in parallel.
+    Files,
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Default, JsonSchema)]
 struct AgentDispatchContextParams {
     #[serde(default)]
     notes: Vec<String>,
@@ -2266,7 +4691,7 @@ struct AgentDispatchContextParams {
     files: Vec<AgentDispatchContextFileParams>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct AgentDispatchContextFileParams {
     #[serde(default)]
     path: Option<String>,
@@ -2280,7 +4705,7 @@ struct AgentDispatchContextFileParams {
     content_base64: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct AgentParameterOverrides {
     #[serde(default)]
     temperature: Option<f32>,
@@ -2306,18 +4731,105 @@ impl AgentParameterOverrides {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct AgentStatusParams {
     task_id: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AgentRespondParams {
+    task_id: String,
+    answer: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AgentApplyParams {
+    task_id: String,
+    project_id: String,
+    /// Report what would change without changing anything.
+    #[serde(default)]
+    dry_run: bool,
+    /// Run the task's commands in the project; they are skipped otherwise.
+    #[serde(default)]
+    run_commands: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct AgentHistoryParams {
     #[serde(default)]
     limit: Option<usize>,
+    #[serde(default)]
+    agent: Option<AgentKind>,
+    #[serde(default)]
+    status: Option<AgentTaskStatus>,
+    /// Only tasks of this user; admins only, anyone may pass their own id.
+    #[serde(default)]
+    requested_by_id: Option<i64>,
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+    /// `next_cursor` of the previous page, or empty for the first one.
+    #[serde(default)]
+    cursor: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl AgentHistoryParams {
+    /// Calls with a filter or a `cursor` get a page with `entries` and
+    /// `next_cursor`; plain calls keep getting the bare list of entries.
+    fn paged(&self) -> bool {
+        self.agent.is_some()
+            || self.status.is_some()
+            || self.requested_by_id.is_some()
+            || self.since.is_some()
+            || self.until.is_some()
+            || self.cursor.is_some()
+    }
+
+    fn into_query(
+        self,
+        ctx: &RequestContext,
+    ) -> std::result::Result<AgentHistoryQuery, RpcMethodError> {
+        let others = self
+            .requested_by_id
+            .is_some_and(|user_id| user_id != i64::from(ctx.user_id));
+        if others && !ctx.is_admin() {
+            return Err(RpcMethodError::forbidden("insufficient permissions"));
+        }
+        let limit = self.limit.unwrap_or(20).clamp(1, 256);
+        let cursor = match self.cursor {
+            Some(raw) if !raw.is_empty() => Some(Uuid::parse_str(&raw).map_err(|err| {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid history cursor",
+                    Some(json!({ "detail": err.to_string() })),
+                )
+            })?),
+            _ => None,
+        };
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since >= until {
+                return Err(RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "since must be earlier than until",
+                    None,
+                ));
+            }
+        }
+        Ok(AgentHistoryQuery {
+            agent: self.agent,
+            status: self.status,
+            requested_by_id: self.requested_by_id,
+            since: self.since,
+            until: self.until,
+            cursor,
+            limit,
+            max_bytes: None,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct WasmInvokeParams {
     #[serde(default)]
     module_path: Option<String>,
@@ -2334,7 +4846,7 @@ struct WasmInvokeParams {
     table_elements_limit: Option<u32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "type", content = "value")]
 enum WasmParam {
     #[serde(rename = "i32")]
@@ -2363,12 +4875,12 @@ fn resolve_wasm_module(
 ) -> std::result::Result<WasmModuleSource, RpcMethodError> {
     match (&params.module_path, &params.module_bytes) {
         (Some(_), Some(_)) => Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "specify either module_path or module_bytes",
             None,
         )),
         (None, None) => Err(RpcMethodError::new(
-            -32602,
+            ErrorCode::InvalidParams,
             "missing wasm module source",
             None,
         )),
@@ -2376,14 +4888,14 @@ fn resolve_wasm_module(
         (None, Some(bytes)) => {
             if bytes.is_empty() {
                 return Err(RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "module_bytes must not be empty",
                     None,
                 ));
             }
             let decoded = BASE64.decode(bytes.as_bytes()).map_err(|err| {
                 RpcMethodError::new(
-                    -32602,
+                    ErrorCode::InvalidParams,
                     "invalid base64 payload",
                     Some(json!({ "detail": err.to_string() })),
                 )
@@ -2406,6 +4918,45 @@ fn wasm_value_to_json(value: WasmValue) -> Value {
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn jwt_verifier_accepts_only_configured_algorithms() {
+        let env = |entries: &'static [(&'static str, &'static str)]| -> config::EnvLookup {
+            Box::new(move |key| {
+                entries
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            })
+        };
+        let config = config::Config::new(None, None, env(&[])).unwrap();
+        JwtVerifier::from_config(&config);
+        assert!(config.finish().is_err());
+
+        let config =
+            config::Config::new(None, None, env(&[("AUTH_JWT_SECRET", "s3cret")])).unwrap();
+        let verifier = JwtVerifier::from_config(&config);
+        config.finish().unwrap();
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "sub": 7, "username": "dev", "role": "developer", "exp": now + 60, "iat": now,
+            "iss": "cyber-dev-studio", "jti": "t-1",
+        });
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"s3cret"),
+        )
+        .unwrap();
+        assert_eq!(verifier.verify(&token).await.unwrap().jti, "t-1");
+
+        // RS256 needs API_JWT_JWKS_URL; the signature is never looked at.
+        let header = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"alg":"RS256","kid":"k1"}"#);
+        let (_, rest) = token.split_once('.').unwrap();
+        let forged = format!("{header}.{rest}");
+        assert_eq!(verifier.verify(&forged).await.unwrap_err().code, -32090);
+    }
+
     #[test]
     fn normalize_project_name_trims_and_limits_length() {
         assert_eq!(normalize_project_name("  demo  ").unwrap(), "demo");
@@ -2414,6 +4965,273 @@ mod tests {
         assert!(normalize_project_name(&oversized).is_err());
     }
 
+    #[tokio::test]
+    async fn peer_is_optional() {
+        let (mut parts, ()) = Request::new(()).into_parts();
+        let Peer(peer) = Peer::from_request_parts(&mut parts, &()).await.unwrap();
+        assert!(peer.is_none());
+
+        let addr: SocketAddr = "192.0.2.7:4711".parse().unwrap();
+        parts.extensions.insert(ConnectInfo(addr));
+        let Peer(peer) = Peer::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(peer, Some(addr));
+    }
+
+    #[test]
+    fn request_logs_mask_the_query_token() {
+        let uri: Uri = "/events/agents/42?x=1&access_token=secret.jwt&y"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redact_access_token(&uri),
+            "/events/agents/42?x=1&access_token=<redacted>&y"
+        );
+        let uri: Uri = "/notify/ws".parse().unwrap();
+        assert_eq!(redact_access_token(&uri), "/notify/ws");
+    }
+
+    #[test]
+    fn project_file_query_clamps_limit_and_compiles_glob() {
+        let params: ProjectOpenParams = serde_json::from_value(json!({
+            "project_id": Uuid::new_v4().to_string(),
+            "limit": 0,
+            "glob": "src/*.rs",
+            "prefix": "",
+        }))
+        .unwrap();
+        let query = params.into_file_query().unwrap();
+        assert_eq!(query.limit, 1);
+        assert!(query.prefix.is_none());
+        assert!(query.matches("src/main.rs"));
+        assert!(!query.matches("src/bin/tool.rs"));
+        assert_eq!(query.scan_prefix(), Some(Some("src/")));
+
+        let query = |prefix: &str, glob: &str| {
+            let params: ProjectOpenParams = serde_json::from_value(json!({
+                "project_id": Uuid::new_v4().to_string(),
+                "prefix": prefix,
+                "glob": glob,
+            }))
+            .unwrap();
+            params.into_file_query().unwrap()
+        };
+        assert_eq!(query("s", "src/*.rs").scan_prefix(), Some(Some("src/")));
+        assert_eq!(
+            query("src/bin/", "src/**").scan_prefix(),
+            Some(Some("src/bin/"))
+        );
+        assert_eq!(query("docs/", "**/*.md").scan_prefix(), Some(Some("docs/")));
+        assert_eq!(query("docs/", "src/*.rs").scan_prefix(), None);
+
+        let invalid: ProjectOpenParams = serde_json::from_value(json!({
+            "project_id": Uuid::new_v4().to_string(),
+            "glob": "src/[",
+        }))
+        .unwrap();
+        assert!(invalid.into_file_query().is_err());
+    }
+
+    #[test]
+    fn search_helpers_escape_patterns_and_number_lines() {
+        assert_eq!(escape_like("50%_a\\b"), "50\\%\\_a\\\\b");
+        let text = "fn main() {\n    println!(\"Hello\");\n}\n// hello again";
+        let matches = line_matches(text, "hello", false, 5);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0]["line"], 2);
+        assert_eq!(matches[0]["text"], "println!(\"Hello\");");
+        assert_eq!(line_matches(text, "hello", true, 5).len(), 1);
+        assert_eq!(line_matches(text, "hello", false, 1).len(), 1);
+    }
+
+    #[test]
+    fn project_update_keeps_missing_fields_and_clears_empty_description() {
+        let now = Utc::now();
+        let record = ProjectRecord {
+            id: Uuid::new_v4(),
+            owner_id: 1,
+            tenant_id: 1,
+            name: "demo".to_string(),
+            description: Some("old".to_string()),
+            created_at: now,
+            updated_at: now,
+        };
+        assert!(resolve_project_update(&record, None, None).is_err());
+        let (name, description) =
+            resolve_project_update(&record, Some(" renamed ".to_string()), None).unwrap();
+        assert_eq!(name, "renamed");
+        assert_eq!(description.as_deref(), Some("old"));
+        let (name, description) =
+            resolve_project_update(&record, None, Some("  ".to_string())).unwrap();
+        assert_eq!(name, "demo");
+        assert!(description.is_none());
+    }
+
+    #[test]
+    fn restore_mirror_undoes_writes_newest_first() {
+        let root = std::env::temp_dir().join(format!("restore-mirror-{}", Uuid::new_v4()));
+        let mirror = SandboxFs::new(SandboxConfig::new(&root, 1024).unwrap());
+        mirror.write("kept.txt", b"original").unwrap();
+        // A path saved twice records its original content, then the first write.
+        let previous = vec![
+            (PathBuf::from("kept.txt"), Some(b"original".to_vec())),
+            (PathBuf::from("kept.txt"), Some(b"first".to_vec())),
+            (PathBuf::from("new.txt"), None),
+        ];
+        mirror.write("kept.txt", b"second").unwrap();
+        mirror.write("new.txt", b"added").unwrap();
+
+        restore_mirror(&mirror, &previous);
+        assert_eq!(mirror.read("kept.txt").unwrap(), b"original");
+        assert!(mirror.read("new.txt").is_err());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn project_run_policy_must_narrow_the_global_allowlist() {
+        let root = std::env::temp_dir().join(format!("run-policy-{}", Uuid::new_v4()));
+        let run = SandboxRun::new(
+            RunConfig::new(
+                &root,
+                vec!["/bin/sh".to_string(), "/usr/bin/env".to_string()],
+                Vec::new(),
+                Vec::new(),
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+                1024,
+            )
+            .unwrap(),
+        );
+        let programs = vec![
+            " /bin/sh ".to_string(),
+            "/bin/sh".to_string(),
+            String::new(),
+        ];
+        assert_eq!(
+            normalize_allowed_programs(&run, programs).unwrap(),
+            Some(vec!["/bin/sh".to_string()])
+        );
+        assert_eq!(normalize_allowed_programs(&run, Vec::new()).unwrap(), None);
+        assert!(normalize_allowed_programs(&run, vec!["/usr/bin/python3".to_string()]).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn run_params_share_the_command_fields() {
+        let exec: RunExecParams = serde_json::from_value(json!({
+            "program": "/bin/sh",
+            "args": ["-c", "true"],
+            "project_id": "p",
+        }))
+        .unwrap();
+        assert_eq!(exec.command.program, "/bin/sh");
+        assert_eq!(exec.command.args, ["-c", "true"]);
+        assert_eq!(exec.project_id.as_deref(), Some("p"));
+        let project: ProjectRunParams =
+            serde_json::from_value(json!({ "project_id": "p", "program": "/bin/sh" })).unwrap();
+        assert_eq!(project.command.program, "/bin/sh");
+
+        let policy = ["/bin/sh".to_string()];
+        assert!(ensure_program_allowed(None, "/usr/bin/env").is_ok());
+        assert!(ensure_program_allowed(Some(&policy), "/bin/sh").is_ok());
+        let err = ensure_program_allowed(Some(&policy), "/usr/bin/env").unwrap_err();
+        assert_eq!(err.code, ErrorCode::ProgramNotAllowed.code());
+    }
+
+    #[test]
+    fn batch_segments_group_read_only_runs() {
+        let methods = [
+            "fs.read",
+            "fs.list",
+            "fs.write",
+            "agent.status",
+            "fs.delete",
+            "fs.mkdir",
+        ];
+        assert_eq!(batch_segments(&methods), vec![0..2, 2..3, 3..4, 4..5, 5..6]);
+        assert!(batch_segments(&[]).is_empty());
+        assert!(is_read_only_method("admin.users.list"));
+        assert!(is_read_only_method("project.git.log"));
+        assert!(!is_read_only_method("project.git.commit"));
+        assert!(!is_read_only_method("listing"));
+    }
+
+    #[test]
+    fn requests_without_an_id_are_notifications() {
+        let request = |value: Value| serde_json::from_value::<RpcRequest>(value).unwrap();
+        let notification = request(json!({ "jsonrpc": "2.0", "method": "fs.mkdir" }));
+        assert_eq!(notification.id, None);
+        let null = request(json!({ "jsonrpc": "2.0", "method": "fs.mkdir", "id": null }));
+        assert_eq!(null.id, Some(Value::Null));
+        let call = request(json!({ "jsonrpc": "2.0", "method": "fs.mkdir", "id": 7 }));
+        assert_eq!(call.id, Some(json!(7)));
+    }
+
+    #[test]
+    fn agent_history_filters_by_user_for_admins_only() {
+        let params =
+            |value: Value| -> AgentHistoryParams { serde_json::from_value(value).unwrap() };
+        assert!(!params(json!({ "limit": 5 })).paged());
+        assert!(params(json!({ "cursor": "" })).paged());
+        assert!(params(json!({ "agent": "code" })).paged());
+
+        let dev = llm_mock::request_context(7);
+        let query = params(json!({ "requested_by_id": 7 }))
+            .into_query(&dev)
+            .unwrap();
+        assert_eq!(query.requested_by_id, Some(7));
+        let err = params(json!({ "requested_by_id": 8 }))
+            .into_query(&dev)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden.code());
+
+        let admin = RequestContext {
+            role: Role::Admin,
+            ..llm_mock::request_context(1)
+        };
+        let query = params(json!({ "requested_by_id": 8 }))
+            .into_query(&admin)
+            .unwrap();
+        assert_eq!(query.requested_by_id, Some(8));
+    }
+
+    #[test]
+    fn errors_carry_the_request_id() {
+        let id = Uuid::new_v4();
+        let err = RpcMethodError::unauthorized("missing token").with_request_id(id);
+        assert_eq!(err.data, Some(json!({ "request_id": id })));
+        let err = RpcMethodError::internal("boom").with_request_id(id);
+        assert_eq!(
+            err.data,
+            Some(json!({ "detail": "boom", "request_id": id }))
+        );
+        let err = RpcMethodError::new(ErrorCode::InvalidParams, "bad", Some(json!([1])))
+            .with_request_id(id);
+        assert_eq!(err.data, Some(json!({ "detail": [1], "request_id": id })));
+    }
+
+    #[test]
+    fn validate_params_rejects_oversized_and_deep_payloads() {
+        assert!(validate_params("fs.write", None).is_ok());
+        assert!(validate_params("fs.write", Some(&json!({ "path": "a", "data": "AAAA" }))).is_ok());
+        assert_eq!(
+            validate_params("fs.read", Some(&json!(["a"])))
+                .unwrap_err()
+                .code,
+            -32602
+        );
+
+        let oversized = "A".repeat(MAX_BASE64_PAYLOAD_BYTES / 3 * 4 + 8);
+        let err = validate_params("fs.write", Some(&json!({ "path": "a", "data": oversized })))
+            .unwrap_err();
+        assert_eq!(err.message, "payload too large");
+
+        let mut nested = json!(1);
+        for _ in 0..=MAX_PARAMS_DEPTH {
+            nested = json!({ "inner": nested });
+        }
+        assert!(validate_params("agent.dispatch", Some(&nested)).is_err());
+    }
+
     #[test]
     fn normalize_project_path_rejects_parent_traversal() {
         assert!(normalize_project_path("../secret").is_err());
@@ -2421,4 +5239,24 @@ mod tests {
         let path = normalize_project_path("src/lib.rs").expect("valid path");
         assert_eq!(path.to_string_lossy(), "src/lib.rs");
     }
+
+    #[test]
+    fn normalize_project_path_keeps_out_of_git_dir() {
+        assert!(normalize_project_path(".git/config").is_err());
+        assert!(normalize_project_path("./vendor/.GIT/hooks/pre-commit").is_err());
+        assert!(normalize_project_path(".gitattributes").is_ok());
+        assert!(normalize_project_path(".github/workflows/ci.yml").is_ok());
+    }
+
+    #[test]
+    fn sha256_matches_accepts_http_style_tags() {
+        let sha = hex_encode(Sha256::digest(b"fn main() {}"));
+        assert!(sha256_matches(Some(&sha), &sha));
+        assert!(sha256_matches(
+            Some(&format!("W/\"{}\"", sha.to_uppercase())),
+            &sha
+        ));
+        assert!(!sha256_matches(Some("abc"), &sha));
+        assert!(!sha256_matches(None, &sha));
+    }
 }
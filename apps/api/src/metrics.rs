@@ -0,0 +1,975 @@
+//! Process-wide counters exported in the Prometheus text format on
+//! `/metrics`. Counters are plain atomics so recording stays lock-free on the
+//! request path. Saturation gauges (DB pool, agent queue, sandbox sessions,
+//! workspace disk usage) are refreshed by a background sampler instead, so a
+//! scrape never touches the database or walks the filesystem. The sampler
+//! also copies the lock statistics of the sandbox's instance and task tables.
+//!
+//! Latency histograms cover every RPC method and every sandbox engine call.
+//! Their bucket bounds come from `METRICS_BUCKETS`, or per histogram from
+//! `METRICS_REQUEST_BUCKETS` / `METRICS_SANDBOX_BUCKETS`. Scrapers that ask
+//! for OpenMetrics also get, per bucket, the trace id of the latest call
+//! that landed in it as an exemplar (`METRICS_EXEMPLARS`), so a slow bucket
+//! on a dashboard links straight to a trace.
+//!
+//! Faults injected by chaos mode are counted per subsystem and kind.
+//!
+//! `AppMetrics` is also the [`SandboxMetrics`] sink of the sandbox crate:
+//! workspace bytes read and written, process spawn, wasm compile and
+//! execution, micro instance start and agent LLM latency, with the sandbox
+//! bucket bounds.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use opentelemetry::trace::TraceId;
+use parking_lot::{Mutex, RwLock};
+use sandbox::run::SandboxRun;
+use sandbox::{AgentDispatcher, LockContention, SandboxFs, SandboxMetrics, SandboxMicro};
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::faults::Faults;
+use crate::{quota, tenant, AppState};
+
+/// Bucket bounds in seconds; fine-grained below one second, where most
+/// calls end.
+const DEFAULT_BUCKETS: [f64; 15] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+const OPENMETRICS: &str = "application/openmetrics-text";
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(export))
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Last sampled value of every saturation gauge.
+#[derive(Debug, Default)]
+struct Gauges {
+    db_pool_size: AtomicU64,
+    db_pool_idle: AtomicU64,
+    /// `f64` seconds stored as raw bits.
+    db_pool_acquire_seconds: AtomicU64,
+    agent_pending: AtomicU64,
+    agent_running: AtomicU64,
+    agent_waiting: AtomicU64,
+    micro_instances: AtomicU64,
+    run_sessions: AtomicU64,
+    run_interactive_sessions: AtomicU64,
+    workspace_disk_bytes: AtomicU64,
+    /// Cumulative, so rendered as counters.
+    sandbox_locks: Mutex<Vec<LockContention>>,
+}
+
+fn set(gauge: &AtomicU64, value: usize) {
+    gauge.store(value as u64, Ordering::Relaxed);
+}
+
+#[derive(Debug, Default)]
+struct SandboxDrift {
+    orphans: u64,
+    bytes: u64,
+    removed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MetricsConfig {
+    request_buckets: Arc<[f64]>,
+    sandbox_buckets: Arc<[f64]>,
+    exemplars: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            request_buckets: DEFAULT_BUCKETS.into(),
+            sandbox_buckets: DEFAULT_BUCKETS.into(),
+            exemplars: true,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let default = buckets(config, "METRICS_BUCKETS", &DEFAULT_BUCKETS);
+        Self {
+            request_buckets: buckets(config, "METRICS_REQUEST_BUCKETS", &default).into(),
+            sandbox_buckets: buckets(config, "METRICS_SANDBOX_BUCKETS", &default).into(),
+            exemplars: config.get("METRICS_EXEMPLARS", true),
+        }
+    }
+}
+
+/// Ascending, positive bucket bounds in seconds; `+Inf` is always added.
+fn buckets(config: &Config, key: &'static str, default: &[f64]) -> Vec<f64> {
+    let fallback: Vec<String> = default.iter().map(f64::to_string).collect();
+    let fallback: Vec<&str> = fallback.iter().map(String::as_str).collect();
+    let mut bounds = Vec::new();
+    for item in config.list(key, &fallback) {
+        match item.parse::<f64>() {
+            Ok(bound) if bound.is_finite() && bound > 0.0 => bounds.push(bound),
+            _ => {
+                config.invalid(key, format!("`{item}` is not a positive number of seconds"));
+                return default.to_vec();
+            }
+        }
+    }
+    if bounds.is_empty() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        config.invalid(key, "expected ascending bucket bounds");
+        return default.to_vec();
+    }
+    bounds
+}
+
+/// The latest traced observation of a bucket.
+#[derive(Debug, Clone)]
+struct Exemplar {
+    trace_id: TraceId,
+    seconds: f64,
+    /// Unix time of the observation.
+    at: f64,
+}
+
+/// One label set of a histogram. Buckets are counted individually rather
+/// than cumulatively, so an observation touches a single counter.
+#[derive(Debug)]
+struct Histogram {
+    /// One per bound plus `+Inf`.
+    counts: Box<[AtomicU64]>,
+    sum_nanos: AtomicU64,
+    exemplars: Box<[Mutex<Option<Exemplar>>]>,
+}
+
+impl Histogram {
+    fn new(buckets: usize) -> Self {
+        Self {
+            counts: (0..=buckets).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+            exemplars: (0..=buckets).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+}
+
+/// A latency histogram keyed by label values.
+#[derive(Debug)]
+struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    bounds: Arc<[f64]>,
+    /// Keyed by the rendered label pairs, e.g. `method="fs.read"`.
+    series: RwLock<BTreeMap<String, Arc<Histogram>>>,
+}
+
+impl HistogramVec {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        bounds: Arc<[f64]>,
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            bounds,
+            series: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, values: &[&str], elapsed: Duration, trace_id: Option<TraceId>) {
+        let key = self
+            .labels
+            .iter()
+            .zip(values)
+            .map(|(label, value)| format!("{label}=\"{value}\""))
+            .collect::<Vec<_>>()
+            .join(",");
+        let existing = self.series.read().get(&key).cloned();
+        let histogram = existing.unwrap_or_else(|| {
+            self.series
+                .write()
+                .entry(key)
+                .or_insert_with(|| Arc::new(Histogram::new(self.bounds.len())))
+                .clone()
+        });
+        let seconds = elapsed.as_secs_f64();
+        let bucket = self.bounds.partition_point(|bound| *bound < seconds);
+        histogram.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram
+            .sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if let Some(trace_id) = trace_id {
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            *histogram.exemplars[bucket].lock() = Some(Exemplar {
+                trace_id,
+                seconds,
+                at,
+            });
+        }
+    }
+
+    fn render(&self, out: &mut String, exemplars: bool) {
+        let name = self.name;
+        let _ = writeln!(out, "# HELP {name} {}", self.help);
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (labels, histogram) in self.series.read().iter() {
+            let mut count = 0;
+            let bounds = self.bounds.iter().map(f64::to_string);
+            for (index, le) in bounds.chain(["+Inf".to_string()]).enumerate() {
+                count += histogram.counts[index].load(Ordering::Relaxed);
+                let _ = write!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+                if let Some(exemplar) = exemplars
+                    .then(|| histogram.exemplars[index].lock().clone())
+                    .flatten()
+                {
+                    let _ = write!(
+                        out,
+                        " # {{trace_id=\"{}\"}} {} {:.3}",
+                        exemplar.trace_id, exemplar.seconds, exemplar.at
+                    );
+                }
+                out.push('\n');
+            }
+            let sum = Duration::from_nanos(histogram.sum_nanos.load(Ordering::Relaxed));
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", sum.as_secs_f64());
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AppMetrics {
+    pub(crate) project_cache: CacheCounters,
+    pub(crate) listing_cache: CacheCounters,
+    pub(crate) llm_cache: CacheCounters,
+    pub(crate) auth_cache: CacheCounters,
+    /// Users whose cached authentication was dropped.
+    auth_cache_invalidations: AtomicU64,
+    /// Keyed by requested method name; only known deprecated names are
+    /// recorded, so the label set stays bounded.
+    deprecated_calls: Mutex<BTreeMap<String, u64>>,
+    /// Keyed by canonical method name of calls that ran past their deadline.
+    rpc_timeouts: Mutex<BTreeMap<String, u64>>,
+    /// Keyed by admission class: calls shed for lack of a free slot.
+    admission_shed: Mutex<BTreeMap<&'static str, u64>>,
+    /// Keyed by `default` or the method with a rate limit of its own.
+    rate_limited: Mutex<BTreeMap<String, u64>>,
+    /// Keyed by table: rows deleted by the retention jobs.
+    retention_pruned: Mutex<BTreeMap<&'static str, u64>>,
+    /// Keyed by kind: orphans and their bytes found by the last sandbox GC
+    /// run on this instance, and orphans removed so far.
+    sandbox_drift: Mutex<BTreeMap<&'static str, SandboxDrift>>,
+    sandbox_fs_read_bytes: AtomicU64,
+    sandbox_fs_written_bytes: AtomicU64,
+    /// Chaos mode's injectors; their counters are read at render time.
+    faults: Faults,
+    gauges: Gauges,
+    /// Keyed by canonical method name; unknown methods are not recorded.
+    request_duration: HistogramVec,
+    sandbox_duration: HistogramVec,
+    /// Internals reported through [`SandboxMetrics`]; they carry no trace
+    /// context, so no exemplars either.
+    sandbox_step_duration: HistogramVec,
+    /// Keyed by configured image name.
+    micro_start_duration: HistogramVec,
+    agent_llm_duration: HistogramVec,
+    exemplars: bool,
+}
+
+impl Default for AppMetrics {
+    fn default() -> Self {
+        Self::new(MetricsConfig::default())
+    }
+}
+
+impl AppMetrics {
+    pub(crate) fn new(config: MetricsConfig) -> Self {
+        Self {
+            project_cache: CacheCounters::default(),
+            listing_cache: CacheCounters::default(),
+            llm_cache: CacheCounters::default(),
+            auth_cache: CacheCounters::default(),
+            auth_cache_invalidations: AtomicU64::new(0),
+            deprecated_calls: Mutex::default(),
+            rpc_timeouts: Mutex::default(),
+            admission_shed: Mutex::default(),
+            rate_limited: Mutex::default(),
+            retention_pruned: Mutex::default(),
+            sandbox_drift: Mutex::default(),
+            sandbox_fs_read_bytes: AtomicU64::new(0),
+            sandbox_fs_written_bytes: AtomicU64::new(0),
+            faults: Faults::default(),
+            gauges: Gauges::default(),
+            request_duration: HistogramVec::new(
+                "api_request_duration_seconds",
+                "Time to answer RPC calls, by canonical method.",
+                &["method"],
+                config.request_buckets,
+            ),
+            sandbox_duration: HistogramVec::new(
+                "api_sandbox_duration_seconds",
+                "Time sandbox engines took to execute calls, by engine and action.",
+                &["engine", "action"],
+                config.sandbox_buckets.clone(),
+            ),
+            sandbox_step_duration: HistogramVec::new(
+                "api_sandbox_step_duration_seconds",
+                "Time of sandbox internals: process spawn, wasm compile and execution.",
+                &["step"],
+                config.sandbox_buckets.clone(),
+            ),
+            micro_start_duration: HistogramVec::new(
+                "api_micro_start_duration_seconds",
+                "Time to start micro instances, including the init script, by image.",
+                &["image"],
+                config.sandbox_buckets.clone(),
+            ),
+            agent_llm_duration: HistogramVec::new(
+                "api_agent_llm_duration_seconds",
+                "Time of agent chat completion requests, by outcome.",
+                &["outcome"],
+                config.sandbox_buckets,
+            ),
+            exemplars: config.exemplars,
+        }
+    }
+
+    pub(crate) fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    pub(crate) fn deprecated_call(&self, method: &str) {
+        *self
+            .deprecated_calls
+            .lock()
+            .entry(method.to_string())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn auth_cache_invalidation(&self) {
+        self.auth_cache_invalidations
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rpc_timeout(&self, method: &str) {
+        *self
+            .rpc_timeouts
+            .lock()
+            .entry(method.to_string())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn admission_shed(&self, class: &'static str) {
+        *self.admission_shed.lock().entry(class).or_default() += 1;
+    }
+
+    pub(crate) fn rate_limited(&self, scope: &str) {
+        *self
+            .rate_limited
+            .lock()
+            .entry(scope.to_string())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn retention_pruned(&self, table: &'static str, rows: u64) {
+        *self.retention_pruned.lock().entry(table).or_default() += rows;
+    }
+
+    pub(crate) fn sandbox_drift(&self, kind: &'static str, orphans: u64, bytes: u64, removed: u64) {
+        let mut drift = self.sandbox_drift.lock();
+        let drift = drift.entry(kind).or_default();
+        drift.orphans = orphans;
+        drift.bytes = bytes;
+        drift.removed += removed;
+    }
+
+    pub(crate) fn request_duration(
+        &self,
+        method: &str,
+        elapsed: Duration,
+        trace_id: Option<TraceId>,
+    ) {
+        self.request_duration.observe(&[method], elapsed, trace_id);
+    }
+
+    pub(crate) fn sandbox_duration(
+        &self,
+        engine: &str,
+        action: &str,
+        elapsed: Duration,
+        trace_id: Option<TraceId>,
+    ) {
+        self.sandbox_duration
+            .observe(&[engine, action], elapsed, trace_id);
+    }
+
+    /// The Prometheus text format, or OpenMetrics with exemplars.
+    pub(crate) fn render(&self, openmetrics: bool) -> String {
+        let mut out = String::new();
+        self.render_counters(&mut out);
+        self.render_gauges(&mut out);
+        self.render_sandbox_locks(&mut out);
+        self.render_sandbox_drift(&mut out);
+        let exemplars = openmetrics && self.exemplars;
+        self.request_duration.render(&mut out, exemplars);
+        self.sandbox_duration.render(&mut out, exemplars);
+        self.sandbox_step_duration.render(&mut out, false);
+        self.micro_start_duration.render(&mut out, false);
+        self.agent_llm_duration.render(&mut out, false);
+        if openmetrics {
+            out = to_openmetrics(&out);
+        }
+        out
+    }
+
+    fn render_counters(&self, out: &mut String) {
+        out.push_str("# HELP api_cache_requests_total Cache lookups by cache and result.\n");
+        out.push_str("# TYPE api_cache_requests_total counter\n");
+        for (cache, counters) in [
+            ("project", &self.project_cache),
+            ("project_files", &self.listing_cache),
+            ("llm", &self.llm_cache),
+            ("auth", &self.auth_cache),
+        ] {
+            for (result, value) in [("hit", &counters.hits), ("miss", &counters.misses)] {
+                let _ = writeln!(
+                    out,
+                    "api_cache_requests_total{{cache=\"{cache}\",result=\"{result}\"}} {}",
+                    value.load(Ordering::Relaxed)
+                );
+            }
+        }
+        out.push_str(
+            "# HELP api_auth_cache_invalidations_total Users whose cached authentication was dropped.\n",
+        );
+        out.push_str("# TYPE api_auth_cache_invalidations_total counter\n");
+        let _ = writeln!(
+            out,
+            "api_auth_cache_invalidations_total {}",
+            self.auth_cache_invalidations.load(Ordering::Relaxed)
+        );
+        out.push_str(
+            "# HELP api_rpc_deprecated_calls_total Calls to deprecated RPC method names.\n",
+        );
+        out.push_str("# TYPE api_rpc_deprecated_calls_total counter\n");
+        for (method, count) in self.deprecated_calls.lock().iter() {
+            let _ = writeln!(
+                out,
+                "api_rpc_deprecated_calls_total{{method=\"{method}\"}} {count}"
+            );
+        }
+        out.push_str("# HELP api_rpc_timeouts_total RPC calls aborted at their deadline.\n");
+        out.push_str("# TYPE api_rpc_timeouts_total counter\n");
+        for (method, count) in self.rpc_timeouts.lock().iter() {
+            let _ = writeln!(out, "api_rpc_timeouts_total{{method=\"{method}\"}} {count}");
+        }
+        out.push_str(
+            "# HELP api_admission_shed_total Calls shed by admission control, by class.\n",
+        );
+        out.push_str("# TYPE api_admission_shed_total counter\n");
+        for (class, count) in self.admission_shed.lock().iter() {
+            let _ = writeln!(out, "api_admission_shed_total{{class=\"{class}\"}} {count}");
+        }
+        out.push_str("# HELP api_rate_limited_total Calls rejected by rate limits, by scope.\n");
+        out.push_str("# TYPE api_rate_limited_total counter\n");
+        for (scope, count) in self.rate_limited.lock().iter() {
+            let _ = writeln!(out, "api_rate_limited_total{{scope=\"{scope}\"}} {count}");
+        }
+        out.push_str(
+            "# HELP api_retention_pruned_rows_total Rows deleted by retention jobs, by table.\n",
+        );
+        out.push_str("# TYPE api_retention_pruned_rows_total counter\n");
+        for (table, count) in self.retention_pruned.lock().iter() {
+            let _ = writeln!(
+                out,
+                "api_retention_pruned_rows_total{{table=\"{table}\"}} {count}"
+            );
+        }
+        out.push_str(
+            "# HELP api_sandbox_fs_bytes_total Bytes of workspace files read and written.\n",
+        );
+        out.push_str("# TYPE api_sandbox_fs_bytes_total counter\n");
+        for (direction, bytes) in [
+            ("read", &self.sandbox_fs_read_bytes),
+            ("write", &self.sandbox_fs_written_bytes),
+        ] {
+            let _ = writeln!(
+                out,
+                "api_sandbox_fs_bytes_total{{direction=\"{direction}\"}} {}",
+                bytes.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str(
+            "# HELP api_chaos_faults_total Faults injected by chaos mode, by subsystem and kind.\n",
+        );
+        out.push_str("# TYPE api_chaos_faults_total counter\n");
+        for (subsystem, counts) in self.faults.counts() {
+            for (fault, count) in [("delay", counts.delayed), ("error", counts.failed)] {
+                let _ = writeln!(
+                    out,
+                    "api_chaos_faults_total{{subsystem=\"{subsystem}\",fault=\"{fault}\"}} {count}"
+                );
+            }
+        }
+    }
+
+    fn render_gauges(&self, out: &mut String) {
+        let gauges = &self.gauges;
+        let load = |gauge: &AtomicU64| gauge.load(Ordering::Relaxed);
+        let mut gauge = |name: &str, help: &str, samples: &[(&str, String)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        gauge(
+            "api_db_pool_connections",
+            "Open Postgres connections by state.",
+            &[
+                ("{state=\"idle\"}", load(&gauges.db_pool_idle).to_string()),
+                (
+                    "{state=\"in_use\"}",
+                    load(&gauges.db_pool_size)
+                        .saturating_sub(load(&gauges.db_pool_idle))
+                        .to_string(),
+                ),
+            ],
+        );
+        gauge(
+            "api_db_pool_acquire_seconds",
+            "Time the last sample waited for a pooled connection.",
+            &[(
+                "",
+                f64::from_bits(load(&gauges.db_pool_acquire_seconds)).to_string(),
+            )],
+        );
+        gauge(
+            "api_agent_tasks",
+            "Live agent tasks by status; pending tasks wait for a concurrency permit.",
+            &[
+                (
+                    "{status=\"pending\"}",
+                    load(&gauges.agent_pending).to_string(),
+                ),
+                (
+                    "{status=\"running\"}",
+                    load(&gauges.agent_running).to_string(),
+                ),
+                (
+                    "{status=\"waiting_for_input\"}",
+                    load(&gauges.agent_waiting).to_string(),
+                ),
+            ],
+        );
+        gauge(
+            "api_micro_instances",
+            "Running micro VM instances.",
+            &[("", load(&gauges.micro_instances).to_string())],
+        );
+        gauge(
+            "api_run_sessions",
+            "run.exec and project.run processes in flight.",
+            &[("", load(&gauges.run_sessions).to_string())],
+        );
+        gauge(
+            "api_run_interactive_sessions",
+            "Open run.session.* processes, including exited ones not yet read.",
+            &[("", load(&gauges.run_interactive_sessions).to_string())],
+        );
+        gauge(
+            "api_workspace_disk_bytes",
+            "Bytes stored below the workspaces directories of all tenants.",
+            &[("", load(&gauges.workspace_disk_bytes).to_string())],
+        );
+    }
+
+    fn render_sandbox_drift(&self, out: &mut String) {
+        let drift = self.sandbox_drift.lock();
+        for (name, kind, help, value) in [
+            (
+                "api_sandbox_orphans",
+                "gauge",
+                "Sandbox entries without a database row found by the last GC run, by kind.",
+                &(|drift: &SandboxDrift| drift.orphans) as &dyn Fn(&SandboxDrift) -> u64,
+            ),
+            (
+                "api_sandbox_orphan_bytes",
+                "gauge",
+                "Bytes held by the orphans of the last GC run, by kind.",
+                &|drift: &SandboxDrift| drift.bytes,
+            ),
+            (
+                "api_sandbox_gc_removed_total",
+                "counter",
+                "Orphaned sandbox entries deleted by the GC, by kind.",
+                &|drift: &SandboxDrift| drift.removed,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (label, drift) in drift.iter() {
+                let _ = writeln!(out, "{name}{{kind=\"{label}\"}} {}", value(drift));
+            }
+        }
+    }
+
+    fn render_sandbox_locks(&self, out: &mut String) {
+        let locks = self.gauges.sandbox_locks.lock();
+        let mut counter = |name: &str, help: &str, value: &dyn Fn(&LockContention) -> String| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for lock in locks.iter() {
+                let _ = writeln!(out, "{name}{{map=\"{}\"}} {}", lock.map, value(lock));
+            }
+        };
+        counter(
+            "api_sandbox_lock_acquisitions_total",
+            "Shard locks taken on sandbox instance and task tables.",
+            &|lock| lock.acquisitions.to_string(),
+        );
+        counter(
+            "api_sandbox_lock_contended_total",
+            "Shard locks that were already held and had to be waited for.",
+            &|lock| lock.contended.to_string(),
+        );
+        counter(
+            "api_sandbox_lock_wait_seconds_total",
+            "Time spent waiting for held shard locks.",
+            &|lock| lock.wait.as_secs_f64().to_string(),
+        );
+    }
+}
+
+impl SandboxMetrics for AppMetrics {
+    fn fs_read(&self, bytes: u64) {
+        self.sandbox_fs_read_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn fs_written(&self, bytes: u64) {
+        self.sandbox_fs_written_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn run_spawn(&self, elapsed: Duration) {
+        self.sandbox_step_duration
+            .observe(&["run_spawn"], elapsed, None);
+    }
+
+    fn wasm_compile(&self, elapsed: Duration) {
+        self.sandbox_step_duration
+            .observe(&["wasm_compile"], elapsed, None);
+    }
+
+    fn wasm_execute(&self, elapsed: Duration) {
+        self.sandbox_step_duration
+            .observe(&["wasm_execute"], elapsed, None);
+    }
+
+    fn micro_start(&self, image: &str, elapsed: Duration) {
+        self.micro_start_duration.observe(&[image], elapsed, None);
+    }
+
+    fn llm_request(&self, success: bool, elapsed: Duration) {
+        let outcome = if success { "ok" } else { "error" };
+        self.agent_llm_duration.observe(&[outcome], elapsed, None);
+    }
+}
+
+/// Handles the sampler reads from; cloned out of `AppState` at startup.
+pub(crate) struct SamplerSources {
+    pub(crate) pool: PgPool,
+    pub(crate) sandbox: Arc<SandboxFs>,
+    pub(crate) run: Arc<SandboxRun>,
+    pub(crate) micro: Arc<SandboxMicro>,
+    pub(crate) agents: Arc<AgentDispatcher>,
+}
+
+impl SamplerSources {
+    async fn sample(&self, gauges: &Gauges) {
+        set(&gauges.db_pool_size, self.pool.size() as usize);
+        set(&gauges.db_pool_idle, self.pool.num_idle());
+        let started = Instant::now();
+        match self.pool.acquire().await {
+            Ok(_connection) => gauges
+                .db_pool_acquire_seconds
+                .store(started.elapsed().as_secs_f64().to_bits(), Ordering::Relaxed),
+            Err(err) => warn!(error = %err, "metrics sampler failed to acquire a connection"),
+        }
+
+        let tasks = self.agents.task_counts();
+        set(&gauges.agent_pending, tasks.pending);
+        set(&gauges.agent_running, tasks.running);
+        set(&gauges.agent_waiting, tasks.waiting_for_input);
+        set(&gauges.micro_instances, self.micro.active_instances());
+        set(&gauges.run_sessions, self.run.active_sessions());
+        set(&gauges.run_interactive_sessions, self.run.open_sessions());
+        let mut locks = vec![self.micro.lock_contention(), self.run.lock_contention()];
+        locks.extend(self.agents.lock_contention());
+        *gauges.sandbox_locks.lock() = locks;
+
+        let root: PathBuf = self.sandbox.base_dir().to_path_buf();
+        let measure = move || {
+            tenant::present(&root)
+                .into_iter()
+                .map(|tenant_id| {
+                    quota::dir_size(
+                        &root
+                            .join(tenant::directory_relative(tenant_id))
+                            .join("workspaces"),
+                    )
+                })
+                .sum::<u64>()
+        };
+        match tokio::task::spawn_blocking(measure).await {
+            Ok(bytes) => gauges.workspace_disk_bytes.store(bytes, Ordering::Relaxed),
+            Err(err) => warn!(error = %err, "metrics sampler failed to measure workspaces"),
+        }
+    }
+}
+
+pub(crate) fn spawn_sampler(
+    metrics: Arc<AppMetrics>,
+    sources: SamplerSources,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            sources.sample(&metrics.gauges).await;
+        }
+    })
+}
+
+/// OpenMetrics names counter families without their `_total` suffix and
+/// ends the exposition with `# EOF`.
+fn to_openmetrics(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 6);
+    for line in text.lines() {
+        let metadata = line
+            .strip_prefix("# HELP ")
+            .map(|rest| ("# HELP ", rest))
+            .or_else(|| line.strip_prefix("# TYPE ").map(|rest| ("# TYPE ", rest)));
+        match metadata.and_then(|(kind, rest)| {
+            let (name, tail) = rest.split_once(' ')?;
+            Some((kind, name.strip_suffix("_total")?, tail))
+        }) {
+            Some((kind, family, tail)) => {
+                let _ = writeln!(out, "{kind}{family} {tail}");
+            }
+            None => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+async fn export(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(OPENMETRICS));
+    let content_type = if openmetrics {
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    } else {
+        "text/plain; version=0.0.4"
+    };
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        state.metrics.render(openmetrics),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_emits_labelled_cache_counters() {
+        let metrics = AppMetrics::default();
+        metrics.project_cache.hit();
+        metrics.project_cache.hit();
+        metrics.listing_cache.miss();
+        let text = metrics.render(false);
+        assert!(text.contains("api_cache_requests_total{cache=\"project\",result=\"hit\"} 2"));
+        assert!(
+            text.contains("api_cache_requests_total{cache=\"project_files\",result=\"miss\"} 1")
+        );
+        assert!(text.contains("# TYPE api_cache_requests_total counter"));
+    }
+
+    #[tokio::test]
+    async fn render_counts_injected_faults_of_enabled_subsystems() {
+        let faults = Faults {
+            llm: sandbox::FaultInjector::new(sandbox::FaultConfig {
+                error_rate: 1.0,
+                ..Default::default()
+            }),
+            ..Faults::default()
+        };
+        let metrics = AppMetrics::default().with_faults(faults.clone());
+        assert!(faults.llm.should_fail("llm.chat").await);
+        let text = metrics.render(false);
+        assert!(text.contains("api_chaos_faults_total{subsystem=\"llm\",fault=\"error\"} 1"));
+        assert!(text.contains("api_chaos_faults_total{subsystem=\"llm\",fault=\"delay\"} 0"));
+        assert!(!text.contains("subsystem=\"fs\""));
+    }
+
+    #[test]
+    fn render_emits_sampled_gauges() {
+        let metrics = AppMetrics::default();
+        set(&metrics.gauges.db_pool_size, 5);
+        set(&metrics.gauges.db_pool_idle, 2);
+        set(&metrics.gauges.agent_pending, 3);
+        metrics
+            .gauges
+            .db_pool_acquire_seconds
+            .store(0.25f64.to_bits(), Ordering::Relaxed);
+        let text = metrics.render(false);
+        assert!(text.contains("api_db_pool_connections{state=\"in_use\"} 3"));
+        assert!(text.contains("api_db_pool_acquire_seconds 0.25"));
+        assert!(text.contains("api_agent_tasks{status=\"pending\"} 3"));
+        assert!(text.contains("# TYPE api_workspace_disk_bytes gauge"));
+    }
+
+    #[test]
+    fn render_emits_sandbox_lock_counters() {
+        let metrics = AppMetrics::default();
+        *metrics.gauges.sandbox_locks.lock() = vec![LockContention {
+            map: "micro_instances",
+            acquisitions: 40,
+            contended: 3,
+            wait: Duration::from_millis(1500),
+        }];
+        let text = metrics.render(false);
+        assert!(text.contains("api_sandbox_lock_acquisitions_total{map=\"micro_instances\"} 40"));
+        assert!(text.contains("api_sandbox_lock_contended_total{map=\"micro_instances\"} 3"));
+        assert!(text.contains("api_sandbox_lock_wait_seconds_total{map=\"micro_instances\"} 1.5"));
+    }
+
+    #[test]
+    fn sandbox_drift_keeps_the_last_run_and_counts_removals() {
+        let metrics = AppMetrics::default();
+        metrics.sandbox_drift("user", 3, 4096, 3);
+        metrics.sandbox_drift("user", 1, 10, 1);
+        let text = metrics.render(false);
+        assert!(text.contains("api_sandbox_orphans{kind=\"user\"} 1"));
+        assert!(text.contains("api_sandbox_orphan_bytes{kind=\"user\"} 10"));
+        assert!(text.contains("api_sandbox_gc_removed_total{kind=\"user\"} 4"));
+    }
+
+    #[test]
+    fn histograms_count_cumulatively_and_carry_exemplars() {
+        let config = MetricsConfig {
+            request_buckets: [0.01, 0.1, 1.0].into(),
+            ..MetricsConfig::default()
+        };
+        let metrics = AppMetrics::new(config);
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        metrics.request_duration("fs.read", Duration::from_millis(5), None);
+        metrics.request_duration("fs.read", Duration::from_millis(50), Some(trace_id));
+        metrics.request_duration("fs.read", Duration::from_secs(3), None);
+        metrics.sandbox_duration("micro", "execute", Duration::from_millis(20), None);
+
+        let text = metrics.render(false);
+        assert!(text.contains("# TYPE api_request_duration_seconds histogram"));
+        assert!(text
+            .contains("api_request_duration_seconds_bucket{method=\"fs.read\",le=\"0.01\"} 1\n"));
+        assert!(
+            text.contains("api_request_duration_seconds_bucket{method=\"fs.read\",le=\"0.1\"} 2\n")
+        );
+        assert!(text
+            .contains("api_request_duration_seconds_bucket{method=\"fs.read\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("api_request_duration_seconds_sum{method=\"fs.read\"} 3.055"));
+        assert!(text.contains("api_request_duration_seconds_count{method=\"fs.read\"} 3"));
+        assert!(text.contains(
+            "api_sandbox_duration_seconds_bucket{engine=\"micro\",action=\"execute\",le=\"0.025\"} 1"
+        ));
+        assert!(!text.contains("trace_id"));
+
+        let text = metrics.render(true);
+        assert!(
+            text.contains("le=\"0.1\"} 2 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.05 ")
+        );
+        assert!(text.contains("# TYPE api_cache_requests counter"));
+        assert!(text.contains("api_cache_requests_total{cache=\"project\",result=\"hit\"} 0"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn sandbox_internals_are_exported() {
+        let metrics = AppMetrics::default();
+        metrics.fs_read(10);
+        metrics.fs_written(4);
+        metrics.fs_written(4);
+        metrics.wasm_compile(Duration::from_millis(3));
+        metrics.micro_start("python", Duration::from_millis(200));
+        metrics.llm_request(false, Duration::from_secs(2));
+
+        let text = metrics.render(false);
+        assert!(text.contains("api_sandbox_fs_bytes_total{direction=\"read\"} 10\n"));
+        assert!(text.contains("api_sandbox_fs_bytes_total{direction=\"write\"} 8\n"));
+        assert!(text.contains("api_sandbox_step_duration_seconds_count{step=\"wasm_compile\"} 1"));
+        assert!(!text.contains("step=\"run_spawn\""));
+        assert!(text.contains("api_micro_start_duration_seconds_count{image=\"python\"} 1"));
+        assert!(
+            text.contains("api_agent_llm_duration_seconds_bucket{outcome=\"error\",le=\"2.5\"} 1")
+        );
+    }
+
+    #[test]
+    fn bucket_settings_must_ascend() {
+        let config = Config::new(
+            None,
+            None,
+            Box::new(|key| match key {
+                "METRICS_BUCKETS" => Some("0.05,0.5,5".to_string()),
+                "METRICS_SANDBOX_BUCKETS" => Some("1,0.5".to_string()),
+                _ => None,
+            }),
+        )
+        .unwrap();
+        let settings = MetricsConfig::from_config(&config);
+        assert_eq!(&*settings.request_buckets, &[0.05, 0.5, 5.0]);
+        assert_eq!(&*settings.sandbox_buckets, &[0.05, 0.5, 5.0]);
+        assert!(config.finish().is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! Per-user notification inbox. Agent tasks that finish or wait for input,
+//! project shares, quota warnings and finished jobs land in
+//! `notifications`; `notify.list` and `notify.markRead` page through and
+//! acknowledge them, and `GET /notify/ws` pushes new ones as they arrive so
+//! clients don't have to poll. Inserts are announced with `pg_notify`, so a
+//! socket on any api instance sees notifications created by another. The
+//! socket also carries the user's domain events from this instance.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use chrono::{DateTime, Utc};
+use sandbox::AgentTaskStatus;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::postgres::{PgListener, PgRow};
+use sqlx::types::Json;
+use sqlx::{PgPool, Row};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::errors::ErrorCode;
+use crate::events::{DomainEvent, EventRecorder};
+use crate::rest::error_response;
+use crate::{authenticate_request, AppState, Peer, RequestContext, RpcMethodError};
+
+const CHANNEL: &str = "notifications";
+const LIVE_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+const COLUMNS: &str = "id, user_id, kind, title, data, read_at, created_at";
+
+/// A stored notification on its way to the owner's open sockets.
+#[derive(Debug)]
+struct Pushed {
+    user_id: i32,
+    notification: Value,
+}
+
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    pool: PgPool,
+    live: broadcast::Sender<Arc<Pushed>>,
+}
+
+impl Notifier {
+    pub(crate) fn new(pool: PgPool) -> Self {
+        let (live, _) = broadcast::channel(LIVE_CAPACITY);
+        Self { pool, live }
+    }
+
+    /// Stores a notification in the background. Failures are logged, never
+    /// surfaced to whoever caused the event.
+    fn emit(&self, user_id: i32, kind: &str, title: &str, data: Value) {
+        let pool = self.pool.clone();
+        let kind = kind.to_string();
+        let title = title.to_string();
+        tokio::spawn(async move {
+            // Only ids go through pg_notify; its payload is capped at 8000
+            // bytes, so listeners load the row themselves.
+            let result = sqlx::query(
+                "WITH created AS ( \
+                    INSERT INTO notifications (user_id, kind, title, data) \
+                    VALUES ($1, $2, $3, $4) RETURNING id, user_id \
+                 ) \
+                 SELECT pg_notify($5, json_build_object('id', id, 'user_id', user_id)::text) \
+                 FROM created",
+            )
+            .bind(user_id)
+            .bind(&kind)
+            .bind(&title)
+            .bind(Json(data))
+            .bind(CHANNEL)
+            .execute(&pool)
+            .await;
+            if let Err(err) = result {
+                warn!(kind = %kind, user_id, error = %err, "failed to store notification");
+            }
+        });
+    }
+
+    /// Forwards `pg_notify` announcements to this instance's sockets.
+    pub(crate) fn spawn_listener(&self) -> JoinHandle<()> {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = notifier.listen().await {
+                    warn!(error = %err, "notification listener failed, reconnecting");
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        })
+    }
+
+    async fn listen(&self) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        loop {
+            let announcement = listener.recv().await?;
+            if self.live.receiver_count() == 0 {
+                continue;
+            }
+            let Some(id) = serde_json::from_str::<Value>(announcement.payload())
+                .ok()
+                .and_then(|payload| payload.get("id").and_then(Value::as_i64))
+            else {
+                continue;
+            };
+            let row = sqlx::query(&format!(
+                "SELECT {COLUMNS} FROM notifications WHERE id = $1"
+            ))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+            if let Some(row) = row {
+                let _ = self.live.send(Arc::new(Pushed {
+                    user_id: row.get("user_id"),
+                    notification: notification_value(&row),
+                }));
+            }
+        }
+    }
+
+    async fn unread(&self, user_id: i32) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM notifications WHERE user_id = $1 AND read_at IS NULL",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// Sends a `hello` with the unread count, then every new notification of
+    /// `user_id` and, as `event` messages, the user's domain events. A
+    /// `resync` message means notification pushes were dropped and the
+    /// client should reload through `notify.list`.
+    async fn serve(
+        self,
+        mut socket: WebSocket,
+        user_id: i32,
+        mut events: broadcast::Receiver<Arc<DomainEvent>>,
+    ) {
+        let mut live = self.live.subscribe();
+        let unread = match self.unread(user_id).await {
+            Ok(unread) => unread,
+            Err(err) => {
+                warn!(user_id, error = %err, "failed to count unread notifications");
+                return;
+            }
+        };
+        if send(&mut socket, json!({ "type": "hello", "unread": unread }))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        loop {
+            let message = tokio::select! {
+                pushed = live.recv() => match pushed {
+                    Ok(pushed) if pushed.user_id == user_id => {
+                        json!({ "type": "notification", "notification": pushed.notification })
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => json!({ "type": "resync" }),
+                    Err(RecvError::Closed) => break,
+                },
+                event = events.recv() => match event {
+                    Ok(event) if event.user_id() == Some(user_id) => {
+                        json!({ "type": "event", "event": event.envelope() })
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                incoming = socket.recv() => match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+            };
+            if send(&mut socket, message).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: Value) -> Result<(), axum::Error> {
+    socket.send(Message::Text(message.to_string())).await
+}
+
+fn notification_value(row: &PgRow) -> Value {
+    let read_at: Option<DateTime<Utc>> = row.get("read_at");
+    json!({
+        "id": row.get::<i64, _>("id"),
+        "kind": row.get::<String, _>("kind"),
+        "title": row.get::<String, _>("title"),
+        "data": row.get::<Json<Value>, _>("data").0,
+        "read": read_at.is_some(),
+        "read_at": read_at.map(|at| at.to_rfc3339()),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+    })
+}
+
+/// Turns domain events into notifications: top-level agent tasks that
+/// finish or need an answer (subtasks are covered by their parent), project
+/// shares, quota warnings and finished background jobs.
+impl EventRecorder for Notifier {
+    fn record(&self, event: &DomainEvent) {
+        if let (Some(user_id), Some((kind, title, data))) = (event.user_id(), notification(event)) {
+            self.emit(user_id, kind, title, data);
+        }
+    }
+}
+
+/// The notification kind, title and data for `event`, if it raises one.
+fn notification(event: &DomainEvent) -> Option<(&'static str, &'static str, Value)> {
+    match event {
+        DomainEvent::Agent(snapshot) => {
+            if snapshot.parent_id.is_some() {
+                return None;
+            }
+            let (kind, title) = match snapshot.status {
+                AgentTaskStatus::Completed => ("agent.completed", "Agent task completed"),
+                AgentTaskStatus::Failed => ("agent.failed", "Agent task failed"),
+                AgentTaskStatus::WaitingForInput => {
+                    ("agent.waiting", "Agent task is waiting for input")
+                }
+                _ => return None,
+            };
+            let data = json!({
+                "task_id": snapshot.id,
+                "objective": snapshot.objective,
+                "summary": snapshot.summary,
+                "question": snapshot.pending_question,
+            });
+            Some((kind, title, data))
+        }
+        DomainEvent::ProjectShared { .. } => Some((
+            "project.shared",
+            "A project was shared with you",
+            event.data(),
+        )),
+        DomainEvent::QuotaWarning { .. } => {
+            Some(("quota.warning", "You are close to your quota", event.data()))
+        }
+        DomainEvent::JobFinished {
+            succeeded: true, ..
+        } => Some(("job.succeeded", "Background job finished", event.data())),
+        DomainEvent::JobFinished { .. } => {
+            Some(("job.failed", "Background job failed", event.data()))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/notify/ws", get(socket))
+}
+
+#[derive(Debug, Deserialize)]
+struct SocketQuery {
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+/// Browsers cannot set headers on a WebSocket handshake, so the bearer token
+/// may also be passed as `?access_token=`.
+async fn socket(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    Query(query): Query<SocketQuery>,
+    mut headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if let Some(token) = query.access_token {
+        match HeaderValue::from_str(&format!("Bearer {token}")) {
+            Ok(value) => {
+                headers.insert(header::AUTHORIZATION, value);
+            }
+            Err(_) => return error_response(RpcMethodError::unauthorized("invalid token")),
+        }
+    }
+    let ctx = match authenticate_request(&state, &headers, peer).await {
+        Ok(ctx) => ctx,
+        Err(err) => return error_response(err),
+    };
+    let notifier = state.notifier.clone();
+    let events = state.events.subscribe();
+    upgrade.on_upgrade(move |socket| notifier.serve(socket, ctx.user_id, events))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct NotifyListParams {
+    #[serde(default)]
+    unread_only: bool,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct NotifyMarkReadParams {
+    #[serde(default)]
+    ids: Vec<i64>,
+    /// Mark every unread notification instead of `ids`.
+    #[serde(default)]
+    all: bool,
+}
+
+pub(crate) async fn list(
+    notifier: &Notifier,
+    ctx: &RequestContext,
+    params: NotifyListParams,
+) -> Result<Value, RpcMethodError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let rows = sqlx::query(&format!(
+        "SELECT {COLUMNS} FROM notifications \
+         WHERE user_id = $1 \
+           AND (NOT $2 OR read_at IS NULL) \
+           AND ($3::bigint IS NULL OR id < $3) \
+         ORDER BY id DESC LIMIT $4"
+    ))
+    .bind(ctx.user_id)
+    .bind(params.unread_only)
+    .bind(params.cursor)
+    .bind(limit)
+    .fetch_all(&notifier.pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list notifications: {err}")))?;
+    let unread = notifier.unread(ctx.user_id).await.map_err(|err| {
+        RpcMethodError::internal(&format!("failed to count notifications: {err}"))
+    })?;
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    let notifications: Vec<Value> = rows.iter().map(notification_value).collect();
+    Ok(json!({
+        "notifications": notifications,
+        "unread": unread,
+        "next_cursor": next_cursor,
+    }))
+}
+
+pub(crate) async fn mark_read(
+    notifier: &Notifier,
+    ctx: &RequestContext,
+    params: NotifyMarkReadParams,
+) -> Result<Value, RpcMethodError> {
+    if !params.all && params.ids.is_empty() {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "either ids or all is required",
+            None,
+        ));
+    }
+    let updated = sqlx::query(
+        "UPDATE notifications SET read_at = NOW() \
+         WHERE user_id = $1 AND read_at IS NULL AND ($2 OR id = ANY($3))",
+    )
+    .bind(ctx.user_id)
+    .bind(params.all)
+    .bind(&params.ids)
+    .execute(&notifier.pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to update notifications: {err}")))?
+    .rows_affected();
+    let unread = notifier.unread(ctx.user_id).await.map_err(|err| {
+        RpcMethodError::internal(&format!("failed to count notifications: {err}"))
+    })?;
+    Ok(json!({ "updated": updated, "unread": unread }))
+}
+
+#[cfg(test)]
+mod tests {
+    use sandbox::{AgentKind, AgentParameters, AgentTaskSnapshot};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn task(status: AgentTaskStatus, parent_id: Option<Uuid>) -> DomainEvent {
+        DomainEvent::Agent(Box::new(AgentTaskSnapshot {
+            id: Uuid::new_v4(),
+            agent: AgentKind::Code,
+            status,
+            objective: "write a parser".to_string(),
+            model: "mock-model".to_string(),
+            summary: Some("wrote the parser".to_string()),
+            error: None,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            outcome: None,
+            metadata: None,
+            parameters: AgentParameters::default(),
+            previews: Vec::new(),
+            parent_id,
+            children: Vec::new(),
+            persona: None,
+            system_prompt_overridden: false,
+            pending_question: None,
+        }))
+    }
+
+    fn kind_of(event: &DomainEvent) -> Option<&'static str> {
+        notification(event).map(|(kind, _, _)| kind)
+    }
+
+    #[test]
+    fn finished_and_waiting_top_level_tasks_notify() {
+        let completed = task(AgentTaskStatus::Completed, None);
+        let (kind, _, data) = notification(&completed).unwrap();
+        assert_eq!(kind, "agent.completed");
+        assert_eq!(data["summary"], "wrote the parser");
+        assert_eq!(
+            kind_of(&task(AgentTaskStatus::Failed, None)),
+            Some("agent.failed")
+        );
+        assert_eq!(
+            kind_of(&task(AgentTaskStatus::WaitingForInput, None)),
+            Some("agent.waiting")
+        );
+        assert_eq!(kind_of(&task(AgentTaskStatus::Running, None)), None);
+        // Subtasks are covered by their parent.
+        let subtask = task(AgentTaskStatus::Completed, Some(Uuid::new_v4()));
+        assert_eq!(kind_of(&subtask), None);
+    }
+
+    #[test]
+    fn jobs_notify_by_outcome_and_other_events_do_not() {
+        let job = |succeeded| DomainEvent::JobFinished {
+            user_id: 1,
+            job_id: 7,
+            kind: "project.export",
+            succeeded,
+            error: None,
+        };
+        assert_eq!(kind_of(&job(true)), Some("job.succeeded"));
+        assert_eq!(kind_of(&job(false)), Some("job.failed"));
+        let deleted = DomainEvent::ProjectDeleted {
+            user_id: 1,
+            project_id: Uuid::new_v4(),
+        };
+        assert_eq!(kind_of(&deleted), None);
+    }
+}
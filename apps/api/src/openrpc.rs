@@ -0,0 +1,453 @@
+//! `rpc.discover` support. The OpenRPC document is generated from the same
+//! param structs `process_request` deserializes into, so it cannot drift from
+//! what the server actually accepts. Names from `versioning::DEPRECATED` are
+//! flagged `deprecated`.
+
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::admin::{
+    AdminDisableParams, AdminSetRoleParams, AdminSetTokenBalanceParams, AdminUsersListParams,
+};
+use crate::audit::AuditQueryParams;
+use crate::billing::{BillingLedgerParams, BillingUsageParams};
+use crate::error_log::ErrorsRecentParams;
+use crate::errors::ErrorCode;
+use crate::flags::{FlagClearParams, FlagSetParams};
+use crate::fs_batch::FsBatchParams;
+use crate::git::{GitCommitParams, GitDiffParams, GitLogParams};
+use crate::jobs::{JobIdParams, JobListParams};
+use crate::llm_usage::LlmUsageParams;
+use crate::notify::{NotifyListParams, NotifyMarkReadParams};
+use crate::pipeline::AgentPipelineParams;
+use crate::quota::QuotaStatusParams;
+use crate::rate_limit::{RateLimitClearParams, RateLimitSetParams};
+use crate::rbac::{GrantAddParams, GrantIdParams, GrantListParams, RoleNameParams, RoleSetParams};
+use crate::revocation::TokenRevokeParams;
+use crate::scheduler::{ScheduleNameParams, ScheduleUpdateParams};
+use crate::snapshot::{SnapshotCreateParams, SnapshotListParams, SnapshotRestoreParams};
+use crate::transfer::ProjectImportParams;
+use crate::versioning;
+use crate::webhooks::{WebhookCreateParams, WebhookIdParams};
+use crate::workspace::{WorkspaceCreateParams, WorkspaceIdParams};
+use crate::{
+    AgentApplyParams, AgentDispatchParams, AgentHistoryParams, AgentRespondParams,
+    AgentStatusParams, FsPathParams, FsReadParams, FsWalkParams, FsWriteParams, LlmAdminLoadParams,
+    LlmChatParams, LlmCompletionParams, LlmEmbedParams, LlmModelParams, MicroExecuteParams,
+    MicroStartParams, MicroStopParams, ProjectActivityParams, ProjectCreateParams,
+    ProjectFileHistoryParams, ProjectFilePathParams, ProjectFileReadParams,
+    ProjectFileRestoreParams, ProjectFileSaveParams, ProjectIdParams, ProjectOpenParams,
+    ProjectRunParams, ProjectSearchParams, ProjectUpdateParams, RunExecParams,
+    RunSessionKillParams, RunSessionWriteParams, WasmInvokeParams,
+};
+
+const OPENRPC_VERSION: &str = "1.2.6";
+
+pub(crate) fn document() -> &'static Value {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    DOCUMENT.get_or_init(build_document)
+}
+
+/// Whether `name` is a documented method (without version prefix).
+pub(crate) fn has_method(name: &str) -> bool {
+    static NAMES: OnceLock<HashSet<String>> = OnceLock::new();
+    NAMES
+        .get_or_init(|| {
+            document()["methods"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|method| method["name"].as_str())
+                .map(str::to_string)
+                .collect()
+        })
+        .contains(name)
+}
+
+fn build_document() -> Value {
+    let mut settings = SchemaSettings::draft07();
+    settings.definitions_path = "#/components/schemas/".to_string();
+    settings.option_add_null_type = false;
+    let mut gen = settings.into_generator();
+
+    let mut methods = vec![
+        method::<FsReadParams>(&mut gen, "fs.read", "Read a sandbox file as base64."),
+        method::<FsWriteParams>(&mut gen, "fs.write", "Write base64 data to a sandbox file."),
+        method::<FsPathParams>(&mut gen, "fs.list", "List a sandbox directory."),
+        method::<FsPathParams>(
+            &mut gen,
+            "fs.stat",
+            "Describe a sandbox file, directory or symlink.",
+        ),
+        method::<FsWalkParams>(
+            &mut gen,
+            "fs.walk",
+            "List a sandbox directory tree, filtered by globs.",
+        ),
+        method::<FsPathParams>(&mut gen, "fs.delete", "Delete a sandbox file or directory."),
+        method::<FsPathParams>(&mut gen, "fs.mkdir", "Create a sandbox directory."),
+        method::<FsBatchParams>(
+            &mut gen,
+            "fs.batch",
+            "Run several sandbox file operations in order.",
+        ),
+        method::<ProjectCreateParams>(&mut gen, "project.create", "Create a project."),
+        no_params("project.list", "List projects visible to the caller."),
+        method::<ProjectOpenParams>(&mut gen, "project.open", "Open a project and its files."),
+        method::<ProjectSearchParams>(&mut gen, "project.search", "Search project files."),
+        method::<ProjectActivityParams>(
+            &mut gen,
+            "project.activity",
+            "Page through a project's activity feed.",
+        ),
+        method::<ProjectUpdateParams>(&mut gen, "project.update", "Rename or describe a project."),
+        method::<ProjectRunParams>(
+            &mut gen,
+            "project.run",
+            "Run a command inside a project workspace.",
+        ),
+        method::<ProjectIdParams>(&mut gen, "project.delete", "Delete a project."),
+        method::<ProjectIdParams>(
+            &mut gen,
+            "project.export",
+            "Export a project to a JSON bundle in a background job.",
+        ),
+        method::<ProjectImportParams>(
+            &mut gen,
+            "project.import",
+            "Create a project and fill it from a bundle in a background job.",
+        ),
+        method::<SnapshotCreateParams>(
+            &mut gen,
+            "project.snapshot.create",
+            "Snapshot a project's files.",
+        ),
+        method::<SnapshotListParams>(
+            &mut gen,
+            "project.snapshot.list",
+            "List a project's snapshots, newest first.",
+        ),
+        method::<SnapshotRestoreParams>(
+            &mut gen,
+            "project.snapshot.restore",
+            "Restore a project's files from a snapshot.",
+        ),
+        method::<ProjectIdParams>(
+            &mut gen,
+            "project.git.init",
+            "Create a git repository in a project.",
+        ),
+        method::<ProjectIdParams>(
+            &mut gen,
+            "project.git.status",
+            "Show a project repository's changes.",
+        ),
+        method::<GitDiffParams>(&mut gen, "project.git.diff", "Diff a project repository."),
+        method::<GitCommitParams>(
+            &mut gen,
+            "project.git.commit",
+            "Commit changes in a project repository.",
+        ),
+        method::<GitLogParams>(
+            &mut gen,
+            "project.git.log",
+            "List a project repository's commits.",
+        ),
+        method::<ProjectFileSaveParams>(&mut gen, "project.file.save", "Save a project file."),
+        method::<ProjectFileReadParams>(&mut gen, "project.file.read", "Read a project file."),
+        method::<ProjectFilePathParams>(&mut gen, "project.file.delete", "Delete a project file."),
+        method::<ProjectFileHistoryParams>(
+            &mut gen,
+            "project.file.history",
+            "List archived versions of a project file.",
+        ),
+        method::<ProjectFileRestoreParams>(
+            &mut gen,
+            "project.file.restore",
+            "Restore an archived file version.",
+        ),
+        method::<RunExecParams>(&mut gen, "run.exec", "Execute a process in the sandbox."),
+        no_params("run.describe", "Describe the process runner limits."),
+        method::<RunExecParams>(
+            &mut gen,
+            "run.session.start",
+            "Start a process that keeps stdin open for run.session.write.",
+        ),
+        method::<RunSessionWriteParams>(
+            &mut gen,
+            "run.session.write",
+            "Write stdin to a run session and read its output since the last call.",
+        ),
+        method::<RunSessionKillParams>(
+            &mut gen,
+            "run.session.kill",
+            "Kill a run session and return its remaining output.",
+        ),
+        method::<WasmInvokeParams>(&mut gen, "wasm.invoke", "Invoke a wasm function."),
+        no_params("wasm.describe", "Describe the wasm runtime limits."),
+        method::<MicroStartParams>(&mut gen, "micro.start", "Start a micro vm."),
+        method::<MicroExecuteParams>(&mut gen, "micro.execute", "Run code in a micro vm."),
+        method::<MicroStopParams>(&mut gen, "micro.stop", "Stop a micro vm."),
+        no_params("micro.describe", "List micro images and running vms."),
+        method::<WorkspaceCreateParams>(
+            &mut gen,
+            "workspace.create",
+            "Create an expiring scratch workspace.",
+        ),
+        no_params("workspace.list", "List the caller's active workspaces."),
+        method::<WorkspaceIdParams>(&mut gen, "workspace.destroy", "Destroy a workspace."),
+        method::<WebhookCreateParams>(
+            &mut gen,
+            "webhook.create",
+            "Register a signed webhook for selected events.",
+        ),
+        no_params(
+            "webhook.list",
+            "List the caller's webhooks and delivery state.",
+        ),
+        method::<WebhookIdParams>(&mut gen, "webhook.delete", "Delete a webhook."),
+        method::<QuotaStatusParams>(
+            &mut gen,
+            "quota.status",
+            "Report project and storage usage against quotas.",
+        ),
+        method::<LlmChatParams>(&mut gen, "llm.chat", "Chat completion."),
+        method::<LlmCompletionParams>(&mut gen, "llm.completion", "Text completion."),
+        method::<LlmCompletionParams>(
+            &mut gen,
+            "llm.completions",
+            "Deprecated alias of llm.completion.",
+        ),
+        method::<LlmEmbedParams>(&mut gen, "llm.embed", "Compute embeddings."),
+        method::<LlmUsageParams>(&mut gen, "llm.usage", "Aggregate recorded llm usage."),
+        no_params("llm.list_models", "List available models."),
+        no_params("llm.status", "Report llm server status."),
+        method::<LlmModelParams>(&mut gen, "llm.download", "Download a model."),
+        method::<LlmAdminLoadParams>(&mut gen, "llm.start", "Load a model."),
+        method::<LlmModelParams>(&mut gen, "llm.stop", "Unload a model."),
+        no_params("agent.list", "List registered agents."),
+        method::<AgentHistoryParams>(&mut gen, "agent.history", "Page through agent history."),
+        method::<AgentStatusParams>(&mut gen, "agent.status", "Get an agent task snapshot."),
+        method::<AgentStatusParams>(&mut gen, "agent.cancel", "Cancel an agent task."),
+        method::<AgentRespondParams>(
+            &mut gen,
+            "agent.respond",
+            "Answer a task waiting for input.",
+        ),
+        method::<AgentApplyParams>(
+            &mut gen,
+            "agent.apply",
+            "Apply a completed task's actions to a project.",
+        ),
+        method::<AgentDispatchParams>(&mut gen, "agent.dispatch", "Dispatch an agent task."),
+        method::<AgentPipelineParams>(
+            &mut gen,
+            "agent.pipeline",
+            "Run agent tasks one after another in a background job.",
+        ),
+        method::<BillingUsageParams>(&mut gen, "billing.usage", "Summarize token spend."),
+        method::<BillingLedgerParams>(&mut gen, "billing.ledger", "Page through ledger entries."),
+        method::<AuditQueryParams>(&mut gen, "audit.query", "Search the RPC audit log."),
+        method::<AdminUsersListParams>(&mut gen, "admin.users.list", "List user accounts."),
+        method::<AdminSetRoleParams>(&mut gen, "admin.users.setRole", "Change a user's role."),
+        method::<AdminSetTokenBalanceParams>(
+            &mut gen,
+            "admin.users.setTokenBalance",
+            "Set a user's token balance.",
+        ),
+        method::<AdminDisableParams>(
+            &mut gen,
+            "admin.users.disable",
+            "Disable or re-enable a user account.",
+        ),
+        no_params("admin.roles.list", "List roles and their permissions."),
+        method::<RoleSetParams>(&mut gen, "admin.roles.set", "Create or update a role."),
+        method::<RoleNameParams>(&mut gen, "admin.roles.delete", "Delete a custom role."),
+        method::<GrantListParams>(&mut gen, "admin.grants.list", "List a user's grants."),
+        method::<GrantAddParams>(
+            &mut gen,
+            "admin.grants.add",
+            "Grant a user a permission, optionally in one project.",
+        ),
+        method::<GrantIdParams>(
+            &mut gen,
+            "admin.grants.revoke",
+            "Revoke a permission grant.",
+        ),
+        method::<TokenRevokeParams>(
+            &mut gen,
+            "admin.tokens.revoke",
+            "Revoke one JWT or every token of a user.",
+        ),
+        no_params(
+            "admin.schedules.list",
+            "List maintenance schedules and their last runs.",
+        ),
+        method::<ScheduleUpdateParams>(
+            &mut gen,
+            "admin.schedules.update",
+            "Retime, pause or resume a maintenance schedule.",
+        ),
+        method::<ScheduleNameParams>(
+            &mut gen,
+            "admin.schedules.run",
+            "Run a maintenance job on the next scheduler tick.",
+        ),
+        no_params(
+            "admin.runners.list",
+            "List connected remote runners and their load.",
+        ),
+        no_params(
+            "admin.sandbox.reload",
+            "Reload run allowlists, micro images and wasm limits from configuration.",
+        ),
+        no_params(
+            "admin.flags.list",
+            "List feature flags with their defaults and overrides.",
+        ),
+        method::<FlagSetParams>(
+            &mut gen,
+            "admin.flags.set",
+            "Turn a feature flag on or off for everyone, a role or a user.",
+        ),
+        method::<FlagClearParams>(
+            &mut gen,
+            "admin.flags.clear",
+            "Remove a feature flag override.",
+        ),
+        no_params(
+            "admin.rateLimits.list",
+            "List the shared rate limit and the limits of single methods.",
+        ),
+        method::<RateLimitSetParams>(
+            &mut gen,
+            "admin.rateLimits.set",
+            "Override the rate limit of one method.",
+        ),
+        method::<RateLimitClearParams>(
+            &mut gen,
+            "admin.rateLimits.clear",
+            "Remove a method's rate limit override.",
+        ),
+        method::<ErrorsRecentParams>(
+            &mut gen,
+            "errors.recent",
+            "List recently failed calls, optionally for one request id.",
+        ),
+        method::<NotifyListParams>(&mut gen, "notify.list", "Page through notifications."),
+        method::<NotifyMarkReadParams>(&mut gen, "notify.markRead", "Mark notifications as read."),
+        method::<JobIdParams>(&mut gen, "job.status", "Get a background job."),
+        method::<JobListParams>(&mut gen, "job.list", "Page through background jobs."),
+        method::<JobIdParams>(&mut gen, "job.cancel", "Cancel a queued or running job."),
+        method::<JobIdParams>(&mut gen, "job.retry", "Requeue a dead or cancelled job."),
+        no_params("rpc.discover", "Return this OpenRPC document."),
+        no_params(
+            "rpc.errors",
+            "List every error code with retry hint and docs link.",
+        ),
+    ];
+
+    for method in &mut methods {
+        let deprecated = versioning::DEPRECATED
+            .iter()
+            .any(|(name, _)| method["name"] == *name);
+        if deprecated {
+            method["deprecated"] = json!(true);
+        }
+    }
+
+    let errors: Vec<Value> = ErrorCode::ALL
+        .iter()
+        .map(|code| json!({ "code": code.code(), "message": code.message() }))
+        .collect();
+
+    json!({
+        "openrpc": OPENRPC_VERSION,
+        "info": {
+            "title": "coder api",
+            "description": format!(
+                "Every method is also callable as `{}.<method>`.",
+                versioning::CURRENT_VERSION
+            ),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "methods": methods,
+        "components": {
+            "schemas": gen.take_definitions(),
+            "errors": errors,
+        },
+    })
+}
+
+fn method<T: JsonSchema>(gen: &mut SchemaGenerator, name: &str, summary: &str) -> Value {
+    let object = T::json_schema(gen).into_object().object.unwrap_or_default();
+    let params: Vec<Value> = object
+        .properties
+        .iter()
+        .map(|(field, schema)| {
+            json!({
+                "name": field,
+                "required": object.required.contains(field),
+                "schema": schema,
+            })
+        })
+        .collect();
+    descriptor(name, summary, params)
+}
+
+fn no_params(name: &str, summary: &str) -> Value {
+    descriptor(name, summary, Vec::new())
+}
+
+fn descriptor(name: &str, summary: &str, params: Vec<Value>) -> Value {
+    json!({
+        "name": name,
+        "summary": summary,
+        "paramStructure": "by-name",
+        "params": params,
+        "result": { "name": "result", "schema": {} },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_describes_method_params() {
+        let document = document();
+        let methods = document["methods"].as_array().expect("methods");
+        let write = methods
+            .iter()
+            .find(|method| method["name"] == "fs.write")
+            .expect("fs.write");
+        let params = write["params"].as_array().expect("params");
+        let required = |name: &str| {
+            params
+                .iter()
+                .find(|param| param["name"] == name)
+                .map(|param| param["required"].clone())
+        };
+        assert_eq!(required("path"), Some(json!(true)));
+        assert_eq!(required("data"), Some(json!(true)));
+        assert_eq!(required("project_id"), Some(json!(false)));
+
+        let dispatch = methods
+            .iter()
+            .find(|method| method["name"] == "agent.dispatch")
+            .expect("agent.dispatch");
+        let agent = dispatch["params"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|param| param["name"] == "agent")
+            .expect("agent param");
+        let reference = agent["schema"]["$ref"].as_str().expect("agent ref");
+        let name = reference.trim_start_matches("#/components/schemas/");
+        assert!(document["components"]["schemas"].get(name).is_some());
+    }
+}
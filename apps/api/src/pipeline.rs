@@ -0,0 +1,245 @@
+//! `agent.pipeline`: agent dispatches run one after another in a background
+//! job, each step seeing the previous step's summary. The requests are built
+//! (and their context files read) when the pipeline is submitted, so the job
+//! only needs the dispatcher. Finished steps are checkpointed in the job's
+//! progress and a retried job resumes after the last of them. A step left
+//! waiting for input longer than `JOB_PIPELINE_INPUT_TIMEOUT_SECS` is
+//! cancelled and fails the job, so it does not hold a worker forever.
+
+use std::time::Duration;
+
+use sandbox::{
+    AgentDispatchRequest, AgentDispatcher, AgentEvent, AgentKind, AgentPersona, AgentTaskSnapshot,
+    AgentTaskStatus, SandboxError,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::billing::Charge;
+use crate::errors::ErrorCode;
+use crate::jobs::{ClaimedJob, JobError, JobKind};
+use crate::{
+    build_agent_context, enrich_agent_metadata, telemetry, tenant_fs, AgentDispatchContextParams,
+    AppState, RequestContext, RpcMethodError,
+};
+
+const MAX_STEPS: usize = 8;
+/// How often a running step is re-checked in case a transition was missed.
+const STATUS_POLL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AgentPipelineParams {
+    /// Steps in execution order; at most eight.
+    steps: Vec<AgentPipelineStep>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AgentPipelineStep {
+    agent: AgentKind,
+    objective: String,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    context: Option<AgentDispatchContextParams>,
+    #[serde(default)]
+    persona: Option<AgentPersona>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PipelinePayload {
+    steps: Vec<AgentDispatchRequest>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PipelineProgress {
+    outputs: Vec<StepOutput>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StepOutput {
+    task_id: Uuid,
+    status: AgentTaskStatus,
+    #[serde(default)]
+    summary: Option<String>,
+}
+
+pub(crate) async fn start(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    params: AgentPipelineParams,
+) -> Result<Value, RpcMethodError> {
+    ctx.ensure_tokens()?;
+    if params.steps.is_empty() || params.steps.len() > MAX_STEPS {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid params",
+            Some(json!({ "detail": format!("a pipeline has between 1 and {MAX_STEPS} steps") })),
+        ));
+    }
+    let sandbox = tenant_fs(state, ctx)?;
+    let mut steps = Vec::with_capacity(params.steps.len());
+    for step in params.steps {
+        if step.objective.trim().is_empty() {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "invalid params",
+                Some(json!({ "detail": "objective must not be empty" })),
+            ));
+        }
+        let context = build_agent_context(&sandbox, step.context).map_err(|err| {
+            RpcMethodError::from_sandbox(
+                ErrorCode::AgentContext,
+                "failed to prepare agent context",
+                err,
+            )
+        })?;
+        steps.push(AgentDispatchRequest {
+            agent: step.agent,
+            objective: step.objective,
+            context,
+            model: step.model,
+            metadata: enrich_agent_metadata(None, ctx),
+            parameters: None,
+            subtasks: Vec::new(),
+            system_prompt: None,
+            persona: step.persona,
+            traceparent: telemetry::traceparent(ctx),
+        });
+    }
+    let task_count = steps.len() as i64;
+    let payload = serde_json::to_value(PipelinePayload { steps })
+        .map_err(|err| RpcMethodError::internal(&err.to_string()))?;
+    let job_id = state
+        .jobs
+        .enqueue(JobKind::AgentPipeline, ctx.user_id, payload)
+        .await?;
+    state
+        .billing
+        .charge(ctx, method, Charge::AgentTasks(task_count))
+        .await;
+    Ok(json!({ "job_id": job_id, "status": "queued", "steps": task_count }))
+}
+
+pub(crate) async fn run(state: &AppState, job: &ClaimedJob) -> Result<Value, JobError> {
+    let payload: PipelinePayload =
+        serde_json::from_value(job.payload.clone()).map_err(JobError::fatal)?;
+    let mut progress: PipelineProgress = match &job.progress {
+        Some(progress) => serde_json::from_value(progress.clone()).map_err(JobError::fatal)?,
+        None => PipelineProgress::default(),
+    };
+    let total = payload.steps.len();
+    for (index, mut request) in payload
+        .steps
+        .into_iter()
+        .enumerate()
+        .skip(progress.outputs.len())
+    {
+        if let Some(summary) = progress
+            .outputs
+            .last()
+            .and_then(|out| out.summary.as_deref())
+        {
+            request.objective = format!(
+                "{}\n\nResult of the previous step:\n{summary}",
+                request.objective
+            );
+        }
+        if let Some(Value::Object(metadata)) = request.metadata.as_mut() {
+            metadata.insert("pipeline_job_id".to_string(), json!(job.id));
+            metadata.insert("pipeline_step".to_string(), json!(index));
+        }
+        let snapshot =
+            run_step(&state.agents, request, state.jobs.pipeline_input_timeout()).await?;
+        let failed = snapshot.status != AgentTaskStatus::Completed;
+        let error = snapshot.error.as_ref().map(|err| err.message.clone());
+        progress.outputs.push(StepOutput {
+            task_id: snapshot.id,
+            status: snapshot.status,
+            summary: snapshot.summary,
+        });
+        let checkpoint = serde_json::to_value(&progress).map_err(JobError::fatal)?;
+        state.jobs.checkpoint(job, &checkpoint).await?;
+        if failed {
+            return Err(JobError::Fatal(format!(
+                "step {index} ended as {:?}: {}",
+                snapshot.status,
+                error.unwrap_or_else(|| "no error reported".to_string())
+            )));
+        }
+    }
+    Ok(json!({ "steps": total, "outputs": progress.outputs }))
+}
+
+/// Cancels the dispatched task when the step is abandoned, e.g. because the
+/// job was cancelled and the worker dropped the attempt.
+struct CancelOnDrop<'a> {
+    agents: &'a AgentDispatcher,
+    id: Option<Uuid>,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let _ = self.agents.cancel(&id);
+        }
+    }
+}
+
+/// Dispatches one step and waits until it reaches a terminal status, or
+/// until it has waited for input longer than `input_timeout`.
+async fn run_step(
+    agents: &AgentDispatcher,
+    request: AgentDispatchRequest,
+    input_timeout: Duration,
+) -> Result<AgentTaskSnapshot, JobError> {
+    // Subscribe first so a fast task cannot finish unseen.
+    let mut events = agents.subscribe();
+    let submission = agents.dispatch(request).map_err(|err| match err {
+        SandboxError::RateLimited { .. } => JobError::retry(err),
+        other => JobError::fatal(other),
+    })?;
+    let id = submission.id;
+    let mut guard = CancelOnDrop {
+        agents,
+        id: Some(id),
+    };
+    let mut poll = tokio::time::interval(STATUS_POLL);
+    let mut waiting_since = None;
+    loop {
+        let polled = tokio::select! {
+            event = events.recv() => match event {
+                Ok(AgentEvent::Status(snapshot)) if snapshot.id == id => Some(Some(*snapshot)),
+                Ok(_) | Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => Some(agents.status(&id)),
+            },
+            _ = poll.tick() => Some(agents.status(&id)),
+        };
+        let snapshot = match polled {
+            None => continue,
+            Some(Some(snapshot)) => snapshot,
+            Some(None) => {
+                guard.id = None;
+                return Err(JobError::fatal(format!("agent task {id} disappeared")));
+            }
+        };
+        if snapshot.status.is_terminal() {
+            guard.id = None;
+            // Broadcast snapshots may predate the outcome being attached.
+            return Ok(agents.status(&id).unwrap_or(snapshot));
+        }
+        if snapshot.status != AgentTaskStatus::WaitingForInput {
+            waiting_since = None;
+        } else if waiting_since.get_or_insert_with(Instant::now).elapsed() >= input_timeout {
+            // The guard cancels the task on the way out.
+            return Err(JobError::fatal(format!(
+                "agent task {id} waited for input longer than {}s",
+                input_timeout.as_secs()
+            )));
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Per-user storage quotas. Usage is the sum of the user's `project_files`,
+//! the snapshot blobs of their projects and their private sandbox
+//! directories (`users/<id>` and workspace sessions); project directories
+//! only mirror `project_files`, so they are not counted twice. Limits are
+//! checked before a project is created, a file is saved or a snapshot is
+//! taken and reported by `quota.status`. Crossing
+//! `QUOTA_WARN_PERCENT` of a limit leaves a `quota.warning` notification.
+//!
+//! Storage checks run in the transaction that writes the files and hold an
+//! advisory lock on the owner until it ends, so concurrent saves of one user
+//! are checked one after the other instead of all passing on the same usage.
+//! Walking the sandbox directories is the expensive part of the usage; its
+//! result is reused for `QUOTA_SANDBOX_CACHE_SECS`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use moka::future::Cache;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::pool::PoolConnection;
+use sqlx::{PgConnection, PgPool, Postgres, Row};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::events::DomainEvent;
+use crate::{billing, workspace, AppState, RequestContext, RpcMethodError};
+
+const DEFAULT_MAX_PROJECTS: i64 = 100;
+const DEFAULT_MAX_BYTES: i64 = 1024 * 1024 * 1024;
+const DEFAULT_WARN_PERCENT: i64 = 90;
+/// Class of the advisory locks that serialize storage checks per owner.
+const QUOTA_LOCK: i32 = 0x7175_6f74;
+
+/// Limits applied to every user; `None` disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QuotaConfig {
+    max_projects: Option<i64>,
+    max_bytes: Option<i64>,
+    /// Share of a limit at which the user is warned; 0 disables warnings.
+    warn_percent: i64,
+    /// How long a measured sandbox usage is reused; 0 measures every time.
+    sandbox_ttl: Duration,
+}
+
+impl QuotaConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let limit = |name: &'static str, default: i64| {
+            let value = config.get(name, default);
+            (value > 0).then_some(value)
+        };
+        Self {
+            max_projects: limit("QUOTA_MAX_PROJECTS", DEFAULT_MAX_PROJECTS),
+            max_bytes: limit("QUOTA_MAX_BYTES", DEFAULT_MAX_BYTES),
+            warn_percent: config
+                .get("QUOTA_WARN_PERCENT", DEFAULT_WARN_PERCENT)
+                .clamp(0, 100),
+            sandbox_ttl: config.secs("QUOTA_SANDBOX_CACHE_SECS", 60),
+        }
+    }
+}
+
+/// The configured limits and the measured sandbox usage per user.
+#[derive(Clone)]
+pub(crate) struct Quotas {
+    config: QuotaConfig,
+    sandbox: Cache<i32, i64>,
+}
+
+impl Quotas {
+    pub(crate) fn new(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            sandbox: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(config.sandbox_ttl.max(Duration::from_millis(1)))
+                .build(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct QuotaStatusParams {
+    #[serde(default)]
+    user_id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    projects: i64,
+    project_bytes: i64,
+    snapshot_bytes: i64,
+    sandbox_bytes: i64,
+}
+
+impl Usage {
+    fn bytes(&self) -> i64 {
+        self.project_bytes
+            .saturating_add(self.snapshot_bytes)
+            .saturating_add(self.sandbox_bytes)
+    }
+}
+
+fn exceeded(quota: &str, limit: i64, used: i64, requested: i64) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::QuotaExceeded,
+        "quota exceeded",
+        Some(json!({
+            "quota": quota,
+            "limit": limit,
+            "used": used,
+            "requested": requested,
+        })),
+    )
+}
+
+/// Fails when `used + requested` would go past `limit`.
+fn check(quota: &str, limit: Option<i64>, used: i64, requested: i64) -> Result<(), RpcMethodError> {
+    match limit {
+        Some(limit) if requested > 0 && used.saturating_add(requested) > limit => {
+            Err(exceeded(quota, limit, used, requested))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether growing from `used` by `requested` passes `percent` of `limit`.
+/// Only the request that crosses the threshold warns, not every one after.
+fn crosses_warning(limit: Option<i64>, percent: i64, used: i64, requested: i64) -> bool {
+    let Some(limit) = limit else {
+        return false;
+    };
+    if percent == 0 || requested <= 0 {
+        return false;
+    }
+    let threshold = limit.saturating_mul(percent) / 100;
+    used < threshold && used.saturating_add(requested) >= threshold
+}
+
+fn warn_if_crossing(
+    state: &AppState,
+    user_id: i32,
+    quota: &'static str,
+    limit: Option<i64>,
+    used: i64,
+    requested: i64,
+) {
+    if !crosses_warning(limit, state.quotas.config.warn_percent, used, requested) {
+        return;
+    }
+    state.events.publish(DomainEvent::QuotaWarning {
+        user_id,
+        quota,
+        limit,
+        used: used.saturating_add(requested),
+        percent: state.quotas.config.warn_percent,
+    });
+}
+
+async fn usage(state: &AppState, user_id: i32) -> Result<Usage, RpcMethodError> {
+    let row = sqlx::query(
+        "SELECT \
+            (SELECT tenant_id FROM users WHERE id = $1) AS tenant_id, \
+            (SELECT COUNT(*) FROM projects WHERE user_id = $1) AS projects, \
+            (SELECT COALESCE(SUM(f.size), 0) FROM project_files f \
+             JOIN projects p ON p.id = f.project_id WHERE p.user_id = $1)::BIGINT AS project_bytes, \
+            (SELECT COALESCE(SUM(b.size), 0) FROM project_blobs b \
+             JOIN projects p ON p.id = b.project_id WHERE p.user_id = $1)::BIGINT AS snapshot_bytes",
+    )
+    .bind(user_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load quota usage: {err}")))?;
+    let sandbox_bytes = match row.get::<Option<i32>, _>("tenant_id") {
+        Some(tenant_id) => cached_sandbox_usage(state, tenant_id, user_id).await?,
+        None => 0,
+    };
+    Ok(Usage {
+        projects: row.get("projects"),
+        project_bytes: row.get("project_bytes"),
+        snapshot_bytes: row.get("snapshot_bytes"),
+        sandbox_bytes,
+    })
+}
+
+async fn cached_sandbox_usage(
+    state: &AppState,
+    tenant_id: i32,
+    user_id: i32,
+) -> Result<i64, RpcMethodError> {
+    let quotas = &state.quotas;
+    if !quotas.config.sandbox_ttl.is_zero() {
+        if let Some(bytes) = quotas.sandbox.get(&user_id).await {
+            return Ok(bytes);
+        }
+    }
+    let root = state.sandbox.base_dir();
+    let bytes = sandbox_usage(&state.pool, root, tenant_id, user_id).await?;
+    if !quotas.config.sandbox_ttl.is_zero() {
+        quotas.sandbox.insert(user_id, bytes).await;
+    }
+    Ok(bytes)
+}
+
+async fn sandbox_usage(
+    pool: &PgPool,
+    root: &Path,
+    tenant_id: i32,
+    user_id: i32,
+) -> Result<i64, RpcMethodError> {
+    let workspaces: Vec<Uuid> =
+        sqlx::query_scalar("SELECT id FROM workspace_sessions WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .map_err(|err| {
+                RpcMethodError::internal(&format!("failed to load workspaces: {err}"))
+            })?;
+    let mut dirs: Vec<PathBuf> = workspaces
+        .iter()
+        .map(|id| root.join(workspace::directory_relative(tenant_id, id)))
+        .collect();
+    dirs.push(root.join(crate::user_directory_relative(tenant_id, user_id)));
+    tokio::task::spawn_blocking(move || dirs.iter().map(|dir| dir_size(dir)).sum::<u64>())
+        .await
+        .map(|bytes| bytes.min(i64::MAX as u64) as i64)
+        .map_err(|err| RpcMethodError::internal(&format!("failed to measure sandbox usage: {err}")))
+}
+
+/// Total size of regular files below `dir`; missing directories count as
+/// empty and symlinks are not followed.
+pub(crate) fn dir_size(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            Ok(kind) if kind.is_file() => entry.metadata().map(|meta| meta.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+pub(crate) async fn ensure_project_slot(
+    state: &AppState,
+    ctx: &RequestContext,
+) -> Result<(), RpcMethodError> {
+    if state.quotas.config.max_projects.is_none() {
+        return Ok(());
+    }
+    let usage = usage(state, ctx.user_id).await?;
+    let limit = state.quotas.config.max_projects;
+    check("projects", limit, usage.projects, 1)?;
+    warn_if_crossing(state, ctx.user_id, "projects", limit, usage.projects, 1);
+    Ok(())
+}
+
+/// A connection for checking a quota up front, ahead of the transaction
+/// that writes; nothing stays locked after the check.
+pub(crate) async fn connection(
+    state: &AppState,
+) -> Result<PoolConnection<Postgres>, RpcMethodError> {
+    state
+        .pool
+        .acquire()
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load quota usage: {err}")))
+}
+
+/// Takes the quota lock of the project's owner until `conn`'s transaction
+/// ends and returns the owner.
+async fn lock_owner(conn: &mut PgConnection, project_id: &Uuid) -> Result<i32, RpcMethodError> {
+    let load_error =
+        |err: sqlx::Error| RpcMethodError::internal(&format!("failed to load quota usage: {err}"));
+    let owner: Option<i32> = sqlx::query_scalar("SELECT user_id FROM projects WHERE id = $1")
+        .bind(project_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(load_error)?;
+    let owner = owner.ok_or_else(|| {
+        RpcMethodError::new(ErrorCode::ProjectNotFound, "project not found", None)
+    })?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+        .bind(QUOTA_LOCK)
+        .bind(owner)
+        .execute(&mut *conn)
+        .await
+        .map_err(load_error)?;
+    Ok(owner)
+}
+
+/// Checks that replacing each of `files` with the given number of bytes keeps
+/// the project owner within their storage quota. Only growth counts against
+/// the limit; growth and shrinkage of all files add up. Run it on the
+/// transaction that writes the files.
+pub(crate) async fn ensure_files_fit(
+    state: &AppState,
+    conn: &mut PgConnection,
+    project_id: &Uuid,
+    files: &BTreeMap<PathBuf, i64>,
+) -> Result<(), RpcMethodError> {
+    if state.quotas.config.max_bytes.is_none() || files.is_empty() {
+        return Ok(());
+    }
+    let owner = lock_owner(conn, project_id).await?;
+    let paths: Vec<String> = files
+        .keys()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    let existing: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(size), 0)::BIGINT FROM project_files \
+         WHERE project_id = $1 AND path = ANY($2)",
+    )
+    .bind(project_id)
+    .bind(&paths)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load quota usage: {err}")))?;
+    let usage = usage(state, owner).await?;
+    let growth = files.values().sum::<i64>() - existing;
+    let limit = state.quotas.config.max_bytes;
+    check("bytes", limit, usage.bytes(), growth)?;
+    warn_if_crossing(state, owner, "bytes", limit, usage.bytes(), growth);
+    Ok(())
+}
+
+/// Like [`ensure_files_fit`] for replacing all of the project's files with
+/// `size` bytes in total, as a snapshot restore does.
+pub(crate) async fn ensure_project_fits(
+    state: &AppState,
+    conn: &mut PgConnection,
+    project_id: &Uuid,
+    size: i64,
+) -> Result<(), RpcMethodError> {
+    if state.quotas.config.max_bytes.is_none() {
+        return Ok(());
+    }
+    let owner = lock_owner(conn, project_id).await?;
+    let existing: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(size), 0)::BIGINT FROM project_files WHERE project_id = $1",
+    )
+    .bind(project_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load quota usage: {err}")))?;
+    let usage = usage(state, owner).await?;
+    let growth = size - existing;
+    let limit = state.quotas.config.max_bytes;
+    check("bytes", limit, usage.bytes(), growth)?;
+    warn_if_crossing(state, owner, "bytes", limit, usage.bytes(), growth);
+    Ok(())
+}
+
+/// Checks that snapshotting the project's current files keeps the owner
+/// within their storage quota. Contents already stored as blobs are free.
+pub(crate) async fn ensure_snapshot_fits(
+    state: &AppState,
+    conn: &mut PgConnection,
+    project_id: &Uuid,
+) -> Result<(), RpcMethodError> {
+    if state.quotas.config.max_bytes.is_none() {
+        return Ok(());
+    }
+    let owner = lock_owner(conn, project_id).await?;
+    let growth: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(size), 0)::BIGINT FROM ( \
+            SELECT DISTINCT ON (f.sha256) f.size FROM project_files f \
+            WHERE f.project_id = $1 AND NOT EXISTS ( \
+                SELECT 1 FROM project_blobs b \
+                WHERE b.project_id = $1 AND b.sha256 = f.sha256 \
+            ) \
+         ) fresh",
+    )
+    .bind(project_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load quota usage: {err}")))?;
+    let usage = usage(state, owner).await?;
+    let limit = state.quotas.config.max_bytes;
+    check("bytes", limit, usage.bytes(), growth)?;
+    warn_if_crossing(state, owner, "bytes", limit, usage.bytes(), growth);
+    Ok(())
+}
+
+pub(crate) async fn status(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: QuotaStatusParams,
+) -> Result<Value, RpcMethodError> {
+    let user_id = billing::target_user(&state.pool, ctx, params.user_id).await?;
+    // Measured afresh, so the caller sees the sandbox as it is now.
+    state.quotas.sandbox.invalidate(&user_id).await;
+    let usage = usage(state, user_id).await?;
+    Ok(json!({
+        "user_id": user_id,
+        "projects": {
+            "used": usage.projects,
+            "limit": state.quotas.config.max_projects,
+        },
+        "bytes": {
+            "used": usage.bytes(),
+            "limit": state.quotas.config.max_bytes,
+            "project_files": usage.project_bytes,
+            "snapshots": usage.snapshot_bytes,
+            "sandbox": usage.sandbox_bytes,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_only_rejects_growth_past_the_limit() {
+        assert!(check("bytes", Some(100), 90, 10).is_ok());
+        let err = check("bytes", Some(100), 90, 11).unwrap_err();
+        assert_eq!(err.code, -32060);
+        assert!(check("bytes", Some(100), 150, -20).is_ok());
+        assert!(check("bytes", None, i64::MAX, 1).is_ok());
+    }
+
+    #[test]
+    fn warns_once_when_crossing_the_threshold() {
+        assert!(crosses_warning(Some(100), 90, 85, 5));
+        assert!(!crosses_warning(Some(100), 90, 90, 5));
+        assert!(!crosses_warning(Some(100), 90, 80, 5));
+        assert!(!crosses_warning(Some(100), 0, 85, 5));
+        assert!(!crosses_warning(None, 90, 85, 5));
+        assert!(!crosses_warning(Some(100), 90, 95, -10));
+    }
+
+    #[test]
+    fn dir_size_sums_nested_files() {
+        let root = std::env::temp_dir().join(format!("quota-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+        std::fs::write(root.join("a.txt"), b"12345").unwrap();
+        std::fs::write(root.join("nested/b.txt"), b"678").unwrap();
+        assert_eq!(dir_size(&root), 8);
+        assert_eq!(dir_size(&root.join("missing")), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+//! Per-caller rate limits. Every call, whether it comes over RPC, REST or
+//! gRPC, takes a token from a bucket of its caller: the API key it was made
+//! with, otherwise the user. Buckets refill at `RATE_LIMIT_PER_MINUTE` and
+//! hold up to `RATE_LIMIT_BURST` tokens (`0` per minute lifts the limit), so
+//! one caller cannot keep the sandbox executors busy for everyone else. A
+//! call that finds its bucket empty fails with -32094 and `retry_after_ms`.
+//! REST routes without a method of their own count as the call they stand
+//! in for: each uploaded file as `project.file.save`, an export download as
+//! `job.status`.
+//!
+//! Methods with a limit of their own (`RATE_LIMIT_METHODS`, `method=rate`
+//! or `method=rate/burst` pairs, or `admin.rateLimits.set`) draw from a
+//! separate bucket per caller instead of the shared one. Overrides in
+//! `rate_limit_overrides` take precedence over the configuration and are
+//! cached for `RATE_LIMIT_CACHE_TTL_SECS`; `admin.rateLimits.*` drops the
+//! cache. Buckets live in memory, so each API instance limits on its own.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Error as SqlxError, PgPool, Row};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::metrics::AppMetrics;
+use crate::{openrpc, RequestContext, RpcMethodError};
+
+/// Buckets idle this long are full again and may be dropped.
+const BUCKET_IDLE: Duration = Duration::from_secs(60 * 60);
+const MAX_BUCKETS: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limit {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl Limit {
+    /// Without a burst a caller may spend a minute's worth at once.
+    fn new(per_minute: u32, burst: Option<u32>) -> Self {
+        Self {
+            per_minute,
+            burst: burst.unwrap_or(per_minute).max(1),
+        }
+    }
+
+    fn is_unlimited(self) -> bool {
+        self.per_minute == 0
+    }
+
+    /// Parses `rate` or `rate/burst`.
+    fn parse(value: &str) -> Option<Self> {
+        let (rate, burst) = match value.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst.trim().parse().ok()?)),
+            None => (value, None),
+        };
+        Some(Self::new(rate.trim().parse().ok()?, burst))
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "per_minute": self.per_minute, "burst": self.burst })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimitConfig {
+    default: Limit,
+    methods: HashMap<String, Limit>,
+    ttl: Duration,
+}
+
+impl RateLimitConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let per_minute = config.get("RATE_LIMIT_PER_MINUTE", 600);
+        let burst = config.get("RATE_LIMIT_BURST", 100);
+        let mut methods = HashMap::new();
+        for (method, value) in config.pairs("RATE_LIMIT_METHODS") {
+            if !openrpc::has_method(&method) {
+                config.invalid("RATE_LIMIT_METHODS", format!("unknown method `{method}`"));
+                continue;
+            }
+            match Limit::parse(&value) {
+                Some(limit) => {
+                    methods.insert(method, limit);
+                }
+                None => config.invalid(
+                    "RATE_LIMIT_METHODS",
+                    format!("`{method}={value}`: expected rate or rate/burst"),
+                ),
+            }
+        }
+        Self {
+            default: Limit::new(per_minute, Some(burst)),
+            methods,
+            ttl: config.secs("RATE_LIMIT_CACHE_TTL_SECS", 5),
+        }
+    }
+}
+
+/// Whose tokens a call spends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Caller {
+    ApiKey(Uuid),
+    User(i32),
+}
+
+impl Caller {
+    fn of(ctx: &RequestContext) -> Self {
+        match ctx.api_key_id {
+            Some(api_key_id) => Self::ApiKey(api_key_id),
+            None => Self::User(ctx.user_id),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            refilled: now,
+        }
+    }
+
+    /// Takes a token, or tells how long until the next one.
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        let per_second = f64::from(limit.per_minute) / 60.0;
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(f64::from(limit.burst));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Override {
+    limit: Limit,
+    updated_by: Option<i32>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimits {
+    pool: PgPool,
+    config: Arc<RateLimitConfig>,
+    /// Keyed by caller and by the method with a limit of its own; `None`
+    /// is the shared bucket.
+    buckets: Cache<(Caller, Option<String>), Arc<Mutex<Bucket>>>,
+    /// All overrides under the unit key; the table holds a handful of rows.
+    overrides: Cache<(), Arc<HashMap<String, Override>>>,
+}
+
+impl RateLimits {
+    pub(crate) fn new(pool: PgPool, config: RateLimitConfig) -> Self {
+        Self {
+            pool,
+            buckets: Cache::builder()
+                .max_capacity(MAX_BUCKETS)
+                .time_to_idle(BUCKET_IDLE)
+                .build(),
+            overrides: Cache::builder()
+                .max_capacity(1)
+                .time_to_live(config.ttl)
+                .build(),
+            config: Arc::new(config),
+        }
+    }
+
+    /// The limit of the canonical `method` and whether it has a bucket of
+    /// its own.
+    fn limit(&self, overrides: &HashMap<String, Override>, method: &str) -> (Limit, bool) {
+        match overrides
+            .get(method)
+            .map(|o| o.limit)
+            .or_else(|| self.config.methods.get(method).copied())
+        {
+            Some(limit) => (limit, true),
+            None => (self.config.default, false),
+        }
+    }
+
+    /// Takes a token for the canonical `method` or fails with -32094.
+    pub(crate) async fn check(
+        &self,
+        method: &str,
+        ctx: &RequestContext,
+        metrics: &AppMetrics,
+    ) -> Result<(), RpcMethodError> {
+        let overrides = self.overrides().await?;
+        let (limit, own) = self.limit(&overrides, method);
+        if limit.is_unlimited() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let scope = own.then(|| method.to_string());
+        let bucket = self
+            .buckets
+            .get_with((Caller::of(ctx), scope), async move {
+                Arc::new(Mutex::new(Bucket::full(limit, now)))
+            })
+            .await;
+        let taken = bucket.lock().take(limit, now);
+        taken.map_err(|retry_after| {
+            let scope = if own { method } else { "default" };
+            metrics.rate_limited(scope);
+            debug!(method, scope, user_id = ctx.user_id, "rate limited");
+            RpcMethodError::new(
+                ErrorCode::RateLimited,
+                "rate limited",
+                Some(json!({
+                    "scope": scope,
+                    "per_minute": limit.per_minute,
+                    "burst": limit.burst,
+                    "retry_after_ms": retry_after.as_millis() as u64,
+                })),
+            )
+        })
+    }
+
+    async fn overrides(&self) -> Result<Arc<HashMap<String, Override>>, RpcMethodError> {
+        if let Some(overrides) = self.overrides.get(&()).await {
+            return Ok(overrides);
+        }
+        let rows = sqlx::query(
+            "SELECT method, per_minute, burst, updated_by, updated_at FROM rate_limit_overrides",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load rate limits: {err}")))?;
+        // Rows of methods this build no longer knows are ignored.
+        let overrides: HashMap<String, Override> = rows
+            .iter()
+            .filter_map(|row| {
+                let method: String = row.get("method");
+                if !openrpc::has_method(&method) {
+                    return None;
+                }
+                let per_minute: i32 = row.get("per_minute");
+                let burst: Option<i32> = row.get("burst");
+                let limit = Limit::new(
+                    u32::try_from(per_minute).ok()?,
+                    burst.and_then(|burst| u32::try_from(burst).ok()),
+                );
+                let updated = Override {
+                    limit,
+                    updated_by: row.get("updated_by"),
+                    updated_at: row.get("updated_at"),
+                };
+                Some((method, updated))
+            })
+            .collect();
+        let overrides = Arc::new(overrides);
+        self.overrides.insert((), overrides.clone()).await;
+        Ok(overrides)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RateLimitSetParams {
+    method: String,
+    /// Calls per minute; `0` lifts the limit for the method.
+    per_minute: u32,
+    /// Calls that may be made at once; `per_minute` when omitted.
+    #[serde(default)]
+    burst: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RateLimitClearParams {
+    method: String,
+}
+
+fn known_method(method: &str) -> Result<(), RpcMethodError> {
+    if openrpc::has_method(method) {
+        return Ok(());
+    }
+    Err(RpcMethodError::new(
+        ErrorCode::InvalidParams,
+        "unknown method",
+        Some(json!({ "method": method })),
+    ))
+}
+
+fn db_error(err: SqlxError) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update rate limits: {err}"))
+}
+
+/// The shared limit and every method limit, configured or overridden.
+pub(crate) async fn list(limits: &RateLimits) -> Result<Value, RpcMethodError> {
+    limits.overrides.invalidate_all();
+    let overrides = limits.overrides().await?;
+    let mut methods: Vec<&str> = limits
+        .config
+        .methods
+        .keys()
+        .chain(overrides.keys())
+        .map(String::as_str)
+        .collect();
+    methods.sort_unstable();
+    methods.dedup();
+    let listed: Vec<Value> = methods
+        .into_iter()
+        .map(|method| {
+            let (limit, _) = limits.limit(&overrides, method);
+            let mut entry = json!({
+                "method": method,
+                "per_minute": limit.per_minute,
+                "burst": limit.burst,
+                "configured": limits.config.methods.get(method).map(|limit| limit.to_json()),
+            });
+            if let Some(o) = overrides.get(method) {
+                entry["updated_by"] = json!(o.updated_by);
+                entry["updated_at"] = json!(o.updated_at.to_rfc3339());
+            }
+            entry
+        })
+        .collect();
+    Ok(json!({
+        "default": limits.config.default.to_json(),
+        "methods": listed,
+    }))
+}
+
+/// Creates or replaces the override of one method's limit.
+pub(crate) async fn set(
+    limits: &RateLimits,
+    ctx: &RequestContext,
+    params: RateLimitSetParams,
+) -> Result<Value, RpcMethodError> {
+    known_method(&params.method)?;
+    let out_of_range = |field: &str| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "rate limit out of range",
+            Some(json!({ "field": field, "max": i32::MAX })),
+        )
+    };
+    let per_minute = i32::try_from(params.per_minute).map_err(|_| out_of_range("per_minute"))?;
+    let burst = params
+        .burst
+        .map(|burst| i32::try_from(burst.max(1)).map_err(|_| out_of_range("burst")))
+        .transpose()?;
+    sqlx::query(
+        "INSERT INTO rate_limit_overrides (method, per_minute, burst, updated_by) \
+         VALUES ($1, $2, $3, $4) \
+         ON CONFLICT (method) DO UPDATE SET per_minute = EXCLUDED.per_minute, \
+            burst = EXCLUDED.burst, updated_by = EXCLUDED.updated_by, updated_at = NOW()",
+    )
+    .bind(&params.method)
+    .bind(per_minute)
+    .bind(burst)
+    .bind(ctx.user_id)
+    .execute(&limits.pool)
+    .await
+    .map_err(db_error)?;
+    limits.overrides.invalidate_all();
+    let limit = Limit::new(params.per_minute, params.burst);
+    Ok(json!({
+        "method": params.method,
+        "per_minute": limit.per_minute,
+        "burst": limit.burst,
+    }))
+}
+
+/// Removes the override of one method; its configured limit, or the shared
+/// one, applies again.
+pub(crate) async fn clear(
+    limits: &RateLimits,
+    params: RateLimitClearParams,
+) -> Result<Value, RpcMethodError> {
+    known_method(&params.method)?;
+    let deleted = sqlx::query("DELETE FROM rate_limit_overrides WHERE method = $1")
+        .bind(&params.method)
+        .execute(&limits.pool)
+        .await
+        .map_err(db_error)?;
+    limits.overrides.invalidate_all();
+    Ok(json!({
+        "method": params.method,
+        "cleared": deleted.rows_affected() > 0,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_mock::request_context;
+
+    fn limits(default: Limit, methods: &[(&str, Limit)]) -> RateLimits {
+        RateLimits::new(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            RateLimitConfig {
+                default,
+                methods: methods
+                    .iter()
+                    .map(|(method, limit)| (method.to_string(), *limit))
+                    .collect(),
+                ttl: Duration::from_secs(60),
+            },
+        )
+    }
+
+    /// Skips the database; no overrides apply.
+    async fn without_overrides(limits: &RateLimits) {
+        limits.overrides.insert((), Arc::new(HashMap::new())).await;
+    }
+
+    #[test]
+    fn buckets_refill_at_the_configured_rate() {
+        let limit = Limit::new(60, Some(2));
+        let start = Instant::now();
+        let mut bucket = Bucket::full(limit, start);
+        assert!(bucket.take(limit, start).is_ok());
+        assert!(bucket.take(limit, start).is_ok());
+        let wait = bucket.take(limit, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.take(limit, later).is_ok());
+        assert!(bucket.take(limit, later).is_err());
+        // Refills stop at the burst.
+        let idle = later + Duration::from_secs(60);
+        for _ in 0..2 {
+            assert!(bucket.take(limit, idle).is_ok());
+        }
+        assert!(bucket.take(limit, idle).is_err());
+    }
+
+    #[test]
+    fn limits_parse_with_optional_burst() {
+        assert_eq!(Limit::parse("30"), Some(Limit::new(30, Some(30))));
+        assert_eq!(Limit::parse("30/5"), Some(Limit::new(30, Some(5))));
+        assert_eq!(Limit::parse("0").map(Limit::is_unlimited), Some(true));
+        assert_eq!(Limit::parse("fast"), None);
+        assert_eq!(Limit::parse("30/"), None);
+    }
+
+    #[tokio::test]
+    async fn callers_and_methods_have_separate_buckets() {
+        let metrics = AppMetrics::default();
+        let limits = limits(
+            Limit::new(60, Some(1)),
+            &[
+                ("run.exec", Limit::new(60, Some(2))),
+                ("fs.read", Limit::new(0, None)),
+            ],
+        );
+        without_overrides(&limits).await;
+        let (alice, bob) = (request_context(1), request_context(2));
+
+        assert!(limits.check("project.list", &alice, &metrics).await.is_ok());
+        let err = limits
+            .check("project.create", &alice, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited.code());
+        let data = err.data.unwrap();
+        assert_eq!(data["scope"], "default");
+        assert!(data["retry_after_ms"].as_u64().unwrap() > 0);
+        assert!(limits.check("project.list", &bob, &metrics).await.is_ok());
+
+        // An API key of the same user has its own bucket.
+        let key = RequestContext {
+            api_key_id: Some(Uuid::new_v4()),
+            ..request_context(1)
+        };
+        assert!(limits.check("project.list", &key, &metrics).await.is_ok());
+
+        for _ in 0..2 {
+            assert!(limits.check("run.exec", &alice, &metrics).await.is_ok());
+        }
+        let err = limits
+            .check("run.exec", &alice, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(err.data.unwrap()["scope"], "run.exec");
+        for _ in 0..10 {
+            assert!(limits.check("fs.read", &alice, &metrics).await.is_ok());
+        }
+        let rendered = metrics.render(false);
+        assert!(rendered.contains("api_rate_limited_total{scope=\"default\"} 1"));
+        assert!(rendered.contains("api_rate_limited_total{scope=\"run.exec\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn overrides_take_precedence_over_configured_limits() {
+        let limits = limits(
+            Limit::new(60, Some(1)),
+            &[("run.exec", Limit::new(60, Some(2)))],
+        );
+        let overrides = HashMap::from([(
+            "run.exec".to_string(),
+            Override {
+                limit: Limit::new(0, None),
+                updated_by: None,
+                updated_at: Utc::now(),
+            },
+        )]);
+        assert_eq!(
+            limits.limit(&overrides, "run.exec"),
+            (Limit::new(0, None), true)
+        );
+        assert_eq!(
+            limits.limit(&HashMap::new(), "run.exec"),
+            (Limit::new(60, Some(2)), true)
+        );
+        assert_eq!(
+            limits.limit(&overrides, "fs.read"),
+            (Limit::new(60, Some(1)), false)
+        );
+    }
+}
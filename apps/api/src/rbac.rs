@@ -0,0 +1,531 @@
+//! Role-based access control backed by `roles`, `role_permissions` and
+//! `permission_grants`. A caller's effective permissions are resolved during
+//! authentication through a read-through cache keyed by user and role, so a
+//! role change applies on the next request. `admin.roles.*` and
+//! `admin.grants.*` invalidate the cache; the TTL only bounds how long
+//! changes made by another API instance can go unnoticed.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use auth_core::KeyScope;
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Error as SqlxError, PgPool, Row};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{parse_project_id, Permission, RequestContext, Role, RpcMethodError};
+
+const MAX_ROLE_NAME: usize = 32;
+
+/// Everything one caller may do: the permissions of their role plus grants,
+/// which either apply everywhere or only inside one project. Calls made with
+/// a scoped API key are further limited by the key's [`KeyScope`].
+#[derive(Debug, Default)]
+pub(crate) struct PermissionSet {
+    global: HashSet<Permission>,
+    projects: HashMap<Uuid, HashSet<Permission>>,
+    scope: Option<KeyScope>,
+}
+
+impl PermissionSet {
+    pub(crate) fn allows(&self, permission: Permission, project_id: Option<&Uuid>) -> bool {
+        let granted = self.global.contains(&permission)
+            || project_id
+                .and_then(|id| self.projects.get(id))
+                .is_some_and(|granted| granted.contains(&permission));
+        granted
+            && self
+                .scope
+                .as_ref()
+                .is_none_or(|scope| scope.allows(permission, project_id))
+    }
+
+    /// Whether the API key used for the call may touch `project_id` at all.
+    pub(crate) fn in_scope(&self, project_id: &Uuid) -> bool {
+        self.scope
+            .as_ref()
+            .is_none_or(|scope| scope.allows_project(project_id))
+    }
+
+    /// The same permissions, narrowed to what a scoped API key allows.
+    pub(crate) fn with_scope(&self, scope: KeyScope) -> Self {
+        Self {
+            global: self.global.clone(),
+            projects: self.projects.clone(),
+            scope: Some(scope),
+        }
+    }
+
+    /// Whether `permission` was granted on `project_id` itself. Such a grant
+    /// opens the project to the grantee for that permission only, whoever
+    /// owns it.
+    pub(crate) fn has_project_grant(&self, project_id: &Uuid, permission: Permission) -> bool {
+        self.projects
+            .get(project_id)
+            .is_some_and(|granted| granted.contains(&permission))
+    }
+
+    fn insert(&mut self, permission: Permission, project_id: Option<Uuid>) {
+        match project_id {
+            Some(id) => {
+                self.projects.entry(id).or_default().insert(permission);
+            }
+            None => {
+                self.global.insert(permission);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RbacConfig {
+    capacity: u64,
+    ttl: Duration,
+}
+
+impl RbacConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config.get("RBAC_CACHE_CAPACITY", 10_000),
+            ttl: config.secs("RBAC_CACHE_TTL_SECS", 30),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Rbac {
+    pool: PgPool,
+    cache: Cache<(i32, String), Arc<PermissionSet>>,
+}
+
+impl Rbac {
+    pub(crate) fn new(pool: PgPool, config: RbacConfig) -> Self {
+        Self {
+            pool,
+            cache: Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .support_invalidation_closures()
+                .build(),
+        }
+    }
+
+    pub(crate) async fn permissions(
+        &self,
+        user_id: i32,
+        role: &Role,
+    ) -> Result<Arc<PermissionSet>, RpcMethodError> {
+        let key = (user_id, role.as_str().to_string());
+        if let Some(permissions) = self.cache.get(&key).await {
+            return Ok(permissions);
+        }
+        let rows = sqlx::query(
+            "SELECT permission, NULL::uuid AS project_id FROM role_permissions WHERE role = $2 \
+             UNION ALL \
+             SELECT permission, project_id FROM permission_grants WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .bind(&key.1)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to load permissions: {err}")))?;
+        let mut permissions = PermissionSet::default();
+        for row in rows {
+            let name: String = row.get("permission");
+            match Permission::parse(&name) {
+                Some(permission) => permissions.insert(permission, row.get("project_id")),
+                None => warn!(permission = %name, user_id, "ignoring unknown permission"),
+            }
+        }
+        let permissions = Arc::new(permissions);
+        self.cache.insert(key, permissions.clone()).await;
+        Ok(permissions)
+    }
+
+    fn invalidate_user(&self, user_id: i32) {
+        if let Err(err) = self
+            .cache
+            .invalidate_entries_if(move |(id, _), _| *id == user_id)
+        {
+            warn!(user_id, error = %err, "failed to invalidate permissions");
+            self.cache.invalidate_all();
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RoleSetParams {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    permissions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RoleNameParams {
+    name: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GrantListParams {
+    user_id: i32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GrantAddParams {
+    user_id: i32,
+    permission: String,
+    /// Limits the grant to one project.
+    #[serde(default)]
+    project_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GrantIdParams {
+    grant_id: i64,
+}
+
+fn role_not_found(name: &str) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::RoleNotFound,
+        "role not found",
+        Some(json!({ "role": name })),
+    )
+}
+
+fn parse_permission(value: &str) -> Result<Permission, RpcMethodError> {
+    Permission::parse(value).ok_or_else(|| {
+        let supported: Vec<&str> = Permission::ALL.iter().map(|p| p.as_str()).collect();
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "unsupported permission",
+            Some(json!({ "permission": value, "supported": supported })),
+        )
+    })
+}
+
+/// Role names are lowercase identifiers; `admin` stays as seeded so there is
+/// always a role that can undo a mistake.
+fn validate_role_name(name: &str) -> Result<(), RpcMethodError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_ROLE_NAME
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid role name",
+            Some(json!({ "role": name, "pattern": "[a-z0-9_-]{1,32}" })),
+        ));
+    }
+    if name == Role::Admin.as_str() {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "the admin role cannot be changed",
+            None,
+        ));
+    }
+    Ok(())
+}
+
+fn is_foreign_key_violation(err: &SqlxError) -> bool {
+    matches!(err, SqlxError::Database(db_err) if db_err.code().as_deref() == Some("23503"))
+}
+
+fn db_error(err: SqlxError) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update permissions: {err}"))
+}
+
+/// All roles, with how many users of the caller's tenant hold each.
+pub(crate) async fn list_roles(
+    pool: &PgPool,
+    ctx: &RequestContext,
+) -> Result<Value, RpcMethodError> {
+    let rows = sqlx::query(
+        "SELECT roles.name, roles.description, roles.builtin, \
+            COALESCE(array_agg(role_permissions.permission ORDER BY role_permissions.permission) \
+                FILTER (WHERE role_permissions.permission IS NOT NULL), '{}') AS permissions, \
+            (SELECT COUNT(*) FROM users \
+             WHERE users.role = roles.name AND users.tenant_id = $1) AS users \
+         FROM roles LEFT JOIN role_permissions ON role_permissions.role = roles.name \
+         GROUP BY roles.name ORDER BY roles.builtin DESC, roles.name",
+    )
+    .bind(ctx.tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list roles: {err}")))?;
+    let roles: Vec<Value> = rows
+        .iter()
+        .map(|row| {
+            json!({
+                "name": row.get::<String, _>("name"),
+                "description": row.get::<Option<String>, _>("description"),
+                "builtin": row.get::<bool, _>("builtin"),
+                "permissions": row.get::<Vec<String>, _>("permissions"),
+                "users": row.get::<i64, _>("users"),
+            })
+        })
+        .collect();
+    let permissions: Vec<&str> = Permission::ALL.iter().map(|p| p.as_str()).collect();
+    Ok(json!({ "roles": roles, "permissions": permissions }))
+}
+
+/// Creates a role or replaces its permission list. Built-in roles other than
+/// `admin` may be retuned but keep their `builtin` flag.
+pub(crate) async fn set_role(rbac: &Rbac, params: RoleSetParams) -> Result<Value, RpcMethodError> {
+    validate_role_name(&params.name)?;
+    let mut permissions = params
+        .permissions
+        .iter()
+        .map(|name| parse_permission(name).map(Permission::as_str))
+        .collect::<Result<Vec<_>, _>>()?;
+    permissions.sort_unstable();
+    permissions.dedup();
+
+    let mut tx = rbac.pool.begin().await.map_err(db_error)?;
+    let builtin: bool = sqlx::query_scalar(
+        "INSERT INTO roles (name, description) VALUES ($1, $2) \
+         ON CONFLICT (name) DO UPDATE SET \
+            description = COALESCE(EXCLUDED.description, roles.description), \
+            updated_at = NOW() \
+         RETURNING builtin",
+    )
+    .bind(&params.name)
+    .bind(&params.description)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    sqlx::query("DELETE FROM role_permissions WHERE role = $1")
+        .bind(&params.name)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query("INSERT INTO role_permissions (role, permission) SELECT $1, unnest($2::varchar[])")
+        .bind(&params.name)
+        .bind(&permissions)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+    rbac.cache.invalidate_all();
+
+    Ok(json!({
+        "name": params.name,
+        "builtin": builtin,
+        "permissions": permissions,
+    }))
+}
+
+/// Deletes a custom role. Roles still assigned to a user are kept.
+pub(crate) async fn delete_role(
+    rbac: &Rbac,
+    params: RoleNameParams,
+) -> Result<Value, RpcMethodError> {
+    let builtin: Option<bool> = sqlx::query_scalar("SELECT builtin FROM roles WHERE name = $1")
+        .bind(&params.name)
+        .fetch_optional(&rbac.pool)
+        .await
+        .map_err(db_error)?;
+    match builtin {
+        None => return Err(role_not_found(&params.name)),
+        Some(true) => {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "built-in roles cannot be deleted",
+                Some(json!({ "role": params.name })),
+            ))
+        }
+        Some(false) => {}
+    }
+    let deleted = sqlx::query("DELETE FROM roles WHERE name = $1")
+        .bind(&params.name)
+        .execute(&rbac.pool)
+        .await
+        .map_err(|err| {
+            if is_foreign_key_violation(&err) {
+                RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "role is still assigned to users",
+                    Some(json!({ "role": params.name })),
+                )
+            } else {
+                db_error(err)
+            }
+        })?;
+    if deleted.rows_affected() == 0 {
+        return Err(role_not_found(&params.name));
+    }
+    rbac.cache.invalidate_all();
+    Ok(json!({ "name": params.name, "deleted": true }))
+}
+
+fn grant_value(row: &sqlx::postgres::PgRow) -> Value {
+    json!({
+        "id": row.get::<i64, _>("id"),
+        "user_id": row.get::<i32, _>("user_id"),
+        "permission": row.get::<String, _>("permission"),
+        "project_id": row.get::<Option<Uuid>, _>("project_id"),
+        "granted_by": row.get::<Option<i32>, _>("granted_by"),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+    })
+}
+
+pub(crate) async fn list_grants(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    params: GrantListParams,
+) -> Result<Value, RpcMethodError> {
+    let rows = sqlx::query(
+        "SELECT id, user_id, permission, project_id, granted_by, created_at \
+         FROM permission_grants \
+         WHERE user_id = $1 AND user_id IN (SELECT id FROM users WHERE tenant_id = $2) \
+         ORDER BY id",
+    )
+    .bind(params.user_id)
+    .bind(ctx.tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list grants: {err}")))?;
+    let grants: Vec<Value> = rows.iter().map(grant_value).collect();
+    Ok(json!({ "grants": grants }))
+}
+
+/// Grants one permission to a user on top of their role. Granting the same
+/// permission twice returns the existing grant. User and project must belong
+/// to the caller's tenant.
+pub(crate) async fn add_grant(
+    rbac: &Rbac,
+    ctx: &RequestContext,
+    params: GrantAddParams,
+) -> Result<Value, RpcMethodError> {
+    let permission = parse_permission(&params.permission)?;
+    let project_id = params
+        .project_id
+        .as_deref()
+        .map(parse_project_id)
+        .transpose()?;
+    let row = sqlx::query(
+        "INSERT INTO permission_grants (user_id, permission, project_id, granted_by) \
+         SELECT $1, $2, $3, $4 \
+         WHERE EXISTS (SELECT 1 FROM users WHERE id = $1 AND tenant_id = $5) \
+           AND ($3::uuid IS NULL \
+                OR EXISTS (SELECT 1 FROM projects WHERE id = $3 AND tenant_id = $5)) \
+         ON CONFLICT (user_id, permission, \
+            COALESCE(project_id, '00000000-0000-0000-0000-000000000000'::uuid)) \
+         DO UPDATE SET granted_by = permission_grants.granted_by \
+         RETURNING id, user_id, permission, project_id, granted_by, created_at",
+    )
+    .bind(params.user_id)
+    .bind(permission.as_str())
+    .bind(project_id)
+    .bind(ctx.user_id)
+    .bind(ctx.tenant_id)
+    .fetch_optional(&rbac.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "unknown user or project",
+            Some(json!({ "user_id": params.user_id, "project_id": params.project_id })),
+        )
+    })?;
+    rbac.invalidate_user(params.user_id);
+    Ok(grant_value(&row))
+}
+
+pub(crate) async fn revoke_grant(
+    rbac: &Rbac,
+    ctx: &RequestContext,
+    params: GrantIdParams,
+) -> Result<Value, RpcMethodError> {
+    let user_id: Option<i32> = sqlx::query_scalar(
+        "DELETE FROM permission_grants \
+         WHERE id = $1 AND user_id IN (SELECT id FROM users WHERE tenant_id = $2) \
+         RETURNING user_id",
+    )
+    .bind(params.grant_id)
+    .bind(ctx.tenant_id)
+    .fetch_optional(&rbac.pool)
+    .await
+    .map_err(db_error)?;
+    let user_id = user_id.ok_or_else(|| {
+        RpcMethodError::new(
+            ErrorCode::GrantNotFound,
+            "grant not found",
+            Some(json!({ "grant_id": params.grant_id })),
+        )
+    })?;
+    rbac.invalidate_user(user_id);
+    Ok(json!({ "grant_id": params.grant_id, "revoked": true }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_grants_only_apply_inside_their_project() {
+        let project = Uuid::new_v4();
+        let mut permissions = PermissionSet::default();
+        permissions.insert(Permission::FsRead, None);
+        permissions.insert(Permission::Execute, Some(project));
+
+        assert!(permissions.allows(Permission::FsRead, None));
+        assert!(permissions.allows(Permission::FsRead, Some(&Uuid::new_v4())));
+        assert!(permissions.allows(Permission::Execute, Some(&project)));
+        assert!(!permissions.allows(Permission::Execute, None));
+        assert!(!permissions.allows(Permission::Execute, Some(&Uuid::new_v4())));
+        assert!(permissions.has_project_grant(&project, Permission::Execute));
+        // A grant opens the project for its own permission only.
+        assert!(!permissions.has_project_grant(&project, Permission::FsRead));
+        assert!(!permissions.has_project_grant(&Uuid::new_v4(), Permission::Execute));
+    }
+
+    #[test]
+    fn key_scopes_narrow_permissions() {
+        let (project, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut permissions = PermissionSet::default();
+        permissions.insert(Permission::FsRead, None);
+        permissions.insert(Permission::FsWrite, None);
+        assert!(KeyScope::parse(&[]).is_none());
+
+        let scope = KeyScope::parse(&["fs:read".to_string(), format!("project:{project}")]);
+        let scoped = permissions.with_scope(scope.unwrap());
+        assert!(scoped.allows(Permission::FsRead, Some(&project)));
+        assert!(!scoped.allows(Permission::FsRead, Some(&other)));
+        assert!(!scoped.allows(Permission::FsRead, None));
+        assert!(!scoped.allows(Permission::FsWrite, Some(&project)));
+        assert!(scoped.in_scope(&project) && !scoped.in_scope(&other));
+
+        // A scope never adds a permission the owner lacks.
+        let scoped = permissions.with_scope(KeyScope::parse(&["run:exec".to_string()]).unwrap());
+        assert!(!scoped.allows(Permission::Execute, None));
+        let scoped =
+            permissions.with_scope(KeyScope::parse(&["project:nope".to_string()]).unwrap());
+        assert!(!scoped.allows(Permission::FsRead, Some(&project)));
+        assert!(!scoped.in_scope(&project));
+    }
+
+    #[test]
+    fn validates_role_and_permission_names() {
+        assert!(validate_role_name("reviewer").is_ok());
+        assert!(validate_role_name("Reviewer").is_err());
+        assert!(validate_role_name("").is_err());
+        assert!(validate_role_name("admin").is_err());
+        assert_eq!(parse_permission("execute").unwrap(), Permission::Execute);
+        assert_eq!(parse_permission("root").unwrap_err().code, -32602);
+        for permission in Permission::ALL {
+            assert_eq!(Permission::parse(permission.as_str()), Some(permission));
+        }
+    }
+}
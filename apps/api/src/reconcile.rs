@@ -0,0 +1,298 @@
+//! Project deletion across Postgres and the sandbox mirror. `project.delete`
+//! locks the project row, moves its directory into the `.trash/projects`
+//! directory of its tenant, deletes
+//! the rows and commits; only then is the trashed copy purged. A failed commit
+//! moves the directory back. The scheduler's `trash_purge` job reconciles
+//! whatever a crash leaves behind: trashed directories of projects that still
+//! exist are restored, the rest are purged, and live directories without a
+//! row in that tenant are trashed.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Utc;
+use sandbox::SandboxFs;
+use sqlx::PgPool;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::engine::Engines;
+use crate::errors::ErrorCode;
+use crate::{tenant, RpcMethodError};
+
+const PROJECTS_DIR: &str = "projects";
+const TRASH_DIR: &str = ".trash/projects";
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SweepConfig {
+    grace: Duration,
+}
+
+impl SweepConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            grace: config.secs("PROJECT_SWEEP_GRACE_SECS", 60),
+        }
+    }
+}
+
+fn projects_dir(tenant_id: i32) -> PathBuf {
+    tenant::directory_relative(tenant_id).join(PROJECTS_DIR)
+}
+
+fn trash_dir(tenant_id: i32) -> PathBuf {
+    tenant::directory_relative(tenant_id).join(TRASH_DIR)
+}
+
+fn project_dir(tenant_id: i32, project_id: &Uuid) -> PathBuf {
+    projects_dir(tenant_id).join(project_id.to_string())
+}
+
+/// Trash entries are named `<project id>.<unix seconds>` so the sweeper can
+/// tell in-flight deletions from leftovers.
+fn parse_trash_entry(name: &str) -> Option<(Uuid, i64)> {
+    let (id, trashed_at) = name.split_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, trashed_at.parse().ok()?))
+}
+
+fn delete_error(err: impl std::fmt::Display) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to delete project: {err}"))
+}
+
+/// Deletes the project rows and its sandbox directory. Engines clean up
+/// first, since micro VMs started in the project work inside it.
+pub(crate) async fn delete_project(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    engines: &Engines,
+    tenant_id: i32,
+    project_id: &Uuid,
+) -> Result<(), RpcMethodError> {
+    let mut tx = pool.begin().await.map_err(delete_error)?;
+    let locked: Option<Uuid> =
+        sqlx::query_scalar("SELECT id FROM projects WHERE id = $1 FOR UPDATE")
+            .bind(project_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(delete_error)?;
+    if locked.is_none() {
+        return Err(RpcMethodError::new(
+            ErrorCode::ProjectNotFound,
+            "project not found",
+            None,
+        ));
+    }
+
+    let live = project_dir(tenant_id, project_id);
+    engines.cleanup(&live).await.map_err(|err| {
+        RpcMethodError::from_sandbox(
+            ErrorCode::ProjectFilesRemove,
+            "failed to remove project files",
+            err,
+        )
+    })?;
+    let trashed = if sandbox.base_dir().join(&live).exists() {
+        let target = trash_dir(tenant_id).join(format!("{project_id}.{}", Utc::now().timestamp()));
+        sandbox.move_path(&live, &target).map_err(|err| {
+            RpcMethodError::from_sandbox(
+                ErrorCode::ProjectFilesRemove,
+                "failed to remove project files",
+                err,
+            )
+        })?;
+        Some(target)
+    } else {
+        None
+    };
+
+    let committed = async {
+        sqlx::query("DELETE FROM projects WHERE id = $1")
+            .bind(project_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(err) = committed {
+        if let Some(trashed) = &trashed {
+            if let Err(restore_err) = sandbox.move_path(trashed, &live) {
+                error!(%project_id, error = %restore_err, "failed to restore project directory after aborted delete");
+            }
+        }
+        return Err(delete_error(err));
+    }
+
+    if let Some(trashed) = trashed {
+        if let Err(err) = sandbox.delete(&trashed) {
+            warn!(%project_id, error = %err, "leaving trashed project directory for the sweeper");
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SweepAction {
+    /// The row survived an aborted delete; move the directory back.
+    Restore { entry: String, project_id: Uuid },
+    /// The row is gone; drop the trashed copy.
+    Purge(String),
+    /// A live directory without a row.
+    Trash(Uuid),
+}
+
+fn plan_sweep(
+    live: &[String],
+    trashed: &[String],
+    existing: &HashSet<Uuid>,
+    now: i64,
+    grace: Duration,
+) -> Vec<SweepAction> {
+    let live_ids: HashSet<Uuid> = live
+        .iter()
+        .filter_map(|name| Uuid::parse_str(name).ok())
+        .collect();
+    let mut actions = Vec::new();
+    for entry in trashed {
+        let Some((project_id, trashed_at)) = parse_trash_entry(entry) else {
+            continue;
+        };
+        if now - trashed_at < grace.as_secs() as i64 {
+            continue;
+        }
+        if !existing.contains(&project_id) {
+            actions.push(SweepAction::Purge(entry.clone()));
+        } else if !live_ids.contains(&project_id) {
+            actions.push(SweepAction::Restore {
+                entry: entry.clone(),
+                project_id,
+            });
+        }
+    }
+    for project_id in live_ids {
+        if !existing.contains(&project_id) {
+            actions.push(SweepAction::Trash(project_id));
+        }
+    }
+    actions
+}
+
+fn list_names(sandbox: &SandboxFs, dir: &Path) -> Vec<String> {
+    if !sandbox.base_dir().join(dir).is_dir() {
+        return Vec::new();
+    }
+    match sandbox.list(dir) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter(|entry| entry.is_dir)
+            .map(|entry| entry.name)
+            .collect(),
+        Err(err) => {
+            warn!(dir = %dir.display(), error = %err, "failed to list directory for project sweep");
+            Vec::new()
+        }
+    }
+}
+
+pub(crate) async fn sweep(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    config: SweepConfig,
+) -> Result<usize, sqlx::Error> {
+    let mut applied = 0;
+    for tenant_id in tenant::present(sandbox.base_dir()) {
+        applied += sweep_tenant(pool, sandbox, config, tenant_id).await?;
+    }
+    Ok(applied)
+}
+
+async fn sweep_tenant(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    config: SweepConfig,
+    tenant_id: i32,
+) -> Result<usize, sqlx::Error> {
+    let trash = trash_dir(tenant_id);
+    let live = list_names(sandbox, &projects_dir(tenant_id));
+    let trashed = list_names(sandbox, &trash);
+    let candidates: Vec<Uuid> = live
+        .iter()
+        .filter_map(|name| Uuid::parse_str(name).ok())
+        .chain(
+            trashed
+                .iter()
+                .filter_map(|name| parse_trash_entry(name).map(|(id, _)| id)),
+        )
+        .collect();
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+    let existing: Vec<Uuid> =
+        sqlx::query_scalar("SELECT id FROM projects WHERE id = ANY($1) AND tenant_id = $2")
+            .bind(&candidates)
+            .bind(tenant_id)
+            .fetch_all(pool)
+            .await?;
+    let existing: HashSet<Uuid> = existing.into_iter().collect();
+
+    let actions = plan_sweep(
+        &live,
+        &trashed,
+        &existing,
+        Utc::now().timestamp(),
+        config.grace,
+    );
+    let mut applied = 0;
+    for action in &actions {
+        let result = match action {
+            SweepAction::Restore { entry, project_id } => {
+                sandbox.move_path(trash.join(entry), project_dir(tenant_id, project_id))
+            }
+            SweepAction::Purge(entry) => sandbox.delete(trash.join(entry)),
+            SweepAction::Trash(project_id) => sandbox.move_path(
+                project_dir(tenant_id, project_id),
+                trash.join(format!("{project_id}.{}", Utc::now().timestamp())),
+            ),
+        };
+        match result {
+            Ok(()) => applied += 1,
+            Err(err) => warn!(?action, tenant_id, error = %err, "project sweep action failed"),
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_restores_purges_and_trashes() {
+        let kept = Uuid::new_v4();
+        let gone = Uuid::new_v4();
+        let orphan = Uuid::new_v4();
+        let fresh = Uuid::new_v4();
+        let existing: HashSet<Uuid> = [kept].into_iter().collect();
+        let live = vec![orphan.to_string(), "README".to_string()];
+        let trashed = vec![
+            format!("{kept}.100"),
+            format!("{gone}.100"),
+            format!("{fresh}.990"),
+            "garbage".to_string(),
+        ];
+
+        let actions = plan_sweep(&live, &trashed, &existing, 1000, Duration::from_secs(60));
+        assert_eq!(
+            actions,
+            vec![
+                SweepAction::Restore {
+                    entry: format!("{kept}.100"),
+                    project_id: kept,
+                },
+                SweepAction::Purge(format!("{gone}.100")),
+                SweepAction::Trash(orphan),
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Hot reload of sandbox policies. SIGHUP or `admin.sandbox.reload` reads the
+//! configuration again (environment and `--config` file), validates all of
+//! it and swaps the run allowlists, micro images and wasm limits in place.
+//! Running processes, micro instances and session counters are kept, so an
+//! allowlist can be tightened during an incident without a restart. Every
+//! other setting still applies only at startup.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use secrets::Secrets;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::config::{ApiConfig, Config};
+use crate::errors::ErrorCode;
+use crate::{sandbox_policies, sandbox_root, AppState, RpcMethodError};
+
+pub(crate) struct Reloader {
+    path: Option<PathBuf>,
+    secrets: Arc<Secrets>,
+    /// Serializes reloads so two of them cannot interleave their swaps.
+    lock: Mutex<()>,
+}
+
+fn invalid_config(detail: impl ToString) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::InvalidConfiguration,
+        "invalid configuration",
+        Some(json!({ "detail": detail.to_string() })),
+    )
+}
+
+impl Reloader {
+    pub(crate) fn new(path: Option<PathBuf>, secrets: Arc<Secrets>) -> Self {
+        Self {
+            path,
+            secrets,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Applies the current sandbox settings. Nothing is swapped unless the
+    /// whole configuration is valid.
+    pub(crate) async fn reload(&self, state: &AppState) -> Result<Value, RpcMethodError> {
+        let _guard = self.lock.lock().await;
+        let mut config =
+            Config::load(self.path.as_deref()).map_err(|err| invalid_config(format!("{err:#}")))?;
+        // The secrets manager connection from startup stays in use; its
+        // settings are only read so they are not reported as unknown.
+        config.secrets_config();
+        config.resolve_secrets(&self.secrets).await;
+        let settings = ApiConfig::read(&config).sandbox;
+        config.finish().map_err(invalid_config)?;
+
+        let root = state.sandbox.base_dir();
+        let configured = sandbox_root(&settings.root).map_err(invalid_config)?;
+        if configured != root {
+            return Err(invalid_config(format!(
+                "SANDBOX_ROOT cannot change without a restart (running with {})",
+                root.display()
+            )));
+        }
+        let (run, wasm, micro) =
+            sandbox_policies(settings, root).map_err(|err| invalid_config(format!("{err:#}")))?;
+        let swap = |err: sandbox::SandboxError| RpcMethodError::internal(&err.to_string());
+        state.run.reload(run).map_err(swap)?;
+        state.wasm.reload(wasm).map_err(swap)?;
+        state.micro.reload(micro).map_err(swap)?;
+
+        let run = state.run.config();
+        let mut allowed: Vec<&String> = run.allowed_programs().collect();
+        allowed.sort();
+        let micro = state.micro.config();
+        let images: Vec<&str> = micro.images().map(|image| image.name()).collect();
+        let wasm = state.wasm.config();
+        info!(
+            allowed_programs = allowed.len(),
+            micro_images = images.len(),
+            "sandbox policies reloaded"
+        );
+        Ok(json!({
+            "run": {
+                "allowed_programs": allowed,
+                "default_timeout_ms": run.default_timeout().as_millis() as u64,
+                "max_timeout_ms": run.max_timeout().as_millis() as u64,
+                "max_output_bytes": run.max_output_bytes(),
+            },
+            "micro": {
+                "images": images,
+                "default_timeout_ms": micro.default_timeout().as_millis() as u64,
+                "max_timeout_ms": micro.max_timeout().as_millis() as u64,
+                "max_output_bytes": micro.max_output_bytes(),
+            },
+            "wasm": {
+                "max_memory_bytes": wasm.max_memory_bytes(),
+                "max_table_elements": wasm.max_table_elements(),
+                "default_fuel": wasm.default_fuel(),
+            },
+        }))
+    }
+}
+
+/// Reloads the sandbox policies on every SIGHUP.
+#[cfg(unix)]
+pub(crate) fn spawn_sighup_listener(state: AppState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(error = %err, "failed to listen for SIGHUP");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received; reloading sandbox policies");
+            if let Err(err) = state.reloader.reload(&state).await {
+                error!(error = %err.message, data = ?err.data, "sandbox policy reload failed");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_sighup_listener(_state: AppState) {}
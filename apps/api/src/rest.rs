@@ -0,0 +1,993 @@
+//! REST facade over the JSON-RPC methods for clients that cannot speak
+//! JSON-RPC. Routes authenticate exactly like `/rpc` and forward to
+//! `process_audited_request`, so permissions and validation live in one place.
+//! The whole facade sits behind the `rest` feature flag, the streaming routes
+//! additionally behind `streaming`.
+//! Raw file transfers are the exception: uploads and downloads skip the
+//! base64 round trip, talk to `project_files` directly and audit themselves.
+//! Uploads are read into memory up to `REST_UPLOAD_MAX_BYTES` before any of
+//! them is stored.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Multipart, Path as UrlPath, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::admission::Permit;
+use crate::audit::{self, AuditEvent};
+use crate::billing::Charge;
+use crate::errors::ErrorCode;
+use crate::flags::Flag;
+use crate::jobs::{self, JobKind};
+use crate::llm::ChatStream;
+use crate::llm_usage::{Outcome, UsageEntry};
+use crate::{
+    authenticate_request, authentication_failed, load_project, normalize_project_path,
+    parse_params, parse_project_id, process_audited_request, store_project_files, validate_params,
+    AppState, LlmChatParams, Peer, Permission, ProjectRecord, RequestContext, RpcMethodError,
+};
+use crate::{engine, telemetry, transfer, versioning};
+
+pub(crate) fn routes(body_limit: usize, upload_limit: usize) -> Router<AppState> {
+    Router::new()
+        .route("/projects", get(get_projects))
+        .route(
+            "/projects/:id/files/*path",
+            get(get_project_file).put(put_project_file),
+        )
+        .route(
+            "/projects/:id/upload",
+            post(post_project_upload).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/jobs/:id/artifact", get(get_job_artifact))
+        .route(
+            "/runs",
+            post(post_run).layer(DefaultBodyLimit::max(body_limit)),
+        )
+        .route(
+            "/llm/chat/stream",
+            post(post_chat_stream).layer(DefaultBodyLimit::max(body_limit)),
+        )
+        .route(
+            "/sandbox/:method/stream",
+            post(post_engine_stream).layer(DefaultBodyLimit::max(body_limit)),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+struct FileSaveQuery {
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    #[serde(default)]
+    dir: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+async fn get_projects(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+) -> Response {
+    call(&state, &headers, peer, "project.list", None).await
+}
+
+/// Serves the stored file bytes with an ETag derived from the sha256 column
+/// and single-range support. Ranges are sliced in Postgres so partial reads
+/// never load the whole file.
+async fn get_project_file(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    UrlPath((project_id, path)): UrlPath<(String, String)>,
+) -> Response {
+    let started = Instant::now();
+    let (ctx, project) =
+        match project_target(&state, &headers, peer, &project_id, Permission::FsRead).await {
+            Ok(target) => target,
+            Err(err) => return error_response(err),
+        };
+    let project_id = project.id;
+    let digest = audit::params_digest(Some(&json!({
+        "project_id": project_id,
+        "path": path,
+    })));
+    let result = download(&state, &headers, &project_id, &path).await;
+    let outcome = match &result {
+        Ok(_) => Ok(Value::Null),
+        Err(err) => Err(RpcMethodError {
+            code: err.code,
+            message: err.message.clone(),
+            data: None,
+        }),
+    };
+    state
+        .audit
+        .record(AuditEvent::new(
+            &ctx,
+            "project.file.read",
+            digest,
+            &outcome,
+            started.elapsed(),
+        ))
+        .await;
+    result.unwrap_or_else(error_response)
+}
+
+async fn download(
+    state: &AppState,
+    headers: &HeaderMap,
+    project_id: &Uuid,
+    path: &str,
+) -> Result<Response, RpcMethodError> {
+    let relative_path = normalize_project_path(path)?;
+    let meta = project_file_meta(&state.pool, project_id, &relative_path).await?;
+    let etag = format!("\"{}\"", hex::encode(&meta.sha256));
+    let content_type = mime_guess::from_path(&relative_path)
+        .first_or_octet_stream()
+        .to_string();
+    let last_modified = meta
+        .updated_at
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, &etag));
+    if not_modified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)],
+        )
+            .into_response());
+    }
+
+    let size = meta.size.max(0) as u64;
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| parse_range(value, size));
+    let (status, start, end) = match range {
+        Some(Err(())) => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response());
+        }
+        Some(Ok(Some((start, end)))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(Ok(None)) | None if size == 0 => (StatusCode::OK, 0, 0),
+        Some(Ok(None)) | None => (StatusCode::OK, 0, size - 1),
+    };
+    let data = if size == 0 {
+        Vec::new()
+    } else {
+        project_file_slice(
+            &state.pool,
+            project_id,
+            &relative_path,
+            start,
+            end - start + 1,
+        )
+        .await?
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, data.len())
+        .header(header::ETAG, etag)
+        .header(header::LAST_MODIFIED, last_modified)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{size}"));
+    }
+    response
+        .body(Body::from(data))
+        .map_err(|err| RpcMethodError::internal(&err.to_string()))
+}
+
+struct ProjectFileMeta {
+    size: i64,
+    sha256: Vec<u8>,
+    updated_at: DateTime<Utc>,
+}
+
+async fn project_file_meta(
+    pool: &PgPool,
+    project_id: &Uuid,
+    path: &Path,
+) -> Result<ProjectFileMeta, RpcMethodError> {
+    let path_str = path.to_string_lossy().to_string();
+    let row = sqlx::query(
+        "SELECT size, sha256, updated_at FROM project_files WHERE project_id = $1 AND path = $2",
+    )
+    .bind(project_id)
+    .bind(&path_str)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to read project file: {err}")))?;
+    let row = row.ok_or_else(|| {
+        RpcMethodError::new(
+            ErrorCode::ProjectConflict,
+            "project file not found",
+            Some(json!({ "path": path_str })),
+        )
+    })?;
+    Ok(ProjectFileMeta {
+        size: row.get("size"),
+        sha256: row.get("sha256"),
+        updated_at: row.get("updated_at"),
+    })
+}
+
+async fn project_file_slice(
+    pool: &PgPool,
+    project_id: &Uuid,
+    path: &Path,
+    start: u64,
+    length: u64,
+) -> Result<Vec<u8>, RpcMethodError> {
+    let content: Option<Vec<u8>> = sqlx::query_scalar(
+        "SELECT substring(content FROM $3 FOR $4) FROM project_files WHERE project_id = $1 AND path = $2",
+    )
+    .bind(project_id)
+    .bind(path.to_string_lossy().to_string())
+    .bind(start as i64 + 1)
+    .bind(length as i64)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to read project file: {err}")))?;
+    content.ok_or_else(|| {
+        RpcMethodError::new(ErrorCode::ProjectConflict, "project file not found", None)
+    })
+}
+
+fn etag_matches(header_value: &str, etag: &str) -> bool {
+    header_value
+        .split(',')
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+/// Parses a `Range` header into an inclusive byte range. Returns `Ok(None)`
+/// for headers that should be ignored (other units, multiple ranges) and
+/// `Err(())` when the range cannot be satisfied.
+fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 || size == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size - 1)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (start, end.min(size.saturating_sub(1)))
+        }
+    };
+    if start >= size || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Saves the raw request body as the project file, hashing it as it is read.
+/// The body is never base64-encoded but is held in memory until it is
+/// stored; it is rejected with 413 as soon as it exceeds the upload limit.
+async fn put_project_file(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    UrlPath((project_id, path)): UrlPath<(String, String)>,
+    Query(query): Query<FileSaveQuery>,
+    body: Body,
+) -> Response {
+    let limit = state.upload_limit;
+    let declared = headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return payload_too_large(limit);
+    }
+    let (ctx, project) =
+        match project_target(&state, &headers, peer, &project_id, Permission::FsWrite).await {
+            Ok(target) => target,
+            Err(err) => return error_response(err),
+        };
+
+    let mut data = Vec::with_capacity(declared.unwrap_or_default());
+    let mut hasher = Sha256::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                return error_response(RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "failed to read request body",
+                    Some(json!({ "detail": err.to_string() })),
+                ))
+            }
+        };
+        if data.len() + chunk.len() > limit {
+            return payload_too_large(limit);
+        }
+        hasher.update(&chunk);
+        data.extend_from_slice(&chunk);
+    }
+    let upload = Upload {
+        path,
+        sha256: hasher.finalize().to_vec(),
+        data,
+    };
+
+    let message = query.message.as_deref();
+    match save_uploads(&state, &ctx, &project, &[upload], message).await {
+        Ok(mut saved) => Json(saved[0].take()).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Multipart upload of one or more files. Each part with a filename is
+/// stored under `?dir=` (the project root by default); other parts are
+/// ignored. The files are read in full first and then saved all or none.
+async fn post_project_upload(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    UrlPath(project_id): UrlPath<String>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Response {
+    let (ctx, project) =
+        match project_target(&state, &headers, peer, &project_id, Permission::FsWrite).await {
+            Ok(target) => target,
+            Err(err) => return error_response(err),
+        };
+    let dir = query.dir.unwrap_or_default();
+    let mut uploads = Vec::new();
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return multipart_error(err),
+        };
+        let file_name = match field.file_name() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => continue,
+        };
+        let path = Path::new(dir.trim_matches('/')).join(&file_name);
+
+        let mut data = Vec::new();
+        let mut hasher = Sha256::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    if data.len() + chunk.len() > state.upload_limit {
+                        return payload_too_large(state.upload_limit);
+                    }
+                    hasher.update(&chunk);
+                    data.extend_from_slice(&chunk);
+                }
+                Ok(None) => break,
+                Err(err) => return multipart_error(err),
+            }
+        }
+        uploads.push(Upload {
+            path: path.to_string_lossy().to_string(),
+            sha256: hasher.finalize().to_vec(),
+            data,
+        });
+    }
+    if uploads.is_empty() {
+        return error_response(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "multipart body contained no files",
+            None,
+        ));
+    }
+    let message = query.message.as_deref();
+    match save_uploads(&state, &ctx, &project, &uploads, message).await {
+        Ok(saved) => Json(json!({ "files": saved })).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Authenticates a file route and checks access to the project.
+async fn project_target(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    project_id: &str,
+    permission: Permission,
+) -> Result<(RequestContext, ProjectRecord), RpcMethodError> {
+    let ctx = authenticate(state, headers, peer, &[]).await?;
+    ctx.require_for(permission, Some(project_id))?;
+    let project_id = parse_project_id(project_id)?;
+    let project = load_project(state, &ctx, &project_id, permission).await?;
+    Ok((ctx, project))
+}
+
+/// Downloads the bundle written by a finished `project.export` job. The
+/// download is rate limited like the `job.status` call it amounts to.
+async fn get_job_artifact(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    UrlPath(job_id): UrlPath<i64>,
+) -> Response {
+    artifact(&state, &headers, peer, job_id)
+        .await
+        .unwrap_or_else(error_response)
+}
+
+async fn artifact(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    job_id: i64,
+) -> Result<Response, RpcMethodError> {
+    let ctx = authenticate(state, headers, peer, &[]).await?;
+    ctx.require(Permission::FsRead)?;
+    state
+        .rate_limits
+        .check("job.status", &ctx, &state.metrics)
+        .await?;
+    let job = jobs::load(&state.pool, &ctx, job_id).await?;
+    if job["kind"] != JobKind::ProjectExport.as_str() || job["status"] != "succeeded" {
+        return Err(RpcMethodError::new(
+            ErrorCode::JobState,
+            "job is not in a state that allows this",
+            Some(json!({ "job_id": job_id, "status": job["status"] })),
+        ));
+    }
+    let data = state
+        .sandbox
+        .read(transfer::artifact_path(job_id))
+        .map_err(|err| {
+            RpcMethodError::from_sandbox(ErrorCode::FsRead, "failed to read file", err)
+        })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"project-export-{job_id}.json\""),
+            ),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+/// An uploaded file: the path it goes to, its content and SHA-256.
+struct Upload {
+    path: String,
+    data: Vec<u8>,
+    sha256: Vec<u8>,
+}
+
+/// Stores uploaded files together, all or none, and audits each as a
+/// `project.file.save` call, drawing from that method's rate limit once per
+/// file. The audit digests cover the target and content hash instead of the
+/// body.
+async fn save_uploads(
+    state: &AppState,
+    ctx: &RequestContext,
+    project: &ProjectRecord,
+    uploads: &[Upload],
+    message: Option<&str>,
+) -> Result<Value, RpcMethodError> {
+    let started = Instant::now();
+    let save = async {
+        let mut paths = Vec::with_capacity(uploads.len());
+        for upload in uploads {
+            state
+                .rate_limits
+                .check("project.file.save", ctx, &state.metrics)
+                .await?;
+            paths.push(normalize_project_path(&upload.path)?);
+        }
+        let files: Vec<(&Path, &[u8], &[u8])> = paths
+            .iter()
+            .zip(uploads)
+            .map(|(path, upload)| (path.as_path(), &upload.data[..], &upload.sha256[..]))
+            .collect();
+        store_project_files(state, ctx.user_id, project, &files, message).await
+    };
+    let result = save.await.map(Value::from);
+    for upload in uploads {
+        let digest = audit::params_digest(Some(&json!({
+            "project_id": project.id,
+            "path": upload.path,
+            "sha256": hex::encode(&upload.sha256),
+        })));
+        state
+            .audit
+            .record(AuditEvent::new(
+                ctx,
+                "project.file.save",
+                digest,
+                &result,
+                started.elapsed(),
+            ))
+            .await;
+    }
+    if let Err(err) = &result {
+        let paths: Vec<&str> = uploads.iter().map(|upload| upload.path.as_str()).collect();
+        error!(?paths, message = %err.message, "upload failed");
+    }
+    result
+}
+
+fn payload_too_large(limit: usize) -> Response {
+    let body = json!({
+        "error": {
+            "code": ErrorCode::InvalidParams.code(),
+            "message": "upload exceeds size limit",
+            "data": { "limit": limit },
+        }
+    });
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+}
+
+fn multipart_error(err: axum::extract::multipart::MultipartError) -> Response {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return err.into_response();
+    }
+    error_response(RpcMethodError::new(
+        ErrorCode::InvalidParams,
+        "invalid multipart body",
+        Some(json!({ "detail": err.body_text() })),
+    ))
+}
+
+/// Takes the `run.exec` params object as the JSON body.
+async fn post_run(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    Json(params): Json<Value>,
+) -> Response {
+    call(&state, &headers, peer, "run.exec", Some(params)).await
+}
+
+/// Streams a sandbox engine method (`run.exec`, `micro.execute`, ...) as
+/// server-sent events, one per output event, terminated by `data: [DONE]`;
+/// failures while streaming arrive as an `error` event. The call is audited
+/// once the engine has started it.
+async fn post_engine_stream(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    UrlPath(requested): UrlPath<String>,
+    Json(params): Json<Value>,
+) -> Response {
+    let ctx = match authenticate(&state, &headers, peer, &[Flag::Streaming]).await {
+        Ok(ctx) => ctx,
+        Err(err) => return error_response(err),
+    };
+    let started = Instant::now();
+    let digest = audit::params_digest(Some(&params));
+    let (method, opened) = match state.versions.resolve(&requested, &ctx, &state.metrics) {
+        Ok(method) => {
+            let admitted = match state.rate_limits.check(&method, &ctx, &state.metrics).await {
+                Ok(()) => state.admission.admit(&method, &ctx, &state.metrics).await,
+                Err(err) => Err(err),
+            };
+            let opened = match admitted {
+                Ok(permit) => engine::open_stream(&state, &ctx, &method, Some(params))
+                    .await
+                    .map(|output| (permit, output)),
+                Err(err) => Err(err),
+            };
+            (method, opened)
+        }
+        Err(err) => (requested, Err(err)),
+    };
+    let outcome = match &opened {
+        Ok(_) => Ok(Value::Null),
+        Err(err) => Err(RpcMethodError {
+            code: err.code,
+            message: err.message.clone(),
+            data: None,
+        }),
+    };
+    state
+        .audit
+        .record(AuditEvent::new(
+            &ctx,
+            &method,
+            digest,
+            &outcome,
+            started.elapsed(),
+        ))
+        .await;
+    let (permit, output) = match opened {
+        Ok(opened) => opened,
+        Err(err) => return error_response(stream_failed(&state, &ctx, &method, err, started)),
+    };
+    let events = output
+        .map(move |event| {
+            Ok::<_, Infallible>(match event {
+                Ok(value) => Event::default().data(value.to_string()),
+                Err(err) => error_event(&stream_failed(&state, &ctx, &method, err, started)),
+            })
+        })
+        .chain(stream::once(async move {
+            // The slot is held until the stream ends or is dropped.
+            drop(permit);
+            Ok(Event::default().data("[DONE]"))
+        }));
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Streams `llm.chat` as server-sent events carrying OpenAI
+/// `chat.completion.chunk` objects, terminated by `data: [DONE]`. Failures
+/// after the stream started arrive as an `error` event.
+async fn post_chat_stream(
+    State(state): State<AppState>,
+    Peer(peer): Peer,
+    headers: HeaderMap,
+    Json(params): Json<Value>,
+) -> Response {
+    let ctx = match authenticate(&state, &headers, peer, &[Flag::Streaming]).await {
+        Ok(ctx) => ctx,
+        Err(err) => return error_response(err),
+    };
+    let mut record = ChatStreamRecord {
+        digest: audit::params_digest(Some(&params)),
+        started: Instant::now(),
+        usage: None,
+        prompt_chars: 0,
+        streamed_chars: 0,
+        error: None,
+        entry: None,
+        permit: None,
+        state,
+        ctx,
+    };
+    let chunks = match open_chat_stream(&mut record, params).await {
+        Ok(chunks) => chunks,
+        Err(err) => {
+            let err = stream_failed(&record.state, &record.ctx, "llm.chat", err, record.started);
+            let response = error_response(RpcMethodError {
+                code: err.code,
+                message: err.message.clone(),
+                data: err.data.clone(),
+            });
+            record.error = Some(err);
+            return response;
+        }
+    };
+    let events = stream::unfold(
+        (chunks, Some(record)),
+        |(mut chunks, mut record)| async move {
+            let current = record.as_mut()?;
+            let event = match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    if chunk.get("usage").is_some_and(|usage| !usage.is_null()) {
+                        current.usage = Some(chunk.clone());
+                    }
+                    current.streamed_chars += delta_chars(&chunk);
+                    return Some((
+                        Ok(Event::default().data(chunk.to_string())),
+                        (chunks, record),
+                    ));
+                }
+                Some(Err(err)) => {
+                    let err = stream_failed(
+                        &current.state,
+                        &current.ctx,
+                        "llm.chat",
+                        err,
+                        current.started,
+                    );
+                    let event = error_event(&err);
+                    current.error = Some(err);
+                    event
+                }
+                None => Event::default().data("[DONE]"),
+            };
+            // Dropping the record bills and audits the call.
+            Some((Ok::<_, Infallible>(event), (chunks, None)))
+        },
+    );
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn open_chat_stream(
+    record: &mut ChatStreamRecord,
+    params: Value,
+) -> Result<ChatStream, RpcMethodError> {
+    let (state, ctx) = (&record.state, &record.ctx);
+    ctx.require(Permission::LlmUse)?;
+    ctx.ensure_tokens()?;
+    validate_params("llm.chat", Some(&params))?;
+    let params: LlmChatParams = parse_params(Some(params))?;
+    state
+        .rate_limits
+        .check("llm.chat", ctx, &state.metrics)
+        .await?;
+    record.permit = Some(
+        state
+            .admission
+            .admit("llm.chat", ctx, &state.metrics)
+            .await?,
+    );
+    let provider = state.llm.provider(&params.model);
+    record.entry = Some(UsageEntry::start("llm.chat", &params.model, provider));
+    record.prompt_chars = params
+        .messages
+        .iter()
+        .map(|message| message.content.chars().count())
+        .sum();
+    state.llm.chat_stream(ctx, params).await
+}
+
+/// Rough characters per token for billing streams that end without usage.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Characters of generated text in an OpenAI `chat.completion.chunk`.
+fn delta_chars(chunk: &Value) -> usize {
+    chunk["choices"]
+        .as_array()
+        .map(|choices| {
+            choices
+                .iter()
+                .filter_map(|choice| choice["delta"]["content"].as_str())
+                .map(|content| content.chars().count())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// A `usage` block estimated from the text sent and received, for streams
+/// that end before the provider reports one.
+fn estimated_usage(prompt_chars: usize, completion_chars: usize) -> Value {
+    let tokens = |chars: usize| chars.div_ceil(CHARS_PER_TOKEN) as i64;
+    let (prompt, completion) = (tokens(prompt_chars), tokens(completion_chars));
+    json!({
+        "usage": {
+            "prompt_tokens": prompt,
+            "completion_tokens": completion,
+            "total_tokens": prompt + completion,
+            "estimated": true,
+        },
+    })
+}
+
+/// Bills, audits and records usage of a streamed chat once it ends,
+/// including when the client disconnects early. Usage is only known from
+/// the final chunk, so a stream abandoned before it is billed by an
+/// estimate from the prompt and the text streamed so far.
+struct ChatStreamRecord {
+    state: AppState,
+    ctx: RequestContext,
+    digest: Option<String>,
+    started: Instant,
+    usage: Option<Value>,
+    prompt_chars: usize,
+    /// Generated characters passed on to the client.
+    streamed_chars: usize,
+    error: Option<RpcMethodError>,
+    entry: Option<UsageEntry>,
+    /// Admission slot, released with the record.
+    permit: Option<Permit>,
+}
+
+impl Drop for ChatStreamRecord {
+    fn drop(&mut self) {
+        let mut usage = self.usage.take();
+        let mut settled = true;
+        if let Some(entry) = self.entry.take() {
+            if usage.is_none() && self.error.is_none() {
+                usage = Some(estimated_usage(self.prompt_chars, self.streamed_chars));
+            }
+            settled = entry.provider().settles_usage;
+            let outcome = match (&self.error, &usage) {
+                (Some(err), _) => Outcome::Failed(err),
+                (None, Some(usage)) => Outcome::Served(usage),
+                (None, None) => Outcome::Served(&Value::Null),
+            };
+            entry.finish(&self.state.pool, &self.ctx, outcome);
+        }
+        let outcome = match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(Value::Null),
+        };
+        let event = AuditEvent::new(
+            &self.ctx,
+            "llm.chat",
+            self.digest.take(),
+            &outcome,
+            self.started.elapsed(),
+        );
+        let state = self.state.clone();
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            if let Some(usage) = usage {
+                state
+                    .billing
+                    .charge(&ctx, "llm.chat", Charge::llm(&usage, settled))
+                    .await;
+            }
+            state.audit.record(event).await;
+        });
+    }
+}
+
+async fn call(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    method: &str,
+    params: Option<Value>,
+) -> Response {
+    let ctx = match authenticate(state, headers, peer, &[]).await {
+        Ok(ctx) => ctx,
+        Err(err) => return error_response(err),
+    };
+    match process_audited_request(state, &ctx, versioning::versioned(method), params).await {
+        Ok(result) => Json(result).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Does for a streamed call what `process_audited_request` does for every
+/// other: logs the failure, keeps it for `errors.recent` and tags it with the
+/// request id.
+fn stream_failed(
+    state: &AppState,
+    ctx: &RequestContext,
+    method: &str,
+    err: RpcMethodError,
+    started: Instant,
+) -> RpcMethodError {
+    let err = err.with_request_id(ctx.request_id);
+    error!(
+        request_id = %ctx.request_id,
+        method,
+        code = err.code,
+        message = %err.message,
+        "stream failed"
+    );
+    state.errors.record(ctx, method, &err, started.elapsed());
+    err
+}
+
+pub(crate) fn error_event(err: &RpcMethodError) -> Event {
+    Event::default()
+        .event("error")
+        .data(json!({ "code": err.code, "message": err.message, "data": err.data }).to_string())
+}
+
+/// Authenticates like `/rpc`, then checks that the REST facade and `flags`
+/// are on for the caller.
+async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    flags: &[Flag],
+) -> Result<RequestContext, RpcMethodError> {
+    let ctx = authenticate_request(state, headers, peer)
+        .await
+        .map_err(|err| authentication_failed(telemetry::request_id(headers), err))?;
+    for flag in [Flag::Rest].iter().chain(flags) {
+        state.flags.require(*flag, &ctx).await?;
+    }
+    Ok(ctx)
+}
+
+pub(crate) fn error_response(err: RpcMethodError) -> Response {
+    let status = http_status(err.code);
+    let body = json!({
+        "error": {
+            "code": err.code,
+            "message": err.message,
+            "data": err.data,
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+fn http_status(code: i64) -> StatusCode {
+    match ErrorCode::from_code(code) {
+        Some(ErrorCode::Unauthorized) => StatusCode::UNAUTHORIZED,
+        Some(ErrorCode::Forbidden | ErrorCode::EmailNotVerified | ErrorCode::FeatureDisabled) => {
+            StatusCode::FORBIDDEN
+        }
+        Some(ErrorCode::InsufficientBalance) => StatusCode::PAYMENT_REQUIRED,
+        Some(ErrorCode::RateLimited) => StatusCode::TOO_MANY_REQUESTS,
+        Some(ErrorCode::Overloaded) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(ErrorCode::LlmUpstream) => StatusCode::BAD_GATEWAY,
+        Some(ErrorCode::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+        Some(
+            ErrorCode::MethodNotFound
+            | ErrorCode::AgentTaskNotFound
+            | ErrorCode::ProjectConflict
+            | ErrorCode::ProjectNotFound
+            | ErrorCode::JobNotFound,
+        ) => StatusCode::NOT_FOUND,
+        Some(ErrorCode::InvalidRequest | ErrorCode::InvalidParams) => StatusCode::BAD_REQUEST,
+        Some(ErrorCode::Internal) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_headers_parse_to_inclusive_bounds() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(Some((990, 999))));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=5-1", 1000), Err(()));
+        assert!(etag_matches("W/\"abc\", \"def\"", "\"abc\""));
+        assert!(!etag_matches("\"abc\"", "\"def\""));
+    }
+
+    #[test]
+    fn rpc_codes_map_to_http_status() {
+        assert_eq!(http_status(-32090), StatusCode::UNAUTHORIZED);
+        assert_eq!(http_status(-32055), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(-32071), StatusCode::FORBIDDEN);
+        assert_eq!(http_status(-32602), StatusCode::BAD_REQUEST);
+        assert_eq!(http_status(-32010), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(http_status(-32048), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn abandoned_streams_are_billed_by_estimate() {
+        let chunk = json!({
+            "choices": [{ "index": 0, "delta": { "content": "héllo" } }],
+        });
+        assert_eq!(delta_chars(&chunk), 5);
+        assert_eq!(delta_chars(&json!({ "choices": [{ "delta": {} }] })), 0);
+        let usage = estimated_usage(10, 5);
+        assert_eq!(usage["usage"]["prompt_tokens"], 3);
+        assert_eq!(usage["usage"]["completion_tokens"], 2);
+        assert_eq!(usage["usage"]["total_tokens"], 5);
+    }
+}
@@ -0,0 +1,182 @@
+//! Data retention. The scheduler's `data_retention` job deletes rows that
+//! have outlived the window of their table, so a long-lived installation
+//! doesn't grow without bound:
+//!
+//! - `project_activity`: `PROJECT_ACTIVITY_RETENTION_DAYS` (default 180)
+//! - `llm_usage`: `LLM_USAGE_RETENTION_DAYS` (default 90); the daily rollup
+//!   in `llm_usage_daily` keeps the totals
+//! - `llm_usage_daily`: `LLM_USAGE_DAILY_RETENTION_DAYS` (default 0)
+//! - `tokens_used`: `TOKENS_USED_RETENTION_DAYS` (default 90)
+//! - `api_key_usage`: `API_KEY_USAGE_RETENTION_DAYS` (default 400)
+//!
+//! Zero keeps the rows forever. Finished agent tasks live in the memory of
+//! each instance; `agent_history_retention` drops them after
+//! `AGENT_HISTORY_RETENTION_DAYS` (default 7). Every deleted row is counted
+//! in `api_retention_pruned_rows_total`, as are those of the audit log,
+//! webhook event and job queue retention jobs.
+
+use chrono::{Duration as ChronoDuration, Utc};
+use sandbox::AgentDispatcher;
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::metrics::AppMetrics;
+
+/// Rows per `DELETE`, so a first run over a large backlog doesn't hold one
+/// long transaction.
+const DELETE_BATCH: i64 = 10_000;
+
+/// `(table, age column, setting, default days)`.
+const TABLES: &[(&str, &str, &str, i32)] = &[
+    (
+        "project_activity",
+        "created_at",
+        "PROJECT_ACTIVITY_RETENTION_DAYS",
+        180,
+    ),
+    ("llm_usage", "created_at", "LLM_USAGE_RETENTION_DAYS", 90),
+    (
+        "llm_usage_daily",
+        "day",
+        "LLM_USAGE_DAILY_RETENTION_DAYS",
+        0,
+    ),
+    (
+        "tokens_used",
+        "created_at",
+        "TOKENS_USED_RETENTION_DAYS",
+        90,
+    ),
+    ("api_key_usage", "day", "API_KEY_USAGE_RETENTION_DAYS", 400),
+];
+
+/// `usage_aggregation` rebuilds the rollup from the day before its previous
+/// run; raw usage has to outlive that or the rebuilt days come out short.
+const MIN_LLM_USAGE_DAYS: i32 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetentionConfig {
+    /// Per entry of `TABLES`; zero keeps the rows forever.
+    days: [i32; TABLES.len()],
+    /// Zero keeps finished agent tasks until `AGENT_HISTORY_CAPACITY` pushes
+    /// them out.
+    agent_history_days: i32,
+}
+
+impl RetentionConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let mut days = [0; TABLES.len()];
+        for (slot, &(table, _, key, default)) in days.iter_mut().zip(TABLES) {
+            *slot = config.get(key, default).max(0);
+            if table == "llm_usage" && (1..MIN_LLM_USAGE_DAYS).contains(slot) {
+                config.invalid(key, format!("must be 0 or at least {MIN_LLM_USAGE_DAYS}"));
+            }
+        }
+        Self {
+            days,
+            agent_history_days: config.get("AGENT_HISTORY_RETENTION_DAYS", 7).max(0),
+        }
+    }
+}
+
+/// Runs the retention of every table in `TABLES`.
+pub(crate) async fn prune(
+    pool: &PgPool,
+    metrics: &AppMetrics,
+    config: RetentionConfig,
+) -> Result<Value, sqlx::Error> {
+    let mut deleted = Map::new();
+    for (&(table, column, _, _), &days) in TABLES.iter().zip(&config.days) {
+        let rows = purge(pool, table, column, days).await?;
+        metrics.retention_pruned(table, rows);
+        deleted.insert(table.to_string(), json!(rows));
+    }
+    Ok(json!({ "deleted": deleted }))
+}
+
+/// Drops finished agent tasks of this instance past their retention.
+pub(crate) fn prune_agent_history(
+    agents: &AgentDispatcher,
+    metrics: &AppMetrics,
+    config: RetentionConfig,
+) -> Value {
+    if config.agent_history_days == 0 {
+        return json!({ "deleted": 0 });
+    }
+    let cutoff = Utc::now() - ChronoDuration::days(config.agent_history_days.into());
+    let deleted = agents.prune_history(cutoff);
+    metrics.retention_pruned("agent_history", deleted as u64);
+    json!({ "deleted": deleted })
+}
+
+/// Deletes the rows of `table` whose `column` lies more than `days` back, in
+/// batches of `DELETE_BATCH`. Zero days deletes nothing.
+pub(crate) async fn purge(
+    pool: &PgPool,
+    table: &str,
+    column: &str,
+    days: i32,
+) -> Result<u64, sqlx::Error> {
+    if days == 0 {
+        return Ok(0);
+    }
+    let statement = format!(
+        "DELETE FROM {table} WHERE ctid IN ( \
+            SELECT ctid FROM {table} \
+            WHERE {column} < NOW() - make_interval(days => $1) LIMIT $2 \
+         )"
+    );
+    let mut deleted = 0;
+    loop {
+        let batch = sqlx::query(&statement)
+            .bind(days)
+            .bind(DELETE_BATCH)
+            .execute(pool)
+            .await?
+            .rows_affected();
+        deleted += batch;
+        if batch < DELETE_BATCH as u64 {
+            return Ok(deleted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn retained_tables_and_columns_exist() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../database/migrations");
+        let mut schema = String::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            schema.push_str(&std::fs::read_to_string(entry.unwrap().path()).unwrap());
+        }
+        let schema = schema.to_lowercase();
+        for &(table, column, _, _) in TABLES {
+            let start = schema
+                .find(&format!("create table if not exists {table} ("))
+                .unwrap_or_else(|| panic!("no migration creates {table}"));
+            let body = &schema[start..];
+            let body = &body[..body.find(");").unwrap()];
+            assert!(
+                body.contains(&format!("\n    {column} ")),
+                "{table}.{column}"
+            );
+        }
+    }
+
+    #[test]
+    fn pruned_rows_are_counted_by_table() {
+        let metrics = AppMetrics::default();
+        metrics.retention_pruned("llm_usage", 3);
+        metrics.retention_pruned("llm_usage", 2);
+        metrics.retention_pruned("agent_history", 0);
+        let text = metrics.render(false);
+        assert!(text.contains("api_retention_pruned_rows_total{table=\"llm_usage\"} 5"));
+        assert!(text.contains("api_retention_pruned_rows_total{table=\"agent_history\"} 0"));
+    }
+}
@@ -0,0 +1,171 @@
+//! JWT revocation. `/auth/logout` (auth service) and `admin.tokens.revoke`
+//! put a token's `jti` into `revoked_tokens`; revoking a user instead sets
+//! `users.tokens_revoked_at`, which rejects every token issued before then.
+//! The cutoff is read with the user row, whose cached copy (`auth_cache`)
+//! is dropped as soon as the cutoff moves. `jti` lookups go
+//! through a read-through cache, so a token costs one query per TTL;
+//! revocations made through this instance update the cache at once, the TTL
+//! bounds how long a logout elsewhere can go unnoticed. API keys are not
+//! affected; they are deleted through the auth service.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use moka::future::Cache;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use crate::auth_cache::AuthCache;
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::{RequestContext, RpcMethodError};
+
+const MAX_JTI: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RevocationConfig {
+    capacity: u64,
+    ttl: Duration,
+    /// Lifetime of tokens issued by the auth service; a revoked `jti` is kept
+    /// this long, after which the token has expired anyway.
+    token_lifetime: ChronoDuration,
+}
+
+impl RevocationConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            capacity: config.get("AUTH_REVOCATION_CACHE_CAPACITY", 10_000),
+            ttl: config.secs("AUTH_REVOCATION_CACHE_TTL_SECS", 30),
+            token_lifetime: ChronoDuration::minutes(config.get("AUTH_JWT_EXP_MINUTES", 60)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Revocations {
+    pool: PgPool,
+    cache: Cache<String, bool>,
+    token_lifetime: ChronoDuration,
+}
+
+impl Revocations {
+    pub(crate) fn new(pool: PgPool, config: RevocationConfig) -> Self {
+        Self {
+            pool,
+            cache: Cache::builder()
+                .max_capacity(config.capacity)
+                .time_to_live(config.ttl)
+                .build(),
+            token_lifetime: config.token_lifetime,
+        }
+    }
+
+    pub(crate) async fn is_revoked(&self, jti: &str) -> Result<bool, RpcMethodError> {
+        if let Some(revoked) = self.cache.get(jti).await {
+            return Ok(revoked);
+        }
+        let revoked: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1)")
+                .bind(jti)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(|err| {
+                    RpcMethodError::internal(&format!("failed to check token revocation: {err}"))
+                })?;
+        self.cache.insert(jti.to_string(), revoked).await;
+        Ok(revoked)
+    }
+}
+
+/// Whether a token issued at `issued_at` (seconds) falls under the user's
+/// revocation cutoff. `iat` has whole seconds, so tokens from the cutoff's
+/// own second stay valid, such as a login right after the revocation.
+pub(crate) fn issued_before_cutoff(issued_at: usize, cutoff: Option<DateTime<Utc>>) -> bool {
+    cutoff.is_some_and(|cutoff| (issued_at as i64) < cutoff.timestamp())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct TokenRevokeParams {
+    /// `jti` claim of the one token to revoke.
+    #[serde(default)]
+    jti: Option<String>,
+    /// Revokes every token issued to this user so far.
+    #[serde(default)]
+    user_id: Option<i32>,
+}
+
+fn db_error(err: sqlx::Error) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to revoke tokens: {err}"))
+}
+
+/// `admin.tokens.revoke`: exactly one of `jti` or `user_id`.
+pub(crate) async fn revoke(
+    revocations: &Revocations,
+    auth_cache: &AuthCache,
+    ctx: &RequestContext,
+    params: TokenRevokeParams,
+) -> Result<Value, RpcMethodError> {
+    match (params.jti, params.user_id) {
+        (Some(jti), None) => {
+            if jti.is_empty() || jti.len() > MAX_JTI {
+                return Err(RpcMethodError::new(
+                    ErrorCode::InvalidParams,
+                    "invalid params",
+                    Some(json!({ "field": "jti", "max_length": MAX_JTI })),
+                ));
+            }
+            sqlx::query(
+                "INSERT INTO revoked_tokens (jti, expires_at, revoked_by) VALUES ($1, $2, $3) \
+                 ON CONFLICT (jti) DO NOTHING",
+            )
+            .bind(&jti)
+            .bind(Utc::now() + revocations.token_lifetime)
+            .bind(ctx.user_id)
+            .execute(&revocations.pool)
+            .await
+            .map_err(db_error)?;
+            revocations.cache.insert(jti.clone(), true).await;
+            Ok(json!({ "jti": jti }))
+        }
+        (None, Some(user_id)) => {
+            let revoked_at: DateTime<Utc> = sqlx::query_scalar(
+                "UPDATE users SET tokens_revoked_at = NOW(), updated_at = NOW() \
+                 WHERE id = $1 AND tenant_id = $2 RETURNING tokens_revoked_at",
+            )
+            .bind(user_id)
+            .bind(ctx.tenant_id)
+            .fetch_optional(&revocations.pool)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| RpcMethodError::new(ErrorCode::UserNotFound, "user not found", None))?;
+            auth_cache.invalidate_user(user_id);
+            Ok(json!({ "user_id": user_id, "tokens_revoked_at": revoked_at.to_rfc3339() }))
+        }
+        _ => Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid params",
+            Some(json!({ "detail": "pass exactly one of jti or user_id" })),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn cutoff_covers_tokens_issued_before_it() {
+        let cutoff = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+        let at = |secs: i64| (cutoff.timestamp() + secs) as usize;
+        assert!(!issued_before_cutoff(at(-60), None));
+        assert!(issued_before_cutoff(at(-60), Some(cutoff)));
+        assert!(!issued_before_cutoff(at(0), Some(cutoff)));
+        let within_second = cutoff + chrono::Duration::milliseconds(500);
+        assert!(!issued_before_cutoff(at(0), Some(within_second)));
+        assert!(!issued_before_cutoff(at(1), Some(cutoff)));
+    }
+}
@@ -0,0 +1,62 @@
+//! Applies rotated secrets manager values to the settings that can change
+//! while the API runs. New database connections log in with a rotated
+//! `DATABASE_URL`; after the HS256 secret rotates, tokens signed with the
+//! previous one stay valid; a rotated `LLM_SERVER_ADMIN_TOKEN` is sent from
+//! the next admin call on. Every other setting keeps the value it was
+//! started with.
+
+use std::str::FromStr;
+
+use secrets::Secrets;
+use sqlx::postgres::PgConnectOptions;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::AppState;
+
+/// Follows the rotations of whichever of these settings are references.
+pub(crate) fn spawn(secrets: &Secrets, config: &Config, state: AppState) {
+    let settings = ["DATABASE_URL", "LLM_SERVER_ADMIN_TOKEN"]
+        .into_iter()
+        .chain(state.auth.secret_setting);
+    let watched: Vec<(&'static str, String)> = settings
+        .filter_map(|key| Some((key, config.reference(key)?.to_string())))
+        .collect();
+    if watched.is_empty() {
+        return;
+    }
+    let mut rotations = secrets.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let rotation = match rotations.recv().await {
+                Ok(rotation) => rotation,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(missed, "missed secret rotations");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            for (key, reference) in &watched {
+                if rotation.concerns(reference) {
+                    apply(&state, key, &rotation.value);
+                }
+            }
+        }
+    });
+}
+
+fn apply(state: &AppState, key: &str, value: &str) {
+    match key {
+        "DATABASE_URL" => match PgConnectOptions::from_str(value) {
+            Ok(options) => state.pool.set_connect_options(options),
+            Err(err) => {
+                warn!(error = %err, "ignoring a rotated DATABASE_URL that does not parse");
+                return;
+            }
+        },
+        "LLM_SERVER_ADMIN_TOKEN" => state.llm.set_admin_token(value.to_string()),
+        _ => state.auth.rotate_secret(value),
+    }
+    info!(setting = key, "applied a rotated secret");
+}
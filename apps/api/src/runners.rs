@@ -0,0 +1,561 @@
+//! Remote runners (the `runner` crate). Runners connect to `GET /runners/ws`
+//! with `RUNNER_TOKEN` and take `run.exec`, `micro.*` and agent calls off
+//! the gateway: a starting call goes to the least loaded runner whose
+//! capabilities match, and follow-up calls on a micro VM or agent task go
+//! to the runner that started it. Without a matching runner calls run
+//! locally unless `RUNNER_LOCAL_FALLBACK` is off. VMs and tasks of a runner
+//! that disconnects are gone; calls on them answer as for an unknown id.
+//! A call its runner leaves unanswered for `RUNNER_CALL_TIMEOUT_SECS`
+//! (default 900) fails like one the runner rejected.
+//!
+//! Remote agent tasks are not part of `agent.list`/`agent.history` and do
+//! not raise agent notifications or webhooks.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use runner::protocol::{
+    Call, Capabilities, Capability, ErrorKind, FromRunner, Outcome, RemoteError, ToRunner,
+    PROTOCOL_VERSION,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::rest::error_response;
+use crate::{AppState, RpcMethodError};
+
+/// Heartbeats a runner may miss before it is dropped.
+const MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RunnerConfig {
+    /// Without a token the endpoint is disabled and everything runs locally.
+    token: Option<String>,
+    heartbeat: Duration,
+    local_fallback: bool,
+    /// How long a call may go unanswered, even by a runner that keeps
+    /// sending heartbeats.
+    call_timeout: Duration,
+}
+
+impl RunnerConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            token: config.secret("RUNNER_TOKEN"),
+            heartbeat: config
+                .secs("RUNNER_HEARTBEAT_SECS", 10)
+                .max(Duration::from_secs(1)),
+            local_fallback: config.get("RUNNER_LOCAL_FALLBACK", true),
+            call_timeout: config
+                .secs("RUNNER_CALL_TIMEOUT_SECS", 900)
+                .max(Duration::from_secs(1)),
+        }
+    }
+}
+
+struct Connected {
+    name: String,
+    capabilities: Capabilities,
+    outbox: mpsc::UnboundedSender<ToRunner>,
+    /// Calls sent and not yet answered.
+    in_flight: AtomicUsize,
+    /// Calls executing according to the last heartbeat.
+    active: AtomicUsize,
+    connected_at: DateTime<Utc>,
+    last_seen: Mutex<Instant>,
+}
+
+struct Pending {
+    runner: Uuid,
+    reply: oneshot::Sender<Outcome>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Runners {
+    config: Arc<RunnerConfig>,
+    connected: Arc<RwLock<HashMap<Uuid, Arc<Connected>>>>,
+    pending: Arc<Mutex<HashMap<Uuid, Pending>>>,
+    /// The runner that owns a micro VM or agent task, by its id.
+    pins: Arc<Mutex<HashMap<Uuid, Uuid>>>,
+}
+
+/// Takes the call off the runner's in-flight count however the wait ends.
+struct InFlight(Arc<Connected>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Runners {
+    pub(crate) fn new(config: RunnerConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            connected: Arc::default(),
+            pending: Arc::default(),
+            pins: Arc::default(),
+        }
+    }
+
+    /// Runs a starting call on a runner that can take it; `Ok(None)` means
+    /// the caller should run it locally. The id of the runner is returned
+    /// with the result so follow-up calls can be pinned to it.
+    pub(crate) async fn offload(
+        &self,
+        call: Call,
+    ) -> Result<Option<(Uuid, Value)>, RpcMethodError> {
+        match self.pick(&call) {
+            Some(runner) => Ok(Some((runner, self.call(runner, call).await?))),
+            None if self.config.local_fallback || self.config.token.is_none() => Ok(None),
+            None => {
+                let (code, message) = failure(&call);
+                Err(RpcMethodError::new(
+                    code,
+                    message,
+                    Some(json!({ "detail": "no connected runner can take the call" })),
+                ))
+            }
+        }
+    }
+
+    /// The least loaded runner that accepts `call` and has a free slot.
+    fn pick(&self, call: &Call) -> Option<Uuid> {
+        self.connected
+            .read()
+            .iter()
+            .filter(|(_, runner)| runner.capabilities.accepts(call))
+            .map(|(id, runner)| (*id, runner.in_flight.load(Ordering::Relaxed), runner))
+            .filter(|(_, in_flight, runner)| *in_flight < runner.capabilities.slots)
+            .min_by_key(|(_, in_flight, _)| *in_flight)
+            .map(|(id, _, _)| id)
+    }
+
+    /// Sends `call` to `runner` and waits for its result, at most
+    /// `RUNNER_CALL_TIMEOUT_SECS`.
+    pub(crate) async fn call(&self, runner: Uuid, call: Call) -> Result<Value, RpcMethodError> {
+        let (code, message) = failure(&call);
+        let call_method = call.method();
+        let connected = self.connected.read().get(&runner).cloned();
+        let Some(connected) = connected else {
+            return Err(disconnected());
+        };
+        connected.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = InFlight(connected.clone());
+        let id = Uuid::new_v4();
+        let (reply, outcome) = oneshot::channel();
+        self.pending.lock().insert(id, Pending { runner, reply });
+        if connected.outbox.send(ToRunner::Call { id, call }).is_err() {
+            self.pending.lock().remove(&id);
+            return Err(disconnected());
+        }
+        match tokio::time::timeout(self.config.call_timeout, outcome).await {
+            Ok(Ok(Outcome::Ok(value))) => Ok(value),
+            Ok(Ok(Outcome::Error(err))) => Err(remote_error(code, message, err)),
+            Ok(Err(_)) => Err(disconnected()),
+            Err(_) => {
+                // A late answer finds nothing waiting and is dropped.
+                self.pending.lock().remove(&id);
+                warn!(%runner, method = call_method, "runner did not answer in time");
+                Err(RpcMethodError::new(
+                    code,
+                    message,
+                    Some(json!({ "detail": "runner did not answer in time" })),
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn pin(&self, id: Uuid, runner: Uuid) {
+        self.pins.lock().insert(id, runner);
+    }
+
+    pub(crate) fn unpin(&self, id: &Uuid) {
+        self.pins.lock().remove(id);
+    }
+
+    /// The runner a micro VM or agent task was started on, while it is
+    /// connected.
+    pub(crate) fn pinned(&self, id: &Uuid) -> Option<Uuid> {
+        self.pins.lock().get(id).copied()
+    }
+
+    /// Stops the VMs every micro runner keeps below `scope`. Runners that
+    /// fail are logged and skipped so the directory can still go.
+    pub(crate) async fn cleanup(&self, scope: &Path) -> usize {
+        let runners: Vec<Uuid> = self
+            .connected
+            .read()
+            .iter()
+            .filter(|(_, runner)| runner.capabilities.engines.contains(&Capability::Micro))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut stopped = 0;
+        for runner in runners {
+            let call = Call::MicroCleanup {
+                scope: scope.to_path_buf(),
+            };
+            match self.call(runner, call).await {
+                Ok(result) => stopped += result["stopped"].as_u64().unwrap_or(0) as usize,
+                Err(err) => warn!(%runner, error = %err.message, "runner cleanup failed"),
+            }
+        }
+        stopped
+    }
+
+    /// `admin.runners.list`.
+    pub(crate) fn list(&self) -> Value {
+        let mut runners: Vec<Value> = self
+            .connected
+            .read()
+            .iter()
+            .map(|(id, runner)| {
+                json!({
+                    "id": id.to_string(),
+                    "name": runner.name,
+                    "engines": runner.capabilities.engines,
+                    "programs": runner.capabilities.programs,
+                    "images": runner.capabilities.images,
+                    "slots": runner.capabilities.slots,
+                    "in_flight": runner.in_flight.load(Ordering::Relaxed),
+                    "active": runner.active.load(Ordering::Relaxed),
+                    "connected_at": runner.connected_at.to_rfc3339(),
+                    "last_seen_ms": runner.last_seen.lock().elapsed().as_millis() as u64,
+                })
+            })
+            .collect();
+        runners.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        json!({
+            "runners": runners,
+            "local_fallback": self.config.local_fallback,
+        })
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(expected) = self.config.token.as_deref() else {
+            return false;
+        };
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Comparing digests keeps the comparison time independent of how
+        // much of the token matches.
+        Sha256::digest(presented.as_bytes()) == Sha256::digest(expected.as_bytes())
+    }
+
+    async fn serve(self, mut socket: WebSocket) {
+        let silence = self.config.heartbeat * MISSED_HEARTBEATS;
+        let registration = match tokio::time::timeout(silence, socket.recv()).await {
+            Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<FromRunner>(&text),
+            _ => return,
+        };
+        let (name, capabilities) = match registration {
+            Ok(FromRunner::Register {
+                version,
+                name,
+                capabilities,
+            }) if version == PROTOCOL_VERSION => (name, capabilities),
+            Ok(FromRunner::Register { version, .. }) => {
+                let reason = format!(
+                    "protocol version {version} is not supported, expected {PROTOCOL_VERSION}"
+                );
+                let _ = send(&mut socket, &ToRunner::Rejected { reason }).await;
+                return;
+            }
+            _ => {
+                let reason = "expected a register message".to_string();
+                let _ = send(&mut socket, &ToRunner::Rejected { reason }).await;
+                return;
+            }
+        };
+
+        let runner_id = Uuid::new_v4();
+        let registered = ToRunner::Registered {
+            runner_id,
+            heartbeat_secs: self.config.heartbeat.as_secs(),
+        };
+        if send(&mut socket, &registered).await.is_err() {
+            return;
+        }
+        let (outbox, mut calls) = mpsc::unbounded_channel();
+        let connected = Arc::new(Connected {
+            name,
+            capabilities,
+            outbox,
+            in_flight: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            connected_at: Utc::now(),
+            last_seen: Mutex::new(Instant::now()),
+        });
+        info!(%runner_id, name = %connected.name, engines = ?connected.capabilities.engines, "runner connected");
+        self.connected.write().insert(runner_id, connected.clone());
+
+        let mut watchdog = tokio::time::interval(self.config.heartbeat);
+        loop {
+            tokio::select! {
+                Some(call) = calls.recv() => {
+                    if send(&mut socket, &call).await.is_err() {
+                        break;
+                    }
+                }
+                incoming = socket.recv() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        *connected.last_seen.lock() = Instant::now();
+                        match serde_json::from_str::<FromRunner>(&text) {
+                            Ok(FromRunner::Heartbeat { active }) => {
+                                connected.active.store(active, Ordering::Relaxed);
+                            }
+                            Ok(FromRunner::Result { id, outcome }) => self.complete(runner_id, id, outcome),
+                            Ok(FromRunner::Register { .. }) => {}
+                            Err(err) => warn!(%runner_id, error = %err, "ignoring malformed runner message"),
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                _ = watchdog.tick() => {
+                    if connected.last_seen.lock().elapsed() > silence {
+                        warn!(%runner_id, name = %connected.name, "runner missed its heartbeats");
+                        break;
+                    }
+                }
+            }
+        }
+        self.disconnect(runner_id);
+        info!(%runner_id, name = %connected.name, "runner disconnected");
+    }
+
+    fn complete(&self, runner: Uuid, id: Uuid, outcome: Outcome) {
+        let mut pending = self.pending.lock();
+        // A runner can only answer its own calls.
+        if pending.get(&id).is_some_and(|call| call.runner == runner) {
+            if let Some(call) = pending.remove(&id) {
+                let _ = call.reply.send(outcome);
+            }
+        }
+    }
+
+    /// Forgets `runner`; callers still waiting on it get an error.
+    fn disconnect(&self, runner: Uuid) {
+        self.connected.write().remove(&runner);
+        self.pending.lock().retain(|_, call| call.runner != runner);
+        self.pins.lock().retain(|_, owner| *owner != runner);
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ToRunner) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).map_err(axum::Error::new)?;
+    socket.send(Message::Text(text)).await
+}
+
+/// The error code and message the local handler of `call` reports.
+fn failure(call: &Call) -> (ErrorCode, &'static str) {
+    match call {
+        Call::RunExec { .. } => (ErrorCode::RunExecute, "failed to execute process"),
+        Call::MicroStart { .. } => (ErrorCode::MicroStart, "failed to start micro vm"),
+        Call::MicroExecute { .. } => (ErrorCode::MicroExecute, "failed to execute micro vm code"),
+        Call::MicroStop { .. } | Call::MicroCleanup { .. } => {
+            (ErrorCode::MicroStop, "failed to stop micro vm")
+        }
+        Call::AgentDispatch { .. } => (ErrorCode::AgentDispatch, "failed to dispatch agent"),
+        Call::AgentStatus { .. } => (ErrorCode::AgentTaskNotFound, "agent task not found"),
+        Call::AgentCancel { .. } => (ErrorCode::AgentCancel, "failed to cancel agent"),
+        Call::AgentRespond { .. } => (ErrorCode::AgentResume, "failed to resume agent task"),
+    }
+}
+
+fn remote_error(code: ErrorCode, message: &str, err: RemoteError) -> RpcMethodError {
+    match err.kind {
+        ErrorKind::RateLimited => {
+            RpcMethodError::rate_limited(Duration::from_millis(err.retry_after_ms.unwrap_or(0)))
+        }
+        ErrorKind::NotFound
+            if matches!(code, ErrorCode::AgentResume | ErrorCode::AgentTaskNotFound) =>
+        {
+            RpcMethodError::new(ErrorCode::AgentTaskNotFound, "agent task not found", None)
+        }
+        ErrorKind::Invalid => RpcMethodError::internal(&err.detail),
+        ErrorKind::Failed | ErrorKind::NotFound => {
+            RpcMethodError::new(code, message, Some(json!({ "detail": err.detail })))
+        }
+    }
+}
+
+fn disconnected() -> RpcMethodError {
+    RpcMethodError::internal("runner disconnected before answering")
+}
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/runners/ws", get(socket))
+}
+
+async fn socket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if state.runners.config.token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !state.runners.authorized(&headers) {
+        return error_response(RpcMethodError::unauthorized("invalid runner token"));
+    }
+    let runners = state.runners.clone();
+    upgrade.on_upgrade(move |socket| runners.serve(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use sandbox::run::RunRequest;
+
+    use super::*;
+
+    fn connect(
+        runners: &Runners,
+        capabilities: Capabilities,
+    ) -> (Uuid, mpsc::UnboundedReceiver<ToRunner>) {
+        let (outbox, calls) = mpsc::unbounded_channel();
+        let id = Uuid::new_v4();
+        runners.connected.write().insert(
+            id,
+            Arc::new(Connected {
+                name: id.to_string(),
+                capabilities,
+                outbox,
+                in_flight: AtomicUsize::new(0),
+                active: AtomicUsize::new(0),
+                connected_at: Utc::now(),
+                last_seen: Mutex::new(Instant::now()),
+            }),
+        );
+        (id, calls)
+    }
+
+    fn runners(local_fallback: bool) -> Runners {
+        Runners::new(RunnerConfig {
+            token: Some("secret".to_string()),
+            heartbeat: Duration::from_secs(10),
+            local_fallback,
+            call_timeout: Duration::from_secs(10),
+        })
+    }
+
+    fn shell() -> Call {
+        Call::RunExec {
+            scope: PathBuf::from("tenants/1/users/7"),
+            request: (&RunRequest::new("/bin/sh")).into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_go_to_a_matching_runner_and_pins_die_with_it() {
+        let runners = runners(true);
+        let capabilities = Capabilities {
+            engines: vec![Capability::Run, Capability::Micro],
+            programs: vec!["/bin/sh".to_string()],
+            images: vec!["python".to_string()],
+            slots: 1,
+        };
+        assert!(runners.offload(shell()).await.unwrap().is_none());
+        let (runner, mut calls) = connect(&runners, capabilities);
+        assert_eq!(runners.pick(&shell()), Some(runner));
+
+        let answering = runners.clone();
+        let answer = tokio::spawn(async move {
+            let Some(ToRunner::Call { id, call }) = calls.recv().await else {
+                panic!("expected a call");
+            };
+            assert_eq!(call.method(), "run.exec");
+            answering.complete(runner, id, Outcome::Ok(json!({ "exit_code": 0 })));
+            calls
+        });
+        let (used, result) = runners.offload(shell()).await.unwrap().unwrap();
+        assert_eq!((used, result["exit_code"].as_i64()), (runner, Some(0)));
+        answer.await.unwrap();
+
+        let vm = Uuid::new_v4();
+        runners.pin(vm, runner);
+        assert_eq!(runners.pinned(&vm), Some(runner));
+        runners.disconnect(runner);
+        assert_eq!(runners.pinned(&vm), None);
+        assert!(runners.pick(&shell()).is_none());
+    }
+
+    #[tokio::test]
+    async fn without_fallback_unmatched_calls_fail() {
+        let runners = runners(false);
+        connect(
+            &runners,
+            Capabilities {
+                engines: vec![Capability::Micro],
+                images: vec!["node".to_string()],
+                slots: 2,
+                ..Capabilities::default()
+            },
+        );
+        let err = runners.offload(shell()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RunExecute.code());
+
+        let err = remote_error(
+            ErrorCode::AgentResume,
+            "failed to resume agent task",
+            RemoteError::new(ErrorKind::NotFound, "unknown task"),
+        );
+        assert_eq!(err.code, ErrorCode::AgentTaskNotFound.code());
+    }
+
+    #[tokio::test]
+    async fn unanswered_calls_time_out() {
+        let runners = Runners::new(RunnerConfig {
+            call_timeout: Duration::from_millis(50),
+            ..runners(true).config.as_ref().clone()
+        });
+        let (runner, mut calls) = connect(&runners, Capabilities::default());
+        let err = runners.call(runner, shell()).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RunExecute.code());
+        assert!(runners.pending.lock().is_empty());
+        assert_eq!(
+            runners.connected.read()[&runner]
+                .in_flight
+                .load(Ordering::Relaxed),
+            0
+        );
+
+        // The answer arrives too late and goes nowhere.
+        let Some(ToRunner::Call { id, .. }) = calls.recv().await else {
+            panic!("expected a call");
+        };
+        runners.complete(runner, id, Outcome::Ok(json!({ "exit_code": 0 })));
+    }
+
+    #[test]
+    fn only_the_configured_token_is_accepted() {
+        let runners = runners(true);
+        let mut headers = HeaderMap::new();
+        assert!(!runners.authorized(&headers));
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!runners.authorized(&headers));
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(runners.authorized(&headers));
+    }
+}
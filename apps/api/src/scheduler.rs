@@ -0,0 +1,737 @@
+//! Built-in scheduler for maintenance jobs. Each job has a row in `schedules`
+//! holding its cron expression (see `cron`), so operators can retime, pause
+//! or trigger it over RPC without a deploy. Cluster jobs run only on the
+//! instance holding the scheduler's Postgres advisory lock; when that
+//! instance dies its session ends, the lock is released and another instance
+//! takes over on its next tick. Micro VMs and finished agent tasks live in
+//! the memory of the instance that started them, so their GC and retention
+//! run on every instance.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use sandbox::{AgentDispatcher, SandboxFs, SandboxMicro};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::postgres::PgRow;
+use sqlx::types::Json;
+use sqlx::{PgConnection, PgPool, Row};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::cron::Cron;
+use crate::errors::ErrorCode;
+use crate::gc::{self, GcConfig};
+use crate::jobs::JobKind;
+use crate::metrics::AppMetrics;
+use crate::reconcile::{self, SweepConfig};
+use crate::retention::{self, RetentionConfig};
+use crate::{transfer, RpcMethodError};
+
+const TICK: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Session-level advisory lock held by the leader ("codersch").
+const LEADER_LOCK: i64 = 0x636f_6465_7273_6368;
+const COLUMNS: &str = "name, cron, enabled, run_requested_at, last_started_at, last_finished_at, \
+    last_status, last_duration_ms, last_result, last_error, updated_at";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    /// Runs on the leader only.
+    Cluster,
+    /// Runs on every instance.
+    Instance,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Cluster => "cluster",
+            Scope::Instance => "instance",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Job {
+    MicroVmGc,
+    TrashPurge,
+    AuditRetention,
+    UsageAggregation,
+    EventRetention,
+    QueueRetention,
+    TokenRevocationPrune,
+    SandboxGc,
+    DataRetention,
+    AgentHistoryRetention,
+}
+
+impl Job {
+    const ALL: [Job; 10] = [
+        Job::MicroVmGc,
+        Job::TrashPurge,
+        Job::AuditRetention,
+        Job::UsageAggregation,
+        Job::EventRetention,
+        Job::QueueRetention,
+        Job::TokenRevocationPrune,
+        Job::SandboxGc,
+        Job::DataRetention,
+        Job::AgentHistoryRetention,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Job::MicroVmGc => "micro_vm_gc",
+            Job::TrashPurge => "trash_purge",
+            Job::AuditRetention => "audit_retention",
+            Job::UsageAggregation => "usage_aggregation",
+            Job::EventRetention => "event_retention",
+            Job::QueueRetention => "queue_retention",
+            Job::TokenRevocationPrune => "token_revocation_prune",
+            Job::SandboxGc => "sandbox_gc",
+            Job::DataRetention => "data_retention",
+            Job::AgentHistoryRetention => "agent_history_retention",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|job| job.name() == name)
+    }
+
+    fn default_cron(self) -> &'static str {
+        match self {
+            Job::MicroVmGc => "*/5 * * * *",
+            Job::TrashPurge => "*/5 * * * *",
+            Job::AuditRetention => "17 3 * * *",
+            Job::UsageAggregation => "7 * * * *",
+            Job::EventRetention => "37 * * * *",
+            Job::QueueRetention => "47 3 * * *",
+            Job::TokenRevocationPrune => "27 * * * *",
+            Job::SandboxGc => "53 4 * * *",
+            Job::DataRetention => "23 4 * * *",
+            Job::AgentHistoryRetention => "33 * * * *",
+        }
+    }
+
+    fn scope(self) -> Scope {
+        match self {
+            Job::MicroVmGc | Job::AgentHistoryRetention => Scope::Instance,
+            _ => Scope::Cluster,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Job::MicroVmGc => "Stop micro VMs that have been idle for MICRO_VM_IDLE_TIMEOUT_SECS.",
+            Job::TrashPurge => {
+                "Purge trashed project directories and reconcile orphans with the database."
+            }
+            Job::AuditRetention => "Delete audit log entries older than AUDIT_RETENTION_DAYS.",
+            Job::UsageAggregation => "Roll llm_usage up into llm_usage_daily.",
+            Job::EventRetention => "Delete webhook events older than WEBHOOK_EVENT_RETENTION_DAYS.",
+            Job::QueueRetention => {
+                "Delete finished background jobs and their exports after JOB_RETENTION_DAYS."
+            }
+            Job::TokenRevocationPrune => "Delete revoked tokens that have expired anyway.",
+            Job::SandboxGc => {
+                "Report sandbox data without a database row; SANDBOX_GC_REMOVE deletes it."
+            }
+            Job::DataRetention => {
+                "Delete project activity and usage rows past their *_RETENTION_DAYS."
+            }
+            Job::AgentHistoryRetention => {
+                "Drop finished agent tasks older than AGENT_HISTORY_RETENTION_DAYS."
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SchedulerConfig {
+    enabled: bool,
+    micro_idle: Duration,
+    /// Zero keeps audit entries forever.
+    audit_retention_days: i32,
+    /// Zero keeps webhook events forever.
+    event_retention_days: i32,
+    /// Zero keeps finished jobs forever.
+    job_retention_days: i32,
+    sweep: SweepConfig,
+    gc: GcConfig,
+    retention: RetentionConfig,
+}
+
+impl SchedulerConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: config.get("SCHEDULER_ENABLED", true),
+            micro_idle: config.secs("MICRO_VM_IDLE_TIMEOUT_SECS", 1800),
+            audit_retention_days: config.get("AUDIT_RETENTION_DAYS", 90).max(0),
+            event_retention_days: config.get("WEBHOOK_EVENT_RETENTION_DAYS", 7).max(0),
+            job_retention_days: config.get("JOB_RETENTION_DAYS", 30).max(0),
+            sweep: SweepConfig::from_config(config),
+            gc: GcConfig::from_config(config),
+            retention: RetentionConfig::from_config(config),
+        }
+    }
+}
+
+/// One row of `schedules`.
+#[derive(Debug)]
+struct Schedule {
+    name: String,
+    cron: String,
+    enabled: bool,
+    run_requested_at: Option<DateTime<Utc>>,
+    last_started_at: Option<DateTime<Utc>>,
+    last_finished_at: Option<DateTime<Utc>>,
+    last_status: Option<String>,
+    last_duration_ms: Option<i64>,
+    last_result: Option<Value>,
+    last_error: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+impl Schedule {
+    fn from_row(row: &PgRow) -> Self {
+        Self {
+            name: row.get("name"),
+            cron: row.get("cron"),
+            enabled: row.get("enabled"),
+            run_requested_at: row.get("run_requested_at"),
+            last_started_at: row.get("last_started_at"),
+            last_finished_at: row.get("last_finished_at"),
+            last_status: row.get("last_status"),
+            last_duration_ms: row.get("last_duration_ms"),
+            last_result: row
+                .get::<Option<Json<Value>>, _>("last_result")
+                .map(|Json(result)| result),
+            last_error: row.get("last_error"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    /// A manual run was requested after the job last started. Instance jobs
+    /// pass their own last run, so each instance answers the request once
+    /// rather than only the first one to see it.
+    fn run_requested(&self, last_run: Option<DateTime<Utc>>) -> bool {
+        match (self.run_requested_at, last_run) {
+            (Some(requested), Some(started)) => requested > started,
+            (requested, _) => requested.is_some(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let job = Job::parse(&self.name);
+        let next_run_at = self
+            .cron
+            .parse::<Cron>()
+            .ok()
+            .filter(|_| self.enabled)
+            .and_then(|cron| next_run(&cron, self.last_started_at, self.updated_at));
+        json!({
+            "name": self.name,
+            "description": job.map(Job::description),
+            "scope": job.map(|job| job.scope().as_str()),
+            "cron": self.cron,
+            "enabled": self.enabled,
+            "next_run_at": next_run_at,
+            "run_requested": self.run_requested(self.last_started_at),
+            "last_started_at": self.last_started_at,
+            "last_finished_at": self.last_finished_at,
+            "last_status": self.last_status,
+            "last_duration_ms": self.last_duration_ms,
+            "last_result": self.last_result,
+            "last_error": self.last_error,
+        })
+    }
+}
+
+/// When a job is next due: the first match after its last run, or after its
+/// schedule was last edited if that is later.
+fn next_run(
+    cron: &Cron,
+    last_run: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let base = last_run.map_or(updated_at, |last| last.max(updated_at));
+    cron.next_after(base)
+}
+
+/// Holds the leader lock on a connection detached from the pool, so the lock
+/// lives exactly as long as that session.
+#[derive(Default)]
+struct Leadership {
+    conn: Option<PgConnection>,
+}
+
+impl Leadership {
+    async fn hold(&mut self, pool: &PgPool) -> bool {
+        if let Some(conn) = self.conn.as_mut() {
+            let ping = tokio::time::timeout(PING_TIMEOUT, sqlx::query("SELECT 1").execute(conn));
+            if matches!(ping.await, Ok(Ok(_))) {
+                return true;
+            }
+            warn!("lost the scheduler leader session");
+            self.conn = None;
+        }
+        let mut conn = match pool.acquire().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(error = %err, "failed to acquire a connection for scheduler election");
+                return false;
+            }
+        };
+        let acquired = sqlx::query_scalar::<_, bool>("SELECT pg_try_advisory_lock($1)")
+            .bind(LEADER_LOCK)
+            .fetch_one(&mut *conn)
+            .await;
+        match acquired {
+            Ok(true) => {
+                info!("acquired scheduler leadership");
+                self.conn = Some(conn.detach());
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!(error = %err, "scheduler election failed");
+                false
+            }
+        }
+    }
+}
+
+pub(crate) struct Scheduler {
+    pool: PgPool,
+    sandbox: Arc<SandboxFs>,
+    micro: Arc<SandboxMicro>,
+    agents: Arc<AgentDispatcher>,
+    metrics: Arc<AppMetrics>,
+    config: SchedulerConfig,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        pool: PgPool,
+        sandbox: Arc<SandboxFs>,
+        micro: Arc<SandboxMicro>,
+        agents: Arc<AgentDispatcher>,
+        metrics: Arc<AppMetrics>,
+        config: SchedulerConfig,
+    ) -> Self {
+        Self {
+            pool,
+            sandbox,
+            micro,
+            agents,
+            metrics,
+            config,
+        }
+    }
+
+    pub(crate) fn spawn(self) -> Option<JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        Some(tokio::spawn(async move {
+            let mut seeded = false;
+            let mut leadership = Leadership::default();
+            // Instance jobs track their last run per process; the row only
+            // records the latest run on any instance.
+            let started = Utc::now();
+            let mut local_runs: HashMap<Job, DateTime<Utc>> = HashMap::new();
+            let mut ticker = tokio::time::interval(TICK);
+            loop {
+                ticker.tick().await;
+                if !seeded {
+                    seeded = match seed(&self.pool).await {
+                        Ok(()) => true,
+                        Err(err) => {
+                            warn!(error = %err, "failed to seed schedules");
+                            continue;
+                        }
+                    };
+                }
+                let leader = leadership.hold(&self.pool).await;
+                let schedules = match load(&self.pool).await {
+                    Ok(schedules) => schedules,
+                    Err(err) => {
+                        warn!(error = %err, "failed to load schedules");
+                        continue;
+                    }
+                };
+                for schedule in schedules {
+                    let Some(job) = Job::parse(&schedule.name) else {
+                        continue;
+                    };
+                    let last_run = match job.scope() {
+                        Scope::Cluster if !leader => continue,
+                        Scope::Cluster => schedule.last_started_at,
+                        Scope::Instance => Some(local_runs.get(&job).copied().unwrap_or(started)),
+                    };
+                    let due = schedule.run_requested(last_run)
+                        || (schedule.enabled && self.is_due(&schedule, last_run));
+                    if due {
+                        local_runs.insert(job, Utc::now());
+                        self.run(job, schedule.last_started_at).await;
+                    }
+                }
+            }
+        }))
+    }
+
+    fn is_due(&self, schedule: &Schedule, last_run: Option<DateTime<Utc>>) -> bool {
+        match schedule.cron.parse::<Cron>() {
+            Ok(cron) => next_run(&cron, last_run, schedule.updated_at)
+                .is_some_and(|next| next <= Utc::now()),
+            Err(err) => {
+                warn!(schedule = %schedule.name, error = %err, "skipping schedule with invalid cron");
+                false
+            }
+        }
+    }
+
+    async fn run(&self, job: Job, previous_run: Option<DateTime<Utc>>) {
+        if let Err(err) =
+            sqlx::query("UPDATE schedules SET last_started_at = NOW() WHERE name = $1")
+                .bind(job.name())
+                .execute(&self.pool)
+                .await
+        {
+            warn!(job = job.name(), error = %err, "failed to mark scheduled job as started");
+            return;
+        }
+        let started = Instant::now();
+        let outcome = self.execute(job, previous_run).await;
+        let duration_ms = started.elapsed().as_millis().min(i64::MAX as u128) as i64;
+        let (status, result, error) = match outcome {
+            Ok(result) => {
+                info!(job = job.name(), duration_ms, %result, "scheduled job finished");
+                ("ok", Some(Json(result)), None)
+            }
+            Err(err) => {
+                warn!(job = job.name(), duration_ms, error = %err, "scheduled job failed");
+                ("error", None, Some(err))
+            }
+        };
+        if let Err(err) = sqlx::query(
+            "UPDATE schedules SET last_finished_at = NOW(), last_status = $2, \
+                last_duration_ms = $3, last_result = $4, last_error = $5 \
+             WHERE name = $1",
+        )
+        .bind(job.name())
+        .bind(status)
+        .bind(duration_ms)
+        .bind(result)
+        .bind(error)
+        .execute(&self.pool)
+        .await
+        {
+            warn!(job = job.name(), error = %err, "failed to record scheduled job result");
+        }
+    }
+
+    async fn execute(
+        &self,
+        job: Job,
+        previous_run: Option<DateTime<Utc>>,
+    ) -> Result<Value, String> {
+        match job {
+            Job::MicroVmGc => {
+                let stopped = self
+                    .micro
+                    .stop_idle(self.config.micro_idle)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                Ok(json!({ "stopped": stopped }))
+            }
+            Job::TrashPurge => {
+                let applied = reconcile::sweep(&self.pool, &self.sandbox, self.config.sweep)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                Ok(json!({ "applied": applied }))
+            }
+            Job::AuditRetention => {
+                let deleted = retention::purge(
+                    &self.pool,
+                    "audit_log",
+                    "created_at",
+                    self.config.audit_retention_days,
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+                self.metrics.retention_pruned("audit_log", deleted);
+                Ok(json!({ "deleted": deleted }))
+            }
+            Job::UsageAggregation => aggregate_usage(&self.pool, previous_run)
+                .await
+                .map_err(|err| err.to_string()),
+            Job::EventRetention => {
+                let deleted = retention::purge(
+                    &self.pool,
+                    "events",
+                    "created_at",
+                    self.config.event_retention_days,
+                )
+                .await
+                .map_err(|err| err.to_string())?;
+                self.metrics.retention_pruned("events", deleted);
+                Ok(json!({ "deleted": deleted }))
+            }
+            Job::QueueRetention => purge_jobs(
+                &self.pool,
+                &self.sandbox,
+                &self.metrics,
+                self.config.job_retention_days,
+            )
+            .await
+            .map_err(|err| err.to_string()),
+            Job::TokenRevocationPrune => prune_revoked_tokens(&self.pool)
+                .await
+                .map_err(|err| err.to_string()),
+            Job::SandboxGc => gc::collect(
+                &self.pool,
+                &self.sandbox,
+                &self.micro,
+                &self.metrics,
+                self.config.gc,
+                self.config.micro_idle,
+            )
+            .await
+            .map_err(|err| err.to_string()),
+            Job::DataRetention => {
+                retention::prune(&self.pool, &self.metrics, self.config.retention)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            Job::AgentHistoryRetention => Ok(retention::prune_agent_history(
+                &self.agents,
+                &self.metrics,
+                self.config.retention,
+            )),
+        }
+    }
+}
+
+async fn seed(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let names: Vec<&str> = Job::ALL.iter().map(|job| job.name()).collect();
+    let crons: Vec<&str> = Job::ALL.iter().map(|job| job.default_cron()).collect();
+    sqlx::query(
+        "INSERT INTO schedules (name, cron) SELECT * FROM UNNEST($1::text[], $2::text[]) \
+         ON CONFLICT (name) DO NOTHING",
+    )
+    .bind(&names)
+    .bind(&crons)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn load(pool: &PgPool) -> Result<Vec<Schedule>, sqlx::Error> {
+    let rows = sqlx::query(&format!("SELECT {COLUMNS} FROM schedules ORDER BY name"))
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(Schedule::from_row).collect())
+}
+
+/// Expired tokens fail validation on their own, so their revocations can go.
+async fn prune_revoked_tokens(pool: &PgPool) -> Result<Value, sqlx::Error> {
+    let deleted = sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(json!({ "deleted": deleted }))
+}
+
+/// Deletes jobs that finished before the retention window, along with the
+/// bundles written by export jobs among them.
+async fn purge_jobs(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    metrics: &AppMetrics,
+    retention_days: i32,
+) -> Result<Value, sqlx::Error> {
+    if retention_days == 0 {
+        return Ok(json!({ "deleted": 0 }));
+    }
+    let rows = sqlx::query(
+        "DELETE FROM jobs WHERE status IN ('succeeded', 'dead', 'cancelled') \
+            AND finished_at < NOW() - make_interval(days => $1) \
+         RETURNING id, kind",
+    )
+    .bind(retention_days)
+    .fetch_all(pool)
+    .await?;
+    let mut artifacts = 0;
+    for row in &rows {
+        if row.get::<String, _>("kind") != JobKind::ProjectExport.as_str() {
+            continue;
+        }
+        let id: i64 = row.get("id");
+        match sandbox.delete(transfer::artifact_path(id)) {
+            Ok(()) => artifacts += 1,
+            Err(err) => warn!(job = id, error = %err, "failed to delete export bundle"),
+        }
+    }
+    metrics.retention_pruned("jobs", rows.len() as u64);
+    Ok(json!({ "deleted": rows.len(), "artifacts": artifacts }))
+}
+
+/// Rebuilds the daily rollup from the day before the previous run onwards;
+/// usage rows are written after the call finishes, so the last day or two
+/// may still grow. The first run covers all of `llm_usage`.
+async fn aggregate_usage(
+    pool: &PgPool,
+    previous_run: Option<DateTime<Utc>>,
+) -> Result<Value, sqlx::Error> {
+    let since = previous_run.map(|at| at.date_naive() - ChronoDuration::days(1));
+    let rows = sqlx::query(
+        "INSERT INTO llm_usage_daily (day, user_id, model, provider, calls, errors, cached, \
+            prompt_tokens, completion_tokens, total_tokens) \
+         SELECT (created_at AT TIME ZONE 'UTC')::date, user_id, model, provider, COUNT(*), \
+            COUNT(*) FILTER (WHERE status = 'error'), COUNT(*) FILTER (WHERE status = 'cached'), \
+            SUM(prompt_tokens), SUM(completion_tokens), SUM(total_tokens) \
+         FROM llm_usage \
+         WHERE $1::date IS NULL OR created_at >= ($1::date)::timestamp AT TIME ZONE 'UTC' \
+         GROUP BY 1, 2, 3, 4 \
+         ON CONFLICT (day, user_id, model, provider) DO UPDATE SET \
+            calls = EXCLUDED.calls, errors = EXCLUDED.errors, cached = EXCLUDED.cached, \
+            prompt_tokens = EXCLUDED.prompt_tokens, \
+            completion_tokens = EXCLUDED.completion_tokens, \
+            total_tokens = EXCLUDED.total_tokens",
+    )
+    .bind(since)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(json!({ "since": since, "rows": rows }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ScheduleUpdateParams {
+    name: String,
+    /// Five-field cron expression, evaluated in UTC.
+    #[serde(default)]
+    cron: Option<String>,
+    #[serde(default)]
+    enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ScheduleNameParams {
+    name: String,
+}
+
+fn schedule_not_found(name: &str) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::ScheduleNotFound,
+        "schedule not found",
+        Some(json!({ "name": name })),
+    )
+}
+
+fn db_error(err: sqlx::Error) -> RpcMethodError {
+    RpcMethodError::internal(&format!("failed to update schedule: {err}"))
+}
+
+pub(crate) async fn list(pool: &PgPool) -> Result<Value, RpcMethodError> {
+    let schedules = load(pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to list schedules: {err}")))?;
+    let schedules: Vec<Value> = schedules.iter().map(Schedule::to_json).collect();
+    Ok(json!({ "schedules": schedules }))
+}
+
+pub(crate) async fn update(
+    pool: &PgPool,
+    params: ScheduleUpdateParams,
+) -> Result<Value, RpcMethodError> {
+    if let Some(cron) = &params.cron {
+        cron.parse::<Cron>().map_err(|err| {
+            RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "invalid cron expression",
+                Some(json!({ "cron": cron, "reason": err.to_string() })),
+            )
+        })?;
+    }
+    let row = sqlx::query(&format!(
+        "UPDATE schedules SET cron = COALESCE($2, cron), enabled = COALESCE($3, enabled), \
+            updated_at = NOW() \
+         WHERE name = $1 RETURNING {COLUMNS}"
+    ))
+    .bind(&params.name)
+    .bind(params.cron.as_deref().map(str::trim))
+    .bind(params.enabled)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| schedule_not_found(&params.name))?;
+    Ok(Schedule::from_row(&row).to_json())
+}
+
+/// Asks the scheduler to run the job on its next tick, even when disabled;
+/// instance jobs run once on every instance.
+pub(crate) async fn run_now(
+    pool: &PgPool,
+    params: ScheduleNameParams,
+) -> Result<Value, RpcMethodError> {
+    let row = sqlx::query(&format!(
+        "UPDATE schedules SET run_requested_at = NOW() WHERE name = $1 RETURNING {COLUMNS}"
+    ))
+    .bind(&params.name)
+    .fetch_optional(pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| schedule_not_found(&params.name))?;
+    Ok(Schedule::from_row(&row).to_json())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn next_run_counts_from_last_run_or_edit() {
+        let cron: Cron = "*/5 * * * *".parse().unwrap();
+        let at = |h, m| Utc.with_ymd_and_hms(2024, 5, 1, h, m, 0).unwrap();
+        assert_eq!(next_run(&cron, None, at(9, 1)), Some(at(9, 5)));
+        assert_eq!(next_run(&cron, Some(at(9, 12)), at(9, 1)), Some(at(9, 15)));
+        // Retimed after the last run: the edit wins.
+        assert_eq!(next_run(&cron, Some(at(9, 12)), at(9, 21)), Some(at(9, 25)));
+
+        for job in Job::ALL {
+            assert_eq!(Job::parse(job.name()), Some(job));
+            assert!(job.default_cron().parse::<Cron>().is_ok());
+        }
+    }
+
+    #[test]
+    fn run_requests_reach_every_instance() {
+        let at = |m| Utc.with_ymd_and_hms(2024, 5, 1, 9, m, 0).unwrap();
+        let schedule = Schedule {
+            name: Job::MicroVmGc.name().to_string(),
+            cron: Job::MicroVmGc.default_cron().to_string(),
+            enabled: true,
+            run_requested_at: Some(at(10)),
+            // Another instance already answered the request.
+            last_started_at: Some(at(11)),
+            last_finished_at: None,
+            last_status: None,
+            last_duration_ms: None,
+            last_result: None,
+            last_error: None,
+            updated_at: at(0),
+        };
+        assert!(!schedule.run_requested(schedule.last_started_at));
+        assert!(schedule.run_requested(Some(at(5))));
+        assert!(!schedule.run_requested(Some(at(12))));
+        assert!(schedule.run_requested(None));
+    }
+}
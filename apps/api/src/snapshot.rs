@@ -0,0 +1,464 @@
+//! Project snapshots (migration 035), an undo for whole projects without an
+//! external git. `project.snapshot.create` records the path and sha256 of
+//! every file in `project_files`; contents go to `project_blobs` once per
+//! project and distinct sha256, so unchanged files are not stored again.
+//! `project.snapshot.restore` makes the project's files match a snapshot, in
+//! Postgres and the sandbox mirror, after snapshotting the current state so
+//! the restore can be undone the same way. Replaced and removed files also
+//! land in the per-file history, as with any other save or delete. The
+//! mirror follows once Postgres has committed; files it fails to update are
+//! reported as `mirror_drift` rather than failing a restore that happened.
+//!
+//! A project keeps its newest `PROJECT_SNAPSHOT_LIMIT` snapshots; blobs go
+//! with the last snapshot referring to them and count against the owner's
+//! storage quota. Snapshot operations on a project are serialized by locking
+//! its row.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use sandbox::{SandboxError, SandboxFs};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Error as SqlxError, Postgres, Row, Transaction};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::errors::ErrorCode;
+use crate::{
+    archive_project_file, load_project, map_db_activity_error, parse_project_id,
+    project_directory_relative, quota, record_project_activity, AppState, Permission,
+    RequestContext, RpcMethodError,
+};
+
+const MAX_LABEL_CHARS: usize = 128;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct SnapshotCreateParams {
+    project_id: String,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct SnapshotListParams {
+    project_id: String,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct SnapshotRestoreParams {
+    project_id: String,
+    snapshot_id: i64,
+}
+
+pub(crate) async fn create(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: SnapshotCreateParams,
+) -> Result<Value, RpcMethodError> {
+    ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
+    let project_id = parse_project_id(&params.project_id)?;
+    let _ = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+    let label = params.label.as_deref().and_then(snapshot_label);
+    let snapshot = take(state, &project_id, ctx.user_id, label.as_deref()).await?;
+    record_project_activity(
+        state,
+        project_id,
+        ctx.user_id,
+        "project.snapshot.create",
+        Some(json!({ "snapshot_id": snapshot["id"] })),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(snapshot)
+}
+
+pub(crate) async fn list(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: SnapshotListParams,
+) -> Result<Value, RpcMethodError> {
+    ctx.require_for(Permission::FsRead, Some(params.project_id.as_str()))?;
+    let project_id = parse_project_id(&params.project_id)?;
+    let _ = load_project(state, ctx, &project_id, Permission::FsRead).await?;
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let rows = sqlx::query(
+        "SELECT id, user_id, label, file_count, total_size, created_at FROM project_snapshots \
+         WHERE project_id = $1 ORDER BY id DESC LIMIT $2",
+    )
+    .bind(project_id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list snapshots: {err}")))?;
+    let snapshots: Vec<Value> = rows.iter().map(snapshot_value).collect();
+    Ok(json!({ "snapshots": snapshots }))
+}
+
+pub(crate) async fn restore(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: SnapshotRestoreParams,
+) -> Result<Value, RpcMethodError> {
+    ctx.require_for(Permission::FsWrite, Some(params.project_id.as_str()))?;
+    let project_id = parse_project_id(&params.project_id)?;
+    let project = load_project(state, ctx, &project_id, Permission::FsWrite).await?;
+    let snapshot_id = params.snapshot_id;
+    let files = snapshot_files(state, &project_id, snapshot_id).await?;
+    let total: i64 = files.iter().map(|file| file.content.len() as i64).sum();
+    // Checked before the backup is taken, and again under the owner's quota
+    // lock in the transaction that writes the files.
+    let mut conn = quota::connection(state).await?;
+    quota::ensure_project_fits(state, &mut conn, &project_id, total).await?;
+    drop(conn);
+    let backup = take(
+        state,
+        &project_id,
+        ctx.user_id,
+        Some(&format!("before restoring snapshot {snapshot_id}")),
+    )
+    .await?;
+
+    let restore_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to restore snapshot: {err}"));
+    let mut tx = state.pool.begin().await.map_err(restore_error)?;
+    lock_project(&mut tx, &project_id)
+        .await
+        .map_err(restore_error)?;
+    quota::ensure_project_fits(state, &mut tx, &project_id, total).await?;
+    let current: HashMap<String, Vec<u8>> =
+        sqlx::query("SELECT path, sha256 FROM project_files WHERE project_id = $1")
+            .bind(project_id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(restore_error)?
+            .into_iter()
+            .map(|row| (row.get("path"), row.get("sha256")))
+            .collect();
+    let kept: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+    let mut removed: Vec<&str> = current
+        .keys()
+        .map(String::as_str)
+        .filter(|path| !kept.contains(path))
+        .collect();
+    removed.sort_unstable();
+    for path in &removed {
+        archive_project_file(
+            &mut tx,
+            &project_id,
+            path,
+            None,
+            state.project_version_limit,
+        )
+        .await
+        .map_err(restore_error)?;
+    }
+    sqlx::query("DELETE FROM project_files WHERE project_id = $1 AND NOT (path = ANY($2))")
+        .bind(project_id)
+        .bind(&kept)
+        .execute(&mut *tx)
+        .await
+        .map_err(restore_error)?;
+    let written: Vec<&SnapshotFile> = files
+        .iter()
+        .filter(|file| current.get(&file.path) != Some(&file.sha256))
+        .collect();
+    for file in &written {
+        archive_project_file(
+            &mut tx,
+            &project_id,
+            &file.path,
+            Some(&file.sha256),
+            state.project_version_limit,
+        )
+        .await
+        .map_err(restore_error)?;
+        sqlx::query(
+            "INSERT INTO project_files (project_id, path, content, sha256, size) \
+             VALUES ($1, $2, $3, $4, $5) \
+             ON CONFLICT (project_id, path) DO UPDATE SET content = EXCLUDED.content, \
+                sha256 = EXCLUDED.sha256, size = EXCLUDED.size, updated_at = NOW()",
+        )
+        .bind(project_id)
+        .bind(&file.path)
+        .bind(&file.content)
+        .bind(&file.sha256)
+        .bind(file.content.len() as i64)
+        .execute(&mut *tx)
+        .await
+        .map_err(restore_error)?;
+    }
+    tx.commit().await.map_err(restore_error)?;
+    state.project_cache.invalidate_listings(&project_id);
+
+    let directory = project_directory_relative(project.tenant_id, &project_id);
+    let drift = sync_mirror(&state.sandbox, &directory, &removed, &written);
+    if !drift.is_empty() {
+        warn!(%project_id, snapshot_id, paths = ?drift, "snapshot restore left the mirror out of date");
+    }
+
+    let detail = json!({
+        "snapshot_id": snapshot_id,
+        "backup_snapshot_id": backup["id"],
+        "written": written.len(),
+        "removed": removed.len(),
+        "mirror_drift": drift,
+    });
+    record_project_activity(
+        state,
+        project_id,
+        ctx.user_id,
+        "project.snapshot.restore",
+        Some(detail.clone()),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(detail)
+}
+
+/// Trims a label to at most [`MAX_LABEL_CHARS`]; blank labels are dropped.
+fn snapshot_label(label: &str) -> Option<String> {
+    let label: String = label.trim().chars().take(MAX_LABEL_CHARS).collect();
+    (!label.is_empty()).then_some(label)
+}
+
+/// Brings the sandbox mirror in line with a committed restore and returns
+/// the paths it could not update. A file that is already gone needs no
+/// delete.
+fn sync_mirror(
+    sandbox: &SandboxFs,
+    directory: &Path,
+    removed: &[&str],
+    written: &[&SnapshotFile],
+) -> Vec<String> {
+    let mut drift = Vec::new();
+    for path in removed {
+        match sandbox.delete(directory.join(path)) {
+            Ok(()) => {}
+            Err(SandboxError::Io(err)) if err.kind() == ErrorKind::NotFound => {}
+            Err(_) => drift.push(path.to_string()),
+        }
+    }
+    for file in written {
+        if sandbox
+            .write(directory.join(&file.path), &file.content)
+            .is_err()
+        {
+            drift.push(file.path.clone());
+        }
+    }
+    drift
+}
+
+struct SnapshotFile {
+    path: String,
+    sha256: Vec<u8>,
+    content: Vec<u8>,
+}
+
+async fn snapshot_files(
+    state: &AppState,
+    project_id: &Uuid,
+    snapshot_id: i64,
+) -> Result<Vec<SnapshotFile>, RpcMethodError> {
+    let load_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to load snapshot: {err}"));
+    let exists: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM project_snapshots WHERE id = $1 AND project_id = $2")
+            .bind(snapshot_id)
+            .bind(project_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(load_error)?;
+    if exists.is_none() {
+        return Err(RpcMethodError::new(
+            ErrorCode::ProjectSnapshotNotFound,
+            "project snapshot not found",
+            Some(json!({ "snapshot_id": snapshot_id })),
+        ));
+    }
+    let rows = sqlx::query(
+        "SELECT f.path, f.sha256, b.content FROM project_snapshot_files f \
+         JOIN project_blobs b ON b.project_id = $2 AND b.sha256 = f.sha256 \
+         WHERE f.snapshot_id = $1 ORDER BY f.path",
+    )
+    .bind(snapshot_id)
+    .bind(project_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(load_error)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| SnapshotFile {
+            path: row.get("path"),
+            sha256: row.get("sha256"),
+            content: row.get("content"),
+        })
+        .collect())
+}
+
+/// Snapshots the project's current files and prunes its oldest snapshots
+/// past the limit.
+async fn take(
+    state: &AppState,
+    project_id: &Uuid,
+    user_id: i32,
+    label: Option<&str>,
+) -> Result<Value, RpcMethodError> {
+    let create_error =
+        |err: SqlxError| RpcMethodError::internal(&format!("failed to create snapshot: {err}"));
+    let mut tx = state.pool.begin().await.map_err(create_error)?;
+    lock_project(&mut tx, project_id)
+        .await
+        .map_err(create_error)?;
+    quota::ensure_snapshot_fits(state, &mut tx, project_id).await?;
+    let snapshot_id: i64 = sqlx::query_scalar(
+        "INSERT INTO project_snapshots (project_id, user_id, label) VALUES ($1, $2, $3) \
+         RETURNING id",
+    )
+    .bind(project_id)
+    .bind(user_id)
+    .bind(label)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(create_error)?;
+    // One statement, so blobs, file list and totals all see the same files.
+    let row = sqlx::query(
+        "WITH files AS ( \
+            SELECT path, sha256, content, size FROM project_files WHERE project_id = $1 \
+         ), blobs AS ( \
+            INSERT INTO project_blobs (project_id, sha256, content, size) \
+            SELECT DISTINCT ON (sha256) $1, sha256, content, size FROM files \
+            ON CONFLICT DO NOTHING \
+         ), listed AS ( \
+            INSERT INTO project_snapshot_files (snapshot_id, path, sha256, size) \
+            SELECT $2, path, sha256, size FROM files \
+         ) \
+         UPDATE project_snapshots SET \
+            file_count = (SELECT COUNT(*) FROM files), \
+            total_size = (SELECT COALESCE(SUM(size), 0) FROM files)::BIGINT \
+         WHERE id = $2 \
+         RETURNING id, user_id, label, file_count, total_size, created_at",
+    )
+    .bind(project_id)
+    .bind(snapshot_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(create_error)?;
+    prune(&mut tx, project_id, state.project_snapshot_limit)
+        .await
+        .map_err(create_error)?;
+    tx.commit().await.map_err(create_error)?;
+    Ok(snapshot_value(&row))
+}
+
+/// Keeps snapshot operations on a project from interleaving; saves of
+/// single files are not blocked.
+async fn lock_project(
+    tx: &mut Transaction<'_, Postgres>,
+    project_id: &Uuid,
+) -> Result<(), SqlxError> {
+    sqlx::query("SELECT id FROM projects WHERE id = $1 FOR NO KEY UPDATE")
+        .bind(project_id)
+        .execute(&mut **tx)
+        .await
+        .map(|_| ())
+}
+
+async fn prune(
+    tx: &mut Transaction<'_, Postgres>,
+    project_id: &Uuid,
+    limit: i64,
+) -> Result<(), SqlxError> {
+    let pruned = sqlx::query(
+        "DELETE FROM project_snapshots WHERE project_id = $1 AND id NOT IN ( \
+            SELECT id FROM project_snapshots WHERE project_id = $1 ORDER BY id DESC LIMIT $2 \
+         )",
+    )
+    .bind(project_id)
+    .bind(limit)
+    .execute(&mut **tx)
+    .await?;
+    if pruned.rows_affected() > 0 {
+        sqlx::query(
+            "DELETE FROM project_blobs b WHERE b.project_id = $1 AND NOT EXISTS ( \
+                SELECT 1 FROM project_snapshot_files f \
+                JOIN project_snapshots s ON s.id = f.snapshot_id \
+                WHERE s.project_id = $1 AND f.sha256 = b.sha256 \
+             )",
+        )
+        .bind(project_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+fn snapshot_value(row: &sqlx::postgres::PgRow) -> Value {
+    let created: DateTime<Utc> = row.get("created_at");
+    json!({
+        "id": row.get::<i64, _>("id"),
+        "label": row.get::<Option<String>, _>("label"),
+        "file_count": row.get::<i64, _>("file_count"),
+        "total_size": row.get::<i64, _>("total_size"),
+        "created_by": row.get::<Option<i32>, _>("user_id"),
+        "created_at": created.to_rfc3339(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sandbox::SandboxConfig;
+
+    use super::*;
+
+    #[test]
+    fn labels_are_trimmed_and_blank_ones_dropped() {
+        assert_eq!(
+            snapshot_label("  release 1 "),
+            Some("release 1".to_string())
+        );
+        assert_eq!(snapshot_label(" \t "), None);
+        let long = "x".repeat(MAX_LABEL_CHARS + 10);
+        assert_eq!(
+            snapshot_label(&long).map(|label| label.chars().count()),
+            Some(MAX_LABEL_CHARS)
+        );
+    }
+
+    #[test]
+    fn mirror_sync_reports_drift_instead_of_failing() {
+        let root = std::env::temp_dir().join(format!("snapshot-test-{}", Uuid::new_v4()));
+        let sandbox = SandboxFs::new(SandboxConfig::new(&root, 1024).unwrap());
+        let directory = Path::new("project");
+        sandbox.write(directory.join("old.txt"), b"old").unwrap();
+        sandbox.write(directory.join("blocked"), b"a file").unwrap();
+        let file = |path: &str, content: &[u8]| SnapshotFile {
+            path: path.to_string(),
+            sha256: Vec::new(),
+            content: content.to_vec(),
+        };
+        let written = [
+            file("src/main.rs", b"fn main() {}"),
+            file("blocked/inner.txt", b"x"),
+        ];
+        let drift = sync_mirror(
+            &sandbox,
+            directory,
+            &["old.txt", "never-mirrored.txt"],
+            &written.iter().collect::<Vec<_>>(),
+        );
+        assert_eq!(drift, vec!["blocked/inner.txt".to_string()]);
+        assert!(!root.join("project/old.txt").exists());
+        assert_eq!(
+            std::fs::read(root.join("project/src/main.rs")).unwrap(),
+            b"fn main() {}"
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
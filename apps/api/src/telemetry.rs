@@ -0,0 +1,225 @@
+//! Tracing setup and W3C trace context propagation. Every RPC runs inside an
+//! `rpc` span parented to the caller's `traceparent`; sandbox spans nest under
+//! it, and outgoing LLM and agent calls carry the same trace onward. Spans are
+//! exported over OTLP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set; otherwise the
+//! trace id still shows up in the JSON logs.
+
+use std::collections::HashMap;
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TraceError, TraceId};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
+use serde_json::Value;
+use tracing::{dispatcher, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::logging::{self, LoggingConfig};
+use crate::RequestContext;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, Clone)]
+pub(crate) struct TelemetryConfig {
+    otlp_endpoint: Option<String>,
+    service_name: String,
+    logging: LoggingConfig,
+}
+
+impl TelemetryConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            otlp_endpoint: config.opt("OTEL_EXPORTER_OTLP_ENDPOINT"),
+            service_name: config.string("OTEL_SERVICE_NAME", "api"),
+            logging: LoggingConfig::from_config(config),
+        }
+    }
+}
+
+pub(crate) fn init(config: &TelemetryConfig) {
+    if dispatcher::has_been_set() {
+        return;
+    }
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let otel = match otlp_tracer(config) {
+        Ok(tracer) => tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(err) => {
+            eprintln!("failed to install OTLP exporter: {err}");
+            None
+        }
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info,tower_http=info".into()))
+        .with(logging::layer(&config.logging))
+        .with(otel);
+    if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("failed to install tracing subscriber: {err}");
+    }
+}
+
+fn otlp_tracer(config: &TelemetryConfig) -> Result<Option<sdktrace::Tracer>, TraceError> {
+    let Some(endpoint) = config.otlp_endpoint.clone() else {
+        return Ok(None);
+    };
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            sdktrace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .map(Some)
+}
+
+/// Flushes spans still buffered by the batch exporter.
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// The caller's trace context, or an empty context without `traceparent`.
+pub(crate) fn extract(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Reuses a UUID `X-Request-Id` from the caller so logs on both sides match.
+pub(crate) fn request_id(headers: &HeaderMap) -> Uuid {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Uuid::parse_str(value.trim()).ok())
+        .unwrap_or_else(Uuid::new_v4)
+}
+
+fn trace_id(cx: &Context) -> Option<TraceId> {
+    let span_context = cx.span().span_context().clone();
+    span_context.is_valid().then(|| span_context.trace_id())
+}
+
+/// The span a call runs in; its fields are attached to every log line of
+/// the call (see [`logging`]).
+pub(crate) fn rpc_span(method: &str, ctx: &RequestContext, params: Option<&Value>) -> Span {
+    let project_id = params
+        .and_then(|params| params.get("project_id"))
+        .and_then(Value::as_str);
+    let span = info_span!(
+        "rpc",
+        rpc.method = %method,
+        request_id = %ctx.request_id,
+        user_id = ctx.user_id,
+        project_id,
+        trace_id = tracing::field::Empty,
+    );
+    span.set_parent(ctx.trace_parent.clone());
+    if let Some(trace_id) = span_trace_id(&span, ctx) {
+        span.record("trace_id", tracing::field::display(trace_id));
+    }
+    span
+}
+
+/// The trace `span` belongs to, or the caller's trace when no exporter
+/// assigns one; metrics attach it to observations as an exemplar.
+pub(crate) fn span_trace_id(span: &Span, ctx: &RequestContext) -> Option<TraceId> {
+    trace_id(&span.context()).or_else(|| trace_id(&ctx.trace_parent))
+}
+
+/// Context to propagate from the current span. Without an OTLP exporter spans
+/// carry no OpenTelemetry context, so the caller's context is forwarded as is.
+fn outgoing_context(ctx: Option<&RequestContext>) -> Option<Context> {
+    let current = Span::current().context();
+    if current.span().span_context().is_valid() {
+        return Some(current);
+    }
+    ctx.map(|ctx| ctx.trace_parent.clone())
+        .filter(|cx| cx.span().span_context().is_valid())
+}
+
+/// Adds `traceparent`/`tracestate` for the current trace to outgoing headers.
+pub(crate) fn inject(headers: &mut HeaderMap, ctx: Option<&RequestContext>) {
+    if let Some(cx) = outgoing_context(ctx) {
+        TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(headers));
+    }
+}
+
+/// The `traceparent` value for work handed to the agent dispatcher.
+pub(crate) fn traceparent(ctx: &RequestContext) -> Option<String> {
+    let cx = outgoing_context(Some(ctx))?;
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&cx, &mut carrier);
+    carrier.remove("traceparent")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_and_reinjects_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut incoming = HeaderMap::new();
+        incoming.insert("traceparent", HeaderValue::from_static(header));
+        incoming.insert(REQUEST_ID_HEADER, HeaderValue::from_static("not-a-uuid"));
+        let cx = extract(&incoming);
+        assert_eq!(
+            trace_id(&cx).map(|id| id.to_string()).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert!(trace_id(&extract(&HeaderMap::new())).is_none());
+
+        let ctx = RequestContext {
+            user_id: 1,
+            username: "dev".to_string(),
+            role: crate::Role::Developer,
+            tenant_id: crate::tenant::DEFAULT_TENANT,
+            permissions: Default::default(),
+            token_balance: 0,
+            api_key_id: None,
+            client_ip: None,
+            request_id: request_id(&incoming),
+            trace_parent: cx,
+            credentials: Default::default(),
+        };
+        let mut outgoing = HeaderMap::new();
+        inject(&mut outgoing, Some(&ctx));
+        assert_eq!(outgoing.get("traceparent").unwrap(), header);
+        assert_eq!(traceparent(&ctx).as_deref(), Some(header));
+    }
+}
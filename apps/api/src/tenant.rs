@@ -0,0 +1,106 @@
+//! Tenants (migration 031) in the sandbox. Each tenant's projects, user
+//! directories, workspaces and trash live below `tenants/<id>/`, and no
+//! scope handed to fs/run/micro calls reaches above the caller's tenant
+//! directory; even admins only see their own tenant. Sandbox roots from
+//! before tenants are moved into the default tenant's directory at startup.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+/// The tenant that existing data belongs to.
+pub(crate) const DEFAULT_TENANT: i32 = 1;
+const TENANTS_DIR: &str = "tenants";
+/// Top-level directories of the single-tenant layout.
+const LEGACY_DIRS: [&str; 4] = ["projects", "users", "workspaces", ".trash"];
+
+pub(crate) fn directory_relative(tenant_id: i32) -> PathBuf {
+    PathBuf::from(TENANTS_DIR).join(tenant_id.to_string())
+}
+
+/// Tenants that have a directory below `root`.
+pub(crate) fn present(root: &Path) -> Vec<i32> {
+    let Ok(entries) = fs::read_dir(root.join(TENANTS_DIR)) else {
+        return Vec::new();
+    };
+    let mut tenants: Vec<i32> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    tenants.sort_unstable();
+    tenants
+}
+
+/// Moves the top-level directories of a single-tenant sandbox root into the
+/// default tenant's directory. A directory whose target already exists is
+/// left alone so a half-finished move never overwrites anything; returns
+/// how many directories were moved.
+pub(crate) fn relocate_legacy_layout(root: &Path) -> io::Result<usize> {
+    let target = root.join(directory_relative(DEFAULT_TENANT));
+    let mut moved = 0;
+    for name in LEGACY_DIRS {
+        let legacy = root.join(name);
+        if !legacy.is_dir() || target.join(name).exists() {
+            continue;
+        }
+        fs::create_dir_all(&target)?;
+        fs::rename(&legacy, target.join(name))?;
+        info!(
+            dir = name,
+            "moved sandbox directory into the default tenant"
+        );
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn temp_root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tenant-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn legacy_directories_move_into_the_default_tenant() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("projects/p1")).unwrap();
+        fs::write(root.join("projects/p1/main.rs"), "fn main() {}").unwrap();
+        fs::create_dir_all(root.join("users/7")).unwrap();
+        fs::create_dir_all(root.join("unrelated")).unwrap();
+
+        assert_eq!(relocate_legacy_layout(&root).unwrap(), 2);
+        let tenant = root.join("tenants/1");
+        assert!(tenant.join("projects/p1/main.rs").is_file());
+        assert!(tenant.join("users/7").is_dir());
+        assert!(!root.join("projects").exists());
+        assert!(root.join("unrelated").is_dir());
+        assert_eq!(present(&root), vec![1]);
+
+        assert_eq!(relocate_legacy_layout(&root).unwrap(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn existing_tenant_directories_are_not_overwritten() {
+        let root = temp_root();
+        fs::create_dir_all(root.join("tenants/1/projects/kept")).unwrap();
+        fs::create_dir_all(root.join("projects/stray")).unwrap();
+        fs::create_dir_all(root.join("tenants/12")).unwrap();
+        fs::write(root.join("tenants/notes.txt"), "").unwrap();
+
+        assert_eq!(relocate_legacy_layout(&root).unwrap(), 0);
+        assert!(root.join("tenants/1/projects/kept").is_dir());
+        assert!(root.join("projects/stray").is_dir());
+        assert_eq!(present(&root), vec![1, 12]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
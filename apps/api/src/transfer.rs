@@ -0,0 +1,269 @@
+//! Project export and import as background jobs. `project.export` writes a
+//! JSON bundle of the project's files to `.exports/<job id>.json`, served by
+//! `GET /jobs/<job id>/artifact`. `project.import` creates the project right
+//! away and fills it from a bundle, either passed inline or taken from a
+//! finished export, in a job.
+
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use uuid::Uuid;
+
+use crate::errors::ErrorCode;
+use crate::jobs::{self, ClaimedJob, JobError, JobKind};
+use crate::{
+    fetch_project, map_db_activity_error, normalize_project_path, provision_project,
+    record_project_activity, store_project_file, AppState, RequestContext, RpcMethodError,
+};
+
+/// Below the sandbox root, shared by all tenants.
+pub(crate) const EXPORTS_DIR: &str = ".exports";
+const BUNDLE_FORMAT: &str = "coder.project.v1";
+
+/// Where the bundle of export job `job_id` is stored below the sandbox root.
+pub(crate) fn artifact_path(job_id: i64) -> PathBuf {
+    PathBuf::from(EXPORTS_DIR).join(format!("{job_id}.json"))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    format: String,
+    project: BundleProject,
+    files: Vec<BundleFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleProject {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleFile {
+    path: String,
+    /// Base64 encoded file content.
+    content: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ProjectImportParams {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    /// A bundle in the format written by `project.export`.
+    #[serde(default)]
+    bundle: Option<Value>,
+    /// A finished `project.export` job whose bundle should be imported.
+    #[serde(default)]
+    source_job_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportPayload {
+    project_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ImportPayload {
+    project_id: Uuid,
+    #[serde(default)]
+    bundle: Option<Bundle>,
+    #[serde(default)]
+    source_job_id: Option<i64>,
+}
+
+fn invalid_bundle(detail: impl Into<Value>) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::InvalidParams,
+        "invalid project bundle",
+        Some(json!({ "detail": detail.into() })),
+    )
+}
+
+fn parse_bundle(value: Value) -> Result<Bundle, RpcMethodError> {
+    let bundle: Bundle =
+        serde_json::from_value(value).map_err(|err| invalid_bundle(err.to_string()))?;
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(invalid_bundle(format!(
+            "unsupported format {}, expected {BUNDLE_FORMAT}",
+            bundle.format
+        )));
+    }
+    Ok(bundle)
+}
+
+/// Queues an export of a project the caller may read.
+pub(crate) async fn start_export(
+    state: &AppState,
+    ctx: &RequestContext,
+    project_id: Uuid,
+) -> Result<Value, RpcMethodError> {
+    let payload = json!(ExportPayload { project_id });
+    let job_id = state
+        .jobs
+        .enqueue(JobKind::ProjectExport, ctx.user_id, payload)
+        .await?;
+    Ok(json!({ "job_id": job_id, "status": "queued" }))
+}
+
+pub(crate) async fn start_import(
+    state: &AppState,
+    ctx: &RequestContext,
+    params: ProjectImportParams,
+) -> Result<Value, RpcMethodError> {
+    let bundle = match (params.bundle, params.source_job_id) {
+        (Some(bundle), None) => Some(parse_bundle(bundle)?),
+        (None, Some(source_job_id)) => {
+            let source = jobs::load(&state.pool, ctx, source_job_id).await?;
+            if source["kind"] != JobKind::ProjectExport.as_str() || source["status"] != "succeeded"
+            {
+                return Err(RpcMethodError::new(
+                    ErrorCode::JobState,
+                    "job is not in a state that allows this",
+                    Some(json!({
+                        "job_id": source_job_id,
+                        "detail": "source_job_id must be a succeeded project.export job",
+                    })),
+                ));
+            }
+            None
+        }
+        _ => {
+            return Err(RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "exactly one of bundle and source_job_id is required",
+                None,
+            ))
+        }
+    };
+    let record = provision_project(state, ctx, &params.name, params.description.as_deref()).await?;
+    let payload = json!(ImportPayload {
+        project_id: record.id,
+        bundle,
+        source_job_id: params.source_job_id,
+    });
+    let job_id = state
+        .jobs
+        .enqueue(JobKind::ProjectImport, ctx.user_id, payload)
+        .await?;
+    Ok(json!({ "project": record.to_value(), "job_id": job_id, "status": "queued" }))
+}
+
+pub(crate) async fn export(state: &AppState, job: &ClaimedJob) -> Result<Value, JobError> {
+    let payload: ExportPayload =
+        serde_json::from_value(job.payload.clone()).map_err(JobError::fatal)?;
+    let project = sqlx::query("SELECT name, description FROM projects WHERE id = $1")
+        .bind(payload.project_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(JobError::retry)?
+        .ok_or_else(|| JobError::fatal("project no longer exists"))?;
+    let rows =
+        sqlx::query("SELECT path, content FROM project_files WHERE project_id = $1 ORDER BY path")
+            .bind(payload.project_id)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(JobError::retry)?;
+    let bundle = Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        project: BundleProject {
+            name: project.get("name"),
+            description: project.get("description"),
+        },
+        files: rows
+            .iter()
+            .map(|row| BundleFile {
+                path: row.get("path"),
+                content: BASE64.encode(row.get::<Vec<u8>, _>("content")),
+            })
+            .collect(),
+    };
+    let bytes = serde_json::to_vec(&bundle).map_err(JobError::fatal)?;
+    state
+        .sandbox
+        .write(artifact_path(job.id), &bytes)
+        .map_err(JobError::retry)?;
+    Ok(json!({
+        "project_id": payload.project_id,
+        "files": bundle.files.len(),
+        "bytes": bytes.len(),
+        "artifact": format!("/jobs/{}/artifact", job.id),
+        "exported_at": Utc::now().to_rfc3339(),
+    }))
+}
+
+/// Writes every bundle file into the project. Saves are upserts, so a retry
+/// after a partial import simply rewrites what is already there.
+pub(crate) async fn import(state: &AppState, job: &ClaimedJob) -> Result<Value, JobError> {
+    let payload: ImportPayload =
+        serde_json::from_value(job.payload.clone()).map_err(JobError::fatal)?;
+    let user_id = job
+        .user_id
+        .ok_or_else(|| JobError::fatal("import job has no owner"))?;
+    let bundle = match (payload.bundle, payload.source_job_id) {
+        (Some(bundle), _) => bundle,
+        (None, Some(source_job_id)) => {
+            let bytes = state
+                .sandbox
+                .read(artifact_path(source_job_id))
+                .map_err(|err| JobError::fatal(format!("export bundle is gone: {err}")))?;
+            serde_json::from_slice::<Value>(&bytes)
+                .map_err(JobError::fatal)
+                .and_then(|value| parse_bundle(value).map_err(JobError::from))?
+        }
+        (None, None) => return Err(JobError::fatal("import job has no bundle")),
+    };
+    let project_id = payload.project_id;
+    let project = state
+        .project_cache
+        .project(project_id, || fetch_project(&state.pool, &project_id))
+        .await?;
+    for file in &bundle.files {
+        let path = normalize_project_path(&file.path)?;
+        let data = BASE64
+            .decode(file.content.as_bytes())
+            .map_err(|err| JobError::fatal(format!("invalid content for {}: {err}", file.path)))?;
+        let sha256 = Sha256::digest(&data);
+        store_project_file(state, user_id, &project, &path, &data, &sha256, Some("")).await?;
+    }
+    record_project_activity(
+        state,
+        project_id,
+        user_id,
+        "project.imported",
+        Some(json!({ "files": bundle.files.len(), "source_job_id": payload.source_job_id })),
+    )
+    .await
+    .map_err(|err| map_db_activity_error(err, "failed to record project activity"))?;
+    Ok(json!({ "project_id": project_id, "files": bundle.files.len() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_must_match_the_export_format() {
+        let bundle = json!({
+            "format": BUNDLE_FORMAT,
+            "project": { "name": "demo" },
+            "files": [{ "path": "src/main.rs", "content": BASE64.encode("fn main() {}") }],
+        });
+        let parsed = parse_bundle(bundle).unwrap();
+        assert_eq!(parsed.files.len(), 1);
+        assert_eq!(parsed.project.description, None);
+
+        let other = json!({ "format": "zip", "project": { "name": "demo" }, "files": [] });
+        assert_eq!(parse_bundle(other).unwrap_err().code, -32602);
+        assert!(parse_bundle(json!({ "format": BUNDLE_FORMAT })).is_err());
+        assert_eq!(artifact_path(7), PathBuf::from(".exports/7.json"));
+    }
+}
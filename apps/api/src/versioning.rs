@@ -0,0 +1,164 @@
+//! Versioned method names. Every method is callable as `v1.<method>`, and the
+//! bare name is an alias of the current version. Renamed methods stay
+//! reachable under their old name through `DEPRECATED`. Calls to a
+//! deprecated name are logged and counted on `/metrics`. Once clients have
+//! migrated, `RPC_DISABLED_METHODS` turns those names off without touching
+//! the handlers.
+
+use std::collections::HashSet;
+
+use serde_json::json;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::metrics::AppMetrics;
+use crate::{openrpc, RequestContext, RpcMethodError};
+
+pub(crate) const CURRENT_VERSION: &str = "v1";
+
+/// Old method names and the method that replaced them.
+pub(crate) const DEPRECATED: &[(&str, &str)] = &[("llm.completions", "llm.completion")];
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct VersionConfig {
+    /// Also treat bare names (`fs.read`) as deprecated aliases of `v1.*`.
+    deprecate_unversioned: bool,
+    disabled: HashSet<String>,
+    disable_all: bool,
+}
+
+impl VersionConfig {
+    /// `RPC_DISABLED_METHODS` is a comma-separated list of deprecated names,
+    /// or `*` for all of them.
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let disabled: HashSet<String> = config
+            .list("RPC_DISABLED_METHODS", &[])
+            .into_iter()
+            .collect();
+        Self {
+            deprecate_unversioned: config.get("RPC_DEPRECATE_UNVERSIONED", false),
+            disable_all: disabled.contains("*"),
+            disabled,
+        }
+    }
+
+    /// Maps a requested name to the method `process_request` handles.
+    /// Deprecated names are logged and counted, or rejected with -32062 when
+    /// disabled.
+    pub(crate) fn resolve(
+        &self,
+        requested: &str,
+        ctx: &RequestContext,
+        metrics: &AppMetrics,
+    ) -> Result<String, RpcMethodError> {
+        let method = canonical(requested);
+        let Some(replacement) = self.replacement(requested, method) else {
+            return Ok(method.to_string());
+        };
+        if self.disable_all || self.disabled.contains(requested) {
+            return Err(RpcMethodError::new(
+                ErrorCode::MethodDisabled,
+                "method disabled",
+                Some(json!({ "method": requested, "replacement": replacement })),
+            ));
+        }
+        metrics.deprecated_call(requested);
+        warn!(
+            method = requested,
+            replacement = %replacement,
+            user_id = ctx.user_id,
+            "deprecated rpc method called"
+        );
+        Ok(method.to_string())
+    }
+
+    fn replacement(&self, requested: &str, method: &str) -> Option<String> {
+        let unversioned = strip_version(requested);
+        if let Some((_, replacement)) = DEPRECATED.iter().find(|(name, _)| *name == unversioned) {
+            return Some(versioned(replacement));
+        }
+        let bare = unversioned.len() == requested.len();
+        (self.deprecate_unversioned && bare && openrpc::has_method(method))
+            .then(|| versioned(method))
+    }
+}
+
+/// The handler name for `method`, independent of configuration.
+pub(crate) fn canonical(method: &str) -> &str {
+    let method = strip_version(method);
+    DEPRECATED
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, replacement)| *replacement)
+        .unwrap_or(method)
+}
+
+fn strip_version(method: &str) -> &str {
+    method
+        .strip_prefix(CURRENT_VERSION)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .unwrap_or(method)
+}
+
+/// `method` under the current version, for internal callers such as the REST
+/// and gRPC facades.
+pub(crate) fn versioned(method: &str) -> String {
+    format!("{CURRENT_VERSION}.{method}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Role;
+
+    fn ctx() -> RequestContext {
+        RequestContext {
+            user_id: 1,
+            username: "dev".to_string(),
+            role: Role::Developer,
+            tenant_id: crate::tenant::DEFAULT_TENANT,
+            permissions: Default::default(),
+            token_balance: 0,
+            api_key_id: None,
+            client_ip: None,
+            request_id: uuid::Uuid::new_v4(),
+            trace_parent: opentelemetry::Context::new(),
+            credentials: Default::default(),
+        }
+    }
+
+    #[test]
+    fn resolves_versions_and_deprecated_aliases() {
+        let metrics = AppMetrics::default();
+        let config = VersionConfig::default();
+        assert_eq!(canonical("v1.fs.read"), "fs.read");
+        assert_eq!(canonical("v1.llm.completions"), "llm.completion");
+        assert_eq!(canonical("v2.fs.read"), "v2.fs.read");
+        assert_eq!(
+            config.resolve("fs.read", &ctx(), &metrics).unwrap(),
+            "fs.read"
+        );
+        assert_eq!(
+            config.resolve("llm.completions", &ctx(), &metrics).unwrap(),
+            "llm.completion"
+        );
+        assert!(metrics
+            .render(false)
+            .contains("api_rpc_deprecated_calls_total{method=\"llm.completions\"} 1"));
+
+        let strict = VersionConfig {
+            deprecate_unversioned: true,
+            disabled: HashSet::from(["fs.read".to_string()]),
+            disable_all: false,
+        };
+        assert_eq!(
+            strict.resolve("v1.fs.read", &ctx(), &metrics).unwrap(),
+            "fs.read"
+        );
+        let err = strict.resolve("fs.read", &ctx(), &metrics).unwrap_err();
+        assert_eq!(err.code, -32062);
+        assert_eq!(err.data.unwrap()["replacement"], "v1.fs.read");
+        assert!(strict.resolve("fs.list", &ctx(), &metrics).is_ok());
+    }
+}
@@ -0,0 +1,893 @@
+//! Outgoing webhooks. Domain events (see `events`) of the kinds in
+//! [`EVENT_KINDS`] are stored once in the `events` table together with a
+//! delivery for each of the owner's webhooks whose filters match and a
+//! `webhook.deliver` job (see `jobs`). The job posts the event signed with
+//! the webhook secret; the queue takes care of leasing, retries with
+//! backoff and giving up after `max_attempts`. `webhook_deliveries` keeps
+//! the per-webhook outcome shown by `webhook.list`; a delivery whose job
+//! dies or is cancelled is marked failed, and pending deliveries without a
+//! job (left by versions that retried deliveries themselves) are queued at
+//! startup.
+//!
+//! Target hosts are checked twice: literal addresses when the webhook is
+//! created, and every address the host name resolves to right before each
+//! delivery, which then connects to exactly those addresses.
+//!
+//! Secrets are needed in clear to sign, so they are sealed rather than
+//! hashed: AES-256-GCM under `WEBHOOK_SECRET_KEY`, bound to the webhook id.
+//! Without the key `webhook.create` is refused; secrets stored in clear by
+//! earlier versions are sealed at startup once it is set.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hex::encode as hex_encode;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::{Client, Url};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::{PgPool, Row};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::events::{DomainEvent, EventRecorder};
+use crate::jobs::{ClaimedJob, JobError, Jobs};
+use crate::{RequestContext, RpcMethodError};
+
+/// The domain event kinds delivered to webhooks. Filters are either one of
+/// these, `*`, or a prefix such as `project.*`.
+pub(crate) const EVENT_KINDS: &[&str] = &[
+    "project.created",
+    "project.updated",
+    "project.deleted",
+    "project.run",
+    "project.file.save",
+    "project.file.restore",
+    "project.file.delete",
+    "project.imported",
+    "run.completed",
+    "micro.started",
+    "micro.stopped",
+    "agent.task.pending",
+    "agent.task.running",
+    "agent.task.waiting_for_input",
+    "agent.task.completed",
+    "agent.task.failed",
+    "agent.task.cancelled",
+];
+
+const MIN_SECRET_CHARS: usize = 16;
+const MAX_SECRET_CHARS: usize = 256;
+const MAX_URL_CHARS: usize = 2048;
+/// Prefix of sealed secrets: `v1:<hex nonce and ciphertext>`.
+const SEALED_PREFIX: &str = "v1:";
+/// Transaction-level advisory lock for queueing orphaned deliveries
+/// ("coderwhk").
+const ORPHAN_LOCK: i64 = 0x636f_6465_7277_686b;
+
+/// SQL twin of `filter_matches`, evaluated against `webhooks w` with the
+/// event kind bound as `$2`.
+const MATCHING_FILTER: &str = "EXISTS (SELECT 1 FROM unnest(w.events) AS f \
+     WHERE f = '*' OR f = $2 OR (right(f, 2) = '.*' AND starts_with($2, left(f, -1))))";
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WebhookConfig {
+    max_per_user: i64,
+    /// Permits plain http and private addresses, for local development.
+    allow_insecure: bool,
+    max_attempts: i32,
+    timeout: Duration,
+    secret_key: Option<SecretKey>,
+}
+
+impl WebhookConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let secret_key =
+            config
+                .secret("WEBHOOK_SECRET_KEY")
+                .and_then(|raw| match SecretKey::parse(&raw) {
+                    Some(key) => Some(key),
+                    None => {
+                        config.invalid("WEBHOOK_SECRET_KEY", "must be 64 hex characters");
+                        None
+                    }
+                });
+        Self {
+            max_per_user: config.get("WEBHOOK_MAX_PER_USER", 20).max(1),
+            allow_insecure: config.get("WEBHOOK_ALLOW_INSECURE", false),
+            max_attempts: config.get("WEBHOOK_MAX_ATTEMPTS", 8).max(1),
+            timeout: config
+                .secs("WEBHOOK_TIMEOUT_SECS", 10)
+                .max(Duration::from_secs(1)),
+            secret_key,
+        }
+    }
+}
+
+/// The 256-bit key webhook secrets are sealed with.
+#[derive(Clone, Copy)]
+struct SecretKey([u8; 32]);
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl SecretKey {
+    fn parse(raw: &str) -> Option<Self> {
+        hex::decode(raw.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Self)
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("256-bit key"))
+    }
+
+    fn seal(&self, webhook_id: &Uuid, secret: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = secret.as_bytes().to_vec();
+        self.aead()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(webhook_id.as_bytes()),
+                &mut sealed,
+            )
+            .expect("secret fits one message");
+        format!("{SEALED_PREFIX}{}{}", hex_encode(nonce), hex_encode(sealed))
+    }
+
+    fn open(&self, webhook_id: &Uuid, stored: &str) -> Option<String> {
+        let bytes = hex::decode(stored.strip_prefix(SEALED_PREFIX)?).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let secret = self
+            .aead()
+            .open_in_place(nonce, Aad::from(webhook_id.as_bytes()), &mut sealed)
+            .ok()?;
+        String::from_utf8(secret.to_vec()).ok()
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct WebhookCreateParams {
+    url: String,
+    events: Vec<String>,
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct WebhookIdParams {
+    webhook_id: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct Webhooks {
+    pool: PgPool,
+    config: WebhookConfig,
+    client: Client,
+    jobs: Jobs,
+}
+
+impl Webhooks {
+    pub(crate) fn new(pool: PgPool, config: WebhookConfig, jobs: Jobs) -> anyhow::Result<Self> {
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            pool,
+            config,
+            client,
+            jobs,
+        })
+    }
+
+    /// Records `kind` for `user_id` and queues deliveries in the background;
+    /// failures are logged and never reach the caller. Nothing is stored
+    /// unless one of the user's webhooks subscribes to `kind`.
+    fn emit(&self, user_id: i32, kind: &str, data: Value) {
+        let pool = self.pool.clone();
+        let jobs = self.jobs.clone();
+        let max_attempts = self.config.max_attempts;
+        let kind = kind.to_string();
+        tokio::spawn(async move {
+            let query = format!(
+                "WITH event AS ( \
+                    INSERT INTO events (user_id, kind, payload) SELECT $1, $2, $3 \
+                    WHERE EXISTS (SELECT 1 FROM webhooks w WHERE w.user_id = $1 AND {MATCHING_FILTER}) \
+                    RETURNING id \
+                 ), delivery AS ( \
+                    INSERT INTO webhook_deliveries (webhook_id, event_id) \
+                    SELECT w.id, event.id FROM webhooks w, event \
+                    WHERE w.user_id = $1 AND {MATCHING_FILTER} RETURNING id \
+                 ) \
+                 INSERT INTO jobs (kind, user_id, payload, max_attempts) \
+                 SELECT 'webhook.deliver', $1, jsonb_build_object('delivery_id', delivery.id), $4 \
+                 FROM delivery"
+            );
+            match sqlx::query(&query)
+                .bind(user_id)
+                .bind(&kind)
+                .bind(sqlx::types::Json(data))
+                .bind(max_attempts)
+                .execute(&pool)
+                .await
+            {
+                Ok(done) if done.rows_affected() > 0 => jobs.wake(),
+                Ok(_) => {}
+                Err(err) => warn!(kind = %kind, user_id, error = %err, "failed to record event"),
+            }
+        });
+    }
+
+    pub(crate) async fn create(
+        &self,
+        ctx: &RequestContext,
+        params: WebhookCreateParams,
+    ) -> Result<Value, RpcMethodError> {
+        let Some(key) = self.config.secret_key else {
+            return Err(RpcMethodError::new(
+                ErrorCode::MethodDisabled,
+                "method disabled",
+                Some(json!({ "detail": "WEBHOOK_SECRET_KEY is not set" })),
+            ));
+        };
+        let url = validate_url(&params.url, self.config.allow_insecure)?;
+        let events = validate_filters(params.events)?;
+        let secret = match params.secret {
+            Some(secret) => validate_secret(secret)?,
+            None => generate_secret(),
+        };
+        let webhook_id = Uuid::new_v4();
+        let row = sqlx::query(
+            "WITH existing AS ( \
+                SELECT COUNT(*) AS count FROM webhooks WHERE user_id = $1 \
+             ) \
+             INSERT INTO webhooks (id, user_id, url, secret, events) \
+             SELECT $6, $1, $2, $3, $4 FROM existing WHERE existing.count < $5 \
+             RETURNING id, url, events, created_at",
+        )
+        .bind(ctx.user_id)
+        .bind(url.as_str())
+        .bind(key.seal(&webhook_id, &secret))
+        .bind(&events)
+        .bind(self.config.max_per_user)
+        .bind(webhook_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to create webhook: {err}")))?;
+        let row = row.ok_or_else(|| {
+            RpcMethodError::new(
+                ErrorCode::WebhookLimit,
+                "webhook limit reached",
+                Some(json!({ "max_per_user": self.config.max_per_user })),
+            )
+        })?;
+        // The secret is only ever returned here.
+        let mut value = webhook_value(&row);
+        value["secret"] = Value::String(secret);
+        Ok(value)
+    }
+
+    pub(crate) async fn list(&self, ctx: &RequestContext) -> Result<Value, RpcMethodError> {
+        let rows = sqlx::query(
+            "SELECT w.id, w.url, w.events, w.created_at, \
+                    COUNT(d.id) FILTER (WHERE d.status = 'pending') AS pending, \
+                    COUNT(d.id) FILTER (WHERE d.status = 'failed') AS failed, \
+                    MAX(d.delivered_at) AS last_delivered_at \
+             FROM webhooks w LEFT JOIN webhook_deliveries d ON d.webhook_id = w.id \
+             WHERE w.user_id = $1 GROUP BY w.id ORDER BY w.created_at DESC",
+        )
+        .bind(ctx.user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to list webhooks: {err}")))?;
+        let webhooks: Vec<Value> = rows
+            .iter()
+            .map(|row| {
+                let mut value = webhook_value(row);
+                value["deliveries"] = json!({
+                    "pending": row.get::<i64, _>("pending"),
+                    "failed": row.get::<i64, _>("failed"),
+                    "last_delivered_at": row
+                        .get::<Option<DateTime<Utc>>, _>("last_delivered_at")
+                        .map(|at| at.to_rfc3339()),
+                });
+                value
+            })
+            .collect();
+        Ok(json!({ "webhooks": webhooks }))
+    }
+
+    pub(crate) async fn delete(
+        &self,
+        ctx: &RequestContext,
+        params: WebhookIdParams,
+    ) -> Result<Value, RpcMethodError> {
+        let webhook_id = Uuid::parse_str(&params.webhook_id).map_err(|err| {
+            RpcMethodError::new(
+                ErrorCode::InvalidParams,
+                "invalid webhook identifier",
+                Some(json!({ "detail": err.to_string() })),
+            )
+        })?;
+        let deleted = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND user_id = $2")
+            .bind(webhook_id)
+            .bind(ctx.user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| RpcMethodError::internal(&format!("failed to delete webhook: {err}")))?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(RpcMethodError::new(
+                ErrorCode::WebhookNotFound,
+                "webhook not found",
+                None,
+            ));
+        }
+        Ok(json!({ "status": "ok" }))
+    }
+
+    /// Seals secrets still stored in clear. Does nothing without a key.
+    pub(crate) async fn seal_stored_secrets(&self) -> anyhow::Result<()> {
+        let Some(key) = self.config.secret_key else {
+            return Ok(());
+        };
+        let rows = sqlx::query("SELECT id, secret FROM webhooks WHERE NOT starts_with(secret, $1)")
+            .bind(SEALED_PREFIX)
+            .fetch_all(&self.pool)
+            .await?;
+        for row in &rows {
+            let webhook_id: Uuid = row.get("id");
+            let secret: String = row.get("secret");
+            sqlx::query("UPDATE webhooks SET secret = $2 WHERE id = $1 AND secret = $3")
+                .bind(webhook_id)
+                .bind(key.seal(&webhook_id, &secret))
+                .bind(&secret)
+                .execute(&self.pool)
+                .await?;
+        }
+        if !rows.is_empty() {
+            info!(count = rows.len(), "sealed webhook secrets stored in clear");
+        }
+        Ok(())
+    }
+
+    /// Queues a `webhook.deliver` job with the configured attempt budget for
+    /// every pending delivery that has none. Instances starting together
+    /// take turns, so none is queued twice.
+    pub(crate) async fn queue_orphaned_deliveries(&self) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(ORPHAN_LOCK)
+            .execute(&mut *tx)
+            .await?;
+        let queued = sqlx::query(
+            "INSERT INTO jobs (kind, user_id, payload, attempts, max_attempts, run_at) \
+             SELECT 'webhook.deliver', w.user_id, jsonb_build_object('delivery_id', d.id), \
+                    LEAST(d.attempts, $1 - 1), $1, d.next_attempt_at \
+             FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id \
+             WHERE d.status = 'pending' AND NOT EXISTS ( \
+                SELECT 1 FROM jobs j WHERE j.kind = 'webhook.deliver' \
+                  AND j.payload->>'delivery_id' = d.id::text \
+             )",
+        )
+        .bind(self.config.max_attempts)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        if queued > 0 {
+            info!(count = queued, "queued pending webhook deliveries");
+            self.jobs.wake();
+        }
+        Ok(())
+    }
+
+    /// The signing secret of a webhook as stored by [`Webhooks::create`] or,
+    /// in clear, by earlier versions.
+    fn open_secret(&self, webhook_id: &Uuid, stored: String) -> Result<String, JobError> {
+        if !stored.starts_with(SEALED_PREFIX) {
+            return Ok(stored);
+        }
+        let key = self
+            .config
+            .secret_key
+            .ok_or_else(|| JobError::fatal("WEBHOOK_SECRET_KEY is not set"))?;
+        key.open(webhook_id, &stored)
+            .ok_or_else(|| JobError::fatal("webhook secret does not open with WEBHOOK_SECRET_KEY"))
+    }
+
+    /// Runs a `webhook.deliver` job. Deliveries whose webhook or event is
+    /// gone by now are skipped.
+    pub(crate) async fn deliver(&self, job: &ClaimedJob) -> Result<Value, JobError> {
+        let delivery_id = job.payload["delivery_id"]
+            .as_i64()
+            .ok_or_else(|| JobError::fatal("job payload has no delivery_id"))?;
+        let row = sqlx::query(
+            "SELECT d.id, d.status, w.id AS webhook_id, w.url, w.secret, e.id AS event_id, \
+                    e.kind, e.payload, \
+                    e.created_at \
+             FROM webhook_deliveries d \
+             JOIN webhooks w ON w.id = d.webhook_id \
+             JOIN events e ON e.id = d.event_id \
+             WHERE d.id = $1",
+        )
+        .bind(delivery_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(JobError::retry)?;
+        // Failed deliveries run again when their dead job is retried.
+        let Some(row) = row.filter(|row| row.get::<String, _>("status") != "delivered") else {
+            return Ok(json!({ "delivery_id": delivery_id, "skipped": true }));
+        };
+        let delivery = Delivery {
+            id: row.get("id"),
+            url: row.get("url"),
+            secret: self.open_secret(&row.get("webhook_id"), row.get("secret"))?,
+            body: json!({
+                "id": row.get::<i64, _>("event_id"),
+                "type": row.get::<String, _>("kind"),
+                "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+                "data": row.get::<sqlx::types::Json<Value>, _>("payload").0,
+            }),
+        };
+        let (response_status, error) = match self.post(&delivery).await {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16() as i32), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16() as i32),
+                Some(format!("endpoint responded with {}", response.status())),
+            ),
+            Err(err) => (None, Some(err)),
+        };
+        let status = match &error {
+            None => "delivered",
+            Some(error) => {
+                debug!(
+                    delivery = delivery.id,
+                    attempts = job.attempts,
+                    error = %error,
+                    "webhook delivery failed"
+                );
+                if job.is_last_attempt() {
+                    "failed"
+                } else {
+                    "pending"
+                }
+            }
+        };
+        sqlx::query(
+            "UPDATE webhook_deliveries SET status = $2, attempts = $3, response_status = $4, \
+                last_error = $5, delivered_at = CASE WHEN $2 = 'delivered' THEN NOW() END \
+             WHERE id = $1",
+        )
+        .bind(delivery.id)
+        .bind(status)
+        .bind(job.attempts)
+        .bind(response_status)
+        .bind(&error)
+        .execute(&self.pool)
+        .await
+        .map_err(JobError::retry)?;
+        match error {
+            None => Ok(json!({ "delivery_id": delivery.id, "response_status": response_status })),
+            Some(error) => Err(JobError::Retry(error)),
+        }
+    }
+}
+
+/// Marks the delivery of `webhook.deliver` job `job_id` failed if the job
+/// died or was cancelled while the delivery was still pending. Safe to call
+/// more than once, and after a cancelled attempt recorded its outcome.
+pub(crate) async fn abandon_delivery(
+    pool: &PgPool,
+    job_id: i64,
+    error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE webhook_deliveries SET status = 'failed', last_error = $2 \
+         WHERE status = 'pending' AND id = ( \
+            SELECT (payload->>'delivery_id')::BIGINT FROM jobs \
+            WHERE id = $1 AND kind = 'webhook.deliver' AND status IN ('dead', 'cancelled') \
+         )",
+    )
+    .bind(job_id)
+    .bind(error)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Records every domain event of a kind in [`EVENT_KINDS`] for the user it
+/// belongs to.
+impl EventRecorder for Webhooks {
+    fn record(&self, event: &DomainEvent) {
+        let kind = event.kind();
+        if !EVENT_KINDS.contains(&kind.as_str()) {
+            return;
+        }
+        if let Some(user_id) = event.user_id() {
+            self.emit(user_id, &kind, event.data());
+        }
+    }
+}
+
+impl Webhooks {
+    async fn post(&self, delivery: &Delivery) -> Result<reqwest::Response, String> {
+        let url = Url::parse(&delivery.url).map_err(|err| err.to_string())?;
+        let client = self.client_for(&url).await?;
+        let body = delivery.body.to_string();
+        let timestamp = Utc::now().timestamp();
+        client
+            .post(url)
+            .header("content-type", "application/json")
+            .header(
+                "x-webhook-event",
+                delivery.body["type"].as_str().unwrap_or(""),
+            )
+            .header("x-webhook-id", delivery.body["id"].to_string())
+            .header("x-webhook-timestamp", timestamp.to_string())
+            .header(
+                "x-webhook-signature",
+                format!("sha256={}", sign(&delivery.secret, timestamp, &body)),
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// A client that may only reach public addresses of `url`'s host. Names
+    /// are resolved here and the client is pinned to the checked addresses,
+    /// so a second lookup by the connector cannot swap in another one.
+    async fn client_for(&self, url: &Url) -> Result<Client, String> {
+        if self.config.allow_insecure {
+            return Ok(self.client.clone());
+        }
+        let port = url.port_or_known_default().unwrap_or(443);
+        let host = url
+            .host_str()
+            .ok_or_else(|| "webhook url has no host".to_string())?;
+        let domain = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = domain.parse::<IpAddr>() {
+            return self.public(&[SocketAddr::new(ip, port)]);
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|err| format!("failed to resolve {domain}: {err}"))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("{domain} has no addresses"));
+        }
+        self.public(&addrs)?;
+        Client::builder()
+            .timeout(self.config.timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_to_addrs(domain, &addrs)
+            .build()
+            .map_err(|err| err.to_string())
+    }
+
+    fn public(&self, addrs: &[SocketAddr]) -> Result<Client, String> {
+        match addrs.iter().find(|addr| is_internal(addr.ip())) {
+            Some(addr) => Err(format!("{} is an internal address", addr.ip())),
+            None => Ok(self.client.clone()),
+        }
+    }
+}
+
+struct Delivery {
+    id: i64,
+    url: String,
+    secret: String,
+    body: Value,
+}
+
+fn webhook_value(row: &sqlx::postgres::PgRow) -> Value {
+    json!({
+        "webhook_id": row.get::<Uuid, _>("id"),
+        "url": row.get::<String, _>("url"),
+        "events": row.get::<Vec<String>, _>("events"),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+    })
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`. Receivers recompute it and reject
+/// stale timestamps to guard against replays.
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex_encode(mac.finalize().into_bytes())
+}
+
+fn filter_matches(filter: &str, kind: &str) -> bool {
+    filter == "*"
+        || filter == kind
+        || filter
+            .strip_suffix('*')
+            .is_some_and(|prefix| prefix.ends_with('.') && kind.starts_with(prefix))
+}
+
+fn invalid(message: &str, detail: impl Into<Value>) -> RpcMethodError {
+    RpcMethodError::new(
+        ErrorCode::InvalidParams,
+        message,
+        Some(json!({ "detail": detail.into() })),
+    )
+}
+
+fn validate_filters(filters: Vec<String>) -> Result<Vec<String>, RpcMethodError> {
+    let mut filters: Vec<String> = filters
+        .into_iter()
+        .map(|filter| filter.trim().to_string())
+        .collect();
+    filters.sort();
+    filters.dedup();
+    if filters.is_empty() {
+        return Err(invalid(
+            "invalid webhook events",
+            "at least one event is required",
+        ));
+    }
+    if let Some(unknown) = filters
+        .iter()
+        .find(|filter| !EVENT_KINDS.iter().any(|kind| filter_matches(filter, kind)))
+    {
+        return Err(RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid webhook events",
+            Some(json!({ "detail": format!("unknown event {unknown}"), "events": EVENT_KINDS })),
+        ));
+    }
+    Ok(filters)
+}
+
+fn validate_secret(secret: String) -> Result<String, RpcMethodError> {
+    let chars = secret.chars().count();
+    if !(MIN_SECRET_CHARS..=MAX_SECRET_CHARS).contains(&chars) {
+        return Err(invalid(
+            "invalid webhook secret",
+            format!("secret must be {MIN_SECRET_CHARS} to {MAX_SECRET_CHARS} characters"),
+        ));
+    }
+    Ok(secret)
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex_encode(bytes)
+}
+
+/// Requires https and refuses loopback, private and link-local targets unless
+/// `allow_insecure` is set. Host names are not resolved here.
+fn validate_url(raw: &str, allow_insecure: bool) -> Result<Url, RpcMethodError> {
+    if raw.len() > MAX_URL_CHARS {
+        return Err(invalid("invalid webhook url", "url is too long"));
+    }
+    let url =
+        Url::parse(raw.trim()).map_err(|err| invalid("invalid webhook url", err.to_string()))?;
+    match url.scheme() {
+        "https" => {}
+        "http" if allow_insecure => {}
+        scheme => {
+            return Err(invalid(
+                "invalid webhook url",
+                format!("unsupported scheme {scheme}"),
+            ))
+        }
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(invalid(
+            "invalid webhook url",
+            "credentials are not allowed",
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("invalid webhook url", "missing host"))?;
+    if allow_insecure {
+        return Ok(url);
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let internal = match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal(ip),
+        Err(_) => host.eq_ignore_ascii_case("localhost") || host.ends_with(".localhost"),
+    };
+    if internal {
+        return Err(invalid(
+            "invalid webhook url",
+            "internal addresses are not allowed",
+        ));
+    }
+    Ok(url)
+}
+
+/// Anything but a public unicast address. IPv4 addresses embedded in IPv6
+/// (mapped `::ffff:0:0/96` and NAT64 `64:ff9b::/96`) are judged as IPv4.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => is_internal_v6(ip),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network" (0.0.0.0/8) and reserved (240.0.0.0/4).
+        || a == 0
+        || a >= 240
+        // Shared address space for carrier-grade NAT (100.64.0.0/10).
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments (192.0.0.0/24) and benchmarking
+        // (198.18.0.0/15).
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return is_internal_v4(mapped);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_internal_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local (fc00::/7), link-local (fe80::/10) and the deprecated
+        // site-local (fec0::/10).
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobConfig;
+
+    #[test]
+    fn filters_validate_against_known_kinds() {
+        assert!(filter_matches("*", "run.completed"));
+        assert!(filter_matches("project.*", "project.file.save"));
+        assert!(!filter_matches("project*", "project.created"));
+        assert!(!filter_matches("agent.task.*", "project.run"));
+        let filters = validate_filters(vec![
+            "agent.task.*".to_string(),
+            " run.completed".to_string(),
+            "run.completed".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(filters, vec!["agent.task.*", "run.completed"]);
+        assert!(validate_filters(vec!["billing.*".to_string()]).is_err());
+        assert!(validate_filters(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn urls_must_be_public_https() {
+        assert!(validate_url("https://hooks.example.com/coder", false).is_ok());
+        assert!(validate_url("http://hooks.example.com/coder", false).is_err());
+        assert!(validate_url("https://127.0.0.1/hook", false).is_err());
+        assert!(validate_url("https://10.1.2.3/hook", false).is_err());
+        assert!(validate_url("https://[::1]/hook", false).is_err());
+        assert!(validate_url("https://localhost:8443/hook", false).is_err());
+        assert!(validate_url("https://user:pw@example.com/hook", false).is_err());
+        assert!(validate_url("http://localhost:9000/hook", true).is_ok());
+        assert!(validate_url("https://100.64.0.1/hook", false).is_err());
+        assert!(validate_url("https://[::ffff:10.0.0.1]/hook", false).is_err());
+    }
+
+    #[test]
+    fn internal_addresses_include_embedded_and_shared_ranges() {
+        for internal in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "100.64.0.1",
+            "100.127.255.254",
+            "169.254.169.254",
+            "0.1.2.3",
+            "198.18.0.1",
+            "::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:192.168.1.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(is_internal(internal.parse().unwrap()), "{internal}");
+        }
+        for public in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:2800:220:1::1",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!is_internal(public.parse().unwrap()), "{public}");
+        }
+    }
+
+    #[tokio::test]
+    async fn deliveries_refuse_names_resolving_to_internal_addresses() {
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let config = WebhookConfig {
+            max_per_user: 1,
+            allow_insecure: false,
+            max_attempts: 1,
+            timeout: Duration::from_secs(1),
+            secret_key: None,
+        };
+        let settings = Config::new(None, None, Box::new(|_| None)).unwrap();
+        let jobs = Jobs::new(pool.clone(), JobConfig::from_config(&settings));
+        let webhooks = Webhooks::new(pool, config, jobs).unwrap();
+        let url = Url::parse("https://localhost/hook").unwrap();
+        let err = webhooks.client_for(&url).await.unwrap_err();
+        assert!(err.contains("internal address"), "{err}");
+        let url = Url::parse("https://[::ffff:127.0.0.1]/hook").unwrap();
+        assert!(webhooks.client_for(&url).await.is_err());
+    }
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("whsec-0123456789abcdef", 1_700_000_000, "{}");
+        assert_eq!(signature.len(), 64);
+        assert_eq!(
+            signature,
+            sign("whsec-0123456789abcdef", 1_700_000_000, "{}")
+        );
+        assert_ne!(
+            signature,
+            sign("whsec-0123456789abcdef", 1_700_000_001, "{}")
+        );
+        assert_eq!(generate_secret().len(), 64);
+    }
+
+    #[test]
+    fn secrets_are_sealed_to_their_webhook() {
+        assert!(SecretKey::parse("00ff").is_none());
+        let key = SecretKey::parse(&"ab".repeat(32)).unwrap();
+        let (webhook, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let sealed = key.seal(&webhook, "whsec-0123456789abcdef");
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert!(!sealed.contains("whsec"));
+        assert_ne!(sealed, key.seal(&webhook, "whsec-0123456789abcdef"));
+        assert_eq!(
+            key.open(&webhook, &sealed).as_deref(),
+            Some("whsec-0123456789abcdef")
+        );
+        assert_eq!(key.open(&other, &sealed), None);
+        let wrong = SecretKey::parse(&"cd".repeat(32)).unwrap();
+        assert_eq!(wrong.open(&webhook, &sealed), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Ephemeral scratch workspaces. Each session owns `workspaces/<id>` below its
+//! owner's tenant directory, and fs/run/micro calls that pass `workspace_id` are confined
+//! to it. Sessions expire after their TTL; a background reaper removes the
+//! directory after the sandbox engines cleaned up inside it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sandbox::SandboxFs;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Row};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::engine::Engines;
+use crate::errors::ErrorCode;
+use crate::{tenant, RequestContext, RpcMethodError};
+
+const MIN_TTL: Duration = Duration::from_secs(60);
+const MAX_LABEL_CHARS: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WorkspaceConfig {
+    default_ttl: Duration,
+    max_ttl: Duration,
+    max_per_user: i64,
+    reap_interval: Duration,
+}
+
+impl WorkspaceConfig {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        let default_ttl = config.secs("WORKSPACE_DEFAULT_TTL_SECS", 3600);
+        let max_ttl = config
+            .secs("WORKSPACE_MAX_TTL_SECS", 24 * 3600)
+            .max(MIN_TTL);
+        Self {
+            default_ttl: default_ttl.clamp(MIN_TTL, max_ttl),
+            max_ttl,
+            max_per_user: config.get("WORKSPACE_MAX_PER_USER", 5).max(1),
+            reap_interval: config
+                .secs("WORKSPACE_REAP_INTERVAL_SECS", 60)
+                .max(Duration::from_secs(1)),
+        }
+    }
+
+    fn ttl(&self, requested: Option<u64>) -> Duration {
+        requested
+            .map(Duration::from_secs)
+            .unwrap_or(self.default_ttl)
+            .clamp(MIN_TTL, self.max_ttl)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct WorkspaceCreateParams {
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct WorkspaceIdParams {
+    workspace_id: String,
+}
+
+pub(crate) fn directory_relative(tenant_id: i32, workspace_id: &Uuid) -> PathBuf {
+    tenant::directory_relative(tenant_id)
+        .join("workspaces")
+        .join(workspace_id.to_string())
+}
+
+fn parse_workspace_id(value: &str) -> Result<Uuid, RpcMethodError> {
+    Uuid::parse_str(value).map_err(|err| {
+        RpcMethodError::new(
+            ErrorCode::InvalidParams,
+            "invalid workspace identifier",
+            Some(json!({ "detail": err.to_string() })),
+        )
+    })
+}
+
+fn not_found() -> RpcMethodError {
+    RpcMethodError::new(ErrorCode::WorkspaceNotFound, "workspace not found", None)
+}
+
+/// Resolves an active workspace the caller may use to its sandbox directory.
+/// Expired sessions and sessions of other tenants are treated as missing.
+pub(crate) async fn resolve(
+    pool: &PgPool,
+    ctx: &RequestContext,
+    workspace_id: &str,
+) -> Result<PathBuf, RpcMethodError> {
+    let workspace_id = parse_workspace_id(workspace_id)?;
+    let owner: Option<i32> = sqlx::query_scalar(
+        "SELECT w.user_id FROM workspace_sessions w JOIN users u ON u.id = w.user_id \
+         WHERE w.id = $1 AND w.expires_at > NOW() AND u.tenant_id = $2",
+    )
+    .bind(workspace_id)
+    .bind(ctx.tenant_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to load workspace: {err}")))?;
+    match owner {
+        Some(owner) if owner == ctx.user_id || ctx.is_admin() => {
+            Ok(directory_relative(ctx.tenant_id, &workspace_id))
+        }
+        Some(_) => Err(RpcMethodError::forbidden("workspace access denied")),
+        None => Err(not_found()),
+    }
+}
+
+pub(crate) async fn create(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    config: &WorkspaceConfig,
+    ctx: &RequestContext,
+    params: WorkspaceCreateParams,
+) -> Result<Value, RpcMethodError> {
+    let label = params
+        .label
+        .map(|label| {
+            label
+                .trim()
+                .chars()
+                .take(MAX_LABEL_CHARS)
+                .collect::<String>()
+        })
+        .filter(|label| !label.is_empty());
+    let ttl = config.ttl(params.ttl_secs);
+    let row = sqlx::query(
+        "WITH active AS ( \
+            SELECT COUNT(*) AS count FROM workspace_sessions \
+            WHERE user_id = $1 AND expires_at > NOW() \
+         ) \
+         INSERT INTO workspace_sessions (user_id, label, expires_at) \
+         SELECT $1, $2, NOW() + make_interval(secs => $3) FROM active WHERE active.count < $4 \
+         RETURNING id, label, created_at, expires_at",
+    )
+    .bind(ctx.user_id)
+    .bind(&label)
+    .bind(ttl.as_secs_f64())
+    .bind(config.max_per_user)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to create workspace: {err}")))?;
+    let row = row.ok_or_else(|| {
+        RpcMethodError::new(
+            ErrorCode::WorkspaceLimit,
+            "workspace limit reached",
+            Some(json!({ "max_per_user": config.max_per_user })),
+        )
+    })?;
+
+    let workspace_id: Uuid = row.get("id");
+    if let Err(err) = sandbox.mkdir(directory_relative(ctx.tenant_id, &workspace_id)) {
+        let _ = sqlx::query("DELETE FROM workspace_sessions WHERE id = $1")
+            .bind(workspace_id)
+            .execute(pool)
+            .await;
+        return Err(RpcMethodError::from_sandbox(
+            ErrorCode::SandboxScope,
+            "failed to prepare sandbox scope",
+            err,
+        ));
+    }
+    Ok(session_value(&row))
+}
+
+pub(crate) async fn list(pool: &PgPool, ctx: &RequestContext) -> Result<Value, RpcMethodError> {
+    let rows = sqlx::query(
+        "SELECT id, label, created_at, expires_at FROM workspace_sessions \
+         WHERE user_id = $1 AND expires_at > NOW() ORDER BY created_at DESC",
+    )
+    .bind(ctx.user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| RpcMethodError::internal(&format!("failed to list workspaces: {err}")))?;
+    let workspaces: Vec<Value> = rows.iter().map(session_value).collect();
+    Ok(json!({ "workspaces": workspaces }))
+}
+
+pub(crate) async fn destroy(
+    pool: &PgPool,
+    sandbox: &SandboxFs,
+    engines: &Engines,
+    ctx: &RequestContext,
+    params: WorkspaceIdParams,
+) -> Result<Value, RpcMethodError> {
+    let scope = resolve(pool, ctx, &params.workspace_id).await?;
+    let workspace_id = parse_workspace_id(&params.workspace_id)?;
+    sqlx::query("DELETE FROM workspace_sessions WHERE id = $1")
+        .bind(workspace_id)
+        .execute(pool)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to delete workspace: {err}")))?;
+    let stopped_vms = teardown(sandbox, engines, &scope)
+        .await
+        .map_err(|err| RpcMethodError::internal(&format!("failed to remove workspace: {err}")))?;
+    Ok(json!({ "status": "ok", "stopped_vms": stopped_vms }))
+}
+
+fn session_value(row: &sqlx::postgres::PgRow) -> Value {
+    json!({
+        "workspace_id": row.get::<Uuid, _>("id"),
+        "label": row.get::<Option<String>, _>("label"),
+        "created_at": row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        "expires_at": row.get::<DateTime<Utc>, _>("expires_at").to_rfc3339(),
+    })
+}
+
+async fn teardown(sandbox: &SandboxFs, engines: &Engines, scope: &Path) -> sandbox::Result<usize> {
+    let stopped = engines.cleanup(scope).await?;
+    sandbox.delete(scope)?;
+    Ok(stopped)
+}
+
+/// Periodically deletes expired sessions and their directories. Rows are
+/// removed first so a session can never be resolved while it is torn down.
+pub(crate) fn spawn_reaper(
+    pool: PgPool,
+    sandbox: Arc<SandboxFs>,
+    engines: Engines,
+    config: WorkspaceConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.reap_interval);
+        loop {
+            ticker.tick().await;
+            let expired: Vec<(Uuid, i32)> = match sqlx::query_as(
+                "DELETE FROM workspace_sessions w USING users u \
+                 WHERE u.id = w.user_id AND w.expires_at <= NOW() RETURNING w.id, u.tenant_id",
+            )
+            .fetch_all(&pool)
+            .await
+            {
+                Ok(expired) => expired,
+                Err(err) => {
+                    warn!(error = %err, "failed to reap expired workspaces");
+                    continue;
+                }
+            };
+            for (workspace_id, tenant_id) in &expired {
+                let scope = directory_relative(*tenant_id, workspace_id);
+                if let Err(err) = teardown(&sandbox, &engines, &scope).await {
+                    warn!(%workspace_id, error = %err, "failed to remove expired workspace");
+                }
+            }
+            if !expired.is_empty() {
+                info!(count = expired.len(), "reaped expired workspaces");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_is_clamped_to_configured_bounds() {
+        let config = WorkspaceConfig {
+            default_ttl: Duration::from_secs(600),
+            max_ttl: Duration::from_secs(3600),
+            max_per_user: 5,
+            reap_interval: Duration::from_secs(60),
+        };
+        assert_eq!(config.ttl(None), Duration::from_secs(600));
+        assert_eq!(config.ttl(Some(5)), MIN_TTL);
+        assert_eq!(config.ttl(Some(7200)), Duration::from_secs(3600));
+    }
+}
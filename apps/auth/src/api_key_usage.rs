@@ -0,0 +1,162 @@
+//! `GET /auth/api-keys/:id/usage`: what one of the caller's API keys has
+//! been used for, from the daily counters the API gateway keeps in
+//! `api_key_usage` (migration 028). Covers the last `days` (default 30, at
+//! most 365) with totals, a breakdown per method and a per-day series.
+
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use uuid::Uuid;
+
+use crate::{authenticate, internal, AppState, AuthError};
+
+const DEFAULT_DAYS: i32 = 30;
+const MAX_DAYS: i32 = 365;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UsageQuery {
+    #[serde(default)]
+    days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Counts {
+    requests: i64,
+    errors: i64,
+    error_rate: f64,
+    avg_latency_ms: f64,
+}
+
+impl Counts {
+    fn new(requests: i64, errors: i64, total_latency_ms: i64) -> Self {
+        let ratio = |value: i64| {
+            if requests == 0 {
+                0.0
+            } else {
+                value as f64 / requests as f64
+            }
+        };
+        Self {
+            requests,
+            errors,
+            error_rate: ratio(errors),
+            avg_latency_ms: ratio(total_latency_ms),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct MethodUsage {
+    method: String,
+    #[serde(flatten)]
+    counts: Counts,
+    last_used_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DayUsage {
+    day: NaiveDate,
+    requests: i64,
+    errors: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct KeyUsageResponse {
+    api_key_id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_used_at: Option<DateTime<Utc>>,
+    days: i32,
+    totals: Counts,
+    methods: Vec<MethodUsage>,
+    daily: Vec<DayUsage>,
+}
+
+pub(crate) async fn key_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<KeyUsageResponse>, AuthError> {
+    let user = authenticate(&headers, &state).await?;
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    if !(1..=MAX_DAYS).contains(&days) {
+        return Err(AuthError::BadRequest(format!(
+            "days must be between 1 and {MAX_DAYS}"
+        )));
+    }
+    let key = sqlx::query(
+        "SELECT name, created_at, last_used_at FROM api_keys WHERE id = $1 AND user_id = $2",
+    )
+    .bind(id)
+    .bind(user.user_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal)?
+    .ok_or_else(|| AuthError::NotFound("api key not found".to_string()))?;
+
+    // Today counts as one of the `days`.
+    let since = "CURRENT_DATE - ($2::int - 1)";
+    let methods = sqlx::query(&format!(
+        "SELECT method, SUM(requests)::BIGINT AS requests, SUM(errors)::BIGINT AS errors, \
+            SUM(total_latency_ms)::BIGINT AS total_latency_ms, MAX(last_used_at) AS last_used_at \
+         FROM api_key_usage WHERE api_key_id = $1 AND day >= {since} \
+         GROUP BY method ORDER BY requests DESC, method"
+    ))
+    .bind(id)
+    .bind(days)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+    let daily = sqlx::query(&format!(
+        "SELECT day, SUM(requests)::BIGINT AS requests, SUM(errors)::BIGINT AS errors \
+         FROM api_key_usage WHERE api_key_id = $1 AND day >= {since} \
+         GROUP BY day ORDER BY day"
+    ))
+    .bind(id)
+    .bind(days)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+
+    let (mut requests, mut errors, mut latency) = (0, 0, 0);
+    let methods = methods
+        .iter()
+        .map(|row| {
+            let (method_requests, method_errors, method_latency) = (
+                row.get::<i64, _>("requests"),
+                row.get::<i64, _>("errors"),
+                row.get::<i64, _>("total_latency_ms"),
+            );
+            requests += method_requests;
+            errors += method_errors;
+            latency += method_latency;
+            MethodUsage {
+                method: row.get("method"),
+                counts: Counts::new(method_requests, method_errors, method_latency),
+                last_used_at: row.get("last_used_at"),
+            }
+        })
+        .collect();
+    Ok(Json(KeyUsageResponse {
+        api_key_id: id,
+        name: key.get("name"),
+        created_at: key.get("created_at"),
+        last_used_at: key.get("last_used_at"),
+        days,
+        totals: Counts::new(requests, errors, latency),
+        methods,
+        daily: daily
+            .iter()
+            .map(|row| DayUsage {
+                day: row.get("day"),
+                requests: row.get("requests"),
+                errors: row.get("errors"),
+            })
+            .collect(),
+    }))
+}
@@ -0,0 +1,324 @@
+//! Token balances. Holders of `user.admin` credit or debit a user's
+//! `token_balance` with a reason code; every change is appended to
+//! `billing_ledger` (append-only since migration 025) next to the charges
+//! the API gateway books, with the same sign convention: `tokens` is what
+//! left the balance, so a credit is negative. Users read their own balance
+//! and ledger through `GET /auth/balance`; admins reach the accounts of
+//! their own tenant.
+
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::postgres::{PgConnection, PgRow};
+use sqlx::types::Json as JsonValue;
+use sqlx::Row;
+use tracing::info;
+
+use crate::users::require_admin;
+use crate::{authenticate, internal, AppState, AuthError};
+
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 500;
+const MAX_NOTE: usize = 500;
+/// Kept in sync with `billing_ledger_reason_check`.
+const REASONS: [&str; 5] = ["purchase", "grant", "refund", "chargeback", "correction"];
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    Credit,
+    Debit,
+}
+
+impl Direction {
+    fn kind(self) -> &'static str {
+        match self {
+            Direction::Credit => "credit",
+            Direction::Debit => "debit",
+        }
+    }
+
+    /// The ledger's `tokens` for a change of `amount`.
+    fn tokens(self, amount: i64) -> i64 {
+        match self {
+            Direction::Credit => -amount,
+            Direction::Debit => amount,
+        }
+    }
+}
+
+/// One ledger row for a balance change that has already been applied to
+/// `users.token_balance` in the same transaction.
+pub(crate) struct Posting<'a> {
+    pub(crate) user_id: i32,
+    pub(crate) direction: Direction,
+    pub(crate) method: String,
+    /// One of [`REASONS`].
+    pub(crate) reason: &'a str,
+    pub(crate) amount: i64,
+    pub(crate) balance_after: i64,
+    pub(crate) metadata: serde_json::Value,
+}
+
+impl Posting<'_> {
+    pub(crate) async fn insert(self, conn: &mut PgConnection) -> Result<PgRow, AuthError> {
+        sqlx::query(
+            "INSERT INTO billing_ledger \
+                (user_id, kind, reason, method, units, tokens, balance_after, metadata) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+             RETURNING id, kind, reason, method, units, tokens, balance_after, metadata, created_at",
+        )
+        .bind(self.user_id)
+        .bind(self.direction.kind())
+        .bind(self.reason)
+        .bind(&self.method)
+        .bind(self.amount)
+        .bind(self.direction.tokens(self.amount))
+        .bind(self.balance_after)
+        .bind(JsonValue(self.metadata))
+        .fetch_one(conn)
+        .await
+        .map_err(internal)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AdjustBalanceRequest {
+    amount: i64,
+    reason: String,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LedgerQuery {
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LedgerEntry {
+    id: i64,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    method: String,
+    units: i64,
+    tokens: i64,
+    balance_after: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BalanceResponse {
+    user_id: i32,
+    balance: i64,
+    entries: Vec<LedgerEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<i64>,
+}
+
+fn not_found() -> AuthError {
+    AuthError::NotFound("user not found".to_string())
+}
+
+fn entry(row: &PgRow) -> LedgerEntry {
+    let metadata: Option<JsonValue<serde_json::Value>> = row.get("metadata");
+    LedgerEntry {
+        id: row.get("id"),
+        kind: row.get("kind"),
+        reason: row.get("reason"),
+        method: row.get("method"),
+        units: row.get("units"),
+        tokens: row.get("tokens"),
+        balance_after: row.get("balance_after"),
+        note: metadata.and_then(|JsonValue(value)| value.get("note")?.as_str().map(str::to_string)),
+        created_at: row.get("created_at"),
+    }
+}
+
+async fn balance(
+    state: &AppState,
+    user_id: i32,
+    tenant_id: i32,
+    query: LedgerQuery,
+) -> Result<BalanceResponse, AuthError> {
+    let balance: i64 =
+        sqlx::query_scalar("SELECT token_balance FROM users WHERE id = $1 AND tenant_id = $2")
+            .bind(user_id)
+            .bind(tenant_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(internal)?
+            .ok_or_else(not_found)?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let rows = sqlx::query(
+        "SELECT id, kind, reason, method, units, tokens, balance_after, metadata, created_at \
+         FROM billing_ledger \
+         WHERE user_id = $1 \
+           AND ($2::varchar IS NULL OR kind = $2) \
+           AND ($3::bigint IS NULL OR id < $3) \
+         ORDER BY id DESC LIMIT $4",
+    )
+    .bind(user_id)
+    .bind(&query.kind)
+    .bind(query.cursor)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i64, _>("id")))
+        .flatten();
+    Ok(BalanceResponse {
+        user_id,
+        balance,
+        entries: rows.iter().map(entry).collect(),
+        next_cursor,
+    })
+}
+
+async fn adjust(
+    state: &AppState,
+    headers: &HeaderMap,
+    user_id: i32,
+    direction: Direction,
+    payload: AdjustBalanceRequest,
+) -> Result<Json<LedgerEntry>, AuthError> {
+    let admin = require_admin(headers, state).await?;
+    if payload.amount <= 0 {
+        return Err(AuthError::BadRequest(
+            "amount must be a positive number of tokens".to_string(),
+        ));
+    }
+    if !REASONS.contains(&payload.reason.as_str()) {
+        return Err(AuthError::BadRequest(format!(
+            "unsupported reason '{}', expected one of: {}",
+            payload.reason,
+            REASONS.join(", ")
+        )));
+    }
+    let note = payload
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE) {
+        return Err(AuthError::BadRequest(format!(
+            "note must be at most {MAX_NOTE} characters"
+        )));
+    }
+
+    let mut tx = state.pool.begin().await.map_err(internal)?;
+    let current: i64 = sqlx::query_scalar(
+        "SELECT token_balance FROM users WHERE id = $1 AND tenant_id = $2 FOR UPDATE",
+    )
+    .bind(user_id)
+    .bind(admin.tenant_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal)?
+    .ok_or_else(not_found)?;
+    let tokens = direction.tokens(payload.amount);
+    let updated = current
+        .checked_sub(tokens)
+        .ok_or_else(|| AuthError::BadRequest("amount is too large".to_string()))?;
+    // Charges may overdraw a balance, a manual debit may not.
+    if updated < 0 && tokens > 0 {
+        return Err(AuthError::Conflict(format!(
+            "balance of {current} tokens is too low to debit {}",
+            payload.amount
+        )));
+    }
+    sqlx::query("UPDATE users SET token_balance = $2, updated_at = NOW() WHERE id = $1")
+        .bind(user_id)
+        .bind(updated)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal)?;
+    let row = Posting {
+        user_id,
+        direction,
+        method: format!("auth.balance.{}", direction.kind()),
+        reason: &payload.reason,
+        amount: payload.amount,
+        balance_after: updated,
+        metadata: json!({ "admin_id": admin.user_id, "note": note }),
+    }
+    .insert(&mut tx)
+    .await?;
+    tx.commit().await.map_err(internal)?;
+    info!(
+        admin = admin.user_id,
+        user_id,
+        kind = direction.kind(),
+        amount = payload.amount,
+        reason = %payload.reason,
+        balance = updated,
+        "token balance adjusted"
+    );
+    Ok(Json(entry(&row)))
+}
+
+pub(crate) async fn credit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<AdjustBalanceRequest>,
+) -> Result<Json<LedgerEntry>, AuthError> {
+    adjust(&state, &headers, user_id, Direction::Credit, payload).await
+}
+
+pub(crate) async fn debit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<AdjustBalanceRequest>,
+) -> Result<Json<LedgerEntry>, AuthError> {
+    adjust(&state, &headers, user_id, Direction::Debit, payload).await
+}
+
+pub(crate) async fn user_ledger(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Json<BalanceResponse>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    balance(&state, user_id, admin.tenant_id, query)
+        .await
+        .map(Json)
+}
+
+/// The caller's own balance and ledger, newest first.
+pub(crate) async fn own_balance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Json<BalanceResponse>, AuthError> {
+    let user = authenticate(&headers, &state).await?;
+    balance(&state, user.user_id, user.tenant_id, query)
+        .await
+        .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledger_tokens_count_what_left_the_balance() {
+        assert_eq!(Direction::Credit.tokens(50), -50);
+        assert_eq!(Direction::Debit.tokens(50), 50);
+        assert_eq!(Direction::Credit.kind(), "credit");
+        assert_eq!(Direction::Debit.kind(), "debit");
+    }
+}
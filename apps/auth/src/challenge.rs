@@ -0,0 +1,690 @@
+//! Optional challenge in front of registration and login, to slow down
+//! automated sign-ups and credential stuffing. `AUTH_CHALLENGE` picks the
+//! [`Challenge`]: `pow` hands out hashcash-style puzzles, solved by finding a
+//! `response` for which the SHA-256 of `<challenge>:<response>` starts with
+//! `AUTH_CHALLENGE_POW_BITS` (default 20) zero bits; `captcha` checks the
+//! token of an external CAPTCHA against `AUTH_CAPTCHA_VERIFY_URL`, the
+//! provider's `siteverify` endpoint (hCaptcha, reCAPTCHA and Turnstile all
+//! speak it). Registration needs a solved challenge unless
+//! `AUTH_CHALLENGE_ON_REGISTER=false`, a login only after
+//! `AUTH_CHALLENGE_AFTER_FAILURES` (default 3) failed logins for the
+//! username or from the client's address within
+//! `AUTH_CHALLENGE_FAILURE_WINDOW_SECS` (default 900). A request that needs
+//! a challenge and lacks a valid one fails with 428 and a fresh challenge in
+//! the body; `GET /auth/challenge` hands one out up front.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use hex::encode as hex_encode;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{AppState, AuthError};
+
+const DEFAULT_POW_BITS: u32 = 20;
+const MAX_POW_BITS: u32 = 32;
+const DEFAULT_TTL_SECS: i64 = 300;
+/// Longer responses are never needed to solve a puzzle.
+const MAX_POW_RESPONSE: usize = 64;
+const CAPTCHA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the client sends back: for proof of work the issued `challenge` and
+/// the `response` solving it, for a CAPTCHA only the provider's token as
+/// `response`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Solution {
+    #[serde(default)]
+    challenge: Option<String>,
+    response: String,
+}
+
+#[async_trait]
+pub(crate) trait Challenge: Send + Sync {
+    /// What a client needs to come up with a [`Solution`].
+    fn issue(&self) -> Value;
+
+    /// `client_ip` is passed on to CAPTCHA providers that take it into
+    /// account.
+    async fn verify(
+        &self,
+        pool: &PgPool,
+        solution: &Solution,
+        client_ip: Option<&str>,
+    ) -> Result<bool, AuthError>;
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn number<T: FromStr>(name: &str, default: T) -> anyhow::Result<T> {
+    match env(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("{name} must be a number")),
+        None => Ok(default),
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Challenges {
+    challenge: Option<Arc<dyn Challenge>>,
+    on_register: bool,
+    after_failures: i32,
+    window_secs: f64,
+}
+
+impl Challenges {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        let challenge: Option<Arc<dyn Challenge>> = match env("AUTH_CHALLENGE").as_deref() {
+            None | Some("off") => None,
+            Some("pow") => Some(Arc::new(ProofOfWork::from_env()?)),
+            Some("captcha") => Some(Arc::new(Captcha::from_env()?)),
+            Some(other) => {
+                bail!("unsupported AUTH_CHALLENGE '{other}' (expected pow, captcha or off)")
+            }
+        };
+        Ok(Self {
+            challenge,
+            on_register: env("AUTH_CHALLENGE_ON_REGISTER").is_none_or(|value| value != "false"),
+            after_failures: number("AUTH_CHALLENGE_AFTER_FAILURES", 3)?,
+            window_secs: number::<u32>("AUTH_CHALLENGE_FAILURE_WINDOW_SECS", 900)?.into(),
+        })
+    }
+
+    pub(crate) async fn check_registration(
+        &self,
+        pool: &PgPool,
+        solution: Option<&Solution>,
+        client_ip: Option<&str>,
+    ) -> Result<(), AuthError> {
+        match &self.challenge {
+            Some(challenge) if self.on_register => {
+                require(challenge.as_ref(), pool, solution, client_ip).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checked before the password, so guessing needs a solved challenge
+    /// once the threshold is reached.
+    pub(crate) async fn check_login(
+        &self,
+        pool: &PgPool,
+        username: &str,
+        client_ip: Option<&str>,
+        solution: Option<&Solution>,
+    ) -> Result<(), AuthError> {
+        let Some(challenge) = &self.challenge else {
+            return Ok(());
+        };
+        let failures: i32 = sqlx::query_scalar(
+            "SELECT COALESCE(MAX(failures), 0) FROM login_failures \
+             WHERE key = ANY($1) AND window_started_at > NOW() - make_interval(secs => $2)",
+        )
+        .bind(failure_keys(username, client_ip))
+        .bind(self.window_secs)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+        if failures < self.after_failures {
+            return Ok(());
+        }
+        require(challenge.as_ref(), pool, solution, client_ip).await
+    }
+
+    /// Counts a failed login against the username and the client address;
+    /// failing to do so doesn't change the response.
+    pub(crate) async fn login_failed(
+        &self,
+        pool: &PgPool,
+        username: &str,
+        client_ip: Option<&str>,
+    ) {
+        if self.challenge.is_none() {
+            return;
+        }
+        let result = sqlx::query(
+            "WITH stale AS ( \
+                DELETE FROM login_failures \
+                WHERE last_failed_at < NOW() - make_interval(secs => $2) AND key <> ALL($1) \
+             ) \
+             INSERT INTO login_failures (key) SELECT unnest($1::text[]) \
+             ON CONFLICT (key) DO UPDATE SET \
+                failures = CASE \
+                    WHEN login_failures.window_started_at > NOW() - make_interval(secs => $2) \
+                    THEN login_failures.failures + 1 ELSE 1 END, \
+                window_started_at = CASE \
+                    WHEN login_failures.window_started_at > NOW() - make_interval(secs => $2) \
+                    THEN login_failures.window_started_at ELSE NOW() END, \
+                last_failed_at = NOW()",
+        )
+        .bind(failure_keys(username, client_ip))
+        .bind(self.window_secs)
+        .execute(pool)
+        .await;
+        if let Err(err) = result {
+            warn!(%username, error = %err, "failed to count failed login");
+        }
+    }
+
+    /// A successful login clears the username's count, not the address's:
+    /// one valid account must not reopen an address for guessing.
+    pub(crate) async fn login_succeeded(&self, pool: &PgPool, username: &str) {
+        if self.challenge.is_none() {
+            return;
+        }
+        if let Err(err) = sqlx::query("DELETE FROM login_failures WHERE key = $1")
+            .bind(format!("user:{username}"))
+            .execute(pool)
+            .await
+        {
+            warn!(%username, error = %err, "failed to reset failed logins");
+        }
+    }
+}
+
+fn failure_keys(username: &str, client_ip: Option<&str>) -> Vec<String> {
+    std::iter::once(format!("user:{username}"))
+        .chain(client_ip.map(|ip| format!("ip:{ip}")))
+        .collect()
+}
+
+async fn require(
+    challenge: &dyn Challenge,
+    pool: &PgPool,
+    solution: Option<&Solution>,
+    client_ip: Option<&str>,
+) -> Result<(), AuthError> {
+    let message = match solution {
+        None => "a solved challenge is required",
+        Some(solution) if challenge.verify(pool, solution, client_ip).await? => return Ok(()),
+        Some(_) => "challenge failed",
+    };
+    Err(AuthError::ChallengeRequired(
+        message.to_string(),
+        challenge.issue(),
+    ))
+}
+
+pub(crate) async fn issue_challenge(
+    State(state): State<AppState>,
+) -> Result<Json<Value>, AuthError> {
+    state
+        .challenges
+        .challenge
+        .as_ref()
+        .map(|challenge| Json(challenge.issue()))
+        .ok_or_else(|| AuthError::NotFound("no challenge is configured".to_string()))
+}
+
+/// Stateless puzzles: `<expires>.<bits>.<nonce>.<hmac>`, signed with
+/// `AUTH_CHALLENGE_SECRET` so any instance can check them. A solved puzzle
+/// is recorded in `redeemed_challenges` until it expires.
+struct ProofOfWork {
+    key: Vec<u8>,
+    bits: u32,
+    ttl_secs: i64,
+}
+
+impl ProofOfWork {
+    fn from_env() -> anyhow::Result<Self> {
+        let bits = number("AUTH_CHALLENGE_POW_BITS", DEFAULT_POW_BITS)?;
+        if !(1..=MAX_POW_BITS).contains(&bits) {
+            bail!("AUTH_CHALLENGE_POW_BITS must be between 1 and {MAX_POW_BITS}");
+        }
+        let key = match env("AUTH_CHALLENGE_SECRET") {
+            Some(secret) => secret.into_bytes(),
+            None => {
+                warn!("AUTH_CHALLENGE_SECRET is not set; challenges are only valid on this instance until it restarts");
+                let mut key = vec![0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        let ttl_secs = number("AUTH_CHALLENGE_TTL_SECS", DEFAULT_TTL_SECS)?;
+        if ttl_secs <= 0 {
+            bail!("AUTH_CHALLENGE_TTL_SECS must be positive");
+        }
+        Ok(Self {
+            key,
+            bits,
+            ttl_secs,
+        })
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte != 0 {
+            return bits + byte.leading_zeros();
+        }
+        bits += 8;
+    }
+    bits
+}
+
+#[async_trait]
+impl Challenge for ProofOfWork {
+    fn issue(&self) -> Value {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let expires_at = Utc::now() + chrono::Duration::seconds(self.ttl_secs);
+        let payload = format!(
+            "{}.{}.{}",
+            expires_at.timestamp(),
+            self.bits,
+            hex_encode(nonce)
+        );
+        let signature = hex_encode(self.mac(&payload).finalize().into_bytes());
+        json!({
+            "kind": "pow",
+            "algorithm": "sha256",
+            "challenge": format!("{payload}.{signature}"),
+            "difficulty": self.bits,
+            "expires_at": expires_at,
+        })
+    }
+
+    async fn verify(
+        &self,
+        pool: &PgPool,
+        solution: &Solution,
+        _client_ip: Option<&str>,
+    ) -> Result<bool, AuthError> {
+        let Some(challenge) = solution.challenge.as_deref() else {
+            return Ok(false);
+        };
+        let Some((payload, signature)) = challenge.rsplit_once('.') else {
+            return Ok(false);
+        };
+        let signed = hex::decode(signature)
+            .is_ok_and(|signature| self.mac(payload).verify_slice(&signature).is_ok());
+        let mut parts = payload.splitn(3, '.');
+        let (Some(expires), Some(bits), Some(nonce)) = (
+            parts.next().and_then(|value| value.parse::<i64>().ok()),
+            parts.next().and_then(|value| value.parse::<u32>().ok()),
+            parts.next(),
+        ) else {
+            return Ok(false);
+        };
+        let expires_at = DateTime::<Utc>::from_timestamp(expires, 0);
+        if !signed
+            || expires_at.is_none_or(|at| at <= Utc::now())
+            || bits < self.bits
+            || solution.response.len() > MAX_POW_RESPONSE
+        {
+            return Ok(false);
+        }
+        let digest = Sha256::digest(format!("{challenge}:{}", solution.response).as_bytes());
+        if leading_zero_bits(&digest) < bits {
+            return Ok(false);
+        }
+        // Each puzzle buys one request.
+        let redeemed = sqlx::query(
+            "WITH expired AS (DELETE FROM redeemed_challenges WHERE expires_at < NOW()) \
+             INSERT INTO redeemed_challenges (nonce, expires_at) VALUES ($1, $2) \
+             ON CONFLICT (nonce) DO NOTHING",
+        )
+        .bind(nonce)
+        .bind(expires_at)
+        .execute(pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+        Ok(redeemed.rows_affected() == 1)
+    }
+}
+
+/// Verification with the CAPTCHA provider; `AUTH_CAPTCHA_SITE_KEY` is only
+/// handed to clients so they can render the widget.
+struct Captcha {
+    client: reqwest::Client,
+    verify_url: String,
+    secret: String,
+    site_key: Option<String>,
+}
+
+impl Captcha {
+    fn from_env() -> anyhow::Result<Self> {
+        let required = |name: &str| {
+            env(name).ok_or_else(|| anyhow!("{name} is required for AUTH_CHALLENGE=captcha"))
+        };
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(CAPTCHA_TIMEOUT)
+                .build()?,
+            verify_url: required("AUTH_CAPTCHA_VERIFY_URL")?,
+            secret: required("AUTH_CAPTCHA_SECRET")?,
+            site_key: env("AUTH_CAPTCHA_SITE_KEY"),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    success: bool,
+}
+
+#[async_trait]
+impl Challenge for Captcha {
+    fn issue(&self) -> Value {
+        json!({
+            "kind": "captcha",
+            "site_key": self.site_key,
+        })
+    }
+
+    /// Fails closed: an unreachable provider rejects the request.
+    async fn verify(
+        &self,
+        _pool: &PgPool,
+        solution: &Solution,
+        client_ip: Option<&str>,
+    ) -> Result<bool, AuthError> {
+        let mut form = vec![
+            ("secret", self.secret.as_str()),
+            ("response", solution.response.as_str()),
+        ];
+        if let Some(ip) = client_ip {
+            form.push(("remoteip", ip));
+        }
+        let unavailable = |err: reqwest::Error| {
+            AuthError::Internal(format!("captcha verification failed: {err}"))
+        };
+        let verdict: Verdict = self
+            .client
+            .post(&self.verify_url)
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+        Ok(verdict.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    fn pow(bits: u32, ttl_secs: i64) -> ProofOfWork {
+        ProofOfWork {
+            key: b"challenge-secret".to_vec(),
+            bits,
+            ttl_secs,
+        }
+    }
+
+    /// Nothing listens there: a verification that reaches the database
+    /// fails with an internal error instead of answering.
+    fn unreachable_pool() -> PgPool {
+        PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(500))
+            .connect_lazy("postgres://coder@127.0.0.1:1/coder")
+            .unwrap()
+    }
+
+    fn challenge_of(issued: &Value) -> String {
+        issued["challenge"].as_str().unwrap().to_string()
+    }
+
+    fn solve(challenge: &str, bits: u32) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|response| {
+                leading_zero_bits(&Sha256::digest(
+                    format!("{challenge}:{response}").as_bytes(),
+                )) >= bits
+            })
+            .unwrap()
+    }
+
+    fn solution(challenge: Option<String>, response: &str) -> Solution {
+        Solution {
+            challenge,
+            response: response.to_string(),
+        }
+    }
+
+    #[test]
+    fn zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0x0f, 0x00]), 4);
+        assert_eq!(leading_zero_bits(&[0x00, 0x01]), 15);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn failures_are_counted_per_username_and_address() {
+        assert_eq!(failure_keys("alice", None), ["user:alice"]);
+        assert_eq!(
+            failure_keys("alice", Some("10.0.0.1")),
+            ["user:alice", "ip:10.0.0.1"]
+        );
+    }
+
+    #[test]
+    fn puzzles_are_signed_and_carry_their_difficulty() {
+        let issued = pow(12, 60).issue();
+        assert_eq!(issued["kind"], "pow");
+        assert_eq!(issued["difficulty"], 12);
+        let challenge = challenge_of(&issued);
+        let parts: Vec<&str> = challenge.split('.').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[1], "12");
+        assert_eq!(parts[3].len(), 64);
+        assert_ne!(challenge, challenge_of(&pow(12, 60).issue()));
+    }
+
+    #[tokio::test]
+    async fn invalid_solutions_are_rejected_before_the_database() {
+        let pool = unreachable_pool();
+        let pow = pow(4, 60);
+        let challenge = challenge_of(&pow.issue());
+        let solved = solve(&challenge, 4);
+
+        let missing = solution(None, &solved);
+        assert!(!pow.verify(&pool, &missing, None).await.unwrap());
+
+        let (payload, _) = challenge.rsplit_once('.').unwrap();
+        let forged = solution(Some(format!("{payload}.{}", "0".repeat(64))), &solved);
+        assert!(!pow.verify(&pool, &forged, None).await.unwrap());
+
+        let expired = challenge_of(&self::pow(4, -1).issue());
+        let late = solution(Some(expired.clone()), &solve(&expired, 4));
+        assert!(!pow.verify(&pool, &late, None).await.unwrap());
+
+        let easier = challenge_of(&self::pow(1, 60).issue());
+        let too_easy = solution(Some(easier.clone()), &solve(&easier, 1));
+        assert!(!self::pow(8, 60)
+            .verify(&pool, &too_easy, None)
+            .await
+            .unwrap());
+
+        let long = solution(Some(challenge.clone()), &"0".repeat(MAX_POW_RESPONSE + 1));
+        assert!(!pow.verify(&pool, &long, None).await.unwrap());
+
+        let hard = self::pow(MAX_POW_BITS, 60);
+        let unsolved = solution(Some(challenge_of(&hard.issue())), "0");
+        assert!(!hard.verify(&pool, &unsolved, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_solved_puzzle_is_redeemed_in_the_database() {
+        let pow = pow(4, 60);
+        let challenge = challenge_of(&pow.issue());
+        let solved = solution(Some(challenge.clone()), &solve(&challenge, 4));
+        assert!(matches!(
+            pow.verify(&unreachable_pool(), &solved, None).await,
+            Err(AuthError::Internal(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_solutions_get_a_fresh_challenge() {
+        let pow = pow(4, 60);
+        let err = require(&pow, &unreachable_pool(), None, None)
+            .await
+            .unwrap_err();
+        let AuthError::ChallengeRequired(message, issued) = err else {
+            panic!("expected a challenge, got {err:?}");
+        };
+        assert_eq!(message, "a solved challenge is required");
+        assert_eq!(issued["kind"], "pow");
+
+        let wrong = solution(Some("1.4.00.00".to_string()), "0");
+        let err = require(&pow, &unreachable_pool(), Some(&wrong), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AuthError::ChallengeRequired(message, _) if message == "challenge failed")
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_checked_without_a_challenge() {
+        let challenges = Challenges {
+            challenge: None,
+            on_register: true,
+            after_failures: 0,
+            window_secs: 900.0,
+        };
+        let pool = unreachable_pool();
+        challenges
+            .check_registration(&pool, None, None)
+            .await
+            .unwrap();
+        challenges
+            .check_login(&pool, "alice", Some("10.0.0.1"), None)
+            .await
+            .unwrap();
+
+        let open_registration = Challenges {
+            challenge: Some(Arc::new(pow(4, 60))),
+            on_register: false,
+            ..challenges
+        };
+        open_registration
+            .check_registration(&pool, None, None)
+            .await
+            .unwrap();
+    }
+
+    /// Answers one request with `status` and `body` and hands back what it
+    /// received.
+    fn provider(status: &str, body: &str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn captcha(verify_url: String) -> Captcha {
+        Captcha {
+            client: reqwest::Client::new(),
+            verify_url,
+            secret: "captcha-secret".to_string(),
+            site_key: Some("site".to_string()),
+        }
+    }
+
+    #[test]
+    fn captchas_hand_out_the_site_key() {
+        let issued = captcha("http://127.0.0.1:1".to_string()).issue();
+        assert_eq!(issued, json!({"kind": "captcha", "site_key": "site"}));
+    }
+
+    #[tokio::test]
+    async fn captcha_tokens_are_checked_with_the_provider() {
+        let (url, received) = provider("200 OK", r#"{"success":true}"#);
+        let token = solution(None, "token");
+        assert!(captcha(url)
+            .verify(&unreachable_pool(), &token, Some("10.0.0.1"))
+            .await
+            .unwrap());
+        let request = received.join().unwrap();
+        assert!(request.starts_with("POST /siteverify "));
+        assert!(request.ends_with("secret=captcha-secret&response=token&remoteip=10.0.0.1"));
+
+        let (url, _received) = provider("200 OK", r#"{"success":false}"#);
+        assert!(!captcha(url)
+            .verify(&unreachable_pool(), &token, None)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_provider_fails_closed() {
+        let (url, _received) = provider("503 Service Unavailable", "{}");
+        assert!(matches!(
+            captcha(url)
+                .verify(&unreachable_pool(), &solution(None, "token"), None)
+                .await,
+            Err(AuthError::Internal(_))
+        ));
+    }
+}
@@ -0,0 +1,316 @@
+//! Invitation codes (migration 026). Holders of `user.admin` create
+//! single-use codes bound to a role and an optional initial token grant; a
+//! code is valid for `AUTH_INVITE_TTL_HOURS` (default 168) unless the
+//! request sets `expires_in_hours`, and only its SHA-256 is stored. With
+//! `AUTH_REGISTRATION=invite_only`, `/auth/register` requires a code; in the
+//! default `open` mode a code is optional and still applies its role and
+//! grant. The new account joins the tenant of the admin who created the
+//! code, and admins only see and withdraw their own tenant's codes.
+
+use anyhow::bail;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::postgres::{PgConnection, PgRow};
+use sqlx::Row;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::balance::{Direction, Posting};
+use crate::users::require_admin;
+use crate::{hash_api_key, hex_encode, internal, validate_role, AppState, AuthError};
+
+const DEFAULT_TTL_HOURS: i64 = 168;
+const MAX_TTL_HOURS: i64 = 24 * 90;
+const MAX_NOTE: usize = 500;
+const INVITATION_COLUMNS: &str = "id, role, initial_tokens, note, created_by, created_at, \
+     expires_at, used_at, used_by";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Registration {
+    Open,
+    InviteOnly,
+}
+
+impl Registration {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("AUTH_REGISTRATION").as_deref() {
+            Err(_) | Ok("open") => Ok(Self::Open),
+            Ok("invite_only") => Ok(Self::InviteOnly),
+            Ok(other) => bail!("AUTH_REGISTRATION must be 'open' or 'invite_only', got '{other}'"),
+        }
+    }
+}
+
+fn default_ttl() -> i64 {
+    std::env::var("AUTH_INVITE_TTL_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_TTL_HOURS)
+}
+
+fn generate_code() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    format!("cds_inv_{}", hex_encode(bytes))
+}
+
+fn not_found() -> AuthError {
+    AuthError::NotFound("invitation not found".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateInvitationRequest {
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    initial_tokens: Option<i64>,
+    #[serde(default)]
+    expires_in_hours: Option<i64>,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListInvitationsQuery {
+    /// `pending` (default), `used`, `expired` or `all`.
+    #[serde(default)]
+    status: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct InvitationSummary {
+    id: Uuid,
+    role: String,
+    initial_tokens: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_by: Option<i32>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    used_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    used_by: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct InvitationWithCode {
+    #[serde(flatten)]
+    invitation: InvitationSummary,
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListInvitationsResponse {
+    invitations: Vec<InvitationSummary>,
+}
+
+fn summary(row: &PgRow) -> InvitationSummary {
+    InvitationSummary {
+        id: row.get("id"),
+        role: row.get("role"),
+        initial_tokens: row.get("initial_tokens"),
+        note: row.get("note"),
+        created_by: row.get("created_by"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        used_at: row.get("used_at"),
+        used_by: row.get("used_by"),
+    }
+}
+
+pub(crate) async fn create_invitation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateInvitationRequest>,
+) -> Result<(StatusCode, Json<InvitationWithCode>), AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let role = payload.role.unwrap_or_else(|| "developer".to_string());
+    validate_role(&state.pool, &role).await?;
+    let initial_tokens = payload.initial_tokens.unwrap_or(0);
+    if initial_tokens < 0 {
+        return Err(AuthError::BadRequest(
+            "initial_tokens must not be negative".to_string(),
+        ));
+    }
+    let ttl = payload.expires_in_hours.unwrap_or_else(default_ttl);
+    if !(1..=MAX_TTL_HOURS).contains(&ttl) {
+        return Err(AuthError::BadRequest(format!(
+            "expires_in_hours must be between 1 and {MAX_TTL_HOURS}"
+        )));
+    }
+    let note = payload
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE) {
+        return Err(AuthError::BadRequest(format!(
+            "note must be at most {MAX_NOTE} characters"
+        )));
+    }
+
+    let code = generate_code();
+    let row = sqlx::query(&format!(
+        "INSERT INTO invitations (code_hash, role, initial_tokens, note, created_by, expires_at, tenant_id) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING {INVITATION_COLUMNS}"
+    ))
+    .bind(hash_api_key(&code))
+    .bind(&role)
+    .bind(initial_tokens)
+    .bind(note)
+    .bind(admin.user_id)
+    .bind(Utc::now() + Duration::hours(ttl))
+    .bind(admin.tenant_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(internal)?;
+    let invitation = summary(&row);
+    info!(admin = admin.user_id, id = %invitation.id, %role, initial_tokens, "invitation created");
+    Ok((
+        StatusCode::CREATED,
+        Json(InvitationWithCode { invitation, code }),
+    ))
+}
+
+pub(crate) async fn list_invitations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListInvitationsQuery>,
+) -> Result<Json<ListInvitationsResponse>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let filter = match query.status.as_deref().unwrap_or("pending") {
+        "pending" => "used_at IS NULL AND expires_at > NOW()",
+        "used" => "used_at IS NOT NULL",
+        "expired" => "used_at IS NULL AND expires_at <= NOW()",
+        "all" => "TRUE",
+        other => {
+            return Err(AuthError::BadRequest(format!(
+                "unsupported status '{other}'"
+            )))
+        }
+    };
+    let rows = sqlx::query(&format!(
+        "SELECT {INVITATION_COLUMNS} FROM invitations \
+         WHERE tenant_id = $1 AND {filter} ORDER BY created_at DESC"
+    ))
+    .bind(admin.tenant_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+    Ok(Json(ListInvitationsResponse {
+        invitations: rows.iter().map(summary).collect(),
+    }))
+}
+
+/// Withdraws an unused invitation; used ones stay as the record of how an
+/// account was created.
+pub(crate) async fn delete_invitation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let used: bool = sqlx::query_scalar(
+        "WITH target AS (SELECT id, used_at FROM invitations WHERE id = $1 AND tenant_id = $2), \
+              deleted AS (DELETE FROM invitations WHERE id IN (SELECT id FROM target) \
+                            AND used_at IS NULL RETURNING id) \
+         SELECT target.used_at IS NOT NULL FROM target",
+    )
+    .bind(id)
+    .bind(admin.tenant_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal)?
+    .ok_or_else(not_found)?;
+    if used {
+        return Err(AuthError::Conflict(
+            "invitation has already been used".to_string(),
+        ));
+    }
+    info!(admin = admin.user_id, %id, "invitation deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A pending invitation claimed by a registration in progress.
+pub(crate) struct Invitation {
+    id: Uuid,
+    pub(crate) role: String,
+    pub(crate) initial_tokens: i64,
+    pub(crate) tenant_id: i32,
+    created_by: Option<i32>,
+}
+
+impl Invitation {
+    /// Marks the invitation used. Within the registration's transaction, so
+    /// a failed registration leaves the code valid and two registrations
+    /// cannot both claim it.
+    pub(crate) async fn claim(conn: &mut PgConnection, code: &str) -> Result<Self, AuthError> {
+        let row = sqlx::query(
+            "UPDATE invitations SET used_at = NOW() \
+             WHERE code_hash = $1 AND used_at IS NULL AND expires_at > NOW() \
+             RETURNING id, role, initial_tokens, tenant_id, created_by",
+        )
+        .bind(hash_api_key(code.trim()))
+        .fetch_optional(conn)
+        .await
+        .map_err(internal)?
+        .ok_or_else(|| AuthError::Forbidden("invalid or expired invitation code".to_string()))?;
+        Ok(Self {
+            id: row.get("id"),
+            role: row.get("role"),
+            initial_tokens: row.get("initial_tokens"),
+            tenant_id: row.get("tenant_id"),
+            created_by: row.get("created_by"),
+        })
+    }
+
+    /// Links the invitation to the new account and books its grant, which
+    /// the account was created with.
+    pub(crate) async fn complete(
+        self,
+        conn: &mut PgConnection,
+        user_id: i32,
+    ) -> Result<(), AuthError> {
+        sqlx::query("UPDATE invitations SET used_by = $2 WHERE id = $1")
+            .bind(self.id)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await
+            .map_err(internal)?;
+        if self.initial_tokens > 0 {
+            Posting {
+                user_id,
+                direction: Direction::Credit,
+                method: "auth.register".to_string(),
+                reason: "grant",
+                amount: self.initial_tokens,
+                balance_after: self.initial_tokens,
+                metadata: json!({ "invitation_id": self.id, "admin_id": self.created_by }),
+            }
+            .insert(conn)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_prefixed_and_random() {
+        let code = generate_code();
+        assert!(code.starts_with("cds_inv_"));
+        assert_eq!(code.len(), "cds_inv_".len() + 32);
+        assert_ne!(code, generate_code());
+    }
+}
@@ -0,0 +1,334 @@
+//! Signing keys for the JWTs the auth service issues. By default tokens are
+//! HS256 with `AUTH_JWT_SECRET`, which every verifier has to share. Setting
+//! `AUTH_JWT_PRIVATE_KEY_PATH` (RSA, PEM) switches to RS256: tokens carry the
+//! key's `kid` (`AUTH_JWT_KEY_ID`, or the key's RFC 7638 thumbprint) and
+//! `/.well-known/jwks.json` publishes the public half, so other services can
+//! verify tokens without holding a secret. Public keys of earlier key pairs
+//! listed in `AUTH_JWT_RETIRED_KEY_PATHS` (`path` or `kid=path` if the pair
+//! had an explicit key id) stay published and accepted until the tokens they
+//! signed have expired. If `AUTH_JWT_SECRET` is set as well,
+//! HS256 tokens issued before the switch keep working too.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context as _};
+use auth_core::{DecodingKeys, KeyId};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+pub(crate) struct SigningKeys {
+    algorithm: Algorithm,
+    kid: Option<String>,
+    encoding: EncodingKey,
+    verifying: DecodingKeys,
+    jwks: Value,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn read_pem(path: &str) -> anyhow::Result<String> {
+    std::fs::read_to_string(Path::new(path.trim()))
+        .with_context(|| format!("failed to read key file {path}"))
+}
+
+/// The JWK of an RSA public key, with its RFC 7638 thumbprint as default `kid`.
+fn rsa_jwk(public: &RsaPublicKey, kid: Option<String>) -> (String, Value) {
+    let n = BASE64_URL.encode(public.n().to_bytes_be());
+    let e = BASE64_URL.encode(public.e().to_bytes_be());
+    let kid = kid.unwrap_or_else(|| {
+        let canonical = format!(r#"{{"e":"{e}","kty":"RSA","n":"{n}"}}"#);
+        BASE64_URL.encode(Sha256::digest(canonical.as_bytes()))
+    });
+    let jwk = json!({
+        "kty": "RSA",
+        "use": "sig",
+        "alg": "RS256",
+        "kid": kid,
+        "n": n,
+        "e": e,
+    });
+    (kid, jwk)
+}
+
+/// Tokens are verified against the published keys, the same way the API
+/// gateway does, plus the shared secret if there is one.
+fn verifying(jwks: &Value, secret: Option<&str>) -> anyhow::Result<DecodingKeys> {
+    let set: JwkSet = serde_json::from_value(jwks.clone())?;
+    let keys = DecodingKeys::from_jwks(&set);
+    Ok(match secret {
+        Some(secret) => keys.with_secret(secret),
+        None => keys,
+    })
+}
+
+impl SigningKeys {
+    /// `secret` is `AUTH_JWT_SECRET`, resolved if it names a secrets manager
+    /// entry.
+    pub(crate) fn from_env(secret: Option<String>) -> anyhow::Result<Self> {
+        Self::load(
+            secret,
+            env("AUTH_JWT_PRIVATE_KEY_PATH"),
+            env("AUTH_JWT_KEY_ID"),
+            env("AUTH_JWT_RETIRED_KEY_PATHS").unwrap_or_default(),
+        )
+    }
+
+    fn load(
+        secret: Option<String>,
+        private_key_path: Option<String>,
+        key_id: Option<String>,
+        retired_key_paths: String,
+    ) -> anyhow::Result<Self> {
+        let Some(path) = private_key_path else {
+            let secret = secret.ok_or_else(|| {
+                anyhow!(
+                    "AUTH_JWT_SECRET or AUTH_JWT_PRIVATE_KEY_PATH environment variable is required"
+                )
+            })?;
+            let jwks = json!({ "keys": [] });
+            return Ok(Self {
+                algorithm: Algorithm::HS256,
+                kid: None,
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                verifying: verifying(&jwks, Some(&secret))?,
+                jwks,
+            });
+        };
+
+        let pem = read_pem(&path)?;
+        let private = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|err| anyhow!("AUTH_JWT_PRIVATE_KEY_PATH is not an RSA private key: {err}"))?;
+        let encoding = EncodingKey::from_rsa_pem(pem.as_bytes())?;
+        let (kid, jwk) = rsa_jwk(&private.to_public_key(), key_id);
+        let mut keys = vec![jwk];
+
+        for entry in retired_key_paths
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+        {
+            let (retired_kid, path) = match entry.split_once('=') {
+                Some((kid, path)) => (Some(kid.trim().to_string()), path),
+                None => (None, entry),
+            };
+            let pem = read_pem(path)?;
+            let public = RsaPublicKey::from_public_key_pem(&pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(&pem))
+                .map_err(|err| anyhow!("{path} is not an RSA public key: {err}"))?;
+            let (_, jwk) = rsa_jwk(&public, retired_kid);
+            keys.push(jwk);
+        }
+
+        let jwks = json!({ "keys": keys });
+        Ok(Self {
+            algorithm: Algorithm::RS256,
+            kid: Some(kid),
+            encoding,
+            verifying: verifying(&jwks, secret.as_deref())?,
+            jwks,
+        })
+    }
+
+    pub(crate) fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        header
+    }
+
+    pub(crate) fn encoding(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    /// The key a token claims to be signed with, if it is one of ours.
+    pub(crate) fn decoding(&self, token: &str) -> Option<(KeyId, &DecodingKey)> {
+        let id = KeyId::of(token)?;
+        let key = self.verifying.get(&id)?;
+        Some((id, key))
+    }
+
+    pub(crate) fn jwks(&self) -> &Value {
+        &self.jwks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use auth_core::{Claims, TokenVerifier};
+    use jsonwebtoken::encode;
+    use rand::rngs::OsRng;
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// The current and a retired key pair; generating them is slow.
+    fn key_pairs() -> &'static [RsaPrivateKey; 2] {
+        static KEYS: OnceLock<[RsaPrivateKey; 2]> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            [
+                RsaPrivateKey::new(&mut OsRng, 2048).unwrap(),
+                RsaPrivateKey::new(&mut OsRng, 2048).unwrap(),
+            ]
+        })
+    }
+
+    fn write(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn private_pem(key: &RsaPrivateKey) -> String {
+        key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string()
+    }
+
+    fn claims() -> Claims {
+        Claims::new(
+            7,
+            "dev",
+            "developer",
+            1,
+            "cyber-dev-studio",
+            chrono::Duration::minutes(5),
+        )
+    }
+
+    /// Verifies `token` the way the auth service checks the tokens it gets.
+    fn verify(keys: &SigningKeys, token: &str) -> Option<Claims> {
+        let (id, key) = keys.decoding(token)?;
+        TokenVerifier::new("cyber-dev-studio")
+            .verify(token, &id, key)
+            .ok()
+    }
+
+    fn hmac_token(secret: &str) -> String {
+        encode(
+            &Header::default(),
+            &claims(),
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn thumbprints_follow_rfc_7638() {
+        // The example key of RFC 7638, section 3.1.
+        let n = "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw";
+        let public = RsaPublicKey::new(
+            rsa::BigUint::from_bytes_be(&BASE64_URL.decode(n).unwrap()),
+            rsa::BigUint::from(65537u32),
+        )
+        .unwrap();
+        let (kid, jwk) = rsa_jwk(&public, None);
+        assert_eq!(kid, "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+        assert_eq!(jwk["n"], n);
+        assert_eq!(jwk["e"], "AQAB");
+
+        let (kid, jwk) = rsa_jwk(&public, Some("k1".to_string()));
+        assert_eq!((kid.as_str(), &jwk["kid"]), ("k1", &json!("k1")));
+    }
+
+    #[test]
+    fn a_secret_or_a_key_pair_is_required() {
+        assert!(SigningKeys::load(None, None, None, String::new()).is_err());
+
+        let dir = TempDir::new().unwrap();
+        let path = write(&dir, "jwt.pem", "not a key");
+        let err = SigningKeys::load(None, Some(path), None, String::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is not an RSA private key"));
+    }
+
+    #[test]
+    fn the_secret_alone_signs_hs256_and_publishes_nothing() {
+        let keys =
+            SigningKeys::load(Some("s3cret".to_string()), None, None, String::new()).unwrap();
+        let header = keys.header();
+        assert_eq!((header.alg, header.kid), (Algorithm::HS256, None));
+        assert_eq!(keys.jwks(), &json!({ "keys": [] }));
+
+        let token = encode(&keys.header(), &claims(), keys.encoding()).unwrap();
+        assert_eq!(verify(&keys, &token).unwrap().sub, 7);
+        assert!(verify(&keys, &hmac_token("other")).is_none());
+    }
+
+    #[test]
+    fn a_key_pair_signs_rs256_under_its_thumbprint() {
+        let dir = TempDir::new().unwrap();
+        let [current, _] = key_pairs();
+        let path = write(&dir, "jwt.pem", &private_pem(current));
+        let keys = SigningKeys::load(None, Some(path), None, String::new()).unwrap();
+
+        let (thumbprint, jwk) = rsa_jwk(&current.to_public_key(), None);
+        let header = keys.header();
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid.as_deref(), Some(thumbprint.as_str()));
+        assert_eq!(keys.jwks(), &json!({ "keys": [jwk] }));
+
+        let token = encode(&keys.header(), &claims(), keys.encoding()).unwrap();
+        assert_eq!(verify(&keys, &token).unwrap().sub, 7);
+        // Without AUTH_JWT_SECRET there is nothing to check HS256 tokens with.
+        assert!(keys.decoding(&hmac_token("s3cret")).is_none());
+    }
+
+    #[test]
+    fn retired_keys_and_the_secret_stay_accepted() {
+        let dir = TempDir::new().unwrap();
+        let [current, retired] = key_pairs();
+        let path = write(&dir, "jwt.pem", &private_pem(current));
+        let retired_path = write(
+            &dir,
+            "old.pub.pem",
+            &retired
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        );
+        let keys = SigningKeys::load(
+            Some("s3cret".to_string()),
+            Some(path),
+            Some("current".to_string()),
+            format!("old={retired_path}, "),
+        )
+        .unwrap();
+        let kids: Vec<&Value> = keys.jwks()["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|jwk| &jwk["kid"])
+            .collect();
+        assert_eq!(kids, [&json!("current"), &json!("old")]);
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("old".to_string());
+        let retired_key = EncodingKey::from_rsa_pem(private_pem(retired).as_bytes()).unwrap();
+        let token = encode(&header, &claims(), &retired_key).unwrap();
+        assert_eq!(verify(&keys, &token).unwrap().sub, 7);
+
+        // Signed with the retired key, but claiming to be the current one.
+        header.kid = Some("current".to_string());
+        let token = encode(&header, &claims(), &retired_key).unwrap();
+        assert!(verify(&keys, &token).is_none());
+
+        header.kid = Some("unknown".to_string());
+        let token = encode(&header, &claims(), keys.encoding()).unwrap();
+        assert!(keys.decoding(&token).is_none());
+
+        assert_eq!(verify(&keys, &hmac_token("s3cret")).unwrap().sub, 7);
+    }
+}
@@ -0,0 +1,221 @@
+//! Login tracking (migration 027). Password and OIDC logins record the
+//! client address and user agent on the user, count logins per address in
+//! `login_addresses` and add an `auth.login` row to `audit_log`. A login from
+//! an address the user has never logged in from, other than their very
+//! first, also leaves a `security.new_login` notification in the user's
+//! inbox. `X-Forwarded-For` is only honoured with
+//! `AUTH_TRUST_FORWARDED_FOR=true`, i.e. behind a trusted proxy. Users see
+//! all of it through `GET /auth/me`.
+
+use std::net::SocketAddr;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::types::Json as JsonValue;
+use sqlx::{PgPool, Row};
+use tracing::warn;
+
+use crate::{authenticate, AppState, AuthError};
+
+/// The channel the API gateway's notification listener subscribes to.
+const NOTIFICATION_CHANNEL: &str = "notifications";
+const MAX_USER_AGENT: usize = 512;
+const RECENT_ADDRESSES: i64 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LoginTracker {
+    trust_forwarded: bool,
+}
+
+/// Where a login came from.
+#[derive(Debug)]
+pub(crate) struct Client {
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Client {
+    pub(crate) fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+}
+
+impl LoginTracker {
+    pub(crate) fn from_env() -> Self {
+        Self {
+            trust_forwarded: std::env::var("AUTH_TRUST_FORWARDED_FOR")
+                .map(|value| value == "true")
+                .unwrap_or(false),
+        }
+    }
+
+    pub(crate) fn client(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<ConnectInfo<SocketAddr>>,
+    ) -> Client {
+        let forwarded = self
+            .trust_forwarded
+            .then(|| {
+                headers
+                    .get("x-forwarded-for")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(',').next())
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_string)
+            })
+            .flatten();
+        Client {
+            ip: forwarded.or_else(|| peer.map(|ConnectInfo(addr)| addr.ip().to_string())),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(MAX_USER_AGENT).collect()),
+        }
+    }
+
+    /// Failing to record a login doesn't fail it.
+    pub(crate) async fn record(&self, pool: &PgPool, user_id: i32, client: &Client) {
+        if let Err(err) = record(pool, user_id, client).await {
+            warn!(user_id, error = %err, "failed to record login");
+        }
+    }
+}
+
+async fn record(pool: &PgPool, user_id: i32, client: &Client) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let returning: bool = sqlx::query_scalar(
+        "WITH previous AS (SELECT last_login_at FROM users WHERE id = $1 FOR UPDATE), \
+              updated AS ( \
+                UPDATE users SET last_login_at = NOW(), last_login_ip = $2, \
+                    last_login_user_agent = $3 \
+                WHERE id = $1 \
+              ) \
+         SELECT last_login_at IS NOT NULL FROM previous",
+    )
+    .bind(user_id)
+    .bind(&client.ip)
+    .bind(&client.user_agent)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO audit_log (user_id, method, outcome, latency_ms, client_ip) \
+         VALUES ($1, 'auth.login', 'ok', 0, $2)",
+    )
+    .bind(user_id)
+    .bind(&client.ip)
+    .execute(&mut *tx)
+    .await?;
+    let Some(ip) = &client.ip else {
+        return tx.commit().await;
+    };
+    // `xmax = 0` only holds for a freshly inserted row.
+    let new_address: bool = sqlx::query_scalar(
+        "INSERT INTO login_addresses (user_id, ip) VALUES ($1, $2) \
+         ON CONFLICT (user_id, ip) DO UPDATE \
+            SET last_seen_at = NOW(), logins = login_addresses.logins + 1 \
+         RETURNING xmax = 0",
+    )
+    .bind(user_id)
+    .bind(ip)
+    .fetch_one(&mut *tx)
+    .await?;
+    if new_address && returning {
+        warn!(user_id, %ip, "login from a new address");
+        sqlx::query(
+            "WITH created AS ( \
+                INSERT INTO notifications (user_id, kind, title, data) \
+                VALUES ($1, 'security.new_login', $2, $3) RETURNING id, user_id \
+             ) \
+             SELECT pg_notify($4, json_build_object('id', id, 'user_id', user_id)::text) \
+             FROM created",
+        )
+        .bind(user_id)
+        .bind(format!("New sign-in from {ip}"))
+        .bind(JsonValue(json!({
+            "ip": ip,
+            "user_agent": client.user_agent,
+            "at": Utc::now().to_rfc3339(),
+        })))
+        .bind(NOTIFICATION_CHANNEL)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LoginAddress {
+    ip: String,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    logins: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct MeResponse {
+    id: i32,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    email_verified: bool,
+    role: String,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_login_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_login_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_login_user_agent: Option<String>,
+    recent_addresses: Vec<LoginAddress>,
+}
+
+pub(crate) async fn me(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<MeResponse>, AuthError> {
+    let user = authenticate(&headers, &state).await?;
+    let row = sqlx::query(
+        "SELECT id, username, email, email_verified_at IS NOT NULL AS email_verified, role, \
+            created_at, last_login_at, last_login_ip, last_login_user_agent \
+         FROM users WHERE id = $1",
+    )
+    .bind(user.user_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let addresses = sqlx::query(
+        "SELECT ip, first_seen_at, last_seen_at, logins FROM login_addresses \
+         WHERE user_id = $1 ORDER BY last_seen_at DESC LIMIT $2",
+    )
+    .bind(user.user_id)
+    .bind(RECENT_ADDRESSES)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    Ok(Json(MeResponse {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        email_verified: row.get("email_verified"),
+        role: row.get("role"),
+        created_at: row.get("created_at"),
+        last_login_at: row.get("last_login_at"),
+        last_login_ip: row.get("last_login_ip"),
+        last_login_user_agent: row.get("last_login_user_agent"),
+        recent_addresses: addresses
+            .iter()
+            .map(|row| LoginAddress {
+                ip: row.get("ip"),
+                first_seen_at: row.get("first_seen_at"),
+                last_seen_at: row.get("last_seen_at"),
+                logins: row.get("logins"),
+            })
+            .collect(),
+    }))
+}
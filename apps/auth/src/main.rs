@@ -1,42 +1,72 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use axum::extract::{Path, State};
+use auth_core::api_key::{is_valid_scope, SCOPES as API_KEY_SCOPES};
+use auth_core::{hash_api_key, Claims, TokenVerifier};
+use axum::extract::{ConnectInfo, Path, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{delete, get, post};
+use axum::routing::{delete, get, post, put};
 use axum::{Json, Router};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::encode;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgPoolOptions;
+use server::tls::TlsSettings;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::{PgPool, Row};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
 use tower_http::trace::TraceLayer;
-use tracing::{dispatcher, error, info};
+use tracing::{dispatcher, error, info, warn};
 use uuid::Uuid;
 
 use hex::encode as hex_encode;
 use rand::rngs::OsRng;
 use rand::RngCore;
-use sha2::{Digest, Sha256};
+
+mod api_key_usage;
+mod balance;
+mod challenge;
+mod invitations;
+mod keys;
+mod logins;
+mod notifier;
+mod oidc;
+mod password;
+mod password_policy;
+mod registration_events;
+mod reset;
+mod service;
+mod tenants;
+mod users;
+mod verification;
 
 #[derive(Clone)]
 struct AppState {
     pool: PgPool,
     jwt: JwtConfig,
+    passwords: password::Passwords,
+    password_policy: Arc<password_policy::PasswordPolicy>,
+    reset: Option<reset::PasswordReset>,
+    oidc: Option<oidc::Oidc>,
+    verification: Option<verification::EmailVerification>,
+    registration: invitations::Registration,
+    logins: logins::LoginTracker,
+    challenges: challenge::Challenges,
+    registration_events: registration_events::RegistrationEvents,
 }
 
 #[derive(Clone)]
 struct JwtConfig {
-    secret: Arc<[u8]>,
+    keys: Arc<keys::SigningKeys>,
+    verifier: TokenVerifier,
     expiration: Duration,
     issuer: String,
 }
 
 impl JwtConfig {
-    fn from_env() -> anyhow::Result<Self> {
-        let secret = std::env::var("AUTH_JWT_SECRET")
-            .map_err(|_| anyhow::anyhow!("AUTH_JWT_SECRET environment variable is required"))?;
+    fn from_env(secret: Option<String>) -> anyhow::Result<Self> {
+        let keys = keys::SigningKeys::from_env(secret)?;
         let expiration_minutes = std::env::var("AUTH_JWT_EXP_MINUTES")
             .ok()
             .and_then(|v| v.parse::<i64>().ok())
@@ -44,31 +74,12 @@ impl JwtConfig {
         let issuer =
             std::env::var("AUTH_JWT_ISSUER").unwrap_or_else(|_| "cyber-dev-studio".to_string());
         Ok(Self {
-            secret: Arc::from(secret.into_bytes()),
+            keys: Arc::new(keys),
+            verifier: TokenVerifier::new(&issuer),
             expiration: Duration::minutes(expiration_minutes),
             issuer,
         })
     }
-
-    fn validation(&self) -> Validation {
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation
-            .set_required_spec_claims(&["exp", "iat", "sub", "iss"])
-            .expect("required claim configuration");
-        validation.iss = Some(self.issuer.clone());
-        validation
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: i32,
-    username: String,
-    role: String,
-    exp: usize,
-    iat: usize,
-    iss: String,
-    jti: String,
 }
 
 #[derive(Debug)]
@@ -76,33 +87,165 @@ struct AuthenticatedUser {
     user_id: i32,
     username: String,
     role: String,
+    tenant_id: i32,
+    jti: String,
+    expires_at: usize,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_tracing();
     let bind_addr = resolve_bind_address()?;
-    let pool = build_pool().await?;
-    let jwt = JwtConfig::from_env()?;
-
-    let state = AppState { pool, jwt };
+    let secrets = Arc::new(secrets::Secrets::new(secrets::SecretsConfig::from_env()?)?);
+    let pool = build_pool(&secrets).await?;
+    let jwt = JwtConfig::from_env(secrets.env("AUTH_JWT_SECRET").await?)?;
+    let passwords = password::Passwords::from_env()?;
+    let password_policy = Arc::new(password_policy::PasswordPolicy::from_env()?);
+    let notifier = notifier::from_env()?;
+    let reset = reset::PasswordReset::from_env(notifier.clone());
+    let verification = verification::EmailVerification::from_env(notifier);
+    let oidc = oidc::Oidc::from_env(&pool).await?;
+    let registration = invitations::Registration::from_env()?;
+    let logins = logins::LoginTracker::from_env();
+    let challenges = challenge::Challenges::from_env()?;
+    let registration_events = registration_events::RegistrationEvents::from_env()?;
+    registration_events.spawn_worker(pool.clone());
+    secrets.spawn_refresh();
+    follow_database_rotation(&secrets, pool.clone());
+
+    let state = AppState {
+        pool: pool.clone(),
+        jwt,
+        passwords,
+        password_policy,
+        reset,
+        oidc,
+        verification,
+        registration,
+        logins,
+        challenges,
+        registration_events,
+    };
 
     let app = Router::new()
         .route("/health", get(health))
+        .route("/.well-known/jwks.json", get(jwks))
         .route("/auth/register", post(register_user))
+        .route("/auth/password-policy", get(password_policy::describe))
+        .route("/auth/challenge", get(challenge::issue_challenge))
         .route("/auth/login", post(login_user))
+        .route("/auth/logout", post(logout_user))
+        .route("/auth/me", get(logins::me))
+        .route("/auth/password-reset/request", post(reset::request_reset))
+        .route("/auth/password-reset/confirm", post(reset::confirm_reset))
+        .route("/auth/email", put(verification::change_email))
+        .route(
+            "/auth/email/verify/request",
+            post(verification::request_verification),
+        )
+        .route(
+            "/auth/email/verify/confirm",
+            post(verification::confirm_verification),
+        )
+        .route("/auth/oidc/login", get(oidc::login))
+        .route("/auth/oidc/callback", get(oidc::callback))
+        .route("/admin/users", get(users::list_users))
+        .route("/admin/users/:id/role", put(users::set_role))
+        .route("/admin/users/:id/tenant", put(tenants::move_user))
+        .route("/admin/users/:id/disable", post(users::disable_user))
+        .route("/admin/users/:id/enable", post(users::enable_user))
+        .route(
+            "/admin/users/:id/password-reset",
+            post(users::force_password_reset),
+        )
+        .route("/admin/users/:id/balance/credit", post(balance::credit))
+        .route("/admin/users/:id/balance/debit", post(balance::debit))
+        .route("/admin/users/:id/ledger", get(balance::user_ledger))
+        .route("/auth/balance", get(balance::own_balance))
+        .route(
+            "/admin/invitations",
+            get(invitations::list_invitations).post(invitations::create_invitation),
+        )
+        .route(
+            "/admin/invitations/:id",
+            delete(invitations::delete_invitation),
+        )
+        .route(
+            "/admin/registration-events",
+            get(registration_events::list_events),
+        )
+        .route(
+            "/admin/registration-events/:id/replay",
+            post(registration_events::replay_event),
+        )
+        .route(
+            "/admin/tenants",
+            get(tenants::list_tenants).post(tenants::create_tenant),
+        )
+        .route("/auth/token", post(service::token))
+        .route(
+            "/admin/service-clients",
+            get(service::list_clients).post(service::create_client),
+        )
+        .route("/admin/service-clients/:id", delete(service::delete_client))
+        .route(
+            "/admin/service-clients/:id/secret",
+            post(service::rotate_secret),
+        )
         .route("/auth/api-keys", get(list_api_keys).post(create_api_key))
         .route("/auth/api-keys/:id", delete(delete_api_key))
+        .route("/auth/api-keys/:id/usage", get(api_key_usage::key_usage))
         .with_state(state)
         .layer(TraceLayer::new_for_http());
 
-    info!("binding", %bind_addr, "auth service starting");
-    axum::Server::bind(&bind_addr)
-        .serve(app.into_make_service())
-        .await?;
+    let drain_timeout = std::time::Duration::from_secs(
+        std::env::var("SHUTDOWN_DRAIN_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(30),
+    );
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        server::shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    let mut server = match TlsSettings::from_env()? {
+        Some(settings) => tokio::spawn(server::tls::serve(
+            bind_addr,
+            settings,
+            app,
+            wait_for_shutdown(shutdown_rx.clone()),
+        )),
+        None => {
+            info!(%bind_addr, "auth service starting");
+            let server = axum::Server::bind(&bind_addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
+            tokio::spawn(async move { server.await.map_err(anyhow::Error::from) })
+        }
+    };
+    let deadline = async {
+        wait_for_shutdown(shutdown_rx).await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = &mut server => result??,
+        _ = deadline => {
+            warn!(timeout = ?drain_timeout, "drain deadline elapsed; aborting in-flight requests");
+            server.abort();
+        }
+    }
+
+    pool.close().await;
+    info!("shutdown complete");
     Ok(())
 }
 
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
 fn init_tracing() {
     if dispatcher::has_been_set() {
         return;
@@ -124,9 +267,13 @@ fn resolve_bind_address() -> anyhow::Result<SocketAddr> {
     Ok(raw.parse()?)
 }
 
-async fn build_pool() -> anyhow::Result<PgPool> {
-    let database_url = std::env::var("DATABASE_URL")
-        .map_err(|_| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
+/// `DATABASE_URL` may name a secrets manager entry; new connections follow
+/// its rotations.
+async fn build_pool(secrets: &Arc<secrets::Secrets>) -> anyhow::Result<PgPool> {
+    let database_url = secrets
+        .env("DATABASE_URL")
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
     let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
         .ok()
         .and_then(|v| v.parse::<u32>().ok())
@@ -139,35 +286,128 @@ async fn build_pool() -> anyhow::Result<PgPool> {
     Ok(pool)
 }
 
+/// A rotated `AUTH_JWT_SECRET` still needs a restart; the API gateway keeps
+/// accepting tokens signed with the previous secret meanwhile.
+fn follow_database_rotation(secrets: &secrets::Secrets, pool: PgPool) {
+    let Some(raw) = std::env::var("DATABASE_URL")
+        .ok()
+        .filter(|raw| secrets::is_reference(raw))
+    else {
+        return;
+    };
+    let mut rotations = secrets.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match rotations.recv().await {
+                Ok(rotation) if rotation.concerns(&raw) => {
+                    match rotation.value.parse::<PgConnectOptions>() {
+                        Ok(options) => {
+                            pool.set_connect_options(options);
+                            info!("applied a rotated DATABASE_URL");
+                        }
+                        Err(err) => {
+                            warn!(error = %err, "ignoring a rotated DATABASE_URL that does not parse")
+                        }
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Public keys for verifying RS256 tokens; empty while tokens are HS256.
+async fn jwks(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(state.jwt.keys.jwks().clone())
+}
+
 async fn health() -> impl IntoResponse {
     (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
 }
 
 async fn register_user(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<RegisterResponse>, AuthError> {
-    validate_role(&payload.role)?;
-    if payload.password.len() < 12 {
+    if payload.username.starts_with(service::USERNAME_PREFIX) {
+        return Err(AuthError::BadRequest(format!(
+            "usernames starting with '{}' are reserved for service clients",
+            service::USERNAME_PREFIX
+        )));
+    }
+    let code = match (payload.invite_code.as_deref(), state.registration) {
+        (Some(code), _) => Some(code),
+        (None, invitations::Registration::InviteOnly) => {
+            return Err(AuthError::Forbidden(
+                "registration requires an invitation code".to_string(),
+            ))
+        }
+        (None, invitations::Registration::Open) => None,
+    };
+    if code.is_some() && (payload.role.is_some() || payload.initial_tokens.is_some()) {
         return Err(AuthError::BadRequest(
-            "password must contain at least 12 characters".to_string(),
+            "role and initial tokens come from the invitation".to_string(),
         ));
     }
+    let user_inputs: Vec<&str> = std::iter::once(payload.username.as_str())
+        .chain(payload.email.as_deref())
+        .collect();
+    state
+        .password_policy
+        .check(&payload.password, &user_inputs)?;
+    if let Some(email) = &payload.email {
+        validate_email(email)?;
+    }
+    let client = state.logins.client(&headers, peer);
+    state
+        .challenges
+        .check_registration(&state.pool, payload.challenge.as_ref(), client.ip())
+        .await?;
 
-    let hashed = bcrypt::hash(&payload.password, bcrypt::DEFAULT_COST)
+    let hashed = state.passwords.hash(&payload.password).await?;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
         .map_err(|err| AuthError::Internal(err.to_string()))?;
-    let role = payload.role.unwrap_or_else(|| "developer".to_string());
+    let invitation = match code {
+        Some(code) => Some(invitations::Invitation::claim(&mut tx, code).await?),
+        None => None,
+    };
+    let (role, initial_tokens, tenant_id) = match &invitation {
+        Some(invitation) => (
+            invitation.role.clone(),
+            invitation.initial_tokens,
+            invitation.tenant_id,
+        ),
+        None => (
+            payload.role.unwrap_or_else(|| "developer".to_string()),
+            payload.initial_tokens.unwrap_or(0_i64),
+            tenants::DEFAULT_TENANT,
+        ),
+    };
+    validate_role(&state.pool, &role).await?;
 
     let rec = sqlx::query(
-        "INSERT INTO users (username, password_hash, role, token_balance) VALUES ($1, $2, $3, $4) RETURNING id",
+        "INSERT INTO users (username, password_hash, password_scheme, role, token_balance, email, tenant_id) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
     )
     .bind(&payload.username)
     .bind(&hashed)
+    .bind(password::SCHEME)
     .bind(&role)
-    .bind(payload.initial_tokens.unwrap_or(0_i64))
-    .fetch_one(&state.pool)
+    .bind(initial_tokens)
+    .bind(&payload.email)
+    .bind(tenant_id)
+    .fetch_one(&mut *tx)
     .await
     .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.constraint() == Some("users_email_idx") => {
+            AuthError::Conflict("email address already in use".to_string())
+        }
         sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
             AuthError::Conflict(format!("user '{}' already exists", payload.username))
         }
@@ -175,44 +415,162 @@ async fn register_user(
     })?;
 
     let id: i32 = rec.get("id");
+    if let Some(invitation) = invitation {
+        invitation.complete(&mut tx, id).await?;
+    }
+    state
+        .registration_events
+        .record(&mut tx, id, "password")
+        .await?;
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    state.registration_events.wake();
+    if let (Some(email), Some(verification)) = (payload.email, &state.verification) {
+        verification
+            .send_or_warn(&state.pool, id, payload.username, email)
+            .await;
+    }
     Ok(Json(RegisterResponse { user_id: id }))
 }
 
 async fn login_user(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
     Json(payload): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, AuthError> {
-    let row = sqlx::query("SELECT id, password_hash, role FROM users WHERE username = $1")
-        .bind(&payload.username)
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|err| match err {
-            sqlx::Error::RowNotFound => AuthError::Unauthorized("invalid credentials".to_string()),
-            other => AuthError::Internal(other.to_string()),
-        })?;
-
-    let stored_hash: String = row.get("password_hash");
-    if !bcrypt::verify(&payload.password, &stored_hash)
-        .map_err(|err| AuthError::Internal(err.to_string()))?
-    {
+    let client = state.logins.client(&headers, peer);
+    state
+        .challenges
+        .check_login(
+            &state.pool,
+            &payload.username,
+            client.ip(),
+            payload.challenge.as_ref(),
+        )
+        .await?;
+    let row = sqlx::query(
+        "SELECT id, password_hash, password_scheme, role, tenant_id FROM users \
+         WHERE username = $1 AND disabled_at IS NULL AND kind = 'human'",
+    )
+    .bind(&payload.username)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+
+    let verified = match &row {
+        Some(row) => {
+            state
+                .passwords
+                .verify(
+                    &payload.password,
+                    row.get("password_hash"),
+                    row.get("password_scheme"),
+                )
+                .await?
+        }
+        None => password::Verified::Mismatch,
+    };
+    let Some(row) = row.filter(|_| verified != password::Verified::Mismatch) else {
+        state
+            .challenges
+            .login_failed(&state.pool, &payload.username, client.ip())
+            .await;
         return Err(AuthError::Unauthorized("invalid credentials".to_string()));
-    }
+    };
     let user_id: i32 = row.get("id");
     let role: String = row.get("role");
-
-    let claims = Claims::new(user_id, &payload.username, &role, &state.jwt);
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(&state.jwt.secret),
+    if verified == password::Verified::Outdated {
+        let stored_hash: String = row.get("password_hash");
+        rehash_password(&state, user_id, &payload.password, &stored_hash).await;
+    }
+    state
+        .challenges
+        .login_succeeded(&state.pool, &payload.username)
+        .await;
+    state.logins.record(&state.pool, user_id, &client).await;
+
+    issue_token(
+        &state,
+        user_id,
+        &payload.username,
+        &role,
+        row.get("tenant_id"),
     )
-    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    .map(Json)
+}
+
+/// Upgrades a hash that just verified to the current scheme and parameters.
+/// Failing to do so doesn't fail the login; the next one tries again.
+async fn rehash_password(state: &AppState, user_id: i32, password: &str, old_hash: &str) {
+    let result = match state.passwords.hash(password).await {
+        Ok(hashed) => sqlx::query(
+            "UPDATE users SET password_hash = $2, password_scheme = $3 \
+             WHERE id = $1 AND password_hash = $4",
+        )
+        .bind(user_id)
+        .bind(&hashed)
+        .bind(password::SCHEME)
+        .bind(old_hash)
+        .execute(&state.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match result {
+        Ok(()) => info!(user_id, "password rehashed"),
+        Err(err) => warn!(user_id, error = %err, "failed to rehash password"),
+    }
+}
+
+/// Signs a platform JWT; used by password and OIDC login alike.
+fn issue_token(
+    state: &AppState,
+    user_id: i32,
+    username: &str,
+    role: &str,
+    tenant_id: i32,
+) -> Result<LoginResponse, AuthError> {
+    let claims = Claims::new(
+        user_id,
+        username,
+        role,
+        tenant_id,
+        &state.jwt.issuer,
+        state.jwt.expiration,
+    );
+    let token = encode(&state.jwt.keys.header(), &claims, state.jwt.keys.encoding())
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
 
-    Ok(Json(LoginResponse {
+    Ok(LoginResponse {
         token,
         expires_at: chrono::DateTime::<Utc>::from_timestamp(claims.exp as i64, 0)
             .expect("valid expiration timestamp"),
-    }))
+    })
+}
+
+/// Revokes the presented token; the API gateway rejects it from then on.
+async fn logout_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AuthError> {
+    let user = authenticate(&headers, &state).await?;
+    let expires_at = chrono::DateTime::<Utc>::from_timestamp(user.expires_at as i64, 0)
+        .ok_or_else(|| AuthError::Unauthorized("invalid token".to_string()))?;
+    sqlx::query(
+        "INSERT INTO revoked_tokens (jti, user_id, expires_at, revoked_by) VALUES ($1, $2, $3, $2) \
+         ON CONFLICT (jti) DO NOTHING",
+    )
+    .bind(&user.jti)
+    .bind(user.user_id)
+    .bind(expires_at)
+    .execute(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(user_id = user.user_id, username = %user.username, "token revoked on logout");
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn list_api_keys(
@@ -221,7 +579,7 @@ async fn list_api_keys(
 ) -> Result<Json<ListApiKeysResponse>, AuthError> {
     let user = authenticate(&headers, &state).await?;
     let records = sqlx::query(
-        "SELECT id, name, created_at, last_used_at FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC",
+        "SELECT id, name, scopes, expires_at, created_at, last_used_at FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC",
     )
     .bind(user.user_id)
     .fetch_all(&state.pool)
@@ -233,6 +591,8 @@ async fn list_api_keys(
         .map(|row| ApiKeySummary {
             id: row.get("id"),
             name: row.get("name"),
+            scopes: row.get("scopes"),
+            expires_at: row.get("expires_at"),
             created_at: row.get("created_at"),
             last_used_at: row.get("last_used_at"),
         })
@@ -256,16 +616,25 @@ async fn create_api_key(
         return Err(AuthError::BadRequest("name must not be empty".to_string()));
     }
     let normalized_name = trimmed.to_string();
+    let scopes = normalize_scopes(payload.scopes.unwrap_or_default())?;
+    if payload.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(AuthError::BadRequest(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
 
     let api_key = generate_api_key();
     let hash = hash_api_key(&api_key);
 
     let record = sqlx::query(
-        "INSERT INTO api_keys (user_id, name, api_key_hash) VALUES ($1, $2, $3) RETURNING id, created_at",
+        "INSERT INTO api_keys (user_id, name, api_key_hash, scopes, expires_at, tenant_id) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, created_at",
     )
     .bind(user.user_id)
     .bind(&normalized_name)
     .bind(&hash)
+    .bind(&scopes)
+    .bind(payload.expires_at)
+    .bind(user.tenant_id)
     .fetch_one(&state.pool)
     .await
     .map_err(|err| AuthError::Internal(err.to_string()))?;
@@ -274,6 +643,8 @@ async fn create_api_key(
         id: record.get("id"),
         name: normalized_name,
         key: api_key,
+        scopes,
+        expires_at: payload.expires_at,
         created_at: record.get("created_at"),
     }))
 }
@@ -312,28 +683,57 @@ async fn authenticate(
         .strip_prefix("Bearer ")
         .ok_or_else(|| AuthError::Unauthorized("unsupported authorization scheme".to_string()))?;
 
-    let validation = state.jwt.validation();
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(&state.jwt.secret),
-        &validation,
-    )
-    .map_err(|_| AuthError::Unauthorized("invalid token".to_string()))?;
-    let claims = token_data.claims;
+    let (id, key) = state
+        .jwt
+        .keys
+        .decoding(token)
+        .ok_or_else(|| AuthError::Unauthorized("invalid token".to_string()))?;
+    let claims = state
+        .jwt
+        .verifier
+        .verify(token, &id, key)
+        .map_err(|_| AuthError::Unauthorized("invalid token".to_string()))?;
+    if claims.scope.is_some() {
+        return Err(AuthError::Forbidden(
+            "service tokens cannot be used with the auth service".to_string(),
+        ));
+    }
 
-    let row = sqlx::query("SELECT username, role FROM users WHERE id = $1")
-        .bind(claims.sub)
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|err| match err {
-            sqlx::Error::RowNotFound => AuthError::Unauthorized("user not found".to_string()),
-            other => AuthError::Internal(other.to_string()),
-        })?;
+    // Revoked by logout or an admin, either this token or all of the user's
+    // tokens issued before `tokens_revoked_at`'s second (see migration 018).
+    let row = sqlx::query(
+        "SELECT username, role, tenant_id, \
+            EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $2) \
+                OR COALESCE(date_trunc('second', tokens_revoked_at) > to_timestamp($3), FALSE) \
+                AS revoked \
+         FROM users WHERE id = $1 AND disabled_at IS NULL",
+    )
+    .bind(claims.sub)
+    .bind(&claims.jti)
+    .bind(claims.iat as f64)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::RowNotFound => AuthError::Unauthorized("user not found".to_string()),
+        other => AuthError::Internal(other.to_string()),
+    })?;
+    if row.get::<bool, _>("revoked") {
+        return Err(AuthError::Unauthorized("token revoked".to_string()));
+    }
+    let tenant_id: i32 = row.get("tenant_id");
+    if claims.tenant.is_some_and(|tenant| tenant != tenant_id) {
+        return Err(AuthError::Unauthorized(
+            "token issued for another tenant".to_string(),
+        ));
+    }
 
     Ok(AuthenticatedUser {
         user_id: claims.sub,
         username: row.get("username"),
         role: row.get("role"),
+        tenant_id,
+        jti: claims.jti,
+        expires_at: claims.exp,
     })
 }
 
@@ -343,39 +743,57 @@ fn generate_api_key() -> String {
     format!("cds_{}", hex_encode(bytes))
 }
 
-fn hash_api_key(key: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(key.as_bytes());
-    hex_encode(hasher.finalize())
-}
-
-impl Claims {
-    fn new(user_id: i32, username: &str, role: &str, jwt: &JwtConfig) -> Self {
-        let now = Utc::now();
-        let exp = now + jwt.expiration;
-        Self {
-            sub: user_id,
-            username: username.to_string(),
-            role: role.to_string(),
-            exp: exp.timestamp() as usize,
-            iat: now.timestamp() as usize,
-            iss: jwt.issuer.clone(),
-            jti: Uuid::new_v4().to_string(),
+const MAX_API_KEY_SCOPES: usize = 32;
+
+/// Validates, sorts and deduplicates requested scopes. No scopes means the
+/// key carries all of its owner's permissions.
+fn normalize_scopes(mut scopes: Vec<String>) -> Result<Vec<String>, AuthError> {
+    for scope in &mut scopes {
+        *scope = scope.trim().to_string();
+        if !is_valid_scope(scope) {
+            let names: Vec<&str> = API_KEY_SCOPES.iter().map(|(name, _)| *name).collect();
+            return Err(AuthError::BadRequest(format!(
+                "unsupported scope '{scope}' (expected one of {} or project:<id>)",
+                names.join(", ")
+            )));
         }
     }
+    scopes.sort_unstable();
+    scopes.dedup();
+    if scopes.len() > MAX_API_KEY_SCOPES {
+        return Err(AuthError::BadRequest(format!(
+            "at most {MAX_API_KEY_SCOPES} scopes per key"
+        )));
+    }
+    Ok(scopes)
 }
 
-fn validate_role(role: &Option<String>) -> Result<(), AuthError> {
-    if let Some(role) = role {
-        match role.as_str() {
-            "admin" | "developer" | "viewer" => Ok(()),
-            _ => Err(AuthError::BadRequest(format!(
-                "unsupported role '{}'",
-                role
-            ))),
-        }
+fn validate_email(email: &str) -> Result<(), AuthError> {
+    let valid = email.len() <= 255
+        && email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+    if valid {
+        Ok(())
     } else {
+        Err(AuthError::BadRequest(format!(
+            "invalid email address '{email}'"
+        )))
+    }
+}
+
+/// Roles live in the `roles` table (migration 014): the built-in ones plus
+/// those admins define through `admin.roles.*`.
+async fn validate_role(pool: &PgPool, role: &str) -> Result<(), AuthError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM roles WHERE name = $1)")
+        .bind(role)
+        .fetch_one(pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    if exists {
         Ok(())
+    } else {
+        Err(AuthError::BadRequest(format!("unsupported role '{role}'")))
     }
 }
 
@@ -385,6 +803,15 @@ struct RegisterRequest {
     password: String,
     role: Option<String>,
     initial_tokens: Option<i64>,
+    /// Where password reset mails go; verified through a token sent there.
+    email: Option<String>,
+    /// Required with `AUTH_REGISTRATION=invite_only`; sets role and initial
+    /// tokens.
+    #[serde(default)]
+    invite_code: Option<String>,
+    /// Required when `AUTH_CHALLENGE` is set (see `challenge`).
+    #[serde(default)]
+    challenge: Option<challenge::Solution>,
 }
 
 #[derive(Debug, Serialize)]
@@ -396,6 +823,9 @@ struct RegisterResponse {
 struct LoginRequest {
     username: String,
     password: String,
+    /// Required after repeated failed logins when `AUTH_CHALLENGE` is set.
+    #[serde(default)]
+    challenge: Option<challenge::Solution>,
 }
 
 #[derive(Debug, Serialize)]
@@ -408,6 +838,11 @@ struct LoginResponse {
 struct CreateApiKeyRequest {
     #[serde(default)]
     name: Option<String>,
+    /// Limits the key, e.g. `["fs:read", "project:<id>"]`.
+    #[serde(default)]
+    scopes: Option<Vec<String>>,
+    #[serde(default)]
+    expires_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -415,6 +850,9 @@ struct CreateApiKeyResponse {
     id: Uuid,
     name: String,
     key: String,
+    scopes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<chrono::DateTime<Utc>>,
     created_at: chrono::DateTime<Utc>,
 }
 
@@ -427,6 +865,9 @@ struct ListApiKeysResponse {
 struct ApiKeySummary {
     id: Uuid,
     name: String,
+    scopes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<chrono::DateTime<Utc>>,
     created_at: chrono::DateTime<Utc>,
     #[serde(skip_serializing_if = "Option::is_none")]
     last_used_at: Option<chrono::DateTime<Utc>>,
@@ -438,27 +879,56 @@ enum AuthError {
     BadRequest(String),
     #[error("unauthorized: {0}")]
     Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
     #[error("conflict: {0}")]
     Conflict(String),
     #[error("not found: {0}")]
     NotFound(String),
     #[error("internal error: {0}")]
     Internal(String),
+    #[error("password rejected by policy: {0:?}")]
+    PasswordPolicy(Vec<password_policy::Violation>),
+    #[error("challenge required: {0}")]
+    ChallengeRequired(String, serde_json::Value),
 }
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
         let (status, message) = match &self {
             AuthError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AuthError::PasswordPolicy(_) => (
+                StatusCode::BAD_REQUEST,
+                "password does not meet the password policy".to_string(),
+            ),
             AuthError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
+            AuthError::ChallengeRequired(msg, _) => {
+                (StatusCode::PRECONDITION_REQUIRED, msg.clone())
+            }
+            AuthError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg.clone()),
             AuthError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
             AuthError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             AuthError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
         };
-        error!("auth error", %message, kind = ?self);
-        let body = Json(serde_json::json!({
+        error!(%message, kind = ?self, "auth error");
+        let mut body = serde_json::json!({
             "error": message,
-        }));
+        });
+        match &self {
+            AuthError::PasswordPolicy(violations) => {
+                body["violations"] = serde_json::json!(violations);
+            }
+            AuthError::ChallengeRequired(_, challenge) => {
+                body["challenge"] = challenge.clone();
+            }
+            _ => {}
+        }
+        let body = Json(body);
         (status, body).into_response()
     }
 }
+
+/// Wraps a storage or crypto failure that the caller cannot act on.
+fn internal(err: impl std::fmt::Display) -> AuthError {
+    AuthError::Internal(err.to_string())
+}
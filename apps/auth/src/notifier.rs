@@ -0,0 +1,318 @@
+//! Delivery of one-time tokens for password reset and email verification.
+//! A token never appears in an HTTP response; it goes to the [`Notifier`]
+//! chosen by `AUTH_RESET_NOTIFIER`: `smtp` mails it to the account's address,
+//! `webhook` POSTs it to an operator endpoint (chat bot, ticket system, ...)
+//! signed like the API's webhooks. Without a notifier both flows are
+//! disabled.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context as _};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hex::encode as hex_encode;
+use hmac::{Hmac, Mac};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde_json::json;
+use sha2::Sha256;
+use tracing::{info, warn};
+
+/// What the token in a [`Notice`] is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl Purpose {
+    fn event(self) -> &'static str {
+        match self {
+            Self::PasswordReset => "password_reset.requested",
+            Self::EmailVerification => "email_verification.requested",
+        }
+    }
+}
+
+/// What a notifier needs to reach the user.
+#[derive(Debug, Clone)]
+pub(crate) struct Notice {
+    pub(crate) purpose: Purpose,
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) email: Option<String>,
+    pub(crate) token: String,
+    pub(crate) expires_at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub(crate) trait Notifier: Send + Sync {
+    async fn deliver(&self, notice: &Notice) -> anyhow::Result<()>;
+}
+
+/// Delivers in the background, so a slow mail server or webhook does not
+/// hold up the response.
+pub(crate) fn spawn_delivery(notifier: Arc<dyn Notifier>, notice: Notice) {
+    tokio::spawn(async move {
+        match notifier.deliver(&notice).await {
+            Ok(()) => info!(
+                user_id = notice.user_id,
+                event = notice.purpose.event(),
+                "token sent"
+            ),
+            Err(err) => warn!(
+                user_id = notice.user_id,
+                event = notice.purpose.event(),
+                error = %err,
+                "failed to deliver token"
+            ),
+        }
+    });
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn required(name: &str) -> anyhow::Result<String> {
+    env(name).ok_or_else(|| anyhow!("{name} is required for AUTH_RESET_NOTIFIER"))
+}
+
+/// Returns `None` when no notifier is configured.
+pub(crate) fn from_env() -> anyhow::Result<Option<Arc<dyn Notifier>>> {
+    let links = Links {
+        reset: env("AUTH_RESET_LINK"),
+        verify: env("AUTH_VERIFY_LINK"),
+    };
+    match env("AUTH_RESET_NOTIFIER").as_deref() {
+        None => Ok(None),
+        Some("smtp") => Ok(Some(Arc::new(SmtpNotifier::from_env(links)?))),
+        Some("webhook") => Ok(Some(Arc::new(WebhookNotifier::from_env(links)?))),
+        Some(other) => Err(anyhow!(
+            "unsupported AUTH_RESET_NOTIFIER '{other}' (expected smtp or webhook)"
+        )),
+    }
+}
+
+/// `AUTH_RESET_LINK` and `AUTH_VERIFY_LINK` may contain `{token}`, e.g.
+/// `https://studio.example.com/reset?token={token}`.
+struct Links {
+    reset: Option<String>,
+    verify: Option<String>,
+}
+
+impl Links {
+    fn get(&self, notice: &Notice) -> Option<String> {
+        let template = match notice.purpose {
+            Purpose::PasswordReset => &self.reset,
+            Purpose::EmailVerification => &self.verify,
+        };
+        template
+            .as_deref()
+            .map(|template| template.replace("{token}", &notice.token))
+    }
+}
+
+struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    links: Links,
+}
+
+impl SmtpNotifier {
+    /// `AUTH_SMTP_TLS` is `starttls` (default), `tls` or `none`; the last one
+    /// is only meant for a local relay.
+    fn from_env(links: Links) -> anyhow::Result<Self> {
+        let host = required("AUTH_SMTP_HOST")?;
+        let mut builder = match env("AUTH_SMTP_TLS").as_deref().unwrap_or("starttls") {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            other => bail!("unsupported AUTH_SMTP_TLS '{other}' (expected starttls, tls or none)"),
+        };
+        if let Some(port) = env("AUTH_SMTP_PORT") {
+            builder = builder.port(port.parse().context("AUTH_SMTP_PORT must be a port")?);
+        }
+        if let (Some(username), Some(password)) =
+            (env("AUTH_SMTP_USERNAME"), env("AUTH_SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        let from = required("AUTH_SMTP_FROM")?
+            .parse()
+            .context("AUTH_SMTP_FROM must be a mail address")?;
+        Ok(Self {
+            transport: builder.timeout(Some(Duration::from_secs(10))).build(),
+            from,
+            links,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    async fn deliver(&self, notice: &Notice) -> anyhow::Result<()> {
+        let Some(email) = &notice.email else {
+            bail!("user has no email address");
+        };
+        let (subject, request, ignore) = match notice.purpose {
+            Purpose::PasswordReset => (
+                "Password reset",
+                "someone asked to reset the password of your account. \
+                 Use this code to choose a new one",
+                "your password stays unchanged",
+            ),
+            Purpose::EmailVerification => (
+                "Confirm your email address",
+                "this address was entered for your account. Use this code to confirm it",
+                "the address will not be linked to the account",
+            ),
+        };
+        let mut body = format!(
+            "Hello {},\n\n{request}:\n\n    {}\n\n",
+            notice.username, notice.token
+        );
+        if let Some(link) = self.links.get(notice) {
+            body.push_str(&format!("Or open {link}\n\n"));
+        }
+        body.push_str(&format!(
+            "The code can be used once and expires at {}. If you did not ask for this, \
+             ignore this mail; {ignore}.\n",
+            notice.expires_at.to_rfc3339()
+        ));
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.parse().context("invalid email address")?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    links: Links,
+}
+
+impl WebhookNotifier {
+    fn from_env(links: Links) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            client,
+            url: required("AUTH_RESET_WEBHOOK_URL")?,
+            secret: env("AUTH_RESET_WEBHOOK_SECRET"),
+            links,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn deliver(&self, notice: &Notice) -> anyhow::Result<()> {
+        let body = json!({
+            "event": notice.purpose.event(),
+            "user_id": notice.user_id,
+            "username": notice.username,
+            "email": notice.email,
+            "token": notice.token,
+            "link": self.links.get(notice),
+            "expires_at": notice.expires_at.to_rfc3339(),
+        })
+        .to_string();
+        let timestamp = Utc::now().timestamp();
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("content-type", "application/json")
+            .header("x-webhook-event", notice.purpose.event())
+            .header("x-webhook-timestamp", timestamp.to_string());
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                "x-webhook-signature",
+                format!("sha256={}", sign(secret, timestamp, &body)),
+            );
+        }
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`, the scheme of the API's webhooks.
+pub(crate) fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex_encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notice(purpose: Purpose) -> Notice {
+        Notice {
+            purpose,
+            user_id: 7,
+            username: "jo".to_string(),
+            email: None,
+            token: "t0k3n".to_string(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn links_fill_in_the_token_for_their_purpose() {
+        let links = Links {
+            reset: Some("https://studio.example.com/reset?token={token}".to_string()),
+            verify: None,
+        };
+        assert_eq!(
+            links.get(&notice(Purpose::PasswordReset)).as_deref(),
+            Some("https://studio.example.com/reset?token=t0k3n")
+        );
+        assert_eq!(links.get(&notice(Purpose::EmailVerification)), None);
+    }
+
+    #[test]
+    fn signatures_cover_timestamp_and_body() {
+        assert_eq!(
+            sign("secret", 1_700_000_000, r#"{"a":1}"#),
+            "49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686"
+        );
+        assert_ne!(
+            sign("secret", 1_700_000_001, r#"{"a":1}"#),
+            sign("secret", 1_700_000_000, r#"{"a":1}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn smtp_needs_an_address() {
+        let notifier = SmtpNotifier {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("localhost").build(),
+            from: "coder@example.com".parse().unwrap(),
+            links: Links {
+                reset: None,
+                verify: None,
+            },
+        };
+        let err = notifier
+            .deliver(&notice(Purpose::PasswordReset))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "user has no email address");
+    }
+}
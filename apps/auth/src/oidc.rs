@@ -0,0 +1,661 @@
+//! Single sign-on with an external OpenID Connect provider (authorization
+//! code flow with PKCE). `GET /auth/oidc/login` redirects to the provider;
+//! `GET /auth/oidc/callback` checks that `state` matches the `oidc_state`
+//! cookie set by the login, so a callback can only finish a login started
+//! in the same browser, exchanges the code, validates the ID token against
+//! the provider's JWKS (issuer, audience, nonce) and signs the
+//! platform's own JWT for the linked local user. An identity seen for the
+//! first time gets a new user with `AUTH_OIDC_DEFAULT_ROLE`, unless
+//! `AUTH_OIDC_AUTO_PROVISION=false`. The token is returned as JSON or, for
+//! logins started with an allowed `redirect_to`, in the fragment of a
+//! redirect there, so it never shows up in server logs.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use tokio::sync::{OnceCell, RwLock};
+use tracing::{info, warn};
+
+use crate::tenants::DEFAULT_TENANT;
+use crate::{internal, issue_token, password, validate_role, AppState, AuthError};
+
+/// How long a started login may take until the callback.
+const LOGIN_TTL_MINUTES: i32 = 10;
+const MAX_USERNAME: usize = 64;
+/// Binds a started login to the browser that started it (login CSRF).
+const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Debug, Deserialize)]
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct Oidc {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    scopes: String,
+    default_role: String,
+    auto_provision: bool,
+    allowed_redirects: Vec<String>,
+    client: Client,
+    provider: OnceCell<Provider>,
+    /// The provider's signing keys by `kid`, refetched on an unknown one.
+    keys: RwLock<Vec<(Option<String>, DecodingKey)>>,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64_URL.encode(bytes)
+}
+
+impl Oidc {
+    /// `None` unless `AUTH_OIDC_ISSUER` is set.
+    pub(crate) async fn from_env(pool: &PgPool) -> anyhow::Result<Option<Self>> {
+        let Some(issuer) = env("AUTH_OIDC_ISSUER") else {
+            return Ok(None);
+        };
+        let required = |name: &str| {
+            env(name).ok_or_else(|| anyhow!("{name} is required with AUTH_OIDC_ISSUER"))
+        };
+        let default_role = env("AUTH_OIDC_DEFAULT_ROLE").unwrap_or_else(|| "developer".into());
+        validate_role(pool, &default_role)
+            .await
+            .map_err(|err| anyhow!("AUTH_OIDC_DEFAULT_ROLE: {err}"))?;
+        Ok(Some(Self {
+            inner: Arc::new(Inner {
+                issuer,
+                client_id: required("AUTH_OIDC_CLIENT_ID")?,
+                client_secret: required("AUTH_OIDC_CLIENT_SECRET")?,
+                redirect_url: required("AUTH_OIDC_REDIRECT_URL")?,
+                scopes: env("AUTH_OIDC_SCOPES").unwrap_or_else(|| "openid email profile".into()),
+                default_role,
+                auto_provision: env("AUTH_OIDC_AUTO_PROVISION")
+                    .map(|value| value != "false")
+                    .unwrap_or(true),
+                allowed_redirects: env("AUTH_OIDC_ALLOWED_REDIRECTS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|entry| entry.trim().to_string())
+                    .filter(|entry| !entry.is_empty())
+                    .collect(),
+                client: Client::builder()
+                    .timeout(std::time::Duration::from_secs(10))
+                    .build()?,
+                provider: OnceCell::new(),
+                keys: RwLock::new(Vec::new()),
+            }),
+        }))
+    }
+
+    /// Discovery document, fetched on first use.
+    async fn provider(&self) -> Result<&Provider, AuthError> {
+        self.inner
+            .provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.inner.issuer.trim_end_matches('/')
+                );
+                let response = self.inner.client.get(url).send().await.map_err(internal)?;
+                response
+                    .error_for_status()
+                    .map_err(internal)?
+                    .json::<Provider>()
+                    .await
+                    .map_err(internal)
+            })
+            .await
+    }
+
+    /// The `Set-Cookie` value carrying `value` as the login state; an empty
+    /// value with `max_age` 0 removes the cookie again.
+    fn state_cookie(&self, value: &str, max_age: i32) -> String {
+        let secure = if self.inner.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        format!(
+            "{STATE_COOKIE}={value}; Path=/auth/oidc; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}"
+        )
+    }
+
+    /// A target is allowed if it equals an entry of
+    /// `AUTH_OIDC_ALLOWED_REDIRECTS` or lies below one ending in `/`.
+    fn redirect_allowed(&self, target: &str) -> bool {
+        self.inner.allowed_redirects.iter().any(|allowed| {
+            target == allowed || (allowed.ends_with('/') && target.starts_with(allowed.as_str()))
+        })
+    }
+
+    async fn exchange(&self, code: &str, verifier: &str) -> Result<String, AuthError> {
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            id_token: String,
+        }
+        let provider = self.provider().await?;
+        let response = self
+            .inner
+            .client
+            .post(&provider.token_endpoint)
+            .basic_auth(&self.inner.client_id, Some(&self.inner.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.inner.redirect_url),
+                ("code_verifier", verifier),
+            ])
+            .send()
+            .await
+            .map_err(internal)?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            warn!(%status, %body, "oidc token exchange failed");
+            return Err(AuthError::Unauthorized(
+                "identity provider rejected the login".to_string(),
+            ));
+        }
+        Ok(response
+            .json::<TokenResponse>()
+            .await
+            .map_err(internal)?
+            .id_token)
+    }
+
+    async fn key(&self, kid: Option<&str>) -> Result<DecodingKey, AuthError> {
+        if let Some(key) = find_key(&self.inner.keys.read().await, kid) {
+            return Ok(key);
+        }
+        let provider = self.provider().await?;
+        let set: JwkSet = self
+            .inner
+            .client
+            .get(&provider.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(internal)?
+            .json()
+            .await
+            .map_err(internal)?;
+        let keys: Vec<_> = set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk).ok()?;
+                Some((jwk.common.key_id.clone(), key))
+            })
+            .collect();
+        let key = find_key(&keys, kid);
+        *self.inner.keys.write().await = keys;
+        key.ok_or_else(|| AuthError::Unauthorized("unknown id token signing key".to_string()))
+    }
+
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<Identity, AuthError> {
+        let invalid = || AuthError::Unauthorized("invalid id token".to_string());
+        let header = decode_header(id_token).map_err(|_| invalid())?;
+        // Only asymmetric algorithms; the client secret never signs tokens.
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+        ) {
+            return Err(invalid());
+        }
+        let key = self.key(header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.inner.client_id]);
+        validation.set_issuer(&[&self.inner.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let identity = decode::<Identity>(id_token, &key, &validation)
+            .map_err(|_| invalid())?
+            .claims;
+        if identity.nonce.as_deref() != Some(nonce) {
+            return Err(invalid());
+        }
+        Ok(identity)
+    }
+}
+
+fn find_key(keys: &[(Option<String>, DecodingKey)], kid: Option<&str>) -> Option<DecodingKey> {
+    match kid {
+        Some(kid) => keys
+            .iter()
+            .find(|(key_id, _)| key_id.as_deref() == Some(kid))
+            .map(|(_, key)| key.clone()),
+        // Without a `kid` only an unambiguous set will do.
+        None if keys.len() == 1 => Some(keys[0].1.clone()),
+        None => None,
+    }
+}
+
+/// Claims of the provider's ID token we use.
+#[derive(Debug, Deserialize)]
+struct Identity {
+    sub: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+}
+
+impl Identity {
+    fn verified_email(&self) -> Option<&str> {
+        self.email
+            .as_deref()
+            .filter(|_| self.email_verified == Some(true))
+    }
+
+    /// A username derived from the identity; the caller appends a suffix on
+    /// collisions.
+    fn username_base(&self) -> String {
+        let source = self
+            .preferred_username
+            .as_deref()
+            .or_else(|| {
+                self.email
+                    .as_deref()
+                    .and_then(|email| email.split('@').next())
+            })
+            .unwrap_or("user");
+        let mut base: String = source
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+            .take(MAX_USERNAME - 4)
+            .collect();
+        if base.is_empty() {
+            base.push_str("user");
+        }
+        base
+    }
+}
+
+fn enabled(state: &AppState) -> Result<&Oidc, AuthError> {
+    state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| AuthError::NotFound("single sign-on is not enabled".to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoginQuery {
+    #[serde(default)]
+    redirect_to: Option<String>,
+}
+
+/// The value of cookie `name` in the request, if it sent one.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub(crate) async fn login(
+    State(state): State<AppState>,
+    Query(query): Query<LoginQuery>,
+) -> Result<Response, AuthError> {
+    let oidc = enabled(&state)?;
+    if let Some(target) = &query.redirect_to {
+        if !oidc.redirect_allowed(target) {
+            return Err(AuthError::BadRequest(
+                "redirect_to is not allowed".to_string(),
+            ));
+        }
+    }
+    let provider = oidc.provider().await?;
+    let (login_state, nonce, verifier) = (random_token(), random_token(), random_token());
+    let challenge = BASE64_URL.encode(Sha256::digest(verifier.as_bytes()));
+
+    sqlx::query("DELETE FROM oidc_logins WHERE created_at < NOW() - make_interval(mins => $1)")
+        .bind(LOGIN_TTL_MINUTES)
+        .execute(&state.pool)
+        .await
+        .map_err(internal)?;
+    sqlx::query(
+        "INSERT INTO oidc_logins (state, nonce, code_verifier, redirect_to) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(&login_state)
+    .bind(&nonce)
+    .bind(&verifier)
+    .bind(&query.redirect_to)
+    .execute(&state.pool)
+    .await
+    .map_err(internal)?;
+
+    let url = Url::parse_with_params(
+        &provider.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &oidc.inner.client_id),
+            ("redirect_uri", &oidc.inner.redirect_url),
+            ("scope", &oidc.inner.scopes),
+            ("state", &login_state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(internal)?;
+    let cookie = oidc.state_cookie(&login_state, LOGIN_TTL_MINUTES * 60);
+    Ok(([(SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CallbackQuery {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+pub(crate) async fn callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, AuthError> {
+    let oidc = enabled(&state)?;
+    if let Some(error) = query.error {
+        return Err(AuthError::Unauthorized(format!(
+            "identity provider returned '{error}'"
+        )));
+    }
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return Err(AuthError::BadRequest(
+            "code and state are required".to_string(),
+        ));
+    };
+    if cookie(&headers, STATE_COOKIE) != Some(login_state.as_str()) {
+        return Err(AuthError::Unauthorized(
+            "login was not started in this browser".to_string(),
+        ));
+    }
+    // Consuming the row makes every `state` single-use.
+    let login = sqlx::query(
+        "DELETE FROM oidc_logins WHERE state = $1 AND created_at > NOW() - make_interval(mins => $2) \
+         RETURNING nonce, code_verifier, redirect_to",
+    )
+    .bind(&login_state)
+    .bind(LOGIN_TTL_MINUTES)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal)?
+    .ok_or_else(|| AuthError::Unauthorized("unknown or expired login".to_string()))?;
+
+    let id_token = oidc
+        .exchange(&code, &login.get::<String, _>("code_verifier"))
+        .await?;
+    let identity = oidc
+        .verify_id_token(&id_token, &login.get::<String, _>("nonce"))
+        .await?;
+    let (user_id, username, role, tenant_id) = link_user(&state, oidc, &identity).await?;
+    let token = issue_token(&state, user_id, &username, &role, tenant_id)?;
+    let client = state.logins.client(&headers, peer);
+    state.logins.record(&state.pool, user_id, &client).await;
+    info!(user_id, %username, "oidc login");
+
+    let clear = [(SET_COOKIE, oidc.state_cookie("", 0))];
+    match login.get::<Option<String>, _>("redirect_to") {
+        Some(target) => Ok((
+            clear,
+            Redirect::to(&format!(
+                "{target}#token={}&expires_at={}",
+                token.token,
+                token.expires_at.timestamp()
+            )),
+        )
+            .into_response()),
+        None => Ok((clear, Json(token)).into_response()),
+    }
+}
+
+/// The local user behind an identity, provisioning one if allowed. Accounts
+/// are never linked by email address: whether the provider verified it is
+/// the provider's word, and matching on it would hand over existing accounts.
+/// Provisioned accounts join the default tenant.
+async fn link_user(
+    state: &AppState,
+    oidc: &Oidc,
+    identity: &Identity,
+) -> Result<(i32, String, String, i32), AuthError> {
+    let issuer = &oidc.inner.issuer;
+    let linked = sqlx::query(
+        "UPDATE user_identities SET last_login_at = NOW(), email = COALESCE($3, email) \
+         FROM users WHERE users.id = user_identities.user_id \
+            AND user_identities.issuer = $1 AND user_identities.subject = $2 \
+         RETURNING users.id, users.username, users.role, users.tenant_id, \
+            users.disabled_at IS NOT NULL AS disabled",
+    )
+    .bind(issuer)
+    .bind(&identity.sub)
+    .bind(&identity.email)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal)?;
+    if let Some(row) = linked {
+        if row.get::<bool, _>("disabled") {
+            return Err(AuthError::Unauthorized("account disabled".to_string()));
+        }
+        return Ok((
+            row.get("id"),
+            row.get("username"),
+            row.get("role"),
+            row.get("tenant_id"),
+        ));
+    }
+    if !oidc.inner.auto_provision {
+        return Err(AuthError::Unauthorized(
+            "no account is linked to this identity".to_string(),
+        ));
+    }
+
+    // Nobody can log in with this password; a reset sets a real one.
+    let password_hash = state.passwords.unusable().await?;
+    let mut tx = state.pool.begin().await.map_err(internal)?;
+    let email_taken: bool = match identity.verified_email() {
+        Some(email) => {
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE lower(email) = lower($1))")
+                .bind(email)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(internal)?
+        }
+        None => true,
+    };
+    let email = identity.verified_email().filter(|_| !email_taken);
+    let base = identity.username_base();
+    let mut user = None;
+    for attempt in 1..=20 {
+        let username = if attempt == 1 {
+            base.clone()
+        } else {
+            format!("{base}-{attempt}")
+        };
+        // Only addresses the provider verified are stored, as verified.
+        let id: Option<i32> = sqlx::query_scalar(
+            "INSERT INTO users (username, password_hash, password_scheme, role, email, email_verified_at) \
+             VALUES ($1, $2, $5, $3, $4, CASE WHEN $4 IS NOT NULL THEN NOW() END) \
+             ON CONFLICT (username) DO NOTHING RETURNING id",
+        )
+        .bind(&username)
+        .bind(&password_hash)
+        .bind(&oidc.inner.default_role)
+        .bind(email)
+        .bind(password::SCHEME)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(internal)?;
+        if let Some(id) = id {
+            user = Some((id, username));
+            break;
+        }
+    }
+    let (user_id, username) =
+        user.ok_or_else(|| AuthError::Conflict("no free username for this identity".to_string()))?;
+    sqlx::query(
+        "INSERT INTO user_identities (issuer, subject, user_id, email, last_login_at) \
+         VALUES ($1, $2, $3, $4, NOW())",
+    )
+    .bind(issuer)
+    .bind(&identity.sub)
+    .bind(user_id)
+    .bind(&identity.email)
+    .execute(&mut *tx)
+    .await
+    .map_err(internal)?;
+    state
+        .registration_events
+        .record(&mut tx, user_id, "oidc")
+        .await?;
+    tx.commit().await.map_err(internal)?;
+    state.registration_events.wake();
+    info!(user_id, %username, "provisioned user from oidc identity");
+    Ok((
+        user_id,
+        username,
+        oidc.inner.default_role.clone(),
+        DEFAULT_TENANT,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc(redirect_url: &str, allowed_redirects: &[&str]) -> Oidc {
+        Oidc {
+            inner: Arc::new(Inner {
+                issuer: "https://id.example.com".to_string(),
+                client_id: "coder".to_string(),
+                client_secret: "secret".to_string(),
+                redirect_url: redirect_url.to_string(),
+                scopes: "openid".to_string(),
+                default_role: "developer".to_string(),
+                auto_provision: true,
+                allowed_redirects: allowed_redirects.iter().map(|s| s.to_string()).collect(),
+                client: Client::new(),
+                provider: OnceCell::new(),
+                keys: RwLock::new(Vec::new()),
+            }),
+        }
+    }
+
+    #[test]
+    fn state_cookie_is_scoped_to_the_oidc_routes() {
+        let https = oidc("https://coder.example.com/auth/oidc/callback", &[]);
+        assert_eq!(
+            https.state_cookie("abc", 600),
+            "oidc_state=abc; Path=/auth/oidc; Max-Age=600; HttpOnly; SameSite=Lax; Secure"
+        );
+        let local = oidc("http://localhost:6814/auth/oidc/callback", &[]);
+        assert_eq!(
+            local.state_cookie("", 0),
+            "oidc_state=; Path=/auth/oidc; Max-Age=0; HttpOnly; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn callback_state_is_read_from_the_cookie_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(cookie(&headers, STATE_COOKIE), None);
+        headers.append(COOKIE, "theme=dark; oidc_state=abc".parse().unwrap());
+        headers.append(COOKIE, "other=1".parse().unwrap());
+        assert_eq!(cookie(&headers, STATE_COOKIE), Some("abc"));
+        assert_eq!(cookie(&headers, "other"), Some("1"));
+        assert_eq!(cookie(&headers, "oidc"), None);
+    }
+
+    #[test]
+    fn redirects_must_match_an_allowed_entry() {
+        let oidc = oidc(
+            "https://coder.example.com/auth/oidc/callback",
+            &["https://app.example.com/", "https://exact.example.com/done"],
+        );
+        assert!(oidc.redirect_allowed("https://app.example.com/sso"));
+        assert!(oidc.redirect_allowed("https://exact.example.com/done"));
+        assert!(!oidc.redirect_allowed("https://exact.example.com/done/more"));
+        assert!(!oidc.redirect_allowed("https://app.example.com.evil.test/"));
+    }
+
+    #[test]
+    fn usernames_come_from_the_identity() {
+        let identity = |preferred: Option<&str>, email: Option<&str>| Identity {
+            sub: "42".to_string(),
+            nonce: None,
+            email: email.map(str::to_string),
+            email_verified: Some(false),
+            preferred_username: preferred.map(str::to_string),
+        };
+        assert_eq!(identity(Some("Jo Doe!"), None).username_base(), "JoDoe");
+        assert_eq!(
+            identity(None, Some("jo.doe@example.com")).username_base(),
+            "jo.doe"
+        );
+        assert_eq!(identity(Some("***"), None).username_base(), "user");
+        assert_eq!(
+            identity(None, Some("jo@example.com")).verified_email(),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_without_a_kid_need_an_unambiguous_set() {
+        let key = || DecodingKey::from_secret(b"key");
+        let one = vec![(Some("a".to_string()), key())];
+        let two = vec![(Some("a".to_string()), key()), (None, key())];
+        assert!(find_key(&one, None).is_some());
+        assert!(find_key(&two, None).is_none());
+        assert!(find_key(&two, Some("a")).is_some());
+        assert!(find_key(&two, Some("b")).is_none());
+    }
+}
@@ -0,0 +1,202 @@
+//! Password hashing. New hashes are argon2id with `AUTH_ARGON2_MEMORY_KIB`,
+//! `AUTH_ARGON2_ITERATIONS` and `AUTH_ARGON2_PARALLELISM` (default 19456 KiB,
+//! 2, 1: the OWASP baseline). `users.password_scheme` (migration 024) says
+//! how a stored hash was made; bcrypt hashes from before keep verifying and,
+//! like argon2id hashes with outdated parameters, are replaced on the next
+//! successful login. Hashing and verifying take tens of milliseconds of CPU
+//! each and run on tokio's blocking pool, off the request executor.
+
+use anyhow::{anyhow, Context as _};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+
+use crate::{hex_encode, internal, AuthError};
+
+/// The scheme of every hash [`Passwords::hash`] returns.
+pub(crate) const SCHEME: &str = "argon2id";
+const BCRYPT: &str = "bcrypt";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verified {
+    Mismatch,
+    Match,
+    /// Matches, but the hash should be replaced by [`Passwords::hash`].
+    Outdated,
+}
+
+#[derive(Clone)]
+pub(crate) struct Passwords {
+    params: Params,
+}
+
+impl Passwords {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        let get = |name: &str, default: u32| match std::env::var(name) {
+            Ok(value) => value
+                .parse::<u32>()
+                .with_context(|| format!("{name} must be a positive number")),
+            Err(_) => Ok(default),
+        };
+        let params = Params::new(
+            get("AUTH_ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST)?,
+            get("AUTH_ARGON2_ITERATIONS", Params::DEFAULT_T_COST)?,
+            get("AUTH_ARGON2_PARALLELISM", Params::DEFAULT_P_COST)?,
+            None,
+        )
+        .map_err(|err| anyhow!("invalid argon2 parameters: {err}"))?;
+        Ok(Self { params })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Runs `work` with these settings on the blocking pool.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&Self) -> Result<T, AuthError> + Send + 'static,
+    ) -> Result<T, AuthError> {
+        let passwords = self.clone();
+        tokio::task::spawn_blocking(move || work(&passwords))
+            .await
+            .map_err(internal)?
+    }
+
+    pub(crate) async fn hash(&self, password: &str) -> Result<String, AuthError> {
+        let password = password.to_string();
+        self.blocking(move |passwords| passwords.hash_now(&password))
+            .await
+    }
+
+    fn hash_now(&self, password: &str) -> Result<String, AuthError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(internal)
+    }
+
+    /// A hash of random bytes, for accounts that must not log in with a
+    /// password (single sign-on, service clients, forced resets).
+    pub(crate) async fn unusable(&self) -> Result<String, AuthError> {
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        self.hash(&hex_encode(bytes)).await
+    }
+
+    pub(crate) async fn verify(
+        &self,
+        password: &str,
+        hash: &str,
+        scheme: &str,
+    ) -> Result<Verified, AuthError> {
+        let (password, hash, scheme) = (password.to_string(), hash.to_string(), scheme.to_string());
+        self.blocking(move |passwords| passwords.verify_now(&password, &hash, &scheme))
+            .await
+    }
+
+    fn verify_now(&self, password: &str, hash: &str, scheme: &str) -> Result<Verified, AuthError> {
+        match scheme {
+            BCRYPT => Ok(match bcrypt::verify(password, hash).map_err(internal)? {
+                true => Verified::Outdated,
+                false => Verified::Mismatch,
+            }),
+            SCHEME => {
+                let parsed = PasswordHash::new(hash).map_err(internal)?;
+                if self
+                    .argon2()
+                    .verify_password(password.as_bytes(), &parsed)
+                    .is_err()
+                {
+                    return Ok(Verified::Mismatch);
+                }
+                // Only the cost parameters; a parsed hash also carries its
+                // output length, which the configured ones leave unset.
+                let current = parsed.algorithm == Algorithm::Argon2id.ident()
+                    && Params::try_from(&parsed).is_ok_and(|params| {
+                        (params.m_cost(), params.t_cost(), params.p_cost())
+                            == (
+                                self.params.m_cost(),
+                                self.params.t_cost(),
+                                self.params.p_cost(),
+                            )
+                    });
+                Ok(if current {
+                    Verified::Match
+                } else {
+                    Verified::Outdated
+                })
+            }
+            other => Err(internal(format!("unknown password scheme '{other}'"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passwords(memory_kib: u32) -> Passwords {
+        Passwords {
+            params: Params::new(memory_kib, 1, 1, None).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn argon2id_hashes_verify() {
+        let passwords = passwords(64);
+        let hash = passwords.hash("quokka-jump-7").await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(
+            passwords
+                .verify("quokka-jump-7", &hash, SCHEME)
+                .await
+                .unwrap(),
+            Verified::Match
+        );
+        assert_eq!(
+            passwords
+                .verify("quokka-jump-8", &hash, SCHEME)
+                .await
+                .unwrap(),
+            Verified::Mismatch
+        );
+        let unusable = passwords.unusable().await.unwrap();
+        assert_eq!(
+            passwords.verify("", &unusable, SCHEME).await.unwrap(),
+            Verified::Mismatch
+        );
+    }
+
+    #[tokio::test]
+    async fn old_parameters_and_bcrypt_are_outdated() {
+        let hash = passwords(64).hash("quokka-jump-7").await.unwrap();
+        assert_eq!(
+            passwords(128)
+                .verify("quokka-jump-7", &hash, SCHEME)
+                .await
+                .unwrap(),
+            Verified::Outdated
+        );
+
+        let bcrypt_hash = bcrypt::hash("quokka-jump-7", 4).unwrap();
+        let current = passwords(64);
+        assert_eq!(
+            current
+                .verify("quokka-jump-7", &bcrypt_hash, BCRYPT)
+                .await
+                .unwrap(),
+            Verified::Outdated
+        );
+        assert_eq!(
+            current
+                .verify("quokka-jump-8", &bcrypt_hash, BCRYPT)
+                .await
+                .unwrap(),
+            Verified::Mismatch
+        );
+        assert!(current.verify("x", &bcrypt_hash, "md5").await.is_err());
+    }
+}
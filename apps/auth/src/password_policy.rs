@@ -0,0 +1,315 @@
+//! Rules new passwords must meet, configured per deployment:
+//! `AUTH_PASSWORD_MIN_LENGTH` (default 12) and `AUTH_PASSWORD_MAX_LENGTH`
+//! (default 256, in characters), `AUTH_PASSWORD_REQUIRED_CLASSES` (any of
+//! `lower`, `upper`, `digit`, `symbol`, comma-separated), a deny list of
+//! common passwords (built in unless `AUTH_PASSWORD_DENY_COMMON=false`, plus
+//! one per line from `AUTH_PASSWORD_DENYLIST_FILE`) and, with
+//! `AUTH_PASSWORD_MIN_SCORE` (0-4), a minimum zxcvbn strength score. A
+//! rejected password fails with every violated rule listed, and
+//! `GET /auth/password-policy` lets clients show the rules up front.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context as _};
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+use crate::{AppState, AuthError};
+
+const DEFAULT_MIN_LENGTH: usize = 12;
+const DEFAULT_MAX_LENGTH: usize = 256;
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CharClass {
+    Lower,
+    Upper,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lower" => Some(Self::Lower),
+            "upper" => Some(Self::Upper),
+            "digit" => Some(Self::Digit),
+            "symbol" => Some(Self::Symbol),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Lower => "lower-case letter",
+            Self::Upper => "upper-case letter",
+            Self::Digit => "digit",
+            Self::Symbol => "symbol",
+        }
+    }
+
+    fn matches(self, c: char) -> bool {
+        match self {
+            Self::Lower => c.is_lowercase(),
+            Self::Upper => c.is_uppercase(),
+            Self::Digit => c.is_numeric(),
+            Self::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+}
+
+/// One failed rule, as returned to the client.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Violation {
+    rule: &'static str,
+    message: String,
+}
+
+impl Violation {
+    fn new(rule: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            rule,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    required_classes: Vec<CharClass>,
+    min_score: Option<u8>,
+    /// Whether the built-in list is part of `denylist`.
+    deny_common: bool,
+    denylist: HashSet<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PolicySummary {
+    min_length: usize,
+    max_length: usize,
+    required_classes: Vec<CharClass>,
+    deny_common: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_score: Option<u8>,
+}
+
+impl PasswordPolicy {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        let number = |name: &str, default: usize| match std::env::var(name) {
+            Ok(value) => value
+                .parse::<usize>()
+                .with_context(|| format!("{name} must be a number")),
+            Err(_) => Ok(default),
+        };
+        let min_length = number("AUTH_PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH)?;
+        let max_length = number("AUTH_PASSWORD_MAX_LENGTH", DEFAULT_MAX_LENGTH)?;
+        if min_length == 0 || max_length < min_length {
+            bail!(
+                "AUTH_PASSWORD_MIN_LENGTH must be at least 1 and at most AUTH_PASSWORD_MAX_LENGTH"
+            );
+        }
+
+        let mut required_classes = Vec::new();
+        for name in std::env::var("AUTH_PASSWORD_REQUIRED_CLASSES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let class = CharClass::parse(name).with_context(|| {
+                format!("unknown character class '{name}' in AUTH_PASSWORD_REQUIRED_CLASSES")
+            })?;
+            if !required_classes.contains(&class) {
+                required_classes.push(class);
+            }
+        }
+
+        let deny_common = std::env::var("AUTH_PASSWORD_DENY_COMMON")
+            .map(|value| value != "false")
+            .unwrap_or(true);
+        let mut denylist = HashSet::new();
+        if deny_common {
+            denylist.extend(entries(COMMON_PASSWORDS));
+        }
+        if let Ok(path) = std::env::var("AUTH_PASSWORD_DENYLIST_FILE") {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read AUTH_PASSWORD_DENYLIST_FILE '{path}'"))?;
+            denylist.extend(entries(&contents));
+        }
+
+        let min_score = match std::env::var("AUTH_PASSWORD_MIN_SCORE") {
+            Ok(value) => match value.parse::<u8>() {
+                Ok(score) if score <= 4 => Some(score),
+                _ => bail!("AUTH_PASSWORD_MIN_SCORE must be between 0 and 4"),
+            },
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            min_length,
+            max_length,
+            required_classes,
+            min_score,
+            deny_common,
+            denylist,
+        })
+    }
+
+    /// `user_inputs` (username, email) count against the strength score.
+    pub(crate) fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), AuthError> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(Violation::new(
+                "min_length",
+                format!(
+                    "password must contain at least {} characters",
+                    self.min_length
+                ),
+            ));
+        }
+        if length > self.max_length {
+            violations.push(Violation::new(
+                "max_length",
+                format!(
+                    "password must contain at most {} characters",
+                    self.max_length
+                ),
+            ));
+        }
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                violations.push(Violation::new(
+                    "character_classes",
+                    format!("password must contain a {}", class.name()),
+                ));
+            }
+        }
+        if self.denylist.contains(&password.to_lowercase()) {
+            violations.push(Violation::new("common_password", "password is too common"));
+        }
+        // Scoring is the expensive part and pointless for oversized input.
+        if let Some(min_score) = self.min_score.filter(|_| length <= self.max_length) {
+            let entropy = zxcvbn::zxcvbn(password, user_inputs);
+            let score = u8::from(entropy.score());
+            if score < min_score {
+                let hint = entropy
+                    .feedback()
+                    .and_then(|feedback| feedback.warning())
+                    .map(|warning| format!(": {warning}"))
+                    .unwrap_or_default();
+                violations.push(Violation::new(
+                    "strength",
+                    format!("password is too easy to guess (score {score} of at least {min_score}){hint}"),
+                ));
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::PasswordPolicy(violations))
+        }
+    }
+}
+
+fn entries(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+}
+
+impl PasswordPolicy {
+    fn summary(&self) -> PolicySummary {
+        PolicySummary {
+            min_length: self.min_length,
+            max_length: self.max_length,
+            required_classes: self.required_classes.clone(),
+            deny_common: self.deny_common,
+            min_score: self.min_score,
+        }
+    }
+}
+
+pub(crate) async fn describe(State(state): State<AppState>) -> Json<PolicySummary> {
+    Json(state.password_policy.summary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(deny_common: bool, extra: &[&str]) -> PasswordPolicy {
+        let mut denylist: HashSet<String> = extra.iter().map(|entry| entry.to_string()).collect();
+        if deny_common {
+            denylist.extend(entries(COMMON_PASSWORDS));
+        }
+        PasswordPolicy {
+            min_length: 12,
+            max_length: 16,
+            required_classes: vec![CharClass::Digit, CharClass::Symbol],
+            min_score: None,
+            deny_common,
+            denylist,
+        }
+    }
+
+    fn violated(result: Result<(), AuthError>) -> Vec<&'static str> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(AuthError::PasswordPolicy(violations)) => {
+                violations.iter().map(|violation| violation.rule).collect()
+            }
+            Err(other) => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn every_violated_rule_is_reported() {
+        let policy = policy(true, &[]);
+        assert_eq!(
+            violated(policy.check("short", &[])),
+            ["min_length", "character_classes", "character_classes"]
+        );
+        assert_eq!(
+            violated(policy.check("far-too-long-passphrase-1", &[])),
+            ["max_length"]
+        );
+        assert!(violated(policy.check("quokka-jump-7", &[])).is_empty());
+    }
+
+    #[test]
+    fn length_counts_characters_not_bytes() {
+        let policy = policy(false, &[]);
+        assert!(violated(policy.check("äöüäöüäöüä-1", &[])).is_empty());
+    }
+
+    #[test]
+    fn denylist_matches_case_insensitively() {
+        let policy = policy(false, &["correct-horse-1"]);
+        assert_eq!(
+            violated(policy.check("Correct-Horse-1", &[])),
+            ["common_password"]
+        );
+    }
+
+    #[test]
+    fn summary_reports_the_built_in_list_only_when_enabled() {
+        assert!(policy(true, &[]).summary().deny_common);
+        let custom_only = policy(false, &["correct-horse-1"]);
+        assert!(!custom_only.summary().deny_common);
+        assert_eq!(
+            serde_json::to_value(custom_only.summary()).unwrap(),
+            serde_json::json!({
+                "min_length": 12,
+                "max_length": 16,
+                "required_classes": ["digit", "symbol"],
+                "deny_common": false,
+            })
+        );
+    }
+}
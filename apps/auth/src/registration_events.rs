@@ -0,0 +1,388 @@
+//! Registration events (migration 030) for systems that act on new accounts:
+//! billing, workspace provisioning, default projects. Every account created
+//! through `/auth/register` or OIDC provisioning gets a `user.registered`
+//! event in `registration_events`, written in the transaction that creates
+//! the user, and announced on the `user_registered` channel (`pg_notify`)
+//! for consumers on the database. With `AUTH_REGISTRATION_WEBHOOK_URL` a
+//! background worker also POSTs each event there, signed with
+//! `AUTH_REGISTRATION_WEBHOOK_SECRET` like the reset webhook and retried
+//! with exponential backoff until `AUTH_REGISTRATION_WEBHOOK_MAX_ATTEMPTS`
+//! (default 8) attempts have failed. Holders of `user.admin` list events
+//! with `GET /admin/registration-events` and replay one with
+//! `POST /admin/registration-events/:id/replay`, limited to the events of
+//! their own tenant (`tenant_id` in the payload; events from before tenants
+//! belong to the default one). Events keep their id across replays, so
+//! consumers can deduplicate on `x-webhook-id`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::postgres::{PgConnection, PgRow};
+use sqlx::types::Json as JsonValue;
+use sqlx::{PgPool, Row};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::notifier::sign;
+use crate::users::require_admin;
+use crate::{internal, AppState, AuthError};
+
+const EVENT: &str = "user.registered";
+const CHANNEL: &str = "user_registered";
+const DEFAULT_MAX_ATTEMPTS: i32 = 8;
+const BATCH: i64 = 20;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a claimed event stays with one worker before another may retry.
+/// A batch is sent one event at a time, so the lease outlasts a batch in
+/// which every delivery times out.
+const LEASE: Duration = Duration::from_secs(DELIVERY_TIMEOUT.as_secs() * BATCH as u64 + 60);
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_CAP: Duration = Duration::from_secs(3600);
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+/// The event's tenant; events recorded before tenants existed belong to the
+/// default tenant.
+const TENANT: &str = "COALESCE((payload->>'tenant_id')::int, 1)";
+const COLUMNS: &str = "id, user_id, payload, status, attempts, next_attempt_at, last_error, \
+     created_at, delivered_at, replayed_at";
+
+struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    max_attempts: i32,
+}
+
+#[derive(Clone)]
+pub(crate) struct RegistrationEvents {
+    webhook: Option<Arc<Webhook>>,
+    wake: Arc<Notify>,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+impl RegistrationEvents {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        let webhook = match env("AUTH_REGISTRATION_WEBHOOK_URL") {
+            Some(url) => {
+                let max_attempts = match env("AUTH_REGISTRATION_WEBHOOK_MAX_ATTEMPTS") {
+                    Some(value) => value
+                        .parse::<i32>()
+                        .ok()
+                        .filter(|attempts| *attempts > 0)
+                        .context(
+                            "AUTH_REGISTRATION_WEBHOOK_MAX_ATTEMPTS must be a positive number",
+                        )?,
+                    None => DEFAULT_MAX_ATTEMPTS,
+                };
+                Some(Arc::new(Webhook {
+                    client: reqwest::Client::builder()
+                        .timeout(DELIVERY_TIMEOUT)
+                        .redirect(reqwest::redirect::Policy::none())
+                        .build()?,
+                    url,
+                    secret: env("AUTH_REGISTRATION_WEBHOOK_SECRET"),
+                    max_attempts,
+                }))
+            }
+            None => None,
+        };
+        Ok(Self {
+            webhook,
+            wake: Arc::default(),
+        })
+    }
+
+    /// Events wait for delivery only while there is somewhere to deliver
+    /// them.
+    fn initial_status(&self) -> &'static str {
+        if self.webhook.is_some() {
+            "pending"
+        } else {
+            "skipped"
+        }
+    }
+
+    /// Records the event for `user_id` inside the transaction creating the
+    /// user; the announcement goes out when it commits. `source` is
+    /// `password` or `oidc`.
+    pub(crate) async fn record(
+        &self,
+        conn: &mut PgConnection,
+        user_id: i32,
+        source: &str,
+    ) -> Result<(), AuthError> {
+        sqlx::query(
+            "WITH event AS ( \
+                INSERT INTO registration_events (user_id, payload, status) \
+                SELECT id, jsonb_build_object('user_id', id, 'username', username, 'role', role, \
+                    'email', email, 'tenant_id', tenant_id, 'source', $2::text, \
+                    'registered_at', created_at), $3 \
+                FROM users WHERE id = $1 \
+                RETURNING id, payload \
+             ) \
+             SELECT pg_notify($4, (payload || jsonb_build_object('id', id, 'event', $5::text))::text) \
+             FROM event",
+        )
+        .bind(user_id)
+        .bind(source)
+        .bind(self.initial_status())
+        .bind(CHANNEL)
+        .bind(EVENT)
+        .execute(conn)
+        .await
+        .map_err(internal)?;
+        Ok(())
+    }
+
+    /// Lets the worker deliver right away instead of at its next poll.
+    pub(crate) fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Starts the delivery worker if a webhook is configured.
+    pub(crate) fn spawn_worker(&self, pool: PgPool) {
+        let Some(webhook) = self.webhook.clone() else {
+            return;
+        };
+        let wake = self.wake.clone();
+        tokio::spawn(async move {
+            loop {
+                match deliver_due(&pool, &webhook).await {
+                    // A full batch may mean more are due.
+                    Ok(claimed) if claimed == BATCH => continue,
+                    Ok(_) => {}
+                    Err(err) => warn!(error = %err, "failed to claim registration events"),
+                }
+                tokio::select! {
+                    _ = wake.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+    }
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(BACKOFF_CAP)
+}
+
+/// Claims due events with a lease, so concurrent auth instances never send
+/// one twice at the same time and a crashed one's events are retried.
+async fn deliver_due(pool: &PgPool, webhook: &Webhook) -> Result<i64, sqlx::Error> {
+    let rows = sqlx::query(
+        "UPDATE registration_events \
+         SET attempts = attempts + 1, next_attempt_at = NOW() + make_interval(secs => $2) \
+         WHERE id IN ( \
+            SELECT id FROM registration_events \
+            WHERE status = 'pending' AND next_attempt_at <= NOW() \
+            ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED \
+         ) \
+         RETURNING id, payload, attempts",
+    )
+    .bind(BATCH)
+    .bind(LEASE.as_secs_f64())
+    .fetch_all(pool)
+    .await?;
+    for row in &rows {
+        let id: i64 = row.get("id");
+        let attempts: i32 = row.get("attempts");
+        let JsonValue(payload): JsonValue<Value> = row.get("payload");
+        match webhook.deliver(id, payload).await {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE registration_events \
+                     SET status = 'delivered', delivered_at = NOW(), last_error = NULL \
+                     WHERE id = $1",
+                )
+                .bind(id)
+                .execute(pool)
+                .await?;
+                info!(event_id = id, "registration event delivered");
+            }
+            Err(err) => {
+                let gave_up = attempts >= webhook.max_attempts;
+                warn!(event_id = id, attempts, gave_up, error = %err, "registration event delivery failed");
+                sqlx::query(
+                    "UPDATE registration_events \
+                     SET status = CASE WHEN $2 THEN 'failed' ELSE status END, \
+                        next_attempt_at = NOW() + make_interval(secs => $3), last_error = $4 \
+                     WHERE id = $1",
+                )
+                .bind(id)
+                .bind(gave_up)
+                .bind(backoff(attempts).as_secs_f64())
+                .bind(err.to_string())
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(rows.len() as i64)
+}
+
+impl Webhook {
+    async fn deliver(&self, id: i64, payload: Value) -> anyhow::Result<()> {
+        let mut body = json!({ "event": EVENT, "id": id });
+        if let (Some(body), Value::Object(payload)) = (body.as_object_mut(), payload) {
+            body.extend(payload);
+        }
+        let body = body.to_string();
+        let timestamp = Utc::now().timestamp();
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("content-type", "application/json")
+            .header("x-webhook-event", EVENT)
+            .header("x-webhook-id", id.to_string())
+            .header("x-webhook-timestamp", timestamp.to_string());
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                "x-webhook-signature",
+                format!("sha256={}", sign(secret, timestamp, &body)),
+            );
+        }
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListEventsQuery {
+    /// `pending`, `delivered`, `failed`, `skipped` or `all` (default).
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EventSummary {
+    id: i64,
+    user_id: i32,
+    payload: Value,
+    status: String,
+    attempts: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_attempt_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivered_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replayed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListEventsResponse {
+    events: Vec<EventSummary>,
+}
+
+fn summary(row: &PgRow) -> EventSummary {
+    let status: String = row.get("status");
+    let JsonValue(payload) = row.get("payload");
+    EventSummary {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        payload,
+        // Only pending events are waiting for anything.
+        next_attempt_at: (status == "pending").then(|| row.get("next_attempt_at")),
+        status,
+        attempts: row.get("attempts"),
+        last_error: row.get("last_error"),
+        created_at: row.get("created_at"),
+        delivered_at: row.get("delivered_at"),
+        replayed_at: row.get("replayed_at"),
+    }
+}
+
+pub(crate) async fn list_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListEventsQuery>,
+) -> Result<Json<ListEventsResponse>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let status = match query.status.as_deref() {
+        None | Some("all") => None,
+        Some(status @ ("pending" | "delivered" | "failed" | "skipped")) => Some(status),
+        Some(other) => {
+            return Err(AuthError::BadRequest(format!(
+                "unsupported status '{other}'"
+            )))
+        }
+    };
+    let rows = sqlx::query(&format!(
+        "SELECT {COLUMNS} FROM registration_events \
+         WHERE ($1::text IS NULL OR status = $1) AND ($2::int IS NULL OR user_id = $2) \
+           AND {TENANT} = $4 \
+         ORDER BY id DESC LIMIT $3"
+    ))
+    .bind(status)
+    .bind(query.user_id)
+    .bind(query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE))
+    .bind(admin.tenant_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+    Ok(Json(ListEventsResponse {
+        events: rows.iter().map(summary).collect(),
+    }))
+}
+
+/// Announces the event again and, with a webhook configured, queues a fresh
+/// round of delivery attempts, whatever happened to it before.
+pub(crate) async fn replay_event(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Result<Json<EventSummary>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let events = &state.registration_events;
+    let row = sqlx::query(&format!(
+        "WITH event AS ( \
+            UPDATE registration_events \
+            SET status = $2, attempts = 0, next_attempt_at = NOW(), last_error = NULL, \
+                delivered_at = NULL, replayed_at = NOW() \
+            WHERE id = $1 AND {TENANT} = $5 RETURNING {COLUMNS} \
+         ), \
+         announced AS ( \
+            SELECT pg_notify($3, (payload || jsonb_build_object('id', id, 'event', $4::text, \
+                'replay', true))::text) FROM event \
+         ) \
+         SELECT event.* FROM event, announced"
+    ))
+    .bind(id)
+    .bind(events.initial_status())
+    .bind(CHANNEL)
+    .bind(EVENT)
+    .bind(admin.tenant_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(internal)?
+    .ok_or_else(|| AuthError::NotFound("registration event not found".to_string()))?;
+    events.wake();
+    info!(
+        admin = admin.user_id,
+        event_id = id,
+        "registration event replayed"
+    );
+    Ok(Json(summary(&row)))
+}
@@ -0,0 +1,241 @@
+//! Self-service password reset. `/auth/password-reset/request` creates a
+//! single-use token valid for `AUTH_RESET_TOKEN_TTL_MINUTES` and hands it to
+//! the configured notifier; only its SHA-256 is stored. The response is the
+//! same whether or not the account exists, and delivery runs in the
+//! background so a slow notifier does not hold it up.
+//! `/auth/password-reset/confirm` sets the new password, burns the token and
+//! revokes the user's existing JWTs (migration 018).
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{Duration, Utc};
+use hex::encode as hex_encode;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use tracing::info;
+
+use crate::notifier::{self, Notice, Notifier, Purpose};
+use crate::{password, AppState, AuthError};
+
+/// A new token for the same account is only issued after this long, so the
+/// endpoint cannot be used to flood someone's inbox.
+pub(crate) const REQUEST_COOLDOWN_SECS: f64 = 60.0;
+
+#[derive(Clone)]
+pub(crate) struct PasswordReset {
+    notifier: Arc<dyn Notifier>,
+    ttl: Duration,
+}
+
+impl PasswordReset {
+    /// `None` when no notifier is configured.
+    pub(crate) fn from_env(notifier: Option<Arc<dyn Notifier>>) -> Option<Self> {
+        let ttl_minutes = std::env::var("AUTH_RESET_TOKEN_TTL_MINUTES")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(30);
+        notifier.map(|notifier| Self {
+            notifier,
+            ttl: Duration::minutes(ttl_minutes),
+        })
+    }
+
+    /// Issues a token and delivers it in the background. Returns `false`
+    /// without doing anything while the account's last token is within the
+    /// cooldown.
+    pub(crate) async fn send(
+        &self,
+        pool: &PgPool,
+        user_id: i32,
+        username: String,
+        email: Option<String>,
+    ) -> Result<bool, AuthError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex_encode(bytes);
+        let expires_at = Utc::now() + self.ttl;
+        // Replaces a pending token unless it was issued within the cooldown.
+        let issued = sqlx::query(
+            "INSERT INTO password_resets (user_id, token_hash, expires_at) VALUES ($1, $2, $3) \
+             ON CONFLICT (user_id) DO UPDATE SET \
+                token_hash = EXCLUDED.token_hash, expires_at = EXCLUDED.expires_at, created_at = NOW() \
+             WHERE password_resets.created_at < NOW() - make_interval(secs => $4)",
+        )
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .bind(REQUEST_COOLDOWN_SECS)
+        .execute(pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?
+        .rows_affected();
+        if issued == 0 {
+            return Ok(false);
+        }
+
+        notifier::spawn_delivery(
+            self.notifier.clone(),
+            Notice {
+                purpose: Purpose::PasswordReset,
+                user_id,
+                username,
+                email,
+                token,
+                expires_at,
+            },
+        );
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResetRequest {
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResetConfirm {
+    token: String,
+    new_password: String,
+}
+
+pub(crate) fn enabled(state: &AppState) -> Result<&PasswordReset, AuthError> {
+    state
+        .reset
+        .as_ref()
+        .ok_or_else(|| AuthError::NotFound("password reset is not enabled".to_string()))
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex_encode(Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) async fn request_reset(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetRequest>,
+) -> Result<StatusCode, AuthError> {
+    let reset = enabled(&state)?;
+    let query = match (&payload.username, &payload.email) {
+        (Some(username), None) => sqlx::query(
+            "SELECT id, username, email FROM users \
+             WHERE username = $1 AND disabled_at IS NULL AND kind = 'human'",
+        )
+        .bind(username),
+        (None, Some(email)) => sqlx::query(
+            "SELECT id, username, email FROM users \
+             WHERE lower(email) = lower($1) AND disabled_at IS NULL AND kind = 'human'",
+        )
+        .bind(email),
+        _ => {
+            return Err(AuthError::BadRequest(
+                "pass exactly one of username or email".to_string(),
+            ))
+        }
+    };
+    let Some(row) = query
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?
+    else {
+        return Ok(StatusCode::ACCEPTED);
+    };
+
+    reset
+        .send(
+            &state.pool,
+            row.get("id"),
+            row.get("username"),
+            row.get("email"),
+        )
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub(crate) async fn confirm_reset(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetConfirm>,
+) -> Result<StatusCode, AuthError> {
+    enabled(&state)?;
+    let invalid = || AuthError::BadRequest("invalid or expired reset token".to_string());
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    // Deleting the row is what makes the token single-use; an expired token
+    // is consumed as well. A password the policy rejects rolls it back, so
+    // the token can be used again with a better one.
+    let row = sqlx::query(
+        "DELETE FROM password_resets r USING users u \
+         WHERE r.token_hash = $1 AND u.id = r.user_id \
+         RETURNING r.user_id, r.expires_at > NOW() AS valid, u.username, u.email",
+    )
+    .bind(hash_token(&payload.token))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(invalid)?;
+    if !row.get::<bool, _>("valid") {
+        tx.commit()
+            .await
+            .map_err(|err| AuthError::Internal(err.to_string()))?;
+        return Err(invalid());
+    }
+    let user_id: i32 = row.get("user_id");
+    let username: String = row.get("username");
+    let email: Option<String> = row.get("email");
+    let user_inputs: Vec<&str> = std::iter::once(username.as_str())
+        .chain(email.as_deref())
+        .collect();
+    state
+        .password_policy
+        .check(&payload.new_password, &user_inputs)?;
+    let hashed = state.passwords.hash(&payload.new_password).await?;
+    let updated = sqlx::query(
+        "UPDATE users SET password_hash = $2, password_scheme = $3, tokens_revoked_at = NOW() \
+         WHERE id = $1 AND disabled_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(&hashed)
+    .bind(password::SCHEME)
+    .execute(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .rows_affected();
+    if updated == 0 {
+        return Err(invalid());
+    }
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(user_id, "password reset");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_token_hash_is_stored() {
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn reset_needs_a_notifier() {
+        assert!(PasswordReset::from_env(None).is_none());
+    }
+}
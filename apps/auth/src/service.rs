@@ -0,0 +1,401 @@
+//! Machine identities (migration 023). A service client is a `users` row of
+//! kind `service` plus a client id and secret; `POST /auth/token` with
+//! `grant_type=client_credentials` (RFC 6749 section 4.4) exchanges them for a
+//! JWT valid for `AUTH_SERVICE_TOKEN_TTL_MINUTES`. The token's `scope` claim
+//! holds the client's scopes, or the subset the request asked for, and the
+//! API gateway narrows the service user's permissions to it just as for a
+//! scoped API key. Clients are managed under `/admin/service-clients` by
+//! holders of `user.admin`, each admin seeing only the clients of their own
+//! tenant, where new clients are created; the secret is only shown when it
+//! is created or rotated.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::{Form, Json};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::encode;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::users::require_admin;
+use crate::{
+    hash_api_key, hex_encode, normalize_scopes, password, validate_role, AppState, AuthError,
+    Claims,
+};
+
+/// Usernames of service users; `/auth/register` refuses them.
+pub(crate) const USERNAME_PREFIX: &str = "svc:";
+const MAX_NAME: usize = 48;
+const CLIENT_COLUMNS: &str = "service_clients.client_id, service_clients.name, \
+     service_clients.user_id, users.username, users.role, service_clients.scopes, \
+     service_clients.created_at, service_clients.last_used_at";
+
+fn token_ttl() -> Duration {
+    Duration::minutes(
+        std::env::var("AUTH_SERVICE_TOKEN_TTL_MINUTES")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(15),
+    )
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("cds_svc_{}", hex_encode(bytes))
+}
+
+fn invalid_client() -> AuthError {
+    AuthError::Unauthorized("invalid client credentials".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TokenRequest {
+    grant_type: String,
+    #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    client_secret: Option<String>,
+    /// Space-separated subset of the client's scopes.
+    #[serde(default)]
+    scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+}
+
+/// Client credentials from HTTP Basic authentication, which RFC 6749 prefers,
+/// or from the form body.
+fn credentials(headers: &HeaderMap, form: &TokenRequest) -> Result<(Uuid, String), AuthError> {
+    let basic = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            decoded
+                .split_once(':')
+                .map(|(id, secret)| (id.to_string(), secret.to_string()))
+        });
+    let (client_id, secret) = match (basic, &form.client_id, &form.client_secret) {
+        (Some(pair), _, _) => pair,
+        (None, Some(id), Some(secret)) => (id.clone(), secret.clone()),
+        _ => return Err(invalid_client()),
+    };
+    let client_id = Uuid::parse_str(&client_id).map_err(|_| invalid_client())?;
+    Ok((client_id, secret))
+}
+
+pub(crate) async fn token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TokenRequest>,
+) -> Result<Json<TokenResponse>, AuthError> {
+    if form.grant_type != "client_credentials" {
+        return Err(AuthError::BadRequest(format!(
+            "unsupported grant_type '{}'",
+            form.grant_type
+        )));
+    }
+    let (client_id, secret) = credentials(&headers, &form)?;
+    let row = sqlx::query(
+        "UPDATE service_clients SET last_used_at = NOW() \
+         FROM users WHERE users.id = service_clients.user_id \
+            AND service_clients.client_id = $1 AND service_clients.secret_hash = $2 \
+            AND users.disabled_at IS NULL \
+         RETURNING users.id, users.username, users.role, users.tenant_id, service_clients.scopes",
+    )
+    .bind(client_id)
+    .bind(hash_api_key(&secret))
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(invalid_client)?;
+
+    let allowed: Vec<String> = row.get("scopes");
+    let scopes = match form.scope.as_deref().map(str::trim) {
+        None | Some("") => allowed,
+        Some(requested) => {
+            let requested =
+                normalize_scopes(requested.split_whitespace().map(str::to_string).collect())?;
+            if let Some(extra) = requested.iter().find(|scope| !allowed.contains(scope)) {
+                return Err(AuthError::BadRequest(format!(
+                    "scope '{extra}' is not granted to this client"
+                )));
+            }
+            requested
+        }
+    };
+
+    let user_id: i32 = row.get("id");
+    let username: String = row.get("username");
+    let ttl = token_ttl();
+    let mut claims = Claims::new(
+        user_id,
+        &username,
+        &row.get::<String, _>("role"),
+        row.get("tenant_id"),
+        &state.jwt.issuer,
+        ttl,
+    );
+    claims.scope = Some(scopes.join(" "));
+    let access_token = encode(&state.jwt.keys.header(), &claims, state.jwt.keys.encoding())
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(%client_id, %username, "service token issued");
+    Ok(Json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: ttl.num_seconds(),
+        scope: scopes.join(" "),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateClientRequest {
+    name: String,
+    #[serde(default)]
+    role: Option<String>,
+    scopes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ClientSummary {
+    client_id: Uuid,
+    name: String,
+    user_id: i32,
+    username: String,
+    role: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ClientWithSecret {
+    #[serde(flatten)]
+    client: ClientSummary,
+    client_secret: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListClientsResponse {
+    clients: Vec<ClientSummary>,
+}
+
+fn summary(row: &PgRow) -> ClientSummary {
+    ClientSummary {
+        client_id: row.get("client_id"),
+        name: row.get("name"),
+        user_id: row.get("user_id"),
+        username: row.get("username"),
+        role: row.get("role"),
+        scopes: row.get("scopes"),
+        created_at: row.get("created_at"),
+        last_used_at: row.get("last_used_at"),
+    }
+}
+
+fn not_found() -> AuthError {
+    AuthError::NotFound("service client not found".to_string())
+}
+
+async fn load(
+    state: &AppState,
+    client_id: Uuid,
+    tenant_id: i32,
+) -> Result<ClientSummary, AuthError> {
+    let row = sqlx::query(&format!(
+        "SELECT {CLIENT_COLUMNS} FROM service_clients \
+         JOIN users ON users.id = service_clients.user_id \
+         WHERE service_clients.client_id = $1 AND users.tenant_id = $2"
+    ))
+    .bind(client_id)
+    .bind(tenant_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(not_found)?;
+    Ok(summary(&row))
+}
+
+pub(crate) async fn list_clients(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ListClientsResponse>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let rows = sqlx::query(&format!(
+        "SELECT {CLIENT_COLUMNS} FROM service_clients \
+         JOIN users ON users.id = service_clients.user_id \
+         WHERE users.tenant_id = $1 ORDER BY service_clients.name"
+    ))
+    .bind(admin.tenant_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    Ok(Json(ListClientsResponse {
+        clients: rows.iter().map(summary).collect(),
+    }))
+}
+
+pub(crate) async fn create_client(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateClientRequest>,
+) -> Result<(StatusCode, Json<ClientWithSecret>), AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let name = payload.name.trim();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(AuthError::BadRequest(format!(
+            "invalid client name '{name}' (expected [a-z0-9_-]{{1,{MAX_NAME}}})"
+        )));
+    }
+    let scopes = normalize_scopes(payload.scopes)?;
+    if scopes.is_empty() {
+        return Err(AuthError::BadRequest(
+            "a service client needs at least one scope".to_string(),
+        ));
+    }
+    let role = payload.role.unwrap_or_else(|| "developer".to_string());
+    validate_role(&state.pool, &role).await?;
+
+    let secret = generate_secret();
+    let password_hash = state.passwords.unusable().await?;
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let conflict = || AuthError::Conflict(format!("service client '{name}' already exists"));
+    let user_id: i32 = sqlx::query_scalar(
+        "INSERT INTO users (username, password_hash, password_scheme, role, kind, tenant_id) \
+         VALUES ($1, $2, $4, $3, 'service', $5) \
+         ON CONFLICT (username) DO NOTHING RETURNING id",
+    )
+    .bind(format!("{USERNAME_PREFIX}{name}"))
+    .bind(&password_hash)
+    .bind(&role)
+    .bind(password::SCHEME)
+    .bind(admin.tenant_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(conflict)?;
+    let client_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO service_clients (user_id, name, secret_hash, scopes, created_by) \
+         VALUES ($1, $2, $3, $4, $5) RETURNING client_id",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(hash_api_key(&secret))
+    .bind(&scopes)
+    .bind(admin.user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23505") => conflict(),
+        other => AuthError::Internal(other.to_string()),
+    })?;
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(admin = admin.user_id, %client_id, name, "service client created");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ClientWithSecret {
+            client: load(&state, client_id, admin.tenant_id).await?,
+            client_secret: secret,
+        }),
+    ))
+}
+
+/// Replaces the secret; tokens issued with the old one stay valid until they
+/// expire.
+pub(crate) async fn rotate_secret(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(client_id): Path<Uuid>,
+) -> Result<Json<ClientWithSecret>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let secret = generate_secret();
+    let updated = sqlx::query(
+        "UPDATE service_clients SET secret_hash = $2 \
+         FROM users WHERE users.id = service_clients.user_id \
+            AND service_clients.client_id = $1 AND users.tenant_id = $3",
+    )
+    .bind(client_id)
+    .bind(hash_api_key(&secret))
+    .bind(admin.tenant_id)
+    .execute(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .rows_affected();
+    if updated == 0 {
+        return Err(not_found());
+    }
+    info!(admin = admin.user_id, %client_id, "service client secret rotated");
+    Ok(Json(ClientWithSecret {
+        client: load(&state, client_id, admin.tenant_id).await?,
+        client_secret: secret,
+    }))
+}
+
+/// Removes the credentials and disables the service user, which also ends
+/// its outstanding tokens; the user row stays for audit and ownership.
+pub(crate) async fn delete_client(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(client_id): Path<Uuid>,
+) -> Result<StatusCode, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let user_id: i32 = sqlx::query_scalar(
+        "DELETE FROM service_clients USING users \
+         WHERE users.id = service_clients.user_id \
+            AND service_clients.client_id = $1 AND users.tenant_id = $2 \
+         RETURNING service_clients.user_id",
+    )
+    .bind(client_id)
+    .bind(admin.tenant_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(not_found)?;
+    sqlx::query(
+        "UPDATE users SET disabled_at = COALESCE(disabled_at, NOW()), tokens_revoked_at = NOW(), \
+            updated_at = NOW() \
+         WHERE id = $1",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(admin = admin.user_id, %client_id, user_id, "service client deleted");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,193 @@
+//! Tenants (migration 031). Every account belongs to one tenant, and its
+//! tokens carry it; projects, API keys and invitations follow their owner's
+//! tenant. Admins of the `default` tenant operate the deployment: they
+//! create tenants and move accounts between them. Moving an account revokes
+//! its tokens and drops its project-scoped grants, which point into the old
+//! tenant; an account that still owns projects cannot move, since the
+//! projects' files live below the old tenant's sandbox directory.
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use tracing::info;
+
+use crate::users::require_admin;
+use crate::{internal, AppState, AuthError, AuthenticatedUser};
+
+/// The tenant existing data, open registration and OIDC provisioning
+/// belong to.
+pub(crate) const DEFAULT_TENANT: i32 = 1;
+const MAX_SLUG: usize = 64;
+const MAX_NAME: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateTenantRequest {
+    slug: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MoveUserRequest {
+    tenant_id: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TenantSummary {
+    id: i32,
+    slug: String,
+    name: String,
+    users: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListTenantsResponse {
+    tenants: Vec<TenantSummary>,
+}
+
+fn summary(row: &PgRow) -> TenantSummary {
+    TenantSummary {
+        id: row.get("id"),
+        slug: row.get("slug"),
+        name: row.get("name"),
+        users: row.get("users"),
+        created_at: row.get("created_at"),
+    }
+}
+
+/// Lowercase letters, digits and dashes, not starting with a dash; the
+/// same rule the `tenants.slug` check enforces.
+fn validate_slug(slug: &str) -> Result<(), AuthError> {
+    let valid = !slug.is_empty()
+        && slug.len() <= MAX_SLUG
+        && !slug.starts_with('-')
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(AuthError::BadRequest(format!(
+            "slug must be 1 to {MAX_SLUG} lowercase letters, digits or dashes"
+        )))
+    }
+}
+
+async fn require_operator(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<AuthenticatedUser, AuthError> {
+    let admin = require_admin(headers, state).await?;
+    if admin.tenant_id != DEFAULT_TENANT {
+        return Err(AuthError::Forbidden(
+            "tenants are managed by admins of the default tenant".to_string(),
+        ));
+    }
+    Ok(admin)
+}
+
+pub(crate) async fn list_tenants(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ListTenantsResponse>, AuthError> {
+    require_operator(&headers, &state).await?;
+    let rows = sqlx::query(
+        "SELECT tenants.id, tenants.slug, tenants.name, tenants.created_at, \
+                COUNT(users.id) AS users \
+         FROM tenants LEFT JOIN users ON users.tenant_id = tenants.id \
+         GROUP BY tenants.id ORDER BY tenants.id",
+    )
+    .fetch_all(&state.pool)
+    .await
+    .map_err(internal)?;
+    Ok(Json(ListTenantsResponse {
+        tenants: rows.iter().map(summary).collect(),
+    }))
+}
+
+pub(crate) async fn create_tenant(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateTenantRequest>,
+) -> Result<(StatusCode, Json<TenantSummary>), AuthError> {
+    let admin = require_operator(&headers, &state).await?;
+    let slug = payload.slug.trim();
+    validate_slug(slug)?;
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME {
+        return Err(AuthError::BadRequest(format!(
+            "name must be 1 to {MAX_NAME} characters"
+        )));
+    }
+    let row = sqlx::query(
+        "INSERT INTO tenants (slug, name) VALUES ($1, $2) \
+         RETURNING id, slug, name, created_at, 0::bigint AS users",
+    )
+    .bind(slug)
+    .bind(name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23505") => {
+            AuthError::Conflict(format!("tenant '{slug}' already exists"))
+        }
+        other => internal(other),
+    })?;
+    let tenant = summary(&row);
+    info!(admin = admin.user_id, tenant = tenant.id, %slug, "tenant created");
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+pub(crate) async fn move_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<MoveUserRequest>,
+) -> Result<StatusCode, AuthError> {
+    let admin = require_operator(&headers, &state).await?;
+    if admin.user_id == user_id {
+        return Err(AuthError::BadRequest(
+            "admins cannot change their own account".to_string(),
+        ));
+    }
+    let mut tx = state.pool.begin().await.map_err(internal)?;
+    let previous: i32 = sqlx::query_scalar(
+        "UPDATE users u SET tenant_id = $2, tokens_revoked_at = NOW(), updated_at = NOW() \
+         FROM users old WHERE u.id = $1 AND old.id = u.id RETURNING old.tenant_id",
+    )
+    .bind(user_id)
+    .bind(payload.tenant_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23503") => {
+            if db_err.constraint() == Some("projects_owner_tenant_fkey") {
+                AuthError::Conflict("user still owns projects in their tenant".to_string())
+            } else {
+                AuthError::BadRequest(format!("unknown tenant {}", payload.tenant_id))
+            }
+        }
+        other => internal(other),
+    })?
+    .ok_or_else(|| AuthError::NotFound("user not found".to_string()))?;
+    if previous != payload.tenant_id {
+        sqlx::query("DELETE FROM permission_grants WHERE user_id = $1 AND project_id IS NOT NULL")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal)?;
+    }
+    tx.commit().await.map_err(internal)?;
+    info!(
+        admin = admin.user_id,
+        user_id,
+        from = previous,
+        to = payload.tenant_id,
+        "user moved to tenant"
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
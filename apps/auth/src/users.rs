@@ -0,0 +1,278 @@
+//! User management for holders of the `user.admin` permission, through
+//! their role or an unscoped grant (migration 014): list accounts, change
+//! roles, disable and re-enable accounts and force a password reset. The API
+//! gateway reads role and disabled flag from `users` on every request, so
+//! changes apply to existing JWTs and API keys right away. A forced reset
+//! replaces the password with an unusable one, revokes the user's tokens
+//! and sends a reset token through the configured notifier. Admins only
+//! see and manage accounts of their own tenant.
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use tracing::info;
+
+use crate::{authenticate, password, reset, AppState, AuthError, AuthenticatedUser};
+
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 500;
+/// The permission `admin.users.*` requires in the API gateway.
+const USER_ADMIN: &str = "user.admin";
+const USER_COLUMNS: &str =
+    "id, username, email, email_verified_at IS NOT NULL AS email_verified, role, tenant_id, \
+     disabled_at, created_at";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListUsersQuery {
+    #[serde(default)]
+    role: Option<String>,
+    #[serde(default)]
+    disabled: Option<bool>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    cursor: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetRoleRequest {
+    role: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UserSummary {
+    id: i32,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    email_verified: bool,
+    role: String,
+    tenant_id: i32,
+    disabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListUsersResponse {
+    users: Vec<UserSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<i32>,
+}
+
+fn summary(row: &PgRow) -> UserSummary {
+    let disabled_at: Option<DateTime<Utc>> = row.get("disabled_at");
+    UserSummary {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        email_verified: row.get("email_verified"),
+        role: row.get("role"),
+        tenant_id: row.get("tenant_id"),
+        disabled: disabled_at.is_some(),
+        disabled_at,
+        created_at: row.get("created_at"),
+    }
+}
+
+fn not_found() -> AuthError {
+    AuthError::NotFound("user not found".to_string())
+}
+
+pub(crate) async fn require_admin(
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<AuthenticatedUser, AuthError> {
+    let user = authenticate(headers, state).await?;
+    let allowed: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM role_permissions WHERE role = $2 AND permission = $3) \
+             OR EXISTS (SELECT 1 FROM permission_grants \
+                        WHERE user_id = $1 AND permission = $3 AND project_id IS NULL)",
+    )
+    .bind(user.user_id)
+    .bind(&user.role)
+    .bind(USER_ADMIN)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    if !allowed {
+        return Err(AuthError::Forbidden(format!(
+            "permission '{USER_ADMIN}' required"
+        )));
+    }
+    Ok(user)
+}
+
+/// Admins cannot demote or disable themselves, so there is always at least
+/// the caller left to undo a mistake.
+fn ensure_not_self(admin: &AuthenticatedUser, user_id: i32) -> Result<(), AuthError> {
+    if admin.user_id == user_id {
+        Err(AuthError::BadRequest(
+            "admins cannot change their own account".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) async fn list_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<Json<ListUsersResponse>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let rows = sqlx::query(&format!(
+        "SELECT {USER_COLUMNS} FROM users \
+         WHERE tenant_id = $5 \
+           AND ($1::varchar IS NULL OR role = $1) \
+           AND ($2::boolean IS NULL OR (disabled_at IS NOT NULL) = $2) \
+           AND ($3::integer IS NULL OR id > $3) \
+         ORDER BY id LIMIT $4"
+    ))
+    .bind(&query.role)
+    .bind(query.disabled)
+    .bind(query.cursor)
+    .bind(limit)
+    .bind(admin.tenant_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let next_cursor = (rows.len() as i64 == limit)
+        .then(|| rows.last().map(|row| row.get::<i32, _>("id")))
+        .flatten();
+    Ok(Json(ListUsersResponse {
+        users: rows.iter().map(summary).collect(),
+        next_cursor,
+    }))
+}
+
+/// Any role defined in `roles` (migration 014) may be assigned.
+pub(crate) async fn set_role(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<SetRoleRequest>,
+) -> Result<Json<UserSummary>, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    ensure_not_self(&admin, user_id)?;
+    let row = sqlx::query(&format!(
+        "UPDATE users SET role = $2, updated_at = NOW() WHERE id = $1 AND tenant_id = $3 \
+         RETURNING {USER_COLUMNS}"
+    ))
+    .bind(user_id)
+    .bind(&payload.role)
+    .bind(admin.tenant_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some("23503") => {
+            AuthError::BadRequest(format!("unsupported role '{}'", payload.role))
+        }
+        other => AuthError::Internal(other.to_string()),
+    })?
+    .ok_or_else(not_found)?;
+    info!(admin = admin.user_id, user_id, role = %payload.role, "user role changed");
+    Ok(Json(summary(&row)))
+}
+
+async fn set_disabled(
+    state: &AppState,
+    headers: &HeaderMap,
+    user_id: i32,
+    disabled: bool,
+) -> Result<Json<UserSummary>, AuthError> {
+    let admin = require_admin(headers, state).await?;
+    ensure_not_self(&admin, user_id)?;
+    // Keeps the original timestamp when the account is already disabled.
+    let row = sqlx::query(&format!(
+        "UPDATE users SET \
+            disabled_at = CASE WHEN $2 THEN COALESCE(disabled_at, NOW()) END, \
+            updated_at = NOW() \
+         WHERE id = $1 AND tenant_id = $3 RETURNING {USER_COLUMNS}"
+    ))
+    .bind(user_id)
+    .bind(disabled)
+    .bind(admin.tenant_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(not_found)?;
+    info!(
+        admin = admin.user_id,
+        user_id, disabled, "user account updated"
+    );
+    Ok(Json(summary(&row)))
+}
+
+pub(crate) async fn disable_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+) -> Result<Json<UserSummary>, AuthError> {
+    set_disabled(&state, &headers, user_id, true).await
+}
+
+pub(crate) async fn enable_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+) -> Result<Json<UserSummary>, AuthError> {
+    set_disabled(&state, &headers, user_id, false).await
+}
+
+/// Locks the account until the user picks a new password through the reset
+/// token sent to them.
+pub(crate) async fn force_password_reset(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, AuthError> {
+    let admin = require_admin(&headers, &state).await?;
+    let reset = reset::enabled(&state)?;
+
+    let unusable = state.passwords.unusable().await?;
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let row = sqlx::query(
+        "UPDATE users SET password_hash = $2, password_scheme = $3, tokens_revoked_at = NOW(), \
+            updated_at = NOW() \
+         WHERE id = $1 AND tenant_id = $4 \
+         RETURNING username, email, disabled_at IS NOT NULL AS disabled",
+    )
+    .bind(user_id)
+    .bind(&unusable)
+    .bind(password::SCHEME)
+    .bind(admin.tenant_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(not_found)?;
+    // A pending token must not survive, and the cooldown doesn't apply.
+    sqlx::query("DELETE FROM password_resets WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(admin = admin.user_id, user_id, "password reset forced");
+
+    // Disabled accounts cannot confirm a reset; force it again after
+    // re-enabling them.
+    if !row.get::<bool, _>("disabled") {
+        reset
+            .send(&state.pool, user_id, row.get("username"), row.get("email"))
+            .await?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,257 @@
+//! Email verification (migration 022). Registering with an address, or
+//! changing it through `PUT /auth/email`, sends a single-use token valid for
+//! `AUTH_VERIFY_TOKEN_TTL_HOURS` through the notifier also used for password
+//! reset; `POST /auth/email/verify/request` sends a fresh one.
+//! `POST /auth/email/verify/confirm` sets `users.email_verified_at` if the
+//! account still has the address the token was sent to. Deployments that
+//! set `API_REQUIRE_VERIFIED_EMAIL` only let verified users call the API.
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::{Duration, Utc};
+use hex::encode as hex_encode;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use tracing::{info, warn};
+
+use crate::notifier::{self, Notice, Notifier, Purpose};
+use crate::reset::{hash_token, REQUEST_COOLDOWN_SECS};
+use crate::{authenticate, validate_email, AppState, AuthError};
+
+#[derive(Clone)]
+pub(crate) struct EmailVerification {
+    notifier: Arc<dyn Notifier>,
+    ttl: Duration,
+}
+
+impl EmailVerification {
+    /// `None` when no notifier is configured.
+    pub(crate) fn from_env(notifier: Option<Arc<dyn Notifier>>) -> Option<Self> {
+        let ttl_hours = std::env::var("AUTH_VERIFY_TOKEN_TTL_HOURS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(24);
+        notifier.map(|notifier| Self {
+            notifier,
+            ttl: Duration::hours(ttl_hours),
+        })
+    }
+
+    /// Issues a token for `email` and delivers it in the background. Returns
+    /// `false` without doing anything while the last token for the same
+    /// address is within the cooldown.
+    pub(crate) async fn send(
+        &self,
+        pool: &PgPool,
+        user_id: i32,
+        username: String,
+        email: String,
+    ) -> Result<bool, AuthError> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex_encode(bytes);
+        let expires_at = Utc::now() + self.ttl;
+        let issued = sqlx::query(
+            "INSERT INTO email_verifications (user_id, email, token_hash, expires_at) \
+             VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (user_id) DO UPDATE SET \
+                email = EXCLUDED.email, token_hash = EXCLUDED.token_hash, \
+                expires_at = EXCLUDED.expires_at, created_at = NOW() \
+             WHERE lower(email_verifications.email) <> lower(EXCLUDED.email) \
+                OR email_verifications.created_at < NOW() - make_interval(secs => $5)",
+        )
+        .bind(user_id)
+        .bind(&email)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .bind(REQUEST_COOLDOWN_SECS)
+        .execute(pool)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?
+        .rows_affected();
+        if issued == 0 {
+            return Ok(false);
+        }
+
+        notifier::spawn_delivery(
+            self.notifier.clone(),
+            Notice {
+                purpose: Purpose::EmailVerification,
+                user_id,
+                username,
+                email: Some(email),
+                token,
+                expires_at,
+            },
+        );
+        Ok(true)
+    }
+
+    /// For callers that already succeeded otherwise, e.g. registration: a
+    /// failed send is logged, and the user can ask for a new token.
+    pub(crate) async fn send_or_warn(
+        &self,
+        pool: &PgPool,
+        user_id: i32,
+        username: String,
+        email: String,
+    ) {
+        if let Err(err) = self.send(pool, user_id, username, email).await {
+            warn!(user_id, error = %err, "failed to issue email verification token");
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChangeEmailRequest {
+    email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct VerifyConfirm {
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EmailStatus {
+    email: String,
+    verified: bool,
+}
+
+fn enabled(state: &AppState) -> Result<&EmailVerification, AuthError> {
+    state
+        .verification
+        .as_ref()
+        .ok_or_else(|| AuthError::NotFound("email verification is not enabled".to_string()))
+}
+
+/// Sets a new address; unless it only differs in case it counts as
+/// unverified until confirmed.
+pub(crate) async fn change_email(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<ChangeEmailRequest>,
+) -> Result<Json<EmailStatus>, AuthError> {
+    let user = authenticate(&headers, &state).await?;
+    let email = payload.email.trim().to_string();
+    validate_email(&email)?;
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let row = sqlx::query(
+        "UPDATE users SET \
+            email_verified_at = CASE WHEN lower(email) = lower($2) THEN email_verified_at END, \
+            email = $2, updated_at = NOW() \
+         WHERE id = $1 RETURNING email_verified_at IS NOT NULL AS verified",
+    )
+    .bind(user.user_id)
+    .bind(&email)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.constraint() == Some("users_email_idx") => {
+            AuthError::Conflict("email address already in use".to_string())
+        }
+        other => AuthError::Internal(other.to_string()),
+    })?;
+    let verified: bool = row.get("verified");
+    if !verified {
+        sqlx::query("DELETE FROM email_verifications WHERE user_id = $1")
+            .bind(user.user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| AuthError::Internal(err.to_string()))?;
+    }
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    info!(user_id = user.user_id, verified, "email address changed");
+
+    if let (false, Some(verification)) = (verified, &state.verification) {
+        verification
+            .send_or_warn(&state.pool, user.user_id, user.username, email.clone())
+            .await;
+    }
+    Ok(Json(EmailStatus { email, verified }))
+}
+
+pub(crate) async fn request_verification(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, AuthError> {
+    let verification = enabled(&state)?;
+    let user = authenticate(&headers, &state).await?;
+    let row = sqlx::query(
+        "SELECT email, email_verified_at IS NOT NULL AS verified FROM users WHERE id = $1",
+    )
+    .bind(user.user_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let Some(email) = row.get::<Option<String>, _>("email") else {
+        return Err(AuthError::BadRequest(
+            "the account has no email address".to_string(),
+        ));
+    };
+    if row.get::<bool, _>("verified") {
+        return Err(AuthError::Conflict(
+            "email address already verified".to_string(),
+        ));
+    }
+    verification
+        .send(&state.pool, user.user_id, user.username, email)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub(crate) async fn confirm_verification(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyConfirm>,
+) -> Result<StatusCode, AuthError> {
+    enabled(&state)?;
+    let invalid = || AuthError::BadRequest("invalid or expired verification token".to_string());
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    let row = sqlx::query(
+        "DELETE FROM email_verifications WHERE token_hash = $1 \
+         RETURNING user_id, email, expires_at > NOW() AS valid",
+    )
+    .bind(hash_token(&payload.token))
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|err| AuthError::Internal(err.to_string()))?
+    .ok_or_else(invalid)?;
+    let user_id: i32 = row.get("user_id");
+    let verified = row.get::<bool, _>("valid")
+        && sqlx::query(
+            "UPDATE users SET email_verified_at = NOW(), updated_at = NOW() \
+             WHERE id = $1 AND lower(email) = lower($2)",
+        )
+        .bind(user_id)
+        .bind(row.get::<String, _>("email"))
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?
+        .rows_affected()
+            > 0;
+    // Commits the deletion either way, so a token works once.
+    tx.commit()
+        .await
+        .map_err(|err| AuthError::Internal(err.to_string()))?;
+    if !verified {
+        return Err(invalid());
+    }
+    info!(user_id, "email address verified");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,488 @@
+//! Command-line parsing. `--profile` and `--output`/`-o` are accepted
+//! anywhere before `--`; everything after the program of `run exec` is
+//! passed to it untouched.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use uuid::Uuid;
+
+use crate::output::OutputMode;
+
+pub(crate) const USAGE: &str = "\
+usage: coder [--profile <name>] [--output json|pretty] <command>
+
+commands:
+  fs ls <path> [--project <id> | --workspace <id>]
+  run exec [--cwd <dir>] [--env KEY=VALUE]... [--timeout-ms <ms>] [--stdin <file|->]
+           [--project <id> | --workspace <id>] <program> [args...]
+  agent dispatch --objective <text> [--agent <kind>] [--note <text>]...
+           [--file <sandbox path>]... [--attach <local file>]... [--model <id>]
+           [--persona <name>] [--wait]
+  project export <project-id> [--out <file>] [--no-wait]
+
+Credentials come from the profile in ~/.config/coder/config.toml (or
+$CODER_CONFIG); CODER_URL, CODER_API_KEY and CODER_TOKEN override it.";
+
+const DEFAULT_AGENT: &str = "code";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Cli {
+    pub(crate) profile: Option<String>,
+    pub(crate) output: Option<OutputMode>,
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Help,
+    FsList {
+        path: String,
+        scope: Scope,
+    },
+    RunExec(RunExec),
+    AgentDispatch(AgentDispatch),
+    ProjectExport {
+        project_id: Uuid,
+        out: Option<PathBuf>,
+        wait: bool,
+    },
+}
+
+/// The project or workspace a sandbox call is rooted in.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Scope {
+    pub(crate) project_id: Option<Uuid>,
+    pub(crate) workspace_id: Option<Uuid>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RunExec {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) cwd: Option<String>,
+    pub(crate) timeout_ms: Option<u64>,
+    /// A local file, or `-` for this process's stdin.
+    pub(crate) stdin: Option<String>,
+    pub(crate) scope: Scope,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct AgentDispatch {
+    pub(crate) agent: String,
+    pub(crate) objective: String,
+    pub(crate) notes: Vec<String>,
+    /// Sandbox paths the gateway reads into the agent context.
+    pub(crate) files: Vec<String>,
+    /// Local files uploaded inline.
+    pub(crate) attachments: Vec<PathBuf>,
+    pub(crate) model: Option<String>,
+    pub(crate) persona: Option<String>,
+    pub(crate) wait: bool,
+}
+
+enum Arg {
+    Flag(String, Option<String>),
+    Positional(String),
+    /// `--`: the remaining arguments are positional.
+    Rest,
+}
+
+struct Parser {
+    argv: VecDeque<String>,
+    profile: Option<String>,
+    output: Option<OutputMode>,
+}
+
+impl Parser {
+    fn next(&mut self) -> anyhow::Result<Option<Arg>> {
+        while let Some(arg) = self.argv.pop_front() {
+            if arg == "--" {
+                return Ok(Some(Arg::Rest));
+            }
+            let (name, inline) = match arg.strip_prefix("--") {
+                Some(flag) => match flag.split_once('=') {
+                    Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                    None => (flag.to_string(), None),
+                },
+                None if arg == "-o" => ("output".to_string(), None),
+                None if arg == "-h" => ("help".to_string(), None),
+                None => return Ok(Some(Arg::Positional(arg))),
+            };
+            match name.as_str() {
+                "profile" => self.profile = Some(self.value("profile", inline)?),
+                "output" => {
+                    let value = self.value("output", inline)?;
+                    self.output = Some(
+                        OutputMode::parse(&value)
+                            .ok_or_else(|| anyhow!("--output must be json or pretty"))?,
+                    );
+                }
+                _ => return Ok(Some(Arg::Flag(name, inline))),
+            }
+        }
+        Ok(None)
+    }
+
+    fn value(&mut self, name: &str, inline: Option<String>) -> anyhow::Result<String> {
+        inline
+            .or_else(|| self.argv.pop_front())
+            .ok_or_else(|| anyhow!("--{name} expects a value"))
+    }
+
+    fn switch(name: &str, inline: Option<String>) -> anyhow::Result<bool> {
+        match inline {
+            None => Ok(true),
+            Some(_) => bail!("--{name} takes no value"),
+        }
+    }
+
+    fn uuid(&mut self, name: &str, inline: Option<String>) -> anyhow::Result<Uuid> {
+        let value = self.value(name, inline)?;
+        Uuid::parse_str(&value).map_err(|_| anyhow!("--{name} expects a uuid, got `{value}`"))
+    }
+
+    fn scope(
+        &mut self,
+        scope: &mut Scope,
+        name: &str,
+        inline: Option<String>,
+    ) -> anyhow::Result<bool> {
+        match name {
+            "project" => scope.project_id = Some(self.uuid(name, inline)?),
+            "workspace" => scope.workspace_id = Some(self.uuid(name, inline)?),
+            _ => return Ok(false),
+        }
+        if scope.project_id.is_some() && scope.workspace_id.is_some() {
+            bail!("--project and --workspace are mutually exclusive");
+        }
+        Ok(true)
+    }
+
+    fn rest(&mut self) -> Vec<String> {
+        self.argv.drain(..).collect()
+    }
+}
+
+fn unknown(name: &str) -> anyhow::Error {
+    anyhow!("unknown option `--{name}`")
+}
+
+pub(crate) fn parse(argv: impl IntoIterator<Item = String>) -> anyhow::Result<Cli> {
+    let mut parser = Parser {
+        argv: argv.into_iter().collect(),
+        profile: None,
+        output: None,
+    };
+    let mut words = Vec::new();
+    let command = loop {
+        match parser.next()? {
+            Some(Arg::Positional(word)) if words.is_empty() && word == "help" => {
+                break Command::Help
+            }
+            Some(Arg::Positional(word)) => {
+                words.push(word);
+                if words.len() == 2 {
+                    break command(&mut parser, &words[0], &words[1])?;
+                }
+            }
+            Some(Arg::Flag(name, _)) if name == "help" => break Command::Help,
+            Some(Arg::Flag(name, _)) => return Err(unknown(&name)),
+            Some(Arg::Rest) | None if words.is_empty() => break Command::Help,
+            Some(Arg::Rest) | None => bail!("`{}` needs a subcommand", words[0]),
+        }
+    };
+    Ok(Cli {
+        profile: parser.profile,
+        output: parser.output,
+        command,
+    })
+}
+
+fn command(parser: &mut Parser, group: &str, action: &str) -> anyhow::Result<Command> {
+    match (group, action) {
+        ("fs", "ls") => fs_list(parser),
+        ("run", "exec") => run_exec(parser).map(Command::RunExec),
+        ("agent", "dispatch") => agent_dispatch(parser).map(Command::AgentDispatch),
+        ("project", "export") => project_export(parser),
+        _ => bail!("unknown command `{group} {action}`"),
+    }
+}
+
+fn fs_list(parser: &mut Parser) -> anyhow::Result<Command> {
+    let mut scope = Scope::default();
+    let mut path = None;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Arg::Flag(name, inline) => {
+                if !parser.scope(&mut scope, &name, inline)? {
+                    return Err(unknown(&name));
+                }
+            }
+            Arg::Positional(value) if path.is_none() => path = Some(value),
+            Arg::Positional(value) => bail!("unexpected argument `{value}`"),
+            Arg::Rest => match parser.rest().as_slice() {
+                [value] if path.is_none() => path = Some(value.clone()),
+                [] => {}
+                _ => bail!("fs ls takes a single path"),
+            },
+        }
+    }
+    Ok(Command::FsList {
+        path: path.unwrap_or_else(|| ".".to_string()),
+        scope,
+    })
+}
+
+fn run_exec(parser: &mut Parser) -> anyhow::Result<RunExec> {
+    let mut exec = RunExec::default();
+    let mut argv = loop {
+        let (name, inline) = match parser.next()? {
+            Some(Arg::Flag(name, inline)) => (name, inline),
+            Some(Arg::Positional(program)) => {
+                let mut argv = vec![program];
+                argv.extend(parser.rest());
+                break argv;
+            }
+            Some(Arg::Rest) => break parser.rest(),
+            None => break Vec::new(),
+        };
+        match name.as_str() {
+            "cwd" => exec.cwd = Some(parser.value(&name, inline)?),
+            "stdin" => exec.stdin = Some(parser.value(&name, inline)?),
+            "timeout-ms" => {
+                let value = parser.value(&name, inline)?;
+                exec.timeout_ms = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|ms| *ms > 0)
+                        .ok_or_else(|| anyhow!("--timeout-ms expects a positive integer"))?,
+                );
+            }
+            "env" => {
+                let value = parser.value(&name, inline)?;
+                let (key, value) = value
+                    .split_once('=')
+                    .filter(|(key, _)| !key.is_empty())
+                    .ok_or_else(|| anyhow!("--env expects KEY=VALUE, got `{value}`"))?;
+                exec.env.push((key.to_string(), value.to_string()));
+            }
+            _ => {
+                if !parser.scope(&mut exec.scope, &name, inline)? {
+                    return Err(unknown(&name));
+                }
+            }
+        }
+    };
+    if argv.is_empty() {
+        bail!("run exec needs a program");
+    }
+    exec.program = argv.remove(0);
+    exec.args = argv;
+    Ok(exec)
+}
+
+fn agent_dispatch(parser: &mut Parser) -> anyhow::Result<AgentDispatch> {
+    let mut dispatch = AgentDispatch {
+        agent: DEFAULT_AGENT.to_string(),
+        ..AgentDispatch::default()
+    };
+    while let Some(arg) = parser.next()? {
+        let (name, inline) = match arg {
+            Arg::Flag(name, inline) => (name, inline),
+            Arg::Positional(value) => bail!("unexpected argument `{value}`"),
+            Arg::Rest => bail!("agent dispatch takes no positional arguments"),
+        };
+        match name.as_str() {
+            "agent" => dispatch.agent = parser.value(&name, inline)?,
+            "objective" => dispatch.objective = parser.value(&name, inline)?,
+            "note" => dispatch.notes.push(parser.value(&name, inline)?),
+            "file" => dispatch.files.push(parser.value(&name, inline)?),
+            "attach" => dispatch
+                .attachments
+                .push(PathBuf::from(parser.value(&name, inline)?)),
+            "model" => dispatch.model = Some(parser.value(&name, inline)?),
+            "persona" => dispatch.persona = Some(parser.value(&name, inline)?),
+            "wait" => dispatch.wait = Parser::switch(&name, inline)?,
+            _ => return Err(unknown(&name)),
+        }
+    }
+    if dispatch.objective.trim().is_empty() {
+        bail!("agent dispatch needs --objective");
+    }
+    Ok(dispatch)
+}
+
+fn project_export(parser: &mut Parser) -> anyhow::Result<Command> {
+    let mut project_id = None;
+    let mut out = None;
+    let mut wait = true;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Arg::Flag(name, inline) => match name.as_str() {
+                "out" => out = Some(PathBuf::from(parser.value(&name, inline)?)),
+                "no-wait" => wait = !Parser::switch(&name, inline)?,
+                _ => return Err(unknown(&name)),
+            },
+            Arg::Positional(value) if project_id.is_none() => {
+                project_id = Some(
+                    Uuid::parse_str(&value)
+                        .map_err(|_| anyhow!("project id must be a uuid, got `{value}`"))?,
+                );
+            }
+            Arg::Positional(value) => bail!("unexpected argument `{value}`"),
+            Arg::Rest => bail!("project export takes a single project id"),
+        }
+    }
+    let project_id = project_id.ok_or_else(|| anyhow!("project export needs a project id"))?;
+    if out.is_some() && !wait {
+        bail!("--out needs the finished export; drop --no-wait");
+    }
+    Ok(Command::ProjectExport {
+        project_id,
+        out,
+        wait,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT: &str = "5f0c6d3e-8a1b-4c2d-9e3f-1a2b3c4d5e6f";
+
+    fn parse_args(args: &[&str]) -> anyhow::Result<Cli> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse_args(args).expect("arguments parse").command
+    }
+
+    #[test]
+    fn global_options_are_accepted_anywhere() {
+        let cli = parse_args(&["--profile", "ci", "fs", "ls", "src", "-o", "json"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("ci"));
+        assert_eq!(cli.output, Some(OutputMode::Json));
+        assert_eq!(
+            cli.command,
+            Command::FsList {
+                path: "src".into(),
+                scope: Scope::default(),
+            }
+        );
+        let cli = parse_args(&["fs", "ls", "--output=pretty"]).unwrap();
+        assert_eq!(cli.output, Some(OutputMode::Pretty));
+        assert!(parse_args(&["-o", "yaml", "fs", "ls"]).is_err());
+    }
+
+    #[test]
+    fn fs_ls_defaults_to_root_and_takes_a_scope() {
+        let project = Uuid::parse_str(PROJECT).unwrap();
+        assert_eq!(
+            command(&["fs", "ls", &format!("--project={PROJECT}")]),
+            Command::FsList {
+                path: ".".into(),
+                scope: Scope {
+                    project_id: Some(project),
+                    workspace_id: None,
+                },
+            }
+        );
+        assert!(parse_args(&["fs", "ls", "--project", "nope"]).is_err());
+        assert!(parse_args(&["fs", "ls", "--project", PROJECT, "--workspace", PROJECT]).is_err());
+    }
+
+    #[test]
+    fn run_exec_passes_program_arguments_through() {
+        let Command::RunExec(exec) = command(&[
+            "run",
+            "exec",
+            "--env",
+            "RUST_LOG=debug",
+            "--timeout-ms",
+            "5000",
+            "cargo",
+            "test",
+            "--",
+            "--nocapture",
+            "-o",
+        ]) else {
+            panic!("expected run exec");
+        };
+        assert_eq!(exec.program, "cargo");
+        assert_eq!(exec.args, ["test", "--", "--nocapture", "-o"]);
+        assert_eq!(exec.env, [("RUST_LOG".to_string(), "debug".to_string())]);
+        assert_eq!(exec.timeout_ms, Some(5000));
+
+        let Command::RunExec(exec) = command(&["run", "exec", "--", "--weird-program"]) else {
+            panic!("expected run exec");
+        };
+        assert_eq!(exec.program, "--weird-program");
+        assert!(exec.args.is_empty());
+
+        assert!(parse_args(&["run", "exec"]).is_err());
+        assert!(parse_args(&["run", "exec", "--env", "=x", "ls"]).is_err());
+        assert!(parse_args(&["run", "exec", "--timeout-ms", "0", "ls"]).is_err());
+    }
+
+    #[test]
+    fn agent_dispatch_needs_an_objective() {
+        let Command::AgentDispatch(dispatch) = command(&[
+            "agent",
+            "dispatch",
+            "--objective",
+            "fix the build",
+            "--note",
+            "ci is red",
+            "--file",
+            "src/lib.rs",
+            "--wait",
+        ]) else {
+            panic!("expected agent dispatch");
+        };
+        assert_eq!(dispatch.agent, DEFAULT_AGENT);
+        assert_eq!(dispatch.objective, "fix the build");
+        assert_eq!(dispatch.notes, ["ci is red"]);
+        assert_eq!(dispatch.files, ["src/lib.rs"]);
+        assert!(dispatch.wait);
+        assert!(parse_args(&["agent", "dispatch", "--agent", "test"]).is_err());
+        assert!(parse_args(&["agent", "dispatch", "--objective", "x", "--wait=no"]).is_err());
+    }
+
+    #[test]
+    fn project_export_waits_unless_told_otherwise() {
+        let project_id = Uuid::parse_str(PROJECT).unwrap();
+        assert_eq!(
+            command(&["project", "export", PROJECT, "--out", "bundle.json"]),
+            Command::ProjectExport {
+                project_id,
+                out: Some(PathBuf::from("bundle.json")),
+                wait: true,
+            }
+        );
+        assert_eq!(
+            command(&["project", "export", "--no-wait", PROJECT]),
+            Command::ProjectExport {
+                project_id,
+                out: None,
+                wait: false,
+            }
+        );
+        assert!(parse_args(&["project", "export"]).is_err());
+        assert!(parse_args(&["project", "export", PROJECT, "--no-wait", "--out", "x"]).is_err());
+    }
+
+    #[test]
+    fn help_and_unknown_commands() {
+        assert_eq!(command(&[]), Command::Help);
+        assert_eq!(command(&["--help"]), Command::Help);
+        assert_eq!(command(&["help"]), Command::Help);
+        assert!(parse_args(&["fs"]).is_err());
+        assert!(parse_args(&["fs", "rm", "x"]).is_err());
+        assert!(parse_args(&["--verbose", "fs", "ls"]).is_err());
+    }
+}
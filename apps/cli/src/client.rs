@@ -0,0 +1,129 @@
+//! Minimal client for the API gateway: JSON-RPC calls against `/rpc` and
+//! authenticated downloads from the REST routes, with the profile's API key
+//! (`x-api-key`) or bearer token.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::profile::{Credential, Profile};
+
+const USER_AGENT: &str = concat!("coder-cli/", env!("CARGO_PKG_VERSION"));
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A JSON-RPC error returned by the gateway.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RpcError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+    #[serde(default)]
+    pub(crate) data: Option<Value>,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse {
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+pub(crate) struct Client {
+    http: reqwest::Client,
+    url: String,
+    profile: String,
+    credential: Credential,
+    next_id: AtomicU64,
+}
+
+impl Client {
+    pub(crate) fn new(profile: &Profile) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .context("failed to build http client")?;
+        Ok(Self {
+            http,
+            url: profile.url.clone(),
+            profile: profile.name.clone(),
+            credential: profile.credential.clone(),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    fn unreachable(&self) -> String {
+        format!("failed to reach {} (profile `{}`)", self.url, self.profile)
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.credential {
+            Credential::ApiKey(key) => request.header("x-api-key", key),
+            Credential::Token(token) => request.bearer_auth(token),
+        }
+    }
+
+    pub(crate) async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let body = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id });
+        let response = self
+            .authorized(self.http.post(format!("{}/rpc", self.url)))
+            .json(&body)
+            .send()
+            .await
+            .with_context(|| self.unreachable())?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .context("failed to read rpc response")?;
+        // Errors come back as JSON-RPC errors under any status; only a body
+        // that isn't one (e.g. a proxy page) is reported by status.
+        let response: RpcResponse = serde_json::from_str(&text).map_err(|_| {
+            anyhow!(
+                "{method} failed with HTTP {status}: {}",
+                text.chars().take(200).collect::<String>()
+            )
+        })?;
+        match (response.result, response.error) {
+            (_, Some(err)) => Err(err.into()),
+            (Some(result), None) => Ok(result),
+            (None, None) => Ok(Value::Null),
+        }
+    }
+
+    /// Fetches a REST resource such as `/jobs/<id>/artifact`.
+    pub(crate) async fn download(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .authorized(self.http.get(format!("{}{path}", self.url)))
+            .send()
+            .await
+            .with_context(|| self.unreachable())?;
+        let status = response.status();
+        let body = response.bytes().await.context("failed to read download")?;
+        if status.is_success() {
+            return Ok(body.to_vec());
+        }
+        // The REST layer reports failures as `{"error": {code, message, data}}`.
+        match serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|value| serde_json::from_value::<RpcError>(value["error"].clone()).ok())
+        {
+            Some(err) => Err(err.into()),
+            None => Err(anyhow!("GET {path} failed with HTTP {status}")),
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! The subcommands, each a thin wrapper around one RPC method. JSON output
+//! is the method's result as the gateway returns it; `--wait` style options
+//! poll the matching status method once a second.
+
+use std::io::Write as _;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde_json::{json, Map, Value};
+use tokio::io::AsyncReadExt as _;
+
+use crate::args::{AgentDispatch, Command, RunExec, Scope};
+use crate::client::Client;
+use crate::output::{self, OutputMode};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Job states after which `project.export` will not make progress.
+const JOB_FINAL: [&str; 3] = ["succeeded", "dead", "cancelled"];
+
+pub(crate) async fn run(
+    client: &Client,
+    output: OutputMode,
+    command: Command,
+) -> anyhow::Result<ExitCode> {
+    match command {
+        Command::Help => Ok(ExitCode::SUCCESS),
+        Command::FsList { path, scope } => fs_list(client, output, path, scope).await,
+        Command::RunExec(exec) => run_exec(client, output, exec).await,
+        Command::AgentDispatch(dispatch) => agent_dispatch(client, output, dispatch).await,
+        Command::ProjectExport {
+            project_id,
+            out,
+            wait,
+        } => project_export(client, output, project_id, out.as_deref(), wait).await,
+    }
+}
+
+fn with_scope(mut params: Map<String, Value>, scope: &Scope) -> Value {
+    if let Some(project_id) = scope.project_id {
+        params.insert("project_id".into(), json!(project_id));
+    }
+    if let Some(workspace_id) = scope.workspace_id {
+        params.insert("workspace_id".into(), json!(workspace_id));
+    }
+    Value::Object(params)
+}
+
+fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+async fn fs_list(
+    client: &Client,
+    output: OutputMode,
+    path: String,
+    scope: Scope,
+) -> anyhow::Result<ExitCode> {
+    let params = with_scope(object(json!({ "path": path })), &scope);
+    let entries = client.call("fs.list", params).await?;
+    output.print(&entries, |entries| {
+        entries
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|entry| {
+                let name = entry["name"].as_str().unwrap_or_default();
+                if entry["is_dir"].as_bool().unwrap_or(false) {
+                    format!("{:>10}  {name}/", "-")
+                } else {
+                    let size = output::size(entry["size"].as_u64().unwrap_or(0));
+                    format!("{size:>10}  {name}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    });
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn read_stdin(source: &str) -> anyhow::Result<Vec<u8>> {
+    if source == "-" {
+        let mut data = Vec::new();
+        tokio::io::stdin()
+            .read_to_end(&mut data)
+            .await
+            .context("failed to read stdin")?;
+        return Ok(data);
+    }
+    tokio::fs::read(source)
+        .await
+        .with_context(|| format!("failed to read {source}"))
+}
+
+/// Exit statuses outside 0..=255 (e.g. a killed process) become 1.
+fn exit_code(code: Option<i64>) -> ExitCode {
+    code.and_then(|code| u8::try_from(code).ok())
+        .map(ExitCode::from)
+        .unwrap_or(ExitCode::FAILURE)
+}
+
+async fn run_exec(client: &Client, output: OutputMode, exec: RunExec) -> anyhow::Result<ExitCode> {
+    let mut params = object(json!({
+        "program": exec.program,
+        "args": exec.args,
+        "env": exec
+            .env
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect::<Vec<_>>(),
+    }));
+    if let Some(cwd) = exec.cwd {
+        params.insert("cwd".into(), json!(cwd));
+    }
+    if let Some(timeout_ms) = exec.timeout_ms {
+        params.insert("timeout_ms".into(), json!(timeout_ms));
+    }
+    if let Some(source) = exec.stdin {
+        let data = read_stdin(&source).await?;
+        if !data.is_empty() {
+            params.insert("stdin".into(), json!(BASE64.encode(data)));
+        }
+    }
+    let result = client
+        .call("run.exec", with_scope(params, &exec.scope))
+        .await?;
+    let code = exit_code(result["exit_code"].as_i64());
+    match output {
+        OutputMode::Json => output.print(&result, |_| String::new()),
+        OutputMode::Pretty => {
+            let decode = |field: &str| {
+                BASE64
+                    .decode(result[field].as_str().unwrap_or_default())
+                    .with_context(|| format!("invalid base64 in {field}"))
+            };
+            let (stdout, stderr) = (decode("stdout")?, decode("stderr")?);
+            std::io::stdout().lock().write_all(&stdout)?;
+            std::io::stderr().lock().write_all(&stderr)?;
+        }
+    }
+    Ok(code)
+}
+
+async fn agent_dispatch(
+    client: &Client,
+    output: OutputMode,
+    dispatch: AgentDispatch,
+) -> anyhow::Result<ExitCode> {
+    let mut files: Vec<Value> = dispatch
+        .files
+        .iter()
+        .map(|path| json!({ "path": path }))
+        .collect();
+    for path in &dispatch.attachments {
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let title = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        files.push(json!({ "title": title, "content_base64": BASE64.encode(data) }));
+    }
+    let mut params = object(json!({
+        "agent": dispatch.agent,
+        "objective": dispatch.objective,
+    }));
+    if !dispatch.notes.is_empty() || !files.is_empty() {
+        params.insert(
+            "context".into(),
+            json!({ "notes": dispatch.notes, "files": files }),
+        );
+    }
+    if let Some(model) = dispatch.model {
+        params.insert("model".into(), json!(model));
+    }
+    if let Some(persona) = dispatch.persona {
+        params.insert("persona".into(), json!(persona));
+    }
+    let submission = client.call("agent.dispatch", Value::Object(params)).await?;
+    if !dispatch.wait {
+        output.print(&submission, |submission| {
+            format!(
+                "task {} {}",
+                submission["task_id"].as_str().unwrap_or_default(),
+                submission["status"].as_str().unwrap_or_default()
+            )
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+    let task_id = submission["task_id"]
+        .as_str()
+        .ok_or_else(|| anyhow!("agent.dispatch returned no task_id"))?
+        .to_string();
+    let snapshot = loop {
+        let snapshot = client
+            .call("agent.status", json!({ "task_id": task_id }))
+            .await?;
+        match snapshot["status"].as_str() {
+            // A checkpoint needs `agent.respond`; waiting here would hang.
+            Some("completed" | "failed" | "cancelled" | "waiting_for_input") => break snapshot,
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    };
+    output.print(&snapshot, |snapshot| {
+        let mut lines = vec![format!(
+            "task {} ({}): {}",
+            task_id,
+            snapshot["agent"].as_str().unwrap_or_default(),
+            snapshot["status"].as_str().unwrap_or_default()
+        )];
+        if let Some(summary) = snapshot["summary"].as_str() {
+            lines.push(summary.to_string());
+        }
+        if let Some(message) = snapshot["error"]["message"].as_str() {
+            lines.push(format!("error: {message}"));
+        }
+        lines.join("\n")
+    });
+    Ok(match snapshot["status"].as_str() {
+        Some("completed") => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    })
+}
+
+async fn project_export(
+    client: &Client,
+    output: OutputMode,
+    project_id: uuid::Uuid,
+    out: Option<&Path>,
+    wait: bool,
+) -> anyhow::Result<ExitCode> {
+    let job = client
+        .call("project.export", json!({ "project_id": project_id }))
+        .await?;
+    let job_id = job["job_id"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("project.export returned no job_id"))?;
+    if !wait {
+        output.print(&job, |_| {
+            format!("export job {job_id} queued; fetch it from /jobs/{job_id}/artifact")
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+    let job = loop {
+        let job = client
+            .call("job.status", json!({ "job_id": job_id }))
+            .await?;
+        if JOB_FINAL.contains(&job["status"].as_str().unwrap_or_default()) {
+            break job;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+    if job["status"] != "succeeded" {
+        let reason = job["last_error"].as_str().unwrap_or("no error recorded");
+        return Err(anyhow!(
+            "export job {job_id} ended {}: {reason}",
+            job["status"].as_str().unwrap_or_default()
+        ));
+    }
+    let bundle = client.download(&format!("/jobs/{job_id}/artifact")).await?;
+    match out {
+        Some(path) => {
+            tokio::fs::write(path, &bundle)
+                .await
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            let written = json!({ "job_id": job_id, "path": path, "bytes": bundle.len() });
+            output.print(&written, |_| {
+                format!(
+                    "wrote {} to {}",
+                    output::size(bundle.len() as u64),
+                    path.display()
+                )
+            });
+        }
+        // The bundle is JSON already, so it goes out as-is in both modes.
+        None => std::io::stdout().lock().write_all(&bundle)?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,48 @@
+//! `coder`: the platform from the command line, for scripts, CI jobs and
+//! headless use. Each subcommand maps onto one JSON-RPC method of the API
+//! gateway; see `coder --help` and [`profile`] for credentials.
+
+mod args;
+mod client;
+mod commands;
+mod output;
+mod profile;
+
+use std::process::ExitCode;
+
+use output::OutputMode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = match args::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("error: {err:#}\n\n{}", args::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if cli.command == args::Command::Help {
+        println!("{}", args::USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let env = |name: &str| std::env::var(name).ok();
+    let output = cli
+        .output
+        .or_else(|| env("CODER_OUTPUT").as_deref().and_then(OutputMode::parse));
+    let profile = match profile::resolve(cli.profile.as_deref(), &env) {
+        Ok(profile) => profile,
+        Err(err) => {
+            output.unwrap_or_else(OutputMode::detect).error(&err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let output = output.or(profile.output).unwrap_or_else(OutputMode::detect);
+    let result = match client::Client::new(&profile) {
+        Ok(client) => commands::run(&client, output, cli.command).await,
+        Err(err) => Err(err),
+    };
+    result.unwrap_or_else(|err| {
+        output.error(&err);
+        ExitCode::FAILURE
+    })
+}
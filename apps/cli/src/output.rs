@@ -0,0 +1,113 @@
+//! Output modes. `json` prints every result as a single line of JSON on
+//! stdout (errors go to stderr the same way) for scripts and `jq`; `pretty`
+//! is the human-readable rendering each command chooses. Without `--output`
+//! or a profile setting, `pretty` is used on a terminal and `json`
+//! otherwise.
+
+use std::io::{IsTerminal as _, Write as _};
+
+use serde_json::{json, Value};
+
+use crate::client::RpcError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputMode {
+    Json,
+    Pretty,
+}
+
+impl OutputMode {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(Self::Json),
+            "pretty" => Some(Self::Pretty),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn detect() -> Self {
+        if std::io::stdout().is_terminal() {
+            Self::Pretty
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Prints `value` as JSON, or hands it to `pretty`.
+    pub(crate) fn print(self, value: &Value, pretty: impl FnOnce(&Value) -> String) {
+        let rendered = match self {
+            Self::Json => value.to_string(),
+            Self::Pretty => pretty(value),
+        };
+        if !rendered.is_empty() {
+            println!("{}", rendered.trim_end_matches('\n'));
+        }
+    }
+
+    pub(crate) fn error(self, err: &anyhow::Error) {
+        let rpc = err.downcast_ref::<RpcError>();
+        let mut stderr = std::io::stderr().lock();
+        let _ = match (self, rpc) {
+            (Self::Json, Some(rpc)) => writeln!(
+                stderr,
+                "{}",
+                json!({ "error": { "code": rpc.code, "message": rpc.message, "data": rpc.data } })
+            ),
+            (Self::Json, None) => writeln!(
+                stderr,
+                "{}",
+                json!({ "error": { "message": format!("{err:#}") } })
+            ),
+            (Self::Pretty, Some(rpc)) => {
+                let detail = rpc
+                    .data
+                    .as_ref()
+                    .map(|data| format!("\n{}", indented(data)))
+                    .unwrap_or_default();
+                writeln!(stderr, "error: {} (code {}){detail}", rpc.message, rpc.code)
+            }
+            (Self::Pretty, None) => writeln!(stderr, "error: {err:#}"),
+        };
+    }
+}
+
+/// Pretty-printed JSON, the fallback rendering for results without a
+/// dedicated one.
+pub(crate) fn indented(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+/// Human-readable byte count, e.g. `1.5 KiB`.
+pub(crate) fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(OutputMode::parse("json"), Some(OutputMode::Json));
+        assert_eq!(OutputMode::parse("pretty"), Some(OutputMode::Pretty));
+        assert_eq!(OutputMode::parse("yaml"), None);
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(size(0), "0 B");
+        assert_eq!(size(1023), "1023 B");
+        assert_eq!(size(1536), "1.5 KiB");
+        assert_eq!(size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
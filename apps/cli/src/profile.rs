@@ -0,0 +1,247 @@
+//! Credential profiles. `coder` reads `$CODER_CONFIG`, else
+//! `$XDG_CONFIG_HOME/coder/config.toml`, else `~/.config/coder/config.toml`:
+//!
+//! ```toml
+//! default_profile = "local"
+//!
+//! [profiles.local]
+//! url = "http://localhost:6813"
+//! api_key = "..."
+//!
+//! [profiles.ci]
+//! url = "https://coder.example.com"
+//! token = "eyJ..."
+//! ```
+//!
+//! The profile is picked by `--profile`, then `CODER_PROFILE`, then
+//! `default_profile`, then `default`. `CODER_URL`, `CODER_API_KEY` and
+//! `CODER_TOKEN` override the profile's values, so CI jobs can run without
+//! a file at all. An API key wins over a token when both are set, as it
+//! does in the gateway.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+use crate::output::OutputMode;
+
+const DEFAULT_PROFILE: &str = "default";
+const DEFAULT_URL: &str = "http://localhost:6813";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, ProfileEntry>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileEntry {
+    url: Option<String>,
+    api_key: Option<String>,
+    token: Option<String>,
+    output: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Credential {
+    ApiKey(String),
+    Token(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) credential: Credential,
+    pub(crate) output: Option<OutputMode>,
+}
+
+pub(crate) fn config_path(env: &dyn Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    if let Some(path) = env("CODER_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    env("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("coder").join("config.toml"))
+}
+
+/// Resolves the profile named by `selected` (or the environment's choice)
+/// against the config file, which may be missing as long as the
+/// environment supplies a credential.
+pub(crate) fn resolve(
+    selected: Option<&str>,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<Profile> {
+    let path = config_path(env);
+    let file = match &path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("invalid config file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => ConfigFile::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        },
+        None => ConfigFile::default(),
+    };
+    pick(file, selected, env)
+}
+
+fn pick(
+    mut file: ConfigFile,
+    selected: Option<&str>,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<Profile> {
+    let explicit = selected
+        .map(str::to_string)
+        .or_else(|| env("CODER_PROFILE"));
+    let name = explicit
+        .clone()
+        .or(file.default_profile.take())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    let entry = match file.profiles.remove(&name) {
+        Some(entry) => entry,
+        // Only a profile someone asked for by name has to exist.
+        None if explicit.is_some() => bail!("unknown profile `{name}`"),
+        None => ProfileEntry::default(),
+    };
+    let set = |value: Option<String>| value.filter(|value| !value.is_empty());
+    let api_key = set(env("CODER_API_KEY")).or(set(entry.api_key));
+    let token = set(env("CODER_TOKEN")).or(set(entry.token));
+    let credential = match (api_key, token) {
+        (Some(key), _) => Credential::ApiKey(key),
+        (None, Some(token)) => Credential::Token(token),
+        (None, None) => {
+            return Err(anyhow!(
+                "profile `{name}` has no credentials; set api_key or token in the config file \
+                 or CODER_API_KEY / CODER_TOKEN"
+            ))
+        }
+    };
+    let output = entry
+        .output
+        .as_deref()
+        .map(|value| {
+            OutputMode::parse(value)
+                .ok_or_else(|| anyhow!("profile `{name}`: output must be json or pretty"))
+        })
+        .transpose()?;
+    Ok(Profile {
+        url: set(env("CODER_URL"))
+            .or(set(entry.url))
+            .unwrap_or_else(|| DEFAULT_URL.to_string())
+            .trim_end_matches('/')
+            .to_string(),
+        name,
+        credential,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(contents: &str) -> ConfigFile {
+        toml::from_str(contents).expect("config parses")
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    const CONFIG: &str = r#"
+        default_profile = "local"
+
+        [profiles.local]
+        url = "http://localhost:6813/"
+        api_key = "local-key"
+
+        [profiles.ci]
+        url = "https://coder.example.com"
+        token = "ci-token"
+        output = "json"
+    "#;
+
+    #[test]
+    fn uses_the_default_profile() {
+        let profile = pick(file(CONFIG), None, &env(&[])).unwrap();
+        assert_eq!(profile.name, "local");
+        assert_eq!(profile.url, "http://localhost:6813");
+        assert_eq!(profile.credential, Credential::ApiKey("local-key".into()));
+        assert_eq!(profile.output, None);
+    }
+
+    #[test]
+    fn flag_beats_environment_selection() {
+        let profile = pick(
+            file(CONFIG),
+            Some("ci"),
+            &env(&[("CODER_PROFILE", "local")]),
+        )
+        .unwrap();
+        assert_eq!(profile.name, "ci");
+        assert_eq!(profile.credential, Credential::Token("ci-token".into()));
+        assert_eq!(profile.output, Some(OutputMode::Json));
+    }
+
+    #[test]
+    fn environment_overrides_profile_values() {
+        let profile = pick(
+            file(CONFIG),
+            Some("ci"),
+            &env(&[
+                ("CODER_URL", "http://api:6813"),
+                ("CODER_API_KEY", "env-key"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(profile.url, "http://api:6813");
+        assert_eq!(profile.credential, Credential::ApiKey("env-key".into()));
+    }
+
+    #[test]
+    fn works_without_a_config_file() {
+        let profile = pick(ConfigFile::default(), None, &env(&[("CODER_TOKEN", "t")])).unwrap();
+        assert_eq!(profile.name, DEFAULT_PROFILE);
+        assert_eq!(profile.url, DEFAULT_URL);
+        assert_eq!(profile.credential, Credential::Token("t".into()));
+    }
+
+    #[test]
+    fn rejects_unknown_or_empty_profiles() {
+        assert!(pick(file(CONFIG), Some("prod"), &env(&[])).is_err());
+        assert!(pick(ConfigFile::default(), None, &env(&[])).is_err());
+        assert!(toml::from_str::<ConfigFile>("[profiles.x]\npassword = \"p\"").is_err());
+    }
+
+    #[test]
+    fn config_path_prefers_explicit_then_xdg() {
+        assert_eq!(
+            config_path(&env(&[("CODER_CONFIG", "/etc/coder.toml"), ("HOME", "/h")])),
+            Some(PathBuf::from("/etc/coder.toml"))
+        );
+        assert_eq!(
+            config_path(&env(&[("XDG_CONFIG_HOME", "/x"), ("HOME", "/h")])),
+            Some(PathBuf::from("/x/coder/config.toml"))
+        );
+        assert_eq!(
+            config_path(&env(&[("HOME", "/h")])),
+            Some(PathBuf::from("/h/.config/coder/config.toml"))
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! The runner's side of the `/runners/ws` connection: registers, keeps the
+//! heartbeat going and executes calls concurrently up to its slot count.
+//! A lost connection is retried with backoff; calls still running when it
+//! drops finish, but their results are lost and the API reports the calls
+//! as failed.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn};
+
+use crate::executor::Executor;
+use crate::protocol::{ErrorKind, FromRunner, Outcome, RemoteError, ToRunner, PROTOCOL_VERSION};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// `ws://` or `wss://` URL of the API's `/runners/ws`.
+    pub url: String,
+    /// The API's `RUNNER_TOKEN`.
+    pub token: String,
+    pub name: String,
+}
+
+/// Stays connected to the API until the process ends.
+pub async fn run(options: ConnectOptions, executor: Arc<Executor>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match session(&options, executor.clone()).await {
+            Ok(()) => {
+                info!("api closed the runner connection");
+                backoff = Duration::from_secs(1);
+            }
+            Err(err) => warn!(error = %format!("{err:#}"), "runner connection failed"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn session(options: &ConnectOptions, executor: Arc<Executor>) -> anyhow::Result<()> {
+    let mut request = options
+        .url
+        .as_str()
+        .into_client_request()
+        .context("invalid RUNNER_API_URL")?;
+    request.headers_mut().insert(
+        "authorization",
+        HeaderValue::from_str(&format!("Bearer {}", options.token))
+            .context("RUNNER_TOKEN is not a valid header value")?,
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .context("failed to connect to the api")?;
+
+    let register = FromRunner::Register {
+        version: PROTOCOL_VERSION,
+        name: options.name.clone(),
+        capabilities: executor.capabilities(),
+    };
+    socket
+        .send(Message::Text(serde_json::to_string(&register)?))
+        .await?;
+    let heartbeat = loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text)? {
+                ToRunner::Registered {
+                    runner_id,
+                    heartbeat_secs,
+                } => {
+                    info!(%runner_id, name = %options.name, "registered with the api");
+                    break Duration::from_secs(heartbeat_secs.max(1));
+                }
+                ToRunner::Rejected { reason } => anyhow::bail!("api rejected the runner: {reason}"),
+                ToRunner::Call { .. } => anyhow::bail!("api sent a call before registering"),
+            },
+            Some(Ok(Message::Close(_))) | None => anyhow::bail!("api closed the connection"),
+            Some(Ok(_)) => continue,
+            Some(Err(err)) => return Err(err.into()),
+        }
+    };
+
+    let active = Arc::new(AtomicUsize::new(0));
+    let slots = Arc::new(Semaphore::new(executor.slots()));
+    let (results, mut finished) = mpsc::unbounded_channel::<FromRunner>();
+    let mut ticker = tokio::time::interval(heartbeat);
+    loop {
+        let outgoing = tokio::select! {
+            _ = ticker.tick() => FromRunner::Heartbeat {
+                active: active.load(Ordering::Relaxed),
+            },
+            Some(result) = finished.recv() => result,
+            incoming = socket.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<ToRunner>(&text) {
+                        Ok(ToRunner::Call { id, call }) => {
+                            let (executor, active, slots, results) =
+                                (executor.clone(), active.clone(), slots.clone(), results.clone());
+                            tokio::spawn(async move {
+                                let _permit = slots.acquire_owned().await;
+                                active.fetch_add(1, Ordering::Relaxed);
+                                let outcome = executor.execute(call).await;
+                                active.fetch_sub(1, Ordering::Relaxed);
+                                let _ = results.send(FromRunner::Result { id, outcome });
+                            });
+                            continue;
+                        }
+                        Ok(_) => continue,
+                        Err(err) => match undecodable_call(&text, &err) {
+                            Some(result) => result,
+                            None => {
+                                warn!(error = %err, "ignoring malformed message from the api");
+                                continue;
+                            }
+                        },
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.into()),
+            },
+        };
+        socket
+            .send(Message::Text(serde_json::to_string(&outgoing)?))
+            .await?;
+    }
+}
+
+/// Answers a call this runner cannot decode, e.g. a method added in a newer
+/// API, so the caller does not wait for a result that never comes.
+fn undecodable_call(text: &str, err: &serde_json::Error) -> Option<FromRunner> {
+    let message: Value = serde_json::from_str(text).ok()?;
+    if message.get("type")?.as_str()? != "call" {
+        return None;
+    }
+    let id = serde_json::from_value(message.get("id")?.clone()).ok()?;
+    Some(FromRunner::Result {
+        id,
+        outcome: Outcome::Error(RemoteError::new(ErrorKind::Invalid, err)),
+    })
+}
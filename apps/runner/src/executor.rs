@@ -0,0 +1,218 @@
+//! Executes [`Call`]s against the runner's own sandboxes. The sandbox root
+//! is shared with the API, so scopes resolve to the same directories the
+//! gateway would have used.
+
+use std::time::Duration;
+
+use sandbox::micro::{MicroExecuteRequest, MicroStartRequest, SandboxMicro};
+use sandbox::run::{RunRequest, SandboxRun};
+use sandbox::{AgentDispatcher, SandboxError};
+use serde_json::{json, Value};
+
+use crate::protocol::{
+    process_result, Call, Capabilities, Capability, ErrorKind, Outcome, RemoteError,
+};
+
+pub struct Executor {
+    run: Option<SandboxRun>,
+    micro: Option<SandboxMicro>,
+    agents: Option<AgentDispatcher>,
+    slots: usize,
+}
+
+impl Executor {
+    /// A runner offering whichever of the engines are given.
+    pub fn new(
+        run: Option<SandboxRun>,
+        micro: Option<SandboxMicro>,
+        agents: Option<AgentDispatcher>,
+        slots: usize,
+    ) -> Self {
+        Self {
+            run,
+            micro,
+            agents,
+            slots: slots.max(1),
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        let mut engines = Vec::new();
+        let mut programs = Vec::new();
+        let mut images = Vec::new();
+        if let Some(run) = &self.run {
+            engines.push(Capability::Run);
+            programs = run.config().allowed_programs().cloned().collect();
+            programs.sort();
+        }
+        if let Some(micro) = &self.micro {
+            engines.push(Capability::Micro);
+            images = micro
+                .config()
+                .images()
+                .map(|image| image.name().to_string())
+                .collect();
+        }
+        if self.agents.is_some() {
+            engines.push(Capability::Agent);
+        }
+        Capabilities {
+            engines,
+            programs,
+            images,
+            slots: self.slots,
+        }
+    }
+
+    pub async fn execute(&self, call: Call) -> Outcome {
+        match self.dispatch(call).await {
+            Ok(value) => Outcome::Ok(value),
+            Err(err) => Outcome::Error(err),
+        }
+    }
+
+    async fn dispatch(&self, call: Call) -> Result<Value, RemoteError> {
+        match call {
+            Call::RunExec { scope, request } => {
+                let run = self.run()?.scoped(scope).map_err(failed)?;
+                let request = RunRequest::try_from(request)
+                    .map_err(|err| RemoteError::new(ErrorKind::Invalid, err))?;
+                let output = run.execute(request).await.map_err(failed)?;
+                Ok(process_result(
+                    output.exit_code,
+                    &output.stdout,
+                    &output.stderr,
+                    output.duration,
+                ))
+            }
+            Call::MicroStart {
+                scope,
+                image,
+                init_script,
+            } => {
+                let instance = self
+                    .micro()?
+                    .start(MicroStartRequest {
+                        image,
+                        init_script,
+                        scope: Some(scope),
+                    })
+                    .await
+                    .map_err(failed)?;
+                Ok(json!({
+                    "vm_id": instance.id().to_string(),
+                    "image": instance.image().to_string(),
+                    "working_dir": instance.workdir().display().to_string(),
+                }))
+            }
+            Call::MicroExecute {
+                scope,
+                vm_id,
+                code,
+                timeout_ms,
+            } => {
+                let output = self
+                    .micro()?
+                    .execute(MicroExecuteRequest {
+                        vm_id,
+                        code,
+                        timeout: timeout_ms.map(Duration::from_millis),
+                        scope: Some(scope),
+                    })
+                    .await
+                    .map_err(failed)?;
+                Ok(process_result(
+                    output.exit_code,
+                    &output.stdout,
+                    &output.stderr,
+                    output.duration,
+                ))
+            }
+            Call::MicroStop { scope, vm_id } => {
+                self.micro()?
+                    .stop_in(vm_id, Some(&scope))
+                    .await
+                    .map_err(failed)?;
+                Ok(json!({ "status": "ok" }))
+            }
+            Call::MicroCleanup { scope } => {
+                let stopped = self.micro()?.stop_scope(&scope).await.map_err(failed)?;
+                Ok(json!({ "stopped": stopped }))
+            }
+            Call::AgentDispatch { request } => {
+                let submission = self.agents()?.dispatch(*request).map_err(failed)?;
+                Ok(json!({
+                    "task_id": submission.id.to_string(),
+                    "status": submission.status,
+                }))
+            }
+            Call::AgentStatus { task_id } => {
+                let snapshot = self.agents()?.status(&task_id).ok_or_else(|| {
+                    RemoteError::new(
+                        ErrorKind::NotFound,
+                        format!("agent task '{task_id}' not found"),
+                    )
+                })?;
+                Ok(json!(snapshot))
+            }
+            Call::AgentCancel { task_id } => {
+                let snapshot = self.agents()?.cancel(&task_id).map_err(failed)?;
+                Ok(json!(snapshot))
+            }
+            Call::AgentRespond { task_id, answer } => {
+                let snapshot = self.agents()?.respond(&task_id, answer).map_err(failed)?;
+                Ok(json!(snapshot))
+            }
+        }
+    }
+
+    fn run(&self) -> Result<&SandboxRun, RemoteError> {
+        self.run.as_ref().ok_or_else(|| unsupported("run"))
+    }
+
+    fn micro(&self) -> Result<&SandboxMicro, RemoteError> {
+        self.micro.as_ref().ok_or_else(|| unsupported("micro"))
+    }
+
+    fn agents(&self) -> Result<&AgentDispatcher, RemoteError> {
+        self.agents.as_ref().ok_or_else(|| unsupported("agent"))
+    }
+
+    /// Stops the runner's VMs and agent tasks before it exits.
+    pub async fn shutdown(&self) {
+        if let Some(agents) = &self.agents {
+            agents.cancel_all();
+        }
+        if let Some(micro) = &self.micro {
+            let _ = micro.shutdown().await;
+        }
+    }
+}
+
+fn unsupported(engine: &str) -> RemoteError {
+    RemoteError::new(
+        ErrorKind::Invalid,
+        format!("runner does not offer the {engine} engine"),
+    )
+}
+
+fn failed(err: SandboxError) -> RemoteError {
+    let kind = match &err {
+        SandboxError::MicroVmNotFound(_)
+        | SandboxError::RunSessionNotFound(_)
+        | SandboxError::AgentTaskNotFound(_) => ErrorKind::NotFound,
+        SandboxError::RateLimited { retry_after } => {
+            return RemoteError {
+                kind: ErrorKind::RateLimited,
+                detail: err.to_string(),
+                retry_after_ms: Some(retry_after.as_millis() as u64),
+            };
+        }
+        _ => ErrorKind::Failed,
+    };
+    RemoteError::new(kind, err)
+}
@@ -0,0 +1,13 @@
+//! Remote runners for the API gateway. A runner connects to the API's
+//! `/runners/ws`, advertises which engines, programs and micro images it
+//! offers and then executes `run.exec`, `micro.*` and agent calls the
+//! gateway hands it, so execution scales with the number of runners
+//! instead of the gateway host. [`protocol`] is shared with the API; the
+//! `runner` binary wires an [`Executor`] to a [`connection`].
+
+pub mod connection;
+pub mod executor;
+pub mod protocol;
+
+pub use connection::ConnectOptions;
+pub use executor::Executor;
@@ -0,0 +1,166 @@
+//! `runner`: executes run, micro and agent calls for an API gateway. Connects
+//! to `RUNNER_API_URL` (the API's `ws://…/runners/ws`) with `RUNNER_TOKEN`
+//! and offers the engines named in `RUNNER_ENGINES` (default `run,micro`).
+//! `SANDBOX_ROOT` must be the directory the API uses as its sandbox root;
+//! the `SANDBOX_RUN_*`, `SANDBOX_MICRO_*` and `AGENT_*` variables have the
+//! API's meaning and defaults.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use runner::{connection, ConnectOptions, Executor};
+use sandbox::micro::{MicroConfig, MicroImage, MicroRepl, SandboxMicro};
+use sandbox::run::{RunConfig, SandboxRun};
+use sandbox::{AgentDispatcher, AgentDispatcherConfig, SandboxConfig, SandboxFs};
+use tracing::info;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
+    let options = ConnectOptions {
+        url: std::env::var("RUNNER_API_URL").context("RUNNER_API_URL is required")?,
+        token: std::env::var("RUNNER_TOKEN").context("RUNNER_TOKEN is required")?,
+        name: std::env::var("RUNNER_NAME")
+            .or_else(|_| std::env::var("HOSTNAME"))
+            .unwrap_or_else(|_| "runner".to_string()),
+    };
+    let root = sandbox_root(&var("SANDBOX_ROOT", "./data/sandbox"))?;
+    let engines = list(&var("RUNNER_ENGINES", "run,micro"));
+    let offers = |engine: &str| engines.iter().any(|name| name == engine);
+
+    let run = offers("run").then(|| run_sandbox(&root)).transpose()?;
+    let micro = offers("micro").then(|| micro_sandbox(&root)).transpose()?;
+    let agents = offers("agent")
+        .then(|| agent_dispatcher(&root))
+        .transpose()?;
+    let executor = Arc::new(Executor::new(run, micro, agents, parse("RUNNER_SLOTS", 4)?));
+    info!(
+        name = %options.name,
+        engines = ?executor.capabilities().engines,
+        slots = executor.slots(),
+        "runner starting"
+    );
+
+    tokio::select! {
+        _ = connection::run(options, executor.clone()) => {}
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    executor.shutdown().await;
+    Ok(())
+}
+
+fn run_sandbox(root: &Path) -> anyhow::Result<SandboxRun> {
+    let fixed_env = vec![
+        ("PATH".to_string(), var("SANDBOX_RUN_PATH", "/usr/bin:/bin")),
+        ("HOME".to_string(), root.to_string_lossy().to_string()),
+    ];
+    let config = RunConfig::new(
+        root,
+        list(&var("SANDBOX_RUN_ALLOWED", "/bin/sh,/usr/bin/env")),
+        list(&var("SANDBOX_RUN_ENV_ALLOW", "PATH")),
+        fixed_env,
+        Duration::from_millis(parse("SANDBOX_RUN_DEFAULT_TIMEOUT_MS", 10_000)?),
+        Duration::from_millis(parse("SANDBOX_RUN_MAX_TIMEOUT_MS", 30_000)?),
+        parse("SANDBOX_RUN_MAX_OUTPUT_BYTES", 512 * 1024)?,
+    )?;
+    Ok(SandboxRun::new(config))
+}
+
+fn micro_sandbox(root: &Path) -> anyhow::Result<SandboxMicro> {
+    let images = vec![
+        MicroImage::new(
+            "python",
+            var("SANDBOX_MICRO_PYTHON", "python3"),
+            vec!["-u".to_string()],
+            "py",
+            vec![("PYTHONUNBUFFERED".to_string(), "1".to_string())],
+        )?
+        .with_repl(Some(MicroRepl::Python)),
+        MicroImage::new(
+            "node",
+            var("SANDBOX_MICRO_NODE", "node"),
+            Vec::new(),
+            "js",
+            Vec::new(),
+        )?
+        .with_repl(Some(MicroRepl::Node)),
+    ];
+    let base_env = vec![
+        (
+            "PATH".to_string(),
+            var("SANDBOX_MICRO_PATH", "/usr/bin:/bin"),
+        ),
+        ("LANG".to_string(), "C".to_string()),
+        ("LC_ALL".to_string(), "C".to_string()),
+        ("TERM".to_string(), "dumb".to_string()),
+    ];
+    let config = MicroConfig::new(
+        root,
+        images,
+        Duration::from_millis(parse("SANDBOX_MICRO_DEFAULT_TIMEOUT_MS", 5_000)?),
+        Duration::from_millis(parse("SANDBOX_MICRO_MAX_TIMEOUT_MS", 30_000)?),
+        parse("SANDBOX_MICRO_MAX_OUTPUT_BYTES", 256 * 1024)?,
+        base_env,
+    )?;
+    Ok(SandboxMicro::new(config))
+}
+
+fn agent_dispatcher(root: &Path) -> anyhow::Result<AgentDispatcher> {
+    let config = AgentDispatcherConfig::new(
+        var("AGENT_LLM_ENDPOINT", "http://localhost:6988"),
+        var("AGENT_DEFAULT_MODEL", "nous-hermes-2-3b.Q4"),
+    )
+    .with_timeout(Duration::from_millis(parse(
+        "AGENT_LLM_TIMEOUT_MS",
+        30_000,
+    )?))
+    .with_api_key(std::env::var("AGENT_LLM_API_KEY").ok())
+    .with_native_tools(parse("AGENT_NATIVE_TOOLS", false)?)
+    .with_max_concurrency(parse("AGENT_MAX_CONCURRENCY", 8)?)
+    .with_max_subtasks(parse("AGENT_MAX_SUBTASKS", 16)?);
+    let workspace = SandboxFs::new(SandboxConfig::new(
+        root,
+        parse("SANDBOX_MAX_FILE_SIZE", 512 * 1024)?,
+    )?);
+    let dispatcher =
+        AgentDispatcher::new(config).map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    Ok(dispatcher.with_workspace(Arc::new(workspace)))
+}
+
+fn sandbox_root(raw: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(raw);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+fn var(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| default.to_string())
+}
+
+fn parse<T: FromStr>(key: &str, default: T) -> anyhow::Result<T> {
+    match std::env::var(key) {
+        Ok(raw) => raw
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{key} has an invalid value '{raw}'")),
+        Err(_) => Ok(default),
+    }
+}
+
+fn list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
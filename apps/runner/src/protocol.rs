@@ -0,0 +1,327 @@
+//! Messages between the API and its runners, sent as JSON text frames on
+//! the `/runners/ws` WebSocket. A runner opens with
+//! [`FromRunner::Register`]; the API answers [`ToRunner::Registered`] and
+//! from then on sends [`ToRunner::Call`]s, each answered by a
+//! [`FromRunner::Result`] with the same id, in any order. Runners send a
+//! heartbeat every `heartbeat_secs`, and the API drops a runner that misses
+//! three.
+//!
+//! Runners mount the same sandbox root as the API, so calls carry their
+//! scope as a directory relative to it, and results have the shape of the
+//! JSON-RPC method of the same name.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sandbox::run::RunRequest;
+use sandbox::AgentDispatchRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// Bumped whenever a message changes incompatibly; the API rejects runners
+/// speaking another version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Run,
+    Micro,
+    Agent,
+}
+
+/// What a runner advertises when it registers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub engines: Vec<Capability>,
+    /// The runner's `SANDBOX_RUN_ALLOWED`.
+    #[serde(default)]
+    pub programs: Vec<String>,
+    /// Names of the runner's micro images.
+    #[serde(default)]
+    pub images: Vec<String>,
+    /// Calls the runner executes at the same time.
+    pub slots: usize,
+}
+
+impl Capabilities {
+    /// Whether the runner can take `call`. Follow-up calls on a micro VM or
+    /// agent task go to the runner that started it, so only starting calls
+    /// are matched against programs and images.
+    pub fn accepts(&self, call: &Call) -> bool {
+        let has = |capability| self.engines.contains(&capability);
+        match call {
+            Call::RunExec { request, .. } => {
+                has(Capability::Run) && self.programs.contains(&request.program)
+            }
+            Call::MicroStart { image, .. } => has(Capability::Micro) && self.images.contains(image),
+            Call::MicroExecute { .. } | Call::MicroStop { .. } | Call::MicroCleanup { .. } => {
+                has(Capability::Micro)
+            }
+            Call::AgentDispatch { .. }
+            | Call::AgentStatus { .. }
+            | Call::AgentCancel { .. }
+            | Call::AgentRespond { .. } => has(Capability::Agent),
+        }
+    }
+}
+
+/// A process to start, [`RunRequest`] with base64 stdin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteRun {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    #[serde(default)]
+    pub stdin: Option<String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+impl From<&RunRequest> for RemoteRun {
+    fn from(request: &RunRequest) -> Self {
+        Self {
+            program: request.program.clone(),
+            args: request.args.clone(),
+            env: request.env.clone(),
+            stdin: request.stdin.as_ref().map(|bytes| BASE64.encode(bytes)),
+            working_dir: request.working_dir.clone(),
+            timeout_ms: request.timeout.map(|timeout| timeout.as_millis() as u64),
+        }
+    }
+}
+
+impl TryFrom<RemoteRun> for RunRequest {
+    type Error = base64::DecodeError;
+
+    fn try_from(remote: RemoteRun) -> Result<Self, Self::Error> {
+        let mut request = RunRequest::new(remote.program)
+            .with_args(remote.args)
+            .with_env(remote.env);
+        if let Some(stdin) = remote.stdin {
+            request = request.with_stdin(BASE64.decode(stdin)?);
+        }
+        if let Some(dir) = remote.working_dir {
+            request = request.with_working_dir(dir);
+        }
+        if let Some(timeout_ms) = remote.timeout_ms {
+            request = request.with_timeout(Duration::from_millis(timeout_ms));
+        }
+        Ok(request)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method")]
+pub enum Call {
+    #[serde(rename = "run.exec")]
+    RunExec { scope: PathBuf, request: RemoteRun },
+    #[serde(rename = "micro.start")]
+    MicroStart {
+        scope: PathBuf,
+        image: String,
+        init_script: Option<String>,
+    },
+    #[serde(rename = "micro.execute")]
+    MicroExecute {
+        scope: PathBuf,
+        vm_id: Uuid,
+        code: String,
+        timeout_ms: Option<u64>,
+    },
+    #[serde(rename = "micro.stop")]
+    MicroStop { scope: PathBuf, vm_id: Uuid },
+    /// Stops every VM below `scope` before the API removes the directory;
+    /// answers `{"stopped": <count>}`.
+    #[serde(rename = "micro.cleanup")]
+    MicroCleanup { scope: PathBuf },
+    #[serde(rename = "agent.dispatch")]
+    AgentDispatch { request: Box<AgentDispatchRequest> },
+    #[serde(rename = "agent.status")]
+    AgentStatus { task_id: Uuid },
+    #[serde(rename = "agent.cancel")]
+    AgentCancel { task_id: Uuid },
+    #[serde(rename = "agent.respond")]
+    AgentRespond { task_id: Uuid, answer: String },
+}
+
+impl Call {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Call::RunExec { .. } => "run.exec",
+            Call::MicroStart { .. } => "micro.start",
+            Call::MicroExecute { .. } => "micro.execute",
+            Call::MicroStop { .. } => "micro.stop",
+            Call::MicroCleanup { .. } => "micro.cleanup",
+            Call::AgentDispatch { .. } => "agent.dispatch",
+            Call::AgentStatus { .. } => "agent.status",
+            Call::AgentCancel { .. } => "agent.cancel",
+            Call::AgentRespond { .. } => "agent.respond",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The sandbox refused or failed the call.
+    Failed,
+    /// The VM or agent task is unknown to the runner.
+    NotFound,
+    /// The runner's agent dispatcher is rate limited.
+    RateLimited,
+    /// The call could not be decoded.
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub kind: ErrorKind,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl RemoteError {
+    pub fn new(kind: ErrorKind, detail: impl ToString) -> Self {
+        Self {
+            kind,
+            detail: detail.to_string(),
+            retry_after_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok(Value),
+    Error(RemoteError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FromRunner {
+    Register {
+        version: u32,
+        name: String,
+        capabilities: Capabilities,
+    },
+    /// `active` is the number of calls the runner is executing.
+    Heartbeat {
+        active: usize,
+    },
+    Result {
+        id: Uuid,
+        outcome: Outcome,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToRunner {
+    Registered {
+        runner_id: Uuid,
+        heartbeat_secs: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+    Call {
+        id: Uuid,
+        call: Call,
+    },
+}
+
+/// The result of `run.exec` and `micro.execute`, wherever the process ran.
+pub fn process_result(exit_code: i32, stdout: &[u8], stderr: &[u8], duration: Duration) -> Value {
+    json!({
+        "exit_code": exit_code,
+        "stdout": BASE64.encode(stdout),
+        "stderr": BASE64.encode(stderr),
+        "duration_ms": duration.as_millis() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_round_trip_as_tagged_json() {
+        let run = RunRequest::new("/bin/sh")
+            .with_args(vec!["-c".to_string(), "cat".to_string()])
+            .with_stdin(b"hello".to_vec())
+            .with_timeout(Duration::from_millis(1500));
+        let message = ToRunner::Call {
+            id: Uuid::new_v4(),
+            call: Call::RunExec {
+                scope: PathBuf::from("tenants/1/users/7"),
+                request: RemoteRun::from(&run),
+            },
+        };
+        let encoded = serde_json::to_value(&message).unwrap();
+        assert_eq!(encoded["type"], "call");
+        assert_eq!(encoded["call"]["method"], "run.exec");
+        assert_eq!(encoded["call"]["request"]["stdin"], "aGVsbG8=");
+
+        let ToRunner::Call { call, .. } = serde_json::from_value(encoded).unwrap() else {
+            panic!("expected a call");
+        };
+        let Call::RunExec { scope, request } = call else {
+            panic!("expected run.exec");
+        };
+        assert_eq!(scope, PathBuf::from("tenants/1/users/7"));
+        let decoded = RunRequest::try_from(request).unwrap();
+        assert_eq!(decoded.stdin.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(decoded.timeout, Some(Duration::from_millis(1500)));
+
+        let result: FromRunner = serde_json::from_value(json!({
+            "type": "result",
+            "id": Uuid::nil(),
+            "outcome": { "error": { "kind": "not_found", "detail": "no such vm" } },
+        }))
+        .unwrap();
+        let FromRunner::Result {
+            outcome: Outcome::Error(err),
+            ..
+        } = result
+        else {
+            panic!("expected an error result");
+        };
+        assert_eq!(err.kind, ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn starting_calls_match_programs_and_images() {
+        let capabilities = Capabilities {
+            engines: vec![Capability::Run, Capability::Micro],
+            programs: vec!["/bin/sh".to_string()],
+            images: vec!["python".to_string()],
+            slots: 2,
+        };
+        let run = |program: &str| Call::RunExec {
+            scope: PathBuf::new(),
+            request: RemoteRun::from(&RunRequest::new(program)),
+        };
+        let start = |image: &str| Call::MicroStart {
+            scope: PathBuf::new(),
+            image: image.to_string(),
+            init_script: None,
+        };
+        assert!(capabilities.accepts(&run("/bin/sh")));
+        assert!(!capabilities.accepts(&run("/usr/bin/env")));
+        assert!(capabilities.accepts(&start("python")));
+        assert!(!capabilities.accepts(&start("node")));
+        assert!(!capabilities.accepts(&Call::AgentStatus {
+            task_id: Uuid::nil()
+        }));
+    }
+}
@@ -0,0 +1,119 @@
+//! API keys are stored as their SHA-256 hash and can be limited by scopes:
+//! permission scopes (`fs:read`, ...) name what the key may do, project
+//! scopes (`project:<id>`) the only projects it may do it in. The same
+//! scopes limit service tokens (their `scope` claim).
+
+use std::collections::HashSet;
+
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::Permission;
+
+/// Scope names accepted besides `project:<id>` and the permission each one
+/// stands for.
+pub const SCOPES: [(&str, Permission); 6] = [
+    ("fs:read", Permission::FsRead),
+    ("fs:write", Permission::FsWrite),
+    ("run:exec", Permission::Execute),
+    ("agent:read", Permission::AgentView),
+    ("agent:dispatch", Permission::AgentControl),
+    ("llm:use", Permission::LlmUse),
+];
+
+/// What `api_keys.api_key_hash` (and `service_clients.secret_hash`) holds.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Whether `scope` is one of [`SCOPES`] or names a project by id.
+pub fn is_valid_scope(scope: &str) -> bool {
+    match scope.strip_prefix("project:") {
+        Some(id) => Uuid::parse_str(id).is_ok(),
+        None => SCOPES.iter().any(|(name, _)| *name == scope),
+    }
+}
+
+/// Limits of a scoped API key or service token; a key without scopes of one
+/// kind is not limited in that respect. Administrative permissions cannot be
+/// scoped, so keys for admin work stay unscoped.
+#[derive(Debug, Clone, Default)]
+pub struct KeyScope {
+    permissions: Option<HashSet<Permission>>,
+    projects: Option<HashSet<Uuid>>,
+}
+
+impl KeyScope {
+    /// `None` for an unscoped key. Unknown or malformed scopes grant nothing
+    /// but still count as a limit of their kind.
+    pub fn parse(scopes: &[String]) -> Option<Self> {
+        if scopes.is_empty() {
+            return None;
+        }
+        let mut scope = Self::default();
+        for value in scopes {
+            let parsed = match value.strip_prefix("project:") {
+                Some(id) => {
+                    let projects = scope.projects.get_or_insert_with(HashSet::new);
+                    Uuid::parse_str(id).map(|id| projects.insert(id)).is_ok()
+                }
+                None => {
+                    let permissions = scope.permissions.get_or_insert_with(HashSet::new);
+                    SCOPES
+                        .iter()
+                        .find(|(name, _)| name == value)
+                        .map(|(_, permission)| permissions.insert(*permission))
+                        .is_some()
+                }
+            };
+            if !parsed {
+                warn!(scope = %value, "ignoring unknown api key scope");
+            }
+        }
+        Some(scope)
+    }
+
+    pub fn allows(&self, permission: Permission, project_id: Option<&Uuid>) -> bool {
+        self.permissions
+            .as_ref()
+            .is_none_or(|permissions| permissions.contains(&permission))
+            && self
+                .projects
+                .as_ref()
+                .is_none_or(|projects| project_id.is_some_and(|id| projects.contains(id)))
+    }
+
+    /// Whether the key may touch `project_id` at all.
+    pub fn allows_project(&self, project_id: &Uuid) -> bool {
+        self.projects
+            .as_ref()
+            .is_none_or(|projects| projects.contains(project_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_keys_as_hex_sha256() {
+        assert_eq!(
+            hash_api_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn validates_scopes() {
+        assert!(is_valid_scope("fs:read"));
+        assert!(is_valid_scope(&format!("project:{}", Uuid::new_v4())));
+        assert!(!is_valid_scope("project:nope"));
+        assert!(!is_valid_scope("user:admin"));
+        for (name, permission) in SCOPES {
+            let scope = KeyScope::parse(&[name.to_string()]).unwrap();
+            assert!(scope.allows(permission, None));
+            assert!(!scope.allows(Permission::SystemAdmin, None));
+        }
+    }
+}
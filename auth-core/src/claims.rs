@@ -0,0 +1,74 @@
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Claims of the access tokens the auth service issues.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// The user's id.
+    pub sub: i32,
+    pub username: String,
+    pub role: String,
+    /// The user's tenant. Tokens issued before tenants existed have none and
+    /// stand for the tenant recorded on the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<i32>,
+    pub exp: usize,
+    pub iat: usize,
+    pub iss: String,
+    /// Lets a single token be revoked.
+    pub jti: String,
+    /// Space-separated scopes of a service token (client-credentials grant);
+    /// user tokens have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl Claims {
+    /// A user token valid for `ttl` from now.
+    pub fn new(
+        user_id: i32,
+        username: &str,
+        role: &str,
+        tenant_id: i32,
+        issuer: &str,
+        ttl: Duration,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            sub: user_id,
+            username: username.to_string(),
+            role: role.to_string(),
+            tenant: Some(tenant_id),
+            exp: (now + ttl).timestamp() as usize,
+            iat: now.timestamp() as usize,
+            iss: issuer.to_string(),
+            jti: Uuid::new_v4().to_string(),
+            scope: None,
+        }
+    }
+
+    /// The scopes of a service token, `None` for a user token.
+    pub fn scopes(&self) -> Option<Vec<String>> {
+        self.scope
+            .as_deref()
+            .map(|scope| scope.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_without_a_tenant_still_decode() {
+        let claims = Claims::new(7, "dev", "developer", 3, "iss", Duration::minutes(5));
+        let mut value = serde_json::to_value(&claims).unwrap();
+        assert_eq!(value["tenant"], 3);
+
+        value.as_object_mut().unwrap().remove("tenant");
+        let legacy: Claims = serde_json::from_value(value).unwrap();
+        assert_eq!(legacy.tenant, None);
+        assert_eq!(legacy.sub, 7);
+    }
+}
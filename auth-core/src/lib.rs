@@ -0,0 +1,17 @@
+//! Token and permission model shared by the API gateway and the auth
+//! service, so the two cannot drift apart: the JWT [`Claims`] the auth
+//! service issues, how they are verified against the HS256 secret or the
+//! published JWKS, [`Role`]s, [`Permission`]s and what API key scopes map
+//! onto, and how API keys are hashed for storage.
+
+pub mod api_key;
+pub mod claims;
+pub mod permission;
+pub mod role;
+pub mod verify;
+
+pub use api_key::{hash_api_key, KeyScope};
+pub use claims::Claims;
+pub use permission::Permission;
+pub use role::Role;
+pub use verify::{DecodingKeys, KeyId, TokenVerifier};
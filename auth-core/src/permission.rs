@@ -0,0 +1,53 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    FsRead,
+    FsWrite,
+    Execute,
+    AgentView,
+    AgentControl,
+    LlmUse,
+    LlmAdmin,
+    AgentAdmin,
+    AuditView,
+    UserAdmin,
+    SystemAdmin,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 11] = [
+        Permission::FsRead,
+        Permission::FsWrite,
+        Permission::Execute,
+        Permission::AgentView,
+        Permission::AgentControl,
+        Permission::LlmUse,
+        Permission::LlmAdmin,
+        Permission::AgentAdmin,
+        Permission::AuditView,
+        Permission::UserAdmin,
+        Permission::SystemAdmin,
+    ];
+
+    /// The name stored in `role_permissions` and `permission_grants`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::FsRead => "fs.read",
+            Permission::FsWrite => "fs.write",
+            Permission::Execute => "execute",
+            Permission::AgentView => "agent.view",
+            Permission::AgentControl => "agent.control",
+            Permission::LlmUse => "llm.use",
+            Permission::LlmAdmin => "llm.admin",
+            Permission::AgentAdmin => "agent.admin",
+            Permission::AuditView => "audit.view",
+            Permission::UserAdmin => "user.admin",
+            Permission::SystemAdmin => "system.admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == value)
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+/// A user's role. What each role may do lives in `role_permissions`; only
+/// `admin` keeps special meaning in code (cross-user access, no token floor).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Developer,
+    Viewer,
+    /// Created through `admin.roles.set`.
+    Custom(Arc<str>),
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "admin" => Role::Admin,
+            "developer" => Role::Developer,
+            "viewer" => Role::Viewer,
+            other => Role::Custom(other.into()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Role::Admin => "admin",
+            Role::Developer => "developer",
+            Role::Viewer => "viewer",
+            Role::Custom(name) => name,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Role::Admin)
+    }
+}
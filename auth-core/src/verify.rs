@@ -0,0 +1,248 @@
+//! Verification of access tokens. HS256 tokens are checked against the
+//! secret shared with the auth service, RS256 tokens against the public key
+//! their `kid` names in the auth service's JWKS. Either way `exp`, `iat` and
+//! `iss` are required and the issuer has to match.
+
+use std::collections::HashMap;
+
+use jsonwebtoken::errors::Error;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use tracing::warn;
+
+use crate::Claims;
+
+/// The key a token says it is signed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyId {
+    /// The shared HS256 secret.
+    Hmac,
+    /// The RS256 key with this `kid`.
+    Rsa(String),
+}
+
+impl KeyId {
+    /// `None` for a malformed header, another algorithm or an RS256 token
+    /// without a `kid`.
+    pub fn of(token: &str) -> Option<Self> {
+        let header = decode_header(token).ok()?;
+        match header.alg {
+            Algorithm::HS256 => Some(Self::Hmac),
+            Algorithm::RS256 => header.kid.map(Self::Rsa),
+            _ => None,
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Self::Hmac => Algorithm::HS256,
+            Self::Rsa(_) => Algorithm::RS256,
+        }
+    }
+}
+
+/// Keys tokens can be verified with.
+#[derive(Clone, Default)]
+pub struct DecodingKeys {
+    /// HS256 secrets, newest first.
+    hmac: Vec<DecodingKey>,
+    rsa: HashMap<String, DecodingKey>,
+}
+
+impl DecodingKeys {
+    /// The RSA keys of a JWKS; keys without a `kid` or that cannot be used
+    /// are skipped.
+    pub fn from_jwks(set: &JwkSet) -> Self {
+        let mut rsa = HashMap::new();
+        for jwk in &set.keys {
+            let Some(kid) = &jwk.common.key_id else {
+                continue;
+            };
+            match DecodingKey::from_jwk(jwk) {
+                Ok(key) => {
+                    rsa.insert(kid.clone(), key);
+                }
+                Err(err) => warn!(kid = %kid, error = %err, "ignoring unusable jwk"),
+            }
+        }
+        Self {
+            hmac: Vec::new(),
+            rsa,
+        }
+    }
+
+    /// Also accept HS256 tokens signed with `secret`.
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.hmac = vec![DecodingKey::from_secret(secret.as_bytes())];
+        self
+    }
+
+    /// Makes `secret` the HS256 secret while still accepting the one it
+    /// replaces, so tokens signed before a rotation stay valid until the
+    /// next one.
+    pub fn rotate_secret(&mut self, secret: &str) {
+        self.hmac
+            .insert(0, DecodingKey::from_secret(secret.as_bytes()));
+        self.hmac.truncate(2);
+    }
+
+    /// The newest key `id` names.
+    pub fn get(&self, id: &KeyId) -> Option<&DecodingKey> {
+        self.candidates(id).next()
+    }
+
+    /// Every key a token signed as `id` may verify with, newest first.
+    pub fn candidates(&self, id: &KeyId) -> std::slice::Iter<'_, DecodingKey> {
+        match id {
+            KeyId::Hmac => self.hmac.iter(),
+            KeyId::Rsa(kid) => self
+                .rsa
+                .get(kid)
+                .map(std::slice::from_ref)
+                .unwrap_or_default()
+                .iter(),
+        }
+    }
+}
+
+/// Checks a token's signature and claims for one issuer.
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    validation: Validation,
+}
+
+impl TokenVerifier {
+    pub fn new(issuer: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        // `sub` is numeric, which the spec-claim check rejects as missing;
+        // `Claims` requires it anyway.
+        validation.set_required_spec_claims(&["exp", "iat", "iss"]);
+        validation.set_issuer(&[issuer]);
+        Self { validation }
+    }
+
+    /// `key` has to be the key `id` names, as looked up by the caller.
+    pub fn verify(&self, token: &str, id: &KeyId, key: &DecodingKey) -> Result<Claims, Error> {
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![id.algorithm()];
+        decode::<Claims>(token, key, &validation).map(|data| data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use chrono::Duration;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    fn sign(claims: &Claims) -> String {
+        encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(b"s3cret"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn verifies_hmac_tokens_of_the_issuer() {
+        let keys = DecodingKeys::default().with_secret("s3cret");
+        let verifier = TokenVerifier::new("cyber-dev-studio");
+        let claims = Claims::new(
+            7,
+            "dev",
+            "developer",
+            1,
+            "cyber-dev-studio",
+            Duration::minutes(5),
+        );
+        let token = sign(&claims);
+        let id = KeyId::of(&token).unwrap();
+        assert_eq!(id, KeyId::Hmac);
+        let verified = verifier
+            .verify(&token, &id, keys.get(&id).unwrap())
+            .unwrap();
+        assert_eq!((verified.sub, verified.jti), (7, claims.jti));
+        assert_eq!(verified.tenant, Some(1));
+
+        let foreign = Claims::new(7, "dev", "developer", 1, "elsewhere", Duration::minutes(5));
+        let token = sign(&foreign);
+        assert!(verifier
+            .verify(&token, &id, keys.get(&id).unwrap())
+            .is_err());
+
+        let expired = Claims::new(
+            7,
+            "dev",
+            "developer",
+            1,
+            "cyber-dev-studio",
+            Duration::hours(-1),
+        );
+        let token = sign(&expired);
+        assert!(verifier
+            .verify(&token, &id, keys.get(&id).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_secret() {
+        let mut keys = DecodingKeys::default().with_secret("s3cret");
+        let verifier = TokenVerifier::new("cyber-dev-studio");
+        let claims = Claims::new(
+            7,
+            "dev",
+            "developer",
+            1,
+            "cyber-dev-studio",
+            Duration::minutes(5),
+        );
+        let token = sign(&claims);
+        let verifies = |keys: &DecodingKeys| {
+            keys.candidates(&KeyId::Hmac)
+                .any(|key| verifier.verify(&token, &KeyId::Hmac, key).is_ok())
+        };
+
+        keys.rotate_secret("next");
+        assert_eq!(keys.candidates(&KeyId::Hmac).count(), 2);
+        assert!(verifier
+            .verify(&token, &KeyId::Hmac, keys.get(&KeyId::Hmac).unwrap())
+            .is_err());
+        assert!(verifies(&keys));
+
+        keys.rotate_secret("after");
+        assert_eq!(keys.candidates(&KeyId::Hmac).count(), 2);
+        assert!(!verifies(&keys));
+    }
+
+    #[test]
+    fn identifies_keys_by_header() {
+        // Only the header is looked at.
+        let token = |header: &str| format!("{}.e30.sig", URL_SAFE_NO_PAD.encode(header));
+        assert_eq!(KeyId::of(&token(r#"{"alg":"HS256"}"#)), Some(KeyId::Hmac));
+        assert_eq!(
+            KeyId::of(&token(r#"{"alg":"RS256","kid":"k1"}"#)),
+            Some(KeyId::Rsa("k1".to_string()))
+        );
+        assert_eq!(KeyId::of(&token(r#"{"alg":"RS256"}"#)), None);
+        assert_eq!(KeyId::of(&token(r#"{"alg":"HS512"}"#)), None);
+        assert_eq!(KeyId::of("not a token"), None);
+    }
+
+    #[test]
+    fn loads_rsa_keys_with_a_kid_from_jwks() {
+        let set: JwkSet = serde_json::from_value(json!({ "keys": [
+            { "kty": "RSA", "kid": "k1", "n": "AQAB", "e": "AQAB" },
+            { "kty": "RSA", "n": "AQAB", "e": "AQAB" },
+        ]}))
+        .unwrap();
+        let keys = DecodingKeys::from_jwks(&set);
+        assert!(keys.get(&KeyId::Rsa("k1".to_string())).is_some());
+        assert_eq!(keys.rsa.len(), 1);
+        assert!(keys.get(&KeyId::Hmac).is_none());
+    }
+}
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS billing_ledger (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind VARCHAR(32) NOT NULL,
+    method VARCHAR(100) NOT NULL,
+    units BIGINT NOT NULL,
+    tokens BIGINT NOT NULL,
+    balance_after BIGINT NOT NULL,
+    metadata JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS billing_ledger_user_idx ON billing_ledger(user_id, id DESC);
+CREATE INDEX IF NOT EXISTS billing_ledger_created_idx ON billing_ledger(created_at);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    api_key_id UUID,
+    method TEXT NOT NULL,
+    params_digest VARCHAR(64),
+    outcome VARCHAR(16) NOT NULL CHECK (outcome IN ('ok', 'error')),
+    error_code BIGINT,
+    latency_ms BIGINT NOT NULL,
+    client_ip TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_idx ON audit_log(user_id, id DESC);
+CREATE INDEX IF NOT EXISTS audit_log_method_idx ON audit_log(method, id DESC);
+CREATE INDEX IF NOT EXISTS audit_log_created_idx ON audit_log(created_at);
@@ -0,0 +1,37 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Text view of a file for search. Binary, non-UTF-8 and oversized files
+-- are left out of the index.
+CREATE OR REPLACE FUNCTION project_file_text(content BYTEA)
+RETURNS TEXT AS $$
+BEGIN
+    IF octet_length(content) > 1048576 THEN
+        RETURN NULL;
+    END IF;
+    RETURN convert_from(content, 'UTF8');
+EXCEPTION WHEN others THEN
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+
+ALTER TABLE project_files
+    ADD COLUMN IF NOT EXISTS search_text TEXT;
+
+CREATE OR REPLACE FUNCTION update_project_file_search_text()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_text = project_file_text(NEW.content);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_project_files_search_text ON project_files;
+CREATE TRIGGER trg_project_files_search_text
+BEFORE INSERT OR UPDATE OF content ON project_files
+FOR EACH ROW
+EXECUTE FUNCTION update_project_file_search_text();
+
+UPDATE project_files SET search_text = project_file_text(content) WHERE search_text IS NULL;
+
+CREATE INDEX IF NOT EXISTS project_files_search_text_trgm_idx ON project_files USING GIN (search_text gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS project_files_path_trgm_idx ON project_files USING GIN (path gin_trgm_ops);
@@ -0,0 +1,4 @@
+-- Programs `project.run` may start inside the project. NULL inherits the
+-- global SANDBOX_RUN_ALLOWED list; a list narrows it further.
+ALTER TABLE projects
+    ADD COLUMN IF NOT EXISTS allowed_programs TEXT[];
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS workspace_sessions (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    label VARCHAR(128),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS workspace_sessions_user_idx ON workspace_sessions(user_id, expires_at);
+CREATE INDEX IF NOT EXISTS workspace_sessions_expiry_idx ON workspace_sessions(expires_at);
@@ -0,0 +1,3 @@
+-- Disabled accounts keep their data but can no longer log in or call the API.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS disabled_at TIMESTAMPTZ;
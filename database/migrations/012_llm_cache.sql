@@ -0,0 +1,14 @@
+-- Second tier of the llm response cache, shared by all api instances. Keys are
+-- the hex SHA-256 of the method and its params.
+CREATE TABLE IF NOT EXISTS llm_cache (
+    key CHAR(64) PRIMARY KEY,
+    method VARCHAR(64) NOT NULL,
+    model TEXT NOT NULL,
+    response JSONB NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS llm_cache_expiry_idx ON llm_cache(expires_at);
+CREATE INDEX IF NOT EXISTS llm_cache_created_idx ON llm_cache(created_at);
@@ -0,0 +1,21 @@
+-- One row per llm inference call, whichever provider served it. Cache hits
+-- and failures are kept with zero tokens so call counts and error rates stay
+-- complete.
+CREATE TABLE IF NOT EXISTS llm_usage (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    api_key_id UUID REFERENCES api_keys(id) ON DELETE SET NULL,
+    method VARCHAR(64) NOT NULL,
+    model TEXT NOT NULL,
+    provider VARCHAR(32) NOT NULL,
+    prompt_tokens BIGINT NOT NULL DEFAULT 0,
+    completion_tokens BIGINT NOT NULL DEFAULT 0,
+    total_tokens BIGINT NOT NULL DEFAULT 0,
+    latency_ms BIGINT NOT NULL,
+    status VARCHAR(16) NOT NULL CHECK (status IN ('ok', 'cached', 'error')),
+    error_code INTEGER,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS llm_usage_user_idx ON llm_usage(user_id, created_at);
+CREATE INDEX IF NOT EXISTS llm_usage_created_idx ON llm_usage(created_at);
@@ -0,0 +1,61 @@
+-- Roles and their permissions move from code into the database. The three
+-- built-in roles are seeded with the matrix the API used to hard-code; custom
+-- roles and per-user grants (optionally limited to one project) are managed
+-- through `admin.roles.*` and `admin.grants.*`.
+CREATE TABLE IF NOT EXISTS roles (
+    name VARCHAR(32) PRIMARY KEY,
+    description TEXT,
+    builtin BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO roles (name, description, builtin) VALUES
+    ('admin', 'Full access, including user and model administration', TRUE),
+    ('developer', 'Read, write and execute in own projects; use llms and agents', TRUE),
+    ('viewer', 'Read-only access', TRUE)
+ON CONFLICT (name) DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role VARCHAR(32) NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+    permission VARCHAR(32) NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('admin', 'fs.read'),
+    ('admin', 'fs.write'),
+    ('admin', 'execute'),
+    ('admin', 'agent.view'),
+    ('admin', 'agent.control'),
+    ('admin', 'llm.use'),
+    ('admin', 'llm.admin'),
+    ('admin', 'agent.admin'),
+    ('admin', 'audit.view'),
+    ('admin', 'user.admin'),
+    ('developer', 'fs.read'),
+    ('developer', 'fs.write'),
+    ('developer', 'execute'),
+    ('developer', 'agent.view'),
+    ('developer', 'agent.control'),
+    ('developer', 'llm.use'),
+    ('viewer', 'fs.read'),
+    ('viewer', 'agent.view')
+ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS permission_grants (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permission VARCHAR(32) NOT NULL,
+    project_id UUID REFERENCES projects(id) ON DELETE CASCADE,
+    granted_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS permission_grants_unique_idx ON permission_grants(
+    user_id, permission, COALESCE(project_id, '00000000-0000-0000-0000-000000000000'::uuid)
+);
+
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_role_check;
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_role_fkey;
+ALTER TABLE users ADD CONSTRAINT users_role_fkey FOREIGN KEY (role) REFERENCES roles(name);
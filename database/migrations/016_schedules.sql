@@ -0,0 +1,37 @@
+-- Maintenance jobs run by the api's built-in scheduler. Rows are seeded by
+-- the api on startup; operators retime or pause a job by editing `cron` and
+-- `enabled` (admin.schedules.update). `run_requested_at` asks the next tick
+-- to run the job once regardless of its schedule.
+CREATE TABLE IF NOT EXISTS schedules (
+    name VARCHAR(64) PRIMARY KEY,
+    cron VARCHAR(128) NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    run_requested_at TIMESTAMPTZ,
+    last_started_at TIMESTAMPTZ,
+    last_finished_at TIMESTAMPTZ,
+    last_status VARCHAR(16) CHECK (last_status IN ('ok', 'error')),
+    last_duration_ms BIGINT,
+    last_result JSONB,
+    last_error TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Daily rollup of llm_usage maintained by the usage_aggregation job, so
+-- reports over long ranges don't have to scan the per-call rows.
+CREATE TABLE IF NOT EXISTS llm_usage_daily (
+    day DATE NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    provider VARCHAR(32) NOT NULL,
+    calls BIGINT NOT NULL,
+    errors BIGINT NOT NULL,
+    cached BIGINT NOT NULL,
+    prompt_tokens BIGINT NOT NULL,
+    completion_tokens BIGINT NOT NULL,
+    total_tokens BIGINT NOT NULL,
+    PRIMARY KEY (day, user_id, model, provider)
+);
+
+INSERT INTO role_permissions (role, permission)
+VALUES ('admin', 'system.admin')
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,35 @@
+-- Durable background work. Workers on every api instance claim due rows with
+-- FOR UPDATE SKIP LOCKED and hold a lease (`locked_until`) that they renew
+-- while the job runs, so a crashed worker's job becomes claimable again.
+-- Failures are retried with backoff until `max_attempts`; after that the job
+-- is parked as 'dead' until it is retried by hand.
+CREATE TABLE IF NOT EXISTS jobs (
+    id BIGSERIAL PRIMARY KEY,
+    kind VARCHAR(64) NOT NULL,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    payload JSONB NOT NULL,
+    progress JSONB,
+    result JSONB,
+    status VARCHAR(16) NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'succeeded', 'dead', 'cancelled')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL CHECK (max_attempts > 0),
+    run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    locked_by TEXT,
+    locked_until TIMESTAMPTZ,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS jobs_due_idx ON jobs(run_at) WHERE status = 'queued';
+CREATE INDEX IF NOT EXISTS jobs_lease_idx ON jobs(locked_until) WHERE status = 'running';
+CREATE INDEX IF NOT EXISTS jobs_user_idx ON jobs(user_id, id DESC);
+CREATE INDEX IF NOT EXISTS jobs_finished_idx ON jobs(finished_at) WHERE finished_at IS NOT NULL;
+
+-- Webhook deliveries are attempted by 'webhook.deliver' jobs from now on;
+-- the api queues pending ones at startup with the configured
+-- WEBHOOK_MAX_ATTEMPTS.
+DROP INDEX IF EXISTS webhook_deliveries_due_idx;
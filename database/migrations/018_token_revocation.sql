@@ -0,0 +1,16 @@
+-- Revoked JWTs. `/auth/logout` and `admin.tokens.revoke` add a token's `jti`
+-- here; rows only matter until the token would have expired anyway, after
+-- which the scheduler's `token_revocation_prune` job removes them.
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti TEXT PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_by INTEGER REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS revoked_tokens_expires_idx ON revoked_tokens(expires_at);
+
+-- Revokes every token of a user issued before this instant at once.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS tokens_revoked_at TIMESTAMPTZ;
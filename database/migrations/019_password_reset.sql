@@ -0,0 +1,16 @@
+-- Self-service password reset. A user has at most one pending token; only its
+-- SHA-256 is stored, and confirming the reset deletes the row, so every token
+-- works once. `email` is where the SMTP notifier sends the token.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS email VARCHAR(255);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_idx ON users (lower(email)) WHERE email IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS password_resets (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS password_resets_token_idx ON password_resets(token_hash);
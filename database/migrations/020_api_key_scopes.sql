@@ -0,0 +1,7 @@
+-- Least-privileged API keys. `scopes` narrows what a key may do on top of its
+-- owner's permissions (`fs:read`, `run:exec`, `project:<id>`, ...); an empty
+-- list keeps the owner's full permissions. Keys past `expires_at` no longer
+-- authenticate.
+ALTER TABLE api_keys
+    ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
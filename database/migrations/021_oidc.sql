@@ -0,0 +1,24 @@
+-- Single sign-on through an external OpenID Connect provider. An identity is
+-- the provider's (`issuer`, `subject`) pair, linked to one local user; the
+-- first login creates that user when auto-provisioning is on.
+CREATE TABLE IF NOT EXISTS user_identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_login_at TIMESTAMPTZ,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_idx ON user_identities(user_id);
+
+-- Pending authorization-code logins, keyed by the `state` parameter. Rows
+-- are consumed by the callback and only live for a few minutes.
+CREATE TABLE IF NOT EXISTS oidc_logins (
+    state TEXT PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    redirect_to TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,17 @@
+-- Email verification. `email_verified_at` is set once the user confirms a
+-- token sent to `users.email` and cleared whenever the address changes; the
+-- API can require it (`API_REQUIRE_VERIFIED_EMAIL`). A pending token is
+-- bound to the address it was sent to, so changing the address in between
+-- makes it useless.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS email_verifications (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    email VARCHAR(255) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS email_verifications_token_idx ON email_verifications(token_hash);
@@ -0,0 +1,22 @@
+-- Machine identities for the client-credentials grant. Every service client
+-- is backed by a `users` row of kind `service`, so roles, grants, projects
+-- and billing work as for people, but that row has no usable password and
+-- cannot log in, reset a password or create API keys. Tokens issued to a
+-- client are short-lived and carry the client's scopes (the API key scope
+-- vocabulary of migration 020). Only the SHA-256 of the secret is stored.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS kind VARCHAR(16) NOT NULL DEFAULT 'human';
+
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_kind_check;
+ALTER TABLE users ADD CONSTRAINT users_kind_check CHECK (kind IN ('human', 'service'));
+
+CREATE TABLE IF NOT EXISTS service_clients (
+    client_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id INTEGER NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(64) NOT NULL UNIQUE,
+    secret_hash VARCHAR(64) NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ
+);
@@ -0,0 +1,11 @@
+-- Passwords are hashed with argon2id from now on. Existing rows are bcrypt;
+-- the auth service still verifies those and rehashes them on the next
+-- successful login.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS password_scheme VARCHAR(16) NOT NULL DEFAULT 'bcrypt';
+
+ALTER TABLE users ALTER COLUMN password_scheme SET DEFAULT 'argon2id';
+
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_password_scheme_check;
+ALTER TABLE users ADD CONSTRAINT users_password_scheme_check
+    CHECK (password_scheme IN ('bcrypt', 'argon2id'));
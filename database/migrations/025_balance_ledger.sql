@@ -0,0 +1,37 @@
+-- Manual top-ups and debits. Admins credit or debit `users.token_balance`
+-- through the auth service; each change lands in `billing_ledger` as kind
+-- `credit` or `debit` with a reason code, using the charge sign convention
+-- (a credit has negative `tokens`).
+ALTER TABLE billing_ledger
+    ADD COLUMN IF NOT EXISTS reason VARCHAR(32);
+
+ALTER TABLE billing_ledger DROP CONSTRAINT IF EXISTS billing_ledger_reason_check;
+ALTER TABLE billing_ledger ADD CONSTRAINT billing_ledger_reason_check
+    CHECK (
+        (kind NOT IN ('credit', 'debit') AND reason IS NULL)
+        OR reason IN ('purchase', 'grant', 'refund', 'chargeback', 'correction')
+    );
+
+-- The ledger is append-only. Rows only go away together with their user
+-- (the foreign key cascade runs inside a trigger, hence the depth check).
+CREATE OR REPLACE FUNCTION billing_ledger_append_only()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'billing_ledger is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_billing_ledger_append_only ON billing_ledger;
+CREATE TRIGGER trg_billing_ledger_append_only
+BEFORE UPDATE OR DELETE ON billing_ledger
+FOR EACH ROW
+EXECUTE FUNCTION billing_ledger_append_only();
+
+DROP TRIGGER IF EXISTS trg_billing_ledger_no_truncate ON billing_ledger;
+CREATE TRIGGER trg_billing_ledger_no_truncate
+BEFORE TRUNCATE ON billing_ledger
+FOR EACH STATEMENT
+EXECUTE FUNCTION billing_ledger_append_only();
@@ -0,0 +1,18 @@
+-- Single-use invitation codes. With `AUTH_REGISTRATION=invite_only`,
+-- `/auth/register` only accepts a pending code; the new account gets the
+-- invitation's role and, if set, an initial token grant booked as a `grant`
+-- credit in `billing_ledger`. Only the SHA-256 of the code is stored.
+CREATE TABLE IF NOT EXISTS invitations (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    role VARCHAR(32) NOT NULL,
+    initial_tokens BIGINT NOT NULL DEFAULT 0 CHECK (initial_tokens >= 0),
+    note TEXT,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    used_by INTEGER REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS invitations_pending_idx ON invitations(expires_at) WHERE used_at IS NULL;
@@ -0,0 +1,17 @@
+-- Account-security visibility. Every password or OIDC login updates the
+-- `last_login_*` columns and the user's known client addresses; a login
+-- from an address the user has not logged in from before, after the first
+-- login, leaves a `security.new_login` notification and an audit entry.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS last_login_ip TEXT,
+    ADD COLUMN IF NOT EXISTS last_login_user_agent TEXT;
+
+CREATE TABLE IF NOT EXISTS login_addresses (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip TEXT NOT NULL,
+    first_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    logins BIGINT NOT NULL DEFAULT 1,
+    PRIMARY KEY (user_id, ip)
+);
@@ -0,0 +1,13 @@
+-- Per-key usage, aggregated by day and method. The API gateway's audit
+-- writer adds every batch of API-key calls here, so owners can spot stale or
+-- abused keys without scanning `audit_log`.
+CREATE TABLE IF NOT EXISTS api_key_usage (
+    api_key_id UUID NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    method TEXT NOT NULL,
+    requests BIGINT NOT NULL DEFAULT 0,
+    errors BIGINT NOT NULL DEFAULT 0,
+    total_latency_ms BIGINT NOT NULL DEFAULT 0,
+    last_used_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (api_key_id, day, method)
+);
@@ -0,0 +1,19 @@
+-- Challenges in front of registration and login (see `AUTH_CHALLENGE`).
+-- A solved proof-of-work puzzle is recorded until it expires so it cannot
+-- be submitted twice; failed logins are counted per username and per client
+-- address within a window to decide when a login needs a challenge.
+CREATE TABLE IF NOT EXISTS redeemed_challenges (
+    nonce TEXT PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS redeemed_challenges_expires_idx
+    ON redeemed_challenges (expires_at);
+
+CREATE TABLE IF NOT EXISTS login_failures (
+    -- `user:<username>` or `ip:<address>`.
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 1,
+    window_started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
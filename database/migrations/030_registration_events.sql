@@ -0,0 +1,25 @@
+-- Outbox of new accounts. The auth service records an event in the same
+-- transaction that creates a user (password registration or OIDC
+-- provisioning), announces it on the `user_registered` channel and, with a
+-- registration webhook configured, delivers it with retries. `skipped`
+-- marks events recorded while no webhook was configured; a replay queues
+-- any event again.
+CREATE TABLE IF NOT EXISTS registration_events (
+    id BIGSERIAL PRIMARY KEY,
+    -- No foreign key: the event stays as a record after the account is gone.
+    user_id INTEGER NOT NULL,
+    payload JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'delivered', 'failed', 'skipped')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    delivered_at TIMESTAMPTZ,
+    replayed_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS registration_events_due_idx
+    ON registration_events (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS registration_events_user_idx
+    ON registration_events (user_id);
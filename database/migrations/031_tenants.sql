@@ -0,0 +1,47 @@
+-- Tenants (organizations) sharing one deployment. Every account belongs to
+-- exactly one tenant; projects, API keys and invitations carry the tenant
+-- of the account that owns them, and composite foreign keys keep that
+-- consistent, so a row can never point across tenants. Existing data
+-- belongs to the `default` tenant (id 1), whose admins also manage the
+-- tenants themselves and the role definitions all tenants share. Sandbox
+-- files of a tenant live below `tenants/<id>/` in the sandbox root.
+CREATE TABLE IF NOT EXISTS tenants (
+    id SERIAL PRIMARY KEY,
+    slug VARCHAR(64) NOT NULL UNIQUE CHECK (slug ~ '^[a-z0-9][a-z0-9-]*$'),
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO tenants (id, slug, name) VALUES (1, 'default', 'Default')
+    ON CONFLICT (id) DO NOTHING;
+SELECT setval(pg_get_serial_sequence('tenants', 'id'), (SELECT MAX(id) FROM tenants));
+
+-- Open registration and OIDC provisioning land in the default tenant.
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES tenants(id);
+CREATE UNIQUE INDEX IF NOT EXISTS users_id_tenant_idx ON users(id, tenant_id);
+CREATE INDEX IF NOT EXISTS users_tenant_idx ON users(tenant_id);
+
+-- Projects have to move with their files, so a user who owns projects
+-- cannot change tenants; keys simply follow their owner.
+ALTER TABLE projects ADD COLUMN IF NOT EXISTS tenant_id INTEGER;
+UPDATE projects SET tenant_id = users.tenant_id
+    FROM users WHERE users.id = projects.user_id AND projects.tenant_id IS NULL;
+ALTER TABLE projects ALTER COLUMN tenant_id SET NOT NULL;
+ALTER TABLE projects DROP CONSTRAINT IF EXISTS projects_owner_tenant_fkey;
+ALTER TABLE projects ADD CONSTRAINT projects_owner_tenant_fkey
+    FOREIGN KEY (user_id, tenant_id) REFERENCES users(id, tenant_id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS projects_tenant_idx ON projects(tenant_id);
+
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS tenant_id INTEGER;
+UPDATE api_keys SET tenant_id = users.tenant_id
+    FROM users WHERE users.id = api_keys.user_id AND api_keys.tenant_id IS NULL;
+ALTER TABLE api_keys ALTER COLUMN tenant_id SET NOT NULL;
+ALTER TABLE api_keys DROP CONSTRAINT IF EXISTS api_keys_owner_tenant_fkey;
+ALTER TABLE api_keys ADD CONSTRAINT api_keys_owner_tenant_fkey
+    FOREIGN KEY (user_id, tenant_id) REFERENCES users(id, tenant_id)
+    ON UPDATE CASCADE ON DELETE CASCADE;
+
+-- The account created from an invitation joins the inviting admin's tenant.
+ALTER TABLE invitations
+    ADD COLUMN IF NOT EXISTS tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES tenants(id);
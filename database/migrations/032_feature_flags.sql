@@ -0,0 +1,18 @@
+-- Operator overrides of the API's feature flags (`admin.flags.set`). A row
+-- without role and user applies to everyone, a role row to the users holding
+-- that role, a user row to that user alone; the most specific row wins.
+-- Flags without a matching row keep their configured default
+-- (`FEATURE_FLAGS`).
+CREATE TABLE IF NOT EXISTS feature_flag_overrides (
+    id BIGSERIAL PRIMARY KEY,
+    flag VARCHAR(64) NOT NULL,
+    role VARCHAR(32) REFERENCES roles(name) ON DELETE CASCADE,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    enabled BOOLEAN NOT NULL,
+    updated_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CHECK (role IS NULL OR user_id IS NULL)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS feature_flag_overrides_target_idx
+    ON feature_flag_overrides (flag, COALESCE(role, ''), COALESCE(user_id, 0));
@@ -0,0 +1,64 @@
+-- Announces changes that affect how a user authenticates on the
+-- `auth_changes` channel, so API instances drop their cached lookups for the
+-- user at once instead of after the cache TTL: role, tenant, name, disabling,
+-- token revocation cutoff, email verification, the token balance running
+-- out or being topped up again, and API keys being deleted or re-scoped.
+-- `last_used_at` bumps and ordinary balance changes stay silent.
+CREATE OR REPLACE FUNCTION notify_auth_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    changed JSONB;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := to_jsonb(OLD);
+    ELSE
+        changed := to_jsonb(NEW);
+    END IF;
+    PERFORM pg_notify(
+        'auth_changes',
+        json_build_object(
+            'user_id',
+            (CASE WHEN TG_TABLE_NAME = 'users' THEN changed->'id' ELSE changed->'user_id' END)
+        )::text
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_users_auth_change ON users;
+CREATE TRIGGER trg_users_auth_change
+AFTER UPDATE ON users
+FOR EACH ROW
+WHEN (
+    OLD.role IS DISTINCT FROM NEW.role
+    OR OLD.tenant_id IS DISTINCT FROM NEW.tenant_id
+    OR OLD.username IS DISTINCT FROM NEW.username
+    OR OLD.disabled_at IS DISTINCT FROM NEW.disabled_at
+    OR OLD.tokens_revoked_at IS DISTINCT FROM NEW.tokens_revoked_at
+    OR OLD.email_verified_at IS DISTINCT FROM NEW.email_verified_at
+    OR (OLD.token_balance > 0) IS DISTINCT FROM (NEW.token_balance > 0)
+)
+EXECUTE FUNCTION notify_auth_change();
+
+DROP TRIGGER IF EXISTS trg_users_auth_delete ON users;
+CREATE TRIGGER trg_users_auth_delete
+AFTER DELETE ON users
+FOR EACH ROW
+EXECUTE FUNCTION notify_auth_change();
+
+DROP TRIGGER IF EXISTS trg_api_keys_auth_change ON api_keys;
+CREATE TRIGGER trg_api_keys_auth_change
+AFTER UPDATE ON api_keys
+FOR EACH ROW
+WHEN (
+    OLD.scopes IS DISTINCT FROM NEW.scopes
+    OR OLD.expires_at IS DISTINCT FROM NEW.expires_at
+    OR OLD.api_key_hash IS DISTINCT FROM NEW.api_key_hash
+)
+EXECUTE FUNCTION notify_auth_change();
+
+DROP TRIGGER IF EXISTS trg_api_keys_auth_delete ON api_keys;
+CREATE TRIGGER trg_api_keys_auth_delete
+AFTER DELETE ON api_keys
+FOR EACH ROW
+EXECUTE FUNCTION notify_auth_change();
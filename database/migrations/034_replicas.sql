@@ -0,0 +1,20 @@
+-- API replicas behind a load balancer. Micro VMs and agent tasks live in the
+-- memory of the replica that started them, so each replica holds a lease
+-- here and records the handles it owns; a call for a handle that reaches
+-- another replica is forwarded to the owner's `url`. A replica whose lease
+-- ran out is gone, and deleting its row drops its handles with it.
+CREATE TABLE IF NOT EXISTS replicas (
+    id UUID PRIMARY KEY,
+    url TEXT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    lease_expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS replica_handles (
+    handle UUID PRIMARY KEY,
+    kind TEXT NOT NULL,
+    replica_id UUID NOT NULL REFERENCES replicas(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS replica_handles_replica_idx ON replica_handles(replica_id, created_at);
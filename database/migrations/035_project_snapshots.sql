@@ -0,0 +1,34 @@
+-- Point-in-time copies of a project's files. A snapshot lists the path and
+-- sha256 of every file; contents live in `project_blobs` once per project and
+-- distinct sha256, so snapshots of a mostly unchanged project cost little
+-- more than their file lists. Blobs no snapshot refers to any more are
+-- removed when snapshots are pruned.
+CREATE TABLE IF NOT EXISTS project_blobs (
+    project_id UUID NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    sha256 BYTEA NOT NULL,
+    content BYTEA NOT NULL,
+    size BIGINT NOT NULL,
+    PRIMARY KEY (project_id, sha256)
+);
+
+CREATE TABLE IF NOT EXISTS project_snapshots (
+    id BIGSERIAL PRIMARY KEY,
+    project_id UUID NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    user_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    label TEXT,
+    file_count BIGINT NOT NULL DEFAULT 0,
+    total_size BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS project_snapshots_project_idx ON project_snapshots(project_id, id DESC);
+
+CREATE TABLE IF NOT EXISTS project_snapshot_files (
+    snapshot_id BIGINT NOT NULL REFERENCES project_snapshots(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    sha256 BYTEA NOT NULL,
+    size BIGINT NOT NULL,
+    PRIMARY KEY (snapshot_id, path)
+);
+
+CREATE INDEX IF NOT EXISTS project_snapshot_files_sha256_idx ON project_snapshot_files(sha256);
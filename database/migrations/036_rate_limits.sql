@@ -0,0 +1,11 @@
+-- Operator overrides of per-method rate limits (`admin.rateLimits.set`).
+-- A row gives the method a token bucket of its own per caller, replacing
+-- any limit from `RATE_LIMIT_METHODS`; methods without a row share the
+-- bucket of `RATE_LIMIT_PER_MINUTE`. A burst of NULL means a minute's worth.
+CREATE TABLE IF NOT EXISTS rate_limit_overrides (
+    method VARCHAR(128) PRIMARY KEY,
+    per_minute INTEGER NOT NULL CHECK (per_minute >= 0),
+    burst INTEGER CHECK (burst IS NULL OR burst > 0),
+    updated_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
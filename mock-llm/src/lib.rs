@@ -0,0 +1,16 @@
+//! A scripted stand-in for an OpenAI-compatible LLM server, so the API
+//! gateway and the agent dispatcher can be tested end to end without a
+//! model. Tests start a [`MockLlmServer`] on a free port, queue
+//! [`Reply`]s per [`Endpoint`] and point `LLM_SERVER_URL`,
+//! `OPENAI_BASE_URL` (with `/v1`) or `AgentDispatcherConfig` at
+//! [`MockLlmServer::url`]. The `mock-llm` binary serves the same thing for
+//! containerised test setups.
+
+pub mod reply;
+pub mod server;
+
+pub use reply::{
+    default_embedding, respond, stream_chunks, Endpoint, Reply, ReplyKind, Script, ToolCall,
+    EMBEDDING_DIMENSIONS,
+};
+pub use server::{MockLlmServer, RecordedRequest};
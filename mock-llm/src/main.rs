@@ -0,0 +1,36 @@
+//! `mock-llm`: the scripted LLM server as a process. Listens on
+//! `MOCK_LLM_ADDR` (default `127.0.0.1:6988`, where the API looks for the
+//! local llm server) and, with `MOCK_LLM_SCRIPT`, preloads the replies
+//! from a JSON [`Script`] file.
+
+use std::net::SocketAddr;
+
+use anyhow::Context as _;
+use mock_llm::{MockLlmServer, Script};
+use tracing::info;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
+    let addr: SocketAddr = std::env::var("MOCK_LLM_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:6988".to_string())
+        .parse()
+        .context("MOCK_LLM_ADDR must be an address such as 127.0.0.1:6988")?;
+    let script = match std::env::var("MOCK_LLM_SCRIPT") {
+        Ok(path) => {
+            let contents =
+                std::fs::read_to_string(&path).with_context(|| format!("failed to read {path}"))?;
+            serde_json::from_str::<Script>(&contents)
+                .with_context(|| format!("invalid script {path}"))?
+        }
+        Err(_) => Script::default(),
+    };
+    let server = MockLlmServer::serve(addr, script).await?;
+    info!(url = %server.url(), "mock llm server listening");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,464 @@
+//! Scripted replies and the OpenAI-shaped bodies built from them. Replies
+//! are consumed per endpoint in order; once an endpoint's queue is empty it
+//! answers deterministically: chat and completions echo the last user
+//! message or the prompt, embeddings hash each input into a fixed vector.
+//! Usage counts whitespace-separated words as tokens.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Dimensions of the default embeddings.
+pub const EMBEDDING_DIMENSIONS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Chat,
+    Completions,
+    Embeddings,
+}
+
+impl Endpoint {
+    pub fn path(self) -> &'static str {
+        match self {
+            Endpoint::Chat => "/v1/chat/completions",
+            Endpoint::Completions => "/v1/completions",
+            Endpoint::Embeddings => "/v1/embeddings",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyKind {
+    /// Assistant message content (chat) or completion text.
+    Text(String),
+    /// Native function calls; chat only.
+    ToolCalls(Vec<ToolCall>),
+    /// The vector returned for every input; embeddings only.
+    Embedding(Vec<f32>),
+    /// An error response in the OpenAI shape.
+    Error { status: u16, message: String },
+    /// A response body sent verbatim with status 200.
+    Body(Value),
+}
+
+/// One scripted answer. In script files it is an object with one of the
+/// [`ReplyKind`] keys, e.g. `{"text": "hi", "delay_ms": 50}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    #[serde(flatten)]
+    pub kind: ReplyKind,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delay_ms: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl Reply {
+    fn of(kind: ReplyKind) -> Self {
+        Self { kind, delay_ms: 0 }
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::of(ReplyKind::Text(text.into()))
+    }
+
+    /// A text reply holding `value` as JSON, e.g. an agent's structured
+    /// `{summary, insights, actions}` answer.
+    pub fn json(value: Value) -> Self {
+        Self::text(value.to_string())
+    }
+
+    pub fn tool_call(name: impl Into<String>, arguments: Value) -> Self {
+        Self::of(ReplyKind::ToolCalls(vec![ToolCall {
+            name: name.into(),
+            arguments,
+        }]))
+    }
+
+    pub fn tool_calls(calls: Vec<ToolCall>) -> Self {
+        Self::of(ReplyKind::ToolCalls(calls))
+    }
+
+    pub fn embedding(vector: Vec<f32>) -> Self {
+        Self::of(ReplyKind::Embedding(vector))
+    }
+
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::of(ReplyKind::Error {
+            status,
+            message: message.into(),
+        })
+    }
+
+    pub fn body(body: Value) -> Self {
+        Self::of(ReplyKind::Body(body))
+    }
+
+    /// Holds the response back for `delay`, e.g. to trip client timeouts.
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay_ms = delay.as_millis() as u64;
+        self
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms)
+    }
+}
+
+/// Queued replies per endpoint, loadable from JSON:
+/// `{"chat": [{"text": "..."}], "embeddings": [{"error": {...}}]}`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    #[serde(default)]
+    pub chat: VecDeque<Reply>,
+    #[serde(default)]
+    pub completions: VecDeque<Reply>,
+    #[serde(default)]
+    pub embeddings: VecDeque<Reply>,
+}
+
+impl Script {
+    fn queue(&mut self, endpoint: Endpoint) -> &mut VecDeque<Reply> {
+        match endpoint {
+            Endpoint::Chat => &mut self.chat,
+            Endpoint::Completions => &mut self.completions,
+            Endpoint::Embeddings => &mut self.embeddings,
+        }
+    }
+
+    pub fn push(&mut self, endpoint: Endpoint, reply: Reply) {
+        self.queue(endpoint).push_back(reply);
+    }
+
+    pub fn next(&mut self, endpoint: Endpoint) -> Option<Reply> {
+        self.queue(endpoint).pop_front()
+    }
+}
+
+fn words(text: &str) -> u64 {
+    text.split_whitespace().count() as u64
+}
+
+fn usage(prompt: u64, completion: u64) -> Value {
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    })
+}
+
+fn model(request: &Value) -> &str {
+    request["model"].as_str().unwrap_or("mock")
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (
+        status,
+        json!({ "error": { "message": message, "type": "mock_error" } }),
+    )
+}
+
+/// Strings of an embeddings `input`, which may be one string or a list.
+fn inputs(request: &Value) -> Vec<String> {
+    match &request["input"] {
+        Value::String(text) => vec![text.clone()],
+        Value::Array(items) => items
+            .iter()
+            .map(|item| item.as_str().unwrap_or_default().to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// FNV-1a spread over [`EMBEDDING_DIMENSIONS`] values in `0.0..=1.0`.
+pub fn default_embedding(input: &str) -> Vec<f32> {
+    let hash = input.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    (0..EMBEDDING_DIMENSIONS)
+        .map(|index| ((hash >> (index * 8)) & 0xff) as f32 / 255.0)
+        .collect()
+}
+
+/// The status and body `endpoint` answers `request` with, given the next
+/// scripted reply if there is one.
+pub fn respond(endpoint: Endpoint, request: &Value, reply: Option<&Reply>) -> (u16, Value) {
+    match reply.map(|reply| &reply.kind) {
+        Some(ReplyKind::Error { status, message }) => return error(*status, message),
+        Some(ReplyKind::Body(body)) => return (200, body.clone()),
+        _ => {}
+    }
+    let kind = reply.map(|reply| &reply.kind);
+    match endpoint {
+        Endpoint::Chat => chat(request, kind),
+        Endpoint::Completions => completion(request, kind),
+        Endpoint::Embeddings => embeddings(request, kind),
+    }
+}
+
+fn chat(request: &Value, kind: Option<&ReplyKind>) -> (u16, Value) {
+    let messages = request["messages"].as_array().cloned().unwrap_or_default();
+    let prompt_tokens = messages
+        .iter()
+        .map(|message| words(message["content"].as_str().unwrap_or_default()))
+        .sum();
+    let (content, tool_calls) = match kind {
+        None => {
+            let last = messages
+                .iter()
+                .rev()
+                .find(|message| message["role"] == "user")
+                .and_then(|message| message["content"].as_str())
+                .unwrap_or_default();
+            (Some(format!("mock reply to: {last}")), None)
+        }
+        Some(ReplyKind::Text(text)) => (Some(text.clone()), None),
+        Some(ReplyKind::ToolCalls(calls)) => (None, Some(calls)),
+        Some(_) => return error(500, "scripted reply does not fit the chat endpoint"),
+    };
+    let completion_tokens = match (&content, tool_calls) {
+        (Some(content), _) => words(content),
+        (None, Some(calls)) => calls
+            .iter()
+            .map(|call| words(&call.arguments.to_string()))
+            .sum(),
+        (None, None) => 0,
+    };
+    let mut message = json!({ "role": "assistant", "content": content });
+    if let Some(calls) = tool_calls {
+        message["tool_calls"] = calls
+            .iter()
+            .enumerate()
+            .map(|(index, call)| {
+                json!({
+                    "id": format!("call_{}", index + 1),
+                    "type": "function",
+                    "function": {
+                        "name": call.name,
+                        "arguments": call.arguments.to_string(),
+                    },
+                })
+            })
+            .collect();
+    }
+    let finish_reason = if tool_calls.is_some() {
+        "tool_calls"
+    } else {
+        "stop"
+    };
+    (
+        200,
+        json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "created": 0,
+            "model": model(request),
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": usage(prompt_tokens, completion_tokens),
+        }),
+    )
+}
+
+fn completion(request: &Value, kind: Option<&ReplyKind>) -> (u16, Value) {
+    let prompt = request["prompt"].as_str().unwrap_or_default();
+    let text = match kind {
+        None => format!("mock completion of: {prompt}"),
+        Some(ReplyKind::Text(text)) => text.clone(),
+        Some(_) => return error(500, "scripted reply does not fit the completions endpoint"),
+    };
+    (
+        200,
+        json!({
+            "id": "cmpl-mock",
+            "object": "text_completion",
+            "created": 0,
+            "model": model(request),
+            "choices": [{ "index": 0, "text": text, "finish_reason": "stop" }],
+            "usage": usage(words(prompt), words(&text)),
+        }),
+    )
+}
+
+fn embeddings(request: &Value, kind: Option<&ReplyKind>) -> (u16, Value) {
+    let inputs = inputs(request);
+    let vector = |input: &str| match kind {
+        Some(ReplyKind::Embedding(vector)) => Ok(vector.clone()),
+        None => Ok(default_embedding(input)),
+        Some(_) => Err(()),
+    };
+    let mut data = Vec::with_capacity(inputs.len());
+    for (index, input) in inputs.iter().enumerate() {
+        let Ok(embedding) = vector(input) else {
+            return error(500, "scripted reply does not fit the embeddings endpoint");
+        };
+        data.push(json!({ "object": "embedding", "index": index, "embedding": embedding }));
+    }
+    let prompt_tokens: u64 = inputs.iter().map(|input| words(input)).sum();
+    (
+        200,
+        json!({
+            "object": "list",
+            "data": data,
+            "model": model(request),
+            "usage": { "prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens },
+        }),
+    )
+}
+
+/// Splits a chat completion into `chat.completion.chunk` objects, one per
+/// word of content plus a final one with the finish reason and usage, as a
+/// server answering `"stream": true` would.
+pub fn stream_chunks(response: &Value) -> Vec<Value> {
+    let choice = &response["choices"][0];
+    let chunk = |delta: Value, finish_reason: Value| {
+        json!({
+            "id": response["id"],
+            "object": "chat.completion.chunk",
+            "created": response["created"],
+            "model": response["model"],
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    };
+    let mut chunks = vec![chunk(json!({ "role": "assistant" }), Value::Null)];
+    let content = choice["message"]["content"].as_str().unwrap_or_default();
+    chunks.extend(
+        content
+            .split_inclusive(' ')
+            .map(|piece| chunk(json!({ "content": piece }), Value::Null)),
+    );
+    if let Some(calls) = choice["message"].get("tool_calls") {
+        chunks.push(chunk(json!({ "tool_calls": calls }), Value::Null));
+    }
+    let mut last = chunk(json!({}), choice["finish_reason"].clone());
+    last["usage"] = response["usage"].clone();
+    chunks.push(last);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_echoes_the_last_user_message_by_default() {
+        let request = json!({
+            "model": "tiny",
+            "messages": [
+                { "role": "system", "content": "Be terse." },
+                { "role": "user", "content": "hello there" },
+            ],
+        });
+        let (status, body) = respond(Endpoint::Chat, &request, None);
+        assert_eq!(status, 200);
+        assert_eq!(body["model"], "tiny");
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            "mock reply to: hello there"
+        );
+        assert_eq!(body["usage"]["prompt_tokens"], 4);
+        assert_eq!(body["usage"]["completion_tokens"], 5);
+        assert_eq!(body["usage"]["total_tokens"], 9);
+    }
+
+    #[test]
+    fn tool_calls_carry_string_arguments() {
+        let reply = Reply::tool_call("file_write", json!({ "path": "a.rs", "content": "x" }));
+        let (_, body) = respond(Endpoint::Chat, &json!({ "messages": [] }), Some(&reply));
+        let choice = &body["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert!(choice["message"]["content"].is_null());
+        let call = &choice["message"]["tool_calls"][0];
+        assert_eq!(call["id"], "call_1");
+        assert_eq!(call["function"]["name"], "file_write");
+        let arguments: Value =
+            serde_json::from_str(call["function"]["arguments"].as_str().unwrap()).unwrap();
+        assert_eq!(arguments["path"], "a.rs");
+    }
+
+    #[test]
+    fn errors_and_mismatched_replies() {
+        let (status, body) = respond(
+            Endpoint::Completions,
+            &json!({}),
+            Some(&Reply::error(503, "overloaded")),
+        );
+        assert_eq!(status, 503);
+        assert_eq!(body["error"]["message"], "overloaded");
+        let (status, _) = respond(
+            Endpoint::Embeddings,
+            &json!({ "input": "x" }),
+            Some(&Reply::text("nope")),
+        );
+        assert_eq!(status, 500);
+    }
+
+    #[test]
+    fn embeddings_are_deterministic_per_input() {
+        let request = json!({ "model": "e", "input": ["a b", "c", "a b"] });
+        let (_, body) = respond(Endpoint::Embeddings, &request, None);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 3);
+        assert_eq!(data[0]["embedding"], data[2]["embedding"]);
+        assert_ne!(data[0]["embedding"], data[1]["embedding"]);
+        assert_eq!(
+            data[1]["embedding"].as_array().unwrap().len(),
+            EMBEDDING_DIMENSIONS
+        );
+        assert_eq!(body["usage"]["prompt_tokens"], 5);
+
+        let (_, body) = respond(
+            Endpoint::Embeddings,
+            &json!({ "input": "x" }),
+            Some(&Reply::embedding(vec![0.5, 0.25])),
+        );
+        assert_eq!(body["data"][0]["embedding"], json!([0.5, 0.25]));
+    }
+
+    #[test]
+    fn streams_end_with_usage() {
+        let (_, body) = respond(
+            Endpoint::Chat,
+            &json!({ "messages": [] }),
+            Some(&Reply::text("one two")),
+        );
+        let chunks = stream_chunks(&body);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "one ");
+        assert_eq!(chunks[2]["choices"][0]["delta"]["content"], "two");
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[3]["usage"]["completion_tokens"], 2);
+    }
+
+    #[test]
+    fn scripts_load_from_json() {
+        let script: Script = serde_json::from_value(json!({
+            "chat": [{ "text": "first" }, { "error": { "status": 429, "message": "slow down" }, "delay_ms": 5 }],
+            "embeddings": [{ "embedding": [1.0] }],
+        }))
+        .unwrap();
+        let mut script = script;
+        assert_eq!(script.next(Endpoint::Chat), Some(Reply::text("first")));
+        assert_eq!(
+            script.next(Endpoint::Chat),
+            Some(Reply::error(429, "slow down").after(Duration::from_millis(5)))
+        );
+        assert_eq!(script.next(Endpoint::Chat), None);
+        assert_eq!(script.next(Endpoint::Completions), None);
+        assert!(serde_json::from_value::<Script>(json!({ "chats": [] })).is_err());
+    }
+}
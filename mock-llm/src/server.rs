@@ -0,0 +1,205 @@
+//! The HTTP side: `/v1/chat/completions` (including `"stream": true` as
+//! server-sent events), `/v1/completions`, `/v1/embeddings` and `/health`.
+//! Every request is recorded with its headers so tests can assert on what
+//! a client actually sent.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use crate::reply::{respond, stream_chunks, Endpoint, Reply, Script};
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub endpoint: Endpoint,
+    /// Lower-case header names.
+    pub headers: BTreeMap<String, String>,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct Shared {
+    script: Mutex<Script>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+/// A running mock server; it stops when dropped.
+pub struct MockLlmServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockLlmServer {
+    /// Starts an empty-scripted server on a free loopback port.
+    pub async fn start() -> std::io::Result<Self> {
+        Self::serve(SocketAddr::from(([127, 0, 0, 1], 0)), Script::default()).await
+    }
+
+    pub async fn serve(addr: SocketAddr, script: Script) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            script: Mutex::new(script),
+            requests: Mutex::default(),
+        });
+        let app = Router::new()
+            .route(
+                Endpoint::Chat.path(),
+                post(|state, headers, body| handle(state, Endpoint::Chat, headers, body)),
+            )
+            .route(
+                Endpoint::Completions.path(),
+                post(|state, headers, body| handle(state, Endpoint::Completions, headers, body)),
+            )
+            .route(
+                Endpoint::Embeddings.path(),
+                post(|state, headers, body| handle(state, Endpoint::Embeddings, headers, body)),
+            )
+            .route("/health", get(|| async { Json(json!({ "status": "ok" })) }))
+            .with_state(shared.clone());
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let serve = axum::serve(listener, app).with_graceful_shutdown(async {
+                let _ = stopped.await;
+            });
+            if let Err(err) = serve.await {
+                tracing::error!(error = %err, "mock llm server failed");
+            }
+        });
+        Ok(Self {
+            addr,
+            shared,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base URL without a trailing slash, e.g. `http://127.0.0.1:43121`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Queues `reply` as the next answer of `endpoint`.
+    pub fn push(&self, endpoint: Endpoint, reply: Reply) -> &Self {
+        self.shared.script.lock().push(endpoint, reply);
+        self
+    }
+
+    /// Everything received so far, oldest first.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.shared.requests.lock().clone()
+    }
+}
+
+impl Drop for MockLlmServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn handle(
+    State(shared): State<Arc<Shared>>,
+    endpoint: Endpoint,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Ok(request) = serde_json::from_slice::<Value>(&body) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(
+                json!({ "error": { "message": "request body is not JSON", "type": "mock_error" } }),
+            ),
+        )
+            .into_response();
+    };
+    shared.requests.lock().push(RecordedRequest {
+        endpoint,
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect(),
+        body: request.clone(),
+    });
+    let reply = shared.script.lock().next(endpoint);
+    if let Some(reply) = &reply {
+        tokio::time::sleep(reply.delay()).await;
+    }
+    let (status, response) = respond(endpoint, &request, reply.as_ref());
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    if endpoint == Endpoint::Chat && status.is_success() && request["stream"] == true {
+        let events: String = stream_chunks(&response)
+            .iter()
+            .map(|chunk| format!("data: {chunk}\n\n"))
+            .chain(std::iter::once("data: [DONE]\n\n".to_string()))
+            .collect();
+        return ([(header::CONTENT_TYPE, "text/event-stream")], events).into_response();
+    }
+    (status, Json(response)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_scripted_replies_and_records_requests() {
+        let server = MockLlmServer::start().await.unwrap();
+        server
+            .push(Endpoint::Chat, Reply::text("scripted"))
+            .push(Endpoint::Chat, Reply::error(503, "unavailable"));
+        let http = reqwest::Client::new();
+        let url = format!("{}{}", server.url(), Endpoint::Chat.path());
+        let request = json!({ "model": "m", "messages": [{ "role": "user", "content": "hi" }] });
+
+        let response = http
+            .post(&url)
+            .header("x-user-id", "7")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "scripted");
+
+        let response = http.post(&url).json(&request).send().await.unwrap();
+        assert_eq!(response.status(), 503);
+
+        let mut streamed = request.clone();
+        streamed["stream"] = json!(true);
+        let response = http.post(&url).json(&streamed).send().await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE.as_str()],
+            "text/event-stream"
+        );
+        let events = response.text().await.unwrap();
+        assert!(events.contains("\"content\":\"mock \""));
+        assert!(events.ends_with("data: [DONE]\n\n"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].endpoint, Endpoint::Chat);
+        assert_eq!(requests[0].headers["x-user-id"], "7");
+        assert_eq!(requests[0].body, request);
+    }
+}
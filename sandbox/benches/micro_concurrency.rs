@@ -0,0 +1,112 @@
+//! `micro.execute` throughput under many concurrent callers. Each caller
+//! starts its own instance and executes a trivial shell script in a loop, so
+//! the numbers are dominated by process spawning; the interesting output is
+//! how often the instance table made callers wait for each other.
+//!
+//! `MICRO_BENCH_CALLERS` (default 100) and `MICRO_BENCH_CALLS` (per caller,
+//! default 20) size the run.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sandbox::micro::{
+    MicroConfig, MicroExecuteRequest, MicroImage, MicroStartRequest, SandboxMicro,
+};
+
+fn setting(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+#[tokio::main]
+async fn main() {
+    let callers = setting("MICRO_BENCH_CALLERS", 100);
+    let calls = setting("MICRO_BENCH_CALLS", 20);
+    let root = tempfile::tempdir().expect("temp dir");
+    let image = MicroImage::new("sh", "/bin/sh", Vec::new(), "sh", Vec::new()).expect("image");
+    let config = MicroConfig::new(
+        root.path(),
+        vec![image],
+        Duration::from_secs(5),
+        Duration::from_secs(10),
+        64 * 1024,
+        vec![("PATH".to_string(), "/usr/bin:/bin".to_string())],
+    )
+    .expect("micro config");
+    let micro = Arc::new(SandboxMicro::new(config));
+
+    let mut instances = Vec::with_capacity(callers);
+    for _ in 0..callers {
+        let instance = micro
+            .start(MicroStartRequest {
+                image: "sh".to_string(),
+                init_script: None,
+                scope: None,
+            })
+            .await
+            .expect("instance starts");
+        instances.push(instance.id());
+    }
+
+    let before = micro.lock_contention();
+    let started = Instant::now();
+    let workers: Vec<_> = instances
+        .into_iter()
+        .map(|vm_id| {
+            let micro = micro.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::with_capacity(calls);
+                for _ in 0..calls {
+                    let call = Instant::now();
+                    let output = micro
+                        .execute(MicroExecuteRequest {
+                            vm_id,
+                            code: "echo ok".to_string(),
+                            timeout: None,
+                            scope: None,
+                        })
+                        .await
+                        .expect("execute succeeds");
+                    assert_eq!(output.exit_code, 0);
+                    latencies.push(call.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(callers * calls);
+    for worker in workers {
+        latencies.extend(worker.await.expect("caller finishes"));
+    }
+    let elapsed = started.elapsed();
+    let after = micro.lock_contention();
+    micro.shutdown().await.expect("instances stop");
+
+    latencies.sort_unstable();
+    println!(
+        "micro.execute: {callers} callers x {calls} calls in {:.2?} ({:.0} calls/s)",
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.99),
+        latencies[latencies.len() - 1]
+    );
+    println!(
+        "{}: {} lock acquisitions, {} contended, {:.2?} waiting",
+        after.map,
+        after.acquisitions - before.acquisitions,
+        after.contended - before.contended,
+        after.wait.saturating_sub(before.wait)
+    );
+}
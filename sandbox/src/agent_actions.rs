@@ -0,0 +1,286 @@
+//! Applies the actions of an [`AgentOutcome`](crate::AgentOutcome) to a
+//! workspace: file writes and patches go to a [`SandboxFs`], commands to a
+//! [`SandboxRun`] if one is attached. Messages and checkpoints carry nothing
+//! to apply. Actions run in order and the first failure skips the rest,
+//! since later actions usually build on earlier ones.
+//!
+//! A dry run writes and runs nothing. Patches are applied to an in-memory
+//! overlay instead, so a second patch of the same file sees the first, and
+//! every file action reports the diff it would make.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+
+use crate::agent_dispatcher::{AgentAction, AgentFileContent};
+use crate::diff;
+use crate::errors::{Result, SandboxError};
+use crate::fs::SandboxFs;
+use crate::run::{RunRequest, SandboxRun};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentActionStatus {
+    Applied,
+    /// Would have been applied; dry runs only.
+    Planned,
+    /// Nothing to apply, commands without a [`SandboxRun`], and everything
+    /// after a failure.
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentActionReport {
+    /// Position in the outcome's action list.
+    pub index: usize,
+    /// The action type, as in the outcome.
+    pub action: &'static str,
+    pub status: AgentActionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// For file actions whose old and new content are text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Command output, lossily decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What a file action wrote, or would write in a dry run.
+    #[serde(skip)]
+    pub content: Option<Vec<u8>>,
+    /// How long a command ran.
+    #[serde(skip)]
+    pub duration: Option<std::time::Duration>,
+}
+
+impl AgentActionReport {
+    fn new(index: usize, action: &AgentAction) -> Self {
+        let (kind, path) = match action {
+            AgentAction::Message { .. } => ("message", None),
+            AgentAction::FilePatch { path, .. } => ("file_patch", Some(path.clone())),
+            AgentAction::FileWrite { path, .. } => ("file_write", Some(path.clone())),
+            AgentAction::Command { .. } => ("command", None),
+            AgentAction::Checkpoint { .. } => ("checkpoint", None),
+        };
+        Self {
+            index,
+            action: kind,
+            status: AgentActionStatus::Skipped,
+            path,
+            diff: None,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            error: None,
+            content: None,
+            duration: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentApplyReport {
+    pub dry_run: bool,
+    /// Actions applied, or planned in a dry run.
+    pub applied: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub actions: Vec<AgentActionReport>,
+}
+
+impl AgentApplyReport {
+    pub fn is_success(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentActionExecutor {
+    fs: SandboxFs,
+    run: Option<SandboxRun>,
+    dry_run: bool,
+}
+
+impl AgentActionExecutor {
+    /// Applies file actions to `fs`; commands are skipped until
+    /// [`with_run`](Self::with_run) attaches a process sandbox.
+    pub fn new(fs: SandboxFs) -> Self {
+        Self {
+            fs,
+            run: None,
+            dry_run: false,
+        }
+    }
+
+    /// Runs command actions in `run`, subject to its allowlists and limits.
+    pub fn with_run(mut self, run: SandboxRun) -> Self {
+        self.run = Some(run);
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub async fn apply(&self, actions: &[AgentAction]) -> AgentApplyReport {
+        // Content of the files a dry run has touched so far.
+        let mut overlay: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut reports = Vec::with_capacity(actions.len());
+        let mut failed = false;
+        for (index, action) in actions.iter().enumerate() {
+            let mut report = AgentActionReport::new(index, action);
+            if !failed {
+                let outcome = match action {
+                    AgentAction::FileWrite { path, content } => {
+                        decode(content).and_then(|content| {
+                            self.write_file(&mut overlay, &mut report, path, content)
+                        })
+                    }
+                    AgentAction::FilePatch { path, patch } => self
+                        .current(&overlay, path)
+                        .and_then(|current| {
+                            let current = text(current.unwrap_or_default())?;
+                            let patched = diff::apply_patch(&current, patch)?;
+                            Ok(patched.into_bytes())
+                        })
+                        .and_then(|content| {
+                            self.write_file(&mut overlay, &mut report, path, content)
+                        }),
+                    AgentAction::Command { command, args } => {
+                        self.run_command(&mut report, command, args).await
+                    }
+                    AgentAction::Message { .. } | AgentAction::Checkpoint { .. } => Ok(()),
+                };
+                if let Err(err) = outcome {
+                    report.status = AgentActionStatus::Failed;
+                    report.error = Some(err.to_string());
+                    failed = true;
+                }
+            }
+            reports.push(report);
+        }
+
+        let count = |status| {
+            reports
+                .iter()
+                .filter(|report: &&AgentActionReport| report.status == status)
+                .count()
+        };
+        AgentApplyReport {
+            dry_run: self.dry_run,
+            applied: count(AgentActionStatus::Applied) + count(AgentActionStatus::Planned),
+            failed: count(AgentActionStatus::Failed),
+            skipped: count(AgentActionStatus::Skipped),
+            actions: reports,
+        }
+    }
+
+    fn done(&self) -> AgentActionStatus {
+        if self.dry_run {
+            AgentActionStatus::Planned
+        } else {
+            AgentActionStatus::Applied
+        }
+    }
+
+    /// The content of `path` as earlier actions of this run left it; `None`
+    /// if the file does not exist.
+    fn current(&self, overlay: &HashMap<String, Vec<u8>>, path: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(content) = overlay.get(path) {
+            return Ok(Some(content.clone()));
+        }
+        match self.fs.read(path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(SandboxError::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_file(
+        &self,
+        overlay: &mut HashMap<String, Vec<u8>>,
+        report: &mut AgentActionReport,
+        path: &str,
+        content: Vec<u8>,
+    ) -> Result<()> {
+        let current = self.current(overlay, path)?;
+        if let (Ok(before), Ok(after)) = (
+            std::str::from_utf8(current.as_deref().unwrap_or_default()),
+            std::str::from_utf8(&content),
+        ) {
+            report.diff = Some(diff::unified_diff(path, before, after));
+        }
+        if self.dry_run {
+            overlay.insert(path.to_string(), content.clone());
+        } else {
+            self.fs.write(path, &content)?;
+        }
+        report.content = Some(content);
+        report.status = self.done();
+        Ok(())
+    }
+
+    async fn run_command(
+        &self,
+        report: &mut AgentActionReport,
+        command: &str,
+        args: &[String],
+    ) -> Result<()> {
+        let Some(run) = &self.run else {
+            return Ok(());
+        };
+        if self.dry_run {
+            if !run
+                .config()
+                .allowed_programs()
+                .any(|allowed| allowed == command)
+            {
+                return Err(SandboxError::InvalidOperation(format!(
+                    "program '{command}' is not permitted in sandbox"
+                )));
+            }
+            report.status = AgentActionStatus::Planned;
+            return Ok(());
+        }
+        let output = run
+            .execute(RunRequest::new(command).with_args(args.to_vec()))
+            .await?;
+        report.exit_code = Some(output.exit_code);
+        report.stdout = Some(String::from_utf8_lossy(&output.stdout).into_owned());
+        report.stderr = Some(String::from_utf8_lossy(&output.stderr).into_owned());
+        report.duration = Some(output.duration);
+        if output.exit_code != 0 {
+            return Err(SandboxError::InvalidOperation(format!(
+                "command exited with status {}",
+                output.exit_code
+            )));
+        }
+        report.status = AgentActionStatus::Applied;
+        Ok(())
+    }
+}
+
+fn decode(content: &AgentFileContent) -> Result<Vec<u8>> {
+    match content {
+        AgentFileContent::Utf8(text) => Ok(text.clone().into_bytes()),
+        AgentFileContent::Base64(encoded) => BASE64.decode(encoded.as_bytes()).map_err(|err| {
+            SandboxError::InvalidOperation(format!("invalid base64 file content: {err}"))
+        }),
+    }
+}
+
+fn text(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| {
+        SandboxError::InvalidOperation("cannot patch a file that is not valid UTF-8".to_string())
+    })
+}
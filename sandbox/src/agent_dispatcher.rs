@@ -1,24 +1,41 @@
 use std::collections::{HashMap, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::diff;
 use crate::errors::{Result, SandboxError};
+use crate::fs::SandboxFs;
+use crate::metrics::{self, SandboxMetrics};
+use crate::shard::{LockContention, ShardedMap};
 use async_trait::async_trait;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
 use chrono::{DateTime, Utc};
 use parking_lot::Mutex;
 use reqwest::StatusCode;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, oneshot, Semaphore};
 use tokio::task;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
 const DEFAULT_HISTORY_CAPACITY: usize = 128;
 const DEFAULT_MAX_CONTEXT_BYTES: usize = 512 * 1024; // 512KB
+const DEFAULT_MAX_CONCURRENT_TASKS: usize = 8;
+const DEFAULT_MAX_SUBTASKS: usize = 16;
+const DEFAULT_MAX_SYSTEM_PROMPT_BYTES: usize = 8 * 1024;
+const DEFAULT_MAX_CHECKPOINTS: usize = 3;
+const DEFAULT_INPUT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+const CIRCUIT_PROBE_RETRY: Duration = Duration::from_secs(1);
+const RATE_WINDOW_MINUTE: Duration = Duration::from_secs(60);
+const EVENT_BUFFER: usize = 256;
+const RATE_WINDOW_HOUR: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Debug, Clone)]
 pub struct AgentDispatcherConfig {
@@ -28,6 +45,16 @@ pub struct AgentDispatcherConfig {
     pub history_capacity: usize,
     pub max_context_bytes: usize,
     pub api_key: Option<String>,
+    pub native_tools: bool,
+    pub rate_limit: DispatchRateLimit,
+    pub max_concurrent_tasks: usize,
+    pub max_subtasks: usize,
+    pub max_system_prompt_bytes: usize,
+    pub max_checkpoints: usize,
+    pub input_timeout: Duration,
+    pub circuit_failure_threshold: u32,
+    pub circuit_cooldown: Duration,
+    pub metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl AgentDispatcherConfig {
@@ -39,6 +66,16 @@ impl AgentDispatcherConfig {
             history_capacity: DEFAULT_HISTORY_CAPACITY,
             max_context_bytes: DEFAULT_MAX_CONTEXT_BYTES,
             api_key: None,
+            native_tools: false,
+            rate_limit: DispatchRateLimit::default(),
+            max_concurrent_tasks: DEFAULT_MAX_CONCURRENT_TASKS,
+            max_subtasks: DEFAULT_MAX_SUBTASKS,
+            max_system_prompt_bytes: DEFAULT_MAX_SYSTEM_PROMPT_BYTES,
+            max_checkpoints: DEFAULT_MAX_CHECKPOINTS,
+            input_timeout: DEFAULT_INPUT_TIMEOUT,
+            circuit_failure_threshold: DEFAULT_CIRCUIT_FAILURE_THRESHOLD,
+            circuit_cooldown: DEFAULT_CIRCUIT_COOLDOWN,
+            metrics: metrics::none(),
         }
     }
 
@@ -61,9 +98,156 @@ impl AgentDispatcherConfig {
         self.max_context_bytes = max_context_bytes.max(1024);
         self
     }
+
+    /// Advertise agent actions as OpenAI-style `tools` so models with native
+    /// function calling can return them as `tool_calls`.
+    pub fn with_native_tools(mut self, enabled: bool) -> Self {
+        self.native_tools = enabled;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, per_minute: Option<u32>, per_hour: Option<u32>) -> Self {
+        self.rate_limit = DispatchRateLimit {
+            per_minute: per_minute.filter(|limit| *limit > 0),
+            per_hour: per_hour.filter(|limit| *limit > 0),
+        };
+        self
+    }
+
+    /// Caps how many agent invocations (including fan-out subtasks) run at
+    /// once; further tasks stay pending until a slot frees up.
+    pub fn with_max_concurrency(mut self, max_concurrent_tasks: usize) -> Self {
+        self.max_concurrent_tasks = max_concurrent_tasks.max(1);
+        self
+    }
+
+    pub fn with_max_subtasks(mut self, max_subtasks: usize) -> Self {
+        self.max_subtasks = max_subtasks.max(1);
+        self
+    }
+
+    pub fn with_system_prompt_limit(mut self, max_system_prompt_bytes: usize) -> Self {
+        self.max_system_prompt_bytes = max_system_prompt_bytes.max(256);
+        self
+    }
+
+    /// Limits how often a single task may pause for user input; `0` turns
+    /// checkpoints into plain insights.
+    pub fn with_max_checkpoints(mut self, max_checkpoints: usize) -> Self {
+        self.max_checkpoints = max_checkpoints;
+        self
+    }
+
+    /// How long a task waits for an answer before it completes with the
+    /// question left as an unanswered insight.
+    pub fn with_input_timeout(mut self, input_timeout: Duration) -> Self {
+        self.input_timeout = input_timeout;
+        self
+    }
+
+    /// Opens the LLM circuit after `failure_threshold` consecutive backend
+    /// failures and keeps it open for `cooldown` before letting a probe
+    /// through. A threshold of `0` disables the breaker.
+    pub fn with_circuit_breaker(mut self, failure_threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_failure_threshold = failure_threshold;
+        self.circuit_cooldown = cooldown;
+        self
+    }
+
+    /// Reports the latency of every LLM request of the default agents to
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn SandboxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+/// Per-user dispatch budget enforced over sliding windows. Users are keyed by
+/// the `requested_by_id` metadata field; requests without it are not limited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DispatchRateLimit {
+    pub per_minute: Option<u32>,
+    pub per_hour: Option<u32>,
+}
+
+impl DispatchRateLimit {
+    fn is_enabled(&self) -> bool {
+        self.per_minute.is_some() || self.per_hour.is_some()
+    }
+
+    fn retention(&self) -> Duration {
+        if self.per_hour.is_some() {
+            RATE_WINDOW_HOUR
+        } else {
+            RATE_WINDOW_MINUTE
+        }
+    }
+}
+
+struct DispatchRateLimiter {
+    limits: DispatchRateLimit,
+    windows: ShardedMap<String, VecDeque<Instant>>,
+}
+
+impl DispatchRateLimiter {
+    fn new(limits: DispatchRateLimit) -> Self {
+        Self {
+            limits,
+            windows: ShardedMap::new("agent_rate_windows"),
+        }
+    }
+
+    fn acquire(&self, key: &str, now: Instant) -> Result<()> {
+        if !self.limits.is_enabled() {
+            return Ok(());
+        }
+        let retention = self.limits.retention();
+        let checks = [
+            (self.limits.per_minute, RATE_WINDOW_MINUTE),
+            (self.limits.per_hour, RATE_WINDOW_HOUR),
+        ];
+        self.windows.with_entry(key.to_string(), |window| {
+            while let Some(oldest) = window.front() {
+                if now.duration_since(*oldest) >= retention {
+                    window.pop_front();
+                } else {
+                    break;
+                }
+            }
+            for (limit, span) in checks {
+                let Some(limit) = limit else {
+                    continue;
+                };
+                let mut in_span = window
+                    .iter()
+                    .filter(|instant| now.duration_since(**instant) < span);
+                let oldest = in_span.next().copied();
+                let count = oldest.map(|_| 1 + in_span.count()).unwrap_or(0);
+                if count >= limit as usize {
+                    let elapsed = oldest
+                        .map(|instant| now.duration_since(instant))
+                        .unwrap_or_default();
+                    return Err(SandboxError::RateLimited {
+                        retry_after: span.saturating_sub(elapsed),
+                    });
+                }
+            }
+            window.push_back(now);
+            Ok(())
+        })
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
+fn rate_limit_key(metadata: Option<&Value>) -> Option<String> {
+    let value = metadata?.get("requested_by_id")?;
+    match value {
+        Value::Number(number) => Some(number.to_string()),
+        Value::String(text) if !text.trim().is_empty() => Some(text.trim().to_string()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum AgentKind {
     Code,
@@ -187,6 +371,53 @@ pub struct AgentDispatchRequest {
     pub metadata: Option<Value>,
     #[serde(default)]
     pub parameters: Option<AgentParameters>,
+    /// When non-empty the dispatch fans out into one child task per entry,
+    /// run in parallel, with their results aggregated into the parent task.
+    #[serde(default)]
+    pub subtasks: Vec<AgentSubtask>,
+    /// Replaces the agent's built-in system prompt for this dispatch only.
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub persona: Option<AgentPersona>,
+    /// W3C `traceparent` of the caller, forwarded on the task's LLM calls.
+    #[serde(default)]
+    pub traceparent: Option<String>,
+}
+
+/// Tone variants layered on top of the system prompt without replacing it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentPersona {
+    Concise,
+    Thorough,
+    Mentor,
+}
+
+impl AgentPersona {
+    fn instructions(self) -> &'static str {
+        match self {
+            AgentPersona::Concise => {
+                "Keep the summary to one or two sentences and only list insights that require action."
+            }
+            AgentPersona::Thorough => {
+                "Be exhaustive: cover edge cases, explain trade-offs, and justify every proposed action."
+            }
+            AgentPersona::Mentor => {
+                "Explain your reasoning as you would to a junior engineer, including why each action is needed."
+            }
+        }
+    }
+}
+
+/// A slice of a fan-out dispatch. The subtask context is appended to the
+/// parent context; the parent objective applies when none is given.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AgentSubtask {
+    #[serde(default)]
+    pub objective: Option<String>,
+    #[serde(default)]
+    pub context: AgentContext,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -230,13 +461,19 @@ pub enum AgentAction {
         #[serde(default)]
         args: Vec<String>,
     },
+    /// Pauses the task until the user answers `question` via
+    /// [`AgentDispatcher::respond`].
+    Checkpoint {
+        question: String,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum AgentTaskStatus {
     Pending,
     Running,
+    WaitingForInput,
     Completed,
     Failed,
     Cancelled,
@@ -251,6 +488,36 @@ impl AgentTaskStatus {
     }
 }
 
+/// What [`AgentDispatcher::subscribe`] receivers get.
+#[derive(Debug, Clone)]
+pub enum AgentEvent {
+    /// The task changed status, starting with `Pending`.
+    Status(Box<AgentTaskSnapshot>),
+    /// Output of a task that is still running.
+    Output(AgentTaskOutput),
+}
+
+impl AgentEvent {
+    pub fn task_id(&self) -> Uuid {
+        match self {
+            AgentEvent::Status(snapshot) => snapshot.id,
+            AgentEvent::Output(output) => output.task_id,
+        }
+    }
+}
+
+/// The response of one agent round, sent before the task waits for input or
+/// finishes, or, on a fan-out parent, the result of a finished subtask.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentTaskOutput {
+    pub task_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subtask_id: Option<Uuid>,
+    pub summary: String,
+    /// The raw model response; empty for failed subtasks.
+    pub text: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentTaskSnapshot {
     pub id: Uuid,
@@ -261,7 +528,7 @@ pub struct AgentTaskSnapshot {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub summary: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    pub error: Option<AgentTaskError>,
     pub created_at: DateTime<Utc>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub started_at: Option<DateTime<Utc>>,
@@ -272,6 +539,137 @@ pub struct AgentTaskSnapshot {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Value>,
     pub parameters: AgentParameters,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<AgentFilePreview>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<AgentPersona>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub system_prompt_overridden: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_question: Option<String>,
+}
+
+/// Machine-readable failure attached to a failed task so clients can branch
+/// on `kind` rather than matching on `message`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AgentTaskError {
+    pub kind: AgentFailureKind,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentFailureKind {
+    LlmHttp,
+    Parse,
+    Tool,
+    Timeout,
+    ContextOverflow,
+    Network,
+    CircuitOpen,
+    Subtasks,
+    Internal,
+}
+
+impl AgentTaskError {
+    fn new(kind: AgentFailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            http_status: None,
+        }
+    }
+}
+
+impl From<&SandboxError> for AgentTaskError {
+    fn from(err: &SandboxError) -> Self {
+        let kind = match err {
+            SandboxError::LlmHttp { status, .. } => {
+                return Self {
+                    http_status: Some(*status),
+                    ..Self::new(AgentFailureKind::LlmHttp, err.to_string())
+                };
+            }
+            SandboxError::LlmParse(_) => AgentFailureKind::Parse,
+            SandboxError::ToolFailed(_) => AgentFailureKind::Tool,
+            SandboxError::LlmTimeout(_) | SandboxError::Timeout(_) => AgentFailureKind::Timeout,
+            SandboxError::LlmContextOverflow(_) | SandboxError::ContextTooLarge { .. } => {
+                AgentFailureKind::ContextOverflow
+            }
+            SandboxError::Network(_) => AgentFailureKind::Network,
+            SandboxError::CircuitOpen { .. } => AgentFailureKind::CircuitOpen,
+            _ => AgentFailureKind::Internal,
+        };
+        Self::new(kind, err.to_string())
+    }
+}
+
+/// "What will change" view of a file action, computed against the workspace
+/// when the task finishes. `diff` is absent when the change cannot be
+/// rendered as text; `error` then explains why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentFilePreview {
+    pub path: String,
+    pub is_new: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AgentHistoryQuery {
+    pub agent: Option<AgentKind>,
+    pub status: Option<AgentTaskStatus>,
+    pub requested_by_id: Option<i64>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub cursor: Option<Uuid>,
+    pub limit: usize,
+    /// Serialized size a page may reach; it ends early past it, but always
+    /// holds at least one entry.
+    pub max_bytes: Option<usize>,
+}
+
+impl AgentHistoryQuery {
+    fn matches(&self, snapshot: &AgentTaskSnapshot) -> bool {
+        if self.agent.is_some_and(|agent| agent != snapshot.agent) {
+            return false;
+        }
+        if self.status.is_some_and(|status| status != snapshot.status) {
+            return false;
+        }
+        if let Some(user_id) = self.requested_by_id {
+            let requester = snapshot
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get("requested_by_id"))
+                .and_then(Value::as_i64);
+            if requester != Some(user_id) {
+                return false;
+            }
+        }
+        if self.since.is_some_and(|since| snapshot.created_at < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| snapshot.created_at >= until) {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentHistoryPage {
+    pub entries: Vec<AgentTaskSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -297,9 +695,16 @@ struct AgentTaskState {
     started_at: Option<DateTime<Utc>>,
     finished_at: Option<DateTime<Utc>>,
     outcome: Option<AgentOutcome>,
-    error: Option<String>,
+    error: Option<AgentTaskError>,
     metadata: Option<Value>,
     parameters: AgentParameters,
+    previews: Vec<AgentFilePreview>,
+    parent_id: Option<Uuid>,
+    children: Vec<Uuid>,
+    persona: Option<AgentPersona>,
+    system_prompt_overridden: bool,
+    pending_question: Option<String>,
+    responder: Option<oneshot::Sender<String>>,
 }
 
 impl AgentTaskState {
@@ -324,6 +729,13 @@ impl AgentTaskState {
             error: None,
             metadata,
             parameters,
+            previews: Vec::new(),
+            parent_id: None,
+            children: Vec::new(),
+            persona: None,
+            system_prompt_overridden: false,
+            pending_question: None,
+            responder: None,
         }
     }
 
@@ -342,6 +754,12 @@ impl AgentTaskState {
             outcome: self.outcome.clone(),
             metadata: self.metadata.clone(),
             parameters: self.parameters.clone(),
+            previews: self.previews.clone(),
+            parent_id: self.parent_id,
+            children: self.children.clone(),
+            persona: self.persona,
+            system_prompt_overridden: self.system_prompt_overridden,
+            pending_question: self.pending_question.clone(),
         }
     }
 }
@@ -365,6 +783,9 @@ pub struct AgentInvocation {
     pub model: String,
     pub metadata: Option<Value>,
     pub parameters: AgentParameters,
+    pub system_prompt: Option<String>,
+    pub persona: Option<AgentPersona>,
+    pub traceparent: Option<String>,
 }
 
 #[async_trait]
@@ -377,12 +798,25 @@ trait Agent: Send + Sync {
     ) -> Result<AgentOutcome>;
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AgentTaskCounts {
+    pub pending: usize,
+    pub running: usize,
+    pub waiting_for_input: usize,
+}
+
 #[derive(Clone)]
 pub struct AgentDispatcher {
     config: AgentDispatcherConfig,
     agents: HashMap<AgentKind, Arc<dyn Agent>>, // each entry already inside Arc
-    tasks: Arc<Mutex<HashMap<Uuid, AgentTaskEntry>>>,
+    /// Live tasks, sharded so status polls and cancellations of different
+    /// tasks do not queue behind each other.
+    tasks: Arc<ShardedMap<Uuid, AgentTaskEntry>>,
     history: Arc<Mutex<VecDeque<AgentTaskSnapshot>>>,
+    limiter: Arc<DispatchRateLimiter>,
+    permits: Arc<Semaphore>,
+    workspace: Option<Arc<SandboxFs>>,
+    events: broadcast::Sender<AgentEvent>,
 }
 
 impl AgentDispatcher {
@@ -391,8 +825,10 @@ impl AgentDispatcher {
             config.llm_endpoint.clone(),
             config.request_timeout,
             config.api_key.clone(),
+            CircuitBreaker::new(config.circuit_failure_threshold, config.circuit_cooldown),
+            config.metrics.clone(),
         )?);
-        let agents = default_agents(client, config.default_model.clone());
+        let agents = default_agents(client, config.default_model.clone(), config.native_tools);
         Self::with_agents(config, agents)
     }
 
@@ -405,14 +841,26 @@ impl AgentDispatcher {
                 "agent dispatcher requires at least one agent".to_string(),
             ));
         }
+        let limiter = Arc::new(DispatchRateLimiter::new(config.rate_limit));
+        let permits = Arc::new(Semaphore::new(config.max_concurrent_tasks));
         Ok(Self {
             config,
             agents,
-            tasks: Arc::new(Mutex::new(HashMap::new())),
+            tasks: Arc::new(ShardedMap::new("agent_tasks")),
             history: Arc::new(Mutex::new(VecDeque::new())),
+            limiter,
+            permits,
+            workspace: None,
+            events: broadcast::channel(EVENT_BUFFER).0,
         })
     }
 
+    /// Enables diff previews for file actions, resolved against `workspace`.
+    pub fn with_workspace(mut self, workspace: Arc<SandboxFs>) -> Self {
+        self.workspace = Some(workspace);
+        self
+    }
+
     pub fn dispatch(&self, request: AgentDispatchRequest) -> Result<AgentTaskSubmission> {
         if request.objective.trim().is_empty() {
             return Err(SandboxError::InvalidOperation(
@@ -426,7 +874,19 @@ impl AgentDispatcher {
             .cloned()
             .ok_or_else(|| SandboxError::AgentUnavailable(request.agent.to_string()))?;
 
-        let context_size = request.context.total_bytes()?;
+        if request.subtasks.len() > self.config.max_subtasks {
+            return Err(SandboxError::InvalidOperation(format!(
+                "at most {} subtasks may be dispatched at once",
+                self.config.max_subtasks
+            )));
+        }
+
+        let mut context_size = request.context.total_bytes()?;
+        for subtask in &request.subtasks {
+            context_size = context_size
+                .checked_add(subtask.context.total_bytes()?)
+                .ok_or_else(|| SandboxError::InvalidOperation("context too large".to_string()))?;
+        }
         if context_size > self.config.max_context_bytes {
             return Err(SandboxError::ContextTooLarge {
                 provided: context_size,
@@ -434,29 +894,29 @@ impl AgentDispatcher {
             });
         }
 
+        if let Some(prompt) = &request.system_prompt {
+            if prompt.trim().is_empty() {
+                return Err(SandboxError::InvalidOperation(
+                    "system prompt override must not be empty".to_string(),
+                ));
+            }
+            if prompt.len() > self.config.max_system_prompt_bytes {
+                return Err(SandboxError::InvalidOperation(format!(
+                    "system prompt override exceeds {} bytes",
+                    self.config.max_system_prompt_bytes
+                )));
+            }
+        }
+
+        if let Some(key) = rate_limit_key(request.metadata.as_ref()) {
+            self.limiter.acquire(&key, Instant::now())?;
+        }
+
         let parameters = request.parameters.unwrap_or_default();
         let id = Uuid::new_v4();
         let model = request
             .model
             .unwrap_or_else(|| self.config.default_model.clone());
-        let state = Arc::new(Mutex::new(AgentTaskState::new(
-            id,
-            request.agent,
-            request.objective.clone(),
-            model.clone(),
-            request.metadata.clone(),
-            parameters.clone(),
-        )));
-        let entry = AgentTaskEntry {
-            agent: request.agent,
-            state: state.clone(),
-            cancellation: CancellationToken::new(),
-        };
-        self.tasks.lock().insert(id, entry.clone());
-
-        let tasks_map = self.tasks.clone();
-        let history = self.history.clone();
-        let history_capacity = self.config.history_capacity;
         let invocation = AgentInvocation {
             id,
             agent: request.agent,
@@ -465,84 +925,358 @@ impl AgentDispatcher {
             model,
             metadata: request.metadata,
             parameters,
+            system_prompt: request.system_prompt,
+            persona: request.persona,
+            traceparent: request.traceparent,
+        };
+        let cancellation = CancellationToken::new();
+        let state = self.register_task(&invocation, None, cancellation.clone());
+
+        if request.subtasks.is_empty() {
+            self.spawn_agent_task(agent_impl, state.clone(), invocation, cancellation);
+        } else {
+            let mut children = Vec::with_capacity(request.subtasks.len());
+            for subtask in request.subtasks {
+                let mut context = invocation.context.clone();
+                context.notes.extend(subtask.context.notes);
+                context.files.extend(subtask.context.files);
+                let child = AgentInvocation {
+                    id: Uuid::new_v4(),
+                    objective: subtask
+                        .objective
+                        .filter(|objective| !objective.trim().is_empty())
+                        .unwrap_or_else(|| invocation.objective.clone()),
+                    context,
+                    ..invocation.clone()
+                };
+                let child_cancellation = cancellation.child_token();
+                let child_state = self.register_task(&child, Some(id), child_cancellation.clone());
+                children.push((child, child_state, child_cancellation));
+            }
+            state.lock().children = children.iter().map(|(child, _, _)| child.id).collect();
+
+            let handles = children
+                .into_iter()
+                .map(|(child, child_state, child_cancellation)| {
+                    let handle = self.spawn_agent_task(
+                        agent_impl.clone(),
+                        child_state.clone(),
+                        child,
+                        child_cancellation,
+                    );
+                    (child_state, handle)
+                })
+                .collect();
+            self.spawn_fan_out(state.clone(), handles);
+        }
+
+        let snapshot = state.lock().snapshot();
+        Ok(AgentTaskSubmission {
+            id,
+            status: snapshot,
+        })
+    }
+
+    fn register_task(
+        &self,
+        invocation: &AgentInvocation,
+        parent_id: Option<Uuid>,
+        cancellation: CancellationToken,
+    ) -> Arc<Mutex<AgentTaskState>> {
+        let mut state = AgentTaskState::new(
+            invocation.id,
+            invocation.agent,
+            invocation.objective.clone(),
+            invocation.model.clone(),
+            invocation.metadata.clone(),
+            invocation.parameters.clone(),
+        );
+        state.parent_id = parent_id;
+        state.persona = invocation.persona;
+        state.system_prompt_overridden = invocation.system_prompt.is_some();
+        let state = Arc::new(Mutex::new(state));
+        let entry = AgentTaskEntry {
+            agent: invocation.agent,
+            state: state.clone(),
+            cancellation,
         };
-        let state_for_task = state.clone();
-        let cancellation = entry.cancellation.clone();
+        self.tasks.insert(invocation.id, entry);
+        announce(&self.events, &state.lock());
+        state
+    }
+
+    /// Status changes and output of every task. Receivers that fall more
+    /// than a few hundred events behind skip ahead.
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Runs a single agent invocation once a concurrency permit is available.
+    fn spawn_agent_task(
+        &self,
+        agent_impl: Arc<dyn Agent>,
+        state: Arc<Mutex<AgentTaskState>>,
+        invocation: AgentInvocation,
+        cancellation: CancellationToken,
+    ) -> task::JoinHandle<()> {
+        let tasks_map = self.tasks.clone();
+        let history = self.history.clone();
+        let history_capacity = self.config.history_capacity;
+        let workspace = self.workspace.clone();
+        let permits = self.permits.clone();
+        let events = self.events.clone();
+        let max_checkpoints = self.config.max_checkpoints;
+        let input_timeout = self.config.input_timeout;
+        // Created here so the task span is a child of the dispatching request.
+        let span = info_span!("agent_task", task_id = %invocation.id, agent = %invocation.agent);
+        task::spawn(
+            async move {
+                let mut permit = tokio::select! {
+                    permit = permits.clone().acquire_owned() => permit.ok(),
+                    _ = cancellation.cancelled() => None,
+                };
+                {
+                    let mut guard = state.lock();
+                    if guard.status == AgentTaskStatus::Pending && !cancellation.is_cancelled() {
+                        guard.status = AgentTaskStatus::Running;
+                        guard.started_at = Some(Utc::now());
+                        announce(&events, &guard);
+                    }
+                }
+                let mut invocation = invocation;
+                let mut checkpoints = 0usize;
+                let outcome = loop {
+                    if cancellation.is_cancelled() {
+                        break Err(SandboxError::Cancelled);
+                    }
+                    let mut result = match agent_impl
+                        .execute(invocation.clone(), cancellation.clone())
+                        .await
+                    {
+                        Ok(result) => result,
+                        Err(err) => break Err(err),
+                    };
+                    let question = take_checkpoint(&mut result);
+                    publish(&events, || {
+                        AgentEvent::Output(AgentTaskOutput {
+                            task_id: invocation.id,
+                            subtask_id: None,
+                            summary: result.summary.clone(),
+                            text: result.raw_response.clone(),
+                        })
+                    });
+                    let Some(question) = question else {
+                        break Ok(result);
+                    };
+                    if checkpoints >= max_checkpoints {
+                        result
+                            .insights
+                            .push(format!("unanswered clarification: {question}"));
+                        break Ok(result);
+                    }
+                    checkpoints += 1;
+
+                    let (sender, receiver) = oneshot::channel();
+                    {
+                        let mut guard = state.lock();
+                        if guard.status == AgentTaskStatus::Cancelled {
+                            break Err(SandboxError::Cancelled);
+                        }
+                        guard.status = AgentTaskStatus::WaitingForInput;
+                        guard.pending_question = Some(question.clone());
+                        guard.responder = Some(sender);
+                        announce(&events, &guard);
+                    }
+                    // Waiting on a human must not hold a concurrency slot.
+                    drop(permit.take());
+                    let answer = tokio::select! {
+                        answer = tokio::time::timeout(input_timeout, receiver) => {
+                            answer.map(|answer| answer.ok())
+                        }
+                        _ = cancellation.cancelled() => Ok(None),
+                    };
+                    let answer = match answer {
+                        Ok(Some(answer)) => answer,
+                        Ok(None) => break Err(SandboxError::Cancelled),
+                        // Nobody answered in time; finish with what we have.
+                        Err(_) => {
+                            let mut guard = state.lock();
+                            guard.pending_question = None;
+                            guard.responder = None;
+                            drop(guard);
+                            result
+                                .insights
+                                .push(format!("unanswered clarification: {question}"));
+                            break Ok(result);
+                        }
+                    };
+                    permit = tokio::select! {
+                        permit = permits.clone().acquire_owned() => permit.ok(),
+                        _ = cancellation.cancelled() => None,
+                    };
+                    invocation.context.notes.push(format!(
+                        "Clarification requested: {question}\nUser answer: {answer}"
+                    ));
+                };
+                drop(permit);
+                let previews = match (&outcome, &workspace) {
+                    (Ok(result), Some(workspace)) => {
+                        build_file_previews(workspace, &result.actions)
+                    }
+                    _ => Vec::new(),
+                };
+                let mut guard = state.lock();
+                if guard.status == AgentTaskStatus::Cancelled {
+                    guard.finished_at.get_or_insert_with(Utc::now);
+                } else {
+                    match outcome {
+                        Ok(result) => {
+                            guard.status = AgentTaskStatus::Completed;
+                            guard.finished_at = Some(Utc::now());
+                            guard.outcome = Some(result);
+                            guard.previews = previews;
+                        }
+                        Err(err) => match err {
+                            SandboxError::Cancelled => {
+                                guard.status = AgentTaskStatus::Cancelled;
+                                guard.finished_at = Some(Utc::now());
+                            }
+                            other => {
+                                guard.status = AgentTaskStatus::Failed;
+                                guard.finished_at = Some(Utc::now());
+                                guard.error = Some(AgentTaskError::from(&other));
+                            }
+                        },
+                    }
+                }
+                let snapshot = guard.snapshot();
+                drop(guard);
+
+                retire_task(&tasks_map, &history, history_capacity, &events, snapshot);
+            }
+            .instrument(span),
+        )
+    }
+
+    /// Waits for every subtask of a fan-out dispatch and folds their results
+    /// into the parent task. The parent itself holds no concurrency permit.
+    fn spawn_fan_out(
+        &self,
+        state: Arc<Mutex<AgentTaskState>>,
+        children: Vec<(Arc<Mutex<AgentTaskState>>, task::JoinHandle<()>)>,
+    ) {
+        let tasks_map = self.tasks.clone();
+        let history = self.history.clone();
+        let history_capacity = self.config.history_capacity;
+        let events = self.events.clone();
         task::spawn(async move {
             {
-                let mut guard = state_for_task.lock();
+                let mut guard = state.lock();
                 if guard.status == AgentTaskStatus::Pending {
                     guard.status = AgentTaskStatus::Running;
                     guard.started_at = Some(Utc::now());
+                    announce(&events, &guard);
                 }
             }
-            let outcome = agent_impl.execute(invocation, cancellation.clone()).await;
-            let mut guard = state_for_task.lock();
+            let parent_id = state.lock().id;
+            let mut snapshots = Vec::with_capacity(children.len());
+            for (child, handle) in children {
+                if let Err(err) = handle.await {
+                    error!(error = %err, "agent subtask terminated unexpectedly");
+                }
+                let snapshot = child.lock().snapshot();
+                publish(&events, || {
+                    AgentEvent::Output(subtask_output(parent_id, &snapshot))
+                });
+                snapshots.push(snapshot);
+            }
+
+            let mut guard = state.lock();
             if guard.status == AgentTaskStatus::Cancelled {
                 guard.finished_at.get_or_insert_with(Utc::now);
             } else {
-                match outcome {
-                    Ok(result) => {
+                match aggregate_subtasks(&snapshots) {
+                    Ok((outcome, previews)) => {
                         guard.status = AgentTaskStatus::Completed;
-                        guard.finished_at = Some(Utc::now());
-                        guard.outcome = Some(result);
+                        guard.outcome = Some(outcome);
+                        guard.previews = previews;
+                    }
+                    Err(message) => {
+                        guard.status = AgentTaskStatus::Failed;
+                        guard.error =
+                            Some(AgentTaskError::new(AgentFailureKind::Subtasks, message));
                     }
-                    Err(err) => match err {
-                        SandboxError::Cancelled => {
-                            guard.status = AgentTaskStatus::Cancelled;
-                            guard.finished_at = Some(Utc::now());
-                        }
-                        other => {
-                            guard.status = AgentTaskStatus::Failed;
-                            guard.finished_at = Some(Utc::now());
-                            guard.error = Some(other.to_string());
-                        }
-                    },
                 }
+                guard.finished_at = Some(Utc::now());
             }
             let snapshot = guard.snapshot();
             drop(guard);
 
-            let mut tasks_guard = tasks_map.lock();
-            tasks_guard.remove(&snapshot.id);
-            drop(tasks_guard);
-
-            let mut history_guard = history.lock();
-            history_guard.push_back(snapshot.clone());
-            while history_guard.len() > history_capacity {
-                history_guard.pop_front();
-            }
+            retire_task(&tasks_map, &history, history_capacity, &events, snapshot);
         });
+    }
 
-        let snapshot = state.lock().snapshot();
-        Ok(AgentTaskSubmission {
-            id,
-            status: snapshot,
-        })
+    /// Answers the clarification question of a task in `WaitingForInput`
+    /// and resumes it.
+    pub fn respond(&self, id: &Uuid, answer: impl Into<String>) -> Result<AgentTaskSnapshot> {
+        let answer = answer.into();
+        if answer.trim().is_empty() {
+            return Err(SandboxError::InvalidOperation(
+                "answer must not be empty".to_string(),
+            ));
+        }
+        let entry = self
+            .tasks
+            .get_cloned(id)
+            .ok_or_else(|| SandboxError::AgentTaskNotFound(id.to_string()))?;
+        let mut state = entry.state.lock();
+        if state.status != AgentTaskStatus::WaitingForInput {
+            return Err(SandboxError::InvalidOperation(
+                "agent task is not waiting for input".to_string(),
+            ));
+        }
+        let responder = state.responder.take().ok_or_else(|| {
+            SandboxError::InvalidOperation("agent task is not waiting for input".to_string())
+        })?;
+        responder
+            .send(answer)
+            .map_err(|_| SandboxError::Cancelled)?;
+        state.status = AgentTaskStatus::Running;
+        state.pending_question = None;
+        announce(&self.events, &state);
+        Ok(state.snapshot())
+    }
+
+    /// Cancels every active task, e.g. on shutdown. Returns how many were
+    /// still running.
+    pub fn cancel_all(&self) -> usize {
+        let ids = self.tasks.keys_where(|_| true);
+        ids.iter().filter(|id| self.cancel(id).is_ok()).count()
     }
 
     pub fn cancel(&self, id: &Uuid) -> Result<AgentTaskSnapshot> {
-        let entry = {
-            let guard = self.tasks.lock();
-            guard
-                .get(id)
-                .cloned()
-                .ok_or_else(|| SandboxError::AgentTaskNotFound(id.to_string()))?
-        };
+        let entry = self
+            .tasks
+            .get_cloned(id)
+            .ok_or_else(|| SandboxError::AgentTaskNotFound(id.to_string()))?;
         entry.cancellation.cancel();
-        {
+        let (snapshot, children) = {
             let mut state = entry.state.lock();
-            if state.status.is_terminal() {
-                return Ok(state.snapshot());
+            if !state.status.is_terminal() {
+                state.status = AgentTaskStatus::Cancelled;
+                state.finished_at = Some(Utc::now());
             }
-            state.status = AgentTaskStatus::Cancelled;
-            state.finished_at = Some(Utc::now());
-            Ok(state.snapshot())
+            (state.snapshot(), state.children.clone())
+        };
+        for child in children {
+            // Children that already finished have left the task table.
+            let _ = self.cancel(&child);
         }
+        Ok(snapshot)
     }
 
     pub fn status(&self, id: &Uuid) -> Option<AgentTaskSnapshot> {
-        if let Some(entry) = self.tasks.lock().get(id) {
+        if let Some(entry) = self.tasks.get_cloned(id) {
             return Some(entry.state.lock().snapshot());
         }
         self.history
@@ -558,6 +1292,82 @@ impl AgentDispatcher {
         guard.iter().rev().take(limit).cloned().collect()
     }
 
+    /// Returns finished tasks newest-first, filtered by `query`. The returned
+    /// cursor is the id of the last entry and resumes the listing after it.
+    pub fn history_page(&self, query: &AgentHistoryQuery) -> Result<AgentHistoryPage> {
+        let guard = self.history.lock();
+        let mut iter = guard.iter().rev().peekable();
+        if let Some(cursor) = query.cursor {
+            if !guard.iter().any(|snapshot| snapshot.id == cursor) {
+                return Err(SandboxError::InvalidOperation(
+                    "history cursor is no longer available".to_string(),
+                ));
+            }
+            for snapshot in iter.by_ref() {
+                if snapshot.id == cursor {
+                    break;
+                }
+            }
+        }
+        let limit = query.limit.max(1);
+        let mut entries = Vec::new();
+        let mut bytes = 0usize;
+        let mut has_more = false;
+        for snapshot in iter.filter(|snapshot| query.matches(snapshot)) {
+            if entries.len() == limit {
+                has_more = true;
+                break;
+            }
+            if let Some(max_bytes) = query.max_bytes {
+                bytes += serde_json::to_vec(snapshot).map_or(0, |encoded| encoded.len());
+                if bytes > max_bytes && !entries.is_empty() {
+                    has_more = true;
+                    break;
+                }
+            }
+            entries.push(snapshot.clone());
+        }
+        let next_cursor = if has_more {
+            entries.last().map(|snapshot| snapshot.id)
+        } else {
+            None
+        };
+        Ok(AgentHistoryPage {
+            entries,
+            next_cursor,
+        })
+    }
+
+    /// Drops finished tasks from the history that ended before `cutoff` and
+    /// returns how many went.
+    pub fn prune_history(&self, cutoff: DateTime<Utc>) -> usize {
+        let mut guard = self.history.lock();
+        let before = guard.len();
+        guard.retain(|snapshot| !matches!(snapshot.finished_at, Some(at) if at < cutoff));
+        before - guard.len()
+    }
+
+    /// Counts live tasks by status. Pending tasks are queued for a
+    /// concurrency permit.
+    pub fn task_counts(&self) -> AgentTaskCounts {
+        let entries = self.tasks.values_cloned();
+        let mut counts = AgentTaskCounts::default();
+        for entry in entries {
+            match entry.state.lock().status {
+                AgentTaskStatus::Pending => counts.pending += 1,
+                AgentTaskStatus::Running => counts.running += 1,
+                AgentTaskStatus::WaitingForInput => counts.waiting_for_input += 1,
+                _ => {}
+            }
+        }
+        counts
+    }
+
+    /// Lock statistics of the task table and the per-user rate windows.
+    pub fn lock_contention(&self) -> Vec<LockContention> {
+        vec![self.tasks.contention(), self.limiter.windows.contention()]
+    }
+
     pub fn list_agents(&self) -> Vec<AgentMetadata> {
         let mut entries: Vec<_> = self.agents.values().map(|agent| agent.metadata()).collect();
         entries.sort_by_key(|meta| meta.agent);
@@ -565,14 +1375,271 @@ impl AgentDispatcher {
     }
 }
 
-struct LlmClient {
-    http: reqwest::Client,
-    base_url: String,
+/// Strips checkpoint actions from `outcome`, returning the first question.
+fn take_checkpoint(outcome: &mut AgentOutcome) -> Option<String> {
+    let mut question = None;
+    outcome.actions.retain(|action| match action {
+        AgentAction::Checkpoint { question: asked } => {
+            question.get_or_insert_with(|| asked.clone());
+            false
+        }
+        _ => true,
+    });
+    question
+}
+
+fn retire_task(
+    tasks: &ShardedMap<Uuid, AgentTaskEntry>,
+    history: &Mutex<VecDeque<AgentTaskSnapshot>>,
+    capacity: usize,
+    events: &broadcast::Sender<AgentEvent>,
+    snapshot: AgentTaskSnapshot,
+) {
+    tasks.remove(&snapshot.id);
+    publish(events, || AgentEvent::Status(Box::new(snapshot.clone())));
+
+    let mut history_guard = history.lock();
+    history_guard.push_back(snapshot);
+    while history_guard.len() > capacity {
+        history_guard.pop_front();
+    }
+}
+
+/// Publishes the current state of a task to `subscribe` receivers.
+fn announce(events: &broadcast::Sender<AgentEvent>, state: &AgentTaskState) {
+    publish(events, || AgentEvent::Status(Box::new(state.snapshot())));
+}
+
+/// Builds the event only when someone listens.
+fn publish(events: &broadcast::Sender<AgentEvent>, event: impl FnOnce() -> AgentEvent) {
+    if events.receiver_count() > 0 {
+        let _ = events.send(event());
+    }
+}
+
+fn subtask_output(parent_id: Uuid, child: &AgentTaskSnapshot) -> AgentTaskOutput {
+    let (summary, text) = match (&child.outcome, &child.error) {
+        (Some(outcome), _) => (outcome.summary.clone(), outcome.raw_response.clone()),
+        (None, Some(error)) => (error.message.clone(), String::new()),
+        (None, None) => ("subtask cancelled".to_string(), String::new()),
+    };
+    AgentTaskOutput {
+        task_id: parent_id,
+        subtask_id: Some(child.id),
+        summary,
+        text,
+    }
+}
+
+/// Merges finished subtasks into a parent outcome. The parent only fails when
+/// no subtask completed; individual failures are reported as insights.
+fn aggregate_subtasks(
+    children: &[AgentTaskSnapshot],
+) -> std::result::Result<(AgentOutcome, Vec<AgentFilePreview>), String> {
+    let mut outcome = AgentOutcome::default();
+    let mut previews = Vec::new();
+    let mut lines = Vec::new();
+    for child in children {
+        match (&child.status, &child.outcome) {
+            (AgentTaskStatus::Completed, Some(result)) => {
+                lines.push(format!("- [{}] {}", child.id, result.summary));
+                outcome.insights.extend(result.insights.iter().cloned());
+                outcome.actions.extend(result.actions.iter().cloned());
+                previews.extend(child.previews.iter().cloned());
+            }
+            (status, _) => {
+                let reason = child
+                    .error
+                    .as_ref()
+                    .map(|error| error.message.clone())
+                    .unwrap_or_else(|| format!("{status:?}").to_lowercase());
+                outcome
+                    .insights
+                    .push(format!("subtask {} did not complete: {reason}", child.id));
+            }
+        }
+    }
+    if lines.is_empty() {
+        return Err(format!("all {} subtasks failed", children.len()));
+    }
+    outcome.summary = format!(
+        "{} of {} subtasks completed\n{}",
+        lines.len(),
+        children.len(),
+        lines.join("\n")
+    );
+    Ok((outcome, previews))
+}
+
+fn build_file_previews(workspace: &SandboxFs, actions: &[AgentAction]) -> Vec<AgentFilePreview> {
+    actions
+        .iter()
+        .filter_map(|action| match action {
+            AgentAction::FileWrite { path, content } => {
+                Some(preview_file_write(workspace, path, content))
+            }
+            AgentAction::FilePatch { path, patch } => {
+                Some(preview_file_patch(workspace, path, patch))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_workspace_text(workspace: &SandboxFs, path: &str) -> Result<Option<String>> {
+    match workspace.read(path) {
+        Ok(bytes) => String::from_utf8(bytes).map(Some).map_err(|_| {
+            SandboxError::InvalidOperation("current file is not valid UTF-8".to_string())
+        }),
+        Err(SandboxError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn preview_file_write(
+    workspace: &SandboxFs,
+    path: &str,
+    content: &AgentFileContent,
+) -> AgentFilePreview {
+    let mut preview = AgentFilePreview {
+        path: path.to_string(),
+        is_new: false,
+        diff: None,
+        error: None,
+    };
+    let proposed = match content {
+        AgentFileContent::Utf8(text) => text.clone(),
+        AgentFileContent::Base64(encoded) => match BASE64
+            .decode(encoded.as_bytes())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+        {
+            Some(text) => text,
+            None => {
+                preview.error = Some("proposed content is binary".to_string());
+                return preview;
+            }
+        },
+    };
+    match read_workspace_text(workspace, path) {
+        Ok(current) => {
+            preview.is_new = current.is_none();
+            let current = current.unwrap_or_default();
+            preview.diff = Some(diff::unified_diff(path, &current, &proposed));
+        }
+        Err(err) => preview.error = Some(err.to_string()),
+    }
+    preview
+}
+
+fn preview_file_patch(workspace: &SandboxFs, path: &str, patch: &str) -> AgentFilePreview {
+    let mut preview = AgentFilePreview {
+        path: path.to_string(),
+        is_new: false,
+        diff: None,
+        error: None,
+    };
+    let result = read_workspace_text(workspace, path).and_then(|current| {
+        preview.is_new = current.is_none();
+        let current = current.unwrap_or_default();
+        let patched = diff::apply_patch(&current, patch)?;
+        Ok(diff::unified_diff(path, &current, &patched))
+    });
+    match result {
+        Ok(rendered) => preview.diff = Some(rendered),
+        Err(err) => preview.error = Some(err.to_string()),
+    }
+    preview
+}
+
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+/// Fails LLM calls fast while the backend is unhealthy. Only transport
+/// errors, timeouts and 5xx/429 responses count as failures.
+struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    fn acquire(&self, now: Instant) -> Result<()> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        match *state {
+            CircuitState::Closed { .. } => Ok(()),
+            CircuitState::Open { until } if now >= until => {
+                *state = CircuitState::HalfOpen;
+                Ok(())
+            }
+            CircuitState::Open { until } => Err(SandboxError::CircuitOpen {
+                retry_after: until.saturating_duration_since(now),
+            }),
+            CircuitState::HalfOpen => Err(SandboxError::CircuitOpen {
+                retry_after: CIRCUIT_PROBE_RETRY,
+            }),
+        }
+    }
+
+    fn record(&self, outcome: &Result<ChatCompletionResponse>, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let failed = match outcome {
+            Ok(_) => false,
+            Err(SandboxError::Network(_)) | Err(SandboxError::LlmTimeout(_)) => true,
+            Err(SandboxError::LlmHttp { status, .. }) => *status >= 500 || *status == 429,
+            Err(_) => false,
+        };
+        let mut state = self.state.lock();
+        *state = match (&*state, failed) {
+            (_, false) => CircuitState::Closed { failures: 0 },
+            (CircuitState::Closed { failures }, true) if failures + 1 < self.failure_threshold => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (CircuitState::Open { until }, true) => CircuitState::Open { until: *until },
+            (_, true) => {
+                warn!(cooldown = ?self.cooldown, "llm circuit opened after repeated failures");
+                CircuitState::Open {
+                    until: now + self.cooldown,
+                }
+            }
+        };
+    }
+}
+
+struct LlmClient {
+    http: reqwest::Client,
+    base_url: String,
     api_key: Option<String>,
+    breaker: CircuitBreaker,
+    metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl LlmClient {
-    fn new(base_url: String, timeout: Duration, api_key: Option<String>) -> Result<Self> {
+    fn new(
+        base_url: String,
+        timeout: Duration,
+        api_key: Option<String>,
+        breaker: CircuitBreaker,
+        metrics: Arc<dyn SandboxMetrics>,
+    ) -> Result<Self> {
         let http = reqwest::Client::builder()
             .timeout(timeout)
             .build()
@@ -581,10 +1648,29 @@ impl LlmClient {
             http,
             base_url,
             api_key,
+            breaker,
+            metrics,
         })
     }
 
-    async fn chat(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+    async fn chat(
+        &self,
+        request: ChatCompletionRequest,
+        traceparent: Option<&str>,
+    ) -> Result<ChatCompletionResponse> {
+        self.breaker.acquire(Instant::now())?;
+        let started = Instant::now();
+        let outcome = self.send_chat(request, traceparent).await;
+        self.metrics.llm_request(outcome.is_ok(), started.elapsed());
+        self.breaker.record(&outcome, Instant::now());
+        outcome
+    }
+
+    async fn send_chat(
+        &self,
+        request: ChatCompletionRequest,
+        traceparent: Option<&str>,
+    ) -> Result<ChatCompletionResponse> {
         let url = format!(
             "{}/v1/chat/completions",
             self.base_url.trim_end_matches('/')
@@ -593,29 +1679,53 @@ impl LlmClient {
         if let Some(key) = &self.api_key {
             req = req.bearer_auth(key);
         }
-        let response = req
-            .send()
-            .await
-            .map_err(|err| SandboxError::Network(err.to_string()))?;
+        if let Some(traceparent) = traceparent {
+            req = req.header("traceparent", traceparent);
+        }
+        let response = req.send().await.map_err(|err| {
+            if err.is_timeout() {
+                SandboxError::LlmTimeout(err.to_string())
+            } else {
+                SandboxError::Network(err.to_string())
+            }
+        })?;
         if !response.status().is_success() {
             let status = response.status();
             let body = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "<unavailable>".to_string());
-            return Err(SandboxError::AgentFailed(format!(
-                "llm request failed with status {status}: {body}"
-            )));
+            if is_context_overflow(status, &body) {
+                return Err(SandboxError::LlmContextOverflow(body));
+            }
+            return Err(SandboxError::LlmHttp {
+                status: status.as_u16(),
+                body,
+            });
         }
         response
             .json::<ChatCompletionResponse>()
             .await
             .map_err(|err| {
-                SandboxError::AgentFailed(format!("invalid llm response payload: {err}"))
+                if err.is_timeout() {
+                    SandboxError::LlmTimeout(err.to_string())
+                } else {
+                    SandboxError::LlmParse(err.to_string())
+                }
             })
     }
 }
 
+/// OpenAI-compatible servers report an oversized prompt as a 400 whose body
+/// names the context window rather than with a dedicated status code.
+fn is_context_overflow(status: StatusCode, body: &str) -> bool {
+    if status != StatusCode::BAD_REQUEST && status != StatusCode::PAYLOAD_TOO_LARGE {
+        return false;
+    }
+    let body = body.to_ascii_lowercase();
+    body.contains("context_length") || body.contains("context length")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatCompletionRequest {
     pub model: String,
@@ -624,6 +1734,10 @@ struct ChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
     pub top_p: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<ChatTool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -632,6 +1746,20 @@ struct ChatMessage {
     pub content: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatTool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: ChatToolFunction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatToolFunction {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
 #[derive(Debug, Deserialize)]
 struct ChatCompletionResponse {
     pub choices: Vec<ChatCompletionChoice>,
@@ -639,7 +1767,162 @@ struct ChatCompletionResponse {
 
 #[derive(Debug, Deserialize)]
 struct ChatCompletionChoice {
-    pub message: ChatMessage,
+    pub message: ChatResponseMessage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ChatResponseMessage {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatToolCall {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub function: ChatToolCallFunction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatToolCallFunction {
+    pub name: String,
+    /// JSON-encoded arguments; some servers send an object instead of a string.
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+fn agent_action_tools() -> Vec<ChatTool> {
+    let definitions = [
+        (
+            "file_patch",
+            "Propose a unified diff patch for an existing file.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Sandbox-relative file path." },
+                    "patch": { "type": "string", "description": "Unified diff to apply." }
+                },
+                "required": ["path", "patch"]
+            }),
+        ),
+        (
+            "file_write",
+            "Create or replace a file with the provided content.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Sandbox-relative file path." },
+                    "content": { "type": "string", "description": "Full file content." },
+                    "encoding": { "type": "string", "enum": ["utf-8", "base64"] }
+                },
+                "required": ["path", "content"]
+            }),
+        ),
+        (
+            "message",
+            "Report a finding or note to the user.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "title": { "type": "string" },
+                    "body": { "type": "string" }
+                },
+                "required": ["title", "body"]
+            }),
+        ),
+        (
+            "checkpoint",
+            "Pause and ask the user a clarification question instead of guessing.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "question": { "type": "string" }
+                },
+                "required": ["question"]
+            }),
+        ),
+        (
+            "command",
+            "Suggest a command to run inside the sandbox.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string" },
+                    "args": { "type": "array", "items": { "type": "string" } }
+                },
+                "required": ["command"]
+            }),
+        ),
+    ];
+    definitions
+        .into_iter()
+        .map(|(name, description, parameters)| ChatTool {
+            kind: "function".to_string(),
+            function: ChatToolFunction {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+        })
+        .collect()
+}
+
+/// Maps tool calls to actions. Calls that do not map are returned as
+/// rejection messages for the log; they are not the model's insights.
+fn tool_call_actions(calls: &[ChatToolCall]) -> (Vec<AgentAction>, Vec<String>) {
+    let mut actions = Vec::new();
+    let mut rejected = Vec::new();
+    for call in calls {
+        match tool_call_to_action(call) {
+            Ok(action) => actions.push(action),
+            Err(err) => rejected.push(err.to_string()),
+        }
+    }
+    (actions, rejected)
+}
+
+fn tool_call_to_action(call: &ChatToolCall) -> Result<AgentAction> {
+    let arguments = match &call.function.arguments {
+        Value::String(raw) if raw.trim().is_empty() => Value::Object(Default::default()),
+        Value::String(raw) => serde_json::from_str::<Value>(raw).map_err(|err| {
+            SandboxError::InvalidOperation(format!(
+                "tool call '{}' has invalid arguments: {err}",
+                call.function.name
+            ))
+        })?,
+        Value::Null => Value::Object(Default::default()),
+        other => other.clone(),
+    };
+    let mut object = arguments.as_object().cloned().ok_or_else(|| {
+        SandboxError::InvalidOperation(format!(
+            "tool call '{}' arguments must be an object",
+            call.function.name
+        ))
+    })?;
+    if call.function.name == "file_write" {
+        if let Some(Value::String(content)) = object.remove("content") {
+            let encoding = object
+                .remove("encoding")
+                .and_then(|value| value.as_str().map(str::to_string))
+                .unwrap_or_else(|| "utf-8".to_string());
+            object.insert(
+                "content".to_string(),
+                json!({ "encoding": encoding, "data": content }),
+            );
+        }
+    }
+    object.insert(
+        "type".to_string(),
+        Value::String(call.function.name.clone()),
+    );
+    serde_json::from_value(Value::Object(object)).map_err(|err| {
+        SandboxError::InvalidOperation(format!(
+            "tool call '{}' does not map to an agent action: {err}",
+            call.function.name
+        ))
+    })
 }
 
 struct LlmBackedAgent {
@@ -651,6 +1934,7 @@ struct LlmBackedAgent {
     default_model: String,
     default_parameters: AgentParameters,
     client: Arc<LlmClient>,
+    native_tools: bool,
 }
 
 impl LlmBackedAgent {
@@ -662,8 +1946,8 @@ impl LlmBackedAgent {
         capabilities: Vec<String>,
         default_model: impl Into<String>,
         client: Arc<LlmClient>,
-    ) -> Arc<Self> {
-        Arc::new(Self {
+    ) -> Self {
+        Self {
             kind,
             name: name.into(),
             description: description.into(),
@@ -672,7 +1956,13 @@ impl LlmBackedAgent {
             default_model: default_model.into(),
             default_parameters: AgentParameters::default(),
             client,
-        })
+            native_tools: false,
+        }
+    }
+
+    fn with_native_tools(mut self, enabled: bool) -> Self {
+        self.native_tools = enabled;
+        self
     }
 
     fn build_user_prompt(&self, invocation: &AgentInvocation) -> String {
@@ -713,6 +2003,10 @@ impl LlmBackedAgent {
             prompt.push_str(&metadata.to_string());
             prompt.push('\n');
         }
+        prompt.push_str(
+            "\nIf you cannot proceed without clarification, return a single action \
+             {\"type\": \"checkpoint\", \"question\": string}; the task resumes with the answer.\n",
+        );
         prompt
     }
 }
@@ -743,49 +2037,94 @@ impl Agent for LlmBackedAgent {
         } else {
             invocation.model.clone()
         };
+        let mut system_prompt = invocation
+            .system_prompt
+            .clone()
+            .unwrap_or_else(|| self.system_prompt.clone());
+        if let Some(persona) = invocation.persona {
+            system_prompt.push_str("\n\n");
+            system_prompt.push_str(persona.instructions());
+        }
         let mut messages = vec![ChatMessage {
             role: "system".to_string(),
-            content: self.system_prompt.clone(),
+            content: system_prompt,
         }];
         messages.push(ChatMessage {
             role: "user".to_string(),
             content: self.build_user_prompt(&invocation),
         });
         let params = invocation.parameters;
+        let (tools, tool_choice) = if self.native_tools {
+            (Some(agent_action_tools()), Some("auto".to_string()))
+        } else {
+            (None, None)
+        };
         let request = ChatCompletionRequest {
             model,
             messages,
             temperature: params.temperature,
             max_tokens: params.max_tokens,
             top_p: params.top_p,
+            tools,
+            tool_choice,
         };
-        let response = self.client.chat(request).await?;
-        let text = response
+        let response = self
+            .client
+            .chat(request, invocation.traceparent.as_deref())
+            .await?;
+        let message = response
             .choices
-            .first()
-            .map(|choice| choice.message.content.clone())
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
             .unwrap_or_default();
+        let text = message.content.unwrap_or_default();
+        let tool_calls = message.tool_calls.unwrap_or_default();
 
         if cancellation.is_cancelled() {
             return Err(SandboxError::Cancelled);
         }
 
-        let parsed: std::result::Result<LlmAgentPayload, _> = serde_json::from_str(&text);
         let mut outcome = AgentOutcome {
             summary: String::new(),
             insights: Vec::new(),
             actions: Vec::new(),
             raw_response: text.clone(),
         };
-        match parsed {
-            Ok(payload) => {
-                outcome.summary = payload.summary;
-                outcome.insights = payload.insights.unwrap_or_default();
-                outcome.actions = payload.actions.unwrap_or_default();
+        if !text.trim().is_empty() {
+            let parsed: std::result::Result<LlmAgentPayload, _> = serde_json::from_str(&text);
+            match parsed {
+                Ok(payload) => {
+                    outcome.summary = payload.summary;
+                    outcome.insights = payload.insights.unwrap_or_default();
+                    outcome.actions = payload.actions.unwrap_or_default();
+                }
+                // Prose is taken as the summary, but a JSON answer that
+                // misses the schema is a failed task, not a summary.
+                Err(err) if tool_calls.is_empty() && text.trim_start().starts_with('{') => {
+                    return Err(SandboxError::LlmParse(format!(
+                        "structured response does not match the agent schema: {err}"
+                    )));
+                }
+                Err(err) => {
+                    if tool_calls.is_empty() {
+                        warn!(kind = %self.kind, error = %err, "failed to parse structured response");
+                    }
+                    outcome.summary = text.trim().to_string();
+                }
+            }
+        }
+        if !tool_calls.is_empty() {
+            if outcome.raw_response.trim().is_empty() {
+                outcome.raw_response = serde_json::to_string(&tool_calls).unwrap_or_default();
             }
-            Err(err) => {
-                warn!("agent", kind = %self.kind, "failed to parse structured response: {err}");
-                outcome.summary = text.trim().to_string();
+            let (actions, rejected) = tool_call_actions(&tool_calls);
+            outcome.actions.extend(actions);
+            for err in &rejected {
+                warn!(kind = %self.kind, "ignoring tool call: {err}");
+            }
+            if text.trim().is_empty() && rejected.len() == tool_calls.len() {
+                return Err(SandboxError::ToolFailed(rejected.join("; ")));
             }
         }
         if outcome.summary.trim().is_empty() {
@@ -807,6 +2146,7 @@ struct LlmAgentPayload {
 fn default_agents(
     client: Arc<LlmClient>,
     default_model: String,
+    native_tools: bool,
 ) -> HashMap<AgentKind, Arc<dyn Agent>> {
     let mut agents: HashMap<AgentKind, Arc<dyn Agent>> = HashMap::new();
     let entries = vec![
@@ -857,14 +2197,17 @@ fn default_agents(
     for (kind, name, description, prompt, capabilities) in entries {
         agents.insert(
             kind,
-            LlmBackedAgent::new(
-                kind,
-                name,
-                description,
-                prompt,
-                capabilities,
-                default_model.clone(),
-                client.clone(),
+            Arc::new(
+                LlmBackedAgent::new(
+                    kind,
+                    name,
+                    description,
+                    prompt,
+                    capabilities,
+                    default_model.clone(),
+                    client.clone(),
+                )
+                .with_native_tools(native_tools),
             ),
         );
     }
@@ -875,7 +2218,6 @@ fn default_agents(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::json;
     use tokio::time::sleep;
 
     struct StubAgent {
@@ -944,6 +2286,10 @@ mod tests {
                 model: None,
                 metadata: Some(json!({ "priority": "high" })),
                 parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
             })
             .expect("dispatch success");
         assert_eq!(submission.status.status, AgentTaskStatus::Pending);
@@ -953,6 +2299,46 @@ mod tests {
         assert_eq!(status.outcome.unwrap().summary, "handled: build module");
     }
 
+    #[tokio::test]
+    async fn subscribers_observe_status_and_output() {
+        let dispatcher = stub_dispatcher();
+        let mut events = dispatcher.subscribe();
+        let submission = dispatcher
+            .dispatch(AgentDispatchRequest {
+                agent: AgentKind::Code,
+                objective: "watch me".to_string(),
+                context: AgentContext::default(),
+                model: None,
+                metadata: None,
+                parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
+            })
+            .expect("dispatch success");
+        let mut seen = Vec::new();
+        while seen.last().map(String::as_str) != Some("completed") {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .expect("event")
+                .expect("open channel");
+            assert_eq!(event.task_id(), submission.id);
+            seen.push(match event {
+                AgentEvent::Status(snapshot) => serde_json::to_value(snapshot.status)
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+                AgentEvent::Output(output) => {
+                    assert_eq!(output.summary, "handled: watch me");
+                    "output".to_string()
+                }
+            });
+        }
+        assert_eq!(seen, vec!["pending", "running", "output", "completed"]);
+    }
+
     #[tokio::test]
     async fn cancel_marks_task() {
         let dispatcher = stub_dispatcher();
@@ -964,6 +2350,10 @@ mod tests {
                 model: None,
                 metadata: None,
                 parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
             })
             .expect("dispatch success");
         let snapshot = dispatcher.cancel(&submission.id).expect("cancel");
@@ -982,6 +2372,10 @@ mod tests {
                     model: None,
                     metadata: None,
                     parameters: None,
+                    subtasks: Vec::new(),
+                    system_prompt: None,
+                    persona: None,
+                    traceparent: None,
                 })
                 .expect("dispatch");
         }
@@ -990,4 +2384,513 @@ mod tests {
         assert!(history.len() >= 3);
         assert!(history.iter().all(|entry| entry.status.is_terminal()));
     }
+
+    #[tokio::test]
+    async fn fan_out_aggregates_subtasks() {
+        let metadata = AgentMetadata {
+            agent: AgentKind::Code,
+            name: "stub".to_string(),
+            description: "stub".to_string(),
+            capabilities: vec!["stub".to_string()],
+            default_model: "test".to_string(),
+            default_parameters: AgentParameters::default(),
+        };
+        let mut agents: HashMap<AgentKind, Arc<dyn Agent>> = HashMap::new();
+        agents.insert(AgentKind::Code, Arc::new(StubAgent { metadata }));
+        let dispatcher = AgentDispatcher::with_agents(
+            AgentDispatcherConfig::new("http://localhost", "test").with_max_concurrency(2),
+            agents,
+        )
+        .expect("dispatcher");
+        let subtasks = (0..3)
+            .map(|idx| AgentSubtask {
+                objective: Some(format!("review file-{idx}")),
+                context: AgentContext::default(),
+            })
+            .collect();
+        let submission = dispatcher
+            .dispatch(AgentDispatchRequest {
+                agent: AgentKind::Code,
+                objective: "review files".to_string(),
+                context: AgentContext::default(),
+                model: None,
+                metadata: None,
+                parameters: None,
+                subtasks,
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
+            })
+            .expect("dispatch");
+        assert_eq!(submission.status.children.len(), 3);
+        let child = submission.status.children[0];
+        assert_eq!(
+            dispatcher.status(&child).unwrap().parent_id,
+            Some(submission.id)
+        );
+
+        sleep(Duration::from_millis(100)).await;
+        let parent = dispatcher.status(&submission.id).unwrap();
+        assert_eq!(parent.status, AgentTaskStatus::Completed);
+        let outcome = parent.outcome.unwrap();
+        assert!(outcome.summary.starts_with("3 of 3 subtasks completed"));
+        assert_eq!(outcome.actions.len(), 3);
+        assert!(parent
+            .children
+            .iter()
+            .all(|child| dispatcher.status(child).unwrap().status == AgentTaskStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn dispatch_validates_prompt_overrides() {
+        let dispatcher = stub_dispatcher();
+        let request = AgentDispatchRequest {
+            agent: AgentKind::Code,
+            objective: "tuned task".to_string(),
+            context: AgentContext::default(),
+            model: None,
+            metadata: None,
+            parameters: None,
+            subtasks: Vec::new(),
+            system_prompt: Some("x".repeat(DEFAULT_MAX_SYSTEM_PROMPT_BYTES + 1)),
+            persona: Some(AgentPersona::Concise),
+            traceparent: None,
+        };
+        let err = dispatcher
+            .dispatch(request.clone())
+            .expect_err("oversized prompt rejected");
+        assert!(matches!(err, SandboxError::InvalidOperation(_)));
+
+        let submission = dispatcher
+            .dispatch(AgentDispatchRequest {
+                system_prompt: Some("You only write Rust.".to_string()),
+                ..request
+            })
+            .expect("dispatch");
+        assert_eq!(submission.status.persona, Some(AgentPersona::Concise));
+        assert!(submission.status.system_prompt_overridden);
+    }
+
+    #[test]
+    fn failures_map_to_typed_errors() {
+        let error = AgentTaskError::from(&SandboxError::LlmHttp {
+            status: 502,
+            body: "bad gateway".to_string(),
+        });
+        assert_eq!(error.kind, AgentFailureKind::LlmHttp);
+        assert_eq!(error.http_status, Some(502));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap()["kind"],
+            json!("llm_http")
+        );
+
+        let error = AgentTaskError::from(&SandboxError::ToolFailed("bad args".to_string()));
+        assert_eq!(error.kind, AgentFailureKind::Tool);
+        assert!(error.http_status.is_none());
+
+        assert!(is_context_overflow(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":{"code":"context_length_exceeded"}}"#
+        ));
+        assert!(!is_context_overflow(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "context_length_exceeded"
+        ));
+    }
+
+    struct CheckpointAgent {
+        metadata: AgentMetadata,
+    }
+
+    #[async_trait]
+    impl Agent for CheckpointAgent {
+        fn metadata(&self) -> AgentMetadata {
+            self.metadata.clone()
+        }
+
+        async fn execute(
+            &self,
+            invocation: AgentInvocation,
+            _cancellation: CancellationToken,
+        ) -> Result<AgentOutcome> {
+            let answer = invocation
+                .context
+                .notes
+                .iter()
+                .find_map(|note| note.split("User answer: ").nth(1));
+            let outcome = match answer {
+                Some(answer) => AgentOutcome {
+                    summary: format!("using {answer}"),
+                    ..AgentOutcome::default()
+                },
+                None => AgentOutcome {
+                    summary: "need input".to_string(),
+                    actions: vec![AgentAction::Checkpoint {
+                        question: "which database?".to_string(),
+                    }],
+                    ..AgentOutcome::default()
+                },
+            };
+            Ok(outcome)
+        }
+    }
+
+    #[tokio::test]
+    async fn checkpoints_wait_for_user_answer() {
+        let metadata = AgentMetadata {
+            agent: AgentKind::Code,
+            name: "checkpoint".to_string(),
+            description: "checkpoint".to_string(),
+            capabilities: Vec::new(),
+            default_model: "test".to_string(),
+            default_parameters: AgentParameters::default(),
+        };
+        let mut agents: HashMap<AgentKind, Arc<dyn Agent>> = HashMap::new();
+        agents.insert(AgentKind::Code, Arc::new(CheckpointAgent { metadata }));
+        let dispatcher = AgentDispatcher::with_agents(
+            AgentDispatcherConfig::new("http://localhost", "test"),
+            agents,
+        )
+        .expect("dispatcher");
+        let submission = dispatcher
+            .dispatch(AgentDispatchRequest {
+                agent: AgentKind::Code,
+                objective: "add persistence".to_string(),
+                context: AgentContext::default(),
+                model: None,
+                metadata: None,
+                parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
+            })
+            .expect("dispatch");
+        assert!(dispatcher.respond(&submission.id, "postgres").is_err());
+
+        sleep(Duration::from_millis(20)).await;
+        let waiting = dispatcher.status(&submission.id).unwrap();
+        assert_eq!(waiting.status, AgentTaskStatus::WaitingForInput);
+        assert_eq!(waiting.pending_question.as_deref(), Some("which database?"));
+
+        dispatcher
+            .respond(&submission.id, "postgres")
+            .expect("respond");
+        sleep(Duration::from_millis(20)).await;
+        let done = dispatcher.status(&submission.id).unwrap();
+        assert_eq!(done.status, AgentTaskStatus::Completed);
+        assert_eq!(done.summary.as_deref(), Some("using postgres"));
+        assert!(done.outcome.unwrap().actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unanswered_checkpoints_time_out() {
+        let metadata = AgentMetadata {
+            agent: AgentKind::Code,
+            name: "checkpoint".to_string(),
+            description: "checkpoint".to_string(),
+            capabilities: Vec::new(),
+            default_model: "test".to_string(),
+            default_parameters: AgentParameters::default(),
+        };
+        let mut agents: HashMap<AgentKind, Arc<dyn Agent>> = HashMap::new();
+        agents.insert(AgentKind::Code, Arc::new(CheckpointAgent { metadata }));
+        let dispatcher = AgentDispatcher::with_agents(
+            AgentDispatcherConfig::new("http://localhost", "test")
+                .with_input_timeout(Duration::from_millis(20)),
+            agents,
+        )
+        .expect("dispatcher");
+        let submission = dispatcher
+            .dispatch(AgentDispatchRequest {
+                agent: AgentKind::Code,
+                objective: "add persistence".to_string(),
+                context: AgentContext::default(),
+                model: None,
+                metadata: None,
+                parameters: None,
+                subtasks: Vec::new(),
+                system_prompt: None,
+                persona: None,
+                traceparent: None,
+            })
+            .expect("dispatch");
+
+        sleep(Duration::from_millis(100)).await;
+        let done = dispatcher.status(&submission.id).unwrap();
+        assert_eq!(done.status, AgentTaskStatus::Completed);
+        assert!(done.pending_question.is_none());
+        assert_eq!(
+            done.outcome.unwrap().insights,
+            vec!["unanswered clarification: which database?".to_string()]
+        );
+        assert!(dispatcher.respond(&submission.id, "postgres").is_err());
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_probes() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        let failure = || -> Result<ChatCompletionResponse> {
+            Err(SandboxError::LlmHttp {
+                status: 503,
+                body: "unavailable".to_string(),
+            })
+        };
+        let start = Instant::now();
+        breaker.acquire(start).expect("closed");
+        breaker.record(&failure(), start);
+        breaker.acquire(start).expect("below threshold");
+        breaker.record(&failure(), start);
+        let err = breaker.acquire(start).expect_err("open");
+        assert!(matches!(err, SandboxError::CircuitOpen { .. }));
+
+        let later = start + Duration::from_secs(11);
+        breaker.acquire(later).expect("half-open probe");
+        assert!(breaker.acquire(later).is_err());
+        breaker.record(&failure(), later);
+        assert!(breaker.acquire(later + Duration::from_secs(1)).is_err());
+
+        let recovered = later + Duration::from_secs(11);
+        breaker.acquire(recovered).expect("second probe");
+        breaker.record(
+            &Ok(ChatCompletionResponse {
+                choices: Vec::new(),
+            }),
+            recovered,
+        );
+        breaker.acquire(recovered).expect("closed again");
+    }
+
+    #[test]
+    fn tool_calls_map_to_agent_actions() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{
+                "message": {
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {
+                                "name": "file_write",
+                                "arguments": "{\"path\": \"src/lib.rs\", \"content\": \"fn main() {}\"}"
+                            }
+                        },
+                        {
+                            "id": "call_2",
+                            "type": "function",
+                            "function": {
+                                "name": "command",
+                                "arguments": { "command": "cargo", "args": ["test"] }
+                            }
+                        }
+                    ]
+                }
+            }]
+        }))
+        .expect("response parses");
+        let calls = response.choices[0]
+            .message
+            .tool_calls
+            .clone()
+            .expect("tool calls present");
+        let actions: Vec<AgentAction> = calls
+            .iter()
+            .map(|call| tool_call_to_action(call).expect("valid action"))
+            .collect();
+        match &actions[0] {
+            AgentAction::FileWrite { path, content } => {
+                assert_eq!(path, "src/lib.rs");
+                assert!(matches!(content, AgentFileContent::Utf8(body) if body == "fn main() {}"));
+            }
+            other => panic!("unexpected action {other:?}"),
+        }
+        assert!(matches!(
+            &actions[1],
+            AgentAction::Command { command, args } if command == "cargo" && args == &vec!["test".to_string()]
+        ));
+    }
+
+    #[test]
+    fn unknown_tool_calls_are_rejected() {
+        let call = ChatToolCall {
+            id: None,
+            function: ChatToolCallFunction {
+                name: "format_disk".to_string(),
+                arguments: Value::String("{}".to_string()),
+            },
+        };
+        assert!(tool_call_to_action(&call).is_err());
+
+        let valid = ChatToolCall {
+            id: None,
+            function: ChatToolCallFunction {
+                name: "command".to_string(),
+                arguments: json!({ "command": "cargo" }),
+            },
+        };
+        let (actions, rejected) = tool_call_actions(&[call, valid]);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].contains("format_disk"), "{rejected:?}");
+    }
+
+    #[test]
+    fn rate_limiter_enforces_sliding_window() {
+        let limiter = DispatchRateLimiter::new(DispatchRateLimit {
+            per_minute: Some(2),
+            per_hour: None,
+        });
+        let start = Instant::now();
+        limiter.acquire("7", start).expect("first dispatch");
+        limiter
+            .acquire("7", start + Duration::from_secs(10))
+            .expect("second dispatch");
+        let err = limiter
+            .acquire("7", start + Duration::from_secs(20))
+            .expect_err("third dispatch limited");
+        match err {
+            SandboxError::RateLimited { retry_after } => {
+                assert_eq!(retry_after, Duration::from_secs(40));
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+        limiter.acquire("8", start).expect("other users unaffected");
+        limiter
+            .acquire("7", start + Duration::from_secs(61))
+            .expect("window slides");
+    }
+
+    #[tokio::test]
+    async fn dispatch_rejects_users_over_limit() {
+        let metadata = AgentMetadata {
+            agent: AgentKind::Code,
+            name: "stub".to_string(),
+            description: "stub".to_string(),
+            capabilities: vec!["stub".to_string()],
+            default_model: "test".to_string(),
+            default_parameters: AgentParameters::default(),
+        };
+        let mut agents: HashMap<AgentKind, Arc<dyn Agent>> = HashMap::new();
+        agents.insert(AgentKind::Code, Arc::new(StubAgent { metadata }));
+        let dispatcher = AgentDispatcher::with_agents(
+            AgentDispatcherConfig::new("http://localhost", "test").with_rate_limit(Some(1), None),
+            agents,
+        )
+        .expect("dispatcher");
+        let request = AgentDispatchRequest {
+            agent: AgentKind::Code,
+            objective: "task".to_string(),
+            context: AgentContext::default(),
+            model: None,
+            metadata: Some(json!({ "requested_by_id": 42 })),
+            parameters: None,
+            subtasks: Vec::new(),
+            system_prompt: None,
+            persona: None,
+            traceparent: None,
+        };
+        dispatcher
+            .dispatch(request.clone())
+            .expect("first dispatch");
+        let err = dispatcher
+            .dispatch(request)
+            .expect_err("second dispatch limited");
+        assert!(matches!(err, SandboxError::RateLimited { .. }));
+    }
+
+    #[tokio::test]
+    async fn history_page_filters_and_paginates() {
+        let dispatcher = stub_dispatcher();
+        for idx in 0..5 {
+            dispatcher
+                .dispatch(AgentDispatchRequest {
+                    agent: AgentKind::Code,
+                    objective: format!("task-{idx}"),
+                    context: AgentContext::default(),
+                    model: None,
+                    metadata: Some(json!({ "requested_by_id": idx % 2 })),
+                    parameters: None,
+                    subtasks: Vec::new(),
+                    system_prompt: None,
+                    persona: None,
+                    traceparent: None,
+                })
+                .expect("dispatch");
+        }
+        sleep(Duration::from_millis(80)).await;
+
+        let mut query = AgentHistoryQuery {
+            requested_by_id: Some(0),
+            limit: 2,
+            ..AgentHistoryQuery::default()
+        };
+        let first = dispatcher.history_page(&query).expect("first page");
+        assert_eq!(first.entries.len(), 2);
+        let cursor = first.next_cursor.expect("more entries");
+        query.cursor = Some(cursor);
+        let second = dispatcher.history_page(&query).expect("second page");
+        assert_eq!(second.entries.len(), 1);
+        assert!(second.next_cursor.is_none());
+        assert!(first
+            .entries
+            .iter()
+            .chain(second.entries.iter())
+            .all(|entry| entry.metadata.as_ref().unwrap()["requested_by_id"] == 0));
+
+        let tight = dispatcher
+            .history_page(&AgentHistoryQuery {
+                cursor: None,
+                max_bytes: Some(1),
+                ..query.clone()
+            })
+            .expect("page within budget");
+        assert_eq!(tight.entries.len(), 1);
+        assert_eq!(tight.next_cursor, Some(first.entries[0].id));
+
+        query.cursor = Some(Uuid::new_v4());
+        assert!(dispatcher.history_page(&query).is_err());
+
+        assert_eq!(
+            dispatcher.prune_history(Utc::now() - chrono::Duration::hours(1)),
+            0
+        );
+        assert_eq!(dispatcher.prune_history(Utc::now()), 5);
+        assert!(dispatcher.history(10).is_empty());
+    }
+
+    #[test]
+    fn previews_render_diffs_against_workspace() {
+        let temp = tempfile::TempDir::new().expect("temp dir");
+        let workspace = SandboxFs::new(
+            crate::fs::SandboxConfig::new(temp.path(), 64 * 1024).expect("sandbox config"),
+        );
+        workspace
+            .write("src/lib.rs", "fn a() {}\n")
+            .expect("seed file");
+
+        let actions = vec![
+            AgentAction::FileWrite {
+                path: "src/lib.rs".to_string(),
+                content: AgentFileContent::Utf8("fn b() {}\n".to_string()),
+            },
+            AgentAction::FileWrite {
+                path: "README.md".to_string(),
+                content: AgentFileContent::Utf8("hello\n".to_string()),
+            },
+            AgentAction::FilePatch {
+                path: "src/lib.rs".to_string(),
+                patch: "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn missing() {}\n+fn c() {}\n"
+                    .to_string(),
+            },
+        ];
+        let previews = build_file_previews(&workspace, &actions);
+        assert_eq!(previews.len(), 3);
+        let rewrite = previews[0].diff.as_deref().expect("diff rendered");
+        assert!(rewrite.contains("-fn a() {}"));
+        assert!(rewrite.contains("+fn b() {}"));
+        assert!(previews[1].is_new);
+        assert!(previews[2].diff.is_none());
+        assert!(previews[2].error.is_some());
+    }
 }
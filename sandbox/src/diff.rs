@@ -0,0 +1,21 @@
+use diffy::{DiffOptions, Patch};
+
+use crate::errors::{Result, SandboxError};
+
+/// Renders a unified diff between two text buffers using `a/` and `b/` path
+/// prefixes so the output can be fed to `git apply` or `patch -p1`.
+pub fn unified_diff(path: &str, original: &str, modified: &str) -> String {
+    let mut options = DiffOptions::new();
+    options
+        .set_original_filename(format!("a/{path}"))
+        .set_modified_filename(format!("b/{path}"));
+    options.create_patch(original, modified).to_string()
+}
+
+/// Applies a unified diff to `original`, failing if any hunk does not match.
+pub fn apply_patch(original: &str, patch: &str) -> Result<String> {
+    let parsed = Patch::from_str(patch)
+        .map_err(|err| SandboxError::InvalidOperation(format!("invalid patch: {err}")))?;
+    diffy::apply(original, &parsed)
+        .map_err(|err| SandboxError::InvalidOperation(format!("patch does not apply: {err}")))
+}
@@ -26,18 +26,37 @@ pub enum SandboxError {
     MicroImageNotConfigured(String),
     #[error("micro vm '{0}' not found")]
     MicroVmNotFound(String),
+    #[error("git {command} failed: {message}")]
+    Git {
+        command: &'static str,
+        message: String,
+    },
+    #[error("run session '{0}' not found")]
+    RunSessionNotFound(String),
     #[error("agent '{0}' is not registered")]
     AgentUnavailable(String),
     #[error("agent task '{0}' not found")]
     AgentTaskNotFound(String),
     #[error("agent context size {provided} bytes exceeds limit {limit}")]
     ContextTooLarge { provided: usize, limit: usize },
-    #[error("agent execution failed: {0}")]
-    AgentFailed(String),
+    #[error("llm request failed with status {status}: {body}")]
+    LlmHttp { status: u16, body: String },
+    #[error("invalid llm response payload: {0}")]
+    LlmParse(String),
+    #[error("llm request timed out: {0}")]
+    LlmTimeout(String),
+    #[error("llm context window exceeded: {0}")]
+    LlmContextOverflow(String),
+    #[error("agent tool call failed: {0}")]
+    ToolFailed(String),
     #[error("network request failed: {0}")]
     Network(String),
     #[error("agent operation cancelled")]
     Cancelled,
+    #[error("rate limit exceeded; retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    #[error("llm backend unavailable; circuit open for {retry_after:?}")]
+    CircuitOpen { retry_after: Duration },
 }
 
 pub type Result<T> = std::result::Result<T, SandboxError>;
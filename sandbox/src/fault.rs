@@ -0,0 +1,238 @@
+//! Fault injection for resilience testing. A [`FaultInjector`] attached to a
+//! subsystem delays a share of its operations and fails another share with
+//! an injected error before the real work starts, so retries, circuit
+//! breakers and error mapping can be exercised in staging while the real
+//! dependencies stay healthy. The default injector does nothing.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::debug;
+
+use crate::errors::{Result, SandboxError};
+
+/// How often an operation is slowed down or fails. Rates are clamped to
+/// `[0, 1]`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FaultConfig {
+    /// Extra delay of a slowed-down operation.
+    pub latency: Duration,
+    /// Share of operations delayed by `latency`.
+    pub latency_rate: f64,
+    /// Share of operations failing with an injected error.
+    pub error_rate: f64,
+}
+
+impl FaultConfig {
+    pub fn is_active(&self) -> bool {
+        (self.latency_rate > 0.0 && !self.latency.is_zero()) || self.error_rate > 0.0
+    }
+}
+
+/// Faults injected so far. The counters only grow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultCounts {
+    pub delayed: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug)]
+struct Injector {
+    config: FaultConfig,
+    delayed: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Shared by every handle scoped from the one it was attached to.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector {
+    inner: Option<Arc<Injector>>,
+}
+
+/// What to do to the next operation.
+enum Fault {
+    Delay(Duration),
+    Fail,
+}
+
+impl FaultInjector {
+    /// An injector that never does anything when `config` is inactive.
+    pub fn new(config: FaultConfig) -> Self {
+        let config = FaultConfig {
+            latency_rate: config.latency_rate.clamp(0.0, 1.0),
+            error_rate: config.error_rate.clamp(0.0, 1.0),
+            ..config
+        };
+        Self {
+            inner: config.is_active().then(|| {
+                Arc::new(Injector {
+                    config,
+                    delayed: AtomicU64::new(0),
+                    failed: AtomicU64::new(0),
+                })
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    pub fn counts(&self) -> FaultCounts {
+        self.inner
+            .as_ref()
+            .map(|inner| FaultCounts {
+                delayed: inner.delayed.load(Ordering::Relaxed),
+                failed: inner.failed.load(Ordering::Relaxed),
+            })
+            .unwrap_or_default()
+    }
+
+    fn roll(&self, operation: &str) -> Vec<Fault> {
+        let Some(inner) = &self.inner else {
+            return Vec::new();
+        };
+        let mut faults = Vec::new();
+        let config = &inner.config;
+        if config.latency_rate > 0.0 && rand::random::<f64>() < config.latency_rate {
+            inner.delayed.fetch_add(1, Ordering::Relaxed);
+            debug!(operation, latency = ?config.latency, "injecting latency");
+            faults.push(Fault::Delay(config.latency));
+        }
+        if config.error_rate > 0.0 && rand::random::<f64>() < config.error_rate {
+            inner.failed.fetch_add(1, Ordering::Relaxed);
+            debug!(operation, "injecting failure");
+            faults.push(Fault::Fail);
+        }
+        faults
+    }
+
+    /// Whether `operation` fails this time, after waiting out any injected
+    /// latency. Callers turn a failure into the error their dependency
+    /// would have produced.
+    pub async fn should_fail(&self, operation: &str) -> bool {
+        let mut fail = false;
+        for fault in self.roll(operation) {
+            match fault {
+                Fault::Delay(latency) => tokio::time::sleep(latency).await,
+                Fault::Fail => fail = true,
+            }
+        }
+        fail
+    }
+
+    /// Like [`FaultInjector::should_fail`] for synchronous code; the delay
+    /// blocks the thread the way a slow disk would. Called from a worker of
+    /// a multi-threaded runtime, the worker's other tasks move elsewhere
+    /// while it waits.
+    pub fn should_fail_blocking(&self, operation: &str) -> bool {
+        let mut fail = false;
+        for fault in self.roll(operation) {
+            match fault {
+                Fault::Delay(latency) => sleep_blocking(latency),
+                Fault::Fail => fail = true,
+            }
+        }
+        fail
+    }
+
+    /// Injects faults into a sandbox operation; failures are IO errors.
+    pub async fn inject(&self, operation: &str) -> Result<()> {
+        if self.should_fail(operation).await {
+            return Err(injected_io_error(operation));
+        }
+        Ok(())
+    }
+
+    /// The blocking variant of [`FaultInjector::inject`].
+    pub fn inject_blocking(&self, operation: &str) -> Result<()> {
+        if self.should_fail_blocking(operation) {
+            return Err(injected_io_error(operation));
+        }
+        Ok(())
+    }
+}
+
+fn sleep_blocking(latency: Duration) {
+    let multi_threaded = Handle::try_current()
+        .is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread);
+    if multi_threaded {
+        tokio::task::block_in_place(|| std::thread::sleep(latency));
+    } else {
+        std::thread::sleep(latency);
+    }
+}
+
+fn injected_io_error(operation: &str) -> SandboxError {
+    SandboxError::Io(io::Error::other(format!("injected fault in {operation}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn injects_at_the_configured_rates() {
+        let disabled = FaultInjector::default();
+        assert!(!disabled.is_enabled());
+        assert!(disabled.inject("fs.read").await.is_ok());
+
+        let inactive = FaultInjector::new(FaultConfig {
+            latency: Duration::ZERO,
+            latency_rate: 1.0,
+            error_rate: 0.0,
+        });
+        assert!(!inactive.is_enabled());
+
+        let always = FaultInjector::new(FaultConfig {
+            latency: Duration::from_millis(5),
+            latency_rate: 7.0,
+            error_rate: 1.0,
+        });
+        let started = std::time::Instant::now();
+        let err = always.inject("run.exec").await.unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(5));
+        assert!(matches!(err, SandboxError::Io(_)));
+        assert!(err.to_string().contains("run.exec"));
+        assert!(always.inject_blocking("fs.write").is_err());
+        assert_eq!(
+            always.clone().counts(),
+            FaultCounts {
+                delayed: 2,
+                failed: 2
+            }
+        );
+
+        let slow_only = FaultInjector::new(FaultConfig {
+            latency: Duration::from_millis(1),
+            latency_rate: 1.0,
+            error_rate: 0.0,
+        });
+        assert!(slow_only.inject_blocking("fs.list").is_ok());
+        assert_eq!(slow_only.counts().delayed, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn blocking_delays_leave_the_runtime_responsive() {
+        let slow = FaultInjector::new(FaultConfig {
+            latency: Duration::from_millis(300),
+            latency_rate: 1.0,
+            error_rate: 0.0,
+        });
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let _ = sender.send(());
+        });
+        let started = std::time::Instant::now();
+        assert!(slow.inject_blocking("fs.read").is_ok());
+        // The only worker was waiting, yet the spawned task ran.
+        tokio::time::timeout(Duration::ZERO, receiver)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+}
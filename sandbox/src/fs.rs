@@ -1,11 +1,16 @@
 use std::fs;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use serde::Serialize;
 use tracing::instrument;
 
 use crate::errors::{Result, SandboxError};
+use crate::fault::FaultInjector;
+use crate::metrics::{self, SandboxMetrics};
 use crate::path;
 
 #[derive(Clone, Debug)]
@@ -28,23 +33,59 @@ impl SandboxConfig {
 #[derive(Clone, Debug)]
 pub struct SandboxFs {
     config: SandboxConfig,
+    faults: FaultInjector,
+    metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl SandboxFs {
     pub fn new(config: SandboxConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            faults: FaultInjector::default(),
+            metrics: metrics::none(),
+        }
+    }
+
+    /// Injects `faults` into every operation of this handle and the handles
+    /// scoped from it.
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Reports the bytes read and written by this handle and the handles
+    /// scoped from it to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn SandboxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
     }
 
     pub fn base_dir(&self) -> &Path {
         &self.config.base_dir
     }
 
+    /// Returns a handle rooted at `relative`, creating the directory if
+    /// needed. Paths passed to the scoped handle cannot escape it.
+    pub fn scoped(&self, relative: impl AsRef<Path>) -> Result<Self> {
+        let base_dir = self.resolve_path(relative)?;
+        fs::create_dir_all(&base_dir)?;
+        Ok(Self {
+            config: SandboxConfig {
+                base_dir,
+                max_file_size: self.config.max_file_size,
+            },
+            faults: self.faults.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+
     fn resolve_path(&self, relative: impl AsRef<Path>) -> Result<PathBuf> {
         path::resolve(&self.config.base_dir, relative)
     }
 
     #[instrument(skip(self), fields(path = %relative.as_ref().display()))]
     pub fn read(&self, relative: impl AsRef<Path>) -> Result<Vec<u8>> {
+        self.faults.inject_blocking("fs.read")?;
         let path = self.resolve_path(relative)?;
         let metadata = fs::metadata(&path)?;
         if metadata.len() > self.config.max_file_size {
@@ -53,11 +94,13 @@ impl SandboxFs {
         let mut file = fs::File::open(path)?;
         let mut buffer = Vec::with_capacity(metadata.len() as usize);
         file.read_to_end(&mut buffer)?;
+        self.metrics.fs_read(buffer.len() as u64);
         Ok(buffer)
     }
 
     #[instrument(skip(self, bytes), fields(path = %relative.as_ref().display(), size = bytes.as_ref().len()))]
     pub fn write(&self, relative: impl AsRef<Path>, bytes: impl AsRef<[u8]>) -> Result<()> {
+        self.faults.inject_blocking("fs.write")?;
         let path = self.resolve_path(relative)?;
         let data = bytes.as_ref();
         let size = data.len() as u64;
@@ -68,11 +111,13 @@ impl SandboxFs {
             fs::create_dir_all(parent)?;
         }
         fs::write(path, data)?;
+        self.metrics.fs_written(size);
         Ok(())
     }
 
     #[instrument(skip(self))]
     pub fn delete(&self, relative: impl AsRef<Path>) -> Result<()> {
+        self.faults.inject_blocking("fs.delete")?;
         let path = self.resolve_path(relative)?;
         if path.is_dir() {
             fs::remove_dir_all(path)?;
@@ -84,6 +129,7 @@ impl SandboxFs {
 
     #[instrument(skip(self))]
     pub fn mkdir(&self, relative: impl AsRef<Path>) -> Result<()> {
+        self.faults.inject_blocking("fs.mkdir")?;
         let path = self.resolve_path(relative)?;
         fs::create_dir_all(path)?;
         Ok(())
@@ -91,6 +137,7 @@ impl SandboxFs {
 
     #[instrument(skip(self))]
     pub fn copy(&self, source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
+        self.faults.inject_blocking("fs.copy")?;
         let from = self.resolve_path(source)?;
         let to = self.resolve_path(target)?;
         if from.is_dir() {
@@ -101,12 +148,14 @@ impl SandboxFs {
         if let Some(parent) = to.parent() {
             fs::create_dir_all(parent)?;
         }
-        fs::copy(from, to)?;
+        let copied = fs::copy(from, to)?;
+        self.metrics.fs_written(copied);
         Ok(())
     }
 
     #[instrument(skip(self))]
     pub fn move_path(&self, source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
+        self.faults.inject_blocking("fs.move")?;
         let from = self.resolve_path(source)?;
         let to = self.resolve_path(target)?;
         if let Some(parent) = to.parent() {
@@ -118,22 +167,63 @@ impl SandboxFs {
 
     #[instrument(skip(self))]
     pub fn list(&self, relative: impl AsRef<Path>) -> Result<Vec<FileEntry>> {
+        self.faults.inject_blocking("fs.list")?;
         let path = self.resolve_path(relative)?;
         let mut entries = Vec::new();
         for entry in fs::read_dir(path)? {
             let entry = entry?;
-            let metadata = entry.metadata()?;
-            entries.push(FileEntry {
-                name: entry.file_name().into_string().map_err(|_| {
-                    SandboxError::InvalidOperation("invalid utf8 filename".to_string())
-                })?,
-                is_dir: metadata.is_dir(),
-                size: metadata.len(),
-            });
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| SandboxError::InvalidOperation("invalid utf8 filename".to_string()))?;
+            entries.push(FileEntry::new(name, &entry.metadata()?));
         }
         entries.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(entries)
     }
+
+    /// Lists `relative` recursively, depth first and sorted by name within
+    /// each directory, so the result reads like a file tree. Symlinks are
+    /// reported but not followed. Globs match the path relative to
+    /// `relative`; see [`WalkOptions`].
+    #[instrument(skip(self, options))]
+    pub fn walk(&self, relative: impl AsRef<Path>, options: &WalkOptions) -> Result<WalkListing> {
+        self.faults.inject_blocking("fs.walk")?;
+        let root = self.resolve_path(relative)?;
+        let walker = Walker {
+            options,
+            include: glob_set(&options.include)?,
+            exclude: glob_set(&options.exclude)?,
+        };
+        let mut listing = WalkListing {
+            entries: Vec::new(),
+            truncated: false,
+        };
+        walker.walk(&root, "", 1, &mut listing)?;
+        Ok(listing)
+    }
+
+    /// Describes a single entry. Symlinks are reported as such and not
+    /// followed, so their target may lie outside the sandbox.
+    #[instrument(skip(self))]
+    pub fn stat(&self, relative: impl AsRef<Path>) -> Result<FileEntry> {
+        self.faults.inject_blocking("fs.stat")?;
+        let path = self.resolve_path(relative)?;
+        let metadata = fs::symlink_metadata(&path)?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(FileEntry::new(name, &metadata))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
 }
 
 #[derive(Debug, Serialize, PartialEq, Eq)]
@@ -141,4 +231,178 @@ pub struct FileEntry {
     pub name: String,
     pub is_dir: bool,
     pub size: u64,
+    pub kind: FileKind,
+    /// Last modification of the content.
+    pub mtime: Option<DateTime<Utc>>,
+    /// Last change of the content or metadata; the creation time on
+    /// platforms without one.
+    pub ctime: Option<DateTime<Utc>>,
+    /// Permission bits in octal, e.g. `"0644"`.
+    pub permissions: String,
+}
+
+impl FileEntry {
+    fn new(name: String, metadata: &fs::Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else {
+            FileKind::File
+        };
+        Self {
+            name,
+            is_dir: kind == FileKind::Dir,
+            size: metadata.len(),
+            kind,
+            mtime: metadata.modified().ok().map(DateTime::from),
+            ctime: changed(metadata),
+            permissions: format!("{:04o}", mode(metadata)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Levels to descend; 1 lists the direct children only. Unlimited when
+    /// `None`.
+    pub max_depth: Option<usize>,
+    /// Files and symlinks must match one of these unless it is empty.
+    /// Directories are listed regardless, so the tree stays connected.
+    pub include: Vec<String>,
+    /// Entries matching one of these are left out, directories with
+    /// everything below them.
+    pub exclude: Vec<String>,
+    /// The walk stops after this many entries and marks the listing
+    /// truncated.
+    pub max_entries: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalkListing {
+    pub entries: Vec<WalkEntry>,
+    /// The walk stopped at [`WalkOptions::max_entries`].
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct WalkEntry {
+    /// Relative to the walked directory, `/`-separated.
+    pub path: String,
+    /// 1 for the direct children of the walked directory.
+    pub depth: usize,
+    #[serde(flatten)]
+    pub entry: FileEntry,
+}
+
+struct Walker<'a> {
+    options: &'a WalkOptions,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl Walker<'_> {
+    /// Returns `false` once the entry limit is reached.
+    fn walk(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        depth: usize,
+        listing: &mut WalkListing,
+    ) -> Result<bool> {
+        let mut children = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| SandboxError::InvalidOperation("invalid utf8 filename".to_string()))?;
+            children.push((name, entry.metadata()?));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, metadata) in children {
+            let path = format!("{prefix}{name}");
+            if self.exclude.as_ref().is_some_and(|set| set.is_match(&path)) {
+                continue;
+            }
+            let is_dir = metadata.is_dir();
+            if !is_dir
+                && self
+                    .include
+                    .as_ref()
+                    .is_some_and(|set| !set.is_match(&path))
+            {
+                continue;
+            }
+            if self
+                .options
+                .max_entries
+                .is_some_and(|max| listing.entries.len() >= max)
+            {
+                listing.truncated = true;
+                return Ok(false);
+            }
+            listing.entries.push(WalkEntry {
+                path: path.clone(),
+                depth,
+                entry: FileEntry::new(name.clone(), &metadata),
+            });
+            let deeper = self.options.max_depth.is_none_or(|max| depth < max);
+            if is_dir
+                && deeper
+                && !self.walk(&dir.join(&name), &format!("{path}/"), depth + 1, listing)?
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| {
+                SandboxError::InvalidOperation(format!("invalid glob pattern: {err}"))
+            })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|err| SandboxError::InvalidOperation(format!("invalid glob pattern: {err}")))
+}
+
+#[cfg(unix)]
+fn changed(metadata: &fs::Metadata) -> Option<DateTime<Utc>> {
+    use std::os::unix::fs::MetadataExt;
+    DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32)
+}
+
+#[cfg(not(unix))]
+fn changed(metadata: &fs::Metadata) -> Option<DateTime<Utc>> {
+    metadata.created().ok().map(DateTime::from)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
 }
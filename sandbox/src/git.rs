@@ -0,0 +1,503 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::errors::{Result, SandboxError};
+use crate::path;
+use crate::run::{RunConfig, RunOutput, RunRequest, SandboxRun};
+
+/// Passed before every subcommand. The repository is always the `.git` of
+/// the scope root, never one found further up, and configuration that runs
+/// programs on its own (hooks, fsmonitor, pager, signing) is switched off;
+/// repository config and `.gitattributes` are honoured otherwise, except
+/// that a repository defining filter drivers is refused.
+const GLOBAL_ARGS: &[&str] = &[
+    "--git-dir=.git",
+    "--work-tree=.",
+    "--no-pager",
+    "--no-optional-locks",
+    "-c",
+    "core.hooksPath=/dev/null",
+    "-c",
+    "core.fsmonitor=false",
+    "-c",
+    "commit.gpgSign=false",
+    "-c",
+    "color.ui=false",
+];
+
+/// The only variables a caller may set, used for the commit identity.
+const AUTHOR_ENV: &[&str] = &[
+    "GIT_AUTHOR_NAME",
+    "GIT_AUTHOR_EMAIL",
+    "GIT_COMMITTER_NAME",
+    "GIT_COMMITTER_EMAIL",
+];
+
+/// Fields of one `log` record, see [`SandboxGit::log`].
+const LOG_FORMAT: &str = "--format=%H%x1f%an%x1f%ae%x1f%at%x1f%s%x1e";
+
+#[derive(Clone, Debug)]
+pub struct GitConfig {
+    root: PathBuf,
+    binary: String,
+    path: String,
+    timeout: Duration,
+    max_output_bytes: usize,
+}
+
+impl GitConfig {
+    /// `binary` is the git executable, `path` the `PATH` it runs with.
+    /// Every git call gets `timeout` and may print `max_output_bytes` per
+    /// stream.
+    pub fn new(
+        root: impl AsRef<Path>,
+        binary: impl Into<String>,
+        path: impl Into<String>,
+        timeout: Duration,
+        max_output_bytes: usize,
+    ) -> Result<Self> {
+        let binary = binary.into().trim().to_string();
+        if binary.is_empty() {
+            return Err(SandboxError::InvalidOperation(
+                "git binary must not be empty".to_string(),
+            ));
+        }
+        Ok(Self {
+            root: path::ensure_absolute_base(root.as_ref())?,
+            binary,
+            path: path.into(),
+            timeout,
+            max_output_bytes,
+        })
+    }
+
+    pub fn binary(&self) -> &str {
+        &self.binary
+    }
+}
+
+/// Git repositories in sandbox directories, driven through the git binary.
+/// Calls run like [`SandboxRun`] executions, with a cleared environment and
+/// the configured timeout and output limit, but only the git binary is
+/// allowed and it need not be on the run allowlist.
+#[derive(Clone, Debug)]
+pub struct SandboxGit {
+    run: SandboxRun,
+    binary: String,
+}
+
+impl SandboxGit {
+    pub fn new(config: GitConfig) -> Result<Self> {
+        let fixed_env = [
+            ("PATH", config.path.as_str()),
+            ("LC_ALL", "C"),
+            ("GIT_CONFIG_NOSYSTEM", "1"),
+            ("GIT_CONFIG_GLOBAL", "/dev/null"),
+            ("GIT_TERMINAL_PROMPT", "0"),
+            ("GIT_LITERAL_PATHSPECS", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let run = RunConfig::new(
+            &config.root,
+            [config.binary.clone()],
+            AUTHOR_ENV.iter().map(|key| key.to_string()),
+            fixed_env,
+            config.timeout,
+            config.timeout,
+            config.max_output_bytes,
+        )?;
+        Ok(Self {
+            run: SandboxRun::new(run),
+            binary: config.binary,
+        })
+    }
+
+    /// Returns a handle whose repository lives in `relative`.
+    pub fn scoped(&self, relative: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            run: self.run.scoped(relative)?,
+            binary: self.binary.clone(),
+        })
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.run.config().root().to_path_buf()
+    }
+
+    pub fn is_repository(&self) -> bool {
+        self.root().join(".git").is_dir()
+    }
+
+    /// Creates the repository on branch `main`. Returns `false` when one
+    /// already existed, which is left untouched.
+    pub async fn init(&self) -> Result<bool> {
+        if self.is_repository() {
+            return Ok(false);
+        }
+        self.git("init", &["init", "--quiet"], Vec::new()).await?;
+        self.git(
+            "init",
+            &["symbolic-ref", "HEAD", "refs/heads/main"],
+            Vec::new(),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Staged, unstaged and untracked changes, untracked directories listed
+    /// file by file.
+    pub async fn status(&self) -> Result<GitStatus> {
+        self.ensure_repository().await?;
+        let output = self
+            .git(
+                "status",
+                &[
+                    "status",
+                    "--porcelain=v1",
+                    "-z",
+                    "--branch",
+                    "--untracked-files=all",
+                ],
+                Vec::new(),
+            )
+            .await?;
+        Ok(parse_status(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Unified diff of the working tree against the index, or of the index
+    /// against `HEAD` when `staged`; untracked files do not appear.
+    pub async fn diff(&self, path: Option<&str>, staged: bool) -> Result<String> {
+        self.ensure_repository().await?;
+        let mut args = vec!["diff", "--no-ext-diff", "--no-textconv"];
+        if staged {
+            args.push("--cached");
+        }
+        if let Some(path) = path {
+            self.pathspec(path)?;
+            args.extend(["--", path]);
+        }
+        let output = self.git("diff", &args, Vec::new()).await?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Stages `paths`, or every change when empty, and commits them as
+    /// `author`.
+    pub async fn commit(
+        &self,
+        message: &str,
+        author: &GitAuthor,
+        paths: &[String],
+    ) -> Result<GitCommit> {
+        self.ensure_repository().await?;
+        let message = message.trim();
+        if message.is_empty() {
+            return Err(SandboxError::InvalidOperation(
+                "commit message must not be empty".to_string(),
+            ));
+        }
+        let mut add = vec!["add", "--all", "--"];
+        if paths.is_empty() {
+            add.push(".");
+        }
+        for path in paths {
+            self.pathspec(path)?;
+            add.push(path);
+        }
+        self.git("add", &add, Vec::new()).await?;
+        let staged = self
+            .run(&["diff", "--cached", "--quiet"], Vec::new())
+            .await?;
+        if staged.exit_code == 0 {
+            return Err(SandboxError::InvalidOperation(
+                "nothing to commit".to_string(),
+            ));
+        }
+        self.git(
+            "commit",
+            &["commit", "--quiet", "--no-verify", "--message", message],
+            author.env(),
+        )
+        .await?;
+        self.log(1).await?.pop().ok_or_else(|| {
+            SandboxError::InvalidOperation("commit is missing from the log".to_string())
+        })
+    }
+
+    /// The newest `limit` commits of `HEAD`, empty before the first commit.
+    pub async fn log(&self, limit: usize) -> Result<Vec<GitCommit>> {
+        self.ensure_repository().await?;
+        let head = self
+            .run(&["rev-parse", "--quiet", "--verify", "HEAD"], Vec::new())
+            .await?;
+        if head.exit_code != 0 {
+            return Ok(Vec::new());
+        }
+        let count = format!("--max-count={limit}");
+        let output = self
+            .git("log", &["log", &count, LOG_FORMAT], Vec::new())
+            .await?;
+        Ok(parse_log(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Fails unless the scope holds a repository without filter drivers.
+    /// `add` and `status` would run a driver's `clean` or `process` command
+    /// for every path `.gitattributes` assigns to it, outside the run
+    /// allowlist; `--includes` also finds drivers in included files.
+    async fn ensure_repository(&self) -> Result<()> {
+        if !self.is_repository() {
+            return Err(SandboxError::InvalidOperation(
+                "not a git repository".to_string(),
+            ));
+        }
+        let filters = self
+            .run(
+                &[
+                    "config",
+                    "--includes",
+                    "--name-only",
+                    "--get-regexp",
+                    r"^filter\.",
+                ],
+                Vec::new(),
+            )
+            .await?;
+        match filters.exit_code {
+            // No key matched.
+            1 => Ok(()),
+            0 => Err(SandboxError::InvalidOperation(format!(
+                "repository config defines filter drivers: {}",
+                String::from_utf8_lossy(&filters.stdout)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+            _ => Err(SandboxError::Git {
+                command: "config",
+                message: String::from_utf8_lossy(&filters.stderr).trim().to_string(),
+            }),
+        }
+    }
+
+    /// Paths stay inside the scope; literal pathspecs keep them from being
+    /// read as globs or magic.
+    fn pathspec(&self, path: &str) -> Result<()> {
+        path::resolve(&self.root(), path).map(|_| ())
+    }
+
+    /// Runs git and fails on a non-zero exit, with git's message.
+    async fn git(
+        &self,
+        command: &'static str,
+        args: &[&str],
+        env: Vec<(String, String)>,
+    ) -> Result<RunOutput> {
+        let output = self.run(args, env).await?;
+        if output.exit_code != 0 {
+            let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(SandboxError::Git { command, message });
+        }
+        Ok(output)
+    }
+
+    async fn run(&self, args: &[&str], env: Vec<(String, String)>) -> Result<RunOutput> {
+        let args = GLOBAL_ARGS
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect();
+        self.run
+            .execute(
+                RunRequest::new(self.binary.as_str())
+                    .with_args(args)
+                    .with_env(env),
+            )
+            .await
+    }
+}
+
+/// Name and email recorded as author and committer.
+#[derive(Debug, Clone)]
+pub struct GitAuthor {
+    pub name: String,
+    pub email: String,
+}
+
+impl GitAuthor {
+    fn env(&self) -> Vec<(String, String)> {
+        AUTHOR_ENV
+            .iter()
+            .zip([&self.name, &self.email, &self.name, &self.email])
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct GitStatus {
+    /// `None` on a detached `HEAD`.
+    pub branch: Option<String>,
+    pub entries: Vec<GitStatusEntry>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct GitStatusEntry {
+    pub path: String,
+    /// The path before a rename or copy.
+    pub original_path: Option<String>,
+    /// Change staged in the index.
+    pub index: Option<GitChange>,
+    /// Change in the working tree not yet staged.
+    pub worktree: Option<GitChange>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GitChange {
+    Modified,
+    Added,
+    Deleted,
+    Renamed,
+    Copied,
+    TypeChanged,
+    Unmerged,
+    Untracked,
+}
+
+impl GitChange {
+    fn from_code(code: char) -> Option<Self> {
+        match code {
+            'M' => Some(Self::Modified),
+            'A' => Some(Self::Added),
+            'D' => Some(Self::Deleted),
+            'R' => Some(Self::Renamed),
+            'C' => Some(Self::Copied),
+            'T' => Some(Self::TypeChanged),
+            'U' => Some(Self::Unmerged),
+            '?' => Some(Self::Untracked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct GitCommit {
+    pub id: String,
+    pub author_name: String,
+    pub author_email: String,
+    pub time: Option<DateTime<Utc>>,
+    /// First line of the message.
+    pub summary: String,
+}
+
+/// Parses `status --porcelain=v1 -z --branch`: a `## <branch>` header, then
+/// `XY <path>` records, renames and copies followed by their source path.
+fn parse_status(output: &str) -> GitStatus {
+    let mut status = GitStatus {
+        branch: None,
+        entries: Vec::new(),
+    };
+    let mut records = output.split('\0').filter(|record| !record.is_empty());
+    while let Some(record) = records.next() {
+        if let Some(header) = record.strip_prefix("## ") {
+            status.branch = parse_branch(header);
+            continue;
+        }
+        let mut codes = record.chars();
+        let (Some(index), Some(worktree)) = (codes.next(), codes.next()) else {
+            continue;
+        };
+        let Some(path) = record.get(3..) else {
+            continue;
+        };
+        let original_path = if matches!(index, 'R' | 'C') {
+            records.next().map(str::to_string)
+        } else {
+            None
+        };
+        status.entries.push(GitStatusEntry {
+            path: path.to_string(),
+            original_path,
+            index: GitChange::from_code(index),
+            worktree: GitChange::from_code(worktree),
+        });
+    }
+    status
+}
+
+fn parse_branch(header: &str) -> Option<String> {
+    if header.starts_with("HEAD (no branch)") {
+        return None;
+    }
+    let branch = header
+        .strip_prefix("No commits yet on ")
+        .or_else(|| header.strip_prefix("Initial commit on "))
+        .unwrap_or(header);
+    let branch = branch.split("...").next().unwrap_or(branch);
+    branch.split(' ').next().map(str::to_string)
+}
+
+fn parse_log(output: &str) -> Vec<GitCommit> {
+    output
+        .split('\x1e')
+        .filter_map(|record| {
+            let mut fields = record.trim_start_matches('\n').split('\x1f');
+            let id = fields.next().filter(|id| !id.is_empty())?;
+            let author_name = fields.next()?;
+            let author_email = fields.next()?;
+            let time = fields.next()?;
+            let summary = fields.next()?;
+            Some(GitCommit {
+                id: id.to_string(),
+                author_name: author_name.to_string(),
+                author_email: author_email.to_string(),
+                time: time
+                    .parse()
+                    .ok()
+                    .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+                summary: summary.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_porcelain_status() {
+        let output = "## main...origin/main [ahead 1]\0M  src/lib.rs\0 M README.md\0R  new.rs\0old.rs\0?? notes/todo.txt\0";
+        let status = parse_status(output);
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.entries.len(), 4);
+        assert_eq!(status.entries[0].index, Some(GitChange::Modified));
+        assert_eq!(status.entries[0].worktree, None);
+        assert_eq!(status.entries[1].worktree, Some(GitChange::Modified));
+        assert_eq!(status.entries[2].path, "new.rs");
+        assert_eq!(status.entries[2].original_path.as_deref(), Some("old.rs"));
+        assert_eq!(status.entries[3].index, Some(GitChange::Untracked));
+
+        assert_eq!(
+            parse_status("## No commits yet on main\0")
+                .branch
+                .as_deref(),
+            Some("main")
+        );
+        assert_eq!(parse_status("## HEAD (no branch)\0").branch, None);
+    }
+
+    #[test]
+    fn parses_log_records() {
+        let output = "abc\x1fAda\x1fada@example.com\x1f1700000000\x1fsecond\x1e\n\
+                      def\x1fBob\x1fbob@example.com\x1f1690000000\x1ffirst\x1e\n";
+        let commits = parse_log(output);
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].id, "abc");
+        assert_eq!(commits[0].summary, "second");
+        assert_eq!(commits[1].author_email, "bob@example.com");
+        assert_eq!(commits[1].time.unwrap().timestamp(), 1_690_000_000);
+    }
+}
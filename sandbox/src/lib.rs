@@ -1,21 +1,36 @@
+pub mod agent_actions;
 pub mod agent_dispatcher;
+pub mod diff;
 pub mod errors;
+pub mod fault;
 pub mod fs;
+pub mod git;
+pub mod metrics;
 pub mod micro;
 pub mod run;
 pub mod wasm;
 
 pub(crate) mod path;
+pub(crate) mod shard;
 
+pub use agent_actions::{
+    AgentActionExecutor, AgentActionReport, AgentActionStatus, AgentApplyReport,
+};
 pub use agent_dispatcher::{
     AgentAction, AgentContext, AgentContextFile, AgentDispatchRequest, AgentDispatcher,
-    AgentDispatcherConfig, AgentFileContent, AgentKind, AgentMetadata, AgentOutcome,
-    AgentParameters, AgentTaskSnapshot, AgentTaskStatus, AgentTaskSubmission,
+    AgentDispatcherConfig, AgentEvent, AgentFailureKind, AgentFileContent, AgentFilePreview,
+    AgentHistoryPage, AgentHistoryQuery, AgentKind, AgentMetadata, AgentOutcome, AgentParameters,
+    AgentPersona, AgentSubtask, AgentTaskCounts, AgentTaskError, AgentTaskOutput,
+    AgentTaskSnapshot, AgentTaskStatus, AgentTaskSubmission, DispatchRateLimit,
 };
 pub use errors::{Result, SandboxError};
-pub use fs::{FileEntry, SandboxConfig, SandboxFs};
+pub use fault::{FaultConfig, FaultCounts, FaultInjector};
+pub use fs::{FileEntry, FileKind, SandboxConfig, SandboxFs, WalkEntry, WalkListing, WalkOptions};
+pub use git::{GitAuthor, GitChange, GitCommit, GitConfig, GitStatus, GitStatusEntry, SandboxGit};
+pub use metrics::{NoMetrics, SandboxMetrics};
 pub use micro::{
-    MicroConfig, MicroExecuteRequest, MicroImage, MicroInstance, MicroOutput, MicroStartRequest,
-    SandboxMicro,
+    MicroConfig, MicroExecuteRequest, MicroImage, MicroInstance, MicroOutput, MicroRepl,
+    MicroStartRequest, SandboxMicro,
 };
+pub use shard::LockContention;
 pub use wasm::{SandboxWasm, WasmConfig, WasmInvocation, WasmModuleSource, WasmValue};
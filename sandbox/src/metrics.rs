@@ -0,0 +1,49 @@
+//! Instrumentation of the sandbox internals. An embedder implements
+//! [`SandboxMetrics`] on top of whatever it exports to (Prometheus,
+//! OpenTelemetry, statsd) and attaches it with `with_metrics` on a
+//! subsystem, or on [`AgentDispatcherConfig`](crate::AgentDispatcherConfig)
+//! for the LLM calls of the agents. Handles scoped from an instrumented one
+//! report to the same sink. Every hook defaults to doing nothing, so an
+//! implementation only overrides what it records, and nothing is recorded
+//! until a sink is attached.
+//!
+//! Hooks run inline on the instrumented path and must not block.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub trait SandboxMetrics: Debug + Send + Sync {
+    /// Bytes read from a workspace file.
+    fn fs_read(&self, _bytes: u64) {}
+
+    /// Bytes written to a workspace file, including copies.
+    fn fs_written(&self, _bytes: u64) {}
+
+    /// Time the OS took to spawn a run process, one-shot or interactive.
+    fn run_spawn(&self, _elapsed: Duration) {}
+
+    /// Time to compile a wasm module.
+    fn wasm_compile(&self, _elapsed: Duration) {}
+
+    /// Time to instantiate a compiled wasm module and run the invoked
+    /// function, traps included.
+    fn wasm_execute(&self, _elapsed: Duration) {}
+
+    /// Time to start a micro instance of `image`, including its init script.
+    fn micro_start(&self, _image: &str, _elapsed: Duration) {}
+
+    /// Time of one agent chat completion request; calls rejected by the open
+    /// circuit never reach the backend and are not recorded.
+    fn llm_request(&self, _success: bool, _elapsed: Duration) {}
+}
+
+/// The sink attached by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoMetrics;
+
+impl SandboxMetrics for NoMetrics {}
+
+pub(crate) fn none() -> Arc<dyn SandboxMetrics> {
+    Arc::new(NoMetrics)
+}
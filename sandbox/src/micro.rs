@@ -1,16 +1,105 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use parking_lot::Mutex;
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex as AsyncMutex;
 use tokio::time::timeout;
 use uuid::Uuid;
 
 use crate::errors::{Result, SandboxError};
+use crate::metrics::{self, SandboxMetrics};
 use crate::path;
+use crate::shard::{LockContention, ShardedMap};
+
+/// Interpreters that can run as a long-lived process per instance, so
+/// variables and imports survive from one execution to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MicroRepl {
+    /// Runs the image command with `-c` and a driver that `exec`s each
+    /// execution in one module namespace.
+    Python,
+    /// Runs the image command with `-e` and a driver that evaluates each
+    /// execution in the global context, awaiting a returned promise.
+    Node,
+}
+
+impl MicroRepl {
+    fn driver(self) -> [&'static str; 2] {
+        match self {
+            Self::Python => ["-c", PYTHON_DRIVER],
+            Self::Node => ["-e", NODE_DRIVER],
+        }
+    }
+}
+
+/// Reads `<length>\n<code>` frames from a private copy of stdin and ends
+/// the output of each execution with the marker on both streams; stdin of
+/// the executed code is `/dev/null`. `SystemExit` ends the interpreter.
+const PYTHON_DRIVER: &str = r#"import os, sys, traceback
+marker = b"\x1e" + os.environ.pop("MICRO_REPL_NONCE").encode()
+requests = os.fdopen(os.dup(0), "rb")
+os.dup2(os.open(os.devnull, os.O_RDONLY), 0)
+scope = {"__name__": "__main__", "__builtins__": __builtins__}
+while True:
+    header = requests.readline()
+    if not header:
+        break
+    code = requests.read(int(header)).decode()
+    status = 0
+    try:
+        exec(compile(code, "<micro>", "exec"), scope)
+    except SystemExit:
+        raise
+    except BaseException:
+        kind, error, trace = sys.exc_info()
+        traceback.print_exception(kind, error, trace.tb_next)
+        status = 1
+    sys.stdout.flush()
+    sys.stderr.flush()
+    os.write(2, marker + b"\n")
+    os.write(1, marker + b" " + str(status).encode() + b"\n")
+"#;
+
+/// The same protocol as [`PYTHON_DRIVER`]; `process.exit` ends the
+/// interpreter.
+const NODE_DRIVER: &str = r#"const fs = require("fs");
+const vm = require("vm");
+const marker = "\x1e" + process.env.MICRO_REPL_NONCE;
+delete process.env.MICRO_REPL_NONCE;
+globalThis.require = require;
+let pending = Buffer.alloc(0);
+let queue = Promise.resolve();
+async function run(code) {
+  let status = 0;
+  try {
+    await vm.runInThisContext(code, { filename: "micro.js" });
+  } catch (err) {
+    fs.writeSync(2, (err && err.stack ? err.stack : String(err)) + "\n");
+    status = 1;
+  }
+  fs.writeSync(2, marker + "\n");
+  fs.writeSync(1, marker + " " + status + "\n");
+}
+process.stdin.on("data", (chunk) => {
+  pending = Buffer.concat([pending, chunk]);
+  for (;;) {
+    const eol = pending.indexOf(10);
+    if (eol < 0) return;
+    const end = eol + 1 + Number(pending.subarray(0, eol).toString());
+    if (pending.length < end) return;
+    const code = pending.subarray(eol + 1, end).toString();
+    pending = pending.subarray(end);
+    queue = queue.then(() => run(code));
+  }
+});
+"#;
 
 #[derive(Clone, Debug)]
 pub struct MicroImage {
@@ -19,6 +108,7 @@ pub struct MicroImage {
     args: Vec<String>,
     extension: String,
     env: HashMap<String, String>,
+    repl: Option<MicroRepl>,
 }
 
 impl MicroImage {
@@ -64,9 +154,18 @@ impl MicroImage {
             args,
             extension,
             env,
+            repl: None,
         })
     }
 
+    /// Keeps one interpreter per instance running instead of spawning the
+    /// command for every execution. The image args come before the driver,
+    /// so they must not take a script of their own.
+    pub fn with_repl(mut self, repl: Option<MicroRepl>) -> Self {
+        self.repl = repl;
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -86,6 +185,10 @@ impl MicroImage {
     pub fn env(&self) -> impl Iterator<Item = (&String, &String)> {
         self.env.iter()
     }
+
+    pub fn repl(&self) -> Option<MicroRepl> {
+        self.repl
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -183,48 +286,103 @@ impl MicroConfig {
 
 #[derive(Debug)]
 pub struct SandboxMicro {
-    config: MicroConfig,
-    instances: Mutex<HashMap<Uuid, MicroVm>>,
+    config: ArcSwap<MicroConfig>,
+    /// Sharded so calls on different instances do not queue behind each
+    /// other.
+    instances: ShardedMap<Uuid, MicroVm>,
+    metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl SandboxMicro {
     pub fn new(config: MicroConfig) -> Self {
         Self {
-            config,
-            instances: Mutex::new(HashMap::new()),
+            config: ArcSwap::from_pointee(config),
+            instances: ShardedMap::new("micro_instances"),
+            metrics: metrics::none(),
         }
     }
 
-    pub fn config(&self) -> &MicroConfig {
-        &self.config
+    /// Reports the start latency of every instance to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn SandboxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn config(&self) -> Arc<MicroConfig> {
+        self.config.load_full()
+    }
+
+    /// Replaces the configuration. Running instances stay up, but executing
+    /// in one whose image was removed fails from now on. The root cannot
+    /// change.
+    pub fn reload(&self, config: MicroConfig) -> Result<()> {
+        path::ensure_same_root(self.config.load().root(), config.root())?;
+        self.config.store(Arc::new(config));
+        Ok(())
+    }
+
+    /// Number of running instances across all scopes.
+    pub fn active_instances(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Lock statistics of the instance table.
+    pub fn lock_contention(&self) -> LockContention {
+        self.instances.contention()
+    }
+
+    /// Working directories of the running instances.
+    pub fn workdirs(&self) -> Vec<PathBuf> {
+        self.instances
+            .values_cloned()
+            .into_iter()
+            .map(|vm| vm.workdir)
+            .collect()
     }
 
     pub async fn start(&self, request: MicroStartRequest) -> Result<MicroInstance> {
-        let image = self
-            .config
+        let started = Instant::now();
+        let config = self.config.load_full();
+        let image = config
             .image(&request.image)
             .cloned()
             .ok_or_else(|| SandboxError::MicroImageNotConfigured(request.image.clone()))?;
 
         let vm_id = Uuid::new_v4();
-        let workdir = self.config.root().join(vm_id.to_string());
+        let parent = match &request.scope {
+            Some(scope) => path::resolve(config.root(), scope)?,
+            None => config.root().to_path_buf(),
+        };
+        let workdir = parent.join(vm_id.to_string());
         fs::create_dir_all(&workdir).await?;
 
-        if let Some(script) = request.init_script {
-            if !script.trim().is_empty() {
-                if let Err(err) = run_code(
-                    &image,
-                    &self.config,
-                    &workdir,
-                    &script,
-                    self.config.default_timeout(),
-                )
+        let mut interpreter = None;
+        let script = request
+            .init_script
+            .filter(|script| !script.trim().is_empty());
+        let limit = config.default_timeout();
+        let prepared = match (image.repl(), script) {
+            (Some(repl), Some(script)) => interpret(
+                repl,
+                &image,
+                &config,
+                &workdir,
+                &mut interpreter,
+                &script,
+                limit,
+            )
+            .await
+            .map(drop),
+            (Some(repl), None) => Interpreter::spawn(repl, &image, &config, &workdir)
+                .map(|spawned| interpreter = Some(spawned)),
+            (None, Some(script)) => run_code(&image, &config, &workdir, &script, limit)
                 .await
-                {
-                    let _ = fs::remove_dir_all(&workdir).await;
-                    return Err(err);
-                }
-            }
+                .map(drop),
+            (None, None) => Ok(()),
+        };
+        if let Err(err) = prepared {
+            let _ = fs::remove_dir_all(&workdir).await;
+            return Err(err);
         }
 
         let instance = MicroInstance {
@@ -232,59 +390,86 @@ impl SandboxMicro {
             image: image.name().to_string(),
             workdir: workdir.clone(),
         };
-        let mut guard = self.instances.lock();
-        guard.insert(
+        self.instances.insert(
             vm_id,
             MicroVm {
                 id: vm_id,
                 image: instance.image.clone(),
                 workdir,
+                scope: request.scope,
+                last_used: Instant::now(),
+                interpreter: Arc::new(AsyncMutex::new(interpreter)),
             },
         );
+        self.metrics.micro_start(&instance.image, started.elapsed());
         Ok(instance)
     }
 
     pub async fn execute(&self, request: MicroExecuteRequest) -> Result<MicroOutput> {
-        let (image, workdir) = {
-            let guard = self.instances.lock();
-            let vm = guard
-                .get(&request.vm_id)
-                .ok_or_else(|| SandboxError::MicroVmNotFound(request.vm_id.to_string()))?;
-            let image = self
-                .config
-                .image(&vm.image)
-                .cloned()
-                .ok_or_else(|| SandboxError::MicroImageNotConfigured(vm.image.clone()))?;
-            (image, vm.workdir.clone())
-        };
+        let config = self.config.load_full();
+        let (image, workdir, interpreter) = self
+            .instances
+            .with_mut(&request.vm_id, |vm| {
+                if !vm.visible_in(request.scope.as_deref()) {
+                    return None;
+                }
+                vm.last_used = Instant::now();
+                Some((vm.image.clone(), vm.workdir.clone(), vm.interpreter.clone()))
+            })
+            .flatten()
+            .ok_or_else(|| SandboxError::MicroVmNotFound(request.vm_id.to_string()))?;
+        let image = config
+            .image(&image)
+            .cloned()
+            .ok_or(SandboxError::MicroImageNotConfigured(image))?;
 
-        let timeout = request
-            .timeout
-            .unwrap_or_else(|| self.config.default_timeout());
+        let timeout = request.timeout.unwrap_or_else(|| config.default_timeout());
         if timeout.is_zero() {
             return Err(SandboxError::InvalidOperation(
                 "micro execution timeout must be greater than zero".to_string(),
             ));
         }
-        if timeout > self.config.max_timeout() {
+        if timeout > config.max_timeout() {
             return Err(SandboxError::InvalidOperation(format!(
                 "requested timeout {:?} exceeds maximum {:?}",
                 timeout,
-                self.config.max_timeout()
+                config.max_timeout()
             )));
         }
 
-        run_code(&image, &self.config, &workdir, &request.code, timeout).await
+        let output = match image.repl() {
+            Some(repl) => {
+                let mut slot = interpreter.lock().await;
+                interpret(
+                    repl,
+                    &image,
+                    &config,
+                    &workdir,
+                    &mut slot,
+                    &request.code,
+                    timeout,
+                )
+                .await
+            }
+            None => run_code(&image, &config, &workdir, &request.code, timeout).await,
+        };
+        self.instances
+            .with_mut(&request.vm_id, |vm| vm.last_used = Instant::now());
+        output
     }
 
     pub async fn stop(&self, vm_id: Uuid) -> Result<()> {
-        let workdir = {
-            let mut guard = self.instances.lock();
-            let vm = guard
-                .remove(&vm_id)
-                .ok_or_else(|| SandboxError::MicroVmNotFound(vm_id.to_string()))?;
-            vm.workdir
-        };
+        self.stop_in(vm_id, None).await
+    }
+
+    /// Stops `vm_id` only if it was started in `scope`; `None` matches any
+    /// instance.
+    pub async fn stop_in(&self, vm_id: Uuid, scope: Option<&Path>) -> Result<()> {
+        let workdir = self
+            .instances
+            .remove_if(&vm_id, |vm| vm.visible_in(scope))
+            .ok_or_else(|| SandboxError::MicroVmNotFound(vm_id.to_string()))?
+            .workdir;
 
         match fs::remove_dir_all(&workdir).await {
             Ok(_) => Ok(()),
@@ -292,12 +477,60 @@ impl SandboxMicro {
             Err(err) => Err(SandboxError::Io(err)),
         }
     }
+
+    /// Stops every instance started in `scope`, returning how many were
+    /// stopped.
+    pub async fn stop_scope(&self, scope: &Path) -> Result<usize> {
+        let ids = self.instances.keys_where(|vm| vm.visible_in(Some(scope)));
+        let mut stopped = 0;
+        for vm_id in ids {
+            match self.stop_in(vm_id, Some(scope)).await {
+                Ok(()) => stopped += 1,
+                Err(SandboxError::MicroVmNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(stopped)
+    }
+
+    /// Stops every instance that has not been started or executed in for
+    /// at least `idle`, returning how many were stopped.
+    pub async fn stop_idle(&self, idle: Duration) -> Result<usize> {
+        let ids = self
+            .instances
+            .keys_where(|vm| vm.last_used.elapsed() >= idle);
+        let mut stopped = 0;
+        for vm_id in ids {
+            match self.stop(vm_id).await {
+                Ok(()) => stopped += 1,
+                Err(SandboxError::MicroVmNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(stopped)
+    }
+
+    /// Stops every running instance, returning how many were stopped.
+    pub async fn shutdown(&self) -> Result<usize> {
+        let ids = self.instances.keys_where(|_| true);
+        let mut stopped = 0;
+        for vm_id in ids {
+            match self.stop(vm_id).await {
+                Ok(()) => stopped += 1,
+                Err(SandboxError::MicroVmNotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(stopped)
+    }
 }
 
 #[derive(Debug)]
 pub struct MicroStartRequest {
     pub image: String,
     pub init_script: Option<String>,
+    /// Directory below the sandbox root that holds the instance workdir.
+    pub scope: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -305,6 +538,8 @@ pub struct MicroExecuteRequest {
     pub vm_id: Uuid,
     pub code: String,
     pub timeout: Option<Duration>,
+    /// Restricts execution to instances started in this scope.
+    pub scope: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -336,33 +571,220 @@ pub struct MicroOutput {
     pub duration: Duration,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct MicroVm {
     id: Uuid,
     image: String,
     workdir: PathBuf,
+    scope: Option<PathBuf>,
+    last_used: Instant,
+    /// The running interpreter of an image with a REPL, spawned again by
+    /// the next execution after it exited, timed out or overflowed. Locked
+    /// for a whole execution, so executions of one instance queue.
+    interpreter: Arc<AsyncMutex<Option<Interpreter>>>,
 }
 
-async fn run_code(
+impl MicroVm {
+    fn visible_in(&self, scope: Option<&Path>) -> bool {
+        match scope {
+            Some(scope) => self.scope.as_deref() == Some(scope),
+            None => true,
+        }
+    }
+}
+
+/// A long-lived interpreter speaking the protocol of the REPL drivers.
+/// Dropping it kills the process.
+#[derive(Debug)]
+struct Interpreter {
+    child: Child,
+    requests: ChildStdin,
+    stdout: ChildStdout,
+    stderr: ChildStderr,
+    /// Output read past the marker of the previous execution, such as that
+    /// of a timer that fired late.
+    stdout_rest: Vec<u8>,
+    stderr_rest: Vec<u8>,
+    marker: Vec<u8>,
+}
+
+impl Interpreter {
+    fn spawn(
+        repl: MicroRepl,
+        image: &MicroImage,
+        config: &MicroConfig,
+        workdir: &Path,
+    ) -> Result<Self> {
+        let nonce = Uuid::new_v4().simple().to_string();
+        let mut command = command(image, config, workdir);
+        command.stdin(std::process::Stdio::piped());
+        command.env("MICRO_REPL_NONCE", &nonce);
+        command.args(repl.driver());
+        let mut child = command.spawn()?;
+        let (Some(requests), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            return Err(SandboxError::InvalidOperation(
+                "micro interpreter pipes are unavailable".to_string(),
+            ));
+        };
+        Ok(Self {
+            child,
+            requests,
+            stdout,
+            stderr,
+            stdout_rest: Vec::new(),
+            stderr_rest: Vec::new(),
+            marker: format!("\x1e{nonce}").into_bytes(),
+        })
+    }
+
+    /// Runs `source`. The flag is false when the interpreter exited
+    /// before finishing it; the output then carries its exit status.
+    async fn execute(&mut self, source: &str, limit: usize) -> Result<(MicroOutput, bool)> {
+        let start = Instant::now();
+        let mut frame = format!("{}\n", source.len()).into_bytes();
+        frame.extend_from_slice(source.as_bytes());
+        // A broken pipe means the interpreter is gone; its pipes close and
+        // its exit status tells why.
+        let _ = self.requests.write_all(&frame).await;
+
+        let (stdout, stderr) = tokio::join!(
+            read_to_marker(
+                &mut self.stdout,
+                &mut self.stdout_rest,
+                &self.marker,
+                limit,
+                "stdout"
+            ),
+            read_to_marker(
+                &mut self.stderr,
+                &mut self.stderr_rest,
+                &self.marker,
+                limit,
+                "stderr"
+            ),
+        );
+        match (stdout?, stderr?) {
+            (Some((stdout, status)), Some((stderr, _))) => {
+                let exit_code = status.trim().parse().map_err(|_| {
+                    SandboxError::InvalidOperation(format!(
+                        "micro interpreter sent an invalid status '{status}'"
+                    ))
+                })?;
+                let duration = start.elapsed();
+                Ok((
+                    MicroOutput {
+                        exit_code,
+                        stdout,
+                        stderr,
+                        duration,
+                    },
+                    true,
+                ))
+            }
+            (stdout, stderr) => {
+                let status = self.child.wait().await?;
+                let exit_code = status.code().ok_or(SandboxError::TerminatedBySignal)?;
+                let stdout =
+                    stdout.map_or_else(|| std::mem::take(&mut self.stdout_rest), |read| read.0);
+                let stderr =
+                    stderr.map_or_else(|| std::mem::take(&mut self.stderr_rest), |read| read.0);
+                let duration = start.elapsed();
+                Ok((
+                    MicroOutput {
+                        exit_code,
+                        stdout,
+                        stderr,
+                        duration,
+                    },
+                    false,
+                ))
+            }
+        }
+    }
+}
+
+/// Reads `pipe` into `buffer` until `marker` and the rest of its line show
+/// up, returning the output before the marker and that line; later output
+/// stays in `buffer`. `None` once the pipe closed without a marker.
+async fn read_to_marker<R: AsyncRead + Unpin>(
+    pipe: &mut R,
+    buffer: &mut Vec<u8>,
+    marker: &[u8],
+    limit: usize,
+    stream: &'static str,
+) -> Result<Option<(Vec<u8>, String)>> {
+    let mut chunk = [0u8; 8192];
+    loop {
+        if let Some(at) = buffer
+            .windows(marker.len())
+            .position(|window| window == marker)
+        {
+            if let Some(eol) = buffer[at..].iter().position(|byte| *byte == b'\n') {
+                if at > limit {
+                    return Err(SandboxError::OutputTooLarge { stream, limit });
+                }
+                let line =
+                    String::from_utf8_lossy(&buffer[at + marker.len()..at + eol]).into_owned();
+                let output = buffer.drain(..=at + eol).take(at).collect();
+                return Ok(Some((output, line)));
+            }
+        } else if buffer.len() > limit + marker.len() {
+            return Err(SandboxError::OutputTooLarge { stream, limit });
+        }
+        let read = pipe.read(&mut chunk).await?;
+        if read == 0 {
+            if buffer.len() > limit {
+                return Err(SandboxError::OutputTooLarge { stream, limit });
+            }
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Runs `source` in the interpreter of `slot`, spawning one if there is
+/// none. The interpreter is dropped, and killed, when it exits, times out
+/// or fails; the next execution starts a fresh one with empty state.
+async fn interpret(
+    repl: MicroRepl,
     image: &MicroImage,
     config: &MicroConfig,
     workdir: &Path,
+    slot: &mut Option<Interpreter>,
     source: &str,
-    timeout: Duration,
+    limit: Duration,
 ) -> Result<MicroOutput> {
-    let mut contents = source.to_string();
-    if !contents.ends_with('\n') {
-        contents.push('\n');
-    }
-    let script_name = format!("script_{}.{}", Uuid::new_v4(), image.extension());
-    let script_path = workdir.join(script_name);
-
+    let interpreter = match slot {
+        Some(interpreter) => interpreter,
+        None => slot.insert(Interpreter::spawn(repl, image, config, workdir)?),
+    };
+    match timeout(
+        limit,
+        interpreter.execute(source, config.max_output_bytes()),
+    )
+    .await
     {
-        let mut file = fs::File::create(&script_path).await?;
-        file.write_all(contents.as_bytes()).await?;
-        file.sync_all().await?;
+        Ok(Ok((output, true))) => Ok(output),
+        Ok(Ok((output, false))) => {
+            *slot = None;
+            Ok(output)
+        }
+        Ok(Err(err)) => {
+            *slot = None;
+            Err(err)
+        }
+        Err(_) => {
+            *slot = None;
+            Err(SandboxError::Timeout(limit))
+        }
     }
+}
 
+/// The image command in `workdir` with a clean environment and piped
+/// output.
+fn command(image: &MicroImage, config: &MicroConfig, workdir: &Path) -> Command {
     let mut command = Command::new(image.command());
     command.kill_on_drop(true);
     command.current_dir(workdir);
@@ -381,6 +803,30 @@ async fn run_code(
     for arg in image.args() {
         command.arg(arg);
     }
+    command
+}
+
+async fn run_code(
+    image: &MicroImage,
+    config: &MicroConfig,
+    workdir: &Path,
+    source: &str,
+    timeout: Duration,
+) -> Result<MicroOutput> {
+    let mut contents = source.to_string();
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    let script_name = format!("script_{}.{}", Uuid::new_v4(), image.extension());
+    let script_path = workdir.join(script_name);
+
+    {
+        let mut file = fs::File::create(&script_path).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+    }
+
+    let mut command = command(image, config, workdir);
     command.arg(&script_path);
 
     let start = Instant::now();
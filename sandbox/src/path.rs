@@ -11,6 +11,19 @@ pub fn ensure_absolute_base(base_dir: &Path) -> Result<PathBuf> {
     Ok(base_dir.to_path_buf())
 }
 
+/// Reloaded configurations must keep the root: running sessions and scoped
+/// handles hold paths below it.
+pub fn ensure_same_root(current: &Path, reloaded: &Path) -> Result<()> {
+    if current != reloaded {
+        return Err(SandboxError::InvalidOperation(format!(
+            "sandbox root cannot change on reload ({} -> {})",
+            current.display(),
+            reloaded.display()
+        )));
+    }
+    Ok(())
+}
+
 pub fn resolve(base_dir: &Path, relative: impl AsRef<Path>) -> Result<PathBuf> {
     let relative = relative.as_ref();
     if relative.components().count() == 0 {
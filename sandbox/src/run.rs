@@ -1,15 +1,33 @@
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{oneshot, Mutex as AsyncMutex, Notify};
 use tokio::time::timeout;
 use tracing::instrument;
+use uuid::Uuid;
 
 use crate::errors::{Result, SandboxError};
+use crate::fault::FaultInjector;
+use crate::metrics::{self, SandboxMetrics};
 use crate::path;
+use crate::shard::{LockContention, ShardedMap};
+
+/// Sessions allowed at once unless [`RunConfig::with_sessions`] says
+/// otherwise.
+const DEFAULT_MAX_SESSIONS: usize = 64;
+/// How long the output of an exited session is kept for a last read.
+const SESSION_LINGER: Duration = Duration::from_secs(60);
+/// How long an exited session waits for the rest of its output; a
+/// background process holding the pipes open would otherwise keep it open.
+const SESSION_DRAIN: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub struct RunConfig {
@@ -20,6 +38,9 @@ pub struct RunConfig {
     default_timeout: Duration,
     max_timeout: Duration,
     max_output_bytes: usize,
+    max_sessions: usize,
+    /// Longest lifetime of a session, also its default.
+    max_session_timeout: Duration,
 }
 
 impl RunConfig {
@@ -73,13 +94,42 @@ impl RunConfig {
             default_timeout,
             max_timeout,
             max_output_bytes,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            max_session_timeout: max_timeout,
         })
     }
 
+    /// Allows `max_sessions` interactive sessions at once across all scopes,
+    /// each living up to `max_timeout`, which is also the default. Without
+    /// this, 64 sessions may be open and live as long as an execution may.
+    pub fn with_sessions(mut self, max_sessions: usize, max_timeout: Duration) -> Self {
+        self.max_sessions = max_sessions;
+        self.max_session_timeout = max_timeout;
+        self
+    }
+
     pub fn root(&self) -> &Path {
         &self.root
     }
 
+    /// Returns a copy of this configuration rooted at `relative`. `HOME`
+    /// follows the new root when it was pinned to the old one.
+    pub fn scoped(&self, relative: impl AsRef<Path>) -> Result<Self> {
+        let root = path::resolve(&self.root, relative)?;
+        fs::create_dir_all(&root)?;
+        let mut fixed_env = self.fixed_env.clone();
+        if let Some(home) = fixed_env.get_mut("HOME") {
+            if Path::new(home.as_str()) == self.root {
+                *home = root.to_string_lossy().to_string();
+            }
+        }
+        Ok(Self {
+            root,
+            fixed_env,
+            ..self.clone()
+        })
+    }
+
     pub fn allowed_programs(&self) -> impl Iterator<Item = &String> {
         self.allowed_programs.iter()
     }
@@ -96,6 +146,14 @@ impl RunConfig {
         self.max_output_bytes
     }
 
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    pub fn max_session_timeout(&self) -> Duration {
+        self.max_session_timeout
+    }
+
     fn is_program_allowed(&self, program: &str) -> bool {
         self.allowed_programs.contains(program)
     }
@@ -107,102 +165,105 @@ impl RunConfig {
 
 #[derive(Clone, Debug)]
 pub struct SandboxRun {
-    config: RunConfig,
+    config: Arc<ArcSwap<RunConfig>>,
+    active: Arc<AtomicUsize>,
+    /// Interactive processes of this handle and every handle scoped from it.
+    sessions: Arc<ShardedMap<Uuid, Arc<Session>>>,
+    /// Sessions being started, not yet in `sessions`.
+    starting: Arc<AtomicUsize>,
+    faults: FaultInjector,
+    metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl SandboxRun {
     pub fn new(config: RunConfig) -> Self {
-        Self { config }
+        Self {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            active: Arc::new(AtomicUsize::new(0)),
+            sessions: Arc::new(ShardedMap::new("run_sessions")),
+            starting: Arc::new(AtomicUsize::new(0)),
+            faults: FaultInjector::default(),
+            metrics: metrics::none(),
+        }
     }
 
-    pub fn config(&self) -> &RunConfig {
-        &self.config
+    /// Injects `faults` into every execution of this handle and the
+    /// handles scoped from it.
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
     }
 
-    #[instrument(skip(self, request), fields(program = %request.program))]
-    pub async fn execute(&self, request: RunRequest) -> Result<RunOutput> {
-        self.execute_inner(request).await
+    /// Reports the spawn latency of every process of this handle and the
+    /// handles scoped from it to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn SandboxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
     }
 
-    async fn execute_inner(&self, request: RunRequest) -> Result<RunOutput> {
-        let RunRequest {
-            program,
-            args,
-            stdin,
-            env,
-            working_dir,
-            timeout,
-        } = request;
+    pub fn config(&self) -> Arc<RunConfig> {
+        self.config.load_full()
+    }
 
-        if !self.config.is_program_allowed(&program) {
-            return Err(SandboxError::InvalidOperation(format!(
-                "program '{}' is not permitted in sandbox",
-                program
-            )));
-        }
+    /// Replaces the configuration for executions started from now on;
+    /// running processes keep the one they started with. The root cannot
+    /// change. Handles scoped earlier keep their copy.
+    pub fn reload(&self, config: RunConfig) -> Result<()> {
+        path::ensure_same_root(self.config.load().root(), config.root())?;
+        self.config.store(Arc::new(config));
+        Ok(())
+    }
 
-        let working_dir = match &working_dir {
-            Some(dir) => {
-                let resolved = path::resolve(self.config.root(), dir)?;
-                if !resolved.exists() {
-                    return Err(SandboxError::InvalidOperation(format!(
-                        "working directory '{}' does not exist",
-                        dir
-                    )));
-                }
-                if !resolved.is_dir() {
-                    return Err(SandboxError::InvalidOperation(format!(
-                        "working directory '{}' is not a directory",
-                        dir
-                    )));
-                }
-                resolved
-            }
-            None => self.config.root().to_path_buf(),
-        };
+    /// Scoped handles share the parent's session counter and sessions, but
+    /// only reach the sessions started in their own scope.
+    pub fn scoped(&self, relative: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            config: Arc::new(ArcSwap::from_pointee(self.config.load().scoped(relative)?)),
+            active: self.active.clone(),
+            sessions: self.sessions.clone(),
+            starting: self.starting.clone(),
+            faults: self.faults.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
 
-        let timeout_duration = timeout.unwrap_or_else(|| self.config.default_timeout());
-        if timeout_duration.is_zero() {
-            return Err(SandboxError::InvalidOperation(
-                "timeout must be greater than zero".to_string(),
-            ));
-        }
-        if timeout_duration > self.config.max_timeout() {
-            return Err(SandboxError::InvalidOperation(format!(
-                "requested timeout {:?} exceeds maximum {:?}",
-                timeout_duration,
-                self.config.max_timeout()
-            )));
-        }
+    /// Number of `execute` calls currently in flight across this handle and
+    /// every handle scoped from it.
+    pub fn active_sessions(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
 
-        let mut command = Command::new(&program);
-        command.current_dir(working_dir);
-        command.kill_on_drop(true);
-        command.stdout(std::process::Stdio::piped());
-        command.stderr(std::process::Stdio::piped());
-        if stdin.is_some() {
-            command.stdin(std::process::Stdio::piped());
-        } else {
-            command.stdin(std::process::Stdio::null());
-        }
-        command.env_clear();
-        for (key, value) in &self.config.fixed_env {
-            command.env(key, value);
-        }
-        for (key, value) in env {
-            if !self.config.is_env_allowed(&key) {
-                return Err(SandboxError::InvalidOperation(format!(
-                    "environment variable '{}' is not permitted",
-                    key
-                )));
-            }
-            command.env(key, value);
-        }
-        for arg in args {
-            command.arg(arg);
-        }
+    /// Number of interactive sessions, running or waiting for their last
+    /// read, across all scopes.
+    pub fn open_sessions(&self) -> usize {
+        self.sessions.len()
+    }
 
-        let mut child = command.spawn()?;
+    /// Lock statistics of the session table.
+    pub fn lock_contention(&self) -> LockContention {
+        self.sessions.contention()
+    }
+
+    #[instrument(skip(self, request), fields(program = %request.program))]
+    pub async fn execute(&self, request: RunRequest) -> Result<RunOutput> {
+        let _session = ActiveSession::enter(&self.active);
+        self.faults.inject("run.exec").await?;
+        self.execute_inner(request).await
+    }
+
+    async fn execute_inner(&self, request: RunRequest) -> Result<RunOutput> {
+        let config = self.config.load_full();
+        let Prepared {
+            mut command,
+            stdin,
+            timeout: timeout_duration,
+        } = prepare(
+            &config,
+            request,
+            config.default_timeout(),
+            config.max_timeout(),
+        )?;
+        let mut child = self.spawn(&mut command)?;
 
         if let Some(stdin) = stdin {
             if let Some(mut handle) = child.stdin.take() {
@@ -217,16 +278,16 @@ impl SandboxRun {
         };
         let duration = start.elapsed();
 
-        if output.stdout.len() > self.config.max_output_bytes() {
+        if output.stdout.len() > config.max_output_bytes() {
             return Err(SandboxError::OutputTooLarge {
                 stream: "stdout",
-                limit: self.config.max_output_bytes(),
+                limit: config.max_output_bytes(),
             });
         }
-        if output.stderr.len() > self.config.max_output_bytes() {
+        if output.stderr.len() > config.max_output_bytes() {
             return Err(SandboxError::OutputTooLarge {
                 stream: "stderr",
-                limit: self.config.max_output_bytes(),
+                limit: config.max_output_bytes(),
             });
         }
 
@@ -242,6 +303,437 @@ impl SandboxRun {
             duration,
         })
     }
+
+    fn spawn(&self, command: &mut Command) -> Result<Child> {
+        let started = Instant::now();
+        let child = command.spawn()?;
+        self.metrics.run_spawn(started.elapsed());
+        Ok(child)
+    }
+
+    /// Starts `request` as an interactive session: the process keeps its
+    /// stdin open for [`write_session`](Self::write_session) and its output
+    /// is collected by [`read_session`](Self::read_session). The request
+    /// timeout, `max_session_timeout` by default, bounds the lifetime of the
+    /// process. Output nobody reads is held up to `max_output_bytes` per
+    /// stream; past that the process blocks on its next write.
+    #[instrument(skip(self, request), fields(program = %request.program))]
+    pub async fn start_session(&self, request: RunRequest) -> Result<RunSessionInfo> {
+        self.faults.inject("run.session").await?;
+        let config = self.config.load_full();
+        let starting = StartingSession::enter(&self.starting);
+        if self.sessions.len() + starting.ahead >= config.max_sessions() {
+            return Err(SandboxError::InvalidOperation(format!(
+                "run session limit of {} reached",
+                config.max_sessions()
+            )));
+        }
+        let Prepared {
+            mut command,
+            stdin,
+            timeout: lifetime,
+        } = prepare(
+            &config,
+            request,
+            config.max_session_timeout(),
+            config.max_session_timeout(),
+        )?;
+        command.stdin(std::process::Stdio::piped());
+        let mut child = self.spawn(&mut command)?;
+        let mut pipe = child.stdin.take();
+        if let (Some(pipe), Some(stdin)) = (pipe.as_mut(), stdin) {
+            pipe.write_all(&stdin).await?;
+        }
+
+        let id = Uuid::new_v4();
+        let (kill, killed) = oneshot::channel();
+        let session = Arc::new(Session {
+            root: config.root().to_path_buf(),
+            started: Instant::now(),
+            stdin: AsyncMutex::new(pipe),
+            kill: Mutex::new(Some(kill)),
+            state: Mutex::new(SessionState::default()),
+            changed: Notify::new(),
+            drained: Notify::new(),
+        });
+        let limit = config.max_output_bytes();
+        let pumps = [
+            child
+                .stdout
+                .take()
+                .map(|pipe| tokio::spawn(pump(session.clone(), pipe, Stream::Stdout, limit))),
+            child
+                .stderr
+                .take()
+                .map(|pipe| tokio::spawn(pump(session.clone(), pipe, Stream::Stderr, limit))),
+        ];
+        self.sessions.insert(id, session.clone());
+        drop(starting);
+
+        let sessions = self.sessions.clone();
+        let (exit, exited) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut timed_out = false;
+            let status = tokio::select! {
+                status = child.wait() => status.ok(),
+                _ = killed => {
+                    let _ = child.start_kill();
+                    child.wait().await.ok()
+                }
+                _ = tokio::time::sleep(lifetime) => {
+                    timed_out = true;
+                    let _ = child.start_kill();
+                    child.wait().await.ok()
+                }
+            };
+            let drained = tokio::time::Instant::now() + SESSION_DRAIN;
+            for pump in pumps.into_iter().flatten() {
+                let abort = pump.abort_handle();
+                if tokio::time::timeout_at(drained, pump).await.is_err() {
+                    abort.abort();
+                }
+            }
+            let duration = session.started.elapsed();
+            {
+                let mut state = session.state.lock();
+                state.exit_code = status.and_then(|status| status.code());
+                state.timed_out = timed_out;
+                state.duration = Some(duration);
+            }
+            session.changed.notify_waiters();
+            let _ = exit.send(duration);
+            tokio::time::sleep(SESSION_LINGER).await;
+            sessions.remove(&id);
+        });
+
+        Ok(RunSessionInfo {
+            id,
+            timeout: lifetime,
+            exited,
+        })
+    }
+
+    /// Writes `data` to the stdin of session `id`; `close` closes stdin
+    /// afterwards, which ends most interactive programs.
+    pub async fn write_session(&self, id: Uuid, data: &[u8], close: bool) -> Result<()> {
+        let session = self.session(&id)?;
+        if session.state.lock().duration.is_some() {
+            return Err(SandboxError::InvalidOperation(
+                "run session has exited".to_string(),
+            ));
+        }
+        let mut stdin = session.stdin.lock().await;
+        if !data.is_empty() {
+            let pipe = stdin.as_mut().ok_or_else(|| {
+                SandboxError::InvalidOperation("stdin of run session is closed".to_string())
+            })?;
+            pipe.write_all(data).await?;
+            pipe.flush().await?;
+        }
+        if close {
+            *stdin = None;
+        }
+        Ok(())
+    }
+
+    /// Takes the output of session `id` produced since the last read,
+    /// waiting up to `wait` for some to arrive. Once the read reports the
+    /// exit, the session is gone.
+    pub async fn read_session(&self, id: Uuid, wait: Duration) -> Result<RunSessionOutput> {
+        let session = self.session(&id)?;
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let changed = session.changed.notified();
+            if session.state.lock().has_news() {
+                break;
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                break;
+            }
+        }
+        let output = session.take();
+        if output.exited {
+            self.sessions.remove(&id);
+        }
+        Ok(output)
+    }
+
+    /// Kills the process of session `id` and returns its remaining output.
+    pub async fn kill_session(&self, id: Uuid) -> Result<RunSessionOutput> {
+        let root = self.config.load().root().to_path_buf();
+        let session = self
+            .sessions
+            .remove_if(&id, |session| session.root == root)
+            .ok_or_else(|| SandboxError::RunSessionNotFound(id.to_string()))?;
+        Ok(session.kill().await)
+    }
+
+    /// Kills every session started in `scope` or below it, a directory
+    /// relative to the root of this handle; returns how many were killed.
+    pub async fn kill_scope(&self, scope: impl AsRef<Path>) -> Result<usize> {
+        let root = path::resolve(self.config.load().root(), scope)?;
+        let ids = self
+            .sessions
+            .keys_where(|session| session.root.starts_with(&root));
+        let mut killed = 0;
+        for id in ids {
+            if let Some(session) = self.sessions.remove(&id) {
+                session.kill().await;
+                killed += 1;
+            }
+        }
+        Ok(killed)
+    }
+
+    /// Session `id`, if it was started in the scope of this handle.
+    fn session(&self, id: &Uuid) -> Result<Arc<Session>> {
+        self.sessions
+            .get_cloned(id)
+            .filter(|session| session.root == self.config.load().root())
+            .ok_or_else(|| SandboxError::RunSessionNotFound(id.to_string()))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// An interactive process. The process itself is owned by the task waiting
+/// for it to exit; the session holds its pipes and what it has written.
+struct Session {
+    /// Root of the handle that started it.
+    root: PathBuf,
+    started: Instant,
+    /// `None` once closed.
+    stdin: AsyncMutex<Option<ChildStdin>>,
+    kill: Mutex<Option<oneshot::Sender<()>>>,
+    state: Mutex<SessionState>,
+    /// Signalled when output arrives or the process exits.
+    changed: Notify,
+    /// Signalled when a read empties the buffers.
+    drained: Notify,
+}
+
+#[derive(Default)]
+struct SessionState {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    /// Set once the process has exited and its output is collected.
+    duration: Option<Duration>,
+}
+
+impl SessionState {
+    fn buffer(&mut self, stream: Stream) -> &mut Vec<u8> {
+        match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        }
+    }
+
+    fn has_news(&self) -> bool {
+        !self.stdout.is_empty() || !self.stderr.is_empty() || self.duration.is_some()
+    }
+}
+
+impl Session {
+    fn take(&self) -> RunSessionOutput {
+        let mut state = self.state.lock();
+        let output = RunSessionOutput {
+            stdout: std::mem::take(&mut state.stdout),
+            stderr: std::mem::take(&mut state.stderr),
+            exit_code: state.exit_code,
+            exited: state.duration.is_some(),
+            timed_out: state.timed_out,
+            duration: state.duration.unwrap_or_else(|| self.started.elapsed()),
+        };
+        drop(state);
+        self.drained.notify_waiters();
+        output
+    }
+
+    /// Kills the process and waits for it to be reaped.
+    async fn kill(&self) -> RunSessionOutput {
+        loop {
+            let changed = self.changed.notified();
+            if self.state.lock().duration.is_some() {
+                break;
+            }
+            if let Some(kill) = self.kill.lock().take() {
+                let _ = kill.send(());
+            }
+            changed.await;
+        }
+        self.take()
+    }
+}
+
+/// Copies `pipe` into the session buffer of `stream`, waiting for a read
+/// whenever `limit` bytes are pending.
+async fn pump(
+    session: Arc<Session>,
+    mut pipe: impl AsyncRead + Unpin,
+    stream: Stream,
+    limit: usize,
+) {
+    let mut chunk = [0u8; 8192];
+    loop {
+        loop {
+            let drained = session.drained.notified();
+            if session.state.lock().buffer(stream).len() < limit {
+                break;
+            }
+            drained.await;
+        }
+        match pipe.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => {
+                session
+                    .state
+                    .lock()
+                    .buffer(stream)
+                    .extend_from_slice(&chunk[..read]);
+                session.changed.notify_waiters();
+            }
+        }
+    }
+}
+
+/// A command checked against the configuration, ready to spawn.
+struct Prepared {
+    command: Command,
+    stdin: Option<Vec<u8>>,
+    timeout: Duration,
+}
+
+fn prepare(
+    config: &RunConfig,
+    request: RunRequest,
+    default_timeout: Duration,
+    max_timeout: Duration,
+) -> Result<Prepared> {
+    let RunRequest {
+        program,
+        args,
+        stdin,
+        env,
+        working_dir,
+        timeout,
+    } = request;
+
+    if !config.is_program_allowed(&program) {
+        return Err(SandboxError::InvalidOperation(format!(
+            "program '{}' is not permitted in sandbox",
+            program
+        )));
+    }
+
+    let working_dir = match &working_dir {
+        Some(dir) => {
+            let resolved = path::resolve(config.root(), dir)?;
+            if !resolved.exists() {
+                return Err(SandboxError::InvalidOperation(format!(
+                    "working directory '{}' does not exist",
+                    dir
+                )));
+            }
+            if !resolved.is_dir() {
+                return Err(SandboxError::InvalidOperation(format!(
+                    "working directory '{}' is not a directory",
+                    dir
+                )));
+            }
+            resolved
+        }
+        None => config.root().to_path_buf(),
+    };
+
+    let timeout_duration = timeout.unwrap_or(default_timeout);
+    if timeout_duration.is_zero() {
+        return Err(SandboxError::InvalidOperation(
+            "timeout must be greater than zero".to_string(),
+        ));
+    }
+    if timeout_duration > max_timeout {
+        return Err(SandboxError::InvalidOperation(format!(
+            "requested timeout {:?} exceeds maximum {:?}",
+            timeout_duration, max_timeout
+        )));
+    }
+
+    let mut command = Command::new(&program);
+    command.current_dir(working_dir);
+    command.kill_on_drop(true);
+    command.stdout(std::process::Stdio::piped());
+    command.stderr(std::process::Stdio::piped());
+    if stdin.is_some() {
+        command.stdin(std::process::Stdio::piped());
+    } else {
+        command.stdin(std::process::Stdio::null());
+    }
+    command.env_clear();
+    for (key, value) in &config.fixed_env {
+        command.env(key, value);
+    }
+    for (key, value) in env {
+        if !config.is_env_allowed(&key) {
+            return Err(SandboxError::InvalidOperation(format!(
+                "environment variable '{}' is not permitted",
+                key
+            )));
+        }
+        command.env(key, value);
+    }
+    for arg in args {
+        command.arg(arg);
+    }
+
+    Ok(Prepared {
+        command,
+        stdin,
+        timeout: timeout_duration,
+    })
+}
+
+/// Keeps `SandboxRun::active` incremented for as long as it is alive, so
+/// cancelled or failed executions are not left counted.
+struct ActiveSession<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveSession<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a session against `max_sessions` from the limit check until it
+/// is in `SandboxRun::sessions`, so concurrent starts cannot all pass the
+/// check. `ahead` is the number of starts that were already under way.
+struct StartingSession<'a> {
+    counter: &'a AtomicUsize,
+    ahead: usize,
+}
+
+impl<'a> StartingSession<'a> {
+    fn enter(counter: &'a AtomicUsize) -> Self {
+        let ahead = counter.fetch_add(1, Ordering::SeqCst);
+        Self { counter, ahead }
+    }
+}
+
+impl Drop for StartingSession<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[derive(Debug)]
@@ -292,6 +784,30 @@ impl RunRequest {
     }
 }
 
+/// A started session. Its process is killed once `timeout` has passed.
+#[derive(Debug)]
+pub struct RunSessionInfo {
+    pub id: Uuid,
+    pub timeout: Duration,
+    /// Resolves with the run time once the process has ended, whether it
+    /// exited, timed out or was killed, and whether or not anyone reads it.
+    pub exited: oneshot::Receiver<Duration>,
+}
+
+/// Output of a session since the previous read.
+#[derive(Debug)]
+pub struct RunSessionOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `None` while the process runs, or when a signal ended it.
+    pub exit_code: Option<i32>,
+    pub exited: bool,
+    /// The process was killed at the end of its timeout.
+    pub timed_out: bool,
+    /// Time since the start, or the whole run once exited.
+    pub duration: Duration,
+}
+
 #[derive(Debug)]
 pub struct RunOutput {
     pub exit_code: i32,
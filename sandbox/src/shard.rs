@@ -0,0 +1,226 @@
+//! A hash map split into independently locked shards, so calls touching
+//! different keys rarely wait for each other. Every shard lock is counted,
+//! and so is every time it was already held, which shows whether the maps
+//! still serialize callers under load.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+
+/// Upper bound for the shard count; more shards only cost memory.
+const MAX_SHARDS: usize = 64;
+
+/// How often the shards of one map were locked and had to be waited for.
+/// The counters only grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockContention {
+    /// The map, e.g. `micro_instances`.
+    pub map: &'static str,
+    pub acquisitions: u64,
+    /// Acquisitions that found the shard locked.
+    pub contended: u64,
+    /// Total time spent waiting in contended acquisitions.
+    pub wait: Duration,
+}
+
+pub(crate) struct ShardedMap<K, V> {
+    name: &'static str,
+    shards: Box<[Mutex<HashMap<K, V>>]>,
+    hasher: RandomState,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+impl<K, V> std::fmt::Debug for ShardedMap<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedMap")
+            .field("name", &self.name)
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    /// Four shards per available core, as a power of two.
+    pub(crate) fn new(name: &'static str) -> Self {
+        let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+        Self::with_shards(name, cores * 4)
+    }
+
+    pub(crate) fn with_shards(name: &'static str, shards: usize) -> Self {
+        let shards = shards.clamp(1, MAX_SHARDS).next_power_of_two();
+        Self {
+            name,
+            shards: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn lock<'a>(&self, shard: &'a Mutex<HashMap<K, V>>) -> MutexGuard<'a, HashMap<K, V>> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = shard.try_lock() {
+            return guard;
+        }
+        let started = Instant::now();
+        let guard = shard.lock();
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        guard
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, HashMap<K, V>> {
+        // The shard count is a power of two, so masking picks evenly.
+        let index = self.hasher.hash_one(key) as usize & (self.shards.len() - 1);
+        self.lock(&self.shards[index])
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).insert(key, value)
+    }
+
+    pub(crate) fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).remove(key)
+    }
+
+    /// Removes the entry if `remove` accepts it, under a single lock.
+    pub(crate) fn remove_if(&self, key: &K, remove: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = self.shard(key);
+        if shard.get(key).is_some_and(remove) {
+            shard.remove(key)
+        } else {
+            None
+        }
+    }
+
+    /// Runs `f` on the entry while its shard is locked.
+    pub(crate) fn with_mut<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).get_mut(key).map(f)
+    }
+
+    /// Runs `f` on the entry, inserting `V::default()` first if there is none.
+    pub(crate) fn with_entry<R>(&self, key: K, f: impl FnOnce(&mut V) -> R) -> R
+    where
+        V: Default,
+    {
+        let mut shard = self.shard(&key);
+        f(shard.entry(key).or_default())
+    }
+
+    pub(crate) fn get_cloned(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.shard(key).get(key).cloned()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards.iter().map(|shard| self.lock(shard).len()).sum()
+    }
+
+    /// Keys of the entries matching `filter`, one shard at a time; entries
+    /// added or removed meanwhile may or may not be seen.
+    pub(crate) fn keys_where(&self, mut filter: impl FnMut(&V) -> bool) -> Vec<K>
+    where
+        K: Clone,
+    {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = self.lock(shard);
+            keys.extend(
+                shard
+                    .iter()
+                    .filter(|(_, value)| filter(value))
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+        keys
+    }
+
+    /// Clones of all values, one shard at a time.
+    pub(crate) fn values_cloned(&self) -> Vec<V>
+    where
+        V: Clone,
+    {
+        let mut values = Vec::new();
+        for shard in self.shards.iter() {
+            values.extend(self.lock(shard).values().cloned());
+        }
+        values
+    }
+
+    pub(crate) fn contention(&self) -> LockContention {
+        LockContention {
+            map: self.name,
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn behaves_like_a_map() {
+        let map = ShardedMap::with_shards("test", 5);
+        assert_eq!(map.shards.len(), 8);
+        for key in 0..100 {
+            assert!(map.insert(key, key * 2).is_none());
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map.get_cloned(&21), Some(42));
+        assert_eq!(
+            map.with_mut(&21, |value| std::mem::replace(value, 0)),
+            Some(42)
+        );
+        assert_eq!(map.remove_if(&21, |value| *value != 0), None);
+        assert_eq!(map.remove_if(&21, |value| *value == 0), Some(0));
+        assert_eq!(map.remove(&22), Some(44));
+        let mut small = map.keys_where(|value| *value < 10);
+        small.sort_unstable();
+        assert_eq!(small, vec![0, 1, 2, 3, 4]);
+        assert_eq!(map.values_cloned().len(), 98);
+
+        let counts: ShardedMap<&str, u32> = ShardedMap::with_shards("counts", 1);
+        counts.with_entry("a", |count| *count += 1);
+        counts.with_entry("a", |count| *count += 1);
+        assert_eq!(counts.get_cloned(&"a"), Some(2));
+    }
+
+    #[test]
+    fn counts_contended_acquisitions() {
+        let map = Arc::new(ShardedMap::with_shards("test", 1));
+        map.insert(1, 0u64);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        map.with_mut(&1, |value| *value += 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(map.get_cloned(&1), Some(8_000));
+        let contention = map.contention();
+        assert_eq!(contention.map, "test");
+        assert_eq!(contention.acquisitions, 8_002);
+        assert!(contention.contended <= contention.acquisitions);
+    }
+}
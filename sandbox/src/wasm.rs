@@ -1,10 +1,14 @@
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
+use arc_swap::ArcSwap;
 use wasmer::imports;
 use wasmer::{Engine, Instance, Module, Store, StoreLimitsBuilder, Value};
 
 use crate::errors::{Result, SandboxError};
+use crate::metrics::{self, SandboxMetrics};
 use crate::path;
 
 #[derive(Clone, Debug)]
@@ -63,18 +67,38 @@ impl WasmConfig {
 
 #[derive(Clone, Debug)]
 pub struct SandboxWasm {
-    config: WasmConfig,
+    config: Arc<ArcSwap<WasmConfig>>,
     engine: Engine,
+    metrics: Arc<dyn SandboxMetrics>,
 }
 
 impl SandboxWasm {
     pub fn new(config: WasmConfig) -> Self {
         let engine = Engine::default();
-        Self { config, engine }
+        Self {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            engine,
+            metrics: metrics::none(),
+        }
+    }
+
+    /// Reports the compile and execution time of every invocation to
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn SandboxMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn config(&self) -> Arc<WasmConfig> {
+        self.config.load_full()
     }
 
-    pub fn config(&self) -> &WasmConfig {
-        &self.config
+    /// Replaces the limits for invocations started from now on. The root
+    /// cannot change.
+    pub fn reload(&self, config: WasmConfig) -> Result<()> {
+        path::ensure_same_root(self.config.load().root(), config.root())?;
+        self.config.store(Arc::new(config));
+        Ok(())
     }
 
     pub fn invoke(&self, invocation: WasmInvocation) -> Result<Vec<WasmValue>> {
@@ -89,7 +113,7 @@ impl SandboxWasm {
 
         let bytes = match module {
             WasmModuleSource::Path(path) => {
-                let resolved = path::resolve(self.config.root(), &path)?;
+                let resolved = path::resolve(self.config.load().root(), &path)?;
                 fs::read(resolved)?
             }
             WasmModuleSource::Bytes(bytes) => bytes,
@@ -113,25 +137,28 @@ impl SandboxWasm {
         memory_limit: Option<u64>,
         table_elements_limit: Option<u32>,
     ) -> Result<Vec<WasmValue>> {
+        let compiling = Instant::now();
         let module = Module::new(&self.engine, &bytes).map_err(|err| {
             SandboxError::InvalidOperation(format!("failed to compile wasm module: {err}"))
         })?;
+        self.metrics.wasm_compile(compiling.elapsed());
 
+        let config = self.config.load_full();
         let mut store = Store::new(&self.engine);
-        let fuel_budget = fuel.or(self.config.default_fuel);
+        let fuel_budget = fuel.or(config.default_fuel);
         if let Some(fuel) = fuel_budget {
             store.add_fuel(fuel).map_err(|err| {
                 SandboxError::InvalidOperation(format!("failed to configure wasm fuel: {err}"))
             })?;
         }
 
-        let memory_limit = memory_limit.unwrap_or(self.config.max_memory_bytes);
+        let memory_limit = memory_limit.unwrap_or(config.max_memory_bytes);
         if memory_limit == 0 {
             return Err(SandboxError::InvalidOperation(
                 "wasm memory limit must be greater than zero".to_string(),
             ));
         }
-        let table_limit = table_elements_limit.unwrap_or(self.config.max_table_elements);
+        let table_limit = table_elements_limit.unwrap_or(config.max_table_elements);
         if table_limit == 0 {
             return Err(SandboxError::InvalidOperation(
                 "wasm table element limit must be greater than zero".to_string(),
@@ -144,6 +171,7 @@ impl SandboxWasm {
             .build();
         store.limiter(|_| -> &mut dyn wasmer::StoreLimiter { &mut store_limits });
 
+        let executing = Instant::now();
         let instance = Instance::new(&mut store, &module, &imports! {}).map_err(|err| {
             SandboxError::InvalidOperation(format!("failed to instantiate wasm module: {err}"))
         })?;
@@ -155,9 +183,9 @@ impl SandboxWasm {
         })?;
 
         let params: Vec<Value> = params.iter().map(Value::from).collect();
-        let result_values = function
-            .call(&mut store, &params)
-            .map_err(|err| SandboxError::WasmTrap(err.to_string()))?;
+        let result_values = function.call(&mut store, &params);
+        self.metrics.wasm_execute(executing.elapsed());
+        let result_values = result_values.map_err(|err| SandboxError::WasmTrap(err.to_string()))?;
 
         result_values.into_iter().map(WasmValue::try_from).collect()
     }
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use sandbox::diff::unified_diff;
+use sandbox::run::{RunConfig, SandboxRun};
+use sandbox::{
+    AgentAction, AgentActionExecutor, AgentActionStatus, AgentFileContent, SandboxConfig, SandboxFs,
+};
+use tempfile::TempDir;
+
+fn executor(root: &std::path::Path) -> AgentActionExecutor {
+    let fs = SandboxFs::new(SandboxConfig::new(root, 512 * 1024).unwrap());
+    let run = RunConfig::new(
+        root,
+        vec!["/bin/sh".to_string()],
+        vec!["PATH".to_string()],
+        vec![("PATH".to_string(), "/usr/bin:/bin".to_string())],
+        Duration::from_millis(500),
+        Duration::from_secs(2),
+        8 * 1024,
+    )
+    .expect("valid config");
+    AgentActionExecutor::new(fs).with_run(SandboxRun::new(run))
+}
+
+fn write(path: &str, content: &str) -> AgentAction {
+    AgentAction::FileWrite {
+        path: path.to_string(),
+        content: AgentFileContent::Utf8(content.to_string()),
+    }
+}
+
+fn patch(path: &str, before: &str, after: &str) -> AgentAction {
+    AgentAction::FilePatch {
+        path: path.to_string(),
+        patch: unified_diff(path, before, after),
+    }
+}
+
+fn shell(script: &str) -> AgentAction {
+    AgentAction::Command {
+        command: "/bin/sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+    }
+}
+
+#[tokio::test]
+async fn applies_writes_patches_and_commands_in_order() {
+    let temp = TempDir::new().unwrap();
+    std::fs::write(temp.path().join("main.py"), "print(1)\n").unwrap();
+    let actions = vec![
+        AgentAction::Message {
+            title: "plan".to_string(),
+            body: "fix it".to_string(),
+        },
+        patch("main.py", "print(1)\n", "print(2)\n"),
+        write("notes/todo.txt", "done\n"),
+        shell("cat main.py notes/todo.txt"),
+    ];
+
+    let report = executor(temp.path()).apply(&actions).await;
+    assert!(report.is_success());
+    assert_eq!((report.applied, report.failed, report.skipped), (3, 0, 1));
+    assert_eq!(report.actions[0].status, AgentActionStatus::Skipped);
+    assert!(report.actions[1]
+        .diff
+        .as_deref()
+        .unwrap()
+        .contains("+print(2)"));
+    assert_eq!(report.actions[3].exit_code, Some(0));
+    assert_eq!(
+        report.actions[3].stdout.as_deref(),
+        Some("print(2)\ndone\n")
+    );
+    assert_eq!(
+        std::fs::read_to_string(temp.path().join("main.py")).unwrap(),
+        "print(2)\n"
+    );
+}
+
+#[tokio::test]
+async fn dry_run_changes_nothing_but_chains_patches() {
+    let temp = TempDir::new().unwrap();
+    std::fs::write(temp.path().join("a.txt"), "one\n").unwrap();
+    let actions = vec![
+        patch("a.txt", "one\n", "two\n"),
+        patch("a.txt", "two\n", "three\n"),
+        shell("touch ran"),
+    ];
+
+    let report = executor(temp.path())
+        .with_dry_run(true)
+        .apply(&actions)
+        .await;
+    assert!(report.dry_run);
+    assert_eq!((report.applied, report.failed), (3, 0));
+    assert!(report
+        .actions
+        .iter()
+        .all(|action| action.status == AgentActionStatus::Planned));
+    assert_eq!(report.actions[1].content.as_deref(), Some(&b"three\n"[..]));
+    assert_eq!(
+        std::fs::read_to_string(temp.path().join("a.txt")).unwrap(),
+        "one\n"
+    );
+    assert!(!temp.path().join("ran").exists());
+
+    let denied = executor(temp.path())
+        .with_dry_run(true)
+        .apply(&[AgentAction::Command {
+            command: "rm".to_string(),
+            args: vec!["-rf".to_string(), ".".to_string()],
+        }])
+        .await;
+    assert_eq!(denied.failed, 1);
+}
+
+#[tokio::test]
+async fn first_failure_skips_the_rest() {
+    let temp = TempDir::new().unwrap();
+    let actions = vec![
+        write("a.txt", "a\n"),
+        shell("exit 3"),
+        write("b.txt", "b\n"),
+        patch("missing.txt", "x\n", "y\n"),
+    ];
+
+    let report = executor(temp.path()).apply(&actions).await;
+    assert!(!report.is_success());
+    assert_eq!((report.applied, report.failed, report.skipped), (1, 1, 2));
+    assert_eq!(report.actions[1].status, AgentActionStatus::Failed);
+    assert_eq!(report.actions[1].exit_code, Some(3));
+    assert!(report.actions[1].error.is_some());
+    assert!(temp.path().join("a.txt").exists());
+    assert!(!temp.path().join("b.txt").exists());
+
+    let report = executor(temp.path())
+        .apply(&[patch("missing.txt", "x\n", "y\n")])
+        .await;
+    assert!(report.actions[0]
+        .error
+        .as_deref()
+        .unwrap()
+        .contains("patch does not apply"));
+}
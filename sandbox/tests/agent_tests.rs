@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use mock_llm::{Endpoint, MockLlmServer, Reply};
+use sandbox::{
+    AgentAction, AgentContext, AgentDispatchRequest, AgentDispatcher, AgentDispatcherConfig,
+    AgentFailureKind, AgentFileContent, AgentKind, AgentTaskError, AgentTaskSnapshot,
+    AgentTaskStatus,
+};
+use serde_json::json;
+
+fn dispatcher(server: &MockLlmServer, native_tools: bool) -> AgentDispatcher {
+    let config = AgentDispatcherConfig::new(server.url(), "mock-model")
+        .with_timeout(Duration::from_secs(2))
+        .with_native_tools(native_tools);
+    AgentDispatcher::new(config).expect("dispatcher")
+}
+
+fn request(objective: &str) -> AgentDispatchRequest {
+    AgentDispatchRequest {
+        agent: AgentKind::Code,
+        objective: objective.to_string(),
+        context: AgentContext::default(),
+        model: None,
+        metadata: None,
+        parameters: None,
+        subtasks: Vec::new(),
+        system_prompt: None,
+        persona: None,
+        traceparent: None,
+    }
+}
+
+async fn finished(dispatcher: &AgentDispatcher, objective: &str) -> AgentTaskSnapshot {
+    let submission = dispatcher.dispatch(request(objective)).expect("dispatch");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let snapshot = dispatcher.status(&submission.id).expect("task exists");
+            if snapshot.status.is_terminal() {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("task finishes")
+}
+
+#[tokio::test]
+async fn structured_answers_become_the_outcome() {
+    let server = MockLlmServer::start().await.unwrap();
+    server.push(
+        Endpoint::Chat,
+        Reply::json(json!({
+            "summary": "added the module",
+            "insights": ["tests cover it"],
+            "actions": [{ "type": "message", "title": "done", "body": "module added" }],
+        })),
+    );
+    let dispatcher = dispatcher(&server, false);
+
+    let snapshot = finished(&dispatcher, "add a parser module").await;
+    assert_eq!(snapshot.status, AgentTaskStatus::Completed);
+    let outcome = snapshot.outcome.expect("outcome");
+    assert_eq!(outcome.summary, "added the module");
+    assert_eq!(outcome.insights, ["tests cover it"]);
+    assert!(
+        matches!(&outcome.actions[..], [AgentAction::Message { title, .. }] if title == "done")
+    );
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let body = &requests[0].body;
+    assert_eq!(body["model"], "mock-model");
+    assert_eq!(body["messages"][0]["role"], "system");
+    assert!(body["messages"][1]["content"]
+        .as_str()
+        .unwrap()
+        .contains("add a parser module"));
+    assert!(body.get("tools").is_none());
+}
+
+#[tokio::test]
+async fn native_tool_calls_become_actions() {
+    let server = MockLlmServer::start().await.unwrap();
+    server.push(
+        Endpoint::Chat,
+        Reply::tool_call(
+            "file_write",
+            json!({ "path": "src/lib.rs", "content": "pub fn parse() {}" }),
+        ),
+    );
+    let dispatcher = dispatcher(&server, true);
+
+    let snapshot = finished(&dispatcher, "write the parser").await;
+    assert_eq!(snapshot.status, AgentTaskStatus::Completed);
+    let outcome = snapshot.outcome.expect("outcome");
+    match &outcome.actions[..] {
+        [AgentAction::FileWrite { path, content }] => {
+            assert_eq!(path, "src/lib.rs");
+            assert!(matches!(content, AgentFileContent::Utf8(body) if body == "pub fn parse() {}"));
+        }
+        other => panic!("unexpected actions: {other:?}"),
+    }
+    assert_eq!(server.requests()[0].body["tool_choice"], "auto");
+}
+
+#[tokio::test]
+async fn llm_failures_are_typed() {
+    let server = MockLlmServer::start().await.unwrap();
+    server
+        .push(Endpoint::Chat, Reply::error(503, "model is loading"))
+        .push(
+            Endpoint::Chat,
+            Reply::error(
+                400,
+                "this request exceeds the context_length of 2048 tokens",
+            ),
+        )
+        .push(
+            Endpoint::Chat,
+            Reply::text("too late").after(Duration::from_secs(3)),
+        );
+    let dispatcher = dispatcher(&server, false);
+
+    let snapshot = finished(&dispatcher, "first").await;
+    assert_eq!(snapshot.status, AgentTaskStatus::Failed);
+    let error = snapshot.error.expect("error");
+    assert_eq!(error.kind, AgentFailureKind::LlmHttp);
+    assert_eq!(error.http_status, Some(503));
+
+    let snapshot = finished(&dispatcher, "second").await;
+    assert_eq!(
+        snapshot.error.expect("error").kind,
+        AgentFailureKind::ContextOverflow
+    );
+
+    let snapshot = finished(&dispatcher, "third").await;
+    assert_eq!(
+        snapshot.error.expect("error").kind,
+        AgentFailureKind::Timeout
+    );
+}
+
+#[tokio::test]
+async fn malformed_answers_fail_as_parse_errors() {
+    let server = MockLlmServer::start().await.unwrap();
+    server
+        .push(Endpoint::Chat, Reply::json(json!({ "answer": 42 })))
+        .push(Endpoint::Chat, Reply::body(json!({ "choices": "none" })))
+        .push(Endpoint::Chat, Reply::text("added the module"));
+    let dispatcher = dispatcher(&server, false);
+
+    for objective in ["off schema", "not a completion"] {
+        let snapshot = finished(&dispatcher, objective).await;
+        assert_eq!(snapshot.status, AgentTaskStatus::Failed);
+        let error: AgentTaskError = snapshot.error.expect("error");
+        assert_eq!(error.kind, AgentFailureKind::Parse, "{objective}");
+    }
+
+    // Prose is still an answer.
+    let snapshot = finished(&dispatcher, "prose").await;
+    assert_eq!(snapshot.status, AgentTaskStatus::Completed);
+    assert_eq!(snapshot.summary.as_deref(), Some("added the module"));
+}
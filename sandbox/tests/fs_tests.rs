@@ -1,6 +1,25 @@
-use sandbox::{SandboxConfig, SandboxFs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use sandbox::{FileKind, SandboxConfig, SandboxFs, SandboxMetrics, WalkListing, WalkOptions};
 use tempfile::TempDir;
 
+#[derive(Debug, Default)]
+struct ByteCounter {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl SandboxMetrics for ByteCounter {
+    fn fs_read(&self, bytes: u64) {
+        self.read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn fs_written(&self, bytes: u64) {
+        self.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
 #[test]
 fn write_and_read_roundtrip() {
     let temp = TempDir::new().unwrap();
@@ -31,3 +50,151 @@ fn enforce_file_size_limit() {
     let err = fs.write("large.txt", b"12345").unwrap_err();
     assert!(format!("{}", err).contains("file too large"));
 }
+
+#[test]
+fn scoped_handle_stays_inside_its_directory() {
+    let temp = TempDir::new().unwrap();
+    let config = SandboxConfig::new(temp.path(), 512 * 1024).unwrap();
+    let fs = SandboxFs::new(config);
+    fs.write("shared.txt", b"root").unwrap();
+
+    let scoped = fs.scoped("projects/a").unwrap();
+    scoped.write("notes.txt", b"scoped").unwrap();
+    assert_eq!(fs.read("projects/a/notes.txt").unwrap(), b"scoped");
+    assert!(scoped.read("../../shared.txt").is_err());
+    assert!(scoped.read("shared.txt").is_err());
+}
+
+#[test]
+fn scoped_handles_report_bytes_to_the_attached_metrics() {
+    let temp = TempDir::new().unwrap();
+    let config = SandboxConfig::new(temp.path(), 512 * 1024).unwrap();
+    let counter = Arc::new(ByteCounter::default());
+    let fs = SandboxFs::new(config).with_metrics(counter.clone());
+
+    let scoped = fs.scoped("projects/a").unwrap();
+    scoped.write("notes.txt", b"scoped").unwrap();
+    scoped.copy("notes.txt", "copy.txt").unwrap();
+    assert!(scoped.write("large.txt", vec![0; 600 * 1024]).is_err());
+    assert_eq!(fs.read("projects/a/copy.txt").unwrap(), b"scoped");
+    assert_eq!(counter.written.load(Ordering::Relaxed), 12);
+    assert_eq!(counter.read.load(Ordering::Relaxed), 6);
+}
+
+#[cfg(unix)]
+#[test]
+fn stat_and_list_describe_kinds_and_permissions() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let temp = TempDir::new().unwrap();
+    let config = SandboxConfig::new(temp.path(), 512 * 1024).unwrap();
+    let fs = SandboxFs::new(config);
+    fs.write("src/main.rs", b"fn main() {}").unwrap();
+    std::fs::set_permissions(
+        temp.path().join("src/main.rs"),
+        std::fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+    symlink("/etc/passwd", temp.path().join("src/escape")).unwrap();
+
+    let file = fs.stat("src/main.rs").unwrap();
+    assert_eq!(file.name, "main.rs");
+    assert_eq!((file.kind, file.size), (FileKind::File, 12));
+    assert_eq!(file.permissions, "0640");
+    assert!(file.mtime.is_some() && file.ctime.is_some());
+
+    let link = fs.stat("src/escape").unwrap();
+    assert_eq!(link.kind, FileKind::Symlink);
+    assert!(!link.is_dir);
+
+    let dir = fs.stat("src").unwrap();
+    assert!(dir.is_dir);
+    assert_eq!(dir.kind, FileKind::Dir);
+
+    let entries = fs.list("src").unwrap();
+    let kinds: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![("escape", FileKind::Symlink), ("main.rs", FileKind::File)]
+    );
+    assert_eq!(entries[1], file);
+    assert!(fs.stat("src/missing").is_err());
+}
+
+#[test]
+fn walk_lists_a_filtered_tree_in_order() {
+    let temp = TempDir::new().unwrap();
+    let config = SandboxConfig::new(temp.path(), 512 * 1024).unwrap();
+    let fs = SandboxFs::new(config);
+    for path in [
+        "app/src/main.rs",
+        "app/src/lib.rs",
+        "app/src/util/mod.rs",
+        "app/README.md",
+        "app/node_modules/pkg/index.js",
+    ] {
+        fs.write(path, b"x").unwrap();
+    }
+
+    let paths = |listing: &WalkListing| -> Vec<String> {
+        listing
+            .entries
+            .iter()
+            .map(|entry| entry.path.clone())
+            .collect()
+    };
+    let options = WalkOptions {
+        include: vec!["**/*.rs".to_string()],
+        exclude: vec!["**/node_modules".to_string()],
+        ..WalkOptions::default()
+    };
+    let listing = fs.walk("app", &options).unwrap();
+    assert_eq!(
+        paths(&listing),
+        vec![
+            "src",
+            "src/lib.rs",
+            "src/main.rs",
+            "src/util",
+            "src/util/mod.rs"
+        ]
+    );
+    assert_eq!(listing.entries[4].depth, 3);
+    assert_eq!(listing.entries[4].entry.name, "mod.rs");
+    assert!(!listing.truncated);
+
+    let options = WalkOptions {
+        max_depth: Some(2),
+        include: vec!["*.md".to_string()],
+        ..WalkOptions::default()
+    };
+    let listing = fs.walk("app", &options).unwrap();
+    assert_eq!(
+        paths(&listing),
+        vec![
+            "README.md",
+            "node_modules",
+            "node_modules/pkg",
+            "src",
+            "src/util"
+        ]
+    );
+
+    let options = WalkOptions {
+        max_entries: Some(3),
+        ..WalkOptions::default()
+    };
+    let listing = fs.walk("app", &options).unwrap();
+    assert_eq!(listing.entries.len(), 3);
+    assert!(listing.truncated);
+
+    let options = WalkOptions {
+        include: vec!["[".to_string()],
+        ..WalkOptions::default()
+    };
+    assert!(fs.walk("app", &options).is_err());
+    assert!(fs.walk("../", &WalkOptions::default()).is_err());
+}
@@ -0,0 +1,125 @@
+use std::path::Path;
+use std::time::Duration;
+
+use sandbox::{GitAuthor, GitChange, GitConfig, SandboxError, SandboxGit};
+use tempfile::TempDir;
+
+const GIT: &str = "/usr/bin/git";
+
+fn git(root: &Path) -> Option<SandboxGit> {
+    if !Path::new(GIT).exists() {
+        return None;
+    }
+    let config = GitConfig::new(
+        root,
+        GIT,
+        "/usr/bin:/bin",
+        Duration::from_secs(10),
+        64 * 1024,
+    )
+    .expect("valid config");
+    Some(SandboxGit::new(config).unwrap().scoped("project").unwrap())
+}
+
+fn author() -> GitAuthor {
+    GitAuthor {
+        name: "Ada".to_string(),
+        email: "ada@example.com".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn init_status_commit_and_log() {
+    let temp = TempDir::new().unwrap();
+    let Some(git) = git(temp.path()) else {
+        return;
+    };
+    assert!(matches!(
+        git.status().await,
+        Err(SandboxError::InvalidOperation(_))
+    ));
+    assert!(git.init().await.unwrap());
+    assert!(!git.init().await.unwrap());
+    assert!(git.log(10).await.unwrap().is_empty());
+
+    std::fs::write(git.root().join("main.py"), "print(1)\n").unwrap();
+    let status = git.status().await.unwrap();
+    assert_eq!(status.branch.as_deref(), Some("main"));
+    assert_eq!(status.entries[0].path, "main.py");
+    assert_eq!(status.entries[0].worktree, Some(GitChange::Untracked));
+
+    let first = git.commit("  add main\n", &author(), &[]).await.unwrap();
+    assert_eq!(first.summary, "add main");
+    assert_eq!(first.author_email, "ada@example.com");
+    assert!(git.status().await.unwrap().entries.is_empty());
+    assert!(matches!(
+        git.commit("again", &author(), &[]).await,
+        Err(SandboxError::InvalidOperation(_))
+    ));
+
+    std::fs::write(git.root().join("main.py"), "print(2)\n").unwrap();
+    let diff = git.diff(Some("main.py"), false).await.unwrap();
+    assert!(diff.contains("+print(2)"));
+    assert!(git.diff(None, true).await.unwrap().is_empty());
+    assert!(git.diff(Some("../escape"), false).await.is_err());
+
+    git.commit("bump", &author(), &["main.py".to_string()])
+        .await
+        .unwrap();
+    let log = git.log(10).await.unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[0].summary, "bump");
+    assert_eq!(log[1].id, first.id);
+}
+
+#[tokio::test]
+async fn hooks_do_not_run() {
+    let temp = TempDir::new().unwrap();
+    let Some(git) = git(temp.path()) else {
+        return;
+    };
+    git.init().await.unwrap();
+    let hook = git.root().join(".git/hooks/pre-commit");
+    std::fs::write(&hook, "#!/bin/sh\ntouch hooked\nexit 1\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    std::fs::write(git.root().join("a.txt"), "a\n").unwrap();
+    git.commit("add a", &author(), &[]).await.unwrap();
+    assert!(!git.root().join("hooked").exists());
+}
+
+#[tokio::test]
+async fn filter_drivers_are_refused() {
+    let temp = TempDir::new().unwrap();
+    let Some(git) = git(temp.path()) else {
+        return;
+    };
+    git.init().await.unwrap();
+    std::fs::write(git.root().join(".gitattributes"), "* filter=run\n").unwrap();
+    std::fs::write(
+        git.root().join("included"),
+        "[filter \"run\"]\n\tclean = touch filtered\n",
+    )
+    .unwrap();
+    let config = git.root().join(".git/config");
+    let mut contents = std::fs::read_to_string(&config).unwrap();
+    contents.push_str("[include]\n\tpath = ../included\n");
+    std::fs::write(&config, contents).unwrap();
+
+    for result in [
+        git.status().await.map(|_| ()),
+        git.commit("add", &author(), &[]).await.map(|_| ()),
+        git.diff(None, false).await.map(|_| ()),
+    ] {
+        match result {
+            Err(SandboxError::InvalidOperation(message)) => {
+                assert!(message.contains("filter.run.clean"), "{message}")
+            }
+            other => panic!("expected a refusal, got {other:?}"),
+        }
+    }
+    assert!(!git.root().join("filtered").exists());
+}
@@ -2,7 +2,8 @@ use std::path::PathBuf;
 use std::time::Duration;
 
 use sandbox::micro::{
-    MicroConfig, MicroExecuteRequest, MicroImage, MicroStartRequest, SandboxMicro,
+    MicroConfig, MicroExecuteRequest, MicroImage, MicroOutput, MicroRepl, MicroStartRequest,
+    SandboxMicro,
 };
 use sandbox::SandboxError;
 use tempfile::TempDir;
@@ -32,9 +33,35 @@ fn build_micro_sandbox(root: &std::path::Path) -> SandboxMicro {
         vec![("PYTHONUNBUFFERED".to_string(), "1".to_string())],
     )
     .expect("valid python image");
+    build_with_images(root, vec![image])
+}
+
+/// Python and, where installed, node, each with a persistent interpreter.
+fn build_repl_sandbox(root: &std::path::Path) -> SandboxMicro {
+    let python_command = detect_binary("python3").unwrap_or_else(|| "python3".to_string());
+    let mut images = vec![MicroImage::new(
+        "python",
+        python_command,
+        vec!["-u".to_string()],
+        "py",
+        Vec::new(),
+    )
+    .expect("valid python image")
+    .with_repl(Some(MicroRepl::Python))];
+    if let Some(node) = detect_binary("node") {
+        images.push(
+            MicroImage::new("node", node, Vec::new(), "js", Vec::new())
+                .expect("valid node image")
+                .with_repl(Some(MicroRepl::Node)),
+        );
+    }
+    build_with_images(root, images)
+}
+
+fn build_with_images(root: &std::path::Path, images: Vec<MicroImage>) -> SandboxMicro {
     let config = MicroConfig::new(
         root,
-        vec![image],
+        images,
         Duration::from_millis(500),
         Duration::from_secs(2),
         64 * 1024,
@@ -59,6 +86,7 @@ async fn executes_python_code() {
         .start(MicroStartRequest {
             image: "python".to_string(),
             init_script: Some("import math".to_string()),
+            scope: None,
         })
         .await
         .expect("micro vm starts");
@@ -68,6 +96,7 @@ async fn executes_python_code() {
             vm_id: instance.id(),
             code: "print('micro sandbox')".to_string(),
             timeout: Some(Duration::from_millis(400)),
+            scope: None,
         })
         .await
         .expect("execution succeeds");
@@ -89,8 +118,242 @@ async fn rejects_unknown_image() {
         .start(MicroStartRequest {
             image: "unknown".to_string(),
             init_script: None,
+            scope: None,
         })
         .await
         .expect_err("image should be rejected");
     assert!(matches!(err, SandboxError::MicroImageNotConfigured(_)));
 }
+
+#[tokio::test]
+async fn scoped_instances_are_hidden_from_other_scopes() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_micro_sandbox(temp.path());
+
+    let instance = sandbox
+        .start(MicroStartRequest {
+            image: "python".to_string(),
+            init_script: None,
+            scope: Some(PathBuf::from("projects/a")),
+        })
+        .await
+        .expect("micro vm starts");
+    assert!(instance
+        .workdir()
+        .starts_with(temp.path().join("projects/a")));
+
+    let err = sandbox
+        .execute(MicroExecuteRequest {
+            vm_id: instance.id(),
+            code: "print(1)".to_string(),
+            timeout: None,
+            scope: Some(PathBuf::from("projects/b")),
+        })
+        .await
+        .expect_err("other scope cannot execute");
+    assert!(matches!(err, SandboxError::MicroVmNotFound(_)));
+
+    let err = sandbox
+        .stop_in(instance.id(), Some(std::path::Path::new("projects/b")))
+        .await
+        .expect_err("other scope cannot stop");
+    assert!(matches!(err, SandboxError::MicroVmNotFound(_)));
+    sandbox
+        .stop_in(instance.id(), Some(std::path::Path::new("projects/a")))
+        .await
+        .expect("owning scope stops");
+}
+
+#[tokio::test]
+async fn stop_idle_only_stops_unused_instances() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_micro_sandbox(temp.path());
+
+    let instance = sandbox
+        .start(MicroStartRequest {
+            image: "python".to_string(),
+            init_script: None,
+            scope: None,
+        })
+        .await
+        .expect("micro vm starts");
+
+    let stopped = sandbox
+        .stop_idle(Duration::from_secs(60))
+        .await
+        .expect("idle sweep succeeds");
+    assert_eq!(stopped, 0);
+    assert_eq!(sandbox.active_instances(), 1);
+
+    let stopped = sandbox
+        .stop_idle(Duration::ZERO)
+        .await
+        .expect("idle sweep succeeds");
+    assert_eq!(stopped, 1);
+    assert_eq!(sandbox.active_instances(), 0);
+    assert!(!instance.workdir().exists());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_callers_share_the_instance_table() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = std::sync::Arc::new(build_micro_sandbox(temp.path()));
+
+    let mut callers = Vec::new();
+    for caller in 0..16 {
+        let instance = sandbox
+            .start(MicroStartRequest {
+                image: "python".to_string(),
+                init_script: None,
+                scope: None,
+            })
+            .await
+            .expect("micro vm starts");
+        let sandbox = sandbox.clone();
+        callers.push(tokio::spawn(async move {
+            sandbox
+                .execute(MicroExecuteRequest {
+                    vm_id: instance.id(),
+                    code: format!("print({caller})"),
+                    timeout: Some(Duration::from_secs(2)),
+                    scope: None,
+                })
+                .await
+                .map(|output| (caller, output))
+        }));
+    }
+    for caller in callers {
+        let (caller, output) = caller.await.unwrap().expect("execution succeeds");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            caller.to_string()
+        );
+    }
+
+    assert_eq!(sandbox.active_instances(), 16);
+    let contention = sandbox.lock_contention();
+    assert_eq!(contention.map, "micro_instances");
+    // One insert per start and two lookups per execute, plus the count.
+    assert!(contention.acquisitions >= 16 * 3);
+    assert_eq!(sandbox.shutdown().await.expect("shutdown succeeds"), 16);
+}
+
+async fn run(sandbox: &SandboxMicro, vm_id: uuid::Uuid, code: &str) -> MicroOutput {
+    sandbox
+        .execute(MicroExecuteRequest {
+            vm_id,
+            code: code.to_string(),
+            timeout: Some(Duration::from_secs(2)),
+            scope: None,
+        })
+        .await
+        .expect("execution succeeds")
+}
+
+#[tokio::test]
+async fn repl_keeps_state_between_executions() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_repl_sandbox(temp.path());
+    let instance = sandbox
+        .start(MicroStartRequest {
+            image: "python".to_string(),
+            init_script: Some("import math\nbase = 2".to_string()),
+            scope: None,
+        })
+        .await
+        .expect("micro vm starts");
+    let id = instance.id();
+
+    let output = run(&sandbox, id, "total = math.sqrt(16) + base\nprint(total)").await;
+    assert_eq!(output.exit_code, 0);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "6.0\n");
+    assert!(output.stderr.is_empty());
+
+    let output = run(
+        &sandbox,
+        id,
+        "print('partial', end='')\nraise ValueError('boom')",
+    )
+    .await;
+    assert_eq!(output.exit_code, 1);
+    assert_eq!(output.stdout, b"partial");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("ValueError: boom"), "{stderr}");
+    assert!(!stderr.contains("exec("), "{stderr}");
+
+    let output = run(&sandbox, id, "print(total, input.__name__)").await;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "6.0 input\n");
+
+    let output = run(&sandbox, id, "import sys\nprint('bye')\nsys.exit(3)").await;
+    assert_eq!(output.exit_code, 3);
+    assert_eq!(output.stdout, b"bye\n");
+
+    // Exiting dropped the interpreter; the next one starts empty.
+    let output = run(&sandbox, id, "print('total' in globals())").await;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "False\n");
+    assert_eq!(sandbox.shutdown().await.expect("shutdown succeeds"), 1);
+}
+
+#[tokio::test]
+async fn repl_timeout_restarts_the_interpreter() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_repl_sandbox(temp.path());
+    let instance = sandbox
+        .start(MicroStartRequest {
+            image: "python".to_string(),
+            init_script: None,
+            scope: None,
+        })
+        .await
+        .expect("micro vm starts");
+
+    run(&sandbox, instance.id(), "kept = 1").await;
+    let err = sandbox
+        .execute(MicroExecuteRequest {
+            vm_id: instance.id(),
+            code: "while True: pass".to_string(),
+            timeout: Some(Duration::from_millis(300)),
+            scope: None,
+        })
+        .await
+        .expect_err("execution times out");
+    assert!(matches!(err, SandboxError::Timeout(_)));
+
+    let output = run(&sandbox, instance.id(), "print('kept' in globals())").await;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "False\n");
+}
+
+#[tokio::test]
+async fn node_repl_keeps_globals_and_awaits_promises() {
+    if detect_binary("node").is_none() {
+        return;
+    }
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_repl_sandbox(temp.path());
+    let instance = sandbox
+        .start(MicroStartRequest {
+            image: "node".to_string(),
+            init_script: Some("const path = require('path');\nlet count = 40;".to_string()),
+            scope: None,
+        })
+        .await
+        .expect("micro vm starts");
+    let id = instance.id();
+
+    let output = run(&sandbox, id, "count += 2; console.log(count, path.sep)").await;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "42 /\n");
+
+    let code = "new Promise((done) => setTimeout(() => { console.log('late'); done(); }, 20))";
+    let output = run(&sandbox, id, code).await;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "late\n");
+
+    let output = run(&sandbox, id, "throw new Error('boom')").await;
+    assert_eq!(output.exit_code, 1);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Error: boom"));
+    assert_eq!(
+        run(&sandbox, id, "console.log(count)").await.stdout,
+        b"42\n"
+    );
+}
@@ -60,3 +60,233 @@ async fn rejects_forbidden_environment_variables() {
         .expect_err("env should be rejected");
     assert!(matches!(err, SandboxError::InvalidOperation(_)));
 }
+
+#[tokio::test]
+async fn scoped_run_executes_in_scope_root() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path())
+        .scoped("projects/a")
+        .expect("scope created");
+
+    let request = RunRequest::new("/bin/sh").with_args(vec!["-c".to_string(), "pwd".to_string()]);
+    let result = sandbox.execute(request).await.expect("command succeeds");
+    let cwd = String::from_utf8(result.stdout).unwrap();
+    assert!(cwd.trim_end().ends_with("projects/a"));
+    assert!(sandbox.scoped("../escape").is_err());
+}
+
+// With the clock paused, time only moves once every task waits: the sleep
+// below returns after the spawned call has started its process, and the
+// 500ms run timeout then ends a process that would otherwise run for 30s.
+#[tokio::test(start_paused = true)]
+async fn counts_active_sessions_across_scopes() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path());
+    let scoped = sandbox.scoped("projects/a").expect("scope created");
+
+    let request =
+        RunRequest::new("/bin/sh").with_args(vec!["-c".to_string(), "sleep 30".to_string()]);
+    let running = tokio::spawn(async move { scoped.execute(request).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(sandbox.active_sessions(), 1);
+    let err = running.await.unwrap().unwrap_err();
+    assert!(matches!(err, SandboxError::Timeout(_)));
+    assert_eq!(sandbox.active_sessions(), 0);
+}
+
+#[tokio::test]
+async fn reload_swaps_allowlist_but_keeps_root() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path());
+    let tightened = RunConfig::new(
+        temp.path(),
+        vec!["/usr/bin/env".to_string()],
+        vec!["PATH".to_string()],
+        vec![("PATH".to_string(), "/usr/bin:/bin".to_string())],
+        Duration::from_millis(500),
+        Duration::from_secs(2),
+        8 * 1024,
+    )
+    .expect("valid config");
+    sandbox.reload(tightened).expect("same root reloads");
+
+    let err = sandbox
+        .execute(RunRequest::new("/bin/sh"))
+        .await
+        .expect_err("program no longer allowed");
+    assert!(matches!(err, SandboxError::InvalidOperation(_)));
+
+    let other = TempDir::new().unwrap();
+    let moved = RunConfig::new(
+        other.path(),
+        vec!["/bin/sh".to_string()],
+        Vec::new(),
+        Vec::new(),
+        Duration::from_millis(500),
+        Duration::from_secs(2),
+        8 * 1024,
+    )
+    .expect("valid config");
+    assert!(sandbox.reload(moved).is_err());
+    assert_eq!(sandbox.config().root(), temp.path());
+}
+
+#[tokio::test]
+async fn sessions_take_stdin_chunks_and_stream_output() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path());
+    let request = RunRequest::new("/bin/sh")
+        .with_args(vec![
+            "-c".to_string(),
+            "while read line; do echo \"got $line\"; done; exit 3".to_string(),
+        ])
+        .with_stdin(b"one\n".to_vec());
+    let session = sandbox
+        .start_session(request)
+        .await
+        .expect("session starts");
+
+    let mut stdout = Vec::new();
+    while !stdout.ends_with(b"got one\n") {
+        let output = sandbox
+            .read_session(session.id, Duration::from_millis(500))
+            .await
+            .expect("session readable");
+        assert!(!output.exited);
+        stdout.extend(output.stdout);
+    }
+    sandbox
+        .write_session(session.id, b"two\n", true)
+        .await
+        .expect("stdin written");
+
+    let mut exit = None;
+    while exit.is_none() {
+        let output = sandbox
+            .read_session(session.id, Duration::from_millis(500))
+            .await
+            .expect("session readable");
+        stdout.extend(output.stdout);
+        if output.exited {
+            exit = Some(output.exit_code);
+        }
+    }
+    assert_eq!(stdout, b"got one\ngot two\n");
+    assert_eq!(exit, Some(Some(3)));
+    assert!(matches!(
+        sandbox.read_session(session.id, Duration::ZERO).await,
+        Err(SandboxError::RunSessionNotFound(_))
+    ));
+    assert_eq!(sandbox.open_sessions(), 0);
+}
+
+#[tokio::test]
+async fn sessions_are_scoped_and_killed_at_their_timeout() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path());
+    let scoped = sandbox.scoped("projects/a").expect("scope created");
+    let sleeper =
+        || RunRequest::new("/bin/sh").with_args(vec!["-c".to_string(), "sleep 5".to_string()]);
+
+    let session = scoped
+        .start_session(sleeper())
+        .await
+        .expect("session starts");
+    assert!(matches!(
+        sandbox.write_session(session.id, b"x", false).await,
+        Err(SandboxError::RunSessionNotFound(_))
+    ));
+    let killed = scoped
+        .kill_session(session.id)
+        .await
+        .expect("session killed");
+    assert!(killed.exited);
+    assert_eq!(killed.exit_code, None);
+
+    let short = scoped
+        .start_session(sleeper().with_timeout(Duration::from_millis(100)))
+        .await
+        .expect("session starts");
+    let output = scoped
+        .read_session(short.id, Duration::from_secs(2))
+        .await
+        .expect("session readable");
+    assert!(output.exited && output.timed_out);
+
+    scoped
+        .start_session(sleeper())
+        .await
+        .expect("session starts");
+    assert_eq!(sandbox.kill_scope("projects").await.unwrap(), 1);
+    assert_eq!(sandbox.open_sessions(), 0);
+}
+
+#[tokio::test]
+async fn sessions_report_their_end_without_being_read() {
+    let temp = TempDir::new().unwrap();
+    let sandbox = build_run_sandbox(temp.path());
+    let sleeper =
+        || RunRequest::new("/bin/sh").with_args(vec!["-c".to_string(), "sleep 5".to_string()]);
+
+    let timed_out = sandbox
+        .start_session(sleeper().with_timeout(Duration::from_millis(100)))
+        .await
+        .expect("session starts");
+    let duration = tokio::time::timeout(Duration::from_secs(2), timed_out.exited)
+        .await
+        .expect("session ends at its timeout")
+        .expect("end reported");
+    assert!(duration >= Duration::from_millis(100));
+
+    let killed = sandbox
+        .scoped("killed")
+        .expect("scope created")
+        .start_session(sleeper())
+        .await
+        .expect("session starts");
+    assert_eq!(sandbox.kill_scope("killed").await.unwrap(), 1);
+    tokio::time::timeout(Duration::from_secs(2), killed.exited)
+        .await
+        .expect("killed session ends")
+        .expect("end reported");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_starts_stay_within_the_session_limit() {
+    let temp = TempDir::new().unwrap();
+    let config = RunConfig::new(
+        temp.path(),
+        vec!["/bin/sh".to_string()],
+        vec!["PATH".to_string()],
+        vec![("PATH".to_string(), "/usr/bin:/bin".to_string())],
+        Duration::from_millis(500),
+        Duration::from_secs(2),
+        8 * 1024,
+    )
+    .expect("valid config")
+    .with_sessions(2, Duration::from_secs(2));
+    let sandbox = SandboxRun::new(config);
+    let scoped = sandbox.scoped("busy").expect("scope created");
+    let starts: Vec<_> = (0..8)
+        .map(|_| {
+            let sandbox = scoped.clone();
+            tokio::spawn(async move {
+                sandbox
+                    .start_session(
+                        RunRequest::new("/bin/sh")
+                            .with_args(vec!["-c".to_string(), "sleep 5".to_string()]),
+                    )
+                    .await
+            })
+        })
+        .collect();
+    let mut started = 0;
+    for start in starts {
+        if start.await.expect("start task").is_ok() {
+            started += 1;
+        }
+    }
+    assert_eq!(started, 2);
+    assert_eq!(sandbox.open_sessions(), 2);
+    assert_eq!(sandbox.kill_scope("busy").await.unwrap(), 2);
+}
@@ -1,6 +1,23 @@
 use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
 
+use parking_lot::Mutex;
 use sandbox::wasm::{SandboxWasm, WasmConfig, WasmInvocation, WasmModuleSource, WasmValue};
+use sandbox::SandboxMetrics;
+
+#[derive(Debug, Default)]
+struct Steps(Mutex<Vec<&'static str>>);
+
+impl SandboxMetrics for Steps {
+    fn wasm_compile(&self, _elapsed: Duration) {
+        self.0.lock().push("compile");
+    }
+
+    fn wasm_execute(&self, _elapsed: Duration) {
+        self.0.lock().push("execute");
+    }
+}
 
 #[test]
 fn executes_simple_wasm_function() {
@@ -32,3 +49,28 @@ fn executes_simple_wasm_function() {
     let outputs = sandbox.invoke(invocation).expect("invoke wasm");
     assert_eq!(outputs, vec![WasmValue::I32(12)]);
 }
+
+#[test]
+fn reports_compile_and_execution_separately() {
+    let temp = tempfile::tempdir().expect("create temp dir");
+    let root = temp.path().canonicalize().expect("canonical root");
+    let wasm_bytes = wat::parse_str(
+        r#"
+        (module
+            (func $trap unreachable)
+            (export "trap" (func $trap))
+        )
+        "#,
+    )
+    .expect("compile wat");
+
+    let config = WasmConfig::new(root, 64 * 1024, 1024, None).expect("config");
+    let steps = Arc::new(Steps::default());
+    let sandbox = SandboxWasm::new(config).with_metrics(steps.clone());
+
+    let invocation = WasmInvocation::new(WasmModuleSource::from_bytes(wasm_bytes), "trap");
+    assert!(sandbox.invoke(invocation).is_err());
+    let invalid = WasmInvocation::new(WasmModuleSource::from_bytes(b"not wasm".to_vec()), "trap");
+    assert!(sandbox.invoke(invalid).is_err());
+    assert_eq!(*steps.0.lock(), ["compile", "execute"]);
+}
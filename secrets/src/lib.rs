@@ -0,0 +1,197 @@
+//! Service credentials fetched from a secrets manager instead of plaintext
+//! environment variables. A setting whose value is a reference such as
+//! `vault:secret/coder/api#jwt_secret` (HashiCorp Vault, KV version 2) or
+//! `aws-sm:prod/coder/database#url` (AWS Secrets Manager) is replaced with
+//! the entry's current value by [`Secrets::resolve`].
+//!
+//! Fetched entries are cached for `SECRETS_CACHE_TTL_SECS`. After that they
+//! are fetched again; if the manager cannot be reached the last value keeps
+//! being served. [`Secrets::spawn_refresh`] refreshes the cache in the
+//! background and announces every value that changed as a [`Rotation`], so
+//! services can pick up rotated credentials without a restart.
+
+mod aws;
+mod reference;
+mod store;
+mod vault;
+
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub use reference::{is_reference, Scheme, SecretRef};
+pub use store::{Fetched, Rotation, SecretProvider, Secrets};
+
+#[derive(Debug, Error)]
+pub enum SecretsError {
+    #[error("invalid secret reference `{reference}`: {problem}")]
+    InvalidReference {
+        reference: String,
+        problem: &'static str,
+    },
+    #[error("{0} references need {1}")]
+    NotConfigured(Scheme, &'static str),
+    #[error("invalid secrets configuration: {0}")]
+    Config(String),
+    #[error("{scheme} request for `{path}` failed: {message}")]
+    Request {
+        scheme: Scheme,
+        path: String,
+        message: String,
+    },
+    #[error("secret `{reference}` {problem}")]
+    Value { reference: String, problem: String },
+}
+
+/// Connection to a Vault server; `vault:` references read its KV v2 engine.
+#[derive(Clone)]
+pub struct VaultConfig {
+    pub addr: String,
+    pub token: String,
+    /// Vault Enterprise namespace.
+    pub namespace: Option<String>,
+}
+
+/// Static credentials for AWS Secrets Manager; `aws-sm:` references call
+/// `GetSecretValue` in `region`.
+#[derive(Clone)]
+pub struct AwsConfig {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// Replaces `https://secretsmanager.<region>.amazonaws.com`, e.g. for a
+    /// VPC endpoint.
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct SecretsConfig {
+    pub vault: Option<VaultConfig>,
+    pub aws: Option<AwsConfig>,
+    pub cache_ttl: Duration,
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        Self {
+            vault: None,
+            aws: None,
+            cache_ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+impl SecretsConfig {
+    /// Reads the settings from the process environment.
+    pub fn from_env() -> Result<Self, SecretsError> {
+        Self::from_lookup(|key, _| {
+            std::env::var(key)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        })
+    }
+
+    /// Reads `VAULT_ADDR`, `VAULT_TOKEN`, `VAULT_NAMESPACE`, `AWS_REGION`
+    /// (or `AWS_DEFAULT_REGION`), `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN`,
+    /// `AWS_ENDPOINT_URL_SECRETS_MANAGER` and `SECRETS_CACHE_TTL_SECS`
+    /// through `lookup`, which is told whether the setting is a credential.
+    /// Vault is used once `VAULT_ADDR` is set, AWS once `AWS_ACCESS_KEY_ID`
+    /// is.
+    pub fn from_lookup(
+        lookup: impl Fn(&'static str, bool) -> Option<String>,
+    ) -> Result<Self, SecretsError> {
+        let missing = |key: &str, needed_by: &str| {
+            SecretsError::Config(format!("{key} is required when {needed_by} is set"))
+        };
+        let vault = match lookup("VAULT_ADDR", false) {
+            Some(addr) => Some(VaultConfig {
+                token: lookup("VAULT_TOKEN", true)
+                    .ok_or_else(|| missing("VAULT_TOKEN", "VAULT_ADDR"))?,
+                namespace: lookup("VAULT_NAMESPACE", false),
+                addr,
+            }),
+            None => None,
+        };
+        let aws = match lookup("AWS_ACCESS_KEY_ID", false) {
+            Some(access_key_id) => Some(AwsConfig {
+                region: lookup("AWS_REGION", false)
+                    .or_else(|| lookup("AWS_DEFAULT_REGION", false))
+                    .ok_or_else(|| missing("AWS_REGION", "AWS_ACCESS_KEY_ID"))?,
+                secret_access_key: lookup("AWS_SECRET_ACCESS_KEY", true)
+                    .ok_or_else(|| missing("AWS_SECRET_ACCESS_KEY", "AWS_ACCESS_KEY_ID"))?,
+                session_token: lookup("AWS_SESSION_TOKEN", true),
+                endpoint: lookup("AWS_ENDPOINT_URL_SECRETS_MANAGER", false),
+                access_key_id,
+            }),
+            None => None,
+        };
+        let cache_ttl = match lookup("SECRETS_CACHE_TTL_SECS", false) {
+            Some(raw) => raw
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    SecretsError::Config(format!(
+                        "SECRETS_CACHE_TTL_SECS = {raw:?}: expected a positive number of seconds"
+                    ))
+                })?,
+            None => Self::default().cache_ttl,
+        };
+        Ok(Self {
+            vault,
+            aws,
+            cache_ttl,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn lookup(pairs: &[(&str, &str)]) -> Result<SecretsConfig, SecretsError> {
+        let pairs: HashMap<String, String> = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        SecretsConfig::from_lookup(|key, _| pairs.get(key).cloned())
+    }
+
+    #[test]
+    fn providers_are_enabled_by_their_address_or_key_id() {
+        let config = lookup(&[("AWS_REGION", "eu-central-1")]).unwrap();
+        assert!(config.vault.is_none() && config.aws.is_none());
+
+        let config = lookup(&[
+            ("VAULT_ADDR", "https://vault:8200"),
+            ("VAULT_TOKEN", "s.token"),
+            ("AWS_ACCESS_KEY_ID", "AKID"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("AWS_DEFAULT_REGION", "eu-west-1"),
+            ("SECRETS_CACHE_TTL_SECS", "60"),
+        ])
+        .unwrap();
+        assert_eq!(config.vault.unwrap().addr, "https://vault:8200");
+        assert_eq!(config.aws.unwrap().region, "eu-west-1");
+        assert_eq!(config.cache_ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn incomplete_settings_are_rejected() {
+        let err = lookup(&[("VAULT_ADDR", "https://vault:8200")])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("VAULT_TOKEN"));
+        let err = lookup(&[("AWS_ACCESS_KEY_ID", "AKID"), ("AWS_REGION", "us-east-1")])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("AWS_SECRET_ACCESS_KEY"));
+        assert!(lookup(&[("SECRETS_CACHE_TTL_SECS", "0")]).is_err());
+    }
+}
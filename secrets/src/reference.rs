@@ -0,0 +1,140 @@
+use std::fmt;
+
+use crate::SecretsError;
+
+/// A secrets manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Scheme {
+    Vault,
+    AwsSecretsManager,
+}
+
+impl Scheme {
+    const ALL: [Scheme; 2] = [Scheme::Vault, Scheme::AwsSecretsManager];
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Self::Vault => "vault:",
+            Self::AwsSecretsManager => "aws-sm:",
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.prefix().trim_end_matches(':'))
+    }
+}
+
+/// Whether `raw` names a secrets manager entry rather than being a value.
+pub fn is_reference(raw: &str) -> bool {
+    let raw = raw.trim();
+    Scheme::ALL
+        .iter()
+        .any(|scheme| raw.starts_with(scheme.prefix()))
+}
+
+/// `vault:<mount>/<path>#<field>` or `aws-sm:<secret id>[#<json key>]`.
+/// Vault entries are key/value maps, so the field is required there; an AWS
+/// secret without a key is used as a whole.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SecretRef {
+    pub scheme: Scheme,
+    /// The Vault path including its mount, or the AWS secret id or ARN.
+    pub path: String,
+    pub field: Option<String>,
+}
+
+impl SecretRef {
+    /// `Ok(None)` if `raw` is not a reference.
+    pub fn parse(raw: &str) -> Result<Option<Self>, SecretsError> {
+        let trimmed = raw.trim();
+        let Some((scheme, rest)) = Scheme::ALL.iter().find_map(|scheme| {
+            trimmed
+                .strip_prefix(scheme.prefix())
+                .map(|rest| (*scheme, rest))
+        }) else {
+            return Ok(None);
+        };
+        let invalid = |problem| SecretsError::InvalidReference {
+            reference: trimmed.to_string(),
+            problem,
+        };
+        let (path, field) = match rest.split_once('#') {
+            Some((_, "")) => return Err(invalid("the field after `#` is empty")),
+            Some((path, field)) => (path, Some(field.to_string())),
+            None => (rest, None),
+        };
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return Err(invalid("the path is empty"));
+        }
+        if scheme == Scheme::Vault {
+            if !path.contains('/') {
+                return Err(invalid("expected vault:<mount>/<path>#<field>"));
+            }
+            if field.is_none() {
+                return Err(invalid("vault references need a `#field`"));
+            }
+        }
+        Ok(Some(Self {
+            scheme,
+            path: path.to_string(),
+            field,
+        }))
+    }
+}
+
+impl fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.scheme.prefix(), self.path)?;
+        match &self.field {
+            Some(field) => write!(f, "#{field}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_are_parsed_and_printed_back() {
+        for raw in [
+            "vault:secret/coder/api#jwt_secret",
+            "aws-sm:prod/coder/database",
+            "aws-sm:arn:aws:secretsmanager:eu-central-1:123456789012:secret:db-AbCdEf#url",
+        ] {
+            let reference = SecretRef::parse(raw).unwrap().unwrap();
+            assert_eq!(reference.to_string(), raw);
+        }
+        let reference = SecretRef::parse("vault:secret/coder/api#jwt_secret")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reference.scheme, Scheme::Vault);
+        assert_eq!(reference.path, "secret/coder/api");
+        assert_eq!(reference.field.as_deref(), Some("jwt_secret"));
+    }
+
+    #[test]
+    fn plain_values_are_not_references() {
+        for raw in ["postgres://coder@db/coder", "s3cret", "vault", ""] {
+            assert!(!is_reference(raw));
+            assert!(SecretRef::parse(raw).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn malformed_references_are_rejected() {
+        for raw in [
+            "vault:secret/coder/api",
+            "vault:api#key",
+            "aws-sm:#key",
+            "aws-sm:db#",
+        ] {
+            assert!(is_reference(raw));
+            assert!(SecretRef::parse(raw).is_err(), "{raw}");
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::aws::SecretsManager;
+use crate::vault::Vault;
+use crate::{Scheme, SecretRef, SecretsConfig, SecretsError};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An entry as a secrets manager returned it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetched {
+    /// A JSON object of fields, or a string for an AWS secret that is not
+    /// JSON.
+    pub data: Value,
+    pub version: Option<String>,
+}
+
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    /// The entry at `path` (see [`SecretRef::path`]).
+    async fn fetch(&self, path: &str) -> Result<Fetched, SecretsError>;
+}
+
+/// A resolved value that changed when its entry was fetched again.
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    /// The reference as it was resolved, e.g.
+    /// `vault:secret/coder/api#jwt_secret`.
+    pub reference: String,
+    pub value: String,
+}
+
+impl Rotation {
+    /// Whether this is the value `raw` refers to.
+    pub fn concerns(&self, raw: &str) -> bool {
+        matches!(SecretRef::parse(raw), Ok(Some(reference)) if reference.to_string() == self.reference)
+    }
+}
+
+struct Entry {
+    fetched: Fetched,
+    at: Instant,
+    /// The fields resolved from this entry; these are announced when they
+    /// change.
+    fields: BTreeSet<Option<String>>,
+}
+
+type Key = (Scheme, String);
+
+/// Resolves references through the configured providers and caches the
+/// fetched entries.
+pub struct Secrets {
+    providers: HashMap<Scheme, Arc<dyn SecretProvider>>,
+    ttl: Duration,
+    cache: Mutex<HashMap<Key, Entry>>,
+    rotations: broadcast::Sender<Rotation>,
+}
+
+impl Secrets {
+    pub fn new(config: SecretsConfig) -> Result<Self, SecretsError> {
+        let http = Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .map_err(|err| SecretsError::Config(err.to_string()))?;
+        let mut providers: HashMap<Scheme, Arc<dyn SecretProvider>> = HashMap::new();
+        if let Some(vault) = config.vault {
+            providers.insert(Scheme::Vault, Arc::new(Vault::new(http.clone(), vault)));
+        }
+        if let Some(aws) = config.aws {
+            providers.insert(
+                Scheme::AwsSecretsManager,
+                Arc::new(SecretsManager::new(http, aws)?),
+            );
+        }
+        let (rotations, _) = broadcast::channel(64);
+        Ok(Self {
+            providers,
+            ttl: config.cache_ttl,
+            cache: Mutex::new(HashMap::new()),
+            rotations,
+        })
+    }
+
+    /// Serves `scheme` references from `provider` instead.
+    pub fn with_provider(mut self, scheme: Scheme, provider: Arc<dyn SecretProvider>) -> Self {
+        self.providers.insert(scheme, provider);
+        self
+    }
+
+    /// The value `raw` refers to, or `raw` itself if it is not a reference.
+    pub async fn resolve(&self, raw: &str) -> Result<String, SecretsError> {
+        match SecretRef::parse(raw)? {
+            Some(reference) => self.get(&reference).await,
+            None => Ok(raw.to_string()),
+        }
+    }
+
+    /// The environment variable `name`, resolved. `None` if it is unset or
+    /// blank.
+    pub async fn env(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        match std::env::var(name)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+        {
+            Some(raw) => self.resolve(&raw).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The current value of `reference`. An expired entry that cannot be
+    /// fetched again is served from the cache.
+    pub async fn get(&self, reference: &SecretRef) -> Result<String, SecretsError> {
+        let key = (reference.scheme, reference.path.clone());
+        let stale = match self.cache.lock().get_mut(&key) {
+            Some(entry) => {
+                entry.fields.insert(reference.field.clone());
+                if entry.at.elapsed() < self.ttl {
+                    return field(reference, &entry.fetched.data);
+                }
+                Some(entry.fetched.data.clone())
+            }
+            None => None,
+        };
+        match self.fetch(&key).await {
+            Ok(fetched) => {
+                let value = field(reference, &fetched.data)?;
+                self.store(key, fetched, Some(reference.field.clone()));
+                Ok(value)
+            }
+            Err(err) => match stale {
+                Some(data) => {
+                    warn!(
+                        reference = %reference,
+                        error = %err,
+                        "failed to refresh a secret; serving the cached value"
+                    );
+                    field(reference, &data)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Values that change from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Rotation> {
+        self.rotations.subscribe()
+    }
+
+    /// Fetches every cached entry again and announces the values that
+    /// changed. Entries that cannot be fetched keep their cached value.
+    pub async fn refresh(&self) {
+        let keys: Vec<Key> = self.cache.lock().keys().cloned().collect();
+        for key in keys {
+            match self.fetch(&key).await {
+                Ok(fetched) => self.store(key, fetched, None),
+                Err(err) => warn!(
+                    scheme = %key.0,
+                    path = %key.1,
+                    error = %err,
+                    "failed to refresh a secret; keeping the cached value"
+                ),
+            }
+        }
+    }
+
+    /// Refreshes the cache every `SECRETS_CACHE_TTL_SECS` while the process
+    /// runs.
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        if self.providers.is_empty() {
+            return;
+        }
+        let secrets = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(secrets.ttl);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                secrets.refresh().await;
+            }
+        });
+    }
+
+    async fn fetch(&self, (scheme, path): &Key) -> Result<Fetched, SecretsError> {
+        let needs = match scheme {
+            Scheme::Vault => "VAULT_ADDR and VAULT_TOKEN",
+            Scheme::AwsSecretsManager => "AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_REGION",
+        };
+        let provider = self
+            .providers
+            .get(scheme)
+            .ok_or(SecretsError::NotConfigured(*scheme, needs))?;
+        provider.fetch(path).await
+    }
+
+    fn store(&self, key: Key, fetched: Fetched, resolved: Option<Option<String>>) {
+        let version = fetched.version.clone();
+        let rotations: Vec<Rotation> = {
+            let mut cache = self.cache.lock();
+            let entry = cache.entry(key.clone()).or_insert_with(|| Entry {
+                fetched: fetched.clone(),
+                at: Instant::now(),
+                fields: BTreeSet::new(),
+            });
+            let previous = std::mem::replace(&mut entry.fetched, fetched);
+            entry.at = Instant::now();
+            entry.fields.extend(resolved);
+            if previous.data == entry.fetched.data {
+                return;
+            }
+            entry
+                .fields
+                .iter()
+                .filter_map(|name| {
+                    let reference = SecretRef {
+                        scheme: key.0,
+                        path: key.1.clone(),
+                        field: name.clone(),
+                    };
+                    let value = field(&reference, &entry.fetched.data).ok()?;
+                    let before = field(&reference, &previous.data).ok();
+                    (before.as_ref() != Some(&value)).then(|| Rotation {
+                        reference: reference.to_string(),
+                        value,
+                    })
+                })
+                .collect()
+        };
+        for rotation in rotations {
+            info!(
+                reference = %rotation.reference,
+                version = version.as_deref().unwrap_or("-"),
+                "secret rotated"
+            );
+            let _ = self.rotations.send(rotation);
+        }
+    }
+}
+
+fn field(reference: &SecretRef, data: &Value) -> Result<String, SecretsError> {
+    let problem = |problem: String| SecretsError::Value {
+        reference: reference.to_string(),
+        problem,
+    };
+    let value = match &reference.field {
+        Some(name) => data
+            .get(name)
+            .ok_or_else(|| problem(format!("has no field `{name}`")))?,
+        None => data,
+    };
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(_) | Value::Bool(_) => Ok(value.to_string()),
+        Value::Object(_) => Err(problem("is a JSON object; name a key with `#key`".into())),
+        _ => Err(problem("is not a string".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Fake {
+        data: Mutex<Value>,
+        calls: AtomicUsize,
+        down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl SecretProvider for Fake {
+        async fn fetch(&self, path: &str) -> Result<Fetched, SecretsError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                return Err(SecretsError::Request {
+                    scheme: Scheme::Vault,
+                    path: path.to_string(),
+                    message: "connection refused".into(),
+                });
+            }
+            Ok(Fetched {
+                data: self.data.lock().clone(),
+                version: None,
+            })
+        }
+    }
+
+    fn secrets(ttl: Duration, data: Value) -> (Secrets, Arc<Fake>) {
+        let fake = Arc::new(Fake {
+            data: Mutex::new(data),
+            ..Fake::default()
+        });
+        let config = SecretsConfig {
+            cache_ttl: ttl,
+            ..SecretsConfig::default()
+        };
+        let secrets = Secrets::new(config)
+            .unwrap()
+            .with_provider(Scheme::Vault, fake.clone());
+        (secrets, fake)
+    }
+
+    #[tokio::test]
+    async fn entries_are_cached_per_path() {
+        let (secrets, fake) = secrets(
+            Duration::from_secs(60),
+            json!({ "jwt_secret": "s3cret", "admin_token": "t0ken" }),
+        );
+        assert_eq!(
+            secrets.resolve("vault:kv/coder#jwt_secret").await.unwrap(),
+            "s3cret"
+        );
+        assert_eq!(
+            secrets.resolve("vault:kv/coder#admin_token").await.unwrap(),
+            "t0ken"
+        );
+        assert_eq!(secrets.resolve("plain").await.unwrap(), "plain");
+        assert_eq!(fake.calls.load(Ordering::SeqCst), 1);
+        assert!(secrets
+            .resolve("vault:kv/coder#missing")
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("has no field `missing`"));
+        assert!(matches!(
+            secrets.resolve("aws-sm:coder/db").await,
+            Err(SecretsError::NotConfigured(Scheme::AwsSecretsManager, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_entries_fall_back_to_the_cache_when_the_manager_is_down() {
+        let (secrets, fake) = secrets(Duration::ZERO, json!({ "url": "postgres://old" }));
+        assert_eq!(
+            secrets.resolve("vault:kv/db#url").await.unwrap(),
+            "postgres://old"
+        );
+        fake.down.store(true, Ordering::SeqCst);
+        assert_eq!(
+            secrets.resolve("vault:kv/db#url").await.unwrap(),
+            "postgres://old"
+        );
+        assert!(secrets.resolve("vault:kv/other#url").await.is_err());
+        assert_eq!(fake.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn refresh_announces_changed_values() {
+        let (secrets, fake) = secrets(
+            Duration::from_secs(60),
+            json!({ "url": "postgres://old", "token": "same" }),
+        );
+        secrets.resolve("vault:kv/db#url").await.unwrap();
+        secrets.resolve("vault:kv/db#token").await.unwrap();
+        let mut rotations = secrets.subscribe();
+
+        secrets.refresh().await;
+        assert!(rotations.try_recv().is_err());
+
+        *fake.data.lock() = json!({ "url": "postgres://new", "token": "same" });
+        secrets.refresh().await;
+        let rotation = rotations.try_recv().unwrap();
+        assert_eq!(rotation.reference, "vault:kv/db#url");
+        assert!(rotation.concerns(" vault:kv/db/#url"));
+        assert!(!rotation.concerns("vault:kv/db#token"));
+        assert_eq!(rotation.value, "postgres://new");
+        assert!(rotations.try_recv().is_err());
+        assert_eq!(
+            secrets.resolve("vault:kv/db#url").await.unwrap(),
+            "postgres://new"
+        );
+    }
+}
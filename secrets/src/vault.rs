@@ -0,0 +1,70 @@
+//! HashiCorp Vault's KV version 2 engine. `vault:secret/coder/api#key`
+//! reads `GET /v1/secret/data/coder/api` and takes `key` from the latest
+//! version's data.
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+
+use crate::{Fetched, Scheme, SecretProvider, SecretsError, VaultConfig};
+
+pub(crate) struct Vault {
+    http: Client,
+    config: VaultConfig,
+}
+
+impl Vault {
+    pub(crate) fn new(http: Client, config: VaultConfig) -> Self {
+        Self { http, config }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for Vault {
+    async fn fetch(&self, path: &str) -> Result<Fetched, SecretsError> {
+        let failed = |message: String| SecretsError::Request {
+            scheme: Scheme::Vault,
+            path: path.to_string(),
+            message,
+        };
+        let (mount, rest) = path
+            .split_once('/')
+            .ok_or_else(|| failed("expected <mount>/<path>".into()))?;
+        let url = format!(
+            "{}/v1/{mount}/data/{rest}",
+            self.config.addr.trim_end_matches('/')
+        );
+        let mut request = self
+            .http
+            .get(url)
+            .header("X-Vault-Token", &self.config.token);
+        if let Some(namespace) = &self.config.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| failed(err.to_string()))?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND => return Err(failed("no such secret".into())),
+            status => return Err(failed(format!("vault answered {status}"))),
+        }
+        let mut body: Value = response
+            .json()
+            .await
+            .map_err(|err| failed(format!("invalid response: {err}")))?;
+        let data = body["data"]["data"].take();
+        if !data.is_object() {
+            return Err(failed(
+                "the secret has no data; is the mount a KV version 2 engine?".into(),
+            ));
+        }
+        Ok(Fetched {
+            data,
+            version: body["data"]["metadata"]["version"]
+                .as_u64()
+                .map(|version| version.to_string()),
+        })
+    }
+}
@@ -0,0 +1,200 @@
+//! Optional HTTPS termination with rustls. Setting `TLS_CERT_PATH` and
+//! `TLS_KEY_PATH` makes a service serve TLS itself; the PEM files are re-read
+//! every `TLS_RELOAD_INTERVAL_SECS` so renewed certificates (e.g. from
+//! certbot) apply without a restart. `TLS_REDIRECT_ADDR` adds a plain-HTTP
+//! listener that only redirects to HTTPS, so tokens never travel in clear.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context as _};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use tracing::{info, warn};
+
+/// Default for `TLS_RELOAD_INTERVAL_SECS`.
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// How often the PEM files are re-read; at least a second.
+    pub reload_interval: Duration,
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+impl TlsSettings {
+    /// Reads the settings from the environment. Returns `None` when TLS is
+    /// not configured; setting only one of the certificate and key paths is
+    /// an error.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        let (cert, key) = match (var("TLS_CERT_PATH"), var("TLS_KEY_PATH")) {
+            (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(anyhow!(
+                    "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+                ))
+            }
+        };
+        let reload_interval = var("TLS_RELOAD_INTERVAL_SECS")
+            .map(|value| value.parse::<u64>())
+            .transpose()
+            .context("invalid TLS_RELOAD_INTERVAL_SECS")?
+            .map_or(DEFAULT_RELOAD_INTERVAL, Duration::from_secs);
+        let redirect_addr = var("TLS_REDIRECT_ADDR")
+            .map(|value| value.parse::<SocketAddr>())
+            .transpose()
+            .context("invalid TLS_REDIRECT_ADDR")?;
+        Ok(Some(Self {
+            cert,
+            key,
+            reload_interval,
+            redirect_addr,
+        }))
+    }
+
+    /// Reads the certificate and key once, for listeners such as gRPC that
+    /// do not pick up reloaded files.
+    pub async fn read_pem(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let cert = tokio::fs::read(&self.cert)
+            .await
+            .with_context(|| format!("failed to read {}", self.cert.display()))?;
+        let key = tokio::fs::read(&self.key)
+            .await
+            .with_context(|| format!("failed to read {}", self.key.display()))?;
+        Ok((cert, key))
+    }
+}
+
+/// Serves `app` over HTTPS, with the optional redirect listener alongside,
+/// until `shutdown` resolves; then both stop accepting connections and
+/// in-flight requests are bounded by the caller's drain deadline.
+pub async fn serve(
+    bind_addr: SocketAddr,
+    settings: TlsSettings,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let config = RustlsConfig::from_pem_file(&settings.cert, &settings.key)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load TLS certificate {} / key {}",
+                settings.cert.display(),
+                settings.key.display()
+            )
+        })?;
+    spawn_reloader(config.clone(), settings.clone());
+
+    let handle = Handle::new();
+    let mut handles = vec![handle.clone()];
+    if let Some(redirect_addr) = settings.redirect_addr {
+        let redirect = Handle::new();
+        handles.push(redirect.clone());
+        tokio::spawn(serve_redirect(redirect_addr, bind_addr.port(), redirect));
+    }
+    tokio::spawn(async move {
+        shutdown.await;
+        for handle in handles {
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    info!(%bind_addr, "serving https");
+    axum_server::bind_rustls(bind_addr, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+fn spawn_reloader(config: RustlsConfig, settings: TlsSettings) {
+    tokio::spawn(async move {
+        let interval = settings.reload_interval.max(Duration::from_secs(1));
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = config
+                .reload_from_pem_file(&settings.cert, &settings.key)
+                .await
+            {
+                warn!(error = %err, "failed to reload TLS certificate; keeping the current one");
+            }
+        }
+    });
+}
+
+async fn serve_redirect(addr: SocketAddr, https_port: u16, handle: Handle) {
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port)
+    });
+    info!(%addr, "redirecting http to https");
+    if let Err(err) = axum_server::bind(addr)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await
+    {
+        warn!(%addr, error = %err, "http redirect listener stopped");
+    }
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok());
+    match host.and_then(|host| https_location(host, uri, https_port)) {
+        Some(location) => Redirect::permanent(&location).into_response(),
+        None => (StatusCode::BAD_REQUEST, "missing or invalid host header").into_response(),
+    }
+}
+
+/// Builds the HTTPS URL for a plain-HTTP request, replacing any port in
+/// `host` with the HTTPS port (omitted when it is 443).
+fn https_location(host: &str, uri: &Uri, https_port: u16) -> Option<String> {
+    let authority: axum::http::uri::Authority = host.parse().ok()?;
+    let host = authority.host();
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    Some(match https_port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn https_location_swaps_scheme_and_port() {
+        let uri: Uri = "/rpc?x=1".parse().unwrap();
+        assert_eq!(
+            https_location("api.example.com:80", &uri, 443).as_deref(),
+            Some("https://api.example.com/rpc?x=1")
+        );
+        assert_eq!(
+            https_location("api.example.com", &uri, 6813).as_deref(),
+            Some("https://api.example.com:6813/rpc?x=1")
+        );
+        assert_eq!(
+            https_location("[::1]:8080", &Uri::from_static("/"), 8443).as_deref(),
+            Some("https://[::1]:8443/")
+        );
+        assert!(https_location("bad host", &uri, 443).is_none());
+    }
+}
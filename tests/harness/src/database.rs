@@ -0,0 +1,85 @@
+//! A throwaway Postgres with every migration of `database/migrations`
+//! applied, the way an operator would run them by hand.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Executor, PgPool};
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::{ContainerAsync, ImageExt};
+
+/// pgvector ships `vector`, `pgcrypto`, `pg_trgm` and `uuid-ossp`; only
+/// `pgml` is missing, and nothing calls it at runtime.
+const DEFAULT_IMAGE: &str = "pgvector/pgvector:pg16";
+
+pub(crate) struct Database {
+    _container: ContainerAsync<Postgres>,
+    pub(crate) url: String,
+    pub(crate) pool: PgPool,
+}
+
+impl Database {
+    /// Starts `HARNESS_POSTGRES_IMAGE` (default `pgvector/pgvector:pg16`)
+    /// and migrates it.
+    pub(crate) async fn start() -> anyhow::Result<Self> {
+        let image =
+            std::env::var("HARNESS_POSTGRES_IMAGE").unwrap_or_else(|_| DEFAULT_IMAGE.to_string());
+        let (name, tag) = image.rsplit_once(':').unwrap_or((image.as_str(), "latest"));
+        let container = Postgres::default()
+            .with_name(name)
+            .with_tag(tag)
+            .start()
+            .await
+            .with_context(|| format!("failed to start {image}; is docker running?"))?;
+        let host = container.get_host().await?;
+        let port = container.get_host_port_ipv4(5432).await?;
+        let url = format!("postgres://postgres:postgres@{host}:{port}/postgres");
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect(&url)
+            .await
+            .context("failed to connect to postgres")?;
+        migrate(&pool).await?;
+        Ok(Self {
+            _container: container,
+            url,
+            pool,
+        })
+    }
+}
+
+fn migrations_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../database/migrations")
+}
+
+async fn migrate(pool: &PgPool) -> anyhow::Result<()> {
+    let dir = migrations_dir();
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "sql"));
+    files.sort();
+    if files.is_empty() {
+        bail!("no migrations in {}", dir.display());
+    }
+    let has_pgml: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'pgml')",
+    )
+    .fetch_one(pool)
+    .await?;
+    for path in files {
+        let mut sql = std::fs::read_to_string(&path)?;
+        if !has_pgml {
+            sql = sql.replace("CREATE EXTENSION IF NOT EXISTS pgml;", "");
+        }
+        // A plain string runs as a simple query, so files may hold several
+        // statements.
+        pool.execute(sql.as_str())
+            .await
+            .with_context(|| format!("migration {} failed", path.display()))?;
+    }
+    Ok(())
+}
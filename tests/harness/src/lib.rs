@@ -0,0 +1,354 @@
+//! End-to-end harness for black-box tests of the whole stack: a Postgres
+//! container (testcontainers) with all migrations, the `auth` service, the
+//! API gateway and a [`MockLlmServer`] the agents talk to. Tests only see
+//! what a client sees: HTTP on the auth service and JSON-RPC on `/rpc`.
+//!
+//! Every [`Harness`] gets its own database and sandbox root, so tests can
+//! run in parallel. They need docker and are `#[ignore]`d; run them with
+//! `cargo test -p harness -- --ignored`. `HARNESS_POSTGRES_IMAGE` swaps the
+//! database image and `HARNESS_LOGS=debug` shows the services' logs.
+
+mod database;
+mod services;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+pub use mock_llm::{Endpoint, MockLlmServer, Reply};
+
+use crate::database::Database;
+use crate::services::Service;
+
+const JWT_SECRET: &str = "harness-jwt-secret-0123456789abcdef";
+/// Long and random enough for the default password policy.
+const PASSWORD: &str = "Harness-Passphrase-7f3c-Quokka!";
+/// `micro.start` image running code through `/bin/sh`.
+pub const SHELL_IMAGE: &str = "sh";
+
+pub struct Harness {
+    // Dropped in order: the services go before the database they use.
+    api: Service,
+    auth: Service,
+    llm: MockLlmServer,
+    database: Database,
+    _sandbox: TempDir,
+    http: reqwest::Client,
+}
+
+impl Harness {
+    pub async fn start() -> anyhow::Result<Self> {
+        let binaries = services::binaries().await?;
+        let database = Database::start().await?;
+        let llm = MockLlmServer::start().await?;
+        let sandbox = tempfile::tempdir()?;
+
+        let auth = Service::spawn(
+            "auth",
+            &binaries.auth,
+            "AUTH_BIND_ADDR",
+            &[
+                ("DATABASE_URL", database.url.clone()),
+                ("AUTH_JWT_SECRET", JWT_SECRET.into()),
+            ],
+        )
+        .await?;
+        let micro_images =
+            json!([{ "name": SHELL_IMAGE, "command": "/bin/sh", "extension": "sh" }]);
+        let api = Service::spawn(
+            "api",
+            &binaries.api,
+            "API_BIND_ADDR",
+            &[
+                ("DATABASE_URL", database.url.clone()),
+                ("API_JWT_SECRET", JWT_SECRET.into()),
+                ("LLM_SERVER_URL", llm.url()),
+                ("AGENT_LLM_ENDPOINT", llm.url()),
+                ("AGENT_DEFAULT_MODEL", "mock-model".into()),
+                ("SANDBOX_ROOT", sandbox.path().display().to_string()),
+                ("SANDBOX_MICRO_IMAGES", micro_images.to_string()),
+                ("WEBHOOK_SECRET_KEY", "42".repeat(32)),
+                ("PROJECT_SNAPSHOT_LIMIT", "3".into()),
+                ("PROJECT_FILE_VERSION_LIMIT", "3".into()),
+                // Joins the replica set so calls follow their handles; tests
+                // play the other replicas, none of them calls back.
+                ("REPLICA_URL", "http://api.invalid".into()),
+            ],
+        )
+        .await?;
+
+        Ok(Self {
+            api,
+            auth,
+            llm,
+            database,
+            _sandbox: sandbox,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// The scripted LLM behind `llm.*` and the agents.
+    pub fn llm(&self) -> &MockLlmServer {
+        &self.llm
+    }
+
+    /// Direct access to the database, for assertions only.
+    pub fn pool(&self) -> &PgPool {
+        &self.database.pool
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api.url
+    }
+
+    pub fn auth_url(&self) -> &str {
+        &self.auth.url
+    }
+
+    /// Registers a developer with `tokens` on their balance and returns the
+    /// user id.
+    pub async fn register(&self, username: &str, tokens: i64) -> anyhow::Result<i32> {
+        self.register_with(username, json!({ "initial_tokens": tokens }))
+            .await
+    }
+
+    /// Registers `username` with the harness password and the other
+    /// `/auth/register` fields in `fields`, and returns the user id.
+    pub async fn register_with(&self, username: &str, fields: Value) -> anyhow::Result<i32> {
+        let mut body = json!({ "username": username, "password": PASSWORD });
+        if let (Some(body), Value::Object(fields)) = (body.as_object_mut(), fields) {
+            body.extend(fields);
+        }
+        let response = self
+            .http
+            .post(format!("{}/auth/register", self.auth.url))
+            .json(&body)
+            .send()
+            .await?;
+        let body = expect_success(response, "register").await?;
+        body["user_id"]
+            .as_i64()
+            .and_then(|id| i32::try_from(id).ok())
+            .context("register returned no user_id")
+    }
+
+    pub async fn login(&self, username: &str) -> anyhow::Result<Session> {
+        let response = self
+            .http
+            .post(format!("{}/auth/login", self.auth.url))
+            .json(&json!({ "username": username, "password": PASSWORD }))
+            .send()
+            .await?;
+        let body = expect_success(response, "login").await?;
+        let token = body["token"]
+            .as_str()
+            .context("login returned no token")?
+            .to_string();
+        Ok(self.session(token))
+    }
+
+    /// A freshly registered and logged in developer with 1000 tokens.
+    pub async fn developer(&self) -> anyhow::Result<Session> {
+        let username = format!("dev-{}", &Uuid::new_v4().simple().to_string()[..12]);
+        self.register(&username, 1_000).await?;
+        self.login(&username).await
+    }
+
+    /// A freshly registered and logged in admin of the default tenant.
+    pub async fn admin(&self) -> anyhow::Result<Session> {
+        let username = format!("admin-{}", &Uuid::new_v4().simple().to_string()[..12]);
+        self.register_with(&username, json!({ "role": "admin" }))
+            .await?;
+        self.login(&username).await
+    }
+
+    /// Calls the auth service, as `session` if given, and returns the status
+    /// and the JSON body (`null` when there is none).
+    pub async fn auth(
+        &self,
+        session: Option<&Session>,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.auth.url));
+        if let Some(session) = session {
+            request = request.bearer_auth(&session.token);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request
+            .send()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: request failed: {err}"));
+        let status = response.status().as_u16();
+        let text = response
+            .text()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: reading the response failed: {err}"));
+        let body = if text.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&text)
+                .unwrap_or_else(|err| panic!("{path}: response is not JSON: {err}: {text}"))
+        };
+        (status, body)
+    }
+
+    /// GETs `path` on the API gateway without credentials and returns the
+    /// status and the body, read to the end.
+    pub async fn api_get(&self, path: &str) -> (u16, String) {
+        let response = self
+            .http
+            .get(format!("{}{path}", self.api.url))
+            .send()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: request failed: {err}"));
+        let status = response.status().as_u16();
+        let text = response
+            .text()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: reading the response failed: {err}"));
+        (status, text)
+    }
+
+    /// Sends `body` with `content_type` to the API gateway as `session` and
+    /// returns the status and the JSON body (`null` when there is none).
+    pub async fn api_send(
+        &self,
+        session: &Session,
+        method: reqwest::Method,
+        path: &str,
+        content_type: &str,
+        body: impl Into<reqwest::Body>,
+    ) -> (u16, Value) {
+        let response = self
+            .http
+            .request(method, format!("{}{path}", self.api.url))
+            .bearer_auth(&session.token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body)
+            .send()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: request failed: {err}"));
+        let status = response.status().as_u16();
+        let text = response
+            .text()
+            .await
+            .unwrap_or_else(|err| panic!("{path}: reading the response failed: {err}"));
+        let body = if text.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&text)
+                .unwrap_or_else(|err| panic!("{path}: response is not JSON: {err}: {text}"))
+        };
+        (status, body)
+    }
+
+    /// A session presenting `token` as is, e.g. a forged one.
+    pub fn session(&self, token: impl Into<String>) -> Session {
+        Session {
+            http: self.http.clone(),
+            rpc_url: format!("{}/rpc", self.api.url),
+            token: token.into(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+}
+
+async fn expect_success(response: reqwest::Response, what: &str) -> anyhow::Result<Value> {
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        bail!("{what} failed with {status}: {body}");
+    }
+    serde_json::from_str(&body).with_context(|| format!("{what} returned invalid JSON: {body}"))
+}
+
+/// A logged in client of the API gateway.
+pub struct Session {
+    http: reqwest::Client,
+    rpc_url: String,
+    token: String,
+    next_id: AtomicU64,
+}
+
+impl Session {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Calls `method` and returns its result or JSON-RPC error. Transport
+    /// failures and malformed responses fail the test right away.
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let response = self
+            .http
+            .post(&self.rpc_url)
+            .bearer_auth(&self.token)
+            .json(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .send()
+            .await
+            .unwrap_or_else(|err| panic!("{method}: request failed: {err}"));
+        let body: Value = response
+            .json()
+            .await
+            .unwrap_or_else(|err| panic!("{method}: response is not JSON: {err}"));
+        if let Some(error) = body.get("error") {
+            return Err(RpcError {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+                data: error.get("data").cloned(),
+            });
+        }
+        Ok(body.get("result").cloned().unwrap_or(Value::Null))
+    }
+
+    /// Like [`Session::call`], panicking on errors.
+    pub async fn rpc(&self, method: &str, params: Value) -> Value {
+        self.call(method, params)
+            .await
+            .unwrap_or_else(|err| panic!("{method} failed: {err}"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)?;
+        if let Some(data) = &self.data {
+            write!(f, ": {data}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+pub fn encode(data: impl AsRef<[u8]>) -> String {
+    BASE64.encode(data)
+}
+
+/// Decodes a base64 field such as `stdout` as utf-8 text.
+pub fn decode(value: &Value) -> String {
+    let bytes = BASE64
+        .decode(value.as_str().expect("base64 string"))
+        .expect("valid base64");
+    String::from_utf8(bytes).expect("utf-8")
+}
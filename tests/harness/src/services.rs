@@ -0,0 +1,148 @@
+//! The `api` and `auth` binaries, built once per test process and run as
+//! child processes on free ports.
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::Value;
+use tokio::process::{Child, Command};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) struct Binaries {
+    pub(crate) api: PathBuf,
+    pub(crate) auth: PathBuf,
+}
+
+/// Builds both services with the cargo running the tests, so they share its
+/// profile and target directory.
+pub(crate) async fn binaries() -> anyhow::Result<&'static Binaries> {
+    static BINARIES: OnceLock<Result<Binaries, String>> = OnceLock::new();
+    if let Some(built) = BINARIES.get() {
+        return built.as_ref().map_err(|err| anyhow!("{err}"));
+    }
+    let built = tokio::task::spawn_blocking(|| {
+        BINARIES.get_or_init(|| build().map_err(|err| format!("{err:#}")))
+    })
+    .await?;
+    built.as_ref().map_err(|err| anyhow!("{err}"))
+}
+
+fn build() -> anyhow::Result<Binaries> {
+    let mut command = std::process::Command::new(env!("CARGO"));
+    command
+        .args(["build", "-p", "api", "-p", "auth", "--bins"])
+        .args(["--message-format", "json-render-diagnostics"])
+        .stderr(Stdio::inherit());
+    if !cfg!(debug_assertions) {
+        command.arg("--release");
+    }
+    let output = command.output().context("failed to run cargo build")?;
+    if !output.status.success() {
+        bail!("cargo build of api and auth failed: {}", output.status);
+    }
+    let mut api = None;
+    let mut auth = None;
+    for line in output.stdout.split(|byte| *byte == b'\n') {
+        let Ok(message) = serde_json::from_slice::<Value>(line) else {
+            continue;
+        };
+        let Some(executable) = message["executable"].as_str() else {
+            continue;
+        };
+        match message["target"]["name"].as_str() {
+            Some("api") => api = Some(PathBuf::from(executable)),
+            Some("auth") => auth = Some(PathBuf::from(executable)),
+            _ => {}
+        }
+    }
+    Ok(Binaries {
+        api: api.context("cargo build produced no api binary")?,
+        auth: auth.context("cargo build produced no auth binary")?,
+    })
+}
+
+/// A running service, killed when dropped.
+pub(crate) struct Service {
+    name: &'static str,
+    child: Child,
+    pub(crate) url: String,
+}
+
+impl Service {
+    /// Starts `binary` with `env` plus its bind address under `bind_var`,
+    /// and waits until `/health` answers. Output is discarded unless
+    /// `HARNESS_LOGS` is set, e.g. to `debug`; it doubles as `RUST_LOG`.
+    pub(crate) async fn spawn(
+        name: &'static str,
+        binary: &PathBuf,
+        bind_var: &str,
+        env: &[(&str, String)],
+    ) -> anyhow::Result<Self> {
+        let port = free_port()?;
+        let output = || {
+            if std::env::var_os("HARNESS_LOGS").is_some() {
+                Stdio::inherit()
+            } else {
+                Stdio::null()
+            }
+        };
+        let mut command = Command::new(binary);
+        command
+            .env(bind_var, format!("127.0.0.1:{port}"))
+            .env(
+                "RUST_LOG",
+                std::env::var("HARNESS_LOGS").unwrap_or_else(|_| "warn".into()),
+            )
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .stdout(output())
+            .stderr(output())
+            .kill_on_drop(true);
+        let child = command
+            .spawn()
+            .with_context(|| format!("failed to start {name}"))?;
+        let mut service = Self {
+            name,
+            child,
+            url: format!("http://127.0.0.1:{port}"),
+        };
+        service.wait_healthy().await?;
+        Ok(service)
+    }
+
+    async fn wait_healthy(&mut self) -> anyhow::Result<()> {
+        let client = reqwest::Client::new();
+        let health = format!("{}/health", self.url);
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                bail!("{} exited during startup: {status}", self.name);
+            }
+            let ready = client
+                .get(&health)
+                .timeout(Duration::from_secs(1))
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success());
+            if ready {
+                return Ok(());
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                bail!("{} was not healthy after {STARTUP_TIMEOUT:?}", self.name);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// A port nothing listens on right now. Another process could take it
+/// before the service binds it, which is rare enough for tests.
+fn free_port() -> anyhow::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
@@ -0,0 +1,921 @@
+use std::time::Duration;
+
+use harness::{decode, encode, Endpoint, Harness, Reply, SHELL_IMAGE};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+async fn harness() -> Harness {
+    Harness::start().await.expect("harness starts")
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn project_files_and_processes() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+
+    let project = dev
+        .rpc(
+            "project.create",
+            json!({ "name": "e2e", "description": "harness" }),
+        )
+        .await;
+    let project_id = project["id"].as_str().unwrap().to_string();
+    let listed = dev.rpc("project.list", json!({})).await;
+    assert!(listed.to_string().contains(&project_id));
+
+    dev.rpc(
+        "fs.write",
+        json!({
+            "project_id": project_id,
+            "path": "src/hello.sh",
+            "data": encode("echo hello from the sandbox\n"),
+        }),
+    )
+    .await;
+    let read = dev
+        .rpc(
+            "fs.read",
+            json!({ "project_id": project_id, "path": "src/hello.sh" }),
+        )
+        .await;
+    assert_eq!(decode(&read["data"]), "echo hello from the sandbox\n");
+
+    let run = dev
+        .rpc(
+            "run.exec",
+            json!({
+                "project_id": project_id,
+                "program": "/bin/sh",
+                "args": ["src/hello.sh"],
+            }),
+        )
+        .await;
+    assert_eq!(run["exit_code"], 0);
+    assert_eq!(decode(&run["stdout"]), "hello from the sandbox\n");
+
+    let failed = dev
+        .call(
+            "fs.read",
+            json!({ "project_id": project_id, "path": "../../etc/passwd" }),
+        )
+        .await
+        .unwrap_err();
+    assert!(failed.data.unwrap()["request_id"].is_string());
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn batch_entries_fail_authentication_under_their_own_request_ids() {
+    let harness = harness().await;
+    let forged = harness.session("not-a-token");
+    let batch = json!([
+        { "jsonrpc": "2.0", "id": 1, "method": "project.list" },
+        { "jsonrpc": "2.0", "id": 2, "method": "quota.status" },
+    ]);
+    let (status, body) = harness
+        .api_send(
+            &forged,
+            Method::POST,
+            "/rpc",
+            "application/json",
+            batch.to_string(),
+        )
+        .await;
+    assert_eq!(status, 200);
+    let ids: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|reply| reply["error"]["data"]["request_id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+}
+
+/// A multipart body with one file part per `(name, content)`.
+fn multipart(boundary: &str, files: &[(&str, &str)]) -> String {
+    let mut body = String::new();
+    for (name, content) in files {
+        body.push_str(&format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; \
+             filename=\"{name}\"\r\nContent-Type: application/octet-stream\r\n\r\n\
+             {content}\r\n"
+        ));
+    }
+    body.push_str(&format!("--{boundary}--\r\n"));
+    body
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn project_files_upload_over_rest() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    let project = dev
+        .rpc("project.create", json!({ "name": "uploads" }))
+        .await;
+    let project_id = project["id"].as_str().unwrap().to_string();
+    let read =
+        |path: &'static str| dev.call("fs.read", json!({ "project_id": project_id, "path": path }));
+
+    let (status, saved) = harness
+        .api_send(
+            &dev,
+            Method::PUT,
+            &format!("/projects/{project_id}/files/notes/raw.txt"),
+            "application/octet-stream",
+            "raw body\n",
+        )
+        .await;
+    assert_eq!(status, 200, "{saved}");
+    assert_eq!(
+        decode(&read("notes/raw.txt").await.unwrap()["data"]),
+        "raw body\n"
+    );
+
+    let boundary = "harness-boundary";
+    let content_type = format!("multipart/form-data; boundary={boundary}");
+    let upload = format!("/projects/{project_id}/upload?dir=docs");
+    let body = multipart(boundary, &[("a.txt", "first"), ("b.txt", "second")]);
+    let (status, saved) = harness
+        .api_send(&dev, Method::POST, &upload, &content_type, body)
+        .await;
+    assert_eq!(status, 200, "{saved}");
+    assert_eq!(saved["files"].as_array().unwrap().len(), 2);
+    assert_eq!(decode(&read("docs/a.txt").await.unwrap()["data"]), "first");
+    assert_eq!(decode(&read("docs/b.txt").await.unwrap()["data"]), "second");
+
+    // One bad part fails the whole upload; the good part is not stored.
+    let body = multipart(boundary, &[("c.txt", "third"), ("../escape.txt", "out")]);
+    let (status, failed) = harness
+        .api_send(&dev, Method::POST, &upload, &content_type, body)
+        .await;
+    assert_eq!(status, 400, "{failed}");
+    assert!(read("docs/c.txt").await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn micro_vms_keep_running_between_calls() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+
+    let started = dev
+        .rpc("micro.start", json!({ "image": SHELL_IMAGE }))
+        .await;
+    let vm_id = started["vm_id"].as_str().unwrap().to_string();
+    let first = dev
+        .rpc(
+            "micro.execute",
+            json!({ "vm_id": vm_id, "code": encode("echo 42 > answer && echo stored") }),
+        )
+        .await;
+    assert_eq!(decode(&first["stdout"]), "stored\n");
+    let second = dev
+        .rpc(
+            "micro.execute",
+            json!({ "vm_id": vm_id, "code": encode("cat answer") }),
+        )
+        .await;
+    assert_eq!(decode(&second["stdout"]), "42\n");
+
+    dev.rpc("micro.stop", json!({ "vm_id": vm_id })).await;
+    let stopped = dev
+        .call(
+            "micro.execute",
+            json!({ "vm_id": vm_id, "code": encode("true") }),
+        )
+        .await;
+    assert!(stopped.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn agents_answer_through_the_llm() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    harness.llm().push(
+        Endpoint::Chat,
+        Reply::json(json!({
+            "summary": "wrote the parser",
+            "insights": ["covered by tests"],
+            "actions": [],
+        })),
+    );
+
+    let submission = dev
+        .rpc(
+            "agent.dispatch",
+            json!({ "agent": "code", "objective": "write a parser" }),
+        )
+        .await;
+    let task_id = submission["task_id"].as_str().unwrap().to_string();
+    let snapshot = tokio::time::timeout(Duration::from_secs(30), async {
+        loop {
+            let snapshot = dev.rpc("agent.status", json!({ "task_id": task_id })).await;
+            if !matches!(snapshot["status"].as_str(), Some("pending" | "running")) {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("agent task finishes");
+
+    assert_eq!(snapshot["status"], "completed", "{snapshot}");
+    assert_eq!(snapshot["summary"], "wrote the parser");
+    let requests = harness.llm().requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].body.to_string().contains("write a parser"));
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn finished_agent_tasks_land_in_the_inbox() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    harness.llm().push(
+        Endpoint::Chat,
+        Reply::json(json!({ "summary": "wrote the parser", "actions": [] })),
+    );
+    let submission = dev
+        .rpc(
+            "agent.dispatch",
+            json!({ "agent": "code", "objective": "write a parser" }),
+        )
+        .await;
+    let task_id = submission["task_id"].clone();
+
+    // The notification is written after the status changes.
+    let inbox = tokio::time::timeout(Duration::from_secs(30), async {
+        loop {
+            let inbox = dev.rpc("notify.list", json!({ "unread_only": true })).await;
+            if inbox["unread"] == 1 {
+                return inbox;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("the finished task raises a notification");
+    let notification = &inbox["notifications"][0];
+    assert_eq!(notification["kind"], "agent.completed", "{inbox}");
+    assert_eq!(notification["data"]["task_id"], task_id);
+    assert_eq!(notification["read"], false);
+
+    // Nobody else sees it, and marking it read empties the unread list.
+    let other = harness.admin().await.unwrap();
+    let theirs = other.rpc("notify.list", json!({})).await;
+    assert_eq!(theirs["notifications"], json!([]), "{theirs}");
+    let err = dev.call("notify.markRead", json!({})).await.unwrap_err();
+    assert_eq!(err.code, -32602, "{err}");
+    let marked = dev
+        .rpc("notify.markRead", json!({ "ids": [notification["id"]] }))
+        .await;
+    assert_eq!(marked, json!({ "updated": 1, "unread": 0 }));
+    let inbox = dev.rpc("notify.list", json!({ "unread_only": true })).await;
+    assert_eq!(inbox["notifications"], json!([]), "{inbox}");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn agent_tasks_stream_as_server_sent_events() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    harness.llm().push(
+        Endpoint::Chat,
+        Reply::json(json!({ "summary": "wrote the parser", "actions": [] })),
+    );
+    let submission = dev
+        .rpc(
+            "agent.dispatch",
+            json!({ "agent": "code", "objective": "write a parser" }),
+        )
+        .await;
+    let task_id = submission["task_id"].as_str().unwrap().to_string();
+
+    // EventSource cannot set headers, so the token comes in the query.
+    let path = format!("/events/agents/{task_id}?access_token={}", dev.token());
+    let (status, body) = tokio::time::timeout(Duration::from_secs(30), harness.api_get(&path))
+        .await
+        .expect("stream ends after the terminal status");
+    assert_eq!(status, 200, "{body}");
+    assert!(body.contains("event: status"), "{body}");
+    assert!(body.contains("\"completed\""), "{body}");
+    assert!(body.trim_end().ends_with("data: [DONE]"), "{body}");
+
+    let (status, _) = harness.api_get(&format!("/events/agents/{task_id}")).await;
+    assert_eq!(status, 401);
+    let (status, _) = harness
+        .api_get(&format!("/events/agents/{task_id}?access_token=forged"))
+        .await;
+    assert_eq!(status, 401);
+
+    // Switching the streaming flag off closes the route.
+    let admin = harness.admin().await.unwrap();
+    admin
+        .rpc(
+            "admin.flags.set",
+            json!({ "flag": "streaming", "enabled": false }),
+        )
+        .await;
+    let (status, body) = harness.api_get(&path).await;
+    assert_eq!(status, 403, "{body}");
+    assert!(body.contains("-32071"), "{body}");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn agent_results_apply_to_projects() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    let other = harness.developer().await.unwrap();
+    harness.llm().push(
+        Endpoint::Chat,
+        Reply::json(json!({
+            "summary": "scaffolded the script",
+            "insights": [],
+            "actions": [
+                {
+                    "type": "file_write",
+                    "path": "src/main.sh",
+                    "content": { "encoding": "utf-8", "data": "echo applied\n" },
+                },
+                { "type": "command", "command": "/bin/sh", "args": ["src/main.sh"] },
+            ],
+        })),
+    );
+    let project = dev.rpc("project.create", json!({ "name": "apply" })).await;
+    let project_id = project["id"].as_str().unwrap().to_string();
+    let submission = dev
+        .rpc(
+            "agent.dispatch",
+            json!({ "agent": "code", "objective": "scaffold a script" }),
+        )
+        .await;
+    let task_id = submission["task_id"].as_str().unwrap().to_string();
+    let snapshot = tokio::time::timeout(Duration::from_secs(30), async {
+        loop {
+            let snapshot = dev.rpc("agent.status", json!({ "task_id": task_id })).await;
+            if !matches!(snapshot["status"].as_str(), Some("pending" | "running")) {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("agent task finishes");
+    assert_eq!(snapshot["status"], "completed", "{snapshot}");
+
+    // Only whoever dispatched the task applies it, and only to projects
+    // they may write to.
+    let theirs = other
+        .rpc("project.create", json!({ "name": "theirs" }))
+        .await;
+    let err = other
+        .call(
+            "agent.apply",
+            json!({ "task_id": task_id, "project_id": theirs["id"] }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32091, "{err}");
+    let err = dev
+        .call(
+            "agent.apply",
+            json!({ "task_id": task_id, "project_id": theirs["id"] }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32091, "{err}");
+
+    let plan = dev
+        .rpc(
+            "agent.apply",
+            json!({ "task_id": task_id, "project_id": project_id, "dry_run": true }),
+        )
+        .await;
+    assert_eq!(plan["report"]["dry_run"], true, "{plan}");
+    let unwritten = dev
+        .call(
+            "project.file.read",
+            json!({ "project_id": project_id, "path": "src/main.sh" }),
+        )
+        .await;
+    assert!(unwritten.is_err());
+
+    dev.rpc(
+        "project.update",
+        json!({ "project_id": project_id, "allowed_programs": ["/usr/bin/env"] }),
+    )
+    .await;
+    let err = dev
+        .call(
+            "agent.apply",
+            json!({ "task_id": task_id, "project_id": project_id, "run_commands": true }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32057, "{err}");
+
+    let applied = dev
+        .rpc(
+            "agent.apply",
+            json!({ "task_id": task_id, "project_id": project_id }),
+        )
+        .await;
+    assert_eq!(applied["report"]["dry_run"], false, "{applied}");
+    assert_eq!(applied["report"]["failed"], 0, "{applied}");
+    let file = dev
+        .rpc(
+            "project.file.read",
+            json!({ "project_id": project_id, "path": "src/main.sh" }),
+        )
+        .await;
+    assert_eq!(decode(&file["data"]), "echo applied\n");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn file_versions_are_archived_trimmed_and_restored() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    let project = dev
+        .rpc("project.create", json!({ "name": "versions" }))
+        .await;
+    let project_id = project["id"].as_str().unwrap().to_string();
+    let file = json!({ "project_id": project_id, "path": "notes.txt" });
+    let history = || async {
+        let history = dev.rpc("project.file.history", file.clone()).await;
+        history["versions"].as_array().unwrap().clone()
+    };
+    let sizes = |versions: &[Value]| -> Vec<i64> {
+        versions
+            .iter()
+            .map(|version| version["size"].as_i64().unwrap())
+            .collect()
+    };
+
+    // Saving the same content again archives nothing.
+    for data in ["a", "bb", "bb", "ccc", "dddd", "eeeee"] {
+        dev.rpc(
+            "project.file.save",
+            json!({ "project_id": project_id, "path": "notes.txt", "data": encode(data) }),
+        )
+        .await;
+    }
+    // PROJECT_FILE_VERSION_LIMIT is 3 in the harness, so "a" is gone.
+    let versions = history().await;
+    assert_eq!(sizes(&versions), [4, 3, 2]);
+    let trimmed = versions[2]["version_id"].clone();
+
+    // A restore archives the current content first.
+    dev.rpc(
+        "project.file.restore",
+        json!({ "project_id": project_id, "path": "notes.txt", "version_id": versions[1]["version_id"] }),
+    )
+    .await;
+    let read = dev.rpc("project.file.read", file.clone()).await;
+    assert_eq!(decode(&read["data"]), "ccc");
+    let versions = history().await;
+    assert_eq!(sizes(&versions), [5, 4, 3]);
+
+    // So does a delete, and a deleted file can be brought back.
+    dev.rpc("project.file.delete", file.clone()).await;
+    let versions = history().await;
+    assert_eq!(sizes(&versions), [3, 5, 4]);
+    dev.rpc(
+        "project.file.restore",
+        json!({ "project_id": project_id, "path": "notes.txt", "version_id": versions[1]["version_id"] }),
+    )
+    .await;
+    let read = dev.rpc("project.file.read", file.clone()).await;
+    assert_eq!(decode(&read["data"]), "eeeee");
+
+    // A restore whose mirror write fails leaves the stored file untouched.
+    dev.rpc("fs.delete", file.clone()).await;
+    dev.rpc("fs.mkdir", file.clone()).await;
+    let versions = history().await;
+    let err = dev
+        .call(
+            "project.file.restore",
+            json!({ "project_id": project_id, "path": "notes.txt", "version_id": versions[0]["version_id"] }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32051, "{err}");
+    let read = dev.rpc("project.file.read", file.clone()).await;
+    assert_eq!(decode(&read["data"]), "eeeee");
+    assert_eq!(sizes(&history().await), sizes(&versions));
+
+    let err = dev
+        .call(
+            "project.file.restore",
+            json!({ "project_id": project_id, "path": "notes.txt", "version_id": trimmed }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32056, "{err}");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn snapshots_restore_projects() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+    let project = dev
+        .rpc("project.create", json!({ "name": "snapshots" }))
+        .await;
+    let project_id = project["id"].as_str().unwrap().to_string();
+    for (path, data) in [("a.txt", "one"), ("b.txt", "shared")] {
+        dev.rpc(
+            "project.file.save",
+            json!({ "project_id": project_id, "path": path, "data": encode(data) }),
+        )
+        .await;
+    }
+
+    let first = dev
+        .rpc(
+            "project.snapshot.create",
+            json!({ "project_id": project_id, "label": " first " }),
+        )
+        .await;
+    assert_eq!(first["label"], "first");
+    assert_eq!(first["file_count"], 2);
+    assert_eq!(first["total_size"], 9);
+    let quota = dev.rpc("quota.status", json!({})).await;
+    assert_eq!(quota["bytes"]["snapshots"], 9, "{quota}");
+    // Unchanged contents are stored once.
+    dev.rpc(
+        "project.snapshot.create",
+        json!({ "project_id": project_id }),
+    )
+    .await;
+    let quota = dev.rpc("quota.status", json!({})).await;
+    assert_eq!(quota["bytes"]["snapshots"], 9, "{quota}");
+
+    dev.rpc(
+        "project.file.save",
+        json!({ "project_id": project_id, "path": "a.txt", "data": encode("changed") }),
+    )
+    .await;
+    dev.rpc(
+        "project.file.delete",
+        json!({ "project_id": project_id, "path": "b.txt" }),
+    )
+    .await;
+    let restored = dev
+        .rpc(
+            "project.snapshot.restore",
+            json!({ "project_id": project_id, "snapshot_id": first["id"] }),
+        )
+        .await;
+    assert_eq!(restored["written"], 2, "{restored}");
+    assert_eq!(restored["removed"], 0, "{restored}");
+    assert_eq!(restored["mirror_drift"], json!([]), "{restored}");
+    for (path, data) in [("a.txt", "one"), ("b.txt", "shared")] {
+        let file = dev
+            .rpc(
+                "project.file.read",
+                json!({ "project_id": project_id, "path": path }),
+            )
+            .await;
+        assert_eq!(decode(&file["data"]), data);
+    }
+
+    let listed = dev
+        .rpc("project.snapshot.list", json!({ "project_id": project_id }))
+        .await;
+    let snapshots = listed["snapshots"].as_array().unwrap();
+    assert_eq!(snapshots.len(), 3, "{listed}");
+    assert_eq!(snapshots[0]["id"], restored["backup_snapshot_id"]);
+    assert_eq!(
+        snapshots[0]["label"],
+        format!("before restoring snapshot {}", first["id"])
+    );
+
+    // PROJECT_SNAPSHOT_LIMIT is 3 in the harness, so the oldest goes.
+    dev.rpc(
+        "project.snapshot.create",
+        json!({ "project_id": project_id }),
+    )
+    .await;
+    let listed = dev
+        .rpc("project.snapshot.list", json!({ "project_id": project_id }))
+        .await;
+    let snapshots = listed["snapshots"].as_array().unwrap();
+    assert_eq!(snapshots.len(), 3, "{listed}");
+    assert!(snapshots
+        .iter()
+        .all(|snapshot| snapshot["id"] != first["id"]));
+    let err = dev
+        .call(
+            "project.snapshot.restore",
+            json!({ "project_id": project_id, "snapshot_id": first["id"] }),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.code, -32072, "{err}");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn calls_need_a_valid_token() {
+    let harness = harness().await;
+    let forged = harness.session("not-a-token");
+    let err = forged.call("project.list", json!({})).await.unwrap_err();
+    assert_eq!(err.code, -32090, "{err}");
+
+    // Each user only sees their own projects.
+    let alice = harness.developer().await.unwrap();
+    let bob = harness.developer().await.unwrap();
+    alice
+        .rpc("project.create", json!({ "name": "private" }))
+        .await;
+    let listed: Value = bob.rpc("project.list", json!({})).await;
+    assert!(!listed.to_string().contains("private"));
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn admins_adjust_token_balances() {
+    let harness = harness().await;
+    let admin = harness.admin().await.unwrap();
+    let username = "ledger-user";
+    let user_id = harness.register(username, 100).await.unwrap();
+    let user = harness.login(username).await.unwrap();
+    let path = |action: &str| format!("/admin/users/{user_id}/balance/{action}");
+
+    let (status, credit) = harness
+        .auth(
+            Some(&admin),
+            Method::POST,
+            &path("credit"),
+            Some(json!({ "amount": 50, "reason": "grant", "note": " welcome " })),
+        )
+        .await;
+    assert_eq!(status, 200, "{credit}");
+    assert_eq!(credit["kind"], "credit");
+    assert_eq!(credit["tokens"], -50);
+    assert_eq!(credit["balance_after"], 150);
+    assert_eq!(credit["note"], "welcome");
+
+    // A manual debit may not overdraw the balance.
+    let (status, _) = harness
+        .auth(
+            Some(&admin),
+            Method::POST,
+            &path("debit"),
+            Some(json!({ "amount": 500, "reason": "correction" })),
+        )
+        .await;
+    assert_eq!(status, 409);
+    for invalid in [
+        json!({ "amount": 0, "reason": "correction" }),
+        json!({ "amount": 5, "reason": "gift" }),
+    ] {
+        let (status, _) = harness
+            .auth(Some(&admin), Method::POST, &path("debit"), Some(invalid))
+            .await;
+        assert_eq!(status, 400);
+    }
+    let (status, debit) = harness
+        .auth(
+            Some(&admin),
+            Method::POST,
+            &path("debit"),
+            Some(json!({ "amount": 30, "reason": "chargeback" })),
+        )
+        .await;
+    assert_eq!(status, 200, "{debit}");
+    assert_eq!(debit["tokens"], 30);
+    assert_eq!(debit["balance_after"], 120);
+
+    // Users read their own ledger, newest first, but cannot change it.
+    let (status, own) = harness
+        .auth(Some(&user), Method::GET, "/auth/balance?limit=1", None)
+        .await;
+    assert_eq!(status, 200, "{own}");
+    assert_eq!(own["balance"], 120);
+    assert_eq!(own["entries"][0]["id"], debit["id"]);
+    let cursor = own["next_cursor"].as_i64().expect("a second page");
+    let (_, older) = harness
+        .auth(
+            Some(&user),
+            Method::GET,
+            &format!("/auth/balance?cursor={cursor}"),
+            None,
+        )
+        .await;
+    assert_eq!(older["entries"][0]["id"], credit["id"]);
+    let (status, _) = harness
+        .auth(
+            Some(&user),
+            Method::POST,
+            &path("credit"),
+            Some(json!({ "amount": 1000, "reason": "grant" })),
+        )
+        .await;
+    assert_eq!(status, 403);
+
+    let (status, credits) = harness
+        .auth(
+            Some(&admin),
+            Method::GET,
+            &format!("/admin/users/{user_id}/ledger?kind=credit"),
+            None,
+        )
+        .await;
+    assert_eq!(status, 200, "{credits}");
+    assert_eq!(credits["entries"].as_array().unwrap().len(), 1);
+    assert_eq!(credits["entries"][0]["reason"], "grant");
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn invitations_grant_roles_and_tokens() {
+    let harness = harness().await;
+    let admin = harness.admin().await.unwrap();
+    let (status, invitation) = harness
+        .auth(
+            Some(&admin),
+            Method::POST,
+            "/admin/invitations",
+            Some(json!({ "role": "developer", "initial_tokens": 25, "note": "new hire" })),
+        )
+        .await;
+    assert_eq!(status, 201, "{invitation}");
+    let code = invitation["code"].as_str().unwrap().to_string();
+    assert!(code.starts_with("cds_inv_"));
+
+    // The invitation decides role and grant.
+    let err = harness
+        .register_with(
+            "invited-admin",
+            json!({ "invite_code": code, "role": "admin" }),
+        )
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("400"), "{err}");
+
+    let user_id = harness
+        .register_with("invited", json!({ "invite_code": code }))
+        .await
+        .unwrap();
+    let invited = harness.login("invited").await.unwrap();
+    let (_, balance) = harness
+        .auth(Some(&invited), Method::GET, "/auth/balance", None)
+        .await;
+    assert_eq!(balance["balance"], 25);
+    assert_eq!(balance["entries"][0]["reason"], "grant");
+    assert_eq!(balance["entries"][0]["method"], "auth.register");
+
+    // Codes are single-use.
+    let err = harness
+        .register_with("invited-again", json!({ "invite_code": code }))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("403"), "{err}");
+
+    let (_, used) = harness
+        .auth(
+            Some(&admin),
+            Method::GET,
+            "/admin/invitations?status=used",
+            None,
+        )
+        .await;
+    assert_eq!(used["invitations"][0]["id"], invitation["id"]);
+    assert_eq!(used["invitations"][0]["used_by"], user_id);
+    let (_, pending) = harness
+        .auth(Some(&admin), Method::GET, "/admin/invitations", None)
+        .await;
+    assert_eq!(pending["invitations"], json!([]));
+
+    let used_path = format!("/admin/invitations/{}", invitation["id"].as_str().unwrap());
+    let (status, _) = harness
+        .auth(Some(&admin), Method::DELETE, &used_path, None)
+        .await;
+    assert_eq!(status, 409);
+    let (_, unused) = harness
+        .auth(
+            Some(&admin),
+            Method::POST,
+            "/admin/invitations",
+            Some(json!({ "expires_in_hours": 1 })),
+        )
+        .await;
+    let unused_path = format!("/admin/invitations/{}", unused["id"].as_str().unwrap());
+    let (status, _) = harness
+        .auth(Some(&admin), Method::DELETE, &unused_path, None)
+        .await;
+    assert_eq!(status, 204);
+    let (status, _) = harness
+        .auth(Some(&admin), Method::DELETE, &unused_path, None)
+        .await;
+    assert_eq!(status, 404);
+
+    // Only `user.admin` holders manage invitations.
+    let (status, _) = harness
+        .auth(
+            Some(&invited),
+            Method::POST,
+            "/admin/invitations",
+            Some(json!({})),
+        )
+        .await;
+    assert_eq!(status, 403);
+}
+
+/// Answers one JSON-RPC call with `result` and hands back the raw request.
+async fn replica_stub(result: Value) -> (String, tokio::task::JoinHandle<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request).to_lowercase();
+            let complete = text.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                body.len() >= length
+            });
+            if complete || read == 0 {
+                break;
+            }
+        }
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "result": result }).to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    (url, handle)
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn calls_follow_their_handle_to_the_owning_replica() {
+    let harness = harness().await;
+    let dev = harness.developer().await.unwrap();
+
+    // Another replica with a live lease owns this VM.
+    let (url, received) = replica_stub(json!({ "stdout": encode("from the owner\n") })).await;
+    let replica = uuid::Uuid::new_v4();
+    let vm_id = uuid::Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO replicas (id, url, lease_expires_at) VALUES ($1, $2, NOW() + INTERVAL '1 hour')",
+    )
+    .bind(replica)
+    .bind(&url)
+    .execute(harness.pool())
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO replica_handles (handle, kind, replica_id) VALUES ($1, 'micro', $2)")
+        .bind(vm_id)
+        .bind(replica)
+        .execute(harness.pool())
+        .await
+        .unwrap();
+
+    let params = json!({ "vm_id": vm_id.to_string(), "code": encode("true") });
+    let result = dev.rpc("micro.execute", params.clone()).await;
+    assert_eq!(decode(&result["stdout"]), "from the owner\n");
+    let request = received.await.unwrap();
+    assert!(request.starts_with("POST /rpc "), "{request}");
+    let lowercase = request.to_lowercase();
+    assert!(
+        lowercase.contains("\r\nx-coder-forwarded-by: "),
+        "{request}"
+    );
+    assert!(
+        lowercase.contains(&format!(
+            "\r\nauthorization: bearer {}\r\n",
+            dev.token().to_lowercase()
+        )),
+        "{request}"
+    );
+    assert!(request.contains(r#""method":"micro.execute""#), "{request}");
+
+    // Once the owner's lease ran out, the VM is as unknown here as it would
+    // be there.
+    sqlx::query("UPDATE replicas SET lease_expires_at = NOW() - INTERVAL '1 second' WHERE id = $1")
+        .bind(replica)
+        .execute(harness.pool())
+        .await
+        .unwrap();
+    assert!(dev.call("micro.execute", params).await.is_err());
+}